@@ -0,0 +1,228 @@
+//! Azure CLI authentication for local development
+//!
+//! Shells out to `az account get-access-token` so a developer who has run
+//! `az login` can query a sandbox without an app registration.
+
+use super::{AcquiredToken, AuthError, TokenCache, TokenProvider};
+use async_trait::async_trait;
+use chrono::{Local, NaiveDateTime, TimeZone};
+use serde::Deserialize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::process::Command;
+
+/// Default Azure CLI executable (`az.cmd` is a batch wrapper on Windows)
+const DEFAULT_AZ_PROGRAM: &str = if cfg!(windows) { "az.cmd" } else { "az" };
+
+/// Output of `az account get-access-token --output json`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CliTokenResponse {
+    access_token: String,
+    /// Local time, e.g. `2024-01-01 12:00:00.000000` (all CLI versions)
+    #[serde(default)]
+    expires_on: Option<String>,
+    /// Epoch seconds (Azure CLI 2.54+)
+    #[serde(default, rename = "expires_on")]
+    expires_on_epoch: Option<u64>,
+}
+
+/// Parse CLI output into a token, rejecting tokens that have already expired
+fn parse_cli_output(stdout: &[u8], now: SystemTime) -> Result<AcquiredToken, AuthError> {
+    let response: CliTokenResponse = serde_json::from_slice(stdout).map_err(|e| {
+        AuthError::ParseError(format!("Failed to parse Azure CLI token output: {}", e))
+    })?;
+
+    let expires_at = match (response.expires_on_epoch, response.expires_on.as_deref()) {
+        (Some(epoch), _) => epoch,
+        (None, Some(local)) => parse_local_expiry(local)?,
+        (None, None) => {
+            return Err(AuthError::ParseError(
+                "Azure CLI token output has no expiry".to_string(),
+            ))
+        }
+    };
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+    if expires_at <= now {
+        return Err(AuthError::MissingCredentials(
+            "Azure CLI returned an expired token. Run `az login` to refresh your session."
+                .to_string(),
+        ));
+    }
+
+    Ok(AcquiredToken {
+        access_token: response.access_token,
+        expires_in: Duration::from_secs(expires_at - now),
+    })
+}
+
+/// Parse the CLI's local-time `expiresOn` into epoch seconds
+fn parse_local_expiry(value: &str) -> Result<u64, AuthError> {
+    let naive = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f")
+        .map_err(|e| AuthError::ParseError(format!("Invalid expiresOn '{}': {}", value, e)))?;
+    let local = Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| {
+            AuthError::ParseError(format!("Invalid local time in expiresOn '{}'", value))
+        })?;
+
+    Ok(local.timestamp().max(0) as u64)
+}
+
+/// Token provider backed by the developer's Azure CLI login
+#[derive(Debug)]
+pub struct AzureCliAuth {
+    program: String,
+    /// Tenant passed as `--tenant`; the CLI default tenant if `None`
+    tenant_id: Option<String>,
+    token_cache: TokenCache,
+}
+
+impl AzureCliAuth {
+    /// Create an Azure CLI provider
+    pub fn new(tenant_id: Option<String>) -> Self {
+        Self {
+            program: DEFAULT_AZ_PROGRAM.to_string(),
+            tenant_id,
+            token_cache: TokenCache::default(),
+        }
+    }
+
+    /// Use a specific `az` executable instead of the one on `PATH`
+    pub fn with_program(mut self, program: impl Into<String>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    /// Acquire or return a cached access token for the given resource.
+    pub async fn get_token(&self, resource: &str) -> Result<String, AuthError> {
+        self.token_cache
+            .get_or_acquire(resource, || self.acquire_token(resource))
+            .await
+    }
+
+    /// Clear the token cache for all resources
+    pub async fn clear_cache(&self) {
+        self.token_cache.clear().await;
+    }
+
+    /// Clear the cached token for a single resource
+    pub async fn clear_resource(&self, resource: &str) {
+        self.token_cache.remove(resource).await;
+    }
+
+    /// Run `az account get-access-token` for the resource
+    async fn acquire_token(&self, resource: &str) -> Result<AcquiredToken, AuthError> {
+        let mut command = Command::new(&self.program);
+        command.args([
+            "account",
+            "get-access-token",
+            "--output",
+            "json",
+            "--resource",
+            resource,
+        ]);
+        if let Some(ref tenant_id) = self.tenant_id {
+            command.args(["--tenant", tenant_id]);
+        }
+
+        tracing::debug!("Requesting token from Azure CLI for {}", resource);
+
+        let output = command.output().await.map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                AuthError::MissingCredentials(format!(
+                    "Azure CLI (`{}`) was not found on PATH. Install the Azure CLI and run `az login`.",
+                    self.program
+                ))
+            } else {
+                AuthError::TokenRequestFailed(format!("Failed to run Azure CLI: {}", e))
+            }
+        })?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            tracing::error!("Azure CLI token request failed: {}", stderr.trim());
+            return Err(AuthError::MissingCredentials(format!(
+                "Azure CLI could not provide a token; run `az login` and try again. {}",
+                stderr.trim()
+            )));
+        }
+
+        parse_cli_output(&output.stdout, SystemTime::now())
+    }
+}
+
+#[async_trait]
+impl TokenProvider for AzureCliAuth {
+    async fn get_token(&self, resource: &str) -> Result<String, AuthError> {
+        AzureCliAuth::get_token(self, resource).await
+    }
+
+    async fn clear_cache(&self) {
+        AzureCliAuth::clear_cache(self).await
+    }
+
+    async fn clear_resource(&self, resource: &str) {
+        AzureCliAuth::clear_resource(self, resource).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn at(epoch: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(epoch)
+    }
+
+    #[test]
+    fn epoch_expiry_is_preferred() {
+        let output = json!({
+            "accessToken": "cli-token",
+            "expiresOn": "1999-01-01 00:00:00.000000",
+            "expires_on": 1_700_003_600u64,
+            "tokenType": "Bearer"
+        });
+
+        let token = parse_cli_output(output.to_string().as_bytes(), at(1_700_000_000)).unwrap();
+        assert_eq!(token.access_token, "cli-token");
+        assert_eq!(token.expires_in, Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn local_expiry_is_parsed() {
+        let expires = Local::now() + chrono::Duration::minutes(30);
+        let output = json!({
+            "accessToken": "cli-token",
+            "expiresOn": expires.format("%Y-%m-%d %H:%M:%S%.6f").to_string()
+        });
+
+        let token = parse_cli_output(output.to_string().as_bytes(), SystemTime::now()).unwrap();
+        assert!(token.expires_in > Duration::from_secs(29 * 60));
+        assert!(token.expires_in <= Duration::from_secs(30 * 60));
+    }
+
+    #[test]
+    fn expired_cli_token_asks_for_login() {
+        let output = json!({"accessToken": "old", "expires_on": 1_600_000_000u64});
+
+        let err = parse_cli_output(output.to_string().as_bytes(), at(1_700_000_000)).unwrap_err();
+        assert!(matches!(err, AuthError::MissingCredentials(ref msg) if msg.contains("az login")));
+    }
+
+    #[tokio::test]
+    async fn missing_cli_binary_asks_for_install() {
+        let auth = AzureCliAuth::new(None).with_program("definitely-not-az-cli");
+
+        let err = auth
+            .get_token("https://org.crm.dynamics.com")
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, AuthError::MissingCredentials(ref msg) if msg.contains("not found") && msg.contains("az login")),
+            "{err}"
+        );
+    }
+}
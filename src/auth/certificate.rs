@@ -0,0 +1,258 @@
+//! Certificate credentials for the OAuth2 client assertion flow
+//!
+//! Builds the RS256-signed JWT that replaces `client_secret` when an app
+//! registration only trusts certificates.
+
+use super::AuthError;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs1v15::SigningKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::signature::{SignatureEncoding, Signer};
+use rsa::traits::PrivateKeyParts;
+use rsa::RsaPrivateKey;
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// `client_assertion_type` value for JWT bearer assertions
+pub const CLIENT_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// Lifetime of a signed client assertion
+const ASSERTION_LIFETIME: Duration = Duration::from_secs(600);
+
+/// Private key plus certificate thumbprint used to sign client assertions
+#[derive(Clone)]
+pub struct ClientCertificate {
+    private_key: RsaPrivateKey,
+    thumbprint: String,
+}
+
+impl std::fmt::Debug for ClientCertificate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the private key
+        f.debug_struct("ClientCertificate")
+            .field("thumbprint", &self.thumbprint)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ClientCertificate {
+    /// Load a certificate from a PEM (`.pem`, `.crt`, `.key`) or PKCS#12
+    /// (`.pfx`, `.p12`) file
+    pub fn from_file<P: AsRef<Path>>(path: P, password: Option<&str>) -> Result<Self, AuthError> {
+        let path = path.as_ref();
+        let data = std::fs::read(path).map_err(|e| {
+            AuthError::MissingCredentials(format!(
+                "Failed to read client certificate {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+
+        let is_pkcs12 = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("pfx") || ext.eq_ignore_ascii_case("p12"));
+
+        if is_pkcs12 {
+            Self::from_pkcs12(&data, password.unwrap_or_default())
+        } else {
+            let pem = String::from_utf8(data).map_err(|_| {
+                AuthError::MissingCredentials(format!(
+                    "Client certificate {} is not valid PEM",
+                    path.display()
+                ))
+            })?;
+            Self::from_pem(&pem, password)
+        }
+    }
+
+    /// Parse a PEM bundle containing both the certificate and its private key
+    pub fn from_pem(pem: &str, password: Option<&str>) -> Result<Self, AuthError> {
+        let blocks = pem::parse_many(pem)
+            .map_err(|e| AuthError::MissingCredentials(format!("Invalid PEM: {}", e)))?;
+
+        let certificate = blocks
+            .iter()
+            .find(|block| block.tag() == "CERTIFICATE")
+            .ok_or_else(|| {
+                AuthError::MissingCredentials("PEM has no CERTIFICATE block".to_string())
+            })?;
+
+        let private_key = blocks
+            .iter()
+            .find_map(|block| match block.tag() {
+                "PRIVATE KEY" => Some(RsaPrivateKey::from_pkcs8_der(block.contents())),
+                "RSA PRIVATE KEY" => {
+                    Some(RsaPrivateKey::from_pkcs1_der(block.contents()).map_err(Into::into))
+                }
+                "ENCRYPTED PRIVATE KEY" => Some(RsaPrivateKey::from_pkcs8_encrypted_der(
+                    block.contents(),
+                    password.unwrap_or_default(),
+                )),
+                _ => None,
+            })
+            .ok_or_else(|| {
+                AuthError::MissingCredentials("PEM has no RSA private key block".to_string())
+            })?
+            .map_err(|e| AuthError::MissingCredentials(format!("Invalid private key: {}", e)))?;
+
+        Ok(Self {
+            private_key,
+            thumbprint: thumbprint(certificate.contents()),
+        })
+    }
+
+    /// Parse a PKCS#12 (PFX) archive
+    pub fn from_pkcs12(der: &[u8], password: &str) -> Result<Self, AuthError> {
+        let keystore = p12_keystore::KeyStore::from_pkcs12(der, password)
+            .map_err(|e| AuthError::MissingCredentials(format!("Invalid PFX file: {}", e)))?;
+
+        let (_, chain) = keystore.private_key_chain().ok_or_else(|| {
+            AuthError::MissingCredentials("PFX file contains no private key".to_string())
+        })?;
+        let certificate = chain.chain().first().ok_or_else(|| {
+            AuthError::MissingCredentials("PFX file contains no certificate".to_string())
+        })?;
+
+        let private_key = RsaPrivateKey::from_pkcs8_der(chain.key())
+            .map_err(|e| AuthError::MissingCredentials(format!("Invalid private key: {}", e)))?;
+
+        Ok(Self {
+            private_key,
+            thumbprint: thumbprint(certificate.as_der()),
+        })
+    }
+
+    /// Secret bytes of the private key, to derive other keys from
+    pub(crate) fn key_material(&self) -> Vec<u8> {
+        self.private_key.d().to_bytes_be()
+    }
+
+    /// Base64url-encoded SHA-1 thumbprint, as sent in the `x5t` header
+    pub fn thumbprint(&self) -> &str {
+        &self.thumbprint
+    }
+
+    /// Build a signed client assertion for the given token endpoint
+    pub fn client_assertion(
+        &self,
+        client_id: &str,
+        audience: &str,
+        now: SystemTime,
+    ) -> Result<String, AuthError> {
+        let issued_at = now
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| AuthError::TokenRequestFailed(format!("Invalid system time: {}", e)))?
+            .as_secs();
+
+        let header = json!({
+            "alg": "RS256",
+            "typ": "JWT",
+            "x5t": self.thumbprint,
+        });
+        let claims = json!({
+            "aud": audience,
+            "iss": client_id,
+            "sub": client_id,
+            "jti": format!("{:032x}", rand::random::<u128>()),
+            "nbf": issued_at,
+            "iat": issued_at,
+            "exp": issued_at + ASSERTION_LIFETIME.as_secs(),
+        });
+
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+
+        let signing_key = SigningKey::<Sha256>::new(self.private_key.clone());
+        let signature = signing_key.sign(signing_input.as_bytes()).to_vec();
+
+        Ok(format!(
+            "{}.{}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature)
+        ))
+    }
+}
+
+/// Base64url SHA-1 digest of a DER-encoded certificate
+fn thumbprint(der: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(Sha1::digest(der))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::pkcs1v15::{Signature, VerifyingKey};
+    use rsa::signature::Verifier;
+    use serde_json::Value;
+
+    const TEST_PEM: &str = include_str!("testdata/client-cert.pem");
+    const TEST_PFX: &[u8] = include_bytes!("testdata/client-cert.pfx");
+    const TEST_THUMBPRINT: &str = "iZxCgxgCk4OJ2I-zdUXYSN5hVUg";
+
+    fn decode_part(part: &str) -> Value {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn pem_and_pfx_yield_same_thumbprint() {
+        let pem = ClientCertificate::from_pem(TEST_PEM, None).unwrap();
+        let pfx = ClientCertificate::from_pkcs12(TEST_PFX, "test-password").unwrap();
+
+        assert_eq!(pem.thumbprint(), TEST_THUMBPRINT);
+        assert_eq!(pfx.thumbprint(), TEST_THUMBPRINT);
+    }
+
+    #[test]
+    fn wrong_pfx_password_is_rejected() {
+        assert!(ClientCertificate::from_pkcs12(TEST_PFX, "wrong").is_err());
+    }
+
+    #[test]
+    fn assertion_has_expected_header_and_claims() {
+        let cert = ClientCertificate::from_pem(TEST_PEM, None).unwrap();
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let audience = "https://login.microsoftonline.com/tenant/oauth2/v2.0/token";
+
+        let assertion = cert.client_assertion("client-id", audience, now).unwrap();
+        let parts: Vec<&str> = assertion.split('.').collect();
+        assert_eq!(parts.len(), 3);
+
+        let header = decode_part(parts[0]);
+        assert_eq!(header["alg"], "RS256");
+        assert_eq!(header["typ"], "JWT");
+        assert_eq!(header["x5t"], TEST_THUMBPRINT);
+
+        let claims = decode_part(parts[1]);
+        assert_eq!(claims["aud"], audience);
+        assert_eq!(claims["iss"], "client-id");
+        assert_eq!(claims["sub"], "client-id");
+        assert_eq!(claims["nbf"], 1_700_000_000u64);
+        assert_eq!(claims["exp"], 1_700_000_600u64);
+        assert_eq!(claims["jti"].as_str().unwrap().len(), 32);
+    }
+
+    #[test]
+    fn assertion_signature_verifies_with_public_key() {
+        let cert = ClientCertificate::from_pem(TEST_PEM, None).unwrap();
+        let assertion = cert
+            .client_assertion("client-id", "aud", SystemTime::now())
+            .unwrap();
+        let (signing_input, signature) = assertion.rsplit_once('.').unwrap();
+
+        let verifying_key = VerifyingKey::<Sha256>::new(cert.private_key.to_public_key());
+        let signature =
+            Signature::try_from(URL_SAFE_NO_PAD.decode(signature).unwrap().as_slice()).unwrap();
+        verifying_key
+            .verify(signing_input.as_bytes(), &signature)
+            .unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+//! Azure cloud environments
+//!
+//! Sovereign clouds use their own Entra ID authority and D365 domains, so the
+//! token endpoint and the endpoint sanity check both depend on the cloud.
+
+use reqwest::Url;
+use serde::Deserialize;
+
+/// Azure cloud the tenant lives in
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CloudEnvironment {
+    /// Azure public cloud
+    #[default]
+    Public,
+    /// Azure Government (GCC)
+    UsGov,
+    /// Azure Government GCC High / DoD
+    UsGovHigh,
+    /// Azure China (21Vianet)
+    China,
+}
+
+impl CloudEnvironment {
+    /// Default Entra ID authority host for this cloud
+    pub fn authority_host(&self) -> &'static str {
+        match self {
+            CloudEnvironment::Public => "https://login.microsoftonline.com",
+            CloudEnvironment::UsGov | CloudEnvironment::UsGovHigh => {
+                "https://login.microsoftonline.us"
+            }
+            CloudEnvironment::China => "https://login.chinacloudapi.cn",
+        }
+    }
+
+    /// Host suffixes of D365 endpoints in this cloud
+    pub fn endpoint_suffixes(&self) -> &'static [&'static str] {
+        match self {
+            CloudEnvironment::Public => &[".dynamics.com"],
+            CloudEnvironment::UsGov => &[".dynamics.us", ".crm9.dynamics.com"],
+            CloudEnvironment::UsGovHigh => &[".microsoftdynamics.us", ".appsplatform.us"],
+            CloudEnvironment::China => &[".dynamics.cn"],
+        }
+    }
+
+    /// Whether the endpoint host looks like it belongs to this cloud.
+    ///
+    /// Hosts outside every known D365 domain (on-premise, proxies) always
+    /// match, since there is nothing to compare against.
+    pub fn matches_endpoint(&self, endpoint: &str) -> bool {
+        let Some(host) = Url::parse(endpoint)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_lowercase))
+        else {
+            return true;
+        };
+
+        match Self::owning_cloud(&host) {
+            Some(owner) => owner == *self,
+            None => true,
+        }
+    }
+
+    /// Cloud whose endpoint suffix matches the host most specifically, so
+    /// `.crm9.dynamics.com` (GCC) wins over the public `.dynamics.com`
+    fn owning_cloud(host: &str) -> Option<CloudEnvironment> {
+        [
+            CloudEnvironment::Public,
+            CloudEnvironment::UsGov,
+            CloudEnvironment::UsGovHigh,
+            CloudEnvironment::China,
+        ]
+        .into_iter()
+        .flat_map(|cloud| {
+            cloud
+                .endpoint_suffixes()
+                .iter()
+                .map(move |suffix| (cloud, suffix.len(), host.ends_with(suffix)))
+        })
+        .filter(|(_, _, matched)| *matched)
+        .max_by_key(|(_, len, _)| *len)
+        .map(|(cloud, _, _)| cloud)
+    }
+}
+
+impl std::str::FromStr for CloudEnvironment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "public" | "azurepubliccloud" | "azure" => Ok(CloudEnvironment::Public),
+            "usgov" | "us_gov" | "gcc" | "azureusgovernment" => Ok(CloudEnvironment::UsGov),
+            "usgovhigh" | "us_gov_high" | "gcchigh" | "dod" => Ok(CloudEnvironment::UsGovHigh),
+            "china" | "azurechinacloud" => Ok(CloudEnvironment::China),
+            _ => Err(format!(
+                "Unknown cloud: {}. Use 'public', 'us_gov', 'us_gov_high' or 'china'",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authority_defaults() {
+        assert_eq!(
+            CloudEnvironment::default().authority_host(),
+            "https://login.microsoftonline.com"
+        );
+        assert_eq!(
+            CloudEnvironment::UsGov.authority_host(),
+            "https://login.microsoftonline.us"
+        );
+        assert_eq!(
+            CloudEnvironment::China.authority_host(),
+            "https://login.chinacloudapi.cn"
+        );
+    }
+
+    #[test]
+    fn parse_cloud_names() {
+        assert_eq!(
+            "us_gov".parse::<CloudEnvironment>().unwrap(),
+            CloudEnvironment::UsGov
+        );
+        assert_eq!(
+            "GCCHigh".parse::<CloudEnvironment>().unwrap(),
+            CloudEnvironment::UsGovHigh
+        );
+        assert!("mars".parse::<CloudEnvironment>().is_err());
+    }
+
+    #[test]
+    fn endpoint_matching() {
+        let gov = CloudEnvironment::UsGov;
+        assert!(gov.matches_endpoint("https://org.crm.dynamics.us/api/data/v9.2/"));
+        assert!(!gov.matches_endpoint("https://org.crm.dynamics.com/api/data/v9.2/"));
+        assert!(gov.matches_endpoint("https://org.crm9.dynamics.com/api/data/v9.2/"));
+
+        let public = CloudEnvironment::Public;
+        assert!(public.matches_endpoint("https://org.operations.dynamics.com/data/"));
+        assert!(!public.matches_endpoint("https://org.crm.dynamics.cn/api/data/v9.2/"));
+        // GCC hosts share the public domain but a longer suffix claims them
+        assert!(!public.matches_endpoint("https://org.crm9.dynamics.com/api/data/v9.2/"));
+
+        // On-premise hosts are not validated
+        assert!(gov.matches_endpoint("https://d365.contoso.local/namespaces/AXSF/data/"));
+    }
+}
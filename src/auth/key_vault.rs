@@ -0,0 +1,241 @@
+//! Client secret stored in Azure Key Vault
+//!
+//! The app registration's secret is read from a Key Vault secret URI with a
+//! bootstrap identity (a managed identity, or the same app authenticating
+//! with a certificate), so it never has to sit in a config file. The value
+//! is kept in memory only and read again after Azure AD rejects it, in case
+//! it was rotated.
+
+use super::{AuthError, TokenProvider};
+use crate::network::{apply_tls, ProxySettings, USER_AGENT};
+use reqwest::{Certificate, Client, Url};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Key Vault REST API version used for secret reads
+const KEY_VAULT_API_VERSION: &str = "7.4";
+
+/// Token audience for public cloud vaults
+const PUBLIC_VAULT_RESOURCE: &str = "https://vault.azure.net";
+
+#[derive(Deserialize)]
+struct SecretBundle {
+    value: String,
+}
+
+/// A Key Vault secret read on first use and cached in memory
+pub struct KeyVaultSecret {
+    uri: String,
+    bootstrap: Arc<dyn TokenProvider>,
+    /// Who reads the secret, for error messages, e.g. "managed identity"
+    identity: String,
+    http_client: Client,
+    /// Outbound proxy, named in connection errors
+    proxy: ProxySettings,
+    /// Extra trusted root certificates
+    root_certificates: Vec<Certificate>,
+    cached: Mutex<Option<String>>,
+}
+
+impl std::fmt::Debug for KeyVaultSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyVaultSecret")
+            .field("uri", &self.uri)
+            .field("identity", &self.identity)
+            .finish_non_exhaustive()
+    }
+}
+
+impl KeyVaultSecret {
+    /// Secret at `uri` (`https://<vault>.vault.azure.net/secrets/<name>[/<version>]`),
+    /// read with tokens from `bootstrap`
+    pub fn new(
+        uri: impl Into<String>,
+        bootstrap: Arc<dyn TokenProvider>,
+        identity: impl Into<String>,
+    ) -> Self {
+        Self {
+            uri: uri.into(),
+            bootstrap,
+            identity: identity.into(),
+            http_client: Self::build_http_client(&ProxySettings::default(), &[]),
+            proxy: ProxySettings::default(),
+            root_certificates: Vec::new(),
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Read the vault through an explicit proxy
+    pub fn with_proxy(mut self, proxy: ProxySettings) -> Self {
+        self.http_client = Self::build_http_client(&proxy, &self.root_certificates);
+        self.proxy = proxy;
+        self
+    }
+
+    /// Trust these root certificates as well, e.g. those of an inspecting proxy
+    pub fn with_root_certificates(mut self, certificates: Vec<Certificate>) -> Self {
+        self.http_client = Self::build_http_client(&self.proxy, &certificates);
+        self.root_certificates = certificates;
+        self
+    }
+
+    fn build_http_client(proxy: &ProxySettings, root_certificates: &[Certificate]) -> Client {
+        let builder = apply_tls(
+            Client::builder().user_agent(USER_AGENT),
+            root_certificates,
+            false,
+        );
+        proxy
+            .apply(builder)
+            .and_then(|builder| builder.build())
+            .unwrap_or_else(|_| Client::new())
+    }
+
+    /// Token audience for the vault: `https://vault.<cloud suffix>`, taken
+    /// from the host so sovereign clouds work too
+    fn resource(&self) -> String {
+        Url::parse(&self.uri)
+            .ok()
+            .and_then(|url| {
+                let host = url.host_str()?.to_string();
+                let (_, suffix) = host.split_once(".vault.")?;
+                Some(format!("https://vault.{}", suffix))
+            })
+            .unwrap_or_else(|| PUBLIC_VAULT_RESOURCE.to_string())
+    }
+
+    /// The secret value; concurrent first callers share one read
+    pub async fn get(&self) -> Result<String, AuthError> {
+        let mut cached = self.cached.lock().await;
+        if let Some(secret) = cached.as_ref() {
+            return Ok(secret.clone());
+        }
+        let secret = self.fetch().await?;
+        *cached = Some(secret.clone());
+        Ok(secret)
+    }
+
+    /// Forget the cached value so the next `get` reads the vault again
+    pub async fn invalidate(&self) {
+        *self.cached.lock().await = None;
+    }
+
+    async fn fetch(&self) -> Result<String, AuthError> {
+        let token = self
+            .bootstrap
+            .get_token(&self.resource())
+            .await
+            .map_err(|e| {
+                AuthError::KeyVault(format!(
+                    "could not get a Key Vault token as {}: {}",
+                    self.identity, e
+                ))
+            })?;
+
+        tracing::info!("Reading client secret from Key Vault as {}", self.identity);
+        let response = self
+            .http_client
+            .get(&self.uri)
+            .query(&[("api-version", KEY_VAULT_API_VERSION)])
+            .bearer_auth(token)
+            .send()
+            .await
+            .map_err(|e| {
+                let via = match self.proxy.proxy_for(&self.uri) {
+                    Some(proxy) => format!(" via proxy {}", proxy),
+                    None => String::new(),
+                };
+                AuthError::KeyVault(format!("could not reach {}{}: {}", self.uri, via, e))
+            })?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            let reason = match status.as_u16() {
+                401 | 403 => format!(
+                    "{} is not allowed to read it. Grant it the 'Get' permission on secrets \
+                     (the 'Key Vault Secrets User' role, or an access policy)",
+                    self.identity
+                ),
+                404 => "the secret does not exist; check its name and version".to_string(),
+                _ => format!("unexpected response: {}", body),
+            };
+            return Err(AuthError::KeyVault(format!(
+                "{} returned {}: {}",
+                self.uri, status, reason
+            )));
+        }
+
+        let bundle: SecretBundle = response.json().await.map_err(|e| {
+            AuthError::KeyVault(format!("{} did not return a secret: {}", self.uri, e))
+        })?;
+        Ok(bundle.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::StaticTokenProvider;
+    use serde_json::json;
+    use wiremock::matchers::{header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn secret_at(uri: String) -> KeyVaultSecret {
+        KeyVaultSecret::new(
+            uri,
+            Arc::new(StaticTokenProvider::new("vault-token")),
+            "managed identity 1111",
+        )
+    }
+
+    #[test]
+    fn resource_follows_the_vault_cloud() {
+        let public = secret_at("https://kv.vault.azure.net/secrets/d365".to_string());
+        assert_eq!(public.resource(), "https://vault.azure.net");
+        let china = secret_at("https://kv.vault.azure.cn/secrets/d365/abc".to_string());
+        assert_eq!(china.resource(), "https://vault.azure.cn");
+    }
+
+    #[tokio::test]
+    async fn secret_is_read_once_and_again_after_invalidation() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/secrets/d365"))
+            .and(query_param("api-version", KEY_VAULT_API_VERSION))
+            .and(header("authorization", "Bearer vault-token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": "from-vault",
+                "id": "https://kv.vault.azure.net/secrets/d365/1"
+            })))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let secret = secret_at(format!("{}/secrets/d365", server.uri()));
+        assert_eq!(secret.get().await.unwrap(), "from-vault");
+        assert_eq!(secret.get().await.unwrap(), "from-vault");
+        secret.invalidate().await;
+        assert_eq!(secret.get().await.unwrap(), "from-vault");
+        assert!(!format!("{:?}", secret).contains("from-vault"));
+    }
+
+    #[tokio::test]
+    async fn forbidden_read_names_the_identity_and_permission() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(403).set_body_string("Forbidden"))
+            .mount(&server)
+            .await;
+
+        let error = secret_at(format!("{}/secrets/d365", server.uri()))
+            .get()
+            .await
+            .unwrap_err();
+        assert!(matches!(error, AuthError::KeyVault(_)));
+        let message = error.to_string();
+        assert!(message.contains("managed identity 1111"), "{message}");
+        assert!(message.contains("'Get' permission on secrets"), "{message}");
+    }
+}
@@ -0,0 +1,394 @@
+//! Managed Identity authentication
+//!
+//! Fetches tokens from the Azure Instance Metadata Service (VMs, AKS) or the
+//! App Service / Container Apps identity endpoint, so no client secret needs
+//! to be stored when the server runs inside Azure.
+
+use super::{AcquiredToken, AuthError, TokenCache, TokenProvider};
+use crate::network::{ProxySettings, USER_AGENT};
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::Value;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default Azure Instance Metadata Service token endpoint
+pub const IMDS_TOKEN_ENDPOINT: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+
+/// IMDS must never be reached through a proxy
+const IMDS_HOST: &str = "169.254.169.254";
+
+const IMDS_API_VERSION: &str = "2018-02-01";
+const APP_SERVICE_API_VERSION: &str = "2019-08-01";
+
+/// Where managed identity tokens come from
+#[derive(Debug, Clone, PartialEq)]
+pub enum ManagedIdentitySource {
+    /// Instance Metadata Service (VMs, VM scale sets, AKS)
+    Imds { endpoint: String },
+    /// App Service / Container Apps (`IDENTITY_ENDPOINT` + `IDENTITY_HEADER`)
+    AppService { endpoint: String, header: String },
+}
+
+impl ManagedIdentitySource {
+    /// Detect the source from the environment: App Service variables when
+    /// present, otherwise IMDS
+    pub fn from_env() -> Self {
+        match (
+            std::env::var("IDENTITY_ENDPOINT"),
+            std::env::var("IDENTITY_HEADER"),
+        ) {
+            (Ok(endpoint), Ok(header)) if !endpoint.is_empty() && !header.is_empty() => {
+                ManagedIdentitySource::AppService { endpoint, header }
+            }
+            _ => ManagedIdentitySource::Imds {
+                endpoint: IMDS_TOKEN_ENDPOINT.to_string(),
+            },
+        }
+    }
+}
+
+/// Token response from a managed identity endpoint
+///
+/// IMDS returns `expires_in` as a string, App Service returns `expires_on`
+/// as epoch seconds (also a string), so both are parsed leniently.
+#[derive(Debug, Deserialize)]
+struct ManagedIdentityTokenResponse {
+    access_token: String,
+    #[serde(default)]
+    expires_in: Option<Value>,
+    #[serde(default)]
+    expires_on: Option<Value>,
+}
+
+impl ManagedIdentityTokenResponse {
+    /// Remaining token lifetime
+    fn lifetime(&self, now: SystemTime) -> Result<Duration, AuthError> {
+        if let Some(secs) = self.expires_in.as_ref().and_then(lenient_u64) {
+            return Ok(Duration::from_secs(secs));
+        }
+
+        let expires_on = self
+            .expires_on
+            .as_ref()
+            .and_then(lenient_u64)
+            .ok_or_else(|| {
+                AuthError::ParseError(
+                    "Managed identity response has no expires_in or expires_on".to_string(),
+                )
+            })?;
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+        Ok(Duration::from_secs(expires_on.saturating_sub(now)))
+    }
+}
+
+/// Accept a JSON number or a numeric string
+fn lenient_u64(value: &Value) -> Option<u64> {
+    match value {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Token provider backed by an Azure managed identity
+#[derive(Debug)]
+pub struct ManagedIdentityAuth {
+    source: ManagedIdentitySource,
+    /// Client ID of a user-assigned identity; `None` uses the system identity
+    client_id: Option<String>,
+    http_client: Client,
+    /// Outbound proxy, named in connection errors
+    proxy: ProxySettings,
+    token_cache: TokenCache,
+}
+
+impl ManagedIdentityAuth {
+    /// Create a managed identity provider for an explicit source
+    pub fn new(source: ManagedIdentitySource, client_id: Option<String>) -> Self {
+        let proxy = ProxySettings::default();
+        Self {
+            source,
+            client_id,
+            http_client: Self::build_http_client(&proxy),
+            proxy,
+            token_cache: TokenCache::default(),
+        }
+    }
+
+    /// Send requests through an explicit proxy; IMDS and `no_proxy` hosts
+    /// are still reached directly
+    pub fn with_proxy(mut self, proxy: ProxySettings) -> Self {
+        self.proxy = proxy.bypassing(IMDS_HOST);
+        self.http_client = Self::build_http_client(&self.proxy);
+        self
+    }
+
+    fn build_http_client(proxy: &ProxySettings) -> Client {
+        // IMDS is unreachable outside Azure; fail fast instead of hanging
+        let builder = Client::builder()
+            .user_agent(USER_AGENT)
+            .connect_timeout(Duration::from_secs(5));
+        proxy
+            .apply(builder)
+            .and_then(|builder| builder.build())
+            .unwrap_or_else(|_| Client::new())
+    }
+
+    /// Create a managed identity provider using the environment's source
+    pub fn from_env(client_id: Option<String>) -> Self {
+        Self::new(ManagedIdentitySource::from_env(), client_id)
+    }
+
+    /// Acquire or return a cached access token for the given resource.
+    pub async fn get_token(&self, resource: &str) -> Result<String, AuthError> {
+        self.token_cache
+            .get_or_acquire(resource, || self.acquire_token(resource))
+            .await
+    }
+
+    /// Clear the token cache for all resources
+    pub async fn clear_cache(&self) {
+        self.token_cache.clear().await;
+    }
+
+    /// Clear the cached token for a single resource
+    pub async fn clear_resource(&self, resource: &str) {
+        self.token_cache.remove(resource).await;
+    }
+
+    /// Request a new token from the identity endpoint
+    async fn acquire_token(&self, resource: &str) -> Result<AcquiredToken, AuthError> {
+        let mut query = vec![("resource", resource)];
+        if let Some(ref client_id) = self.client_id {
+            query.push(("client_id", client_id));
+        }
+
+        let endpoint = match self.source {
+            ManagedIdentitySource::Imds { ref endpoint } => endpoint,
+            ManagedIdentitySource::AppService { ref endpoint, .. } => endpoint,
+        };
+        let request = match self.source {
+            ManagedIdentitySource::Imds { ref endpoint } => {
+                query.push(("api-version", IMDS_API_VERSION));
+                self.http_client.get(endpoint).header("Metadata", "true")
+            }
+            ManagedIdentitySource::AppService {
+                ref endpoint,
+                ref header,
+            } => {
+                query.push(("api-version", APP_SERVICE_API_VERSION));
+                self.http_client
+                    .get(endpoint)
+                    .header("X-IDENTITY-HEADER", header)
+            }
+        };
+
+        tracing::debug!("Managed identity source: {:?}", self.source);
+
+        let response = request.query(&query).send().await.map_err(|e| {
+            let via = match self.proxy.proxy_for(endpoint) {
+                Some(proxy) => format!(" via proxy {}", proxy),
+                None => String::new(),
+            };
+            AuthError::MissingCredentials(format!(
+                "Managed identity endpoint is unreachable{} ({}). \
+                 Is this process running in Azure with a managed identity assigned?",
+                via, e
+            ))
+        })?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            tracing::error!(
+                "Managed identity token request failed: {} - {}",
+                status,
+                body
+            );
+            return Err(AuthError::TokenRequestFailed(format!(
+                "Status: {}, Body: {}",
+                status, body
+            )));
+        }
+
+        let token_response: ManagedIdentityTokenResponse = response.json().await.map_err(|e| {
+            AuthError::ParseError(format!(
+                "Failed to parse managed identity token response: {}",
+                e
+            ))
+        })?;
+
+        Ok(AcquiredToken {
+            expires_in: token_response.lifetime(SystemTime::now())?,
+            access_token: token_response.access_token,
+        })
+    }
+}
+
+#[async_trait]
+impl TokenProvider for ManagedIdentityAuth {
+    async fn get_token(&self, resource: &str) -> Result<String, AuthError> {
+        ManagedIdentityAuth::get_token(self, resource).await
+    }
+
+    async fn clear_cache(&self) {
+        ManagedIdentityAuth::clear_cache(self).await
+    }
+
+    async fn clear_resource(&self, resource: &str) {
+        ManagedIdentityAuth::clear_resource(self, resource).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::matchers::{header, method, path, query_param, query_param_is_missing};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn lifetime_prefers_expires_in() {
+        let response: ManagedIdentityTokenResponse = serde_json::from_value(json!({
+            "access_token": "t",
+            "expires_in": "3599",
+            "expires_on": "1"
+        }))
+        .unwrap();
+        assert_eq!(
+            response.lifetime(SystemTime::now()).unwrap(),
+            Duration::from_secs(3599)
+        );
+    }
+
+    #[test]
+    fn lifetime_from_expires_on() {
+        let response: ManagedIdentityTokenResponse = serde_json::from_value(json!({
+            "access_token": "t",
+            "expires_on": "1700003600"
+        }))
+        .unwrap();
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(response.lifetime(now).unwrap(), Duration::from_secs(3600));
+    }
+
+    #[tokio::test]
+    async fn imds_request_and_caching() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/metadata/identity/oauth2/token"))
+            .and(header("Metadata", "true"))
+            .and(query_param("api-version", IMDS_API_VERSION))
+            .and(query_param("resource", "https://org.crm.dynamics.com"))
+            .and(query_param_is_missing("client_id"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "imds-token",
+                "expires_in": "3599",
+                "token_type": "Bearer"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let auth = ManagedIdentityAuth::new(
+            ManagedIdentitySource::Imds {
+                endpoint: format!("{}/metadata/identity/oauth2/token", server.uri()),
+            },
+            None,
+        );
+
+        for _ in 0..2 {
+            assert_eq!(
+                auth.get_token("https://org.crm.dynamics.com")
+                    .await
+                    .unwrap(),
+                "imds-token"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn clearing_one_resource_keeps_the_others() {
+        let server = MockServer::start().await;
+        for (resource, expected) in [
+            ("https://first.crm.dynamics.com", 2),
+            ("https://second.crm.dynamics.com", 1),
+        ] {
+            Mock::given(method("GET"))
+                .and(path("/metadata/identity/oauth2/token"))
+                .and(query_param("resource", resource))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "access_token": format!("token for {resource}"),
+                    "expires_in": "3599",
+                    "token_type": "Bearer"
+                })))
+                .expect(expected)
+                .mount(&server)
+                .await;
+        }
+
+        let auth = ManagedIdentityAuth::new(
+            ManagedIdentitySource::Imds {
+                endpoint: format!("{}/metadata/identity/oauth2/token", server.uri()),
+            },
+            None,
+        );
+        let provider: &dyn TokenProvider = &auth;
+
+        for resource in [
+            "https://first.crm.dynamics.com",
+            "https://second.crm.dynamics.com",
+        ] {
+            provider.get_token(resource).await.unwrap();
+        }
+        provider
+            .clear_resource("https://first.crm.dynamics.com")
+            .await;
+        for resource in [
+            "https://first.crm.dynamics.com",
+            "https://second.crm.dynamics.com",
+        ] {
+            provider.get_token(resource).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn app_service_request_with_user_assigned_identity() {
+        let server = MockServer::start().await;
+        let expires_on = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 3600;
+        Mock::given(method("GET"))
+            .and(path("/msi/token"))
+            .and(header("X-IDENTITY-HEADER", "secret-header"))
+            .and(query_param("api-version", APP_SERVICE_API_VERSION))
+            .and(query_param("client_id", "user-assigned-id"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "app-service-token",
+                "expires_on": expires_on.to_string(),
+                "token_type": "Bearer"
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let auth = ManagedIdentityAuth::new(
+            ManagedIdentitySource::AppService {
+                endpoint: format!("{}/msi/token", server.uri()),
+                header: "secret-header".to_string(),
+            },
+            Some("user-assigned-id".to_string()),
+        );
+
+        assert_eq!(
+            auth.get_token("https://org.crm.dynamics.com")
+                .await
+                .unwrap(),
+            "app-service-token"
+        );
+    }
+}
@@ -3,13 +3,43 @@
 //! Implements OAuth2 Client Credentials flow for:
 //! - Azure AD (Entra ID) - for cloud D365
 //! - ADFS - for on-premise D365
+//!
+//! Clients authenticate with either a shared secret or a certificate-signed
+//! JWT assertion. When running inside Azure, `ManagedIdentityAuth` fetches
+//! tokens from the platform identity endpoint instead, and for local
+//! development `AzureCliAuth` reuses the developer's `az login` session.
+//! The client secret itself may live in Azure Key Vault (`KeyVaultSecret`).
+//! `OAuth2Auth` can keep its tokens in an encrypted file so they survive
+//! process restarts.
+
+mod azure_cli;
+mod certificate;
+mod cloud;
+mod key_vault;
+mod managed_identity;
+mod token_store;
+
+pub use azure_cli::AzureCliAuth;
+pub use certificate::ClientCertificate;
+pub use cloud::CloudEnvironment;
+pub use key_vault::KeyVaultSecret;
+pub use managed_identity::{ManagedIdentityAuth, ManagedIdentitySource};
+use token_store::TokenStore;
 
-use reqwest::{Client, Url};
-use serde::Deserialize;
+use crate::network::{apply_tls, ProxySettings, USER_AGENT};
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use reqwest::{Certificate, Client, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
 
 /// Authentication errors
 #[derive(Error, Debug)]
@@ -20,11 +50,159 @@ pub enum AuthError {
     #[error("HTTP error: {0}")]
     HttpError(#[from] reqwest::Error),
 
+    #[error("HTTP error via proxy {proxy}: {source}")]
+    Proxy {
+        proxy: String,
+        source: reqwest::Error,
+    },
+
     #[error("Token parse error: {0}")]
     ParseError(String),
 
     #[error("Missing credentials: {0}")]
     MissingCredentials(String),
+
+    #[error("Key Vault secret resolution failed: {0}")]
+    KeyVault(String),
+}
+
+/// Azure AD error codes that point at a specific setup mistake
+const AADSTS_HINTS: &[(&str, &str)] = &[
+    (
+        "AADSTS7000215",
+        "The client secret is wrong: use the secret's Value (not its Secret ID) from the app registration's Certificates & secrets page.",
+    ),
+    (
+        "AADSTS7000222",
+        "The client secret has expired: create a new one in the app registration and update CLIENT_SECRET.",
+    ),
+    (
+        "AADSTS700016",
+        "The application was not found in the tenant: check CLIENT_ID and that TENANT_ID is the tenant the app is registered in.",
+    ),
+    (
+        "AADSTS90002",
+        "The tenant was not found: check TENANT_ID (the directory ID GUID or a verified domain).",
+    ),
+    (
+        "AADSTS500011",
+        "The resource was not found in the tenant: check that ENDPOINT (or RESOURCE) is a D365 environment in this tenant.",
+    ),
+    (
+        "AADSTS700027",
+        "The certificate assertion was rejected: upload the certificate's public key to the app registration.",
+    ),
+    (
+        "AADSTS700024",
+        "The certificate assertion is outside its validity window: check the machine's clock.",
+    ),
+    (
+        "AADSTS7000112",
+        "The application is disabled in the tenant: re-enable its enterprise application.",
+    ),
+    (
+        "AADSTS70011",
+        "The requested scope is invalid: check ENDPOINT (or RESOURCE) for typos.",
+    ),
+];
+
+/// Azure AD errors meaning the client secret is wrong or expired
+const SECRET_REJECTED_CODES: &[&str] = &["AADSTS7000215", "AADSTS7000222"];
+
+/// Token request retries after a transient failure, unless configured
+pub const DEFAULT_AUTH_MAX_RETRIES: u32 = 3;
+
+/// First token retry delay; it doubles per attempt, plus jitter
+const AUTH_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+/// Longest wait between two token requests
+const AUTH_RETRY_MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// The AADSTS code in a token error body, e.g. `AADSTS7000215`
+fn aadsts_code(body: &str) -> Option<&str> {
+    let start = body.find("AADSTS")?;
+    let digits = body[start + 6..]
+        .bytes()
+        .take_while(u8::is_ascii_digit)
+        .count();
+    (digits > 0).then(|| &body[start..start + 6 + digits])
+}
+
+/// Text of a failed token request, led by the AADSTS code and, for the
+/// common ones, what it means
+fn token_error_message(status: reqwest::StatusCode, body: &str) -> String {
+    let detail = format!("Status: {}, Body: {}", status, body);
+    let Some(code) = aadsts_code(body) else {
+        return detail;
+    };
+    match AADSTS_HINTS.iter().find(|(known, _)| *known == code) {
+        Some((_, hint)) => format!("{}: {} ({})", code, hint, detail),
+        None => format!("{}: {}", code, detail),
+    }
+}
+
+impl AuthError {
+    /// Likely fix for a token acquisition failure, if it is a known one
+    pub fn remediation_hint(&self) -> Option<&'static str> {
+        match self {
+            AuthError::TokenRequestFailed(body) => aadsts_code(body).and_then(|code| {
+                AADSTS_HINTS
+                    .iter()
+                    .find(|(known, _)| *known == code)
+                    .map(|(_, hint)| *hint)
+            }),
+            AuthError::HttpError(e) => network_hint(e),
+            AuthError::Proxy { source, .. } => network_hint(source),
+            AuthError::MissingCredentials(_) => {
+                Some("Set the missing setting in the environment or config/default.toml.")
+            }
+            AuthError::KeyVault(_) => Some(
+                "Check CLIENT_SECRET_KEYVAULT_URI and that the identity reading it has the 'Get' permission on secrets.",
+            ),
+            AuthError::ParseError(_) => None,
+        }
+    }
+}
+
+/// Likely fix for a request that never got an HTTP response
+pub(crate) fn network_hint(error: &reqwest::Error) -> Option<&'static str> {
+    // reqwest's own message is generic; the cause is in the source chain
+    let mut causes = String::new();
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        causes.push_str(&cause.to_string().to_lowercase());
+        causes.push('\n');
+        source = cause.source();
+    }
+
+    if causes.contains("dns error") || causes.contains("failed to lookup address") {
+        Some("The host name could not be resolved: check ENDPOINT (or the authority host) for typos.")
+    } else if causes.contains("certificate") {
+        Some("The TLS certificate was not accepted: set CA_CERT_PATH to the issuing CA (on-premise or a TLS-inspecting proxy), or INSECURE_SSL=true for test environments only.")
+    } else if error.is_timeout() {
+        Some("The server did not answer in time: check network access and proxy settings.")
+    } else if error.is_connect() {
+        Some("Could not connect: check ENDPOINT, firewalls and proxy settings.")
+    } else {
+        None
+    }
+}
+
+/// Decode the claims of a JWT access token without verifying its signature
+///
+/// Only meant for showing who a token was issued to; the server that
+/// receives the token is the one that validates it.
+pub fn decode_jwt_claims(token: &str) -> Result<serde_json::Value, AuthError> {
+    let payload = token
+        .split('.')
+        .nth(1)
+        .filter(|part| !part.is_empty())
+        .ok_or_else(|| AuthError::ParseError("Token is not a JWT".to_string()))?;
+    let bytes = URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .map_err(|e| AuthError::ParseError(format!("Invalid JWT payload encoding: {}", e)))?;
+    serde_json::from_slice(&bytes)
+        .map_err(|e| AuthError::ParseError(format!("Invalid JWT claims: {}", e)))
 }
 
 /// Token response from OAuth2 server
@@ -40,34 +218,238 @@ struct TokenResponse {
 }
 
 /// Cached token with expiry tracking
-#[derive(Debug, Clone)]
+///
+/// The expiry is wall-clock time so the token can be persisted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct CachedToken {
     access_token: String,
-    expires_at: Instant,
+    expires_at: SystemTime,
 }
 
 impl CachedToken {
     fn is_valid(&self) -> bool {
         // Consider token expired 60 seconds before actual expiry
-        self.expires_at > Instant::now() + Duration::from_secs(60)
+        self.expires_at > SystemTime::now() + Duration::from_secs(60)
+    }
+}
+
+/// Cached tokens by resource, as written to a [`TokenStore`]
+type TokenSnapshot = HashMap<String, CachedToken>;
+
+/// Freshly acquired token, before it is cached
+#[derive(Debug)]
+struct AcquiredToken {
+    access_token: String,
+    expires_in: Duration,
+}
+
+/// A token request that failed, and whether trying again may succeed
+#[derive(Debug)]
+struct FailedAttempt {
+    error: AuthError,
+    transient: bool,
+}
+
+impl From<AuthError> for FailedAttempt {
+    fn from(error: AuthError) -> Self {
+        Self {
+            error,
+            transient: false,
+        }
+    }
+}
+
+/// Source of bearer tokens for OData requests
+///
+/// Implement this to plug an existing token broker into `ODataClient`.
+#[async_trait]
+pub trait TokenProvider: Send + Sync {
+    /// Acquire or return a cached access token for the given resource
+    async fn get_token(&self, resource: &str) -> Result<String, AuthError>;
+
+    /// Drop cached tokens so the next call acquires a fresh one
+    async fn clear_cache(&self) {}
+
+    /// Drop the cached token for `resource` only, e.g. after the service
+    /// rejected it; providers without per-resource caching clear everything
+    async fn clear_resource(&self, _resource: &str) {
+        self.clear_cache().await
+    }
+}
+
+/// Token provider that always returns a pre-acquired token
+///
+/// Useful for tests and short-lived scripts; the token is never refreshed.
+#[derive(Clone)]
+pub struct StaticTokenProvider {
+    token: String,
+}
+
+impl StaticTokenProvider {
+    /// Wrap a pre-acquired access token
+    pub fn new(token: impl Into<String>) -> Self {
+        Self {
+            token: token.into(),
+        }
+    }
+}
+
+impl std::fmt::Debug for StaticTokenProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StaticTokenProvider(<redacted>)")
+    }
+}
+
+#[async_trait]
+impl TokenProvider for StaticTokenProvider {
+    async fn get_token(&self, _resource: &str) -> Result<String, AuthError> {
+        Ok(self.token.clone())
+    }
+}
+
+/// Per-resource token cache shared by all providers
+///
+/// Concurrent callers that miss the cache for the same resource share a
+/// single token request: the first one acquires, the rest wait for it and
+/// then read the freshly cached token.
+#[derive(Debug, Default)]
+struct TokenCache {
+    tokens: RwLock<HashMap<String, CachedToken>>,
+    /// Per-resource locks ensuring only one acquisition is in flight at a time
+    acquisitions: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    /// Encrypted file the tokens are mirrored to, if configured
+    store: Option<Arc<TokenStore>>,
+    /// Changes to `tokens`, counted under its write lock so the store can
+    /// tell which snapshot is the latest
+    version: AtomicU64,
+}
+
+impl TokenCache {
+    /// Cache mirrored to `store`, starting with the tokens saved there
+    fn persistent(store: TokenStore) -> Self {
+        Self {
+            tokens: RwLock::new(store.load()),
+            acquisitions: Default::default(),
+            store: Some(Arc::new(store)),
+            version: AtomicU64::new(0),
+        }
+    }
+
+    /// Return a cached token or run `acquire` (at most once concurrently)
+    async fn get_or_acquire<F, Fut>(&self, resource: &str, acquire: F) -> Result<String, AuthError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<AcquiredToken, AuthError>>,
+    {
+        // Check cache first
+        if let Some(token) = self.cached(resource).await {
+            tracing::debug!("Using cached token");
+            return Ok(token);
+        }
+
+        let lock = self.acquisition_lock(resource);
+        let _guard = lock.lock().await;
+
+        // Another caller may have acquired the token while we waited
+        if let Some(token) = self.cached(resource).await {
+            tracing::debug!("Using token acquired by concurrent request");
+            return Ok(token);
+        }
+
+        // Token expired or not cached, acquire new one
+        tracing::info!("Acquiring new access token");
+        let acquired = acquire().await?;
+
+        tracing::info!(
+            "Token acquired successfully, expires in {} seconds",
+            acquired.expires_in.as_secs()
+        );
+
+        let mut tokens = self.tokens.write().await;
+        tokens.insert(
+            resource.to_string(),
+            CachedToken {
+                access_token: acquired.access_token.clone(),
+                expires_at: SystemTime::now() + acquired.expires_in,
+            },
+        );
+        let snapshot = self.snapshot(&tokens);
+        drop(tokens);
+        self.persist(snapshot).await;
+
+        Ok(acquired.access_token)
+    }
+
+    /// Valid cached token for a resource, if any
+    async fn cached(&self, resource: &str) -> Option<String> {
+        let tokens = self.tokens.read().await;
+        tokens
+            .get(resource)
+            .filter(|cached| cached.is_valid())
+            .map(|cached| cached.access_token.clone())
+    }
+
+    /// Lock serializing token acquisition for one resource
+    fn acquisition_lock(&self, resource: &str) -> Arc<Mutex<()>> {
+        let mut locks = self.acquisitions.lock().unwrap();
+        locks.entry(resource.to_string()).or_default().clone()
+    }
+
+    /// Clear cached tokens for all resources
+    async fn clear(&self) {
+        let mut tokens = self.tokens.write().await;
+        tokens.clear();
+        let snapshot = self.snapshot(&tokens);
+        drop(tokens);
+        self.persist(snapshot).await;
+    }
+
+    /// Clear the cached token for one key
+    async fn remove(&self, key: &str) {
+        let mut tokens = self.tokens.write().await;
+        tokens.remove(key);
+        let snapshot = self.snapshot(&tokens);
+        drop(tokens);
+        self.persist(snapshot).await;
+    }
+
+    /// Numbered copy of the tokens for the store, if there is one; taken
+    /// with the write lock held so the numbers follow the changes
+    fn snapshot(&self, tokens: &HashMap<String, CachedToken>) -> Option<(u64, TokenSnapshot)> {
+        self.store.as_ref()?;
+        let version = self.version.fetch_add(1, Ordering::SeqCst) + 1;
+        Some((version, tokens.clone()))
+    }
+
+    /// Write a snapshot to the store on a blocking thread, after the lock is
+    /// released; the store skips snapshots older than the one it last wrote
+    async fn persist(&self, snapshot: Option<(u64, TokenSnapshot)>) {
+        let (Some(store), Some((version, tokens))) = (self.store.clone(), snapshot) else {
+            return;
+        };
+        let saved = tokio::task::spawn_blocking(move || store.save(version, &tokens)).await;
+        if let Err(e) = saved {
+            tracing::warn!("Token cache write did not finish: {}", e);
+        }
+    }
+
+    /// Number of cached tokens (including expired ones)
+    #[cfg(test)]
+    async fn len(&self) -> usize {
+        self.tokens.read().await.len()
     }
 }
 
 /// Authentication type
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub enum AuthType {
     /// Azure AD (Entra ID) - for cloud D365
+    #[default]
     AzureAd,
     /// ADFS - for on-premise D365
     Adfs,
 }
 
-impl Default for AuthType {
-    fn default() -> Self {
-        AuthType::AzureAd
-    }
-}
-
 impl std::str::FromStr for AuthType {
     type Err = String;
 
@@ -80,17 +462,40 @@ impl std::str::FromStr for AuthType {
     }
 }
 
+/// How the client proves its identity to the token endpoint
+#[derive(Clone)]
+pub enum Credential {
+    /// Shared client secret
+    Secret(String),
+    /// Certificate used to sign a `client_assertion` JWT
+    Certificate(Box<ClientCertificate>),
+    /// Client secret read from Azure Key Vault
+    KeyVault(Arc<KeyVaultSecret>),
+}
+
+impl std::fmt::Debug for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credential::Secret(_) => f.write_str("Secret(<redacted>)"),
+            Credential::Certificate(cert) => f.debug_tuple("Certificate").field(cert).finish(),
+            Credential::KeyVault(secret) => f.debug_tuple("KeyVault").field(secret).finish(),
+        }
+    }
+}
+
 /// Authentication configuration
 #[derive(Debug, Clone)]
 pub struct AuthConfig {
     pub auth_type: AuthType,
     pub tenant_id: String,
     pub client_id: String,
-    pub client_secret: String,
+    pub credential: Credential,
     /// Custom token URL (required for ADFS)
     pub token_url: Option<String>,
     /// Resource/audience (required for ADFS)
     pub resource: Option<String>,
+    /// Entra ID authority host (defaults to the public cloud)
+    pub authority_host: Option<String>,
     /// Skip SSL certificate verification (for self-signed certs)
     pub insecure_ssl: bool,
 }
@@ -100,28 +505,113 @@ pub struct AuthConfig {
 pub struct OAuth2Auth {
     config: AuthConfig,
     http_client: Client,
-    token_cache: Arc<RwLock<Option<CachedToken>>>,
+    /// Extra trusted roots, e.g. an on-premise ADFS CA
+    root_certificates: Vec<Certificate>,
+    /// Outbound proxy, named in connection errors
+    proxy: ProxySettings,
+    /// Cached tokens keyed by resource, so each audience gets its own token
+    token_cache: TokenCache,
+    /// Retries of a token request after a 429, 5xx or connection failure
+    max_retries: u32,
+    /// First retry delay, doubled per attempt
+    retry_base_delay: Duration,
 }
 
 impl OAuth2Auth {
     /// Create a new OAuth2 auth helper
     pub fn new(config: AuthConfig) -> Self {
-        let http_client = if config.insecure_ssl {
-            Client::builder()
-                .danger_accept_invalid_certs(true)
-                .build()
-                .unwrap_or_else(|_| Client::new())
-        } else {
-            Client::new()
-        };
-
+        let proxy = ProxySettings::default();
         Self {
+            http_client: Self::build_http_client(config.insecure_ssl, &[], &proxy),
             config,
-            http_client,
-            token_cache: Arc::new(RwLock::new(None)),
+            root_certificates: Vec::new(),
+            proxy,
+            token_cache: TokenCache::default(),
+            max_retries: DEFAULT_AUTH_MAX_RETRIES,
+            retry_base_delay: AUTH_RETRY_BASE_DELAY,
         }
     }
 
+    /// Retry token requests up to `max_retries` times after a 429, 5xx or
+    /// connection failure; other errors, such as a rejected secret, fail at once
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Send token requests through an explicit proxy
+    pub fn with_proxy(mut self, proxy: ProxySettings) -> Self {
+        self.proxy = proxy;
+        self.rebuild_http_client();
+        self
+    }
+
+    /// Trust these root certificates as well; verification stays on even
+    /// with `insecure_ssl`
+    pub fn with_root_certificates(mut self, certificates: Vec<Certificate>) -> Self {
+        self.root_certificates = certificates;
+        self.rebuild_http_client();
+        self
+    }
+
+    /// Keep tokens in an encrypted file at `path`, so a restarted process
+    /// reuses them instead of requesting new ones.
+    ///
+    /// The key is derived from the token endpoint, the client id and the
+    /// client secret, or the private key of a certificate credential. Key
+    /// Vault credentials have neither when the process starts, so theirs is a
+    /// random key kept in the native secret store; without one, tokens stay
+    /// in memory. A file that does not decrypt is ignored.
+    pub fn with_token_cache_path(mut self, path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let secret = match self.config.credential {
+            Credential::Secret(ref secret) => secret.clone().into_bytes(),
+            Credential::Certificate(ref certificate) => certificate.key_material(),
+            Credential::KeyVault(_) => match TokenStore::keychain_key(&path) {
+                Ok(key) => key,
+                Err(e) => {
+                    tracing::warn!(
+                        "Not caching tokens in {}: no key for Key Vault credentials in the native secret store: {}",
+                        path.display(),
+                        e
+                    );
+                    return self;
+                }
+            },
+        };
+        let store = TokenStore::new(
+            path,
+            &[&self.token_endpoint(), &self.config.client_id],
+            &secret,
+        );
+        self.token_cache = TokenCache::persistent(store);
+        self
+    }
+
+    fn rebuild_http_client(&mut self) {
+        self.http_client = Self::build_http_client(
+            self.config.insecure_ssl,
+            &self.root_certificates,
+            &self.proxy,
+        );
+    }
+
+    fn build_http_client(
+        insecure_ssl: bool,
+        root_certificates: &[Certificate],
+        proxy: &ProxySettings,
+    ) -> Client {
+        let builder = apply_tls(
+            Client::builder().user_agent(USER_AGENT),
+            root_certificates,
+            insecure_ssl,
+        );
+        proxy
+            .apply(builder)
+            .and_then(|builder| builder.build())
+            .unwrap_or_else(|_| Client::new())
+    }
+
     /// Get the token endpoint URL
     fn token_endpoint(&self) -> String {
         match self.config.auth_type {
@@ -132,9 +622,15 @@ impl OAuth2Auth {
                 })
             }
             AuthType::AzureAd => {
-                // Azure AD standard endpoint
+                // Azure AD standard endpoint on the configured cloud
+                let authority = self
+                    .config
+                    .authority_host
+                    .as_deref()
+                    .unwrap_or(CloudEnvironment::Public.authority_host());
                 format!(
-                    "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
+                    "{}/{}/oauth2/v2.0/token",
+                    authority.trim_end_matches('/'),
                     self.config.tenant_id
                 )
             }
@@ -142,85 +638,156 @@ impl OAuth2Auth {
     }
 
     /// Acquire or return a cached access token for the given resource.
+    ///
+    /// Tokens are cached by the scope (Azure AD) or resource (ADFS) actually
+    /// requested, so equivalent resource spellings share one token.
     pub async fn get_token(&self, resource: &str) -> Result<String, AuthError> {
-        // Check cache first
-        {
-            let cache = self.token_cache.read().await;
-            if let Some(ref cached) = *cache {
-                if cached.is_valid() {
-                    tracing::debug!("Using cached token");
-                    return Ok(cached.access_token.clone());
-                }
-            }
-        }
-
-        // Token expired or not cached, acquire new one
-        tracing::info!("Acquiring new access token");
-        let token = self.acquire_token(resource).await?;
-
-        Ok(token)
+        let scope = self.scope_for(resource);
+        self.token_cache
+            .get_or_acquire(&scope, || self.acquire_token(&scope))
+            .await
     }
 
-    /// Acquire a new token
-    async fn acquire_token(&self, resource: &str) -> Result<String, AuthError> {
-        let params = match self.config.auth_type {
+    /// Scope (Azure AD) or resource (ADFS) sent to the token endpoint
+    fn scope_for(&self, resource: &str) -> String {
+        match self.config.auth_type {
             AuthType::AzureAd => {
                 // Azure AD uses scope with /.default suffix
-                let scope = if resource.ends_with('/') {
+                if resource.ends_with('/') {
                     format!("{}.default", resource)
                 } else {
                     format!("{}/.default", resource)
-                };
-
-                vec![
-                    ("grant_type".to_string(), "client_credentials".to_string()),
-                    ("client_id".to_string(), self.config.client_id.clone()),
-                    (
-                        "client_secret".to_string(),
-                        self.config.client_secret.clone(),
-                    ),
-                    ("scope".to_string(), scope),
-                ]
+                }
             }
             AuthType::Adfs => {
                 // ADFS uses resource parameter instead of scope
-                let resource = self
-                    .config
+                self.config
                     .resource
-                    .as_ref()
-                    .map(|r| r.clone())
-                    .unwrap_or_else(|| resource.to_string());
+                    .clone()
+                    .unwrap_or_else(|| resource.to_string())
+            }
+        }
+    }
 
-                vec![
-                    ("grant_type".to_string(), "client_credentials".to_string()),
-                    ("client_id".to_string(), self.config.client_id.clone()),
+    /// Form parameters proving the client's identity
+    async fn credential_params(&self) -> Result<Vec<(String, String)>, AuthError> {
+        match self.config.credential {
+            Credential::Secret(ref secret) => {
+                Ok(vec![("client_secret".to_string(), secret.clone())])
+            }
+            Credential::KeyVault(ref secret) => {
+                Ok(vec![("client_secret".to_string(), secret.get().await?)])
+            }
+            Credential::Certificate(ref cert) => {
+                let assertion = cert.client_assertion(
+                    &self.config.client_id,
+                    &self.token_endpoint(),
+                    SystemTime::now(),
+                )?;
+                Ok(vec![
                     (
-                        "client_secret".to_string(),
-                        self.config.client_secret.clone(),
+                        "client_assertion_type".to_string(),
+                        certificate::CLIENT_ASSERTION_TYPE.to_string(),
                     ),
-                    ("resource".to_string(), resource),
-                ]
+                    ("client_assertion".to_string(), assertion),
+                ])
+            }
+        }
+    }
+
+    /// Acquire a new token
+    ///
+    /// `scope` is the value returned by `scope_for`. A Key Vault secret that
+    /// Azure AD rejects is read again once, in case it was rotated.
+    async fn acquire_token(&self, scope: &str) -> Result<AcquiredToken, AuthError> {
+        let result = self.request_token_with_retry(scope).await;
+        if let (Credential::KeyVault(secret), Err(AuthError::TokenRequestFailed(body))) =
+            (&self.config.credential, &result)
+        {
+            if SECRET_REJECTED_CODES.iter().any(|code| body.contains(code)) {
+                tracing::warn!("Client secret rejected, reading it from Key Vault again");
+                secret.invalidate().await;
+                return self.request_token_with_retry(scope).await;
+            }
+        }
+        result
+    }
+
+    /// Token request retried with exponential backoff and jitter while it
+    /// fails transiently
+    async fn request_token_with_retry(&self, scope: &str) -> Result<AcquiredToken, AuthError> {
+        let mut attempt = 0;
+        loop {
+            match self.request_token(scope).await {
+                Ok(token) => return Ok(token),
+                Err(failed) if failed.transient && attempt < self.max_retries => {
+                    let delay = self.retry_delay(attempt);
+                    tracing::warn!(
+                        "Token request failed ({}), retrying in {} ms",
+                        failed.error,
+                        delay.as_millis()
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(failed) => return Err(failed.error),
             }
+        }
+    }
+
+    /// Wait before retry number `attempt` (from 0): doubling, plus up to 50% jitter
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .retry_base_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(AUTH_RETRY_MAX_DELAY);
+        delay + delay.mul_f64(rand::random_range(0.0..=0.5))
+    }
+
+    /// One token request
+    async fn request_token(&self, scope: &str) -> Result<AcquiredToken, FailedAttempt> {
+        let scope_param = match self.config.auth_type {
+            AuthType::AzureAd => "scope",
+            AuthType::Adfs => "resource",
         };
+        let mut params = vec![
+            ("grant_type".to_string(), "client_credentials".to_string()),
+            ("client_id".to_string(), self.config.client_id.clone()),
+            (scope_param.to_string(), scope.to_string()),
+        ];
+        params.extend(self.credential_params().await?);
 
         tracing::debug!("Token endpoint: {}", self.token_endpoint());
         tracing::debug!("Auth type: {:?}", self.config.auth_type);
 
+        let token_endpoint = self.token_endpoint();
         let response = self
             .http_client
-            .post(&self.token_endpoint())
+            .post(&token_endpoint)
             .form(&params)
             .send()
-            .await?;
+            .await
+            .map_err(|e| FailedAttempt {
+                // DNS failures and refused or timed-out connections are
+                // usually gone a moment later; a request that could not be
+                // built will not be fixed by sending it again
+                transient: e.is_connect() || e.is_timeout(),
+                error: match self.proxy.proxy_for(&token_endpoint) {
+                    Some(proxy) => AuthError::Proxy { proxy, source: e },
+                    None => AuthError::HttpError(e),
+                },
+            })?;
 
         if !response.status().is_success() {
             let status = response.status();
             let body = response.text().await.unwrap_or_default();
             tracing::error!("Token request failed: {} - {}", status, body);
-            return Err(AuthError::TokenRequestFailed(format!(
-                "Status: {}, Body: {}",
-                status, body
-            )));
+            // 4xx answers such as invalid_client won't change on a retry
+            return Err(FailedAttempt {
+                error: AuthError::TokenRequestFailed(token_error_message(status, &body)),
+                transient: status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                    || status.is_server_error(),
+            });
         }
 
         let token_response: TokenResponse = response
@@ -228,29 +795,24 @@ impl OAuth2Auth {
             .await
             .map_err(|e| AuthError::ParseError(format!("Failed to parse token response: {}", e)))?;
 
-        // Cache the token
-        let cached = CachedToken {
-            access_token: token_response.access_token.clone(),
-            expires_at: Instant::now() + Duration::from_secs(token_response.expires_in),
-        };
+        Ok(AcquiredToken {
+            access_token: token_response.access_token,
+            expires_in: Duration::from_secs(token_response.expires_in),
+        })
+    }
 
-        {
-            let mut cache = self.token_cache.write().await;
-            *cache = Some(cached);
+    /// Clear the token cache for all resources, and forget a Key Vault
+    /// secret so a rotated one is picked up
+    pub async fn clear_cache(&self) {
+        self.token_cache.clear().await;
+        if let Credential::KeyVault(ref secret) = self.config.credential {
+            secret.invalidate().await;
         }
-
-        tracing::info!(
-            "Token acquired successfully, expires in {} seconds",
-            token_response.expires_in
-        );
-
-        Ok(token_response.access_token)
     }
 
-    /// Clear the token cache
-    pub async fn clear_cache(&self) {
-        let mut cache = self.token_cache.write().await;
-        *cache = None;
+    /// Clear the cached token for a single resource
+    pub async fn clear_resource(&self, resource: &str) {
+        self.token_cache.remove(&self.scope_for(resource)).await;
     }
 
     /// Get resource URL from endpoint
@@ -263,6 +825,21 @@ impl OAuth2Auth {
     }
 }
 
+#[async_trait]
+impl TokenProvider for OAuth2Auth {
+    async fn get_token(&self, resource: &str) -> Result<String, AuthError> {
+        OAuth2Auth::get_token(self, resource).await
+    }
+
+    async fn clear_cache(&self) {
+        OAuth2Auth::clear_cache(self).await
+    }
+
+    async fn clear_resource(&self, resource: &str) {
+        OAuth2Auth::clear_resource(self, resource).await
+    }
+}
+
 // Keep AzureAdAuth for backward compatibility
 pub type AzureAdAuth = OAuth2Auth;
 
@@ -273,9 +850,10 @@ impl OAuth2Auth {
             auth_type: AuthType::AzureAd,
             tenant_id,
             client_id,
-            client_secret,
+            credential: Credential::Secret(client_secret),
             token_url: None,
             resource: None,
+            authority_host: None,
             insecure_ssl: false,
         })
     }
@@ -285,15 +863,45 @@ impl OAuth2Auth {
 mod tests {
     use super::*;
 
+    #[test]
+    fn jwt_claims_are_decoded_without_verification() {
+        let claims =
+            serde_json::json!({"appid": "app-1", "roles": ["Data.Read"], "exp": 1700000000});
+        let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let token = format!("eyJhbGciOiJub25lIn0.{}.c2ln", payload);
+        assert_eq!(decode_jwt_claims(&token).unwrap(), claims);
+
+        // Padded payloads are tolerated, opaque tokens are not
+        let padded = format!("h.{}==.s", payload);
+        assert_eq!(decode_jwt_claims(&padded).unwrap()["appid"], "app-1");
+        assert!(decode_jwt_claims("opaque-token").is_err());
+        assert!(decode_jwt_claims("h.not*base64.s").is_err());
+    }
+
+    #[test]
+    fn known_token_errors_have_hints() {
+        let bad_secret = AuthError::TokenRequestFailed(
+            "Status: 401, Body: {\"error\":\"invalid_client\",\"error_description\":\"AADSTS7000215: Invalid client secret provided.\"}".to_string(),
+        );
+        assert!(bad_secret
+            .remediation_hint()
+            .unwrap()
+            .contains("client secret is wrong"));
+
+        let unknown = AuthError::TokenRequestFailed("Status: 500, Body: oops".to_string());
+        assert!(unknown.remediation_hint().is_none());
+    }
+
     #[test]
     fn test_create_azure_auth() {
         let auth = AzureAdAuth::new(AuthConfig {
             auth_type: AuthType::AzureAd,
             tenant_id: "tenant-id".to_string(),
             client_id: "client-id".to_string(),
-            client_secret: "secret".to_string(),
+            credential: Credential::Secret("secret".to_string()),
             token_url: None,
             resource: None,
+            authority_host: None,
             insecure_ssl: false,
         });
         assert_eq!(auth.config.tenant_id, "tenant-id");
@@ -307,9 +915,10 @@ mod tests {
             auth_type: AuthType::Adfs,
             tenant_id: "adfs".to_string(),
             client_id: "client-id".to_string(),
-            client_secret: "secret".to_string(),
+            credential: Credential::Secret("secret".to_string()),
             token_url: Some("https://fs.example.com/adfs/oauth2/token".to_string()),
             resource: Some("https://d365.example.com".to_string()),
+            authority_host: None,
             insecure_ssl: false,
         });
         assert_eq!(auth.config.auth_type, AuthType::Adfs);
@@ -325,9 +934,10 @@ mod tests {
             auth_type: AuthType::AzureAd,
             tenant_id: "my-tenant".to_string(),
             client_id: "client-id".to_string(),
-            client_secret: "secret".to_string(),
+            credential: Credential::Secret("secret".to_string()),
             token_url: None,
             resource: None,
+            authority_host: None,
             insecure_ssl: false,
         });
         assert_eq!(
@@ -336,6 +946,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sovereign_cloud_token_endpoint() {
+        let auth = AzureAdAuth::new(AuthConfig {
+            auth_type: AuthType::AzureAd,
+            tenant_id: "gov-tenant".to_string(),
+            client_id: "client-id".to_string(),
+            credential: Credential::Secret("secret".to_string()),
+            token_url: None,
+            resource: None,
+            authority_host: Some(CloudEnvironment::UsGov.authority_host().to_string()),
+            insecure_ssl: false,
+        });
+        assert_eq!(
+            auth.token_endpoint(),
+            "https://login.microsoftonline.us/gov-tenant/oauth2/v2.0/token"
+        );
+    }
+
     #[test]
     fn test_auth_type_from_str() {
         assert_eq!("azure".parse::<AuthType>().unwrap(), AuthType::AzureAd);
@@ -355,18 +983,325 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_static_token_provider() {
+        let provider: Arc<dyn TokenProvider> = Arc::new(StaticTokenProvider::new("fixed"));
+        assert_eq!(
+            provider.get_token("https://a.example.com").await.unwrap(),
+            "fixed"
+        );
+        provider.clear_cache().await;
+        assert_eq!(
+            provider.get_token("https://b.example.com").await.unwrap(),
+            "fixed"
+        );
+    }
+
     #[test]
     fn test_cached_token_validity() {
         let valid_token = CachedToken {
             access_token: "test".to_string(),
-            expires_at: Instant::now() + Duration::from_secs(3600),
+            expires_at: SystemTime::now() + Duration::from_secs(3600),
         };
         assert!(valid_token.is_valid());
 
         let expired_token = CachedToken {
             access_token: "test".to_string(),
-            expires_at: Instant::now() - Duration::from_secs(60),
+            expires_at: SystemTime::now() - Duration::from_secs(60),
         };
         assert!(!expired_token.is_valid());
     }
+
+    mod mock {
+        use super::*;
+        use serde_json::json;
+        use wiremock::matchers::{body_string_contains, header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        fn adfs_auth(server: &MockServer) -> OAuth2Auth {
+            OAuth2Auth::new(AuthConfig {
+                auth_type: AuthType::Adfs,
+                tenant_id: "adfs".to_string(),
+                client_id: "client-id".to_string(),
+                credential: Credential::Secret("secret".to_string()),
+                token_url: Some(format!("{}/token", server.uri())),
+                resource: None,
+                authority_host: None,
+                insecure_ssl: false,
+            })
+        }
+
+        fn token_response(token: &str) -> ResponseTemplate {
+            ResponseTemplate::new(200)
+                .set_body_json(json!({
+                    "access_token": token,
+                    "token_type": "Bearer",
+                    "expires_in": 3600
+                }))
+                .set_delay(Duration::from_millis(100))
+        }
+
+        #[tokio::test]
+        async fn concurrent_callers_share_one_token_request() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/token"))
+                .and(header("user-agent", USER_AGENT))
+                .respond_with(token_response("shared-token"))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let auth = Arc::new(adfs_auth(&server));
+            let callers = (0..8).map(|_| {
+                let auth = auth.clone();
+                tokio::spawn(async move { auth.get_token("https://org.example.com").await })
+            });
+
+            for result in futures::future::join_all(callers).await {
+                assert_eq!(result.unwrap().unwrap(), "shared-token");
+            }
+        }
+
+        fn azure_auth(server: &MockServer) -> OAuth2Auth {
+            OAuth2Auth::new(AuthConfig {
+                auth_type: AuthType::AzureAd,
+                tenant_id: "tenant".to_string(),
+                client_id: "client-id".to_string(),
+                credential: Credential::Secret("secret".to_string()),
+                token_url: None,
+                resource: None,
+                authority_host: Some(server.uri()),
+                insecure_ssl: false,
+            })
+        }
+
+        #[tokio::test]
+        async fn transient_token_failures_are_retried() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/tenant/oauth2/v2.0/token"))
+                .respond_with(ResponseTemplate::new(503))
+                .up_to_n_times(2)
+                .expect(2)
+                .mount(&server)
+                .await;
+            Mock::given(method("POST"))
+                .and(path("/tenant/oauth2/v2.0/token"))
+                .respond_with(token_response("after-retry"))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let mut auth = azure_auth(&server);
+            auth.retry_base_delay = Duration::from_millis(1);
+            assert_eq!(
+                auth.get_token("https://org.example.com").await.unwrap(),
+                "after-retry"
+            );
+        }
+
+        #[tokio::test]
+        async fn rejected_secrets_fail_at_once_with_the_aadsts_code() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/tenant/oauth2/v2.0/token"))
+                .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+                    "error": "invalid_client",
+                    "error_description": "AADSTS7000215: Invalid client secret provided.",
+                    "error_codes": [7000215]
+                })))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let mut auth = azure_auth(&server);
+            auth.retry_base_delay = Duration::from_millis(1);
+            let error = auth
+                .get_token("https://org.example.com")
+                .await
+                .unwrap_err()
+                .to_string();
+            assert!(
+                error
+                    .starts_with("Token request failed: AADSTS7000215: The client secret is wrong"),
+                "{error}"
+            );
+            assert!(error.contains("invalid_client"), "{error}");
+        }
+
+        #[tokio::test]
+        async fn persisted_tokens_survive_a_restart() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/tenant/oauth2/v2.0/token"))
+                .respond_with(token_response("persisted-token"))
+                .expect(1)
+                .mount(&server)
+                .await;
+            let cache_path = std::env::temp_dir().join(format!(
+                "d365-token-cache-{}-{}",
+                std::process::id(),
+                rand::random::<u32>()
+            ));
+
+            let first = azure_auth(&server).with_token_cache_path(&cache_path);
+            assert_eq!(
+                first.get_token("https://org.example.com").await.unwrap(),
+                "persisted-token"
+            );
+
+            // A new process with the same identity skips the token request
+            let restarted = azure_auth(&server).with_token_cache_path(&cache_path);
+            assert_eq!(
+                restarted
+                    .get_token("https://org.example.com")
+                    .await
+                    .unwrap(),
+                "persisted-token"
+            );
+            let _ = std::fs::remove_file(&cache_path);
+        }
+
+        #[tokio::test]
+        async fn tokens_are_cached_per_scope() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/tenant/oauth2/v2.0/token"))
+                .and(body_string_contains("first.example.com%2F.default"))
+                .respond_with(token_response("first-token"))
+                .expect(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("POST"))
+                .and(path("/tenant/oauth2/v2.0/token"))
+                .and(body_string_contains("second.example.com%2F.default"))
+                .respond_with(token_response("second-token"))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let auth = azure_auth(&server);
+            for _ in 0..2 {
+                assert_eq!(
+                    auth.get_token("https://first.example.com").await.unwrap(),
+                    "first-token"
+                );
+                assert_eq!(
+                    auth.get_token("https://second.example.com").await.unwrap(),
+                    "second-token"
+                );
+            }
+            // A trailing slash maps to the same scope
+            assert_eq!(
+                auth.get_token("https://first.example.com/").await.unwrap(),
+                "first-token"
+            );
+
+            assert_eq!(auth.token_cache.len().await, 2);
+        }
+
+        #[tokio::test]
+        async fn clear_resource_only_drops_that_scope() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/tenant/oauth2/v2.0/token"))
+                .and(body_string_contains("first.example.com"))
+                .respond_with(token_response("first-token"))
+                .expect(2)
+                .mount(&server)
+                .await;
+            Mock::given(method("POST"))
+                .and(path("/tenant/oauth2/v2.0/token"))
+                .and(body_string_contains("second.example.com"))
+                .respond_with(token_response("second-token"))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let auth = azure_auth(&server);
+            auth.get_token("https://first.example.com").await.unwrap();
+            auth.get_token("https://second.example.com").await.unwrap();
+
+            auth.clear_resource("https://first.example.com").await;
+            assert_eq!(auth.token_cache.len().await, 1);
+
+            auth.get_token("https://first.example.com").await.unwrap();
+            auth.get_token("https://second.example.com").await.unwrap();
+        }
+
+        #[tokio::test]
+        async fn certificate_credential_sends_client_assertion() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/token"))
+                .and(body_string_contains("client_assertion_type=urn%3Aietf%3Aparams%3Aoauth%3Aclient-assertion-type%3Ajwt-bearer"))
+                .and(body_string_contains("client_assertion=ey"))
+                .respond_with(token_response("cert-token"))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let certificate =
+                ClientCertificate::from_pem(include_str!("testdata/client-cert.pem"), None)
+                    .unwrap();
+            let mut auth = adfs_auth(&server);
+            auth.config.credential = Credential::Certificate(Box::new(certificate));
+
+            assert_eq!(
+                auth.get_token("https://org.example.com").await.unwrap(),
+                "cert-token"
+            );
+
+            let requests = server.received_requests().await.unwrap();
+            let body = String::from_utf8_lossy(&requests[0].body);
+            assert!(!body.contains("client_secret"), "{body}");
+        }
+
+        #[tokio::test]
+        async fn rotated_key_vault_secret_is_read_again_once() {
+            let server = MockServer::start().await;
+            let secret =
+                |value: &str| ResponseTemplate::new(200).set_body_json(json!({ "value": value }));
+            Mock::given(method("GET"))
+                .and(path("/secrets/d365"))
+                .respond_with(secret("old-secret"))
+                .up_to_n_times(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/secrets/d365"))
+                .respond_with(secret("new-secret"))
+                .mount(&server)
+                .await;
+            Mock::given(method("POST"))
+                .and(path("/token"))
+                .and(body_string_contains("client_secret=old-secret"))
+                .respond_with(ResponseTemplate::new(401).set_body_string(
+                    r#"{"error":"invalid_client","error_description":"AADSTS7000215: Invalid client secret provided."}"#,
+                ))
+                .expect(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("POST"))
+                .and(path("/token"))
+                .and(body_string_contains("client_secret=new-secret"))
+                .respond_with(token_response("fresh-token"))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let mut auth = adfs_auth(&server);
+            auth.config.credential = Credential::KeyVault(Arc::new(KeyVaultSecret::new(
+                format!("{}/secrets/d365", server.uri()),
+                Arc::new(StaticTokenProvider::new("vault-token")),
+                "managed identity",
+            )));
+
+            assert_eq!(
+                auth.get_token("https://org.example.com").await.unwrap(),
+                "fresh-token"
+            );
+        }
+    }
 }
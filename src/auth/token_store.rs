@@ -0,0 +1,246 @@
+//! Encrypted on-disk token cache
+//!
+//! MCP hosts start a fresh process for every session, so without a copy on
+//! disk each session pays a token request. Tokens are stored with a
+//! wall-clock expiry, encrypted with AES-256-GCM under a key derived from the
+//! client secret (or the certificate's private key, or for Key Vault
+//! credentials a random key kept in the native secret store) together with
+//! the tenant and client id. A file that cannot be read, decrypted or parsed
+//! is ignored and replaced on the next save.
+
+use super::CachedToken;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// File format version, mixed into the key so old files simply fail to decrypt
+const KEY_CONTEXT: &[u8] = b"d365-odata-mcp token cache v1";
+
+/// AES-GCM nonce length, stored in front of the ciphertext
+const NONCE_LEN: usize = 12;
+
+/// Native secret store service holding the keys of Key Vault credentials
+const KEYCHAIN_SERVICE: &str = "d365-odata-mcp token cache";
+
+/// Token cache file encrypted for one client identity
+pub(crate) struct TokenStore {
+    path: PathBuf,
+    key: [u8; 32],
+    /// Version of the last snapshot written, so a late older one is skipped
+    written: Mutex<u64>,
+}
+
+impl std::fmt::Debug for TokenStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenStore")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TokenStore {
+    /// Store at `path` whose key is derived from `secret` and the identity
+    pub(crate) fn new(path: impl Into<PathBuf>, identity: &[&str], secret: &[u8]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(KEY_CONTEXT);
+        for part in identity {
+            // Length-prefixed so ("ab", "c") and ("a", "bc") differ
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+        hasher.update(secret);
+        Self {
+            path: path.into(),
+            key: hasher.finalize().into(),
+            written: Mutex::new(0),
+        }
+    }
+
+    /// Key material for credentials without a secret of their own: a random
+    /// key in the native secret store, one per cache file, created on first
+    /// use
+    pub(crate) fn keychain_key(path: &Path) -> Result<Vec<u8>, keyring::Error> {
+        let entry = keyring::Entry::new(KEYCHAIN_SERVICE, &path.display().to_string())?;
+        match entry.get_password() {
+            Ok(key) => Ok(key.into_bytes()),
+            Err(keyring::Error::NoEntry) => {
+                let key = URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+                entry.set_password(&key)?;
+                Ok(key.into_bytes())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Cached tokens from disk; empty when the file is missing or unusable
+    pub(crate) fn load(&self) -> HashMap<String, CachedToken> {
+        let Ok(data) = fs::read(&self.path) else {
+            return HashMap::new();
+        };
+        match self.decrypt(&data) {
+            Some(tokens) => {
+                tracing::debug!("Loaded token cache from {}", self.path.display());
+                tokens
+            }
+            None => {
+                tracing::debug!("Ignoring unreadable token cache {}", self.path.display());
+                HashMap::new()
+            }
+        }
+    }
+
+    /// Write `tokens`, the cache's `version`th change, to disk unless a later
+    /// one was written already; blocks, and failures are logged, never
+    /// returned
+    pub(crate) fn save(&self, version: u64, tokens: &HashMap<String, CachedToken>) {
+        let mut written = self.written.lock().unwrap();
+        if version <= *written {
+            return;
+        }
+        match self.try_save(tokens) {
+            Ok(()) => *written = version,
+            Err(e) => {
+                tracing::warn!("Could not write token cache {}: {}", self.path.display(), e)
+            }
+        }
+    }
+
+    fn try_save(&self, tokens: &HashMap<String, CachedToken>) -> std::io::Result<()> {
+        let plaintext = serde_json::to_vec(tokens)?;
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = self
+            .cipher()
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|_| std::io::Error::other("encryption failed"))?;
+
+        if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        // Write next to the target and rename, so readers never see half a file
+        let partial = self.path.with_extension("partial");
+        let mut file = create_private(&partial)?;
+        file.write_all(&nonce)?;
+        file.write_all(&ciphertext)?;
+        file.sync_all()?;
+        fs::rename(&partial, &self.path)
+    }
+
+    fn decrypt(&self, data: &[u8]) -> Option<HashMap<String, CachedToken>> {
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let plaintext = self
+            .cipher()
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .ok()?;
+        serde_json::from_slice(&plaintext).ok()
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.key))
+    }
+}
+
+/// Create (or truncate) a file only the current user can read
+fn create_private(path: &Path) -> std::io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let file = options.open(path)?;
+        // `mode` only applies to new files
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        Ok(file)
+    }
+    #[cfg(not(unix))]
+    {
+        options.open(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "d365-token-store-{}-{}-{}",
+            name,
+            std::process::id(),
+            rand::random::<u32>()
+        ))
+    }
+
+    fn tokens() -> HashMap<String, CachedToken> {
+        HashMap::from([(
+            "https://contoso.crm.dynamics.com/.default".to_string(),
+            CachedToken {
+                access_token: "cached-token".to_string(),
+                expires_at: SystemTime::UNIX_EPOCH + Duration::from_secs(4_000_000_000),
+            },
+        )])
+    }
+
+    #[test]
+    fn tokens_round_trip_only_under_the_same_key() {
+        let path = temp_path("round-trip");
+        let store = TokenStore::new(&path, &["tenant", "client"], b"secret");
+        store.save(1, &tokens());
+
+        let loaded = store.load();
+        let token = &loaded["https://contoso.crm.dynamics.com/.default"];
+        assert_eq!(token.access_token, "cached-token");
+        assert_eq!(
+            token.expires_at,
+            tokens().into_values().next().unwrap().expires_at
+        );
+        assert!(!fs::read(&path)
+            .unwrap()
+            .windows(12)
+            .any(|w| w == b"cached-token"));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // A different secret or identity cannot read it, and nor can garbage
+        assert!(TokenStore::new(&path, &["tenant", "client"], b"other")
+            .load()
+            .is_empty());
+        assert!(TokenStore::new(&path, &["tenant", "other"], b"secret")
+            .load()
+            .is_empty());
+        fs::write(&path, b"corrupt").unwrap();
+        assert!(store.load().is_empty());
+
+        let _ = fs::remove_file(&path);
+        assert!(store.load().is_empty());
+    }
+
+    #[test]
+    fn older_snapshots_never_overwrite_newer_ones() {
+        let path = temp_path("versions");
+        let store = TokenStore::new(&path, &["tenant", "client"], b"secret");
+        store.save(2, &tokens());
+        store.save(1, &HashMap::new());
+        assert_eq!(store.load().len(), 1);
+
+        store.save(3, &HashMap::new());
+        assert!(store.load().is_empty());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,276 @@
+//! Command-line interface
+//!
+//! `serve` (the default) speaks MCP over stdio or HTTP, so on stdio its
+//! stdout carries protocol messages only. The other subcommands are one-shot
+//! helpers for scripts and health probes; their output is formatted here and
+//! never goes through the protocol writer.
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use d365_odata_mcp::config::RuntimeConfig;
+use d365_odata_mcp::odata::metadata::MetadataModel;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Default address for `--transport http`
+pub const DEFAULT_LISTEN_ADDR: &str = "127.0.0.1:8080";
+
+/// Environment variables, listed under `--help`
+const ENV_HELP: &str = "\
+Environment variables (each may also be prefixed with D365_, which wins):
+  TENANT_ID      Azure AD tenant ID (required)
+  CLIENT_ID      Azure AD client/app ID (required)
+  CLIENT_SECRET  Azure AD client secret (required unless USE_KEYCHAIN=true or a certificate is used)
+  CLIENT_CERTIFICATE_PATH      PEM or PFX certificate used instead of CLIENT_SECRET (optional)
+  CLIENT_CERTIFICATE_PASSWORD  Password for an encrypted certificate (optional)
+  CLIENT_SECRET_KEYVAULT_URI   Key Vault secret URI to read CLIENT_SECRET from (optional)
+  AUTH_MODE      'client_credentials' (default), 'managed_identity' or 'azure_cli' (optional)
+  MANAGED_IDENTITY_CLIENT_ID  User-assigned managed identity client ID (optional)
+  ENDPOINT       D365 OData endpoint URL (required)
+  AZURE_CLOUD    'public' (default), 'us_gov', 'us_gov_high' or 'china' (optional)
+  AUTHORITY_HOST Override the Entra ID authority host (optional)
+  AUTH_MAX_RETRIES  Token request retries after transient failures (optional, default 3)
+  HTTPS_PROXY / HTTP_PROXY  Proxy URL for outbound requests (optional)
+  PROXY_USERNAME / PROXY_PASSWORD  Basic credentials for the proxy (optional)
+  NO_PROXY       Comma-separated hosts reached without the proxy (optional)
+  TOKEN_CACHE_PATH  Encrypted file tokens are kept in across restarts (optional)
+  RECORD_DIR     Record every request and response as JSON files (optional)
+  REPLAY_DIR     Answer requests from a recorded directory, offline (optional)
+  CA_CERT_PATH   PEM bundle of extra trusted root certificates; preferred over INSECURE_SSL (optional)
+  COMPRESSION    Accept gzip/deflate/brotli responses (optional, default true)
+  REWRITE_NEXT_LINK_HOST  Follow nextLinks on the endpoint's host, for private endpoints (optional, default false)
+  USER_AGENT_SUFFIX  Appended to the User-Agent of OData requests (optional)
+  LABEL_LANGUAGE  Language of labels and descriptions, sent as Accept-Language, e.g. 'de-DE' (optional)
+  PRODUCT        'dataverse' or 'finops' (required)
+  READ_ONLY      Hide and reject tools that modify data (optional, default true)
+  DRY_RUN_ALL_WRITES  Return write requests instead of sending them (optional, default false)
+  ALLOWED_ENTITIES  Comma-separated entity sets tools may use, e.g. 'CustomersV3,Sales*' (optional)
+  DENIED_ENTITIES   Comma-separated entity sets tools may never use (optional)
+  REQUEST_TIMEOUT_SECS  Seconds a tool call's requests may take (optional, default 120)
+  SHUTDOWN_GRACE_SECS  Seconds running requests get to finish on shutdown (optional, default 10)
+  SESSION_IDLE_SECS  Seconds an HTTP session may sit idle before it expires (optional, default 1800, 0 = never)
+  METADATA_FILE  $metadata snapshot to work offline from, like --metadata-file (optional)
+  CONFIG_RELOAD_SECS  Check the config file this often and reload it when it changes (optional, default 0 = off)
+  DEFAULT_TOP    query_entity page size without top (optional, default 50)
+  MAX_TOP        Largest top query_entity sends (optional, default 1000)
+  MAX_RESPONSE_CHARS  Truncate tool output beyond this many characters (optional, default 100000)
+  DEFAULT_FORMAT Default query_entity output: 'json', 'table' or 'csv' (optional)
+  ANNOTATIONS    Annotations requested: 'none', 'formatted-only' or 'all' (optional, default all)
+  IMPERSONATE_USER_ID  Dataverse user GUID that requests are made on behalf of (optional)
+  IMPERSONATION_HEADER 'system_user_id' (MSCRMCallerID, default) or 'object_id' (CallerObjectId) (optional)
+  DOWNLOAD_DIR   Directory download_file saves files in (optional, default: temp dir)
+  MAX_DOWNLOAD_BYTES  Largest file download_file fetches (optional, default 100 MB)
+  UPLOAD_DIR     Directory upload_file and dmf_import_package may read from; uploads are disabled while unset (optional)
+  EXPORT_DIR     Directory export_entity writes files in; exports are disabled while unset (optional)
+  HISTORY_SIZE   Tool calls query_history keeps; 0 turns it off (optional, default 50)
+  HISTORY_EXCLUDED_ENTITIES  Comma-separated entity sets whose calls are never kept in the history (optional)
+  COMPARE_IGNORED_FIELDS  Comma-separated fields compare_record ignores (optional, default modifiedon,versionnumber,dataAreaId)
+  DISTINCT_VALUES_LIMIT  Records distinct_values reads when de-duplicating client-side (optional, default 10000)
+  D365_ENVIRONMENT  Named [environments.<name>] entry to start with (optional)
+  DEFAULT_COMPANY  F&O legal entity (dataAreaId) for queries and writes without 'company' (optional)
+  USE_KEYCHAIN   Read CLIENT_SECRET from native secret store (optional)
+  CLIENT_SECRET_KEYCHAIN_SERVICE  Secret store service name (required when USE_KEYCHAIN=true)
+  CLIENT_SECRET_KEYCHAIN_ACCOUNT  Secret store account name (optional, defaults to CLIENT_ID)";
+
+/// MCP Server for Microsoft Dynamics 365 OData API
+#[derive(Debug, Clone, Parser)]
+#[command(
+    name = "d365-odata-mcp",
+    version,
+    after_help = ENV_HELP
+)]
+pub struct Cli {
+    /// Config file (default: config/default.toml when it exists)
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Log level written to stderr, e.g. 'debug' or 'd365_odata_mcp=trace'
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Work offline from a $metadata snapshot written by dump-metadata:
+    /// metadata tools use the file, data requests fail, no credentials needed
+    #[arg(long, global = true, value_name = "PATH")]
+    pub metadata_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// `serve` options, also accepted without the subcommand
+    #[command(flatten)]
+    pub serve: ServeArgs,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Run the MCP server (the default)
+    Serve(ServeArgs),
+    /// Run the staged connection test; exits non-zero when a stage fails
+    Check,
+    /// Print the resolved configuration with secrets masked
+    PrintConfig,
+    /// Print the entity sets from $metadata, one per line
+    ListEntities,
+    /// Save $metadata and its parsed JSON model for offline use
+    DumpMetadata(DumpMetadataArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct DumpMetadataArgs {
+    /// Directory to write metadata.xml and metadata.json to
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    pub output: PathBuf,
+}
+
+impl Cli {
+    /// The subcommand to run; a bare invocation serves
+    pub fn subcommand(&self) -> Command {
+        self.command
+            .clone()
+            .unwrap_or_else(|| Command::Serve(self.serve.clone()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TransportKind {
+    Stdio,
+    Http,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ServeArgs {
+    /// 'stdio', or 'http' for streamable HTTP at /mcp
+    #[arg(long, value_enum, default_value_t = TransportKind::Stdio)]
+    pub transport: TransportKind,
+
+    /// Address for the HTTP transport [default: 127.0.0.1:8080]
+    #[arg(long, value_name = "ADDR")]
+    pub listen: Option<SocketAddr>,
+}
+
+/// How MCP messages reach the server
+#[derive(Debug, PartialEq)]
+pub enum Transport {
+    /// Newline-delimited JSON-RPC over stdin/stdout
+    Stdio,
+    /// Streamable HTTP on the given address
+    Http { listen: SocketAddr },
+}
+
+impl ServeArgs {
+    pub fn transport(&self) -> Result<Transport, String> {
+        match (self.transport, self.listen) {
+            (TransportKind::Stdio, Some(_)) => {
+                Err("--listen requires --transport http".to_string())
+            }
+            (TransportKind::Stdio, None) => Ok(Transport::Stdio),
+            (TransportKind::Http, listen) => Ok(Transport::Http {
+                listen: listen.unwrap_or_else(|| DEFAULT_LISTEN_ADDR.parse().unwrap()),
+            }),
+        }
+    }
+}
+
+/// `print-config`: the resolved configuration, secrets masked
+pub fn write_config(out: &mut impl Write, config: &RuntimeConfig) -> io::Result<()> {
+    writeln!(out, "{:#?}", config.redacted())
+}
+
+/// `list-entities`: entity set names, one per line
+pub fn write_entity_sets(out: &mut impl Write, model: &MetadataModel) -> io::Result<()> {
+    for (entity_set, _) in &model.entity_sets {
+        writeln!(out, "{}", entity_set)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("d365-odata-mcp").chain(args.iter().copied()))
+    }
+
+    fn transport(args: &[&str]) -> Result<Transport, String> {
+        match parse(args).map_err(|e| e.to_string())?.subcommand() {
+            Command::Serve(serve) => serve.transport(),
+            other => Err(format!("not serve: {:?}", other)),
+        }
+    }
+
+    #[test]
+    fn stdio_is_the_default_transport() {
+        assert_eq!(transport(&[]).unwrap(), Transport::Stdio);
+        assert_eq!(
+            transport(&["--transport", "stdio"]).unwrap(),
+            Transport::Stdio
+        );
+        assert_eq!(transport(&["serve"]).unwrap(), Transport::Stdio);
+    }
+
+    #[test]
+    fn http_transport_uses_listen_address() {
+        assert_eq!(
+            transport(&["--transport", "http", "--listen", "0.0.0.0:9000"]).unwrap(),
+            Transport::Http {
+                listen: "0.0.0.0:9000".parse().unwrap()
+            }
+        );
+        assert_eq!(
+            transport(&["serve", "--transport=http"]).unwrap(),
+            Transport::Http {
+                listen: DEFAULT_LISTEN_ADDR.parse().unwrap()
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_transport_arguments_are_rejected() {
+        assert!(transport(&["--transport", "websocket"]).is_err());
+        assert!(transport(&["--listen", "0.0.0.0:9000"]).is_err());
+        assert!(transport(&["--transport", "http", "--listen", "nope"]).is_err());
+        assert!(transport(&["--transport"]).is_err());
+        assert!(transport(&["--verbose"]).is_err());
+    }
+
+    #[test]
+    fn global_flags_apply_to_every_subcommand() {
+        let cli = parse(&["check", "--config", "prod.toml", "--log-level", "debug"]).unwrap();
+        assert!(matches!(cli.subcommand(), Command::Check));
+        assert_eq!(cli.config, Some(PathBuf::from("prod.toml")));
+        assert_eq!(cli.log_level.as_deref(), Some("debug"));
+
+        let cli = parse(&["--config", "dev.toml", "list-entities"]).unwrap();
+        assert!(matches!(cli.subcommand(), Command::ListEntities));
+        assert_eq!(cli.config, Some(PathBuf::from("dev.toml")));
+
+        // Serve options belong to serve
+        assert!(parse(&["print-config", "--transport", "http"]).is_err());
+
+        let cli = parse(&["dump-metadata", "--output", "snapshots"]).unwrap();
+        assert!(
+            matches!(cli.subcommand(), Command::DumpMetadata(args) if args.output == Path::new("snapshots"))
+        );
+        let cli = parse(&["--metadata-file", "snapshots/metadata.xml"]).unwrap();
+        assert!(matches!(cli.subcommand(), Command::Serve(_)));
+        assert_eq!(
+            cli.metadata_file,
+            Some(PathBuf::from("snapshots/metadata.xml"))
+        );
+    }
+
+    #[test]
+    fn entity_sets_are_listed_one_per_line() {
+        let model = MetadataModel::parse(
+            r#"<edmx:Edmx><Schema Namespace="Microsoft.Dynamics.CRM">
+            <EntitySet Name="accounts" EntityType="Microsoft.Dynamics.CRM.account" />
+            <EntitySet Name="contacts" EntityType="Microsoft.Dynamics.CRM.contact" />
+            </Schema></edmx:Edmx>"#,
+        );
+        let mut out = Vec::new();
+        write_entity_sets(&mut out, &model).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "accounts\ncontacts\n");
+    }
+}
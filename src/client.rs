@@ -0,0 +1,333 @@
+//! Library entry point for using D365 from other Rust code
+//!
+//! [`D365Client`] builds an [`ODataClient`] from a [`RuntimeConfig`] the way
+//! the MCP server does: credentials (client secret, certificate, Key Vault,
+//! managed identity or Azure CLI), proxy, extra root certificates, retries,
+//! throttling, recording and offline snapshots all follow the configuration.
+//! It needs neither the `mcp-server` feature nor an MCP client.
+//!
+//! ```no_run
+//! use d365_odata_mcp::{D365Client, QueryOptions};
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! // `config/default.toml` when present, then `ENDPOINT`, `TENANT_ID`, ...
+//! let client = D365Client::from_env()?;
+//!
+//! let options = QueryOptions::builder()
+//!     .select(["name", "accountnumber"])
+//!     .filter("statecode eq 0")
+//!     .top(10)
+//!     .build();
+//! let page = client.query("accounts", &options).await?;
+//! for account in &page.value {
+//!     println!("{}", account["name"]);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Paging follows `@odata.nextLink` one page at a time, or all at once:
+//!
+//! ```no_run
+//! use d365_odata_mcp::{D365Client, QueryOptions};
+//!
+//! # async fn run(client: D365Client) -> Result<(), Box<dyn std::error::Error>> {
+//! let options = QueryOptions::builder().max_page_size(500).build();
+//! let mut page = client.query("contacts", &options).await?;
+//! loop {
+//!     println!("{} contacts", page.value.len());
+//!     match client.next_page("contacts", &page, &options).await? {
+//!         Some(next) => page = next,
+//!         None => break,
+//!     }
+//! }
+//!
+//! let every_contact = client.query_all("contacts", &options).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::auth::{
+    AuthConfig, AuthType, AzureCliAuth, ClientCertificate, Credential, KeyVaultSecret,
+    ManagedIdentityAuth, OAuth2Auth, StaticTokenProvider, TokenProvider,
+};
+use crate::config::{AuthMode, Config, RuntimeConfig};
+use crate::network::load_root_certificates;
+use crate::odata::recording::Recording;
+use crate::odata::{MetadataSnapshot, ODataClient, ODataError, ODataResponse, QueryOptions};
+use reqwest::Certificate;
+use serde_json::Value;
+use std::error::Error;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// An [`ODataClient`] configured from a [`RuntimeConfig`]; it dereferences to
+/// the client for everything beyond plain queries
+#[derive(Clone)]
+pub struct D365Client {
+    odata: Arc<ODataClient>,
+}
+
+impl D365Client {
+    /// Build the client `config` describes
+    pub fn from_config(config: &RuntimeConfig) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            odata: Arc::new(create_client(config)?),
+        })
+    }
+
+    /// Build the client from `config/default.toml`, if present, and
+    /// environment variables, as the server resolves them
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        Self::from_config(&Config::load_default()?.to_runtime()?)
+    }
+
+    /// The underlying client, to share with other tasks
+    pub fn odata(&self) -> Arc<ODataClient> {
+        self.odata.clone()
+    }
+
+    /// First page of `entity` matching `options`
+    pub async fn query(
+        &self,
+        entity: &str,
+        options: &QueryOptions,
+    ) -> Result<ODataResponse, ODataError> {
+        self.odata.fetch_entity_page(entity, None, options).await
+    }
+
+    /// The page after `page`, or `None` when it was the last
+    pub async fn next_page(
+        &self,
+        entity: &str,
+        page: &ODataResponse,
+        options: &QueryOptions,
+    ) -> Result<Option<ODataResponse>, ODataError> {
+        match &page.next_link {
+            Some(link) => self
+                .odata
+                .fetch_entity_page(entity, Some(link), options)
+                .await
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Every record of `entity` matching `options`, following every page
+    pub async fn query_all(
+        &self,
+        entity: &str,
+        options: &QueryOptions,
+    ) -> Result<Vec<Value>, ODataError> {
+        self.odata.fetch_all_pages(entity, options).await
+    }
+}
+
+impl Deref for D365Client {
+    type Target = ODataClient;
+
+    fn deref(&self) -> &ODataClient {
+        &self.odata
+    }
+}
+
+fn create_client(runtime_config: &RuntimeConfig) -> Result<ODataClient, Box<dyn Error>> {
+    if let Some(path) = &runtime_config.metadata_file {
+        let snapshot = MetadataSnapshot::load(path)?;
+        tracing::warn!(
+            "Offline: $metadata is read from {}; data requests are refused",
+            path
+        );
+        let client = ODataClient::new(
+            Arc::new(StaticTokenProvider::new("")),
+            runtime_config.endpoint.clone(),
+            runtime_config.product.clone(),
+            0,
+            runtime_config.retry_delay_ms,
+            runtime_config.insecure_ssl,
+        )
+        .with_offline_metadata(snapshot);
+        return Ok(client);
+    }
+
+    let root_certificates = match runtime_config.ca_cert_path {
+        Some(ref path) => load_root_certificates(path)?,
+        None => Vec::new(),
+    };
+    let auth = create_token_provider(runtime_config, &root_certificates)?;
+
+    let cache_ttl = Duration::from_secs(runtime_config.metadata_cache_ttl_secs);
+    let client = ODataClient::with_cache_ttl(
+        auth,
+        runtime_config.endpoint.clone(),
+        runtime_config.product.clone(),
+        runtime_config.max_retries,
+        runtime_config.retry_delay_ms,
+        runtime_config.insecure_ssl,
+        cache_ttl,
+    )
+    .with_page_size(runtime_config.page_size)
+    .with_rate_limit(
+        runtime_config.max_requests_per_minute,
+        runtime_config.max_concurrent_requests,
+    )
+    .with_max_retry_wait(Duration::from_secs(runtime_config.max_retry_wait_secs))
+    .with_request_timeout(Duration::from_secs(runtime_config.request_timeout_secs))
+    .with_circuit_breaker(
+        runtime_config.circuit_breaker_threshold,
+        Duration::from_secs(runtime_config.circuit_breaker_cooldown_secs),
+    )
+    .with_impersonation(
+        runtime_config.impersonate_user_id.clone(),
+        runtime_config.impersonation_header,
+    )
+    .with_proxy(runtime_config.proxy.clone())?
+    .with_root_certificates(root_certificates)
+    .with_compression(runtime_config.compression)
+    .with_next_link_rewrite(runtime_config.rewrite_next_link_host)
+    .with_user_agent_suffix(runtime_config.user_agent_suffix.clone())
+    .with_language(runtime_config.language.clone())
+    .with_annotations(runtime_config.annotations);
+
+    let recording = match (&runtime_config.record_dir, &runtime_config.replay_dir) {
+        (_, Some(dir)) => Some(
+            Recording::replay(dir)
+                .map_err(|e| format!("Cannot replay recordings from {}: {}", dir, e))?,
+        ),
+        (Some(dir), None) => {
+            Some(Recording::record(dir).map_err(|e| format!("Cannot record to {}: {}", dir, e))?)
+        }
+        (None, None) => None,
+    };
+    Ok(match recording {
+        Some(recording) => {
+            tracing::warn!(
+                "{:?} mode: requests go through {}",
+                recording.mode(),
+                recording.dir().display()
+            );
+            client.with_recording(recording)
+        }
+        None => client,
+    })
+}
+
+fn create_token_provider(
+    runtime_config: &RuntimeConfig,
+    root_certificates: &[Certificate],
+) -> Result<Arc<dyn TokenProvider>, Box<dyn Error>> {
+    match runtime_config.auth_mode {
+        AuthMode::ManagedIdentity => {
+            return Ok(Arc::new(
+                ManagedIdentityAuth::from_env(runtime_config.managed_identity_client_id.clone())
+                    .with_proxy(runtime_config.proxy.clone()),
+            ));
+        }
+        AuthMode::AzureCli => {
+            let tenant_id = Some(runtime_config.tenant_id.clone()).filter(|t| !t.is_empty());
+            return Ok(Arc::new(AzureCliAuth::new(tenant_id)));
+        }
+        AuthMode::ClientCredentials => {}
+    }
+
+    // Parse auth type
+    let auth_type: AuthType = runtime_config
+        .auth_type
+        .parse()
+        .unwrap_or(AuthType::AzureAd);
+
+    tracing::debug!("Auth type: {:?}", auth_type);
+
+    let certificate = match runtime_config.client_certificate_path {
+        Some(ref path) => {
+            tracing::info!("Using certificate credential: {}", path);
+            Some(Credential::Certificate(Box::new(
+                ClientCertificate::from_file(
+                    path,
+                    runtime_config.client_certificate_password.as_deref(),
+                )?,
+            )))
+        }
+        None => None,
+    };
+    let auth_config = |credential| AuthConfig {
+        auth_type: auth_type.clone(),
+        tenant_id: runtime_config.tenant_id.clone(),
+        client_id: runtime_config.client_id.clone(),
+        credential,
+        token_url: runtime_config.token_url.clone(),
+        resource: runtime_config.resource.clone(),
+        authority_host: Some(runtime_config.authority_host.clone()),
+        insecure_ssl: runtime_config.insecure_ssl,
+    };
+
+    let credential = match (&runtime_config.client_secret_keyvault_uri, certificate) {
+        // The certificate, or else the managed identity, only reads the vault
+        (Some(uri), certificate) => {
+            let (bootstrap, identity): (Arc<dyn TokenProvider>, String) = match certificate {
+                Some(certificate) => (
+                    Arc::new(
+                        OAuth2Auth::new(auth_config(certificate))
+                            .with_proxy(runtime_config.proxy.clone())
+                            .with_root_certificates(root_certificates.to_vec())
+                            .with_max_retries(runtime_config.auth_max_retries),
+                    ),
+                    format!("app {} (certificate)", runtime_config.client_id),
+                ),
+                None => (
+                    Arc::new(
+                        ManagedIdentityAuth::from_env(
+                            runtime_config.managed_identity_client_id.clone(),
+                        )
+                        .with_proxy(runtime_config.proxy.clone()),
+                    ),
+                    match &runtime_config.managed_identity_client_id {
+                        Some(id) => format!("managed identity {}", id),
+                        None => "the system-assigned managed identity".to_string(),
+                    },
+                ),
+            };
+            tracing::info!(
+                "Reading client secret from Key Vault {} as {}",
+                uri,
+                identity
+            );
+            let secret = Arc::new(
+                KeyVaultSecret::new(uri.clone(), bootstrap, identity)
+                    .with_proxy(runtime_config.proxy.clone())
+                    .with_root_certificates(root_certificates.to_vec()),
+            );
+            prefetch_key_vault_secret(secret.clone());
+            Credential::KeyVault(secret)
+        }
+        (None, Some(certificate)) => certificate,
+        (None, None) => {
+            Credential::Secret(runtime_config.client_secret.clone().unwrap_or_default())
+        }
+    };
+
+    let auth = OAuth2Auth::new(auth_config(credential))
+        .with_proxy(runtime_config.proxy.clone())
+        .with_root_certificates(root_certificates.to_vec())
+        .with_max_retries(runtime_config.auth_max_retries);
+    Ok(Arc::new(match runtime_config.token_cache_path {
+        Some(ref path) => {
+            tracing::info!("Caching tokens in {}", path);
+            auth.with_token_cache_path(path)
+        }
+        None => auth,
+    }))
+}
+
+/// Read a Key Vault secret at startup so a missing permission shows up in
+/// the log right away rather than on the first tool call
+fn prefetch_key_vault_secret(secret: Arc<KeyVaultSecret>) {
+    if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+        runtime.spawn(async move {
+            if let Err(e) = secret.get().await {
+                tracing::error!("{}", e);
+            }
+        });
+    }
+}
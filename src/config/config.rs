@@ -1,9 +1,18 @@
 //! Configuration module for D365 OData MCP
 //!
 //! Loads configuration from TOML file and environment variables.
-//! Environment variables take precedence over file config.
-
+//! Environment variables take precedence over file config, so a container can
+//! run without a config file at all. Every variable may carry a `D365_`
+//! prefix (`D365_ENDPOINT`), which wins over the bare name (`ENDPOINT`).
+
+use super::server::{OutputFormat, ToolPermissions, DEFAULT_COMPARE_IGNORED_FIELDS};
+use crate::auth::{CloudEnvironment, DEFAULT_AUTH_MAX_RETRIES};
+use crate::network::ProxySettings;
+use crate::odata::circuit::{DEFAULT_CIRCUIT_COOLDOWN, DEFAULT_CIRCUIT_THRESHOLD};
+use crate::odata::client::{is_guid, DEFAULT_REQUEST_TIMEOUT_SECS};
+use crate::odata::{parse_language_tag, AnnotationMode, CallerIdHeader};
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::env;
 use std::fs;
 use std::path::Path;
@@ -12,27 +21,89 @@ const USE_KEYCHAIN_ENV: &str = "USE_KEYCHAIN";
 const CLIENT_SECRET_ENV: &str = "CLIENT_SECRET";
 const CLIENT_SECRET_KEYCHAIN_SERVICE_ENV: &str = "CLIENT_SECRET_KEYCHAIN_SERVICE";
 const CLIENT_SECRET_KEYCHAIN_ACCOUNT_ENV: &str = "CLIENT_SECRET_KEYCHAIN_ACCOUNT";
+const CLIENT_CERTIFICATE_PATH_ENV: &str = "CLIENT_CERTIFICATE_PATH";
+const CLIENT_CERTIFICATE_PASSWORD_ENV: &str = "CLIENT_CERTIFICATE_PASSWORD";
+const CLIENT_SECRET_KEYVAULT_URI_ENV: &str = "CLIENT_SECRET_KEYVAULT_URI";
+const ENVIRONMENT_ENV: &str = "D365_ENVIRONMENT";
+/// Optional prefix for every environment variable, e.g. `D365_ENDPOINT`
+const ENV_PREFIX: &str = "D365_";
+/// Seconds running requests get to finish on shutdown when not configured
+pub const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 10;
+/// Seconds an HTTP session may sit idle before it expires when not configured
+pub const DEFAULT_SESSION_IDLE_SECS: u64 = 1800;
+
+/// Config file read when `--config` is not given
+pub const DEFAULT_CONFIG_PATH: &str = "config/default.toml";
 
 /// Product type - Dataverse or Finance & Operations
-#[derive(Debug, Deserialize, Clone, PartialEq)]
-#[serde(rename_all = "lowercase")]
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+#[serde(try_from = "String")]
 pub enum ProductType {
+    #[default]
     Dataverse,
-    #[serde(alias = "fno", alias = "fo")]
     Finops,
 }
 
-impl Default for ProductType {
-    fn default() -> Self {
-        ProductType::Dataverse
+impl std::str::FromStr for ProductType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "dataverse" => Ok(ProductType::Dataverse),
+            "finops" | "fno" | "fo" => Ok(ProductType::Finops),
+            _ => Err(format!(
+                "Unknown product: '{}'. Use 'dataverse' or 'finops' (also 'fno' or 'fo')",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for ProductType {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// How the server obtains access tokens
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMode {
+    /// App registration with a client secret or certificate
+    #[default]
+    ClientCredentials,
+    /// Azure managed identity (IMDS or App Service identity endpoint)
+    ManagedIdentity,
+    /// Developer login via `az account get-access-token`
+    AzureCli,
+}
+
+impl std::str::FromStr for AuthMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "client_credentials" | "client_secret" | "certificate" => {
+                Ok(AuthMode::ClientCredentials)
+            }
+            "managed_identity" | "msi" => Ok(AuthMode::ManagedIdentity),
+            "azure_cli" | "az" | "cli" => Ok(AuthMode::AzureCli),
+            _ => Err(format!(
+                "Unknown auth mode: {}. Use 'client_credentials', 'managed_identity' or 'azure_cli'",
+                s
+            )),
+        }
     }
 }
 
 /// Global configuration settings
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct GlobalConfig {
     #[serde(default)]
     pub product: ProductType,
+    #[serde(default)]
     pub endpoint: String,
     #[serde(default)]
     pub page_size: Option<usize>,
@@ -40,8 +111,133 @@ pub struct GlobalConfig {
     pub concurrency: Option<usize>,
     #[serde(default)]
     pub max_retries: Option<u32>,
+    /// Retries of a token request after a 429, 5xx or connection failure
+    #[serde(default)]
+    pub auth_max_retries: Option<u32>,
     #[serde(default)]
     pub retry_delay_ms: Option<u64>,
+    #[serde(default)]
+    pub max_requests_per_minute: Option<u32>,
+    #[serde(default)]
+    pub max_concurrent_requests: Option<usize>,
+    #[serde(default)]
+    pub max_retry_wait_secs: Option<u64>,
+    /// Consecutive connection failures or 502/503/504s that open the circuit
+    #[serde(default)]
+    pub circuit_breaker_threshold: Option<u32>,
+    /// Seconds an open circuit fails requests before probing the service
+    #[serde(default)]
+    pub circuit_breaker_cooldown_secs: Option<u64>,
+    /// Seconds a tool call's OData requests may take
+    #[serde(default)]
+    pub request_timeout_secs: Option<u64>,
+    /// Seconds running requests get to finish on shutdown
+    #[serde(default)]
+    pub shutdown_grace_secs: Option<u64>,
+    /// Seconds an HTTP session may sit idle before it expires; 0 keeps sessions open
+    #[serde(default)]
+    pub session_idle_secs: Option<u64>,
+    /// Seconds between checks of the config file for changes; 0 turns reloading off
+    #[serde(default)]
+    pub config_reload_secs: Option<u64>,
+    #[serde(default)]
+    pub auth_mode: Option<AuthMode>,
+    #[serde(default)]
+    pub managed_identity_client_id: Option<String>,
+    /// Key Vault secret URI holding the client secret
+    #[serde(default)]
+    pub client_secret_keyvault_uri: Option<String>,
+    /// Encrypted file client credential tokens are kept in across restarts
+    #[serde(default)]
+    pub token_cache_path: Option<String>,
+    /// Directory every request and response is recorded to
+    #[serde(default)]
+    pub record_dir: Option<String>,
+    /// Directory responses are replayed from instead of calling the service
+    #[serde(default)]
+    pub replay_dir: Option<String>,
+    #[serde(default)]
+    pub http_proxy: Option<String>,
+    #[serde(default)]
+    pub https_proxy: Option<String>,
+    #[serde(default)]
+    pub proxy_username: Option<String>,
+    #[serde(default)]
+    pub proxy_password: Option<String>,
+    #[serde(default)]
+    pub no_proxy: Option<String>,
+    /// PEM bundle of extra trusted root certificates
+    #[serde(default)]
+    pub ca_cert_path: Option<String>,
+    /// Ask for compressed responses (default true)
+    #[serde(default)]
+    pub compression: Option<bool>,
+    /// Follow nextLinks on the endpoint's host (default false)
+    #[serde(default)]
+    pub rewrite_next_link_host: Option<bool>,
+    /// Appended to the OData `User-Agent`, e.g. a deployment name
+    #[serde(default)]
+    pub user_agent_suffix: Option<String>,
+    /// Label language sent as `Accept-Language`, e.g. `de-DE`
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(default)]
+    pub cloud: Option<CloudEnvironment>,
+    #[serde(default)]
+    pub authority_host: Option<String>,
+    #[serde(default)]
+    pub read_only: Option<bool>,
+    /// Return the request of every write instead of sending it
+    #[serde(default)]
+    pub dry_run_all_writes: Option<bool>,
+    #[serde(default)]
+    pub allowed_entities: Option<Vec<String>>,
+    #[serde(default)]
+    pub denied_entities: Option<Vec<String>>,
+    #[serde(default)]
+    pub max_response_chars: Option<usize>,
+    /// `query_entity` page size without `top` (default 50)
+    #[serde(default)]
+    pub default_top: Option<usize>,
+    /// Largest `top` `query_entity` sends (default 1000)
+    #[serde(default)]
+    pub max_top: Option<usize>,
+    #[serde(default)]
+    pub default_format: Option<OutputFormat>,
+    /// Instance annotations requested: none, formatted-only or all (default)
+    #[serde(default)]
+    pub annotations: Option<AnnotationMode>,
+    #[serde(default)]
+    pub default_company: Option<String>,
+    #[serde(default)]
+    pub impersonate_user_id: Option<String>,
+    #[serde(default)]
+    pub download_dir: Option<String>,
+    #[serde(default)]
+    pub max_download_bytes: Option<u64>,
+    #[serde(default)]
+    pub upload_dir: Option<String>,
+    #[serde(default)]
+    pub export_dir: Option<String>,
+    /// `$metadata` snapshot to work offline from, as `dump-metadata` writes it
+    #[serde(default)]
+    pub metadata_file: Option<String>,
+    /// Tool calls `query_history` keeps (default 50, 0 turns history off)
+    #[serde(default)]
+    pub history_size: Option<usize>,
+    /// Entity sets whose calls are never kept in the history
+    #[serde(default)]
+    pub history_excluded_entities: Option<Vec<String>>,
+    /// Fields `compare_record` leaves out of its diff
+    #[serde(default)]
+    pub compare_ignored_fields: Option<Vec<String>>,
+    /// Records `distinct_values` reads when it de-duplicates client-side
+    #[serde(default)]
+    pub distinct_values_limit: Option<usize>,
+    #[serde(default)]
+    pub impersonation_header: Option<CallerIdHeader>,
+    #[serde(default)]
+    pub test_connection_on_startup: Option<bool>,
 }
 
 /// Observability configuration
@@ -70,18 +266,77 @@ pub struct EntityConfig {
     pub delta_enabled: Option<bool>,
     #[serde(default)]
     pub cross_company: Option<bool>,
+    /// Fields `query_entity` selects when no `select` is given
+    #[serde(default)]
+    pub default_select: Vec<String>,
+    /// Filter `query_entity` always ANDs onto the query
+    #[serde(default)]
+    pub default_filter: Option<String>,
+    /// `query_entity` page size when no `top` is given; overrides the global one
+    #[serde(default)]
+    pub default_top: Option<usize>,
+    /// Largest `top` `query_entity` sends for this entity; larger ones are lowered
+    #[serde(default)]
+    pub max_top: Option<usize>,
+}
+
+/// A named environment (`[environments.<name>]`), e.g. dev, uat or prod.
+/// Its settings take precedence over `[global]` and environment variables.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct EnvironmentConfig {
+    pub endpoint: String,
+    #[serde(default)]
+    pub product: Option<ProductType>,
+    #[serde(default)]
+    pub tenant_id: Option<String>,
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    #[serde(default)]
+    pub auth_mode: Option<AuthMode>,
+    #[serde(default)]
+    pub read_only: Option<bool>,
+    #[serde(default)]
+    pub default_company: Option<String>,
+    /// Entity policy of this environment, replacing the global lists
+    #[serde(default)]
+    pub allowed_entities: Option<Vec<String>>,
+    #[serde(default)]
+    pub denied_entities: Option<Vec<String>>,
+    /// Switching to this environment requires `confirm: true`
+    #[serde(default)]
+    pub production: bool,
+}
+
+/// A configured environment, as listed by `list_environments`
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvironmentSummary {
+    pub name: String,
+    pub endpoint: String,
+    pub product: ProductType,
+    pub production: bool,
 }
 
 /// Root configuration structure
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
+    #[serde(default)]
     pub global: GlobalConfig,
+    /// Environment used at startup; `D365_ENVIRONMENT` overrides it
+    #[serde(default)]
+    pub default_environment: Option<String>,
+    #[serde(default)]
+    pub environments: BTreeMap<String, EnvironmentConfig>,
     #[serde(default)]
     pub observability: Option<ObservabilityConfig>,
     #[serde(default)]
     pub delta: Option<DeltaConfig>,
     #[serde(default)]
     pub entities: Option<Vec<EntityConfig>>,
+    /// Roles allowed to call each tool, and how sessions get their roles
+    #[serde(default)]
+    pub tool_permissions: Option<ToolPermissions>,
 }
 
 /// Runtime configuration with resolved values from env vars
@@ -91,18 +346,62 @@ pub struct RuntimeConfig {
     pub endpoint: String,
     pub tenant_id: String,
     pub client_id: String,
-    pub client_secret: String,
+    /// How access tokens are obtained
+    pub auth_mode: AuthMode,
+    /// Client ID of a user-assigned managed identity (system identity if `None`)
+    pub managed_identity_client_id: Option<String>,
+    /// Azure cloud (public or sovereign)
+    pub cloud: CloudEnvironment,
+    /// Entra ID authority host; defaults to the cloud's authority
+    pub authority_host: String,
+    /// Client secret; `None` when a certificate or managed identity is used
+    pub client_secret: Option<String>,
+    /// PEM or PFX certificate used for the client assertion flow
+    pub client_certificate_path: Option<String>,
+    /// Password for an encrypted PEM key or PFX archive
+    pub client_certificate_password: Option<String>,
+    /// Key Vault secret URI the client secret is read from; the certificate,
+    /// if any, or else the managed identity is used to read it
+    pub client_secret_keyvault_uri: Option<String>,
+    /// Encrypted file client credential tokens are kept in, so a restarted
+    /// process reuses them; tokens live in memory only when unset
+    pub token_cache_path: Option<String>,
+    /// Directory each request and its response are written to as numbered
+    /// JSON files, for building offline test fixtures
+    pub record_dir: Option<String>,
+    /// Directory of recorded exchanges served instead of calling the service
+    pub replay_dir: Option<String>,
+    /// Outbound proxy for token, Key Vault and OData requests
+    pub proxy: ProxySettings,
     /// Authentication type: "azure" or "adfs"
     pub auth_type: String,
     /// Custom token URL (for ADFS)
     pub token_url: Option<String>,
     /// Resource/audience (for ADFS)
     pub resource: Option<String>,
-    /// Skip SSL certificate verification (for self-signed certs)
+    /// Skip SSL certificate verification (for self-signed certs); ignored
+    /// when `ca_cert_path` is set
     pub insecure_ssl: bool,
+    /// PEM file with one or more extra trusted root certificates, e.g. an
+    /// on-premise CA
+    pub ca_cert_path: Option<String>,
+    /// Accept gzip, deflate and brotli OData responses; turned off only to
+    /// read raw traffic while debugging
+    pub compression: bool,
+    /// Re-base `@odata.nextLink` URLs onto the endpoint's scheme, host and
+    /// port, for F&O behind a private endpoint whose links name the public host
+    pub rewrite_next_link_host: bool,
+    /// Appended to the `User-Agent` of OData requests so traffic can be told
+    /// apart per deployment, e.g. `contoso-prod`
+    pub user_agent_suffix: Option<String>,
+    /// Language of labels and descriptions, sent as `Accept-Language`;
+    /// `query_entity` may override it
+    pub language: Option<String>,
     pub page_size: usize,
     pub concurrency: usize,
     pub max_retries: u32,
+    /// Retries of a token request after a 429, 5xx or connection failure (default: 3)
+    pub auth_max_retries: u32,
     pub retry_delay_ms: u64,
     pub log_level: String,
     pub enable_tracing: bool,
@@ -110,6 +409,152 @@ pub struct RuntimeConfig {
     pub entities: Vec<EntityConfig>,
     /// Metadata cache TTL in seconds (default: 900 = 15 minutes)
     pub metadata_cache_ttl_secs: u64,
+    /// Client-side request rate limit (None = unlimited)
+    pub max_requests_per_minute: Option<u32>,
+    /// Maximum concurrent OData requests (None = unlimited)
+    pub max_concurrent_requests: Option<usize>,
+    /// Upper bound for a single retry wait, including Retry-After (default: 60)
+    pub max_retry_wait_secs: u64,
+    /// Consecutive connection failures or 502/503/504 responses after which
+    /// requests fail at once for the cool-down; 0 disables it (default: 5)
+    pub circuit_breaker_threshold: u32,
+    /// Seconds an open circuit fails requests before one probe is let
+    /// through (default: 30)
+    pub circuit_breaker_cooldown_secs: u64,
+    /// Seconds a tool call's OData requests, retries included, may take
+    /// unless the call passes its own `timeout` (default: 120)
+    pub request_timeout_secs: u64,
+    /// How long running requests may take to finish after stdin closes or a
+    /// shutdown signal arrives, before they are cancelled (default: 10)
+    pub shutdown_grace_secs: u64,
+    /// Seconds an HTTP session may go without requests before it is dropped;
+    /// 0 keeps sessions until the client deletes them (default: 1800)
+    pub session_idle_secs: u64,
+    /// Seconds between checks of the config file, which is reloaded when it
+    /// changes; 0 turns reloading off (default: 0)
+    pub config_reload_secs: u64,
+    /// Hide and reject tools that modify data (default: true)
+    pub read_only: bool,
+    /// Write tools always dry-run: they return the request they would send
+    pub dry_run_all_writes: bool,
+    /// Entity sets tools may touch; empty allows all. Supports `Prefix*`
+    pub allowed_entities: Vec<String>,
+    /// Entity sets tools may never touch; takes precedence over the allowlist
+    pub denied_entities: Vec<String>,
+    /// Maximum characters of tool output returned to the client (default: 100000)
+    pub max_response_chars: usize,
+    /// `query_entity` page size when the call passes no `top` and the entity
+    /// configures no `default_top`; the built-in 50 when unset
+    pub default_top: Option<usize>,
+    /// Largest `top` `query_entity` sends unless the entity configures its
+    /// own `max_top`; the built-in 1000 when unset
+    pub max_top: Option<usize>,
+    /// `query_entity` output format when the call does not pass one
+    pub default_format: OutputFormat,
+    /// Instance annotations requested with `Prefer: odata.include-annotations`
+    /// and folded into `field_label` keys; `query_entity` may override it
+    /// (default: all)
+    pub annotations: AnnotationMode,
+    /// F&O legal entity (`dataAreaId`) used when a tool call passes no `company`
+    pub default_company: Option<String>,
+    /// Dataverse user (GUID) that requests are made on behalf of
+    pub impersonate_user_id: Option<String>,
+    /// Header identifying the impersonated user (default: `MSCRMCallerID`)
+    pub impersonation_header: CallerIdHeader,
+    /// Directory `download_file` writes to (default: `<temp dir>/d365-odata-mcp`)
+    pub download_dir: String,
+    /// Largest file `download_file` fetches (default: 100 MB)
+    pub max_download_bytes: u64,
+    /// Directory `upload_file` may read from; uploads are disabled when unset
+    pub upload_dir: Option<String>,
+    /// Directory `export_entity` writes files in; exports are disabled when unset
+    pub export_dir: Option<String>,
+    /// EDMX snapshot read instead of the service's `$metadata`; when set,
+    /// nothing is sent to the service and no credentials are needed
+    pub metadata_file: Option<String>,
+    /// Tool calls `query_history` keeps; 0 turns the history off (default: 50)
+    pub history_size: usize,
+    /// Entity sets whose calls are left out of the history. Supports `Prefix*`
+    pub history_excluded_entities: Vec<String>,
+    /// Fields `compare_record` leaves out of its diff (default: modifiedon,
+    /// versionnumber, dataAreaId)
+    pub compare_ignored_fields: Vec<String>,
+    /// Records `distinct_values` reads at most when it de-duplicates
+    /// client-side, as for F&O (default: 10000)
+    pub distinct_values_limit: usize,
+    /// Run the `test_connection` checks at startup and log the result (default: false)
+    pub test_connection_on_startup: bool,
+    /// Roles each tool requires and how sessions get theirs; every session
+    /// may call every tool when unset
+    pub tool_permissions: Option<ToolPermissions>,
+    /// Active `[environments]` entry; `None` when only `[global]` is used
+    pub environment: Option<String>,
+    /// Whether the active environment is marked `production`
+    pub production: bool,
+    /// Every configured environment, in name order
+    pub environments: Vec<EnvironmentSummary>,
+}
+
+impl RuntimeConfig {
+    /// Copy with secrets masked, for printing
+    pub fn redacted(&self) -> RuntimeConfig {
+        let mask = |secret: &Option<String>| secret.as_ref().map(|_| "********".to_string());
+        RuntimeConfig {
+            client_secret: mask(&self.client_secret),
+            client_certificate_password: mask(&self.client_certificate_password),
+            proxy: ProxySettings {
+                password: mask(&self.proxy.password),
+                ..self.proxy.clone()
+            },
+            tool_permissions: self
+                .tool_permissions
+                .as_ref()
+                .map(ToolPermissions::redacted),
+            ..self.clone()
+        }
+    }
+
+    /// Whether an `ODataClient` built for `self` serves `other` as well: the
+    /// two differ at most in settings the server reads on each call (entity
+    /// policy, output, directories), not in endpoint, credentials or
+    /// connection settings. Fields not listed here count as client settings,
+    /// so a reload rather rebuilds a client than keeps a stale one.
+    pub fn same_client_settings(&self, other: &RuntimeConfig) -> bool {
+        let other = RuntimeConfig {
+            entities: self.entities.clone(),
+            log_level: self.log_level.clone(),
+            enable_tracing: self.enable_tracing,
+            delta_storage_path: self.delta_storage_path.clone(),
+            shutdown_grace_secs: self.shutdown_grace_secs,
+            session_idle_secs: self.session_idle_secs,
+            config_reload_secs: self.config_reload_secs,
+            read_only: self.read_only,
+            dry_run_all_writes: self.dry_run_all_writes,
+            allowed_entities: self.allowed_entities.clone(),
+            denied_entities: self.denied_entities.clone(),
+            max_response_chars: self.max_response_chars,
+            default_top: self.default_top,
+            max_top: self.max_top,
+            default_format: self.default_format,
+            default_company: self.default_company.clone(),
+            download_dir: self.download_dir.clone(),
+            max_download_bytes: self.max_download_bytes,
+            upload_dir: self.upload_dir.clone(),
+            export_dir: self.export_dir.clone(),
+            history_size: self.history_size,
+            history_excluded_entities: self.history_excluded_entities.clone(),
+            compare_ignored_fields: self.compare_ignored_fields.clone(),
+            distinct_values_limit: self.distinct_values_limit,
+            test_connection_on_startup: self.test_connection_on_startup,
+            tool_permissions: self.tool_permissions.clone(),
+            environment: self.environment.clone(),
+            production: self.production,
+            environments: self.environments.clone(),
+            ..other.clone()
+        };
+        // Not every setting type implements `PartialEq`; `Debug` covers them all
+        format!("{:?}", self) == format!("{:?}", other)
+    }
 }
 
 impl Config {
@@ -122,11 +567,10 @@ impl Config {
 
     /// Load from default path or create default config
     pub fn load_default() -> Result<Self, Box<dyn std::error::Error>> {
-        let default_path = "config/default.toml";
-        if Path::new(default_path).exists() {
-            Self::load_from_path(default_path)
+        if Path::new(DEFAULT_CONFIG_PATH).exists() {
+            Self::load_from_path(DEFAULT_CONFIG_PATH)
         } else {
-            // Minimal default config
+            // Minimal default config; `to_runtime` fills in the rest from env vars
             Ok(Config {
                 global: GlobalConfig {
                     product: ProductType::default(),
@@ -135,20 +579,42 @@ impl Config {
                     concurrency: Some(4),
                     max_retries: Some(3),
                     retry_delay_ms: Some(1000),
+                    ..Default::default()
                 },
+                default_environment: None,
+                environments: BTreeMap::new(),
                 observability: Some(ObservabilityConfig::default()),
                 delta: Some(DeltaConfig::default()),
                 entities: None,
+                tool_permissions: None,
             })
         }
     }
 
     /// Resolve configuration with environment variables
     /// Environment variables take precedence over file config
+    /// Uses the default environment when `[environments]` are configured
     pub fn to_runtime(&self) -> Result<RuntimeConfig, Box<dyn std::error::Error>> {
         self.to_runtime_with_keychain_reader(read_client_secret_from_keychain)
     }
 
+    /// Resolve configuration for a named `[environments]` entry
+    pub fn to_runtime_for(
+        &self,
+        environment: &str,
+    ) -> Result<RuntimeConfig, Box<dyn std::error::Error>> {
+        self.resolve_runtime(Some(environment), read_client_secret_from_keychain)
+    }
+
+    /// Environment selected at startup: `D365_ENVIRONMENT`, then `default_environment`
+    fn startup_environment(&self) -> Option<String> {
+        env::var(ENVIRONMENT_ENV)
+            .ok()
+            .or_else(|| self.default_environment.clone())
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+    }
+
     fn to_runtime_with_keychain_reader<F>(
         &self,
         keychain_reader: F,
@@ -156,74 +622,572 @@ impl Config {
     where
         F: FnOnce(&str, &str) -> Result<String, Box<dyn std::error::Error>>,
     {
-        // Required env vars (no defaults)
-        let tenant_id =
-            env::var("TENANT_ID").map_err(|_| "TENANT_ID environment variable is required")?;
-        let client_id =
-            env::var("CLIENT_ID").map_err(|_| "CLIENT_ID environment variable is required")?;
-        let client_secret = resolve_client_secret(&client_id, keychain_reader)?;
+        self.resolve_runtime(self.startup_environment().as_deref(), keychain_reader)
+    }
+
+    fn resolve_runtime<F>(
+        &self,
+        environment: Option<&str>,
+        keychain_reader: F,
+    ) -> Result<RuntimeConfig, Box<dyn std::error::Error>>
+    where
+        F: FnOnce(&str, &str) -> Result<String, Box<dyn std::error::Error>>,
+    {
+        let selected = match environment {
+            Some(name) => Some(self.environments.get(name).ok_or_else(|| {
+                format!(
+                    "Unknown environment '{}'. Configured environments: {}",
+                    name,
+                    self.environment_names()
+                )
+            })?),
+            None => None,
+        };
+
+        // Missing required settings, reported together
+        let mut missing: Vec<String> = Vec::new();
+
+        let auth_mode = match selected.and_then(|e| e.auth_mode.clone()) {
+            Some(mode) => mode,
+            None => match env_var("AUTH_MODE") {
+                Ok(mode) => mode.parse::<AuthMode>()?,
+                Err(_) => self.global.auth_mode.clone().unwrap_or_default(),
+            },
+        };
+        // Sovereign clouds use their own authority host
+        let cloud = match env_var("AZURE_CLOUD") {
+            Ok(cloud) => cloud.parse::<CloudEnvironment>()?,
+            Err(_) => self.global.cloud.unwrap_or_default(),
+        };
+        let authority_host = env_var("AUTHORITY_HOST")
+            .ok()
+            .or_else(|| self.global.authority_host.clone())
+            .filter(|v| !v.trim().is_empty())
+            .unwrap_or_else(|| cloud.authority_host().to_string());
+
+        let managed_identity_client_id = env_var("MANAGED_IDENTITY_CLIENT_ID")
+            .ok()
+            .or_else(|| self.global.managed_identity_client_id.clone())
+            .filter(|v| !v.trim().is_empty());
+
+        let client_certificate_path = env_var(CLIENT_CERTIFICATE_PATH_ENV)
+            .ok()
+            .filter(|v| !v.trim().is_empty());
+        let client_certificate_password = env_var(CLIENT_CERTIFICATE_PASSWORD_ENV).ok();
+        let client_secret_keyvault_uri = env_var(CLIENT_SECRET_KEYVAULT_URI_ENV)
+            .ok()
+            .or_else(|| self.global.client_secret_keyvault_uri.clone())
+            .filter(|v| !v.trim().is_empty());
+        if let Some(uri) = &client_secret_keyvault_uri {
+            if !uri.starts_with("https://") || !uri.contains("/secrets/") {
+                return Err(format!(
+                    "{CLIENT_SECRET_KEYVAULT_URI_ENV} must be a Key Vault secret URI like \
+                     https://<vault>.vault.azure.net/secrets/<name>, got '{uri}'"
+                )
+                .into());
+            }
+        }
+
+        let setting = |name: &str, file: &Option<String>| {
+            env_var(name)
+                .ok()
+                .or_else(|| file.clone())
+                .filter(|v| !v.trim().is_empty())
+        };
+        let proxy = ProxySettings {
+            http_proxy: setting("HTTP_PROXY", &self.global.http_proxy),
+            https_proxy: setting("HTTPS_PROXY", &self.global.https_proxy),
+            username: setting("PROXY_USERNAME", &self.global.proxy_username),
+            password: setting("PROXY_PASSWORD", &self.global.proxy_password),
+            no_proxy: setting("NO_PROXY", &self.global.no_proxy),
+        };
+        proxy.validate()?;
+
+        // Offline, nothing is sent to the service, so credentials are optional
+        let metadata_file = setting("METADATA_FILE", &self.global.metadata_file);
+
+        // App registration credentials are only required for client credentials
+        let tenant_id = selected
+            .and_then(|e| e.tenant_id.clone())
+            .or_else(|| env_var("TENANT_ID").ok());
+        let client_id = selected
+            .and_then(|e| e.client_id.clone())
+            .or_else(|| env_var("CLIENT_ID").ok());
+        let (tenant_id, client_id, client_secret) =
+            if auth_mode == AuthMode::ClientCredentials && metadata_file.is_none() {
+                if tenant_id.is_none() {
+                    missing.push("TENANT_ID environment variable is required".to_string());
+                }
+                if client_id.is_none() {
+                    missing.push("CLIENT_ID environment variable is required".to_string());
+                }
+                let client_secret = match selected.and_then(|e| e.client_secret.clone()) {
+                    Some(secret) => Some(secret),
+                    None => resolve_credential(
+                        client_id.as_deref().unwrap_or_default(),
+                        client_certificate_path.is_some(),
+                        client_secret_keyvault_uri.is_some(),
+                        keychain_reader,
+                    )
+                    .unwrap_or_else(|e| {
+                        missing.push(e.to_string());
+                        None
+                    }),
+                };
+                (
+                    tenant_id.unwrap_or_default(),
+                    client_id.unwrap_or_default(),
+                    client_secret,
+                )
+            } else {
+                (
+                    tenant_id.unwrap_or_default(),
+                    client_id.unwrap_or_default(),
+                    None,
+                )
+            };
 
         // Optional env vars with fallback to config file
-        let endpoint = env::var("ENDPOINT").unwrap_or_else(|_| self.global.endpoint.clone());
+        let endpoint = match selected {
+            Some(e) => e.endpoint.clone(),
+            None => env_var("ENDPOINT").unwrap_or_else(|_| self.global.endpoint.clone()),
+        };
         if endpoint.is_empty() {
-            return Err("ENDPOINT environment variable or config endpoint is required".into());
+            missing
+                .push("ENDPOINT environment variable or config endpoint is required".to_string());
+        }
+        match missing.len() {
+            0 => {}
+            1 => return Err(missing.remove(0).into()),
+            _ => {
+                return Err(format!(
+                    "Configuration is incomplete ({} problems; variables may also be set with a {} prefix):\n  - {}",
+                    missing.len(),
+                    ENV_PREFIX,
+                    missing.join("\n  - ")
+                )
+                .into())
+            }
         }
 
-        let product = env::var("PRODUCT")
-            .ok()
-            .and_then(|p| match p.to_lowercase().as_str() {
-                "dataverse" => Some(ProductType::Dataverse),
-                "finops" | "fno" | "fo" => Some(ProductType::Finops),
-                _ => None,
-            })
-            .unwrap_or_else(|| self.global.product.clone());
+        let product = match selected.and_then(|e| e.product.clone()) {
+            Some(product) => product,
+            None => match env_var("PRODUCT") {
+                Ok(product) => product.parse::<ProductType>()?,
+                Err(_) => self.global.product.clone(),
+            },
+        };
 
         let obs = self.observability.clone().unwrap_or_default();
+
+        let page_size = env_var("PAGE_SIZE")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .or(self.global.page_size)
+            .unwrap_or(500);
+        let concurrency = env_var("CONCURRENCY")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .or(self.global.concurrency)
+            .unwrap_or(4);
+        let max_retries = env_var("MAX_RETRIES")
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .or(self.global.max_retries)
+            .unwrap_or(3);
+        let auth_max_retries = env_var("AUTH_MAX_RETRIES")
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .or(self.global.auth_max_retries)
+            .unwrap_or(DEFAULT_AUTH_MAX_RETRIES);
+        let retry_delay_ms = env_var("RETRY_DELAY_MS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .or(self.global.retry_delay_ms)
+            .unwrap_or(1000);
+        let log_level = env_var("LOG_LEVEL")
+            .ok()
+            .or(obs.log_level)
+            .unwrap_or_else(|| "info".to_string());
         let delta = self.delta.clone().unwrap_or_default();
 
         // Auth type (azure or adfs)
-        let auth_type = env::var("AUTH_TYPE").unwrap_or_else(|_| "azure".to_string());
+        let auth_type = env_var("AUTH_TYPE").unwrap_or_else(|_| "azure".to_string());
 
         // Custom token URL (for ADFS)
-        let token_url = env::var("TOKEN_URL").ok();
+        let token_url = env_var("TOKEN_URL").ok();
 
         // Resource/audience (for ADFS)
-        let resource = env::var("RESOURCE").ok();
+        let resource = env_var("RESOURCE").ok();
 
         // Skip SSL verification (for self-signed certificates)
-        let insecure_ssl = env::var("INSECURE_SSL")
+        let insecure_ssl = env_var("INSECURE_SSL")
             .map(|v| v.to_lowercase() == "true" || v == "1")
             .unwrap_or(false);
+        let ca_cert_path = setting("CA_CERT_PATH", &self.global.ca_cert_path);
+        let token_cache_path = setting("TOKEN_CACHE_PATH", &self.global.token_cache_path);
+        let record_dir = setting("RECORD_DIR", &self.global.record_dir);
+        let replay_dir = setting("REPLAY_DIR", &self.global.replay_dir);
+        if record_dir.is_some() && replay_dir.is_some() {
+            return Err("Configure either RECORD_DIR or REPLAY_DIR, not both".into());
+        }
+        let compression = parse_bool_env("COMPRESSION", self.global.compression.unwrap_or(true))?;
+        let rewrite_next_link_host = parse_bool_env(
+            "REWRITE_NEXT_LINK_HOST",
+            self.global.rewrite_next_link_host.unwrap_or(false),
+        )?;
+        let user_agent_suffix = setting("USER_AGENT_SUFFIX", &self.global.user_agent_suffix)
+            .map(|suffix| suffix.trim().to_string());
+        // A newline or other control character would break the header
+        if user_agent_suffix
+            .as_deref()
+            .is_some_and(|suffix| suffix.contains(char::is_control))
+        {
+            return Err("USER_AGENT_SUFFIX must not contain control characters".into());
+        }
+        // Not `LANGUAGE`, which gettext reads as a list such as `en_US:en`
+        let language = setting("LABEL_LANGUAGE", &self.global.language)
+            .map(|language| {
+                parse_language_tag(&language).map_err(|e| format!("LABEL_LANGUAGE: {}", e))
+            })
+            .transpose()?;
 
         // Metadata cache TTL in seconds (default: 900 = 15 minutes)
-        let metadata_cache_ttl_secs = env::var("METADATA_CACHE_TTL")
+        let metadata_cache_ttl_secs = env_var("METADATA_CACHE_TTL")
             .ok()
             .and_then(|v| v.parse::<u64>().ok())
             .unwrap_or(900);
 
+        // Client-side rate limiting (Dataverse allows 6000 requests per 5 minutes)
+        let max_requests_per_minute = env_var("MAX_REQUESTS_PER_MINUTE")
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .or(self.global.max_requests_per_minute);
+        let max_concurrent_requests = env_var("MAX_CONCURRENT_REQUESTS")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .or(self.global.max_concurrent_requests);
+
+        // Cap on how long a throttled request waits before retrying
+        let max_retry_wait_secs = env_var("MAX_RETRY_WAIT_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .or(self.global.max_retry_wait_secs)
+            .unwrap_or(60);
+
+        // Stop hammering an environment that is down, e.g. for servicing
+        let circuit_breaker_threshold = env_var("CIRCUIT_BREAKER_THRESHOLD")
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .or(self.global.circuit_breaker_threshold)
+            .unwrap_or(DEFAULT_CIRCUIT_THRESHOLD);
+        let circuit_breaker_cooldown_secs = env_var("CIRCUIT_BREAKER_COOLDOWN_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .or(self.global.circuit_breaker_cooldown_secs)
+            .filter(|secs| *secs > 0)
+            .unwrap_or(DEFAULT_CIRCUIT_COOLDOWN.as_secs());
+        let request_timeout_secs = env_var("REQUEST_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .or(self.global.request_timeout_secs)
+            .filter(|secs| *secs > 0)
+            .unwrap_or(DEFAULT_REQUEST_TIMEOUT_SECS);
+        let shutdown_grace_secs = env_var("SHUTDOWN_GRACE_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .or(self.global.shutdown_grace_secs)
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS);
+        let session_idle_secs = env_var("SESSION_IDLE_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .or(self.global.session_idle_secs)
+            .unwrap_or(DEFAULT_SESSION_IDLE_SECS);
+        let config_reload_secs = env_var("CONFIG_RELOAD_SECS")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .or(self.global.config_reload_secs)
+            .unwrap_or(0);
+
+        // Writes must be enabled explicitly
+        let read_only = match selected.and_then(|e| e.read_only) {
+            Some(read_only) => read_only,
+            None => parse_bool_env("READ_ONLY", self.global.read_only.unwrap_or(true))?,
+        };
+        let dry_run_all_writes = parse_bool_env(
+            "DRY_RUN_ALL_WRITES",
+            self.global.dry_run_all_writes.unwrap_or(false),
+        )?;
+
+        // Entity access policy (comma-separated in env vars)
+        let allowed_entities = selected
+            .and_then(|e| e.allowed_entities.clone())
+            .or_else(|| parse_list_env("ALLOWED_ENTITIES"))
+            .or_else(|| self.global.allowed_entities.clone())
+            .unwrap_or_default();
+        let denied_entities = selected
+            .and_then(|e| e.denied_entities.clone())
+            .or_else(|| parse_list_env("DENIED_ENTITIES"))
+            .or_else(|| self.global.denied_entities.clone())
+            .unwrap_or_default();
+
+        // Keep oversized results out of the model's context
+        let max_response_chars = env_var("MAX_RESPONSE_CHARS")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .or(self.global.max_response_chars)
+            .unwrap_or(100_000);
+        // Unset falls back to the entity's own, then the built-in limits
+        let default_top = env_var("DEFAULT_TOP")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .or(self.global.default_top)
+            .filter(|top| *top > 0);
+        let max_top = env_var("MAX_TOP")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .or(self.global.max_top)
+            .filter(|top| *top > 0);
+
+        let default_format = match env_var("DEFAULT_FORMAT") {
+            Ok(format) => format.parse::<OutputFormat>()?,
+            Err(_) => self.global.default_format.unwrap_or_default(),
+        };
+        let annotations = match env_var("ANNOTATIONS") {
+            Ok(annotations) => annotations.parse::<AnnotationMode>()?,
+            Err(_) => self.global.annotations.unwrap_or_default(),
+        };
+
+        let default_company = selected
+            .and_then(|e| e.default_company.clone())
+            .or_else(|| env_var("DEFAULT_COMPANY").ok())
+            .or_else(|| self.global.default_company.clone())
+            .map(|company| company.trim().to_string())
+            .filter(|company| !company.is_empty());
+
+        // Attribute Dataverse requests to a business user instead of the app
+        let impersonate_user_id = env_var("IMPERSONATE_USER_ID")
+            .ok()
+            .or_else(|| self.global.impersonate_user_id.clone())
+            .map(|user_id| user_id.trim().trim_matches(['{', '}']).to_string())
+            .filter(|user_id| !user_id.is_empty());
+        if let Some(user_id) = &impersonate_user_id {
+            if !is_guid(user_id) {
+                return Err(
+                    format!("IMPERSONATE_USER_ID must be a GUID, got '{}'", user_id).into(),
+                );
+            }
+        }
+        let impersonation_header = match env_var("IMPERSONATION_HEADER") {
+            Ok(header) => header.parse::<CallerIdHeader>()?,
+            Err(_) => self.global.impersonation_header.unwrap_or_default(),
+        };
+
+        let download_dir = env_var("DOWNLOAD_DIR")
+            .ok()
+            .or_else(|| self.global.download_dir.clone())
+            .filter(|dir| !dir.trim().is_empty())
+            .unwrap_or_else(|| {
+                env::temp_dir()
+                    .join("d365-odata-mcp")
+                    .to_string_lossy()
+                    .into_owned()
+            });
+        let max_download_bytes = env_var("MAX_DOWNLOAD_BYTES")
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+            .or(self.global.max_download_bytes)
+            .unwrap_or(100 * 1024 * 1024);
+
+        let upload_dir = env_var("UPLOAD_DIR")
+            .ok()
+            .or_else(|| self.global.upload_dir.clone())
+            .filter(|dir| !dir.trim().is_empty());
+
+        let export_dir = env_var("EXPORT_DIR")
+            .ok()
+            .or_else(|| self.global.export_dir.clone())
+            .filter(|dir| !dir.trim().is_empty());
+
+        // Anyone who can reach the server can send the roles header, so it
+        // must not sit next to API keys that are meant to keep callers out
+        if self.tool_permissions.as_ref().is_some_and(|permissions| {
+            permissions.roles_header.is_some() && !permissions.api_keys.is_empty()
+        }) {
+            return Err("[tool_permissions]: set either api_keys or roles_header, not both; \
+                        a roles header is only safe behind a gateway that authenticates every caller"
+                .into());
+        }
+
+        let history_size = env_var("HISTORY_SIZE")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .or(self.global.history_size)
+            .unwrap_or(50);
+        let history_excluded_entities = parse_list_env("HISTORY_EXCLUDED_ENTITIES")
+            .or_else(|| self.global.history_excluded_entities.clone())
+            .unwrap_or_default();
+        let compare_ignored_fields = parse_list_env("COMPARE_IGNORED_FIELDS")
+            .or_else(|| self.global.compare_ignored_fields.clone())
+            .unwrap_or_else(|| {
+                DEFAULT_COMPARE_IGNORED_FIELDS
+                    .iter()
+                    .map(|field| field.to_string())
+                    .collect()
+            });
+        let distinct_values_limit = env_var("DISTINCT_VALUES_LIMIT")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .or(self.global.distinct_values_limit)
+            .unwrap_or(10_000)
+            .max(1);
+
+        let test_connection_on_startup = parse_bool_env(
+            "TEST_CONNECTION_ON_STARTUP",
+            self.global.test_connection_on_startup.unwrap_or(false),
+        )?;
+
         Ok(RuntimeConfig {
             product,
             endpoint,
             tenant_id,
             client_id,
+            auth_mode,
+            managed_identity_client_id,
+            cloud,
+            authority_host,
             client_secret,
+            client_certificate_path,
+            client_certificate_password,
+            client_secret_keyvault_uri,
+            token_cache_path,
+            record_dir,
+            replay_dir,
+            proxy,
             auth_type,
             token_url,
             resource,
             insecure_ssl,
-            page_size: self.global.page_size.unwrap_or(500),
-            concurrency: self.global.concurrency.unwrap_or(4),
-            max_retries: self.global.max_retries.unwrap_or(3),
-            retry_delay_ms: self.global.retry_delay_ms.unwrap_or(1000),
-            log_level: obs.log_level.unwrap_or_else(|| "info".to_string()),
+            ca_cert_path,
+            compression,
+            rewrite_next_link_host,
+            user_agent_suffix,
+            language,
+            page_size,
+            concurrency,
+            max_retries,
+            auth_max_retries,
+            retry_delay_ms,
+            log_level,
             enable_tracing: obs.enable_tracing.unwrap_or(false),
             delta_storage_path: delta
                 .storage_path
                 .unwrap_or_else(|| "./delta_state.json".to_string()),
             entities: self.entities.clone().unwrap_or_default(),
             metadata_cache_ttl_secs,
+            max_requests_per_minute,
+            max_concurrent_requests,
+            max_retry_wait_secs,
+            circuit_breaker_threshold,
+            circuit_breaker_cooldown_secs,
+            request_timeout_secs,
+            shutdown_grace_secs,
+            session_idle_secs,
+            config_reload_secs,
+            read_only,
+            dry_run_all_writes,
+            allowed_entities,
+            denied_entities,
+            max_response_chars,
+            default_top,
+            max_top,
+            default_format,
+            annotations,
+            default_company,
+            impersonate_user_id,
+            impersonation_header,
+            download_dir,
+            max_download_bytes,
+            upload_dir,
+            export_dir,
+            metadata_file,
+            history_size,
+            history_excluded_entities,
+            compare_ignored_fields,
+            distinct_values_limit,
+            test_connection_on_startup,
+            tool_permissions: self.tool_permissions.clone(),
+            environment: environment.map(String::from),
+            production: selected.is_some_and(|e| e.production),
+            environments: self
+                .environments
+                .iter()
+                .map(|(name, e)| EnvironmentSummary {
+                    name: name.clone(),
+                    endpoint: e.endpoint.clone(),
+                    product: e
+                        .product
+                        .clone()
+                        .unwrap_or_else(|| self.global.product.clone()),
+                    production: e.production,
+                })
+                .collect(),
         })
     }
+
+    /// Comma-separated environment names, for error messages
+    fn environment_names(&self) -> String {
+        if self.environments.is_empty() {
+            return "(none)".to_string();
+        }
+        self.environments
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Resolve the client secret, or `None` when a certificate is configured or
+/// the secret is read from Key Vault later. Exactly one of a secret and a
+/// certificate must be set, unless Key Vault supplies the secret (the
+/// certificate then only reads the vault).
+fn resolve_credential<F>(
+    client_id: &str,
+    certificate_configured: bool,
+    key_vault_configured: bool,
+    keychain_reader: F,
+) -> Result<Option<String>, Box<dyn std::error::Error>>
+where
+    F: FnOnce(&str, &str) -> Result<String, Box<dyn std::error::Error>>,
+{
+    let secret_configured =
+        env_var(CLIENT_SECRET_ENV).is_ok() || parse_bool_env(USE_KEYCHAIN_ENV, false)?;
+
+    if key_vault_configured {
+        if secret_configured {
+            return Err(format!(
+                "Configure either {CLIENT_SECRET_ENV} (or {USE_KEYCHAIN_ENV}) or \
+                 {CLIENT_SECRET_KEYVAULT_URI_ENV}, not both"
+            )
+            .into());
+        }
+        return Ok(None);
+    }
+
+    match (secret_configured, certificate_configured) {
+        (true, true) => Err(format!(
+            "Configure either {CLIENT_SECRET_ENV} (or {USE_KEYCHAIN_ENV}) or \
+             {CLIENT_CERTIFICATE_PATH_ENV}, not both"
+        )
+        .into()),
+        (false, true) => Ok(None),
+        (true, false) => resolve_client_secret(client_id, keychain_reader).map(Some),
+        (false, false) => Err(format!(
+            "{CLIENT_SECRET_ENV} or {CLIENT_CERTIFICATE_PATH_ENV} environment variable is required"
+        )
+        .into()),
+    }
 }
 
 fn resolve_client_secret<F>(
@@ -234,12 +1198,12 @@ where
     F: FnOnce(&str, &str) -> Result<String, Box<dyn std::error::Error>>,
 {
     if !parse_bool_env(USE_KEYCHAIN_ENV, false)? {
-        return env::var(CLIENT_SECRET_ENV)
+        return env_var(CLIENT_SECRET_ENV)
             .map_err(|_| format!("{CLIENT_SECRET_ENV} environment variable is required").into());
     }
 
     let service = required_non_empty_env(CLIENT_SECRET_KEYCHAIN_SERVICE_ENV)?;
-    let account = env::var(CLIENT_SECRET_KEYCHAIN_ACCOUNT_ENV).unwrap_or_else(|_| client_id.into());
+    let account = env_var(CLIENT_SECRET_KEYCHAIN_ACCOUNT_ENV).unwrap_or_else(|_| client_id.into());
 
     if account.trim().is_empty() {
         return Err(format!(
@@ -251,8 +1215,16 @@ where
     keychain_reader(&service, &account)
 }
 
+/// Environment variable `name`, preferring its `D365_`-prefixed form
+fn env_var(name: &str) -> Result<String, env::VarError> {
+    match env::var(format!("{ENV_PREFIX}{name}")) {
+        Err(env::VarError::NotPresent) => env::var(name),
+        value => value,
+    }
+}
+
 fn parse_bool_env(name: &str, default: bool) -> Result<bool, Box<dyn std::error::Error>> {
-    match env::var(name) {
+    match env_var(name) {
         Ok(value) => match value.trim().to_lowercase().as_str() {
             "true" | "1" | "yes" | "on" => Ok(true),
             "false" | "0" | "no" | "off" => Ok(false),
@@ -266,8 +1238,20 @@ fn parse_bool_env(name: &str, default: bool) -> Result<bool, Box<dyn std::error:
     }
 }
 
+/// Parse a comma-separated list, or `None` when the variable is unset
+fn parse_list_env(name: &str) -> Option<Vec<String>> {
+    env_var(name).ok().map(|value| {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect()
+    })
+}
+
 fn required_non_empty_env(name: &str) -> Result<String, Box<dyn std::error::Error>> {
-    match env::var(name) {
+    match env_var(name) {
         Ok(value) if !value.trim().is_empty() => Ok(value),
         Ok(_) | Err(env::VarError::NotPresent) => {
             Err(format!("{name} environment variable is required").into())
@@ -299,6 +1283,8 @@ fn read_client_secret_from_keychain(
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(feature = "mcp-server")]
+    use crate::mcp::D365McpServer;
     use std::ffi::OsString;
     use std::sync::{Mutex, OnceLock};
 
@@ -313,23 +1299,86 @@ mod tests {
         "RESOURCE",
         "INSECURE_SSL",
         "METADATA_CACHE_TTL",
+        "MAX_REQUESTS_PER_MINUTE",
+        "MAX_CONCURRENT_REQUESTS",
+        "MAX_RETRY_WAIT_SECS",
+        "CIRCUIT_BREAKER_THRESHOLD",
+        "CIRCUIT_BREAKER_COOLDOWN_SECS",
+        "REQUEST_TIMEOUT_SECS",
+        "AUTH_MAX_RETRIES",
+        "SHUTDOWN_GRACE_SECS",
+        "SESSION_IDLE_SECS",
         USE_KEYCHAIN_ENV,
         CLIENT_SECRET_KEYCHAIN_SERVICE_ENV,
         CLIENT_SECRET_KEYCHAIN_ACCOUNT_ENV,
+        CLIENT_CERTIFICATE_PATH_ENV,
+        CLIENT_CERTIFICATE_PASSWORD_ENV,
+        CLIENT_SECRET_KEYVAULT_URI_ENV,
+        "HTTP_PROXY",
+        "HTTPS_PROXY",
+        "PROXY_USERNAME",
+        "PROXY_PASSWORD",
+        "NO_PROXY",
+        "CA_CERT_PATH",
+        "TOKEN_CACHE_PATH",
+        "RECORD_DIR",
+        "REPLAY_DIR",
+        "COMPRESSION",
+        "REWRITE_NEXT_LINK_HOST",
+        "USER_AGENT_SUFFIX",
+        "LABEL_LANGUAGE",
+        "METADATA_FILE",
+        "AUTH_MODE",
+        "MANAGED_IDENTITY_CLIENT_ID",
+        "AZURE_CLOUD",
+        "AUTHORITY_HOST",
+        "READ_ONLY",
+        "DRY_RUN_ALL_WRITES",
+        "ALLOWED_ENTITIES",
+        "DENIED_ENTITIES",
+        "MAX_RESPONSE_CHARS",
+        "DEFAULT_TOP",
+        "MAX_TOP",
+        "DEFAULT_FORMAT",
+        "ANNOTATIONS",
+        "DEFAULT_COMPANY",
+        "IMPERSONATE_USER_ID",
+        "IMPERSONATION_HEADER",
+        "DOWNLOAD_DIR",
+        "MAX_DOWNLOAD_BYTES",
+        "UPLOAD_DIR",
+        "EXPORT_DIR",
+        "HISTORY_SIZE",
+        "HISTORY_EXCLUDED_ENTITIES",
+        "COMPARE_IGNORED_FIELDS",
+        "DISTINCT_VALUES_LIMIT",
+        "CONFIG_RELOAD_SECS",
+        "TEST_CONNECTION_ON_STARTUP",
+        "PAGE_SIZE",
+        "CONCURRENCY",
+        "MAX_RETRIES",
+        "RETRY_DELAY_MS",
+        "LOG_LEVEL",
+        ENVIRONMENT_ENV,
     ];
 
     struct EnvGuard {
-        saved: Vec<(&'static str, Option<OsString>)>,
+        saved: Vec<(String, Option<OsString>)>,
     }
 
     impl EnvGuard {
         fn new(vars: &[(&str, &str)]) -> Self {
-            let saved = RUNTIME_ENV_VARS
+            let keys = RUNTIME_ENV_VARS
                 .iter()
-                .map(|key| (*key, env::var_os(key)))
+                .flat_map(|key| [key.to_string(), format!("{ENV_PREFIX}{key}")]);
+            let saved = keys
+                .map(|key| {
+                    let value = env::var_os(&key);
+                    (key, value)
+                })
                 .collect::<Vec<_>>();
 
-            for key in RUNTIME_ENV_VARS {
+            for (key, _) in &saved {
                 env::remove_var(key);
             }
 
@@ -372,10 +1421,14 @@ mod tests {
                 concurrency: Some(4),
                 max_retries: Some(3),
                 retry_delay_ms: Some(1000),
+                ..Default::default()
             },
+            default_environment: None,
+            environments: BTreeMap::new(),
             observability: Some(ObservabilityConfig::default()),
             delta: Some(DeltaConfig::default()),
             entities: None,
+            tool_permissions: None,
         }
     }
 
@@ -414,53 +1467,179 @@ mod tests {
         let toml_str = r#"product = "dataverse""#;
         let test: Test = toml::from_str(toml_str).unwrap();
         assert_eq!(test.product, ProductType::Dataverse);
+
+        let toml_str = r#"product = "FnO""#;
+        let test: Test = toml::from_str(toml_str).unwrap();
+        assert_eq!(test.product, ProductType::Finops);
     }
 
     #[test]
-    fn runtime_uses_client_secret_when_use_keychain_is_missing() {
-        let mut vars = base_env();
-        vars.push((CLIENT_SECRET_ENV, "direct-secret"));
+    fn product_type_errors_list_the_valid_values() {
+        assert_eq!(
+            " Dataverse ".parse::<ProductType>().unwrap(),
+            ProductType::Dataverse
+        );
+        let err = "crm".parse::<ProductType>().unwrap_err();
+        assert!(err.contains("'crm'"), "{err}");
+        assert!(err.contains("'dataverse' or 'finops'"), "{err}");
+    }
+
+    #[test]
+    fn runtime_prefixed_env_vars_win_over_bare_names_and_file() {
+        let mut config = test_config();
+        config.global.max_retries = Some(5);
+        let vars = [
+            ("D365_TENANT_ID", "tenant-id"),
+            ("D365_CLIENT_ID", "client-id"),
+            ("D365_CLIENT_SECRET", "prefixed-secret"),
+            (CLIENT_SECRET_ENV, "bare-secret"),
+            ("ENDPOINT", "https://bare.operations.dynamics.com/data/"),
+            (
+                "D365_ENDPOINT",
+                "https://prefixed.operations.dynamics.com/data/",
+            ),
+            ("D365_PRODUCT", "FinOps"),
+            ("D365_MAX_RETRIES", "7"),
+            ("PAGE_SIZE", "250"),
+        ];
 
         with_env(&vars, || {
-            let runtime = test_config()
+            let runtime = config
                 .to_runtime_with_keychain_reader(unused_keychain_reader)
                 .unwrap();
+            assert_eq!(
+                runtime.endpoint,
+                "https://prefixed.operations.dynamics.com/data/"
+            );
+            assert_eq!(runtime.client_secret.as_deref(), Some("prefixed-secret"));
+            assert_eq!(runtime.product, ProductType::Finops);
+            assert_eq!(runtime.max_retries, 7);
+            assert_eq!(runtime.page_size, 250);
+            // Unset in the environment, so the file value stays
+            assert_eq!(runtime.retry_delay_ms, 1000);
+        });
+
+        // The prefixed value is used even when the bare one is valid
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_ENV, "direct-secret"));
+        vars.push(("D365_PRODUCT", "crm"));
+        with_env(&vars, || {
+            let err = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("Unknown product"), "{err}");
+        });
+    }
+
+    #[test]
+    fn runtime_reports_every_missing_setting_at_once() {
+        let mut config = test_config();
+        config.global.endpoint = String::new();
+
+        with_env(&[], || {
+            let err = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("4 problems"), "{err}");
+            assert!(err.contains("D365_"), "{err}");
+            for setting in ["TENANT_ID", "CLIENT_ID", CLIENT_SECRET_ENV, "ENDPOINT"] {
+                assert!(err.contains(&format!("  - {setting}")), "{err}");
+            }
+        });
 
-            assert_eq!(runtime.client_secret, "direct-secret");
+        // Offline from a snapshot, only the endpoint is still needed
+        with_env(&[("METADATA_FILE", "snapshots/metadata.xml")], || {
+            let err = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap_err()
+                .to_string();
+            assert!(err.starts_with("ENDPOINT"), "{err}");
         });
     }
 
     #[test]
-    fn runtime_uses_client_secret_when_use_keychain_is_false() {
+    fn only_connection_changes_need_a_new_client() {
         let mut vars = base_env();
         vars.push((CLIENT_SECRET_ENV, "direct-secret"));
-        vars.push((USE_KEYCHAIN_ENV, "false"));
 
         with_env(&vars, || {
             let runtime = test_config()
                 .to_runtime_with_keychain_reader(unused_keychain_reader)
                 .unwrap();
-
-            assert_eq!(runtime.client_secret, "direct-secret");
+            let policy_only = RuntimeConfig {
+                read_only: !runtime.read_only,
+                allowed_entities: vec!["accounts".to_string()],
+                config_reload_secs: 5,
+                ..runtime.clone()
+            };
+            assert!(runtime.same_client_settings(&policy_only));
+
+            for changed in [
+                RuntimeConfig {
+                    endpoint: "https://other.crm.dynamics.com/api/data/v9.2/".to_string(),
+                    ..policy_only.clone()
+                },
+                RuntimeConfig {
+                    client_secret: Some("rotated-secret".to_string()),
+                    ..policy_only.clone()
+                },
+                RuntimeConfig {
+                    max_retries: 9,
+                    ..policy_only
+                },
+            ] {
+                assert!(!runtime.same_client_settings(&changed));
+            }
         });
     }
 
     #[test]
-    fn runtime_uses_keychain_without_client_secret_when_enabled() {
+    fn runtime_uses_client_secret_when_use_keychain_is_missing() {
         let mut vars = base_env();
-        vars.push((USE_KEYCHAIN_ENV, "true"));
-        vars.push((CLIENT_SECRET_KEYCHAIN_SERVICE_ENV, "test-service"));
+        vars.push((CLIENT_SECRET_ENV, "direct-secret"));
 
         with_env(&vars, || {
             let runtime = test_config()
-                .to_runtime_with_keychain_reader(|service, account| {
-                    assert_eq!(service, "test-service");
-                    assert_eq!(account, "client-id");
-                    Ok("keychain-secret".to_string())
-                })
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
                 .unwrap();
 
-            assert_eq!(runtime.client_secret, "keychain-secret");
+            assert_eq!(runtime.client_secret.as_deref(), Some("direct-secret"));
+        });
+    }
+
+    #[test]
+    fn runtime_uses_client_secret_when_use_keychain_is_false() {
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_ENV, "direct-secret"));
+        vars.push((USE_KEYCHAIN_ENV, "false"));
+
+        with_env(&vars, || {
+            let runtime = test_config()
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+
+            assert_eq!(runtime.client_secret.as_deref(), Some("direct-secret"));
+        });
+    }
+
+    #[test]
+    fn runtime_uses_keychain_without_client_secret_when_enabled() {
+        let mut vars = base_env();
+        vars.push((USE_KEYCHAIN_ENV, "true"));
+        vars.push((CLIENT_SECRET_KEYCHAIN_SERVICE_ENV, "test-service"));
+
+        with_env(&vars, || {
+            let runtime = test_config()
+                .to_runtime_with_keychain_reader(|service, account| {
+                    assert_eq!(service, "test-service");
+                    assert_eq!(account, "client-id");
+                    Ok("keychain-secret".to_string())
+                })
+                .unwrap();
+
+            assert_eq!(runtime.client_secret.as_deref(), Some("keychain-secret"));
         });
     }
 
@@ -529,6 +1708,179 @@ mod tests {
         });
     }
 
+    #[test]
+    fn key_vault_uri_replaces_the_client_secret() {
+        let mut vars = base_env();
+        vars.push((
+            CLIENT_SECRET_KEYVAULT_URI_ENV,
+            "https://kv.vault.azure.net/secrets/d365",
+        ));
+        vars.push((CLIENT_CERTIFICATE_PATH_ENV, "/certs/bootstrap.pem"));
+
+        with_env(&vars, || {
+            let runtime = test_config()
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+            assert_eq!(runtime.client_secret, None);
+            assert_eq!(
+                runtime.client_secret_keyvault_uri.as_deref(),
+                Some("https://kv.vault.azure.net/secrets/d365")
+            );
+            assert_eq!(
+                runtime.client_certificate_path.as_deref(),
+                Some("/certs/bootstrap.pem")
+            );
+        });
+
+        vars.push((CLIENT_SECRET_ENV, "also-a-secret"));
+        with_env(&vars, || {
+            let err = test_config()
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("not both"), "{err}");
+        });
+
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_KEYVAULT_URI_ENV, "kv/d365"));
+        with_env(&vars, || {
+            let err = test_config()
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("Key Vault secret URI"), "{err}");
+        });
+    }
+
+    #[test]
+    fn network_settings_come_from_env_and_file() {
+        let mut config = test_config();
+        config.global.https_proxy = Some("http://file-proxy:8080".to_string());
+        config.global.no_proxy = Some("localhost".to_string());
+        config.global.ca_cert_path = Some("/etc/ssl/file-ca.pem".to_string());
+        config.global.compression = Some(false);
+        config.global.rewrite_next_link_host = Some(true);
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_ENV, "secret"));
+        vars.push(("D365_HTTPS_PROXY", "http://proxy.corp.local:3128"));
+        vars.push(("PROXY_USERNAME", "svc-d365"));
+        vars.push(("PROXY_PASSWORD", "proxy-secret"));
+        vars.push(("CA_CERT_PATH", "/etc/ssl/corp-ca.pem"));
+
+        with_env(&vars, || {
+            let runtime = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+            assert_eq!(
+                runtime.ca_cert_path.as_deref(),
+                Some("/etc/ssl/corp-ca.pem")
+            );
+            assert!(!runtime.compression);
+            assert!(runtime.rewrite_next_link_host);
+            assert_eq!(
+                runtime.proxy,
+                ProxySettings {
+                    http_proxy: None,
+                    https_proxy: Some("http://proxy.corp.local:3128".to_string()),
+                    username: Some("svc-d365".to_string()),
+                    password: Some("proxy-secret".to_string()),
+                    no_proxy: Some("localhost".to_string()),
+                }
+            );
+        });
+
+        vars.push(("HTTP_PROXY", "http://[bad"));
+        with_env(&vars, || {
+            let err = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("Invalid proxy URL"), "{err}");
+        });
+    }
+
+    #[test]
+    fn redacted_runtime_masks_secrets() {
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_ENV, "do-not-print"));
+        vars.push(("HTTPS_PROXY", "http://proxy:3128"));
+        vars.push(("PROXY_PASSWORD", "proxy-secret"));
+
+        with_env(&vars, || {
+            let runtime = test_config()
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap()
+                .redacted();
+            assert_eq!(runtime.client_secret.as_deref(), Some("********"));
+            assert_eq!(runtime.client_certificate_password, None);
+            assert_eq!(runtime.client_id, "client-id");
+            assert_eq!(runtime.proxy.password.as_deref(), Some("********"));
+            assert!(!format!("{:?}", runtime).contains("do-not-print"));
+            assert!(!format!("{:?}", runtime).contains("proxy-secret"));
+        });
+    }
+
+    #[test]
+    fn tool_permissions_are_read_and_their_api_keys_masked() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [tool_permissions]
+            default = "deny"
+            stdio_role = "admin"
+            [tool_permissions.tools]
+            "upsert_*" = ["writer"]
+            [tool_permissions.api_keys]
+            do-not-print = ["reader"]
+            "#,
+        )
+        .unwrap();
+        config.global = test_config().global;
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_ENV, "secret"));
+
+        with_env(&vars, || {
+            let runtime = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+            let permissions = runtime.tool_permissions.as_ref().unwrap();
+            assert_eq!(permissions.default, crate::config::UnlistedTools::Deny);
+            assert_eq!(
+                permissions.required_roles("upsert_record"),
+                Some(&["writer".to_string()][..])
+            );
+            assert_eq!(permissions.stdio_roles(), vec!["admin"]);
+            assert!(!format!("{:?}", runtime.redacted()).contains("do-not-print"));
+        });
+    }
+
+    #[test]
+    fn tool_permissions_take_api_keys_or_a_roles_header_not_both() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [tool_permissions]
+            roles_header = "X-Gateway-Roles"
+            [tool_permissions.api_keys]
+            reader-key = ["reader"]
+            "#,
+        )
+        .unwrap();
+        config.global = test_config().global;
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_ENV, "secret"));
+
+        with_env(&vars, || {
+            let error = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap_err();
+            assert!(
+                error
+                    .to_string()
+                    .starts_with("[tool_permissions]: set either api_keys or roles_header"),
+                "{error}"
+            );
+        });
+    }
+
     #[test]
     fn keychain_lookup_error_does_not_include_client_secret() {
         let mut vars = base_env();
@@ -563,4 +1915,448 @@ mod tests {
         assert!(err.contains("mock-account"));
         assert!(!err.contains("client-secret"));
     }
+
+    #[test]
+    fn runtime_rate_limits_prefer_env_over_file() {
+        let mut config = test_config();
+        config.global.max_requests_per_minute = Some(600);
+        config.global.max_concurrent_requests = Some(8);
+
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_ENV, "direct-secret"));
+        vars.push(("MAX_REQUESTS_PER_MINUTE", "1200"));
+
+        with_env(&vars, || {
+            let runtime = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+
+            assert_eq!(runtime.max_requests_per_minute, Some(1200));
+            assert_eq!(runtime.max_concurrent_requests, Some(8));
+        });
+    }
+
+    #[test]
+    fn runtime_uses_certificate_without_client_secret() {
+        let mut vars = base_env();
+        vars.push((CLIENT_CERTIFICATE_PATH_ENV, "/certs/app.pfx"));
+        vars.push((CLIENT_CERTIFICATE_PASSWORD_ENV, "pfx-password"));
+
+        with_env(&vars, || {
+            let runtime = test_config()
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+
+            assert_eq!(runtime.client_secret, None);
+            assert_eq!(
+                runtime.client_certificate_path.as_deref(),
+                Some("/certs/app.pfx")
+            );
+            assert_eq!(
+                runtime.client_certificate_password.as_deref(),
+                Some("pfx-password")
+            );
+        });
+    }
+
+    #[test]
+    fn runtime_rejects_both_secret_and_certificate() {
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_ENV, "direct-secret"));
+        vars.push((CLIENT_CERTIFICATE_PATH_ENV, "/certs/app.pem"));
+
+        with_env(&vars, || {
+            let err = test_config()
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap_err()
+                .to_string();
+
+            assert!(err.contains("not both"), "{err}");
+        });
+    }
+
+    #[test]
+    fn runtime_requires_secret_or_certificate() {
+        with_env(&base_env(), || {
+            let err = test_config()
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap_err()
+                .to_string();
+
+            assert_eq!(
+                err,
+                "CLIENT_SECRET or CLIENT_CERTIFICATE_PATH environment variable is required"
+            );
+        });
+    }
+
+    #[test]
+    fn runtime_managed_identity_needs_no_app_credentials() {
+        let vars = [
+            (
+                "ENDPOINT",
+                "https://example.crm.dynamics.com/api/data/v9.2/",
+            ),
+            ("AUTH_MODE", "managed_identity"),
+            ("MANAGED_IDENTITY_CLIENT_ID", "user-assigned-id"),
+        ];
+
+        with_env(&vars, || {
+            let runtime = test_config()
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+
+            assert_eq!(runtime.auth_mode, AuthMode::ManagedIdentity);
+            assert_eq!(
+                runtime.managed_identity_client_id.as_deref(),
+                Some("user-assigned-id")
+            );
+            assert_eq!(runtime.client_secret, None);
+        });
+    }
+
+    #[test]
+    fn runtime_rejects_unknown_auth_mode() {
+        let mut vars = base_env();
+        vars.push(("AUTH_MODE", "magic"));
+
+        with_env(&vars, || {
+            let err = test_config()
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap_err()
+                .to_string();
+
+            assert!(err.contains("Unknown auth mode"), "{err}");
+        });
+    }
+
+    #[test]
+    fn auth_mode_parses_azure_cli_aliases() {
+        assert_eq!("azure_cli".parse::<AuthMode>().unwrap(), AuthMode::AzureCli);
+        assert_eq!("az".parse::<AuthMode>().unwrap(), AuthMode::AzureCli);
+        assert_eq!(
+            "Managed_Identity".parse::<AuthMode>().unwrap(),
+            AuthMode::ManagedIdentity
+        );
+    }
+
+    #[test]
+    fn runtime_authority_follows_cloud_unless_overridden() {
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_ENV, "direct-secret"));
+        vars.push(("AZURE_CLOUD", "us_gov"));
+
+        with_env(&vars, || {
+            let runtime = test_config()
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+
+            assert_eq!(runtime.cloud, CloudEnvironment::UsGov);
+            assert_eq!(runtime.authority_host, "https://login.microsoftonline.us");
+        });
+
+        vars.push(("AUTHORITY_HOST", "https://login.example.test"));
+        with_env(&vars, || {
+            let runtime = test_config()
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+
+            assert_eq!(runtime.authority_host, "https://login.example.test");
+        });
+    }
+
+    #[test]
+    fn runtime_is_read_only_unless_disabled() {
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_ENV, "direct-secret"));
+
+        with_env(&vars, || {
+            let runtime = test_config()
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+            assert!(runtime.read_only);
+            assert!(!runtime.dry_run_all_writes);
+
+            let mut config = test_config();
+            config.global.read_only = Some(false);
+            config.global.dry_run_all_writes = Some(true);
+            let runtime = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+            assert!(!runtime.read_only);
+            assert!(runtime.dry_run_all_writes);
+        });
+
+        vars.push(("READ_ONLY", "false"));
+        with_env(&vars, || {
+            let runtime = test_config()
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+            assert!(!runtime.read_only);
+
+            #[cfg(feature = "mcp-server")]
+            {
+                let read_only_tools = D365McpServer::tools_for_mode(true);
+                let writable_tools = D365McpServer::tools_for_mode(runtime.read_only);
+                assert!(writable_tools.len() > read_only_tools.len());
+                assert!(!read_only_tools
+                    .iter()
+                    .any(|tool| tool.name == "delete_record"));
+            }
+        });
+    }
+
+    #[test]
+    fn runtime_entity_lists_prefer_env_over_file() {
+        let mut config = test_config();
+        config.global.allowed_entities = Some(vec!["CustomersV3".to_string()]);
+        config.global.denied_entities = Some(vec!["HcmWorkers".to_string()]);
+
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_ENV, "direct-secret"));
+        vars.push(("ALLOWED_ENTITIES", "CustomersV3, Sales*,"));
+
+        with_env(&vars, || {
+            let runtime = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+
+            assert_eq!(runtime.allowed_entities, vec!["CustomersV3", "Sales*"]);
+            assert_eq!(runtime.denied_entities, vec!["HcmWorkers"]);
+        });
+    }
+
+    #[test]
+    fn runtime_default_format_from_file_or_env() {
+        let mut config = test_config();
+        config.global.default_format = Some(OutputFormat::Table);
+
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_ENV, "direct-secret"));
+
+        with_env(&vars, || {
+            let runtime = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+            assert_eq!(runtime.default_format, OutputFormat::Table);
+        });
+
+        vars.push(("DEFAULT_FORMAT", "csv"));
+        with_env(&vars, || {
+            let runtime = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+            assert_eq!(runtime.default_format, OutputFormat::Csv);
+        });
+    }
+
+    #[test]
+    fn runtime_annotations_from_file_or_env() {
+        let mut config = test_config();
+        config.global.annotations = Some(AnnotationMode::None);
+
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_ENV, "direct-secret"));
+
+        with_env(&vars, || {
+            let runtime = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+            assert_eq!(runtime.annotations, AnnotationMode::None);
+        });
+
+        vars.push(("ANNOTATIONS", "formatted-only"));
+        with_env(&vars, || {
+            let runtime = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+            assert_eq!(runtime.annotations, AnnotationMode::FormattedOnly);
+        });
+
+        vars.pop();
+        vars.push(("ANNOTATIONS", "some"));
+        with_env(&vars, || {
+            let err = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("'formatted-only'"), "{err}");
+        });
+    }
+
+    #[test]
+    fn runtime_default_company_from_file_or_env() {
+        let mut config = test_config();
+        config.global.default_company = Some("usmf".to_string());
+
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_ENV, "direct-secret"));
+
+        with_env(&vars, || {
+            let runtime = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+            assert_eq!(runtime.default_company.as_deref(), Some("usmf"));
+        });
+
+        // An empty env var clears the file setting
+        vars.push(("DEFAULT_COMPANY", " "));
+        with_env(&vars, || {
+            let runtime = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+            assert_eq!(runtime.default_company, None);
+        });
+    }
+
+    #[test]
+    fn runtime_impersonation_requires_a_guid() {
+        let config = test_config();
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_ENV, "direct-secret"));
+        vars.push((
+            "IMPERSONATE_USER_ID",
+            "{0C4E1A2B-3D5F-4A6B-8C7D-9E0F1A2B3C4D}",
+        ));
+        vars.push(("IMPERSONATION_HEADER", "CallerObjectId"));
+
+        with_env(&vars, || {
+            let runtime = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+            assert_eq!(
+                runtime.impersonate_user_id.as_deref(),
+                Some("0C4E1A2B-3D5F-4A6B-8C7D-9E0F1A2B3C4D")
+            );
+            assert_eq!(runtime.impersonation_header, CallerIdHeader::ObjectId);
+        });
+
+        vars.push(("IMPERSONATE_USER_ID", "jane@contoso.com"));
+        with_env(&vars, || {
+            let error = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap_err();
+            assert!(error.to_string().contains("must be a GUID"), "{error}");
+        });
+    }
+
+    #[test]
+    fn runtime_label_language_is_a_language_tag() {
+        let mut config = test_config();
+        config.global.language = Some("de-DE".to_string());
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_ENV, "direct-secret"));
+
+        with_env(&vars, || {
+            let runtime = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+            assert_eq!(runtime.language.as_deref(), Some("de-DE"));
+        });
+
+        vars.push(("LABEL_LANGUAGE", "en_US:en"));
+        with_env(&vars, || {
+            let error = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap_err();
+            assert!(
+                error
+                    .to_string()
+                    .starts_with("LABEL_LANGUAGE: Invalid language"),
+                "{error}"
+            );
+        });
+    }
+
+    #[test]
+    fn runtime_user_agent_suffix_is_one_header_line() {
+        let mut config = test_config();
+        config.global.user_agent_suffix = Some(" contoso-prod ".to_string());
+        let mut vars = base_env();
+        vars.push((CLIENT_SECRET_ENV, "direct-secret"));
+
+        with_env(&vars, || {
+            let runtime = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+            assert_eq!(runtime.user_agent_suffix.as_deref(), Some("contoso-prod"));
+        });
+
+        vars.push(("USER_AGENT_SUFFIX", "contoso\r\nX-Injected: 1"));
+        with_env(&vars, || {
+            let error = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "USER_AGENT_SUFFIX must not contain control characters"
+            );
+        });
+    }
+
+    #[test]
+    fn runtime_resolves_named_environments() {
+        let config: Config = toml::from_str(
+            r#"
+            default_environment = "dev"
+
+            [global]
+            read_only = false
+
+            [environments.dev]
+            endpoint = "https://dev.crm.dynamics.com/api/data/v9.2/"
+            client_secret = "dev-secret"
+
+            [environments.prod]
+            endpoint = "https://prod.operations.dynamics.com/data/"
+            product = "finops"
+            tenant_id = "prod-tenant"
+            client_id = "prod-client"
+            client_secret = "prod-secret"
+            read_only = true
+            production = true
+            denied_entities = ["SystemUsers"]
+            "#,
+        )
+        .unwrap();
+        let vars = base_env();
+
+        with_env(&vars, || {
+            let runtime = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+            assert_eq!(runtime.environment.as_deref(), Some("dev"));
+            // The environment's endpoint beats ENDPOINT; unset values fall back
+            assert_eq!(
+                runtime.endpoint,
+                "https://dev.crm.dynamics.com/api/data/v9.2/"
+            );
+            assert_eq!(runtime.tenant_id, "tenant-id");
+            assert_eq!(runtime.client_secret.as_deref(), Some("dev-secret"));
+            assert!(!runtime.read_only);
+            assert!(!runtime.production);
+            assert_eq!(runtime.environments.len(), 2);
+            assert_eq!(runtime.environments[1].product, ProductType::Finops);
+
+            let prod = config.to_runtime_for("prod").unwrap();
+            assert_eq!(prod.product, ProductType::Finops);
+            assert_eq!(prod.client_id, "prod-client");
+            assert!(prod.read_only);
+            assert!(prod.production);
+            assert_eq!(prod.denied_entities, vec!["SystemUsers"]);
+            assert!(runtime.denied_entities.is_empty());
+
+            let error = config.to_runtime_for("qa").unwrap_err();
+            assert!(error.to_string().contains("dev, prod"), "{error}");
+        });
+
+        let mut vars = base_env();
+        vars.push((ENVIRONMENT_ENV, "prod"));
+        with_env(&vars, || {
+            let runtime = config
+                .to_runtime_with_keychain_reader(unused_keychain_reader)
+                .unwrap();
+            assert_eq!(runtime.environment.as_deref(), Some("prod"));
+        });
+    }
 }
@@ -1,5 +1,13 @@
 //! Config module
 
+#[allow(clippy::module_inception)]
 pub mod config;
+pub mod server;
+pub mod watch;
 
-pub use config::{Config, EntityConfig, ProductType, RuntimeConfig};
+pub use config::{
+    AuthMode, Config, EntityConfig, EnvironmentConfig, EnvironmentSummary, ProductType,
+    RuntimeConfig, DEFAULT_CONFIG_PATH, DEFAULT_SESSION_IDLE_SECS, DEFAULT_SHUTDOWN_GRACE_SECS,
+};
+pub use server::{OutputFormat, ToolPermissions, UnlistedTools, DEFAULT_COMPARE_IGNORED_FIELDS};
+pub use watch::ConfigWatcher;
@@ -0,0 +1,169 @@
+//! Settings only the MCP server reads
+//!
+//! They live here so the configuration loads the same with or without the
+//! `mcp-server` feature; the `mcp` module re-exports them.
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Fields that differ between environments by nature, left out of
+/// `compare_record` unless configured otherwise
+pub const DEFAULT_COMPARE_IGNORED_FIELDS: &[&str] = &["modifiedon", "versionnumber", "dataAreaId"];
+
+/// How query results are rendered
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Pretty-printed JSON array
+    #[default]
+    Json,
+    /// GitHub-flavored markdown table
+    #[serde(alias = "markdown")]
+    Table,
+    /// Comma-separated values with a header row
+    Csv,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "json" => Ok(OutputFormat::Json),
+            "table" | "markdown" => Ok(OutputFormat::Table),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!(
+                "Unknown format: {}. Use 'json', 'table' or 'csv'",
+                s
+            )),
+        }
+    }
+}
+
+/// What happens to tools no `tools` pattern matches
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum UnlistedTools {
+    /// Every session may call them
+    #[default]
+    Allow,
+    /// No session may call them
+    Deny,
+}
+
+/// The `[tool_permissions]` section
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct ToolPermissions {
+    /// Tools no pattern matches (default: allow)
+    #[serde(default)]
+    pub default: UnlistedTools,
+    /// Tool name or `*` pattern -> roles allowed to call the matching tools
+    #[serde(default)]
+    pub tools: BTreeMap<String, Vec<String>>,
+    /// HTTP API key -> roles of the sessions it starts
+    #[serde(default)]
+    pub api_keys: BTreeMap<String, Vec<String>>,
+    /// Header with the comma-separated roles of an HTTP session, set by a
+    /// gateway that has already authenticated the caller. Any caller that
+    /// reaches the server directly could set it, so it excludes `api_keys`
+    #[serde(default)]
+    pub roles_header: Option<String>,
+    /// Role of the stdio connection
+    #[serde(default)]
+    pub stdio_role: Option<String>,
+}
+
+impl ToolPermissions {
+    /// Roles allowed to call `tool`, from its most specific pattern: the
+    /// exact name, else the pattern with the most literal characters.
+    /// `None` when no pattern matches.
+    pub fn required_roles(&self, tool: &str) -> Option<&[String]> {
+        self.tools
+            .iter()
+            .filter(|(pattern, _)| wildcard_matches(pattern, tool))
+            .max_by_key(|(pattern, _)| {
+                let literal = pattern.chars().filter(|c| *c != '*').count();
+                (!pattern.contains('*'), literal)
+            })
+            .map(|(_, roles)| roles.as_slice())
+    }
+
+    /// Whether HTTP sessions must authenticate to start
+    pub fn requires_authentication(&self) -> bool {
+        !self.api_keys.is_empty() || self.roles_header.is_some()
+    }
+
+    /// Roles of a session started with `key`, or `None` for an unknown key
+    pub fn roles_for_api_key(&self, key: &str) -> Option<Vec<String>> {
+        self.api_keys.get(key).cloned()
+    }
+
+    /// Roles of the stdio connection
+    pub fn stdio_roles(&self) -> Vec<String> {
+        self.stdio_role.iter().cloned().collect()
+    }
+
+    /// Copy with the API keys masked, for printing
+    pub fn redacted(&self) -> ToolPermissions {
+        ToolPermissions {
+            api_keys: self
+                .api_keys
+                .values()
+                .enumerate()
+                .map(|(i, roles)| (format!("********{}", i + 1), roles.clone()))
+                .collect(),
+            ..self.clone()
+        }
+    }
+}
+
+/// Whether `name` matches `pattern`, where each `*` stands for any run of characters
+fn wildcard_matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No `*` at all
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards_match_any_run_of_characters() {
+        let cases = [
+            ("create_*", "create_record", true),
+            ("create_*", "create_", true),
+            ("create_*", "batch_create", false),
+            ("*_entity", "query_entity", true),
+            ("*_entity", "query_entity_count", false),
+            ("get_*_count", "get_entity_count", true),
+            ("get_*_count", "get_count", false),
+            ("*", "anything", true),
+            ("query_entity", "query_entity", true),
+            ("query_entity", "query_entities", false),
+            ("a*b*a", "aba", true),
+            ("a*b*a", "ab", false),
+        ];
+        for (pattern, name, expected) in cases {
+            assert_eq!(
+                wildcard_matches(pattern, name),
+                expected,
+                "{pattern} vs {name}"
+            );
+        }
+    }
+}
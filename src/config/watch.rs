@@ -0,0 +1,82 @@
+//! Config file change detection
+//!
+//! With `CONFIG_RELOAD_SECS` set, the server polls the config file's
+//! modification time and size and reloads the configuration when either
+//! changes. Polling needs no platform file-watch API and copes with editors
+//! that replace the file instead of writing it in place.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// What identifies one version of the file; `None` while it cannot be read
+type Stamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &Path) -> Stamp {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Polls one config file for changes
+#[derive(Debug)]
+pub struct ConfigWatcher {
+    path: PathBuf,
+    last: Stamp,
+}
+
+impl ConfigWatcher {
+    /// Watch `path`, taking its current state as seen
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let last = stamp(&path);
+        Self { path, last }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the file changed since the last call (or since `new`). A file
+    /// that disappears is not a change: it is usually being replaced, and the
+    /// new one is picked up on a later poll.
+    pub fn changed(&mut self) -> bool {
+        let current = stamp(&self.path);
+        if current.is_none() || current == self.last {
+            return false;
+        }
+        self.last = current;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn changes_are_reported_once_and_missing_files_are_not_changes() {
+        let dir = std::env::temp_dir().join(format!("d365-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, "[global]\n").unwrap();
+
+        let mut watcher = ConfigWatcher::new(&path);
+        assert!(!watcher.changed());
+
+        // Same size, later modification time
+        fs::write(&path, "[GLOBAL]\n").unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(5))
+            .unwrap();
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+
+        fs::remove_file(&path).unwrap();
+        assert!(!watcher.changed());
+        fs::write(&path, "[global]\nread_only = false\n").unwrap();
+        assert!(watcher.changed());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
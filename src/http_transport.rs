@@ -0,0 +1,803 @@
+//! Streamable HTTP transport
+//!
+//! Serves MCP over HTTP so several clients can share one server. JSON-RPC
+//! messages are POSTed to `/mcp` and answered in the response body; a GET on
+//! the same path opens an SSE stream for server-initiated messages.
+//! `/healthz` and `/metrics` serve monitoring outside of MCP.
+//!
+//! With `[tool_permissions]` configured, a session gets its roles at
+//! `initialize`, from the gateway's roles header or from an API key sent as
+//! `X-API-Key` or `Authorization: Bearer`.
+
+use crate::{
+    handle_message, log_to_file, parse_error, shutdown_grace, shutdown_signal, Connection,
+    ServedStats, ServerState,
+};
+use axum::extract::State;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use d365_odata_mcp::config::DEFAULT_SESSION_IDLE_SECS;
+use d365_odata_mcp::mcp::permissions::parse_roles;
+use futures::StreamExt;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::IntoFuture;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+
+/// Header carrying the session assigned at `initialize`
+pub const SESSION_HEADER: &str = "mcp-session-id";
+
+/// Header an API key may be sent in, besides `Authorization: Bearer`
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Server-initiated messages buffered per session before slow streams lag
+const SESSION_CHANNEL_CAPACITY: usize = 64;
+
+/// Per-session state
+struct Session {
+    /// Server-to-client messages for the session's SSE streams
+    sender: broadcast::Sender<String>,
+    /// Running requests and notification delivery for the session
+    connection: Arc<Connection>,
+    /// When the client last sent a request or opened a stream
+    last_seen: Instant,
+}
+
+impl Session {
+    /// Idle for longer than `timeout`, with no request running and no
+    /// stream open
+    fn expired(&self, timeout: Duration) -> bool {
+        self.last_seen.elapsed() > timeout
+            && self.sender.receiver_count() == 0
+            && self.connection.in_flight.is_idle()
+    }
+}
+
+/// Open sessions and their server-to-client message channels
+#[derive(Clone, Default)]
+pub struct Sessions {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    /// Sessions idle for longer are dropped; `None` keeps them until deleted
+    idle_timeout: Option<Duration>,
+}
+
+impl Sessions {
+    /// Sessions that expire after `idle_timeout` without activity
+    pub fn with_idle_timeout(idle_timeout: Option<Duration>) -> Self {
+        Self {
+            idle_timeout,
+            ..Self::default()
+        }
+    }
+
+    /// Open sessions, after dropping the expired ones
+    fn open(&self) -> std::sync::MutexGuard<'_, HashMap<String, Session>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(timeout) = self.idle_timeout {
+            sessions.retain(|id, session| {
+                let expired = session.expired(timeout);
+                if expired {
+                    log_to_file(&format!("HTTP session expired: {}", id));
+                }
+                !expired
+            });
+        }
+        sessions
+    }
+
+    /// Start a session holding `roles` and return its id and connection
+    fn create(&self, roles: Vec<String>) -> (String, Arc<Connection>) {
+        let id = format!("{:032x}", rand::random::<u128>());
+        let (sender, _) = broadcast::channel(SESSION_CHANNEL_CAPACITY);
+        // Notifications go to whichever SSE streams the session has open
+        let streams = sender.clone();
+        let connection =
+            Connection::with_notifier(move |message| streams.send(message.to_string()).is_ok());
+        let _ = connection.roles.set(roles.into());
+        let session = Session {
+            sender,
+            connection: connection.clone(),
+            last_seen: Instant::now(),
+        };
+        self.open().insert(id.clone(), session);
+        (id, connection)
+    }
+
+    /// The session's connection state, or `None` if it does not exist or expired
+    fn connection(&self, id: &str) -> Option<Arc<Connection>> {
+        self.open().get_mut(id).map(|session| {
+            session.last_seen = Instant::now();
+            session.connection.clone()
+        })
+    }
+
+    fn subscribe(&self, id: &str) -> Option<broadcast::Receiver<String>> {
+        self.open().get_mut(id).map(|session| {
+            session.last_seen = Instant::now();
+            session.sender.subscribe()
+        })
+    }
+
+    fn remove(&self, id: &str) -> bool {
+        self.sessions.lock().unwrap().remove(id).is_some()
+    }
+
+    /// Cancel the running requests of every session
+    fn cancel_all(&self) {
+        for session in self.sessions.lock().unwrap().values() {
+            session.connection.in_flight.cancel_all();
+        }
+    }
+}
+
+#[derive(Clone)]
+struct AppState {
+    server: Arc<ServerState>,
+    sessions: Sessions,
+    stats: Arc<ServedStats>,
+}
+
+/// Build the `/mcp`, `/healthz` and `/metrics` router
+pub fn router(server: Arc<ServerState>, sessions: Sessions, stats: Arc<ServedStats>) -> Router {
+    Router::new()
+        .route(
+            "/mcp",
+            post(handle_post).get(handle_get).delete(handle_delete),
+        )
+        .route("/healthz", get(handle_health))
+        .route("/metrics", get(handle_metrics))
+        .with_state(AppState {
+            server,
+            sessions,
+            stats,
+        })
+}
+
+/// Serve MCP over HTTP until the listener fails or a shutdown signal arrives.
+///
+/// On shutdown, open requests get the configured grace period to finish;
+/// whatever is still running then is cancelled.
+pub async fn serve(server: Arc<ServerState>, listen: SocketAddr) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(listen).await?;
+    log_to_file(&format!(
+        "Listening for MCP over HTTP on http://{}/mcp",
+        listener.local_addr()?
+    ));
+
+    let grace = shutdown_grace(&server);
+    let sessions = Sessions::with_idle_timeout(session_idle_timeout(&server));
+    let stats = Arc::new(ServedStats::default());
+    let app = router(server, sessions.clone(), stats.clone());
+    let signalled = tokio_util::sync::CancellationToken::new();
+    let serving = axum::serve(listener, app)
+        .with_graceful_shutdown(signalled.clone().cancelled_owned())
+        .into_future();
+    tokio::pin!(serving);
+
+    tokio::select! {
+        result = &mut serving => result?,
+        _ = shutdown_signal() => {
+            signalled.cancel();
+            log_to_file(&format!("Waiting up to {}s for open requests", grace.as_secs()));
+            // Event streams stay open until their client leaves, so the wait is capped
+            if tokio::time::timeout(grace, &mut serving).await.is_err() {
+                log_to_file("Grace period over, cancelling running requests");
+                sessions.cancel_all();
+            }
+        }
+    }
+
+    log_to_file(&stats.summary());
+    tracing::info!("{}", stats.summary());
+    Ok(())
+}
+
+/// Idle time after which a session expires, from the configuration when
+/// there is one; `None` when sessions never expire
+fn session_idle_timeout(server: &ServerState) -> Option<Duration> {
+    let secs = server
+        .as_ref()
+        .map_or(DEFAULT_SESSION_IDLE_SECS, |s| s.config().session_idle_secs);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+fn session_id(headers: &HeaderMap) -> Option<&str> {
+    headers.get(SESSION_HEADER).and_then(|v| v.to_str().ok())
+}
+
+fn unknown_session() -> Response {
+    (StatusCode::NOT_FOUND, "Unknown or expired MCP session").into_response()
+}
+
+/// Roles of a request starting a session: those in the gateway's roles
+/// header, or those of its API key (the config allows only one of the two).
+/// Without `[tool_permissions]`, or with neither a header nor API keys
+/// configured, there are none to check. `Err` says why the request is
+/// unauthorized.
+fn authenticate(server: &ServerState, headers: &HeaderMap) -> Result<Vec<String>, String> {
+    let Some(permissions) = server
+        .as_ref()
+        .ok()
+        .and_then(|server| server.config().tool_permissions.clone())
+    else {
+        return Ok(Vec::new());
+    };
+    let header_value = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    if let Some(roles) = permissions.roles_header.as_deref().and_then(header_value) {
+        return Ok(parse_roles(roles));
+    }
+    if permissions.api_keys.is_empty() {
+        return match permissions.roles_header {
+            Some(name) => Err(format!("Missing {} header", name)),
+            None => Ok(Vec::new()),
+        };
+    }
+    let key = header_value(API_KEY_HEADER).or_else(|| {
+        header_value(header::AUTHORIZATION.as_str()).and_then(|value| value.strip_prefix("Bearer "))
+    });
+    match key.map(|key| permissions.roles_for_api_key(key.trim())) {
+        Some(Some(roles)) => Ok(roles),
+        Some(None) => Err("Unknown API key".to_string()),
+        None => Err("Missing API key; send it as X-API-Key or Authorization: Bearer".to_string()),
+    }
+}
+
+fn unauthorized(message: String) -> Response {
+    (StatusCode::UNAUTHORIZED, message).into_response()
+}
+
+async fn handle_post(State(state): State<AppState>, headers: HeaderMap, body: String) -> Response {
+    let message: Value = match serde_json::from_str(&body) {
+        Ok(message) => message,
+        Err(e) => {
+            log_to_file(&format!("HTTP parse error: {}", e));
+            return (StatusCode::BAD_REQUEST, Json(parse_error(&e))).into_response();
+        }
+    };
+
+    let is_initialize = message.get("method").and_then(Value::as_str) == Some("initialize");
+    // `initialize` starts a session; its connection records the negotiated
+    // version that later requests in the session are checked against
+    let (session, connection) = match session_id(&headers) {
+        _ if is_initialize => {
+            let roles = match authenticate(&state.server, &headers) {
+                Ok(roles) => roles,
+                Err(message) => return unauthorized(message),
+            };
+            let (id, connection) = state.sessions.create(roles);
+            (Some(id), connection)
+        }
+        Some(id) => match state.sessions.connection(id) {
+            Some(connection) => (None, connection),
+            None => return unknown_session(),
+        },
+        // Without a session there is no stream for notifications and nothing
+        // a cancellation could refer to
+        None => {
+            let roles = match authenticate(&state.server, &headers) {
+                Ok(roles) => roles,
+                Err(message) => return unauthorized(message),
+            };
+            let connection = Arc::new(Connection::default());
+            let _ = connection.roles.set(roles.into());
+            (None, connection)
+        }
+    };
+
+    // Notifications (and batches of only notifications) get no JSON-RPC response
+    let Some(response) = handle_message(&state.server, &connection, message).await else {
+        return StatusCode::ACCEPTED.into_response();
+    };
+    state.stats.record(&response);
+
+    let failed = response.get("error").is_some();
+    let mut http_response = Json(response).into_response();
+    if let Some(id) = session {
+        if failed {
+            state.sessions.remove(&id);
+        } else {
+            log_to_file(&format!("HTTP session started: {}", id));
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                http_response.headers_mut().insert(SESSION_HEADER, value);
+            }
+        }
+    }
+    http_response
+}
+
+async fn handle_get(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let accepts_sse = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/event-stream"));
+    if !accepts_sse {
+        return (
+            StatusCode::NOT_ACCEPTABLE,
+            "GET opens an SSE stream and requires Accept: text/event-stream",
+        )
+            .into_response();
+    }
+
+    let Some(id) = session_id(&headers) else {
+        return (StatusCode::BAD_REQUEST, "Missing Mcp-Session-Id header").into_response();
+    };
+    let Some(receiver) = state.sessions.subscribe(id) else {
+        return unknown_session();
+    };
+
+    // Messages missed by a lagging stream are dropped rather than ending the stream
+    let stream = BroadcastStream::new(receiver).filter_map(|message| async move {
+        message
+            .ok()
+            .map(|data| Ok::<_, Infallible>(Event::default().event("message").data(data)))
+    });
+
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+async fn handle_delete(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    match session_id(&headers) {
+        Some(id) if state.sessions.remove(id) => {
+            log_to_file(&format!("HTTP session closed: {}", id));
+            StatusCode::OK.into_response()
+        }
+        Some(_) => unknown_session(),
+        None => (StatusCode::BAD_REQUEST, "Missing Mcp-Session-Id header").into_response(),
+    }
+}
+
+/// 200 with the health report when healthy, 503 otherwise
+async fn handle_health(State(state): State<AppState>) -> Response {
+    let server = match state.server.as_ref() {
+        Ok(server) => server,
+        Err(message) => {
+            let body = serde_json::json!({"status": "unhealthy", "error": message});
+            return (StatusCode::SERVICE_UNAVAILABLE, Json(body)).into_response();
+        }
+    };
+    let report = server.health().await;
+    let status = if report.healthy() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report.to_json())).into_response()
+}
+
+/// Tool and OData metrics in the Prometheus text format
+async fn handle_metrics(State(state): State<AppState>) -> Response {
+    match state.server.as_ref() {
+        Ok(server) => (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            server.prometheus_metrics(),
+        )
+            .into_response(),
+        Err(message) => (StatusCode::SERVICE_UNAVAILABLE, message.clone()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::configured_server;
+    use serde_json::json;
+
+    /// Serve an unconfigured server on an ephemeral port
+    async fn spawn() -> (String, Sessions) {
+        spawn_server(Err("not configured".to_string())).await
+    }
+
+    /// Serve `server` on an ephemeral port; returns the `/mcp` URL
+    async fn spawn_server(server: ServerState) -> (String, Sessions) {
+        let sessions = Sessions::default();
+        let app = router(Arc::new(server), sessions.clone(), Arc::default());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/mcp", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, sessions)
+    }
+
+    async fn initialize(client: &reqwest::Client, url: &str) -> String {
+        let response = client
+            .post(url)
+            .json(
+                &json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {
+                    "protocolVersion": "2025-06-18",
+                    "capabilities": {},
+                    "clientInfo": {"name": "test-client", "version": "1.0"}
+                }}),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let session = response.headers()[SESSION_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["id"], 1);
+        assert_eq!(body["result"]["serverInfo"]["name"], "d365-odata-mcp");
+        session
+    }
+
+    #[tokio::test]
+    async fn post_dispatches_requests_within_a_session() {
+        let (url, _) = spawn().await;
+        let client = reqwest::Client::new();
+        let session = initialize(&client, &url).await;
+
+        let response = client
+            .post(&url)
+            .header(SESSION_HEADER, &session)
+            .json(&json!({"jsonrpc": "2.0", "id": "list", "method": "tools/list"}))
+            .send()
+            .await
+            .unwrap();
+        let body: Value = response.json().await.unwrap();
+
+        assert_eq!(body["id"], "list");
+        assert!(body["result"]["tools"].as_array().unwrap().len() > 1);
+    }
+
+    #[tokio::test]
+    async fn tool_calls_need_an_initialized_session() {
+        let (url, sessions) = spawn().await;
+        let client = reqwest::Client::new();
+        let call = json!({"jsonrpc": "2.0", "id": 2, "method": "tools/call",
+                          "params": {"name": "list_entities", "arguments": {}}});
+
+        let body: Value = client
+            .post(&url)
+            .json(&call)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(body["error"]["code"], -32600, "{body}");
+
+        // A failed initialize leaves no session behind
+        let response = client
+            .post(&url)
+            .json(&json!({"jsonrpc": "2.0", "id": 1, "method": "initialize",
+                          "params": {"protocolVersion": "2023-01-01"}}))
+            .send()
+            .await
+            .unwrap();
+        assert!(response.headers().get(SESSION_HEADER).is_none());
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["message"], "Unsupported protocol version");
+        assert!(sessions.sessions.lock().unwrap().is_empty());
+
+        let session = initialize(&client, &url).await;
+        let body: Value = client
+            .post(&url)
+            .header(SESSION_HEADER, &session)
+            .json(&call)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(body.get("error").is_none(), "{body}");
+        assert_eq!(body["result"]["isError"], true, "{body}");
+    }
+
+    #[tokio::test]
+    async fn notifications_are_accepted_without_body() {
+        let (url, _) = spawn().await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .post(&url)
+            .json(&json!({"jsonrpc": "2.0", "method": "notifications/initialized"}))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 202);
+        assert!(response.text().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn batches_are_answered_with_an_array() {
+        let (url, _) = spawn().await;
+
+        let response = reqwest::Client::new()
+            .post(&url)
+            .json(&json!([
+                {"jsonrpc": "2.0", "id": 1, "method": "ping"},
+                {"jsonrpc": "2.0", "method": "notifications/cancelled", "params": {"requestId": 1}}
+            ]))
+            .send()
+            .await
+            .unwrap();
+
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body, json!([{"jsonrpc": "2.0", "id": 1, "result": {}}]));
+    }
+
+    #[tokio::test]
+    async fn malformed_json_is_a_parse_error() {
+        let (url, _) = spawn().await;
+
+        let response = reqwest::Client::new()
+            .post(&url)
+            .body("{not json")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 400);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"]["code"], -32700);
+    }
+
+    #[tokio::test]
+    async fn deleted_sessions_are_rejected() {
+        let (url, _) = spawn().await;
+        let client = reqwest::Client::new();
+        let session = initialize(&client, &url).await;
+
+        let deleted = client
+            .delete(&url)
+            .header(SESSION_HEADER, &session)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(deleted.status(), 200);
+
+        let response = client
+            .post(&url)
+            .header(SESSION_HEADER, &session)
+            .json(&json!({"jsonrpc": "2.0", "id": 2, "method": "ping"}))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+    }
+
+    #[test]
+    fn idle_sessions_expire_unless_a_stream_is_open() {
+        let sessions = Sessions::with_idle_timeout(Some(Duration::from_millis(20)));
+        let (idle, _) = sessions.create(Vec::new());
+        let (streaming, _) = sessions.create(Vec::new());
+        let _stream = sessions.subscribe(&streaming).unwrap();
+
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(sessions.connection(&idle).is_none());
+        assert!(sessions.connection(&streaming).is_some());
+
+        let kept = Sessions::default();
+        let (id, _) = kept.create(Vec::new());
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(kept.connection(&id).is_some());
+    }
+
+    #[tokio::test]
+    async fn sse_stream_delivers_only_its_sessions_messages() {
+        let (url, sessions) = spawn().await;
+        let client = reqwest::Client::new();
+        let first = initialize(&client, &url).await;
+        let second = initialize(&client, &url).await;
+
+        let mut stream = client
+            .get(&url)
+            .header(SESSION_HEADER, &first)
+            .header(header::ACCEPT, "text/event-stream")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(stream.status(), 200);
+        assert_eq!(stream.headers()[header::CONTENT_TYPE], "text/event-stream");
+
+        let notify = |id: &str, message: Value| sessions.connection(id).unwrap().notify(message);
+        assert!(!notify(&second, json!({"method": "other"})));
+        assert!(notify(
+            &first,
+            json!({"jsonrpc": "2.0", "method": "notifications/message"})
+        ));
+
+        let chunk = stream.chunk().await.unwrap().unwrap();
+        let text = String::from_utf8_lossy(&chunk);
+        assert!(text.contains("event: message"), "{text}");
+        assert!(text.contains("notifications/message"), "{text}");
+        assert!(!text.contains("other"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn sessions_get_roles_from_api_keys_or_the_gateway_header() {
+        let permissions = |authentication: &str| {
+            format!(
+                r#"read_only = false
+
+[tool_permissions]
+default = "deny"
+{authentication}
+
+[tool_permissions.tools]
+query_entity = ["reader", "writer"]
+"upsert_*" = ["writer"]
+"#
+            )
+        };
+        let server = configured_server(
+            "https://org.crm.dynamics.com/api/data/v9.2/",
+            0,
+            &permissions("[tool_permissions.api_keys]\nreader-key = [\"reader\"]"),
+        );
+        let (url, sessions) = spawn_server(Ok(server)).await;
+        let client = reqwest::Client::new();
+        let initialize = json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {
+            "protocolVersion": "2025-06-18",
+            "capabilities": {},
+            "clientInfo": {"name": "test-client", "version": "1.0"}
+        }});
+        let start = |name: &'static str, value: &'static str| {
+            client
+                .post(&url)
+                .header(name, value)
+                .json(&initialize)
+                .send()
+        };
+        let tool_names = |url: &str, session: String| {
+            let client = client.clone();
+            let url = url.to_string();
+            async move {
+                let body: Value = client
+                    .post(&url)
+                    .header(SESSION_HEADER, session)
+                    .json(&json!({"jsonrpc": "2.0", "id": 2, "method": "tools/list"}))
+                    .send()
+                    .await
+                    .unwrap()
+                    .json()
+                    .await
+                    .unwrap();
+                body["result"]["tools"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|tool| tool["name"].as_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let response = client.post(&url).json(&initialize).send().await.unwrap();
+        assert_eq!(response.status(), 401);
+        let response = start("x-api-key", "wrong-key").await.unwrap();
+        assert_eq!(response.status(), 401);
+        assert!(sessions.sessions.lock().unwrap().is_empty());
+
+        let response = start("authorization", "Bearer reader-key").await.unwrap();
+        assert_eq!(response.status(), 200);
+        let reader = response.headers()[SESSION_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(tool_names(&url, reader.clone()).await, vec!["query_entity"]);
+
+        let body: Value = client
+            .post(&url)
+            .header(SESSION_HEADER, &reader)
+            .json(&json!({"jsonrpc": "2.0", "id": 3, "method": "tools/call",
+                          "params": {"name": "upsert_record", "arguments": {}}}))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(body["result"]["isError"], true, "{body}");
+        let text = body["result"]["content"][0]["text"].as_str().unwrap();
+        assert!(
+            text.contains("requires one of the roles [writer]"),
+            "{text}"
+        );
+
+        // Behind a gateway, its header names the roles
+        let server = configured_server(
+            "https://org.crm.dynamics.com/api/data/v9.2/",
+            0,
+            &permissions("roles_header = \"X-Gateway-Roles\""),
+        );
+        let (gateway_url, _) = spawn_server(Ok(server)).await;
+        let response = client
+            .post(&gateway_url)
+            .json(&initialize)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 401);
+        let response = client
+            .post(&gateway_url)
+            .header("x-gateway-roles", "writer")
+            .json(&initialize)
+            .send()
+            .await
+            .unwrap();
+        let writer = response.headers()[SESSION_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let tools = tool_names(&gateway_url, writer).await;
+        assert!(tools.contains(&"upsert_record".to_string()), "{tools:?}");
+        assert!(!tools.contains(&"get_record".to_string()), "{tools:?}");
+    }
+
+    #[tokio::test]
+    async fn health_and_metrics_are_served_next_to_mcp() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let (url, _) = spawn().await;
+        let client = reqwest::Client::new();
+        let base = url.trim_end_matches("/mcp");
+        let response = client.get(format!("{base}/healthz")).send().await.unwrap();
+        assert_eq!(response.status(), 503);
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({"value": [{"name": "Contoso"}]})),
+            )
+            .mount(&d365)
+            .await;
+        let server = configured_server(&format!("{}/data/", d365.uri()), 0, "");
+        let (url, _) = spawn_server(Ok(server)).await;
+        let base = url.trim_end_matches("/mcp");
+
+        let session = initialize(&client, &url).await;
+        let call = json!({"jsonrpc": "2.0", "id": 1, "method": "tools/call",
+                          "params": {"name": "query_entity", "arguments": {"entity": "accounts"}}});
+        let response = client
+            .post(&url)
+            .header(SESSION_HEADER, &session)
+            .json(&call)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        let response = client.get(format!("{base}/healthz")).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let health: Value = response.json().await.unwrap();
+        assert_eq!(health["status"], "ok");
+        assert_eq!(health["token"]["valid"], true);
+        assert!(health["last_success_age_secs"].is_u64(), "{health}");
+
+        let response = client.get(format!("{base}/metrics")).send().await.unwrap();
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/plain; version=0.0.4"
+        );
+        let metrics = response.text().await.unwrap();
+        for line in [
+            "d365_mcp_tool_calls_total{tool=\"query_entity\"} 1",
+            "d365_mcp_tool_errors_total{tool=\"query_entity\"} 0",
+            "d365_mcp_odata_requests_total 1",
+            "d365_mcp_odata_request_duration_seconds_count 1",
+        ] {
+            assert!(
+                metrics.lines().any(|l| l == line),
+                "missing {line}:\n{metrics}"
+            );
+        }
+    }
+}
@@ -2,12 +2,19 @@
 //!
 //! Model Context Protocol server for Microsoft Dynamics 365 OData APIs.
 //! Supports both Dataverse and Finance & Operations.
+//!
+//! The server lives in `mcp`, behind the default `mcp-server` feature.
+//! Without it the crate is a client library: start from [`D365Client`].
 
 pub mod auth;
+pub mod client;
 pub mod config;
+#[cfg(feature = "mcp-server")]
 pub mod mcp;
+pub mod network;
 pub mod odata;
 
-pub use auth::AzureAdAuth;
+pub use auth::{AzureAdAuth, StaticTokenProvider, TokenProvider};
+pub use client::D365Client;
 pub use config::{Config, ProductType, RuntimeConfig};
 pub use odata::{ODataClient, ODataError, QueryOptions};
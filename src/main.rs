@@ -1,19 +1,41 @@
 //! D365 OData MCP Server
 //!
 //! Entry point for the MCP server binary.
-//! Implements MCP protocol over stdio using JSON-RPC 2.0.
+//! Implements MCP protocol over stdio (default) or streamable HTTP using JSON-RPC 2.0.
 
-use d365_odata_mcp::config::Config;
+mod cli;
+mod http_transport;
+
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use cli::{Cli, Command, Transport};
+use d365_odata_mcp::config::{
+    Config, ConfigWatcher, RuntimeConfig, DEFAULT_CONFIG_PATH, DEFAULT_SHUTDOWN_GRACE_SECS,
+};
+use d365_odata_mcp::mcp::logging::{self, with_log_sink, PROTOCOL_LOG_TARGET};
+use d365_odata_mcp::mcp::permissions::with_session_roles;
 use d365_odata_mcp::mcp::{
-    CallToolParams, CallToolResult, D365McpServer, InitializeResult, JsonRpcRequest,
-    JsonRpcResponse, ListToolsResult, ServerCapabilities, ServerInfo, ToolsCapability,
+    negotiate_protocol_version, with_query_history, CallToolParams, CallToolResult, D365McpServer,
+    EnvironmentLoader, GetPromptParams, InitializeParams, InitializeResult, JsonRpcRequest,
+    JsonRpcResponse, ListPromptsResult, ListResourcesResult, ListToolsResult, LogSink,
+    LoggingCapability, LoggingLevel, PromptsCapability, QueryHistory, ReadResourceParams,
+    ResourcesCapability, ServerCapabilities, ServerInfo, SetLevelParams, ToolPermissions,
+    ToolsCapability, SUPPORTED_PROTOCOL_VERSIONS,
 };
-use d365_odata_mcp::odata::ODataClient;
+use d365_odata_mcp::odata::snapshot;
+use d365_odata_mcp::odata::{with_cancellation, with_mcp_client, ODataClient, ProgressReporter};
+use d365_odata_mcp::D365Client;
+use serde_json::Value;
+use std::collections::HashMap;
 use std::env;
 use std::fs::OpenOptions;
 use std::io::Write;
-use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio_util::sync::CancellationToken;
 
 type ServerState = Result<D365McpServer, String>;
 
@@ -39,55 +61,230 @@ fn main() {
     log_to_file("=== MCP Server Starting ===");
     log_to_file(&format!("Args: {:?}", env::args().collect::<Vec<_>>()));
 
-    // Handle --version and --help flags before starting async runtime
-    let args: Vec<String> = env::args().collect();
+    // Prints --help/--version and exits on usage errors
+    let cli = Cli::parse();
 
-    if args.len() > 1 {
-        match args[1].as_str() {
-            "--version" | "-V" | "-v" => {
-                println!("d365-odata-mcp {}", env!("CARGO_PKG_VERSION"));
-                log_to_file("Exiting: --version flag");
-                return;
-            }
-            "--help" | "-h" => {
-                println!("d365-odata-mcp {}", env!("CARGO_PKG_VERSION"));
-                println!("MCP Server for Microsoft Dynamics 365 OData API\n");
-                println!("Usage: d365-odata-mcp\n");
-                println!("Environment variables:");
-                println!("  TENANT_ID      Azure AD tenant ID (required)");
-                println!("  CLIENT_ID      Azure AD client/app ID (required)");
-                println!(
-                    "  CLIENT_SECRET  Azure AD client secret (required unless USE_KEYCHAIN=true)"
-                );
-                println!("  ENDPOINT       D365 OData endpoint URL (required)");
-                println!("  PRODUCT        'dataverse' or 'finops' (required)");
-                println!("  USE_KEYCHAIN   Read CLIENT_SECRET from native secret store (optional)");
-                println!("  CLIENT_SECRET_KEYCHAIN_SERVICE  Secret store service name (required when USE_KEYCHAIN=true)");
-                println!("  CLIENT_SECRET_KEYCHAIN_ACCOUNT  Secret store account name (optional, defaults to CLIENT_ID)");
-                log_to_file("Exiting: --help flag");
-                return;
-            }
-            _ => {
-                log_to_file(&format!("Unknown arg: {}", args[1]));
-            }
+    log_to_file("Starting tokio runtime...");
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let code = match cli.subcommand() {
+        Command::Serve(serve) => {
+            let transport = match serve.transport() {
+                Ok(transport) => transport,
+                Err(message) => {
+                    log_to_file(&format!("Invalid arguments: {}", message));
+                    Cli::command()
+                        .error(ErrorKind::ArgumentConflict, message)
+                        .exit();
+                }
+            };
+            runtime.block_on(async_main(&cli, transport));
+            0
         }
+        Command::Check => runtime.block_on(run_check(&cli)),
+        Command::PrintConfig => run_print_config(&cli),
+        Command::ListEntities => runtime.block_on(run_list_entities(&cli)),
+        Command::DumpMetadata(args) => runtime.block_on(run_dump_metadata(&cli, &args.output)),
+    };
+    std::process::exit(code);
+}
+
+/// Read the config file (`--config`, else `config/default.toml`) and resolve
+/// it against the environment; `--log-level` wins over `LOG_LEVEL`
+fn load_config(cli: &Cli) -> Result<(Config, RuntimeConfig), Box<dyn std::error::Error>> {
+    load_config_for(cli, None)
+}
+
+/// [`load_config`] resolved for `environment` rather than the startup
+/// environment, when the file still configures it
+fn load_config_for(
+    cli: &Cli,
+    environment: Option<&str>,
+) -> Result<(Config, RuntimeConfig), Box<dyn std::error::Error>> {
+    let mut config = match &cli.config {
+        Some(path) => Config::load_from_path(path)
+            .map_err(|e| format!("Cannot read config file {}: {}", path.display(), e))?,
+        None => Config::load_default()?,
+    };
+    // Set before resolving, since an offline config needs no credentials
+    let metadata_file = cli
+        .metadata_file
+        .as_ref()
+        .map(|path| path.display().to_string());
+    if metadata_file.is_some() {
+        config.global.metadata_file = metadata_file.clone();
+    }
+    let mut runtime_config = match environment {
+        Some(name) if config.environments.contains_key(name) => config.to_runtime_for(name)?,
+        _ => config.to_runtime()?,
+    };
+    if let Some(level) = &cli.log_level {
+        runtime_config.log_level = level.clone();
     }
+    if metadata_file.is_some() {
+        runtime_config.metadata_file = metadata_file;
+    }
+    Ok((config, runtime_config))
+}
 
-    log_to_file("Starting tokio runtime...");
+/// Send `tracing` output to stderr when asked for, so stdout stays free for
+/// MCP messages or command output. MCP clients always get log notifications
+/// at the level they choose.
+fn init_tracing(cli: &Cli, runtime_config: Option<&RuntimeConfig>) {
+    use tracing_subscriber::layer::{Layer, SubscriberExt};
+    use tracing_subscriber::util::SubscriberInitExt;
 
-    // Run async main
-    tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap()
-        .block_on(async_main());
+    let level = match (&cli.log_level, runtime_config) {
+        (Some(level), _) => Some(level.clone()),
+        (None, Some(config)) if config.enable_tracing => Some(config.log_level.clone()),
+        _ => None,
+    };
+    let stderr = level.map(|level| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_ansi(false)
+            .with_filter(tracing_subscriber::EnvFilter::new(level))
+    });
+    let _ = tracing_subscriber::registry()
+        .with(stderr)
+        .with(logging::layer())
+        .try_init();
+}
+
+/// Load the config for a one-shot command; problems go to stderr
+fn load_config_or_report(cli: &Cli) -> Result<(Config, RuntimeConfig), i32> {
+    match load_config(cli) {
+        Ok((config, runtime_config)) => {
+            init_tracing(cli, Some(&runtime_config));
+            Ok((config, runtime_config))
+        }
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            Err(2)
+        }
+    }
+}
+
+/// `check`: the staged connection test; exit code 1 when a stage fails
+async fn run_check(cli: &Cli) -> i32 {
+    let (config, mut runtime_config) = match load_config_or_report(cli) {
+        Ok(loaded) => loaded,
+        Err(code) => return code,
+    };
+    runtime_config.test_connection_on_startup = false;
+    let server = match create_server(config, runtime_config) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            return 2;
+        }
+    };
+
+    let report = server.connection_check().await;
+    println!("{}", report);
+    if report.passed() {
+        0
+    } else {
+        1
+    }
+}
+
+/// `print-config`: the resolved configuration with secrets masked
+fn run_print_config(cli: &Cli) -> i32 {
+    let (_, runtime_config) = match load_config_or_report(cli) {
+        Ok(loaded) => loaded,
+        Err(code) => return code,
+    };
+    match cli::write_config(&mut std::io::stdout().lock(), &runtime_config) {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
+
+/// `list-entities`: entity sets from `$metadata`, one per line
+async fn run_list_entities(cli: &Cli) -> i32 {
+    let (_, runtime_config) = match load_config_or_report(cli) {
+        Ok(loaded) => loaded,
+        Err(code) => return code,
+    };
+    let model = match create_client(&runtime_config) {
+        Ok(client) => client.metadata_model().await,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            return 2;
+        }
+    };
+    match model {
+        Ok(model) => match cli::write_entity_sets(&mut std::io::stdout().lock(), &model) {
+            Ok(()) => 0,
+            Err(_) => 1,
+        },
+        Err(e) => {
+            eprintln!("Failed to read $metadata: {}", e);
+            1
+        }
+    }
+}
+
+/// `dump-metadata`: `$metadata` and its JSON model, written to `output`
+async fn run_dump_metadata(cli: &Cli, output: &Path) -> i32 {
+    let (_, runtime_config) = match load_config_or_report(cli) {
+        Ok(loaded) => loaded,
+        Err(code) => return code,
+    };
+    let document = match create_client(&runtime_config) {
+        Ok(client) => client.fetch_metadata().await,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            return 2;
+        }
+    };
+    let document = match document {
+        Ok(document) => document,
+        Err(e) => {
+            eprintln!("Failed to read $metadata: {}", e);
+            return 1;
+        }
+    };
+    match snapshot::write_snapshot(output, document.xml()) {
+        Ok((edmx, model)) => {
+            let parsed = document.model();
+            println!(
+                "Wrote {} ({} bytes) and {} ({} entity sets, {} entity types, {} enums)",
+                edmx.display(),
+                document.xml().len(),
+                model.display(),
+                parsed.entity_sets.len(),
+                parsed.entity_types.len(),
+                parsed.enums.len()
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Cannot write to {}: {}", output.display(), e);
+            1
+        }
+    }
 }
 
-async fn async_main() {
+async fn async_main(cli: &Cli, transport: Transport) {
     log_to_file("async_main started");
 
     // Try to load configuration - but don't fail startup if env vars missing
-    let server = match create_server() {
+    let server = match load_config(cli) {
+        Ok((config, runtime_config)) => {
+            init_tracing(cli, Some(&runtime_config));
+            create_server(config, runtime_config)
+        }
+        Err(e) => {
+            init_tracing(cli, None);
+            Err(e)
+        }
+    };
+    let server = Arc::new(match server {
         Ok(s) => {
             log_to_file("Server configured successfully");
             Ok(s)
@@ -96,76 +293,522 @@ async fn async_main() {
             log_to_file(&format!("Configuration incomplete: {}", e));
             Err(e.to_string())
         }
-    };
+    });
 
-    log_to_file("Starting stdio loop...");
+    if let Ok(configured) = server.as_ref() {
+        let interval = configured.config().config_reload_secs;
+        if interval > 0 {
+            tokio::spawn(watch_config(
+                cli.clone(),
+                server.clone(),
+                Duration::from_secs(interval),
+            ));
+        }
+    }
 
-    // Run async stdio message loop
-    if let Err(e) = run_stdio_loop(server).await {
+    let result = match transport {
+        Transport::Stdio => {
+            log_to_file("Starting stdio loop...");
+            run_stdio_loop(server).await
+        }
+        Transport::Http { listen } => {
+            log_to_file(&format!("Starting HTTP transport on {}...", listen));
+            http_transport::serve(server, listen).await
+        }
+    };
+
+    if let Err(e) = result {
         log_to_file(&format!("Server error: {}", e));
     }
 }
 
-fn create_server() -> Result<D365McpServer, Box<dyn std::error::Error>> {
-    use d365_odata_mcp::auth::{AuthConfig, AuthType, OAuth2Auth};
-    use std::time::Duration;
+fn create_server(
+    config: Config,
+    runtime_config: RuntimeConfig,
+) -> Result<D365McpServer, Box<dyn std::error::Error>> {
+    if let Some(environment) = &runtime_config.environment {
+        log_to_file(&format!("Environment: {}", environment));
+    }
+    log_to_file(&format!("Auth mode: {:?}", runtime_config.auth_mode));
+    match (&runtime_config.ca_cert_path, runtime_config.insecure_ssl) {
+        (Some(path), true) => log_to_file(&format!(
+            "WARNING: INSECURE_SSL is ignored because CA_CERT_PATH is set; \
+             certificates are verified against the system roots and {}",
+            path
+        )),
+        (Some(path), false) => log_to_file(&format!("Trusting CA bundle: {}", path)),
+        (None, true) => {
+            let warning = "INSECURE_SSL=true: TLS certificate verification is DISABLED for \
+                           every request. Use CA_CERT_PATH to trust an internal CA instead.";
+            log_to_file(&format!("WARNING: {}", warning));
+            tracing::warn!("{}", warning);
+            eprintln!("WARNING: {}", warning);
+        }
+        (None, false) => {}
+    }
+    let proxy = &runtime_config.proxy;
+    if proxy.is_configured() {
+        log_to_file(&format!(
+            "Proxy: https {}, http {}, no_proxy {}",
+            proxy.https_proxy.as_deref().unwrap_or("none"),
+            proxy.http_proxy.as_deref().unwrap_or("none"),
+            proxy.no_proxy.as_deref().unwrap_or("none")
+        ));
+    }
+    log_to_file(&format!(
+        "Metadata cache TTL: {} seconds",
+        runtime_config.metadata_cache_ttl_secs
+    ));
+
+    if !runtime_config
+        .cloud
+        .matches_endpoint(&runtime_config.endpoint)
+    {
+        let warning = format!(
+            "Endpoint {} does not look like a {:?} cloud endpoint; check AZURE_CLOUD",
+            runtime_config.endpoint, runtime_config.cloud
+        );
+        log_to_file(&format!("WARNING: {}", warning));
+    }
 
-    let config = Config::load_default()?;
-    let runtime_config = config.to_runtime()?;
+    let client = create_client(&runtime_config)?;
+    // Offline, the connection test could only fail
+    let test_on_startup =
+        runtime_config.test_connection_on_startup && runtime_config.metadata_file.is_none();
+    let mut server = D365McpServer::new(client, Arc::new(runtime_config));
 
-    // Parse auth type
-    let auth_type: AuthType = runtime_config
-        .auth_type
-        .parse()
-        .unwrap_or(AuthType::AzureAd);
+    if let Some(loader) = environment_loader(config) {
+        server = server.with_environment_loader(loader);
+        // Tools can name any environment per call, so every client is built now
+        for (name, error) in server.load_environments() {
+            log_to_file(&format!(
+                "WARNING: environment '{}' unavailable: {}",
+                name, error
+            ));
+        }
+    }
+
+    // Runs in the background so a slow environment does not delay `initialize`
+    if test_on_startup {
+        let check = server.connection_check();
+        tokio::spawn(async move {
+            let report = check.await;
+            let verdict = if report.passed() { "passed" } else { "FAILED" };
+            log_to_file(&format!(
+                "Startup connection check {}:\n{}",
+                verdict, report
+            ));
+        });
+    }
+
+    Ok(server)
+}
+
+/// Builds the named environments of `config`; each gets its own client, and
+/// with it its own metadata cache
+fn environment_loader(config: Config) -> Option<EnvironmentLoader> {
+    if config.environments.is_empty() {
+        return None;
+    }
+    Some(Arc::new(move |name| {
+        let runtime_config = config.to_runtime_for(name).map_err(|e| e.to_string())?;
+        let client = create_client(&runtime_config).map_err(|e| e.to_string())?;
+        log_to_file(&format!("Loaded environment: {}", name));
+        Ok((client, Arc::new(runtime_config)))
+    }))
+}
 
-    log_to_file(&format!("Auth type: {:?}", auth_type));
+/// Check the config file every `interval` and swap its new contents into
+/// `server`, then tell every client that the tool and resource lists may
+/// have changed. A file that no longer loads is logged and the running
+/// configuration kept.
+async fn watch_config(cli: Cli, server: Arc<ServerState>, interval: Duration) {
+    let path = cli
+        .config
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
     log_to_file(&format!(
-        "Metadata cache TTL: {} seconds",
-        runtime_config.metadata_cache_ttl_secs
+        "Watching {} for changes every {}s",
+        path.display(),
+        interval.as_secs()
     ));
+    let mut watcher = ConfigWatcher::new(path);
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        ticks.tick().await;
+        if !watcher.changed() {
+            continue;
+        }
+        let Ok(server) = server.as_ref() else {
+            return;
+        };
+        match reload_config(&cli, server) {
+            Ok(()) => {
+                log_to_file(&format!("Reloaded {}", watcher.path().display()));
+                tracing::info!("Configuration reloaded from {}", watcher.path().display());
+                notify_list_changed();
+            }
+            Err(e) => {
+                log_to_file(&format!(
+                    "ERROR: {} not reloaded, keeping the running configuration: {}",
+                    watcher.path().display(),
+                    e
+                ));
+                tracing::error!(
+                    "Configuration in {} is invalid, keeping the running one: {}",
+                    watcher.path().display(),
+                    e
+                );
+            }
+        }
+    }
+}
 
-    let auth_config = AuthConfig {
-        auth_type,
-        tenant_id: runtime_config.tenant_id.clone(),
-        client_id: runtime_config.client_id.clone(),
-        client_secret: runtime_config.client_secret.clone(),
-        token_url: runtime_config.token_url.clone(),
-        resource: runtime_config.resource.clone(),
-        insecure_ssl: runtime_config.insecure_ssl,
+/// Load the config file again and swap it into `server`, staying in the
+/// environment the server runs against if the file still configures it
+fn reload_config(cli: &Cli, server: &D365McpServer) -> Result<(), Box<dyn std::error::Error>> {
+    let environment = server.config().environment.clone();
+    let (config, runtime_config) = load_config_for(cli, environment.as_deref())?;
+    let client = if server.keeps_client(&runtime_config) {
+        None
+    } else {
+        Some(create_client(&runtime_config)?)
     };
+    server.reload(client, Arc::new(runtime_config), environment_loader(config));
+    Ok(())
+}
 
-    let auth = Arc::new(OAuth2Auth::new(auth_config));
-
-    let cache_ttl = Duration::from_secs(runtime_config.metadata_cache_ttl_secs);
-    let client = Arc::new(ODataClient::with_cache_ttl(
-        auth,
-        runtime_config.endpoint.clone(),
-        runtime_config.product.clone(),
-        runtime_config.max_retries,
-        runtime_config.retry_delay_ms,
-        runtime_config.insecure_ssl,
-        cache_ttl,
-    ));
+fn create_client(
+    runtime_config: &RuntimeConfig,
+) -> Result<Arc<ODataClient>, Box<dyn std::error::Error>> {
+    let client = D365Client::from_config(runtime_config).inspect_err(|e| {
+        log_to_file(&format!("ERROR: {}", e));
+    })?;
+    Ok(client.odata())
+}
+
+/// Requests currently being handled, so `notifications/cancelled` can stop them
+#[derive(Default)]
+struct InFlight {
+    requests: std::sync::Mutex<HashMap<String, CancellationToken>>,
+    /// Parent of every request token; cancelled when shutdown runs out of time
+    shutdown: CancellationToken,
+}
+
+impl InFlight {
+    /// Register a request and return the token that cancels it
+    fn start(&self, id: &Value) -> CancellationToken {
+        let token = self.shutdown.child_token();
+        self.requests
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), token.clone());
+        token
+    }
+
+    fn finish(&self, id: &Value) {
+        self.requests.lock().unwrap().remove(&id.to_string());
+    }
+
+    /// Cancel a request by id; returns `false` if it is not running
+    fn cancel(&self, id: &Value) -> bool {
+        match self.requests.lock().unwrap().get(&id.to_string()) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Cancel every running request, and any started afterwards
+    fn cancel_all(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Whether no request is running
+    fn is_idle(&self) -> bool {
+        self.requests.lock().unwrap().is_empty()
+    }
+}
+
+/// Responses sent since startup, for the shutdown summary
+struct ServedStats {
+    started: Instant,
+    requests: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Default for ServedStats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            requests: AtomicU64::default(),
+            errors: AtomicU64::default(),
+        }
+    }
+}
+
+impl ServedStats {
+    /// Count a response; each member of a batch counts as one request
+    fn record(&self, response: &Value) {
+        if let Value::Array(batch) = response {
+            batch.iter().for_each(|member| self.record(member));
+            return;
+        }
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let failed = response.get("error").is_some()
+            || response.pointer("/result/isError") == Some(&Value::Bool(true));
+        if failed {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "Shutdown complete: {} requests served, {} errors, uptime {}s",
+            self.requests.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+            self.started.elapsed().as_secs()
+        )
+    }
+}
+
+/// Resolves when the process is asked to stop: SIGTERM or SIGINT on unix,
+/// Ctrl+C elsewhere. Never resolves if the handlers cannot be installed.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match (
+            signal(SignalKind::terminate()),
+            signal(SignalKind::interrupt()),
+        ) {
+            (Ok(mut terminate), Ok(mut interrupt)) => tokio::select! {
+                _ = terminate.recv() => log_to_file("SIGTERM received"),
+                _ = interrupt.recv() => log_to_file("SIGINT received"),
+            },
+            _ => std::future::pending().await,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+        log_to_file("Ctrl+C received");
+    }
+}
+
+/// Grace period for running requests, from the configuration when there is one
+fn shutdown_grace(server: &ServerState) -> Duration {
+    Duration::from_secs(server.as_ref().map_or(DEFAULT_SHUTDOWN_GRACE_SECS, |s| {
+        s.config().shutdown_grace_secs
+    }))
+}
+
+/// Client-side sink for server-initiated messages
+type Notifier = Box<dyn Fn(Value) -> bool + Send + Sync>;
+
+/// State shared by all requests of one client connection
+#[derive(Default)]
+struct Connection {
+    in_flight: InFlight,
+    /// Delivers notifications to the client; `None` when the transport has no channel for them
+    notifier: Option<Notifier>,
+    /// Least severe log level forwarded, set by `logging/setLevel`
+    log_level: Mutex<LoggingLevel>,
+    /// Protocol version agreed at `initialize`; tool calls are refused before it
+    protocol_version: OnceLock<&'static str>,
+    /// Roles the connection was authenticated with, checked against `[tool_permissions]`
+    roles: OnceLock<Arc<[String]>>,
+    /// `name/version` of the MCP client, from its `initialize`; its tool
+    /// calls are traced and sent to the service under this name
+    client: OnceLock<String>,
+    /// Tool calls of this connection, for `query_history` and `replay_query`
+    history: OnceLock<Arc<QueryHistory>>,
+}
+
+impl Connection {
+    /// Connection delivering notifications through `notifier`; it also
+    /// receives log events raised outside any request, and list changes,
+    /// while it is alive
+    fn with_notifier(notifier: impl Fn(Value) -> bool + Send + Sync + 'static) -> Arc<Self> {
+        let connection = Arc::new(Self {
+            notifier: Some(Box::new(notifier)),
+            ..Self::default()
+        });
+        logging::register_log_sink(Arc::downgrade(&connection) as _);
+        let mut connections = open_connections().lock().unwrap();
+        connections.retain(|connection| connection.strong_count() > 0);
+        connections.push(Arc::downgrade(&connection));
+        connection
+    }
+
+    /// Roles of the connection; none until they are set
+    fn roles(&self) -> Arc<[String]> {
+        self.roles
+            .get()
+            .cloned()
+            .unwrap_or_else(|| Arc::from(Vec::new()))
+    }
+
+    /// Send a notification; returns `false` if it could not be delivered
+    fn notify(&self, message: Value) -> bool {
+        self.notifier
+            .as_ref()
+            .is_some_and(|notifier| notifier(message))
+    }
 
-    Ok(D365McpServer::new(client, Arc::new(runtime_config)))
+    /// Send `list_changed` for tools and resources, once the client is initialized
+    fn announce_list_changed(&self) {
+        if self.protocol_version.get().is_none() {
+            return;
+        }
+        for method in [
+            "notifications/tools/list_changed",
+            "notifications/resources/list_changed",
+        ] {
+            self.notify(serde_json::json!({"jsonrpc": "2.0", "method": method}));
+        }
+    }
 }
 
-async fn run_stdio_loop(server: ServerState) -> Result<(), std::io::Error> {
-    let stdin = tokio::io::stdin();
-    let mut stdout = tokio::io::stdout();
-    let mut reader = BufReader::new(stdin);
+/// Connections with a notification channel, for server-wide announcements
+fn open_connections() -> &'static Mutex<Vec<Weak<Connection>>> {
+    static CONNECTIONS: OnceLock<Mutex<Vec<Weak<Connection>>>> = OnceLock::new();
+    CONNECTIONS.get_or_init(Default::default)
+}
+
+/// Tell every client to fetch the tool and resource lists again
+fn notify_list_changed() {
+    let connections: Vec<Arc<Connection>> = open_connections()
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect();
+    for connection in connections {
+        connection.announce_list_changed();
+    }
+}
+
+impl LogSink for Connection {
+    fn log_level(&self) -> LoggingLevel {
+        *self.log_level.lock().unwrap()
+    }
+
+    fn send_log(&self, notification: Value) -> bool {
+        self.notify(notification)
+    }
+}
+
+/// Forwards OData progress to the client as `notifications/progress`,
+/// counting the call's updates so `progress` only ever increases
+struct ProgressNotifier {
+    connection: Arc<Connection>,
+    token: Value,
+    steps: AtomicU64,
+}
+
+impl ProgressReporter for ProgressNotifier {
+    fn report(&self, message: &str) {
+        let progress = self.steps.fetch_add(1, Ordering::Relaxed) + 1;
+        self.connection.notify(serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "notifications/progress",
+            "params": {
+                "progressToken": self.token,
+                "progress": progress,
+                "message": message,
+            },
+        }));
+    }
+}
+
+async fn run_stdio_loop(server: Arc<ServerState>) -> Result<(), std::io::Error> {
+    let grace = shutdown_grace(&server);
+    let shutdown = CancellationToken::new();
+    let signalled = shutdown.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        signalled.cancel();
+    });
+
+    let stats = run_message_loop(
+        server,
+        BufReader::new(tokio::io::stdin()),
+        tokio::io::stdout(),
+        shutdown,
+        grace,
+    )
+    .await?;
+    log_to_file(&stats.summary());
+    tracing::info!("{}", stats.summary());
+    Ok(())
+}
+
+/// Read newline-delimited JSON-RPC messages and write responses and notifications.
+///
+/// Stops reading at EOF or when `shutdown` is cancelled, then gives running
+/// requests `grace` to finish before cancelling them. Returns what was served.
+async fn run_message_loop<R, W>(
+    server: Arc<ServerState>,
+    mut reader: R,
+    mut writer: W,
+    shutdown: CancellationToken,
+    grace: Duration,
+) -> Result<Arc<ServedStats>, std::io::Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
     let mut line = String::new();
 
+    // Requests are handled concurrently so cancellations can arrive while a
+    // tool call runs; a single writer keeps output lines whole
+    let (output, mut outgoing) = tokio::sync::mpsc::unbounded_channel::<Value>();
+    let writer = tokio::spawn(async move {
+        while let Some(message) = outgoing.recv().await {
+            log_to_file("Sending response...");
+            let _ = send_message(&mut writer, &message).await;
+            log_to_file("Response sent");
+        }
+        let _ = writer.flush().await;
+    });
+
+    let notifications = output.clone();
+    let connection = Connection::with_notifier(move |message| notifications.send(message).is_ok());
+    if let Ok(server) = server.as_ref() {
+        let roles = server
+            .config()
+            .tool_permissions
+            .as_ref()
+            .map(ToolPermissions::stdio_roles)
+            .unwrap_or_default();
+        let _ = connection.roles.set(roles.into());
+    }
+
+    let stats = Arc::new(ServedStats::default());
+    let mut handlers = tokio::task::JoinSet::new();
+
     log_to_file("Waiting for input...");
 
     loop {
         line.clear();
 
         log_to_file("Reading line...");
-        let bytes_read = reader.read_line(&mut line).await?;
+        let bytes_read = tokio::select! {
+            read = reader.read_line(&mut line) => read?,
+            _ = shutdown.cancelled() => {
+                log_to_file("Shutdown requested, no longer reading input");
+                break;
+            }
+        };
 
         log_to_file(&format!("Read {} bytes: {:?}", bytes_read, line.trim()));
+        tracing::debug!(target: PROTOCOL_LOG_TARGET, "Received: {}", line.trim());
 
         if bytes_read == 0 {
             log_to_file("EOF received, shutting down");
@@ -178,58 +821,264 @@ async fn run_stdio_loop(server: ServerState) -> Result<(), std::io::Error> {
             continue;
         }
 
-        let request: JsonRpcRequest = match serde_json::from_str::<JsonRpcRequest>(trimmed) {
-            Ok(req) => {
-                log_to_file(&format!(
-                    "Parsed request: method={}, has_id={}",
-                    req.method,
-                    req.id.is_some()
-                ));
-                req
-            }
+        let message = match serde_json::from_str::<Value>(trimmed) {
+            Ok(message) => message,
             Err(e) => {
                 log_to_file(&format!("Parse error: {}", e));
-                let error_response =
-                    JsonRpcResponse::error(None, -32700, &format!("Parse error: {}", e));
-                let _ = send_response(&mut stdout, &error_response).await;
+                let _ = output.send(parse_error(&e));
                 continue;
             }
         };
 
-        // Notifications don't have an id and should NOT receive a response
-        let is_notification = request.id.is_none();
+        // Forget finished handlers so a long session doesn't accumulate them
+        while handlers.try_join_next().is_some() {}
 
-        if is_notification {
-            log_to_file(&format!(
-                "Notification received: {}, no response needed",
-                request.method
-            ));
-            // Still process the notification but don't send response
-            let _ = handle_request(&server, request).await;
+        // `initialize` is answered before the next line is read, so requests
+        // sent right behind it find the connection initialized
+        if message.get("method").and_then(Value::as_str) == Some("initialize") {
+            if let Some(response) = handle_message(&server, &connection, message).await {
+                stats.record(&response);
+                let _ = output.send(response);
+            }
             continue;
         }
 
-        let response = handle_request(&server, request).await;
-        log_to_file("Sending response...");
-        let _ = send_response(&mut stdout, &response).await;
-        log_to_file("Response sent");
+        let server = server.clone();
+        let connection = connection.clone();
+        let output = output.clone();
+        let stats = stats.clone();
+        handlers.spawn(async move {
+            // Notifications (and batches of only notifications) produce no output
+            if let Some(response) = handle_message(&server, &connection, message).await {
+                stats.record(&response);
+                let _ = output.send(response);
+            }
+        });
     }
 
-    Ok(())
+    // Let running requests finish within the grace period, then cancel the rest
+    if !handlers.is_empty() {
+        log_to_file(&format!(
+            "Waiting up to {}s for {} running requests",
+            grace.as_secs(),
+            handlers.len()
+        ));
+        let finished = tokio::time::timeout(grace, async {
+            while handlers.join_next().await.is_some() {}
+        })
+        .await;
+        if finished.is_err() {
+            log_to_file("Grace period over, cancelling running requests");
+            connection.in_flight.cancel_all();
+            while handlers.join_next().await.is_some() {}
+        }
+    }
+
+    // The connection's notifier holds a sender too, so it must go before the
+    // writer can drain the channel and flush
+    drop(output);
+    drop(connection);
+    let _ = writer.await;
+
+    Ok(stats)
+}
+
+/// JSON-RPC parse error response for malformed input
+fn parse_error(error: &serde_json::Error) -> Value {
+    let response = JsonRpcResponse::error(None, -32700, &format!("Parse error: {}", error));
+    serde_json::to_value(response).unwrap()
+}
+
+/// Process one incoming JSON-RPC message: a request, a notification or a batch.
+///
+/// Returns the response to send, or `None` when nothing must be written
+/// (notifications, and batches made only of notifications).
+async fn handle_message(
+    server: &ServerState,
+    connection: &Arc<Connection>,
+    message: Value,
+) -> Option<Value> {
+    match message {
+        Value::Array(batch) => {
+            if batch.is_empty() {
+                let response = JsonRpcResponse::error(None, -32600, "Invalid Request: empty batch");
+                return Some(serde_json::to_value(response).unwrap());
+            }
+
+            log_to_file(&format!("Batch received: {} messages", batch.len()));
+            let mut responses = Vec::new();
+            for member in batch {
+                if let Some(response) = handle_single_message(server, connection, member).await {
+                    responses.push(serde_json::to_value(response).unwrap());
+                }
+            }
+            (!responses.is_empty()).then_some(Value::Array(responses))
+        }
+        message => handle_single_message(server, connection, message)
+            .await
+            .map(|response| serde_json::to_value(response).unwrap()),
+    }
 }
 
-async fn handle_request(server: &ServerState, request: JsonRpcRequest) -> JsonRpcResponse {
+async fn handle_single_message(
+    server: &ServerState,
+    connection: &Arc<Connection>,
+    message: Value,
+) -> Option<JsonRpcResponse> {
+    let id = message.get("id").cloned().filter(|id| !id.is_null());
+    let request: JsonRpcRequest = match serde_json::from_value(message) {
+        Ok(request) => request,
+        Err(e) => {
+            log_to_file(&format!("Invalid request: {}", e));
+            return Some(JsonRpcResponse::error(
+                id,
+                -32600,
+                &format!("Invalid Request: {}", e),
+            ));
+        }
+    };
+
+    log_to_file(&format!(
+        "Parsed request: method={}, has_id={}",
+        request.method,
+        request.id.is_some()
+    ));
+
+    // Notifications don't have an id and must NOT receive a response
+    let Some(id) = request.id.clone() else {
+        handle_notification(&connection.in_flight, &request);
+        return None;
+    };
+
+    // A cancelled request stops its OData work and gets no response
+    let in_flight = &connection.in_flight;
+    let token = in_flight.start(&id);
+    // Log events raised while handling the request go to this client only.
+    // Boxed: tool dispatch makes this future too large to keep on the stack.
+    let handled = with_log_sink(
+        connection.clone(),
+        Box::pin(handle_request(server, connection, request)),
+    );
+    // Biased: a handler that completes because it saw the cancellation must not respond
+    let response = tokio::select! {
+        biased;
+        _ = token.cancelled() => {
+            log_to_file(&format!("Request {} cancelled", id));
+            None
+        }
+        response = with_cancellation(token.clone(), handled) => Some(response),
+    };
+    in_flight.finish(&id);
+    response
+}
+
+fn handle_notification(in_flight: &InFlight, notification: &JsonRpcRequest) {
+    match notification.method.as_str() {
+        "initialized" | "notifications/initialized" => {
+            log_to_file("Client initialized");
+        }
+        "notifications/cancelled" => {
+            let params = notification.params.as_ref();
+            let request_id = params.and_then(|p| p.get("requestId"));
+            let reason = params
+                .and_then(|p| p.get("reason"))
+                .and_then(Value::as_str)
+                .unwrap_or("no reason given");
+
+            match request_id {
+                Some(id) if in_flight.cancel(id) => {
+                    log_to_file(&format!("Cancelling request {}: {}", id, reason));
+                }
+                Some(id) => {
+                    log_to_file(&format!("Cancellation for unknown request {} ignored", id));
+                }
+                None => log_to_file("Cancellation without requestId ignored"),
+            }
+        }
+        method => {
+            log_to_file(&format!("Ignoring unknown notification: {}", method));
+        }
+    }
+}
+
+async fn handle_request(
+    server: &ServerState,
+    connection: &Arc<Connection>,
+    request: JsonRpcRequest,
+) -> JsonRpcResponse {
     let id = request.id.clone();
 
     match request.method.as_str() {
         "initialize" => {
             log_to_file("Handling: initialize");
+            let params: InitializeParams = match request
+                .params
+                .map(serde_json::from_value)
+                .transpose()
+            {
+                Ok(Some(params)) => params,
+                Ok(None) => {
+                    return JsonRpcResponse::error(
+                        id,
+                        -32602,
+                        "Invalid params: initialize requires protocolVersion",
+                    )
+                }
+                Err(e) => {
+                    return JsonRpcResponse::error(id, -32602, &format!("Invalid params: {}", e))
+                }
+            };
+            let Some(version) = negotiate_protocol_version(&params.protocol_version) else {
+                log_to_file(&format!(
+                    "Unsupported protocol version: {}",
+                    params.protocol_version
+                ));
+                return JsonRpcResponse::error_with_data(
+                    id,
+                    -32602,
+                    "Unsupported protocol version",
+                    serde_json::json!({
+                        "supported": SUPPORTED_PROTOCOL_VERSIONS,
+                        "requested": params.protocol_version,
+                    }),
+                );
+            };
+            if connection.protocol_version.set(version).is_err() {
+                return JsonRpcResponse::error(
+                    id,
+                    -32600,
+                    "Invalid Request: the connection is already initialized",
+                );
+            }
+            match &params.client_info {
+                Some(client) => {
+                    tracing::info!(
+                        "MCP client {} initialized with protocol {}",
+                        client.product(),
+                        version
+                    );
+                    let _ = connection.client.set(client.product());
+                }
+                None => tracing::info!("MCP client initialized with protocol {}", version),
+            }
+            // Lists only change while the config file is watched
+            let list_changed = server
+                .as_ref()
+                .is_ok_and(|server| server.config().config_reload_secs > 0);
             let result = InitializeResult {
-                protocol_version: "2024-11-05".to_string(),
+                protocol_version: version.to_string(),
                 capabilities: ServerCapabilities {
                     tools: Some(ToolsCapability {
+                        list_changed: Some(list_changed),
+                    }),
+                    resources: Some(ResourcesCapability {
+                        subscribe: Some(false),
+                        list_changed: Some(list_changed),
+                    }),
+                    prompts: Some(PromptsCapability {
                         list_changed: Some(false),
                     }),
+                    logging: Some(LoggingCapability {}),
                 },
                 server_info: ServerInfo {
                     name: "d365-odata-mcp".to_string(),
@@ -247,7 +1096,7 @@ async fn handle_request(server: &ServerState, request: JsonRpcRequest) -> JsonRp
         "tools/list" => {
             log_to_file("Handling: tools/list");
             let tools = match server {
-                Ok(s) => s.get_tools(),
+                Ok(s) => s.tools_for_roles(&connection.roles()),
                 Err(_) => D365McpServer::get_tools_static(),
             };
             let result = ListToolsResult { tools };
@@ -256,6 +1105,13 @@ async fn handle_request(server: &ServerState, request: JsonRpcRequest) -> JsonRp
 
         "tools/call" => {
             log_to_file("Handling: tools/call");
+            if connection.protocol_version.get().is_none() {
+                return JsonRpcResponse::error(
+                    id,
+                    -32600,
+                    "Invalid Request: the server is not initialized; send initialize first",
+                );
+            }
             let server = match server {
                 Ok(s) => s,
                 Err(config_error) => {
@@ -282,10 +1138,127 @@ async fn handle_request(server: &ServerState, request: JsonRpcRequest) -> JsonRp
             };
 
             let args = params.arguments.unwrap_or_default();
-            let result: CallToolResult = server.call_tool(&params.name, &args).await;
+            // Progress is only reported when the client asked for it and can receive it
+            let progress = params
+                .meta
+                .and_then(|meta| meta.progress_token)
+                .filter(|_| connection.notifier.is_some())
+                .map(|token| {
+                    Arc::new(ProgressNotifier {
+                        connection: connection.clone(),
+                        token,
+                        steps: AtomicU64::new(0),
+                    }) as Arc<dyn ProgressReporter>
+                });
+            let history = connection
+                .history
+                .get_or_init(|| server.new_query_history())
+                .clone();
+            let call = with_session_roles(
+                connection.roles(),
+                with_query_history(
+                    history,
+                    // Boxed: the tool call is too large to nest on the stack
+                    Box::pin(server.call_tool_with_progress(&params.name, &args, progress)),
+                ),
+            );
+            let result: CallToolResult = match connection.client.get() {
+                Some(client) => with_mcp_client(client.clone(), call).await,
+                None => call.await,
+            };
+            JsonRpcResponse::success(id, serde_json::to_value(result).unwrap())
+        }
+
+        "resources/list" => {
+            log_to_file("Handling: resources/list");
+            let resources = match server {
+                Ok(s) => match s.list_resources().await {
+                    Ok(resources) => resources,
+                    Err(e) => return JsonRpcResponse::error(id, e.code, &e.message),
+                },
+                // Nothing to describe until the server can reach D365
+                Err(_) => Vec::new(),
+            };
+            let result = ListResourcesResult { resources };
             JsonRpcResponse::success(id, serde_json::to_value(result).unwrap())
         }
 
+        "resources/read" => {
+            log_to_file("Handling: resources/read");
+            let server = match server {
+                Ok(s) => s,
+                Err(config_error) => {
+                    return JsonRpcResponse::error(
+                        id,
+                        -32603,
+                        &format!("Server not configured. {config_error}"),
+                    );
+                }
+            };
+
+            let params: ReadResourceParams = match request.params.map(serde_json::from_value) {
+                Some(Ok(params)) => params,
+                Some(Err(e)) => {
+                    return JsonRpcResponse::error(id, -32602, &format!("Invalid params: {}", e));
+                }
+                None => return JsonRpcResponse::error(id, -32602, "Missing params"),
+            };
+
+            match server.read_resource(&params.uri).await {
+                Ok(result) => JsonRpcResponse::success(id, serde_json::to_value(result).unwrap()),
+                Err(e) => JsonRpcResponse::error(id, e.code, &e.message),
+            }
+        }
+
+        "prompts/list" => {
+            log_to_file("Handling: prompts/list");
+            let result = ListPromptsResult {
+                prompts: D365McpServer::list_prompts(),
+            };
+            JsonRpcResponse::success(id, serde_json::to_value(result).unwrap())
+        }
+
+        "prompts/get" => {
+            log_to_file("Handling: prompts/get");
+            let server = match server {
+                Ok(s) => s,
+                Err(config_error) => {
+                    return JsonRpcResponse::error(
+                        id,
+                        -32603,
+                        &format!("Server not configured. {config_error}"),
+                    );
+                }
+            };
+
+            let params: GetPromptParams = match request.params.map(serde_json::from_value) {
+                Some(Ok(params)) => params,
+                Some(Err(e)) => {
+                    return JsonRpcResponse::error(id, -32602, &format!("Invalid params: {}", e));
+                }
+                None => return JsonRpcResponse::error(id, -32602, "Missing params"),
+            };
+
+            let args = params.arguments.unwrap_or_default();
+            match server.get_prompt(&params.name, &args).await {
+                Ok(result) => JsonRpcResponse::success(id, serde_json::to_value(result).unwrap()),
+                Err(e) => JsonRpcResponse::error(id, e.code, &e.message),
+            }
+        }
+
+        "logging/setLevel" => {
+            log_to_file("Handling: logging/setLevel");
+            let params: SetLevelParams = match request.params.map(serde_json::from_value) {
+                Some(Ok(params)) => params,
+                Some(Err(e)) => {
+                    return JsonRpcResponse::error(id, -32602, &format!("Invalid params: {}", e));
+                }
+                None => return JsonRpcResponse::error(id, -32602, "Missing params"),
+            };
+            *connection.log_level.lock().unwrap() = params.level;
+            JsonRpcResponse::success(id, serde_json::json!({}))
+        }
+
         "ping" => {
             log_to_file("Handling: ping");
             JsonRpcResponse::success(id, serde_json::json!({}))
@@ -298,15 +1271,659 @@ async fn handle_request(server: &ServerState, request: JsonRpcRequest) -> JsonRp
     }
 }
 
-async fn send_response(
-    stdout: &mut tokio::io::Stdout,
-    response: &JsonRpcResponse,
+async fn send_message<W: AsyncWrite + Unpin>(
+    stdout: &mut W,
+    message: &Value,
 ) -> std::io::Result<()> {
-    let json = serde_json::to_string(response)
+    let json = serde_json::to_string(message)
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
     log_to_file(&format!("Response: {}", json));
+    tracing::debug!(target: PROTOCOL_LOG_TARGET, "Sending: {}", json);
     stdout.write_all(json.as_bytes()).await?;
     stdout.write_all(b"\n").await?;
     stdout.flush().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use d365_odata_mcp::auth::StaticTokenProvider;
+    use d365_odata_mcp::config::ProductType;
+    use serde_json::json;
+
+    fn unconfigured() -> ServerState {
+        Err("not configured".to_string())
+    }
+
+    /// Server for a Dataverse `endpoint` whose `[global]` config section ends
+    /// with `settings`, which may open further sections
+    pub(crate) fn configured_server(
+        endpoint: &str,
+        max_retries: u32,
+        settings: &str,
+    ) -> D365McpServer {
+        let config: Config = toml::from_str(&format!(
+            "[global]\nendpoint = \"{endpoint}\"\nauth_mode = \"azure_cli\"\n{settings}"
+        ))
+        .unwrap();
+        let client = ODataClient::new(
+            Arc::new(StaticTokenProvider::new("test-token")),
+            endpoint.to_string(),
+            ProductType::Dataverse,
+            max_retries,
+            10,
+            false,
+        );
+        D365McpServer::new(Arc::new(client), Arc::new(config.to_runtime().unwrap()))
+    }
+
+    async fn respond(message: Value) -> Option<Value> {
+        handle_message(&unconfigured(), &Arc::default(), message).await
+    }
+
+    fn initialize_request(id: Value, protocol_version: &str) -> Value {
+        json!({"jsonrpc": "2.0", "id": id, "method": "initialize", "params": {
+            "protocolVersion": protocol_version,
+            "capabilities": {},
+            "clientInfo": {"name": "test-client", "version": "1.0"}
+        }})
+    }
+
+    /// Input lines for `run_message_loop`: an `initialize` (id `"init"`), then `messages`
+    fn session_input(messages: &[Value]) -> String {
+        std::iter::once(initialize_request(json!("init"), "2025-06-18"))
+            .chain(messages.iter().cloned())
+            .map(|message| message.to_string() + "\n")
+            .collect()
+    }
+
+    /// Written lines other than the `initialize` response
+    fn after_initialize(written: &str) -> Vec<Value> {
+        written
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .filter(|message| message["id"] != "init")
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn requests_get_responses_with_their_id() {
+        let response = respond(json!({"jsonrpc": "2.0", "id": 7, "method": "ping"}))
+            .await
+            .unwrap();
+
+        assert_eq!(response["id"], 7);
+        assert_eq!(response["result"], json!({}));
+    }
+
+    #[tokio::test]
+    async fn notifications_produce_no_output() {
+        for method in [
+            "notifications/initialized",
+            "initialized",
+            "notifications/cancelled",
+            "notifications/something_new",
+        ] {
+            let message = json!({"jsonrpc": "2.0", "method": method, "params": {"requestId": 1}});
+            assert_eq!(respond(message).await, None, "{method}");
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_responses_skip_notifications() {
+        let batch = json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "ping"},
+            {"jsonrpc": "2.0", "method": "notifications/initialized"},
+            {"jsonrpc": "2.0", "id": "tools", "method": "tools/list"}
+        ]);
+
+        let response = respond(batch).await.unwrap();
+        let responses = response.as_array().unwrap();
+
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0]["id"], 1);
+        assert_eq!(responses[1]["id"], "tools");
+        assert!(responses[1]["result"]["tools"].is_array());
+    }
+
+    #[tokio::test]
+    async fn batch_of_notifications_produces_no_output() {
+        let batch = json!([
+            {"jsonrpc": "2.0", "method": "notifications/initialized"},
+            {"jsonrpc": "2.0", "method": "notifications/cancelled", "params": {"requestId": 3}}
+        ]);
+
+        assert_eq!(respond(batch).await, None);
+    }
+
+    #[tokio::test]
+    async fn invalid_messages_are_reported() {
+        let empty = respond(json!([])).await.unwrap();
+        assert_eq!(empty["error"]["code"], -32600);
+
+        let batch = respond(json!([1, {"jsonrpc": "2.0", "id": 2, "method": "ping"}]))
+            .await
+            .unwrap();
+        assert_eq!(batch[0]["error"]["code"], -32600);
+        assert_eq!(batch[0]["id"], Value::Null);
+        assert_eq!(batch[1]["id"], 2);
+
+        let malformed = serde_json::from_str::<Value>("{not json").unwrap_err();
+        let response = parse_error(&malformed);
+        assert_eq!(response["error"]["code"], -32700);
+        assert_eq!(response["id"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn initialize_negotiates_the_version_and_gates_tool_calls() {
+        let connection = Arc::new(Connection::default());
+        let call = json!({"jsonrpc": "2.0", "id": 1, "method": "tools/call",
+                          "params": {"name": "list_entities", "arguments": {}}});
+
+        let response = handle_message(&unconfigured(), &connection, call.clone())
+            .await
+            .unwrap();
+        assert_eq!(response["error"]["code"], -32600, "{response}");
+        assert!(response["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("not initialized"));
+
+        let missing = respond(json!({"jsonrpc": "2.0", "id": 2, "method": "initialize"}))
+            .await
+            .unwrap();
+        assert_eq!(missing["error"]["code"], -32602);
+
+        let unsupported = respond(initialize_request(json!(3), "2024-01-01"))
+            .await
+            .unwrap();
+        assert_eq!(unsupported["error"]["code"], -32602);
+        assert_eq!(
+            unsupported["error"]["data"],
+            json!({"supported": SUPPORTED_PROTOCOL_VERSIONS, "requested": "2024-01-01"})
+        );
+
+        let response = handle_message(
+            &unconfigured(),
+            &connection,
+            initialize_request(json!(4), "2025-03-26"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response["result"]["protocolVersion"], "2025-03-26");
+        let again = handle_message(
+            &unconfigured(),
+            &connection,
+            initialize_request(json!(5), "2025-06-18"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(again["error"]["code"], -32600);
+        assert_eq!(connection.protocol_version.get(), Some(&"2025-03-26"));
+
+        let response = handle_message(&unconfigured(), &connection, call)
+            .await
+            .unwrap();
+        assert!(response.get("error").is_none(), "{response}");
+        assert_eq!(response["result"]["isError"], true);
+    }
+
+    #[tokio::test]
+    async fn cancelled_notification_signals_the_running_request() {
+        let connection = Arc::new(Connection::default());
+        let in_flight = &connection.in_flight;
+        let token = in_flight.start(&json!(5));
+
+        let cancel = json!({
+            "jsonrpc": "2.0",
+            "method": "notifications/cancelled",
+            "params": {"requestId": 5, "reason": "user aborted"}
+        });
+        assert_eq!(
+            handle_message(&unconfigured(), &connection, cancel).await,
+            None
+        );
+        assert!(token.is_cancelled());
+
+        // Unknown or finished requests are ignored
+        in_flight.finish(&json!(5));
+        assert!(!in_flight.cancel(&json!(5)));
+        assert!(!in_flight.cancel(&json!("5")));
+    }
+
+    #[tokio::test]
+    async fn finished_requests_leave_no_cancellation_entry() {
+        let connection = Arc::new(Connection::default());
+
+        let response = handle_message(
+            &unconfigured(),
+            &connection,
+            json!({"jsonrpc": "2.0", "id": 9, "method": "ping"}),
+        )
+        .await;
+
+        assert!(response.is_some());
+        assert!(connection.in_flight.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn watched_configs_announce_list_changes_to_initialized_clients() {
+        let server: ServerState = Ok(configured_server(
+            "https://example.crm.dynamics.com/api/data/v9.2/",
+            0,
+            "config_reload_secs = 5",
+        ));
+
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let received = sent.clone();
+        let connection = Connection::with_notifier(move |message| {
+            received.lock().unwrap().push(message["method"].clone());
+            true
+        });
+        connection.announce_list_changed();
+        assert!(sent.lock().unwrap().is_empty());
+
+        let response = handle_message(
+            &server,
+            &connection,
+            initialize_request(json!(1), "2025-06-18"),
+        )
+        .await
+        .unwrap();
+        let capabilities = &response["result"]["capabilities"];
+        assert_eq!(capabilities["tools"]["listChanged"], true);
+        assert_eq!(capabilities["resources"]["listChanged"], true);
+        assert_eq!(capabilities["prompts"]["listChanged"], false);
+
+        connection.announce_list_changed();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            vec![
+                json!("notifications/tools/list_changed"),
+                json!("notifications/resources/list_changed")
+            ]
+        );
+
+        // Without reloading the lists never change
+        let unwatched = handle_message(
+            &unconfigured(),
+            &Arc::default(),
+            initialize_request(json!(2), "2025-06-18"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            unwatched["result"]["capabilities"]["tools"]["listChanged"],
+            false
+        );
+    }
+
+    #[tokio::test]
+    async fn progress_notifications_precede_the_tool_result() {
+        use tokio::io::AsyncReadExt;
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/$metadata"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"<edmx><EntitySet Name="accounts" EntityType="Microsoft.Dynamics.CRM.account" /></edmx>"#),
+            )
+            .mount(&d365)
+            .await;
+
+        let server = configured_server(&format!("{}/data/", d365.uri()), 0, "");
+
+        let input = session_input(&[json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": "tools/call",
+            "params": {
+                "name": "list_entities",
+                "arguments": {},
+                "_meta": {"progressToken": "entities"}
+            }
+        })]);
+        let (mut output, server_output) = tokio::io::duplex(4096);
+        let (result, written) = tokio::join!(
+            run_message_loop(
+                Arc::new(Ok(server)),
+                BufReader::new(input.as_bytes()),
+                server_output,
+                CancellationToken::new(),
+                Duration::from_secs(DEFAULT_SHUTDOWN_GRACE_SECS),
+            ),
+            async {
+                let mut written = String::new();
+                output.read_to_string(&mut written).await.unwrap();
+                written
+            }
+        );
+        result.unwrap();
+
+        let messages = after_initialize(&written);
+        let (last, notifications) = messages.split_last().unwrap();
+
+        assert_eq!(last["id"], 7);
+        assert!(last["result"]["content"][0]["text"]
+            .as_str()
+            .unwrap()
+            .contains("accounts"));
+        assert!(!notifications.is_empty(), "{written}");
+        for (step, notification) in notifications.iter().enumerate() {
+            assert_eq!(notification["method"], "notifications/progress");
+            assert_eq!(notification["params"]["progressToken"], "entities");
+            assert_eq!(notification["params"]["progress"], step + 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn query_entity_round_trips_through_json_rpc() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/$metadata"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"<edmx><EntitySet Name="accounts" EntityType="Microsoft.Dynamics.CRM.account" /></edmx>"#),
+            )
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .and(query_param("$top", "2"))
+            .and(query_param("$select", "name"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({"value": [{"name": "Contoso"}, {"name": "Fabrikam"}]})),
+            )
+            .expect(1)
+            .mount(&d365)
+            .await;
+
+        let server = configured_server(&format!("{}/data/", d365.uri()), 0, "");
+        let connection = Arc::new(Connection::default());
+        connection.protocol_version.set("2025-06-18").unwrap();
+
+        let response = handle_message(
+            &Ok(server),
+            &connection,
+            json!({
+                "jsonrpc": "2.0",
+                "id": "q1",
+                "method": "tools/call",
+                "params": {
+                    "name": "query_entity",
+                    "arguments": {"entity": "accounts", "top": 2, "select": "name"}
+                }
+            }),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response["jsonrpc"], "2.0");
+        assert_eq!(response["id"], "q1");
+        let result = &response["result"];
+        assert_ne!(result["isError"], json!(true), "{result}");
+        assert!(result["content"][0]["text"]
+            .as_str()
+            .unwrap()
+            .contains("Fabrikam"));
+        assert_eq!(result["structuredContent"]["entity"], "accounts");
+        assert_eq!(
+            result["structuredContent"]["records"],
+            json!([{"name": "Contoso"}, {"name": "Fabrikam"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn each_connection_calls_the_service_under_its_own_client() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+            .mount(&d365)
+            .await;
+        let server: ServerState = Ok(configured_server(&format!("{}/data/", d365.uri()), 0, ""));
+        let initialize = |name: &str| {
+            json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {
+                "protocolVersion": "2025-06-18",
+                "capabilities": {},
+                "clientInfo": {"name": name, "version": "1.0"}
+            }})
+        };
+        let call = json!({"jsonrpc": "2.0", "id": 2, "method": "tools/call",
+                          "params": {"name": "query_entity", "arguments": {"entity": "accounts"}}});
+
+        let first = Arc::new(Connection::default());
+        let second = Arc::new(Connection::default());
+        handle_message(&server, &first, initialize("first-client")).await;
+        handle_message(&server, &second, initialize("second-client")).await;
+        handle_message(&server, &first, call.clone()).await;
+        handle_message(&server, &second, call).await;
+
+        let user_agents: Vec<String> = d365
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|request| {
+                request
+                    .headers
+                    .get("user-agent")
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(user_agents.len(), 2);
+        assert!(
+            user_agents[0].contains("(first-client/1.0)"),
+            "{user_agents:?}"
+        );
+        assert!(
+            user_agents[1].contains("(second-client/1.0)"),
+            "{user_agents:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn sessions_only_see_their_own_query_history() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+            .mount(&d365)
+            .await;
+        let server: ServerState = Ok(configured_server(&format!("{}/data/", d365.uri()), 0, ""));
+        let call = |name: &str, arguments: Value| {
+            json!({"jsonrpc": "2.0", "id": 1, "method": "tools/call",
+                   "params": {"name": name, "arguments": arguments}})
+        };
+        let text = |response: Option<Value>| {
+            response.unwrap()["result"]["content"][0]["text"]
+                .as_str()
+                .unwrap()
+                .to_string()
+        };
+
+        let first = Arc::new(Connection::default());
+        let second = Arc::new(Connection::default());
+        for connection in [&first, &second] {
+            connection.protocol_version.set("2025-06-18").unwrap();
+        }
+        handle_message(
+            &server,
+            &first,
+            call("query_entity", json!({"entity": "accounts"})),
+        )
+        .await;
+
+        let own = text(handle_message(&server, &first, call("query_history", json!({}))).await);
+        assert!(own.contains("#1"), "{own}");
+        assert!(own.contains("query_entity"), "{own}");
+        let other = text(handle_message(&server, &second, call("query_history", json!({}))).await);
+        assert_eq!(other, "No tool calls recorded yet");
+        let replay =
+            text(handle_message(&server, &second, call("replay_query", json!({"index": 1}))).await);
+        assert_eq!(replay, "The query history is empty");
+    }
+
+    #[tokio::test]
+    async fn set_level_changes_what_the_connection_receives() {
+        let connection = Arc::new(Connection::default());
+        let set_level = |level: Value| json!({"jsonrpc": "2.0", "id": 1, "method": "logging/setLevel", "params": {"level": level}});
+
+        let response = handle_message(&unconfigured(), &connection, set_level(json!("error")))
+            .await
+            .unwrap();
+        assert_eq!(response["result"], json!({}));
+        assert_eq!(connection.log_level(), LoggingLevel::Error);
+
+        let response = handle_message(&unconfigured(), &connection, set_level(json!("loud")))
+            .await
+            .unwrap();
+        assert_eq!(response["error"]["code"], -32602);
+        assert_eq!(connection.log_level(), LoggingLevel::Error);
+
+        let response = respond(initialize_request(json!(2), "2025-06-18"))
+            .await
+            .unwrap();
+        assert_eq!(response["result"]["capabilities"]["logging"], json!({}));
+    }
+
+    #[tokio::test]
+    async fn tool_warnings_arrive_as_log_notifications_before_the_result() {
+        use tokio::io::AsyncReadExt;
+        use tracing_subscriber::layer::SubscriberExt;
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(logging::layer()));
+
+        // The first $metadata request is throttled, which the client logs as a warning
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/$metadata"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(1)
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/$metadata"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(r#"<edmx><EntitySet Name="accounts" EntityType="Microsoft.Dynamics.CRM.account" /></edmx>"#),
+            )
+            .mount(&d365)
+            .await;
+
+        let server = configured_server(&format!("{}/data/", d365.uri()), 3, "");
+
+        let input = session_input(&[json!({
+            "jsonrpc": "2.0",
+            "id": 8,
+            "method": "tools/call",
+            "params": {"name": "list_entities", "arguments": {}}
+        })]);
+        let (mut output, server_output) = tokio::io::duplex(4096);
+        let (result, written) = tokio::join!(
+            run_message_loop(
+                Arc::new(Ok(server)),
+                BufReader::new(input.as_bytes()),
+                server_output,
+                CancellationToken::new(),
+                Duration::from_secs(DEFAULT_SHUTDOWN_GRACE_SECS),
+            ),
+            async {
+                let mut written = String::new();
+                output.read_to_string(&mut written).await.unwrap();
+                written
+            }
+        );
+        result.unwrap();
+
+        let messages = after_initialize(&written);
+        let (last, notifications) = messages.split_last().unwrap();
+
+        assert_eq!(last["id"], 8);
+        assert!(last["result"]["content"][0]["text"]
+            .as_str()
+            .unwrap()
+            .contains("accounts"));
+        assert_eq!(notifications.len(), 1, "{written}");
+        let log = &notifications[0];
+        assert_eq!(log["method"], "notifications/message");
+        assert_eq!(log["params"]["level"], "warning");
+        assert_eq!(log["params"]["logger"], "d365_odata_mcp::odata::client");
+        assert!(log["params"]["data"]
+            .as_str()
+            .unwrap()
+            .contains("Rate limited (429)"));
+    }
+
+    #[tokio::test]
+    async fn shutdown_cancels_requests_that_outlive_the_grace_period() {
+        use tokio::io::AsyncReadExt;
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(30)))
+            .mount(&d365)
+            .await;
+
+        let server = configured_server(&format!("{}/data/", d365.uri()), 0, "");
+
+        let input = session_input(&[
+            json!({"jsonrpc": "2.0", "id": 1, "method": "tools/call", "params": {"name": "list_entities", "arguments": {}}}),
+            json!({"jsonrpc": "2.0", "id": 2, "method": "ping"}),
+            json!({"jsonrpc": "2.0", "id": 3}),
+        ]);
+        let (mut output, server_output) = tokio::io::duplex(4096);
+        let started = Instant::now();
+        let (stats, written) = tokio::join!(
+            run_message_loop(
+                Arc::new(Ok(server)),
+                BufReader::new(input.as_bytes()),
+                server_output,
+                CancellationToken::new(),
+                Duration::from_millis(200),
+            ),
+            async {
+                let mut written = String::new();
+                output.read_to_string(&mut written).await.unwrap();
+                written
+            }
+        );
+        let stats = stats.unwrap();
+
+        // The slow tool call is cancelled after the grace period, without a response
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let ids: Vec<Value> = after_initialize(&written)
+            .into_iter()
+            .filter(|message| message.get("method").is_none())
+            .map(|response| response["id"].clone())
+            .collect();
+        assert_eq!(ids.len(), 2, "{written}");
+        assert!(ids.contains(&json!(2)) && ids.contains(&json!(3)));
+        // The initialize response counts too
+        assert_eq!(stats.requests.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.errors.load(Ordering::Relaxed), 1);
+        assert!(stats.summary().contains("3 requests served, 1 errors"));
+    }
+}
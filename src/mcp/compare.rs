@@ -0,0 +1,166 @@
+//! Field-by-field record comparison
+//!
+//! `compare_record` fetches the same record from two environments and lists
+//! the fields whose values differ. [`diff_records`] compares nested objects
+//! field by field under dotted paths (`address.city`) and arrays element by
+//! element (`lines[2]`), visiting fields in sorted order so the same records
+//! always give the same diff. OData annotations such as `@odata.etag` and
+//! formatted values are never compared.
+
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+pub use crate::config::server::DEFAULT_COMPARE_IGNORED_FIELDS;
+
+/// A field whose value differs; `None` when one record lacks the field
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDifference {
+    pub path: String,
+    pub left: Option<Value>,
+    pub right: Option<Value>,
+}
+
+impl FieldDifference {
+    /// `path: left | right`, with `(missing)` for an absent field
+    pub fn summary(&self) -> String {
+        let show = |value: &Option<Value>| match value {
+            Some(value) => value.to_string(),
+            None => "(missing)".to_string(),
+        };
+        format!(
+            "{}: {} | {}",
+            self.path,
+            show(&self.left),
+            show(&self.right)
+        )
+    }
+}
+
+/// Differences between two records, ignoring fields named in `ignored`
+/// (case-insensitive, at any depth) and OData annotations
+pub fn diff_records(left: &Value, right: &Value, ignored: &[String]) -> Vec<FieldDifference> {
+    let mut differences = Vec::new();
+    diff_values("", Some(left), Some(right), ignored, &mut differences);
+    differences
+}
+
+fn diff_values(
+    path: &str,
+    left: Option<&Value>,
+    right: Option<&Value>,
+    ignored: &[String],
+    differences: &mut Vec<FieldDifference>,
+) {
+    match (left, right) {
+        (Some(Value::Object(left)), Some(Value::Object(right))) => {
+            let fields: BTreeSet<&String> = left.keys().chain(right.keys()).collect();
+            for field in fields {
+                if field.contains('@') || ignored.iter().any(|i| i.eq_ignore_ascii_case(field)) {
+                    continue;
+                }
+                let path = if path.is_empty() {
+                    field.clone()
+                } else {
+                    format!("{}.{}", path, field)
+                };
+                diff_values(
+                    &path,
+                    left.get(field),
+                    right.get(field),
+                    ignored,
+                    differences,
+                );
+            }
+        }
+        (Some(Value::Array(left)), Some(Value::Array(right))) => {
+            for index in 0..left.len().max(right.len()) {
+                diff_values(
+                    &format!("{}[{}]", path, index),
+                    left.get(index),
+                    right.get(index),
+                    ignored,
+                    differences,
+                );
+            }
+        }
+        _ if left != right => differences.push(FieldDifference {
+            path: path.to_string(),
+            left: left.cloned(),
+            right: right.cloned(),
+        }),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn ignored() -> Vec<String> {
+        DEFAULT_COMPARE_IGNORED_FIELDS
+            .iter()
+            .map(|f| f.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn only_differing_fields_are_listed_in_order() {
+        let uat = json!({
+            "@odata.etag": "W/\"1\"",
+            "name": "Contoso",
+            "creditlimit": 1000,
+            "statecode@OData.Community.Display.V1.FormattedValue": "Active",
+            "statecode": 0,
+            "ModifiedOn": "2026-01-01T00:00:00Z",
+            "versionnumber": 12,
+            "address": {"city": "Paris", "lines": ["1 Rue", "Bât A"]},
+            "telephone1": null
+        });
+        let prod = json!({
+            "@odata.etag": "W/\"9\"",
+            "address": {"lines": ["1 Rue"], "city": "Paris", "zip": "75001"},
+            "creditlimit": 2500,
+            "name": "Contoso",
+            "statecode": 0,
+            "modifiedon": "2026-03-01T00:00:00Z",
+            "versionnumber": 98
+        });
+
+        let summaries: Vec<String> = diff_records(&uat, &prod, &ignored())
+            .iter()
+            .map(FieldDifference::summary)
+            .collect();
+        assert_eq!(
+            summaries,
+            vec![
+                r#"address.lines[1]: "Bât A" | (missing)"#,
+                r#"address.zip: (missing) | "75001""#,
+                "creditlimit: 1000 | 2500",
+                "telephone1: null | (missing)",
+            ]
+        );
+        assert!(diff_records(&uat, &uat, &[]).is_empty());
+    }
+
+    #[test]
+    fn values_of_different_shapes_differ_as_a_whole() {
+        let left = json!({"tags": ["a"], "owner": {"id": 1}});
+        let right = json!({"tags": "a", "owner": null});
+        assert_eq!(
+            diff_records(&left, &right, &[]),
+            vec![
+                FieldDifference {
+                    path: "owner".to_string(),
+                    left: Some(json!({"id": 1})),
+                    right: Some(Value::Null),
+                },
+                FieldDifference {
+                    path: "tags".to_string(),
+                    left: Some(json!(["a"])),
+                    right: Some(json!("a")),
+                },
+            ]
+        );
+    }
+}
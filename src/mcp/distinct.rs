@@ -0,0 +1,190 @@
+//! Distinct values of one column
+//!
+//! `distinct_values` lets Dataverse de-duplicate with
+//! `$apply=groupby((field))`; F&O, whose `$apply` support varies by entity,
+//! pages through the column instead and [`DistinctCollector`] de-duplicates
+//! client-side, up to a cap on the records read. Either way the values come
+//! back in the same order: empty first, then booleans, numbers by value and
+//! text case-insensitively.
+
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// Display text Dataverse sends next to choice and lookup values
+const FORMATTED_VALUE: &str = "@OData.Community.Display.V1.FormattedValue";
+
+/// Where the duplicates were removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Deduplication {
+    /// `$apply=groupby` on the server
+    Server,
+    /// Client-side, over the pages read
+    Client,
+    /// Client-side, after the service rejected `$apply`
+    ApplyRejected,
+}
+
+/// One distinct value, with its display text when the service sent one
+#[derive(Debug, Clone, PartialEq)]
+pub struct DistinctValue {
+    pub value: Value,
+    pub label: Option<String>,
+}
+
+impl DistinctValue {
+    /// The value as shown in a list: text unquoted, `(empty)` for null
+    pub fn display(&self) -> String {
+        let value = match &self.value {
+            Value::Null => "(empty)".to_string(),
+            Value::String(text) => text.clone(),
+            other => other.to_string(),
+        };
+        match &self.label {
+            Some(label) if *label != value => format!("{} ({})", value, label),
+            _ => value,
+        }
+    }
+}
+
+/// Collects the distinct values of `field` across pages of records
+#[derive(Debug)]
+pub struct DistinctCollector {
+    field: String,
+    /// Keyed by the value's JSON text, so `1` and `"1"` stay apart
+    seen: HashMap<String, DistinctValue>,
+}
+
+impl DistinctCollector {
+    pub fn new(field: &str) -> Self {
+        Self {
+            field: field.to_string(),
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Add the field's value of every record; a missing field counts as null
+    pub fn add(&mut self, records: &[Value]) {
+        for record in records {
+            let value = record.get(&self.field).cloned().unwrap_or(Value::Null);
+            let label = record
+                .get(format!("{}{}", self.field, FORMATTED_VALUE))
+                .and_then(Value::as_str)
+                .map(String::from);
+            self.seen
+                .entry(value.to_string())
+                .or_insert(DistinctValue { value, label });
+        }
+    }
+
+    /// The values seen, sorted
+    pub fn finish(self) -> Vec<DistinctValue> {
+        let mut values: Vec<DistinctValue> = self.seen.into_values().collect();
+        values.sort_by(|a, b| compare_values(&a.value, &b.value));
+        values
+    }
+}
+
+/// `$apply` grouping by `field`, applying `filter` first
+pub fn groupby_apply(field: &str, filter: Option<&str>) -> String {
+    match filter.filter(|filter| !filter.trim().is_empty()) {
+        Some(filter) => format!("filter({})/groupby(({}))", filter, field),
+        None => format!("groupby(({}))", field),
+    }
+}
+
+/// Whether `field` is a plain property name that can go into `$apply`
+pub fn is_property_name(field: &str) -> bool {
+    !field.is_empty()
+        && field
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '/')
+}
+
+/// Null, then booleans, numbers by value, text case-insensitively, and
+/// anything else by its JSON text
+fn compare_values(a: &Value, b: &Value) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Number(_) => 2,
+            Value::String(_) => 3,
+            _ => 4,
+        }
+    }
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Value::String(a), Value::String(b)) => a
+            .to_lowercase()
+            .cmp(&b.to_lowercase())
+            .then_with(|| a.cmp(b)),
+        _ => rank(a)
+            .cmp(&rank(b))
+            .then_with(|| a.to_string().cmp(&b.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn values_are_deduplicated_across_pages_and_sorted() {
+        let mut collector = DistinctCollector::new("PaymentTerms");
+        collector.add(&[
+            json!({"PaymentTerms": "Net30"}),
+            json!({"PaymentTerms": "cod"}),
+            json!({"PaymentTerms": null}),
+        ]);
+        collector.add(&[
+            json!({"PaymentTerms": "Net30"}),
+            json!({"PaymentTerms": "Net10"}),
+            json!({"OtherField": 1}),
+        ]);
+
+        let shown: Vec<String> = collector
+            .finish()
+            .iter()
+            .map(DistinctValue::display)
+            .collect();
+        assert_eq!(shown, ["(empty)", "cod", "Net10", "Net30"]);
+    }
+
+    #[test]
+    fn numbers_sort_by_value_and_keep_their_labels() {
+        let mut collector = DistinctCollector::new("statecode");
+        collector.add(&[
+            json!({"statecode": 10, "statecode@OData.Community.Display.V1.FormattedValue": "Ten"}),
+            json!({"statecode": 2, "statecode@OData.Community.Display.V1.FormattedValue": "Two"}),
+            json!({"statecode": "2"}),
+        ]);
+
+        let shown: Vec<String> = collector
+            .finish()
+            .iter()
+            .map(DistinctValue::display)
+            .collect();
+        assert_eq!(shown, ["2 (Two)", "10 (Ten)", "2"]);
+    }
+
+    #[test]
+    fn groupby_applies_the_filter_first() {
+        assert_eq!(
+            groupby_apply("paymenttermscode", None),
+            "groupby((paymenttermscode))"
+        );
+        assert_eq!(
+            groupby_apply("paymenttermscode", Some("statecode eq 0")),
+            "filter(statecode eq 0)/groupby((paymenttermscode))"
+        );
+        assert!(is_property_name("_parentaccountid_value"));
+        assert!(!is_property_name("name),groupby((x"));
+        assert!(!is_property_name(""));
+    }
+}
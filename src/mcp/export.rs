@@ -0,0 +1,249 @@
+//! Query results written to local files
+//!
+//! `export_entity` hands each page to an [`ExportWriter`] as it arrives, so a
+//! large result is never held in memory or returned to the chat. CSV cells
+//! are flattened like the `csv` output format; the columns are the `select`
+//! fields, or those of the first page when there is no `select`. JSON Lines
+//! keeps each record as the service returned it, one per line.
+
+use super::format::{csv_header, csv_row, flatten_record, table_columns};
+use serde_json::{Map, Value};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// File format of an export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ExportFormat {
+    Csv,
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "jsonl" | "ndjson" => Ok(ExportFormat::Jsonl),
+            _ => Err(format!(
+                "Unknown export format: {}. Use 'csv' or 'jsonl'",
+                s
+            )),
+        }
+    }
+}
+
+/// Writes pages of records to an export file as they are fetched
+pub(crate) struct ExportWriter {
+    file: BufWriter<File>,
+    format: ExportFormat,
+    select: Option<Vec<String>>,
+    /// CSV columns, fixed by the first page
+    columns: Option<Vec<String>>,
+    rows: usize,
+    bytes: u64,
+}
+
+impl ExportWriter {
+    /// Create the file at `path`; an existing file is never overwritten
+    pub(crate) fn create(
+        path: &Path,
+        format: ExportFormat,
+        select: Option<&[String]>,
+    ) -> std::io::Result<Self> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        Ok(Self {
+            file: BufWriter::new(file),
+            format,
+            select: select.map(<[String]>::to_vec),
+            columns: None,
+            rows: 0,
+            bytes: 0,
+        })
+    }
+
+    /// Append one page of records
+    pub(crate) fn write_page(&mut self, records: &[Value]) -> std::io::Result<()> {
+        match self.format {
+            ExportFormat::Jsonl => {
+                for record in records {
+                    let line = serde_json::to_string(record)?;
+                    self.write_line(&line)?;
+                }
+            }
+            ExportFormat::Csv => {
+                let rows: Vec<Map<String, Value>> = records.iter().map(flatten_record).collect();
+                let columns = match self.columns.take() {
+                    Some(columns) => columns,
+                    None => {
+                        let columns = table_columns(&rows, self.select.as_deref());
+                        self.write_line(&csv_header(&columns))?;
+                        columns
+                    }
+                };
+                for row in &rows {
+                    self.write_line(&csv_row(&columns, row))?;
+                }
+                self.columns = Some(columns);
+            }
+        }
+        self.rows += records.len();
+        Ok(())
+    }
+
+    /// Flush the file; returns the rows and bytes written
+    pub(crate) fn finish(mut self) -> std::io::Result<(usize, u64)> {
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+        Ok((self.rows, self.bytes))
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.bytes += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+/// Resolve `path` (relative to `export_dir`, or absolute inside it) to a new
+/// file in an existing directory under `export_dir`, following symlinks in
+/// the directory part so they cannot lead elsewhere
+pub(crate) fn resolve_export_path(export_dir: &Path, path: &str) -> Result<PathBuf, String> {
+    let base = export_dir
+        .canonicalize()
+        .map_err(|e| format!("EXPORT_DIR {} is not usable: {}", export_dir.display(), e))?;
+    let requested = base.join(path.trim());
+    let outside = || {
+        format!(
+            "'{}' is outside EXPORT_DIR ({}); exports can only be written there",
+            path,
+            base.display()
+        )
+    };
+    // `..` is rejected outright, so a missing directory cannot hide it
+    if requested
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        return Err(outside());
+    }
+    let (Some(parent), Some(name)) = (requested.parent(), requested.file_name()) else {
+        return Err(format!("'{}' is not a file path", path));
+    };
+    let parent = parent
+        .canonicalize()
+        .map_err(|e| format!("Cannot write '{}': {}", path, e))?;
+    if !parent.starts_with(&base) {
+        return Err(outside());
+    }
+    Ok(parent.join(name))
+}
+
+/// Create a new export named after `stem` in `dir`, adding ` (2)`, ` (3)`...
+/// rather than overwriting an earlier export
+pub(crate) fn create_unique(
+    dir: &Path,
+    stem: &str,
+    format: ExportFormat,
+    select: Option<&[String]>,
+) -> std::io::Result<(PathBuf, ExportWriter)> {
+    std::fs::create_dir_all(dir)?;
+    for attempt in 1.. {
+        let path = match attempt {
+            1 => dir.join(format!("{}.{}", stem, format.extension())),
+            n => dir.join(format!("{} ({}).{}", stem, n, format.extension())),
+        };
+        match ExportWriter::create(&path, format, select) {
+            Ok(writer) => return Ok((path, writer)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("the attempt counter is unbounded")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("d365-export-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn csv_columns_are_fixed_by_the_first_page() {
+        let dir = temp_dir("csv");
+        let path = dir.join("accounts.csv");
+        let mut writer = ExportWriter::create(&path, ExportFormat::Csv, None).unwrap();
+        writer
+            .write_page(&[json!({
+                "@odata.etag": "W/\"1\"",
+                "name": "Contoso, Ltd",
+                "address": {"city": "Oslo"}
+            })])
+            .unwrap();
+        writer
+            .write_page(&[json!({"name": "Fabrikam", "extra": 1})])
+            .unwrap();
+        let (rows, bytes) = writer.finish().unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            text,
+            "address.city,name,etag\nOslo,\"Contoso, Ltd\",\"W/\"\"1\"\"\"\n,Fabrikam,\n"
+        );
+        assert_eq!((rows, bytes), (2, text.len() as u64));
+
+        // Never overwritten; a second default-named export gets a suffix
+        assert!(ExportWriter::create(&path, ExportFormat::Csv, None).is_err());
+        let (second, _) = create_unique(&dir, "accounts", ExportFormat::Jsonl, None).unwrap();
+        let (third, _) = create_unique(&dir, "accounts", ExportFormat::Jsonl, None).unwrap();
+        assert_eq!(second, dir.join("accounts.jsonl"));
+        assert_eq!(third, dir.join("accounts (2).jsonl"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn export_paths_stay_inside_the_export_dir() {
+        let dir = temp_dir("paths");
+        std::fs::create_dir(dir.join("march")).unwrap();
+        let base = dir.canonicalize().unwrap();
+
+        assert_eq!(
+            resolve_export_path(&dir, "march/lines.csv").unwrap(),
+            base.join("march").join("lines.csv")
+        );
+        assert_eq!(
+            resolve_export_path(&dir, &base.join("lines.csv").to_string_lossy()).unwrap(),
+            base.join("lines.csv")
+        );
+        for path in [
+            "../lines.csv",
+            "march/../../lines.csv",
+            "/tmp/lines.csv",
+            "..",
+        ] {
+            let error = resolve_export_path(&dir, path).unwrap_err();
+            assert!(error.contains("outside EXPORT_DIR"), "{path}: {error}");
+        }
+        assert!(resolve_export_path(&dir, "missing/lines.csv")
+            .unwrap_err()
+            .starts_with("Cannot write"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
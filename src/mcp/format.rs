@@ -0,0 +1,378 @@
+//! Record output formats for query results
+//!
+//! Pretty-printed JSON is the default; markdown tables and CSV are far more
+//! compact for tabular data.
+
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+
+pub use crate::config::server::OutputFormat;
+
+/// Rendered records, possibly cut short to fit a character budget
+#[derive(Debug, PartialEq)]
+pub struct RenderedRecords {
+    pub text: String,
+    /// Number of leading records included in `text`
+    pub shown: usize,
+}
+
+/// Render records in the given format, keeping only whole records within `budget` characters.
+///
+/// For `table` and `csv` the columns are the `select` fields when given,
+/// otherwise the sorted union of keys across records. Nested
+/// objects are flattened one level deep using dot notation.
+pub fn render_records(
+    records: &[Value],
+    format: OutputFormat,
+    select: Option<&[String]>,
+    budget: usize,
+) -> RenderedRecords {
+    match format {
+        OutputFormat::Json => {
+            let shown = json_records_within(records, budget);
+            RenderedRecords {
+                text: serde_json::to_string_pretty(&records[..shown])
+                    .unwrap_or_else(|_| "[]".to_string()),
+                shown,
+            }
+        }
+        OutputFormat::Table | OutputFormat::Csv => {
+            if records.is_empty() {
+                return RenderedRecords {
+                    text: String::new(),
+                    shown: 0,
+                };
+            }
+
+            let rows: Vec<Map<String, Value>> = records.iter().map(flatten_record).collect();
+            let columns = table_columns(&rows, select);
+            let (header, lines) = if format == OutputFormat::Table {
+                markdown_lines(&columns, &rows)
+            } else {
+                csv_lines(&columns, &rows)
+            };
+            take_lines(header, lines, budget)
+        }
+    }
+}
+
+/// Number of leading records whose pretty-printed JSON array fits in `budget` characters
+fn json_records_within(records: &[Value], budget: usize) -> usize {
+    // "[\n" + "\n]"
+    let mut used = 4;
+    for (index, record) in records.iter().enumerate() {
+        let pretty = serde_json::to_string_pretty(record).unwrap_or_default();
+        // Each line is indented by two spaces inside the array; records are joined by ",\n"
+        let separator = if index == 0 { 0 } else { 2 };
+        let size = pretty.chars().count() + 2 * pretty.lines().count() + separator;
+        if used + size > budget {
+            return index;
+        }
+        used += size;
+    }
+    records.len()
+}
+
+/// Join the header and as many row lines as fit in `budget` characters
+fn take_lines(header: String, lines: Vec<String>, budget: usize) -> RenderedRecords {
+    let mut text = header;
+    let mut used = text.chars().count();
+    let mut shown = 0;
+
+    for line in lines {
+        let size = line.chars().count() + 1;
+        if used + size > budget {
+            break;
+        }
+        text.push('\n');
+        text.push_str(&line);
+        used += size;
+        shown += 1;
+    }
+
+    RenderedRecords { text, shown }
+}
+
+/// Annotation carrying a record's concurrency token
+pub const ETAG_ANNOTATION: &str = "@odata.etag";
+
+/// Table and CSV column holding the record's ETag
+const ETAG_COLUMN: &str = "etag";
+
+/// A record's ETag, as accepted by `If-Match`
+pub fn record_etag(record: &Value) -> Option<&str> {
+    record.get(ETAG_ANNOTATION).and_then(Value::as_str)
+}
+
+/// Flatten nested objects one level deep (`address.city`); OData annotations
+/// are dropped, except the ETag, which becomes the `etag` column
+pub(crate) fn flatten_record(record: &Value) -> Map<String, Value> {
+    let mut flat = Map::new();
+    let Value::Object(fields) = record else {
+        flat.insert("value".to_string(), record.clone());
+        return flat;
+    };
+
+    for (key, value) in fields {
+        if key == ETAG_ANNOTATION {
+            flat.entry(ETAG_COLUMN).or_insert_with(|| value.clone());
+            continue;
+        }
+        if key.starts_with('@') {
+            continue;
+        }
+        match value {
+            Value::Object(nested) => {
+                for (nested_key, nested_value) in nested {
+                    if !nested_key.starts_with('@') {
+                        flat.insert(format!("{}.{}", key, nested_key), nested_value.clone());
+                    }
+                }
+            }
+            _ => {
+                flat.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    flat
+}
+
+/// Table and CSV columns for flattened `rows`, with the ETag last
+pub(crate) fn table_columns(rows: &[Map<String, Value>], select: Option<&[String]>) -> Vec<String> {
+    let mut columns = columns_for(rows, select);
+    // The ETag goes last, after the fields, so it can be copied for updates
+    columns.retain(|column| column != ETAG_COLUMN);
+    if rows.iter().any(|row| row.contains_key(ETAG_COLUMN)) {
+        columns.push(ETAG_COLUMN.to_string());
+    }
+    columns
+}
+
+/// Column order: selected fields (expanded to their flattened children), or
+/// the sorted union of keys so the order does not depend on which rows came back
+fn columns_for(rows: &[Map<String, Value>], select: Option<&[String]>) -> Vec<String> {
+    let mut columns: Vec<String> = Vec::new();
+    let mut push = |column: &str| {
+        if !columns.iter().any(|c| c == column) {
+            columns.push(column.to_string());
+        }
+    };
+
+    match select.filter(|fields| !fields.is_empty()) {
+        Some(fields) => {
+            for field in fields {
+                let prefix = format!("{}.", field);
+                let nested: Vec<&String> = rows
+                    .iter()
+                    .flat_map(|row| row.keys())
+                    .filter(|key| key.starts_with(&prefix))
+                    .collect();
+                if nested.is_empty() {
+                    push(field);
+                    // Labels added by `resolve_labels` sit next to their field
+                    let label = format!("{}_label", field);
+                    if rows.iter().any(|row| row.contains_key(&label)) {
+                        push(&label);
+                    }
+                } else {
+                    nested.into_iter().for_each(|key| push(key));
+                }
+            }
+        }
+        None => rows
+            .iter()
+            .flat_map(|row| row.keys())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .for_each(|key| push(key)),
+    }
+    columns
+}
+
+/// Cell text; `null` and missing values are empty
+fn cell_text(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(other) => other.to_string(),
+    }
+}
+
+fn markdown_lines(columns: &[String], rows: &[Map<String, Value>]) -> (String, Vec<String>) {
+    let escape = |text: &str| {
+        text.replace('|', "\\|")
+            .replace("\r\n", "<br>")
+            .replace('\n', "<br>")
+    };
+    let row_line = |cells: Vec<String>| format!("| {} |", cells.join(" | "));
+
+    let header = format!(
+        "{}\n{}",
+        row_line(columns.iter().map(|c| escape(c)).collect()),
+        row_line(columns.iter().map(|_| "---".to_string()).collect())
+    );
+    let lines = rows
+        .iter()
+        .map(|row| {
+            row_line(
+                columns
+                    .iter()
+                    .map(|column| escape(&cell_text(row.get(column))))
+                    .collect(),
+            )
+        })
+        .collect();
+    (header, lines)
+}
+
+fn csv_lines(columns: &[String], rows: &[Map<String, Value>]) -> (String, Vec<String>) {
+    let lines = rows.iter().map(|row| csv_row(columns, row)).collect();
+    (csv_header(columns), lines)
+}
+
+/// CSV header line for `columns`
+pub(crate) fn csv_header(columns: &[String]) -> String {
+    columns
+        .iter()
+        .map(|c| csv_field(c))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// CSV line with the cells of a flattened `row` under `columns`
+pub(crate) fn csv_row(columns: &[String], row: &Map<String, Value>) -> String {
+    columns
+        .iter()
+        .map(|column| csv_field(&cell_text(row.get(column))))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Quote a CSV field when it contains a delimiter, quote or line break (RFC 4180)
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render(records: &[Value], format: OutputFormat, select: Option<&[String]>) -> String {
+        render_records(records, format, select, usize::MAX).text
+    }
+
+    fn sample() -> Vec<Value> {
+        vec![
+            json!({
+                "@odata.etag": "W/\"1\"",
+                "name": "Contoso, Ltd",
+                "revenue": 1000.5,
+                "address": {"city": "Oslo", "zip": null}
+            }),
+            json!({
+                "name": "Say \"hi\"",
+                "phone": "555-0100",
+                "address": null
+            }),
+        ]
+    }
+
+    #[test]
+    fn parse_formats() {
+        assert_eq!("CSV".parse::<OutputFormat>().unwrap(), OutputFormat::Csv);
+        assert_eq!(
+            "markdown".parse::<OutputFormat>().unwrap(),
+            OutputFormat::Table
+        );
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+
+    #[test]
+    fn columns_are_sorted_union_of_keys() {
+        let csv = render(&sample(), OutputFormat::Csv, None);
+        let header = csv.lines().next().unwrap();
+
+        assert_eq!(
+            header,
+            "address,address.city,address.zip,name,phone,revenue,etag"
+        );
+    }
+
+    #[test]
+    fn csv_quotes_commas_quotes_and_leaves_nulls_empty() {
+        let csv = render(&sample(), OutputFormat::Csv, None);
+        let lines: Vec<&str> = csv.lines().collect();
+
+        assert_eq!(lines[1], ",Oslo,,\"Contoso, Ltd\",,1000.5,\"W/\"\"1\"\"\"");
+        assert_eq!(lines[2], ",,,\"Say \"\"hi\"\"\",555-0100,,");
+    }
+
+    #[test]
+    fn select_sets_columns_and_expands_nested_fields() {
+        let select = vec![
+            "phone".to_string(),
+            "address".to_string(),
+            "name".to_string(),
+        ];
+        let table = render(&sample(), OutputFormat::Table, Some(&select));
+        let lines: Vec<&str> = table.lines().collect();
+
+        assert_eq!(
+            lines[0],
+            "| phone | address.city | address.zip | name | etag |"
+        );
+        assert_eq!(lines[1], "| --- | --- | --- | --- | --- |");
+        assert_eq!(lines[2], "|  | Oslo |  | Contoso, Ltd | W/\"1\" |");
+        assert_eq!(lines[3], "| 555-0100 |  |  | Say \"hi\" |  |");
+    }
+
+    #[test]
+    fn table_escapes_pipes_and_newlines() {
+        let records = vec![json!({"note": "a|b\nc"})];
+
+        let table = render(&records, OutputFormat::Table, None);
+        assert_eq!(table.lines().nth(2).unwrap(), "| a\\|b<br>c |");
+    }
+
+    #[test]
+    fn json_is_unchanged_default() {
+        let records = sample();
+
+        assert_eq!(
+            render(&records, OutputFormat::default(), None),
+            serde_json::to_string_pretty(&records).unwrap()
+        );
+    }
+
+    #[test]
+    fn json_budget_matches_pretty_printed_size() {
+        let records: Vec<Value> = (0..5)
+            .map(|n| json!({"accountid": n, "name": format!("Account {n}"), "address": {"city": "Oslo"}}))
+            .collect();
+
+        for shown in 1..=records.len() {
+            let size = serde_json::to_string_pretty(&records[..shown])
+                .unwrap()
+                .chars()
+                .count();
+            assert_eq!(json_records_within(&records, size), shown);
+            assert_eq!(json_records_within(&records, size - 1), shown - 1);
+        }
+    }
+
+    #[test]
+    fn tabular_budget_keeps_whole_rows() {
+        let records: Vec<Value> = (0..10).map(|n| json!({"id": n})).collect();
+        let full = render(&records, OutputFormat::Csv, None);
+
+        let rendered = render_records(&records, OutputFormat::Csv, None, full.len() - 1);
+        assert_eq!(rendered.shown, 9);
+        assert_eq!(rendered.text.lines().count(), 10);
+        assert!(full.starts_with(&rendered.text));
+    }
+}
@@ -0,0 +1,279 @@
+//! Session query history
+//!
+//! [`QueryHistory`] keeps the last few tool calls of the session: the tool,
+//! its arguments, when it ran, whether it failed and how many rows it
+//! returned. Response bodies are never kept. `query_history` lists the
+//! entries and `replay_query` runs one again, optionally with some arguments
+//! changed. Calls naming an entity in `history_excluded_entities` are not
+//! recorded at all. Each connection, i.e. each HTTP session, keeps its own
+//! history (see [`with_query_history`]), so no session sees another's calls.
+
+use super::policy::EntityPolicy;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+/// Tools that are never recorded: they only read or replay the history
+pub const HISTORY_TOOLS: &[&str] = &["query_history", "replay_query"];
+
+tokio::task_local! {
+    static SESSION_HISTORY: Arc<QueryHistory>;
+}
+
+/// Run `future` with its tool calls recorded in, and replayed from, `history`
+pub async fn with_query_history<F: Future>(history: Arc<QueryHistory>, future: F) -> F::Output {
+    SESSION_HISTORY.scope(history, future).await
+}
+
+/// History of the session the current tool call comes from, if it has one
+pub(crate) fn session_history() -> Option<Arc<QueryHistory>> {
+    SESSION_HISTORY.try_with(Arc::clone).ok()
+}
+
+/// One recorded tool call
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    /// Position in the session, counting from 1; never reused
+    pub index: usize,
+    pub tool: String,
+    /// Arguments with keys sorted and `null`s dropped
+    pub arguments: Map<String, Value>,
+    pub at: DateTime<Utc>,
+    pub succeeded: bool,
+    /// Records returned, for tools that return records
+    pub rows: Option<usize>,
+}
+
+impl HistoryEntry {
+    /// One line for `query_history`
+    pub fn summary(&self) -> String {
+        let outcome = match (self.succeeded, self.rows) {
+            (false, _) => "error".to_string(),
+            (true, Some(1)) => "ok, 1 row".to_string(),
+            (true, Some(rows)) => format!("ok, {} rows", rows),
+            (true, None) => "ok".to_string(),
+        };
+        format!(
+            "#{} {} {} ({}) {}",
+            self.index,
+            self.at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            self.tool,
+            outcome,
+            Value::Object(self.arguments.clone())
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    recent: VecDeque<HistoryEntry>,
+    recorded: usize,
+}
+
+/// The last `capacity` tool calls of the session, oldest first
+#[derive(Debug)]
+pub struct QueryHistory {
+    capacity: usize,
+    /// Denies the excluded entities; everything else is recorded
+    excluded: EntityPolicy,
+    entries: Mutex<Entries>,
+}
+
+impl QueryHistory {
+    /// History of up to `capacity` calls (0 records nothing), leaving out
+    /// calls that name an entity matching `excluded` (`Prefix*` supported)
+    pub fn new(capacity: usize, excluded: &[String]) -> Self {
+        Self {
+            capacity,
+            excluded: EntityPolicy::new(&[], excluded),
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Record a finished call, dropping the oldest entry when full
+    pub fn record(
+        &self,
+        tool: &str,
+        args: &HashMap<String, Value>,
+        succeeded: bool,
+        rows: Option<usize>,
+    ) {
+        if !self.is_enabled() || HISTORY_TOOLS.contains(&tool) {
+            return;
+        }
+        let excluded = ["entity", "target_entity"]
+            .iter()
+            .filter_map(|key| args.get(*key).and_then(Value::as_str))
+            .any(|entity| !self.excluded.is_allowed(entity));
+        if excluded {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.recorded += 1;
+        let entry = HistoryEntry {
+            index: entries.recorded,
+            tool: tool.to_string(),
+            arguments: normalize_arguments(args),
+            at: Utc::now(),
+            succeeded,
+            rows,
+        };
+        if entries.recent.len() == self.capacity {
+            entries.recent.pop_front();
+        }
+        entries.recent.push_back(entry);
+    }
+
+    /// Entries still kept, oldest first
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        self.entries
+            .lock()
+            .unwrap()
+            .recent
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Entry `index`, or why it cannot be replayed
+    pub fn get(&self, index: usize) -> Result<HistoryEntry, String> {
+        let entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.recent.iter().find(|entry| entry.index == index) {
+            return Ok(entry.clone());
+        }
+        Err(match entries.recent.front() {
+            Some(oldest) if index < oldest.index => format!(
+                "History entry #{} is no longer kept; the oldest is #{}",
+                index, oldest.index
+            ),
+            _ if entries.recorded == 0 => "The query history is empty".to_string(),
+            _ => format!(
+                "No history entry #{}; the latest is #{}",
+                index, entries.recorded
+            ),
+        })
+    }
+}
+
+/// Arguments in a stable order without `null` values, so equal calls look alike
+fn normalize_arguments(args: &HashMap<String, Value>) -> Map<String, Value> {
+    // `Map` keeps its keys sorted
+    args.iter()
+        .filter(|(_, value)| !value.is_null())
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// Arguments of `entry` with `overrides` applied; a `null` override removes
+/// the argument
+pub fn replay_arguments(
+    entry: &HistoryEntry,
+    overrides: Option<&Map<String, Value>>,
+) -> HashMap<String, Value> {
+    let mut args: HashMap<String, Value> = entry.arguments.clone().into_iter().collect();
+    for (key, value) in overrides.into_iter().flatten() {
+        if value.is_null() {
+            args.remove(key);
+        } else {
+            args.insert(key.clone(), value.clone());
+        }
+    }
+    args
+}
+
+/// Records in a tool result's structured content, when it has any
+pub fn rows_returned(structured: Option<&Value>) -> Option<usize> {
+    structured?.get("records")?.as_array().map(Vec::len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn args(value: Value) -> HashMap<String, Value> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn history_keeps_the_latest_calls_with_stable_indices() {
+        let history = QueryHistory::new(2, &["systemusers".to_string(), "Hr*".to_string()]);
+        let query = args(json!({"entity": "accounts", "top": 5, "filter": null}));
+        history.record("query_entity", &query, true, Some(5));
+        history.record(
+            "query_entity",
+            &args(json!({"entity": "systemusers"})),
+            true,
+            Some(1),
+        );
+        history.record(
+            "get_record",
+            &args(json!({"entity": "HrWorkers"})),
+            true,
+            None,
+        );
+        history.record("query_history", &HashMap::new(), true, None);
+        history.record("whoami", &HashMap::new(), false, None);
+        history.record("list_entities", &HashMap::new(), true, None);
+
+        let entries = history.entries();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| (e.index, e.tool.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(2, "whoami"), (3, "list_entities")]
+        );
+        assert!(entries[0].summary().ends_with(" whoami (error) {}"));
+        assert_eq!(
+            history.get(1).unwrap_err(),
+            "History entry #1 is no longer kept; the oldest is #2"
+        );
+        assert_eq!(
+            history.get(4).unwrap_err(),
+            "No history entry #4; the latest is #3"
+        );
+
+        let disabled = QueryHistory::new(0, &[]);
+        disabled.record("query_entity", &query, true, Some(5));
+        assert!(disabled.entries().is_empty());
+        assert_eq!(disabled.get(1).unwrap_err(), "The query history is empty");
+    }
+
+    #[test]
+    fn replays_apply_overrides_to_the_normalized_arguments() {
+        let history = QueryHistory::new(5, &[]);
+        let query = args(json!({"top": 5, "entity": "accounts", "filter": null, "select": "name"}));
+        history.record("query_entity", &query, true, Some(5));
+
+        let entry = history.get(1).unwrap();
+        assert_eq!(
+            Value::Object(entry.arguments.clone()),
+            json!({"entity": "accounts", "select": "name", "top": 5})
+        );
+        assert!(entry.summary().ends_with(
+            r#" query_entity (ok, 5 rows) {"entity":"accounts","select":"name","top":5}"#
+        ));
+
+        let overrides = json!({"top": 10, "select": null, "filter": "name eq 'x'"});
+        assert_eq!(
+            replay_arguments(&entry, overrides.as_object()),
+            args(json!({"entity": "accounts", "top": 10, "filter": "name eq 'x'"}))
+        );
+        assert_eq!(
+            replay_arguments(&entry, None),
+            args(json!({"entity": "accounts", "select": "name", "top": 5}))
+        );
+
+        assert_eq!(rows_returned(Some(&json!({"records": [{}, {}]}))), Some(2));
+        assert_eq!(rows_returned(Some(&json!({"entity": "accounts"}))), None);
+        assert_eq!(rows_returned(None), None);
+    }
+}
@@ -0,0 +1,200 @@
+//! Human-readable labels for coded values
+//!
+//! Dataverse sends the display text of choice, lookup and formatted columns
+//! as `field@OData.Community.Display.V1.FormattedValue` annotations; these
+//! are folded into a sibling `field_label` key. F&O has no such annotations,
+//! so numeric enum values are translated with the `EnumType` members from
+//! `$metadata` instead.
+
+use crate::odata::{AnnotationMode, EnumTypeInfo};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+const FORMATTED_VALUE: &str = "@OData.Community.Display.V1.FormattedValue";
+
+/// Key holding the label of `field`
+fn label_key(field: &str) -> String {
+    format!("{}_label", field)
+}
+
+/// Fold formatted-value annotations into `field_label` keys and drop the
+/// other per-field annotations. `@odata.*` control information is kept.
+pub fn fold_formatted_values(value: &mut Value) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(fold_formatted_values),
+        Value::Object(fields) => {
+            let original = std::mem::take(fields);
+            let mut folded = Map::new();
+            for (key, mut field_value) in original {
+                match key.split_once('@') {
+                    Some((field, _)) if !field.is_empty() && key.ends_with(FORMATTED_VALUE) => {
+                        folded.insert(label_key(field), field_value);
+                    }
+                    // Lookup logical names, navigation hints and the like
+                    Some((field, annotation))
+                        if !field.is_empty() && !annotation.starts_with("odata.") => {}
+                    _ => {
+                        fold_formatted_values(&mut field_value);
+                        folded.insert(key, field_value);
+                    }
+                }
+            }
+            *fields = folded;
+        }
+        _ => {}
+    }
+}
+
+/// Fold the annotations requested with `mode` into `field_label` keys:
+/// `all` keeps `@odata.*` control information as [`fold_formatted_values`]
+/// does, `formatted-only` then drops every annotation but `@odata.etag`, and
+/// `none` has nothing to fold.
+pub fn fold_annotations(value: &mut Value, mode: AnnotationMode) {
+    match mode {
+        AnnotationMode::None => {}
+        AnnotationMode::FormattedOnly => {
+            fold_formatted_values(value);
+            drop_annotations(value);
+        }
+        AnnotationMode::All => fold_formatted_values(value),
+    }
+}
+
+/// Remove every annotation key except `@odata.etag`, at any depth
+fn drop_annotations(value: &mut Value) {
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(drop_annotations),
+        Value::Object(fields) => {
+            fields.retain(|key, _| !key.contains('@') || key == "@odata.etag");
+            fields.values_mut().for_each(drop_annotations);
+        }
+        _ => {}
+    }
+}
+
+/// Add `field_label` for numeric values of enum-typed properties
+///
+/// `properties` maps property names to their enum type, as returned by
+/// `MetadataModel::enum_properties`. Values already given as member names are left alone.
+pub fn apply_enum_labels(
+    records: &mut [Value],
+    properties: &HashMap<String, String>,
+    enums: &[EnumTypeInfo],
+) {
+    for record in records {
+        let Value::Object(fields) = record else {
+            continue;
+        };
+        let mut labels = Vec::new();
+        for (property, enum_name) in properties {
+            let Some(number) = fields.get(property).and_then(Value::as_i64) else {
+                continue;
+            };
+            let member = enums
+                .iter()
+                .find(|info| &info.name == enum_name)
+                .and_then(|info| info.member_name(number));
+            if let Some(member) = member {
+                labels.push((label_key(property), Value::String(member.to_string())));
+            }
+        }
+        fields.extend(labels);
+    }
+}
+
+/// Whether any record has an integer top-level value that could be an enum
+pub fn has_integer_values(records: &[Value]) -> bool {
+    records.iter().any(|record| {
+        record
+            .as_object()
+            .is_some_and(|fields| fields.values().any(|value| value.is_i64()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn formatted_values_become_labels() {
+        let mut record = json!({
+            "@odata.etag": "W/\"1\"",
+            "statuscode": 5,
+            "statuscode@OData.Community.Display.V1.FormattedValue": "Resolved",
+            "_parentcustomerid_value": "guid",
+            "_parentcustomerid_value@OData.Community.Display.V1.FormattedValue": "Contoso",
+            "_parentcustomerid_value@Microsoft.Dynamics.CRM.lookuplogicalname": "account",
+            "primarycontactid": {
+                "gendercode": 1,
+                "gendercode@OData.Community.Display.V1.FormattedValue": "Male"
+            }
+        });
+
+        fold_formatted_values(&mut record);
+
+        assert_eq!(
+            record,
+            json!({
+                "@odata.etag": "W/\"1\"",
+                "statuscode": 5,
+                "statuscode_label": "Resolved",
+                "_parentcustomerid_value": "guid",
+                "_parentcustomerid_value_label": "Contoso",
+                "primarycontactid": {"gendercode": 1, "gendercode_label": "Male"}
+            })
+        );
+    }
+
+    #[test]
+    fn formatted_only_output_is_at_most_half_the_raw_payload() {
+        let raw: Value =
+            serde_json::from_str(include_str!("testdata/annotated-account.json")).unwrap();
+        let mut record = raw.clone();
+
+        fold_annotations(&mut record, AnnotationMode::FormattedOnly);
+
+        assert_eq!(record["_ownerid_value_label"], "Alex Wu");
+        assert_eq!(record["industrycode_label"], "Pharmaceuticals");
+        assert_eq!(record["@odata.etag"], "W/\"4811236\"");
+        let keys: Vec<&String> = record.as_object().unwrap().keys().collect();
+        assert!(
+            keys.iter()
+                .all(|key| !key.contains('@') || *key == "@odata.etag"),
+            "{keys:?}"
+        );
+        let raw_size = raw.to_string().len();
+        let pruned_size = record.to_string().len();
+        assert!(
+            pruned_size * 2 <= raw_size,
+            "{pruned_size} bytes of {raw_size}"
+        );
+
+        let mut untouched = raw.clone();
+        fold_annotations(&mut untouched, AnnotationMode::None);
+        assert_eq!(untouched, raw);
+    }
+
+    #[test]
+    fn numeric_enum_values_are_translated() {
+        let enums = vec![EnumTypeInfo {
+            name: "SalesStatus".to_string(),
+            namespace: "Microsoft.Dynamics.DataEntities".to_string(),
+            members: vec![("Backorder".to_string(), 1), ("Invoiced".to_string(), 3)],
+        }];
+        let properties =
+            HashMap::from([("SalesOrderStatus".to_string(), "SalesStatus".to_string())]);
+        let mut records = vec![
+            json!({"SalesOrderStatus": 3}),
+            json!({"SalesOrderStatus": "Backorder"}),
+            json!({"SalesOrderStatus": 9}),
+        ];
+
+        assert!(has_integer_values(&records));
+        apply_enum_labels(&mut records, &properties, &enums);
+
+        assert_eq!(records[0]["SalesOrderStatus_label"], "Invoiced");
+        assert!(records[1].get("SalesOrderStatus_label").is_none());
+        assert!(records[2].get("SalesOrderStatus_label").is_none());
+    }
+}
@@ -0,0 +1,243 @@
+//! MCP logging: `tracing` events forwarded as `notifications/message`
+//!
+//! Many MCP hosts hide the server's stderr, so [`layer`] turns this crate's
+//! events into log notifications as well. Events raised while a request is
+//! handled go to that request's client (see [`with_log_sink`]); others go to
+//! every registered client. Each client picks its minimum level with
+//! `logging/setLevel`. Protocol traffic is logged under
+//! [`PROTOCOL_LOG_TARGET`] and never forwarded, since forwarding it would
+//! log its own notifications.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Target of the raw message debug lines, which are never forwarded
+pub const PROTOCOL_LOG_TARGET: &str = "mcp_protocol";
+
+/// Only this crate's events are forwarded, not those of HTTP dependencies
+const FORWARDED_TARGET_PREFIX: &str = "d365_odata_mcp";
+
+/// Syslog severities used by MCP, least severe first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LoggingLevel {
+    Debug,
+    Info,
+    Notice,
+    #[default]
+    Warning,
+    Error,
+    Critical,
+    Alert,
+    Emergency,
+}
+
+impl From<Level> for LoggingLevel {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::ERROR => LoggingLevel::Error,
+            Level::WARN => LoggingLevel::Warning,
+            Level::INFO => LoggingLevel::Info,
+            _ => LoggingLevel::Debug,
+        }
+    }
+}
+
+/// A client that receives log notifications
+pub trait LogSink: Send + Sync {
+    /// Least severe level the client wants
+    fn log_level(&self) -> LoggingLevel;
+    /// Deliver a `notifications/message`; returns `false` if it could not be
+    fn send_log(&self, notification: Value) -> bool;
+}
+
+tokio::task_local! {
+    static LOG_SINK: Arc<dyn LogSink>;
+}
+
+/// Run `future` with its log events going to `sink` only
+pub async fn with_log_sink<F: Future>(sink: Arc<dyn LogSink>, future: F) -> F::Output {
+    LOG_SINK.scope(sink, future).await
+}
+
+/// Clients that receive events raised outside any request
+fn registered_sinks() -> &'static Mutex<Vec<Weak<dyn LogSink>>> {
+    static SINKS: OnceLock<Mutex<Vec<Weak<dyn LogSink>>>> = OnceLock::new();
+    SINKS.get_or_init(Default::default)
+}
+
+/// Send events raised outside any request to `sink` as well, until it is dropped
+pub fn register_log_sink(sink: Weak<dyn LogSink>) {
+    let mut sinks = registered_sinks().lock().unwrap();
+    sinks.retain(|sink| sink.strong_count() > 0);
+    sinks.push(sink);
+}
+
+/// Layer forwarding this crate's events at debug level and above to MCP clients
+pub fn layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    McpLogLayer.with_filter(filter_fn(|metadata| {
+        *metadata.level() <= Level::DEBUG && metadata.target().starts_with(FORWARDED_TARGET_PREFIX)
+    }))
+}
+
+struct McpLogLayer;
+
+impl<S: Subscriber> Layer<S> for McpLogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if metadata.target().starts_with(PROTOCOL_LOG_TARGET) {
+            return;
+        }
+        let level = LoggingLevel::from(*metadata.level());
+        let notification = || {
+            let mut fields = FieldVisitor::default();
+            event.record(&mut fields);
+            json!({
+                "jsonrpc": "2.0",
+                "method": "notifications/message",
+                "params": {
+                    "level": level,
+                    "logger": metadata.target(),
+                    "data": fields.into_data(),
+                },
+            })
+        };
+        let deliver = |sink: &dyn LogSink| {
+            if level >= sink.log_level() {
+                sink.send_log(notification());
+            }
+        };
+
+        if LOG_SINK.try_with(|sink| deliver(sink.as_ref())).is_ok() {
+            return;
+        }
+        let sinks: Vec<_> = registered_sinks()
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for sink in sinks {
+            deliver(sink.as_ref());
+        }
+    }
+}
+
+/// Collects an event's message and fields
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: Map<String, Value>,
+}
+
+impl FieldVisitor {
+    /// The message alone, or an object with the message and the other fields
+    fn into_data(mut self) -> Value {
+        if self.fields.is_empty() {
+            return Value::String(self.message);
+        }
+        self.fields
+            .insert("message".to_string(), Value::String(self.message));
+        Value::Object(self.fields)
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            name => {
+                self.fields.insert(name.to_string(), value.into());
+            }
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.fields.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.fields.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.fields.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Default)]
+    struct RecordingSink {
+        level: LoggingLevel,
+        sent: Mutex<Vec<Value>>,
+    }
+
+    impl LogSink for RecordingSink {
+        fn log_level(&self) -> LoggingLevel {
+            self.level
+        }
+
+        fn send_log(&self, notification: Value) -> bool {
+            self.sent.lock().unwrap().push(notification);
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn events_at_the_sink_level_become_notifications() {
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer()));
+        let sink = Arc::new(RecordingSink::default());
+
+        with_log_sink(sink.clone(), async {
+            tracing::info!("below the default level");
+            tracing::warn!(status = 429, "Rate limited");
+            tracing::error!("Metadata download failed");
+            tracing::error!(target: PROTOCOL_LOG_TARGET, "Sending: {{}}");
+            tracing::error!(target: "hyper::proto", "dependency noise");
+        })
+        .await;
+
+        let sent = sink.sent.lock().unwrap();
+        assert_eq!(sent.len(), 2, "{sent:?}");
+        assert_eq!(sent[0]["method"], "notifications/message");
+        assert_eq!(
+            sent[0]["params"],
+            json!({
+                "level": "warning",
+                "logger": "d365_odata_mcp::mcp::logging::tests",
+                "data": {"message": "Rate limited", "status": 429}
+            })
+        );
+        assert_eq!(sent[1]["params"]["level"], "error");
+        assert_eq!(sent[1]["params"]["data"], "Metadata download failed");
+    }
+
+    #[test]
+    fn levels_parse_and_order_like_syslog() {
+        let level: LoggingLevel = serde_json::from_value(json!("notice")).unwrap();
+        assert_eq!(level, LoggingLevel::Notice);
+        assert!(LoggingLevel::Debug < LoggingLevel::Info);
+        assert!(LoggingLevel::Warning < LoggingLevel::Emergency);
+        assert!(serde_json::from_value::<LoggingLevel>(json!("verbose")).is_err());
+        assert_eq!(LoggingLevel::from(Level::TRACE), LoggingLevel::Debug);
+    }
+}
@@ -0,0 +1,211 @@
+//! Lookup binding shorthands in write payloads
+//!
+//! Setting a lookup takes `"primarycontactid@odata.bind": "/contacts(<id>)"`,
+//! which is easy to get wrong. Write tools accept two simpler shapes and
+//! rewrite them before sending:
+//!
+//! - `"primarycontactid": {"@lookup": {"entity": "contacts", "id": "<id>"}}`
+//!   (or `"key": {...}` instead of `"id"` for alternate and multi-part keys)
+//! - `"primarycontactid@bind": "contacts(<id>)"`
+//!
+//! A `null` lookup (`{"@lookup": null}` or `"primarycontactid@bind": null`)
+//! becomes `"primarycontactid@odata.bind": null`, which clears the lookup on
+//! update. Dataverse gets a URL relative to the service root; F&O an
+//! absolute one.
+
+use crate::config::ProductType;
+use crate::odata::{format_entity_key, EntityKey};
+use serde_json::{Map, Value};
+
+/// Marker object for a lookup value
+const LOOKUP_MARKER: &str = "@lookup";
+
+/// Suffix of the shorthand binding key
+const BIND_SUFFIX: &str = "@bind";
+
+/// `data` with lookup shorthands rewritten to `@odata.bind` entries
+pub(crate) fn bind_lookups(
+    data: &Value,
+    product: &ProductType,
+    endpoint: &str,
+) -> Result<Value, String> {
+    let Value::Object(fields) = data else {
+        return Ok(data.clone());
+    };
+
+    let mut bound = Map::with_capacity(fields.len());
+    for (field, value) in fields {
+        let (name, target) = if let Some(name) = field.strip_suffix(BIND_SUFFIX) {
+            (name, bind_target(name, value, product)?)
+        } else if let Some(lookup) = lookup_marker(value) {
+            (field.as_str(), lookup_target(field, lookup, product)?)
+        } else {
+            bound.insert(field.clone(), value.clone());
+            continue;
+        };
+
+        let key = format!("{}@odata.bind", name);
+        if fields.contains_key(&key) || bound.contains_key(&key) {
+            return Err(format!("Lookup '{}' is set more than once", name));
+        }
+        let url = target.map(|path| match product {
+            ProductType::Dataverse => format!("/{}", path),
+            ProductType::Finops => format!("{}{}", endpoint, path),
+        });
+        bound.insert(key, url.map_or(Value::Null, Value::from));
+    }
+    Ok(Value::Object(bound))
+}
+
+/// The `@lookup` value of an object that holds nothing else
+fn lookup_marker(value: &Value) -> Option<&Value> {
+    match value {
+        Value::Object(object) if object.len() == 1 => object.get(LOOKUP_MARKER),
+        _ => None,
+    }
+}
+
+/// `entity(key)` for a `field@bind` value: a path, a lookup object or null
+fn bind_target(
+    field: &str,
+    value: &Value,
+    product: &ProductType,
+) -> Result<Option<String>, String> {
+    let Value::String(path) = value else {
+        return lookup_target(field, value, product);
+    };
+    let path = path.trim().trim_start_matches('/');
+    match path.split_once('(') {
+        Some((entity, _)) if !entity.is_empty() && path.ends_with(')') => {
+            Ok(Some(path.to_string()))
+        }
+        _ => Err(format!(
+            "Lookup '{}@bind' must look like 'contacts(<id>)', got '{}'",
+            field, path
+        )),
+    }
+}
+
+/// `entity(key)` for a `{"entity": ..., "id" | "key": ...}` object, `None` for null
+fn lookup_target(
+    field: &str,
+    lookup: &Value,
+    product: &ProductType,
+) -> Result<Option<String>, String> {
+    let invalid = || {
+        format!(
+            "Lookup '{}' must be {{\"entity\": \"<entity set>\", \"id\": \"<id>\"}}, \
+             with \"key\": {{...}} instead of \"id\" for alternate keys, or null to clear it",
+            field
+        )
+    };
+    let lookup = match lookup {
+        Value::Null => return Ok(None),
+        Value::Object(lookup) => lookup,
+        _ => return Err(invalid()),
+    };
+    let entity = lookup
+        .get("entity")
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .filter(|entity| !entity.is_empty())
+        .ok_or_else(invalid)?;
+
+    let key = match (lookup.get("id"), lookup.get("key")) {
+        (Some(Value::String(id)), None) => {
+            format_entity_key(product, id, &[]).map_err(|e| e.to_string())?
+        }
+        (Some(Value::Number(id)), None) => id.to_string(),
+        (None, Some(Value::Object(key))) if !key.is_empty() => {
+            EntityKey::Composite(key.clone().into_iter().collect()).expression(product, None)
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(Some(format!("{}({})", entity, key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const ENDPOINT: &str = "https://contoso.operations.dynamics.com/data/";
+    const GUID: &str = "5f2a1c3e-8b7d-4e6f-9a0b-1c2d3e4f5a6b";
+
+    fn dataverse(data: Value) -> Result<Value, String> {
+        bind_lookups(&data, &ProductType::Dataverse, ENDPOINT)
+    }
+
+    #[test]
+    fn shorthands_become_odata_bind_entries() {
+        let bound = dataverse(json!({
+            "name": "Contoso",
+            "primarycontactid": {"@lookup": {"entity": "contacts", "id": GUID}},
+            "parentaccountid@bind": format!("/accounts({})", GUID),
+            "ownerid@bind": {"entity": "systemusers", "key": {"domainname": "a@b.com"}},
+            "address": {"city": "Oslo"}
+        }))
+        .unwrap();
+        assert_eq!(
+            bound,
+            json!({
+                "name": "Contoso",
+                "primarycontactid@odata.bind": format!("/contacts({})", GUID),
+                "parentaccountid@odata.bind": format!("/accounts({})", GUID),
+                "ownerid@odata.bind": "/systemusers(domainname='a@b.com')",
+                "address": {"city": "Oslo"}
+            })
+        );
+
+        // F&O binds by absolute URL, with string keys quoted
+        let bound = bind_lookups(
+            &json!({"Customer": {"@lookup": {"entity": "CustomersV3", "key": {"dataAreaId": "usmf", "CustomerAccount": "US-001"}}}}),
+            &ProductType::Finops,
+            ENDPOINT,
+        )
+        .unwrap();
+        assert_eq!(
+            bound["Customer@odata.bind"],
+            format!(
+                "{}CustomersV3(CustomerAccount='US-001',dataAreaId='usmf')",
+                ENDPOINT
+            )
+        );
+    }
+
+    #[test]
+    fn null_lookups_clear_the_binding() {
+        let bound = dataverse(json!({
+            "primarycontactid": {"@lookup": null},
+            "parentaccountid@bind": null
+        }))
+        .unwrap();
+        assert_eq!(
+            bound,
+            json!({
+                "primarycontactid@odata.bind": null,
+                "parentaccountid@odata.bind": null
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_lookups_are_rejected() {
+        for data in [
+            json!({"primarycontactid": {"@lookup": {"id": GUID}}}),
+            json!({"primarycontactid": {"@lookup": {"entity": "contacts"}}}),
+            json!({"primarycontactid": {"@lookup": "contacts"}}),
+            json!({"primarycontactid@bind": "contacts"}),
+        ] {
+            let error = dataverse(data.clone()).unwrap_err();
+            assert!(error.contains("primarycontactid"), "{data}: {error}");
+        }
+
+        let error = dataverse(json!({
+            "primarycontactid@bind": format!("contacts({})", GUID),
+            "primarycontactid@odata.bind": format!("/contacts({})", GUID)
+        }))
+        .unwrap_err();
+        assert_eq!(error, "Lookup 'primarycontactid' is set more than once");
+    }
+}
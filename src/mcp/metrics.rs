@@ -0,0 +1,423 @@
+//! Operational metrics and health
+//!
+//! [`MetricsRegistry`] counts tool calls, errors and durations per tool; the
+//! OData side (requests, retries, 429s, token refreshes, latencies) comes
+//! from each client's `RequestStats`. Counters are atomics in a map built
+//! once at startup, so recording a call takes no lock. The stats are served
+//! by the `get_server_stats` tool and, over HTTP, by `/metrics` (Prometheus
+//! text format) and `/healthz`.
+
+use crate::odata::request_log::Histogram;
+use crate::odata::{HistogramSnapshot, RequestStats, LATENCY_BUCKETS_MS};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long the service may keep failing, with no success since, before
+/// the server reports itself unhealthy
+pub const UNHEALTHY_AFTER: Duration = Duration::from_secs(300);
+
+/// Failures in a row it takes before the server reports itself unhealthy,
+/// so one stray error right after startup does not
+pub const UNHEALTHY_FAILURES: u64 = 3;
+
+/// Per-tool counters
+#[derive(Debug, Default)]
+struct ToolCounters {
+    calls: AtomicU64,
+    errors: AtomicU64,
+    duration: Histogram,
+}
+
+/// Snapshot of one tool's counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ToolStats {
+    pub calls: u64,
+    pub errors: u64,
+    pub duration: HistogramSnapshot,
+}
+
+/// Tool call counters since startup
+#[derive(Debug)]
+pub struct MetricsRegistry {
+    started: Instant,
+    /// One entry per known tool; never modified after construction
+    tools: HashMap<String, ToolCounters>,
+    /// Calls to tool names the server does not have
+    unknown: ToolCounters,
+}
+
+impl MetricsRegistry {
+    /// Registry counting calls to `tools`
+    pub fn new<'a>(tools: impl IntoIterator<Item = &'a str>) -> Self {
+        Self {
+            started: Instant::now(),
+            tools: tools
+                .into_iter()
+                .map(|name| (name.to_string(), ToolCounters::default()))
+                .collect(),
+            unknown: ToolCounters::default(),
+        }
+    }
+
+    /// Count one finished tool call
+    pub fn record_tool_call(&self, tool: &str, elapsed: Duration, failed: bool) {
+        let counters = self.tools.get(tool).unwrap_or(&self.unknown);
+        counters.calls.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            counters.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        counters.duration.observe(elapsed);
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Stats per tool, sorted by name; calls to unknown tools come last as `unknown`
+    pub fn tool_stats(&self) -> Vec<(String, ToolStats)> {
+        let snapshot = |counters: &ToolCounters| ToolStats {
+            calls: counters.calls.load(Ordering::Relaxed),
+            errors: counters.errors.load(Ordering::Relaxed),
+            duration: counters.duration.snapshot(),
+        };
+        let mut stats: Vec<(String, ToolStats)> = self
+            .tools
+            .iter()
+            .map(|(name, counters)| (name.clone(), snapshot(counters)))
+            .collect();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        stats.push(("unknown".to_string(), snapshot(&self.unknown)));
+        stats
+    }
+
+    /// Human-readable summary for the `get_server_stats` tool
+    pub fn summary(&self, requests: &RequestStats) -> String {
+        let tools = self.tool_stats();
+        let calls: u64 = tools.iter().map(|(_, stats)| stats.calls).sum();
+        let errors: u64 = tools.iter().map(|(_, stats)| stats.errors).sum();
+
+        let mut text = format!(
+            "Server stats (uptime {}):\nTool calls: {} ({} errors)\n",
+            format_duration(self.uptime()),
+            calls,
+            errors
+        );
+        for (name, stats) in tools.iter().filter(|(_, stats)| stats.calls > 0) {
+            let _ = writeln!(
+                text,
+                "- {}: {} calls, {} errors, avg {} ms, p95 {}",
+                name,
+                stats.calls,
+                stats.errors,
+                stats.duration.mean_ms(),
+                format_bound(&stats.duration, 0.95)
+            );
+        }
+        let _ = write!(
+            text,
+            "OData requests: {} sent, {} retries, {} throttled (429), {} token refreshes\n\
+             Latency: avg {} ms, p50 {}, p95 {}, p99 {}\n\
+             Last successful call: {}",
+            requests.total_requests,
+            requests.retries,
+            requests.throttled,
+            requests.token_refreshes,
+            requests.average_latency_ms,
+            format_bound(&requests.latency, 0.5),
+            format_bound(&requests.latency, 0.95),
+            format_bound(&requests.latency, 0.99),
+            match age(requests.last_success) {
+                Some(age) => format!("{} ago", format_duration(age)),
+                None => "none yet".to_string(),
+            }
+        );
+        text
+    }
+
+    /// Tool and OData metrics in the Prometheus text exposition format
+    pub fn prometheus(&self, requests: &RequestStats) -> String {
+        let mut out = String::new();
+        metric_header(
+            &mut out,
+            "d365_mcp_uptime_seconds",
+            "gauge",
+            "Seconds since the server started",
+        );
+        let _ = writeln!(out, "d365_mcp_uptime_seconds {}", self.uptime().as_secs());
+
+        let tools = self.tool_stats();
+        metric_header(
+            &mut out,
+            "d365_mcp_tool_calls_total",
+            "counter",
+            "Tool calls by tool",
+        );
+        for (name, stats) in &tools {
+            let _ = writeln!(
+                out,
+                "d365_mcp_tool_calls_total{{tool=\"{}\"}} {}",
+                name, stats.calls
+            );
+        }
+        metric_header(
+            &mut out,
+            "d365_mcp_tool_errors_total",
+            "counter",
+            "Tool calls that returned an error, by tool",
+        );
+        for (name, stats) in &tools {
+            let _ = writeln!(
+                out,
+                "d365_mcp_tool_errors_total{{tool=\"{}\"}} {}",
+                name, stats.errors
+            );
+        }
+        metric_header(
+            &mut out,
+            "d365_mcp_tool_duration_seconds",
+            "histogram",
+            "Tool call duration",
+        );
+        for (name, stats) in &tools {
+            histogram_lines(
+                &mut out,
+                "d365_mcp_tool_duration_seconds",
+                &format!("tool=\"{}\"", name),
+                &stats.duration,
+            );
+        }
+
+        for (name, help, value) in [
+            (
+                "d365_mcp_odata_requests_total",
+                "OData requests sent, retries included",
+                requests.total_requests,
+            ),
+            (
+                "d365_mcp_odata_retries_total",
+                "OData requests that retried an earlier attempt",
+                requests.retries,
+            ),
+            (
+                "d365_mcp_odata_throttled_total",
+                "OData responses with status 429",
+                requests.throttled,
+            ),
+            (
+                "d365_mcp_token_refreshes_total",
+                "Access token changes after the first token",
+                requests.token_refreshes,
+            ),
+        ] {
+            metric_header(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        metric_header(
+            &mut out,
+            "d365_mcp_odata_request_duration_seconds",
+            "histogram",
+            "Time from sending an OData request to its response headers",
+        );
+        histogram_lines(
+            &mut out,
+            "d365_mcp_odata_request_duration_seconds",
+            "",
+            &requests.latency,
+        );
+        if let Some(age) = age(requests.last_success) {
+            metric_header(
+                &mut out,
+                "d365_mcp_odata_last_success_age_seconds",
+                "gauge",
+                "Seconds since the last successful OData response",
+            );
+            let _ = writeln!(
+                out,
+                "d365_mcp_odata_last_success_age_seconds {}",
+                age.as_secs()
+            );
+        }
+        out
+    }
+}
+
+/// Outcome of the `/healthz` checks
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthReport {
+    /// Seconds until the access token expires (`None` when it is not a
+    /// JWT), or why no token could be acquired
+    pub token: Result<Option<i64>, String>,
+    pub last_success: Option<SystemTime>,
+    pub last_failure: Option<SystemTime>,
+    /// Failures since the last success
+    pub consecutive_failures: u64,
+}
+
+impl HealthReport {
+    /// Healthy when a valid token is at hand and the service has not failed
+    /// [`UNHEALTHY_FAILURES`] times in a row, with no success for longer
+    /// than [`UNHEALTHY_AFTER`]
+    pub fn healthy(&self) -> bool {
+        let failing = self.consecutive_failures >= UNHEALTHY_FAILURES
+            && age(self.last_success).is_none_or(|age| age > UNHEALTHY_AFTER);
+        self.token_valid() && !failing
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut report = json!({
+            "status": if self.healthy() { "ok" } else { "unhealthy" },
+            "last_success_age_secs": age(self.last_success).map(|age| age.as_secs()),
+            "last_failure_age_secs": age(self.last_failure).map(|age| age.as_secs()),
+            "consecutive_failures": self.consecutive_failures,
+        });
+        match &self.token {
+            Ok(expires_in) => {
+                report["token"] =
+                    json!({"valid": self.token_valid(), "expires_in_secs": expires_in})
+            }
+            Err(message) => report["token"] = json!({"valid": false, "error": message}),
+        }
+        report
+    }
+
+    fn token_valid(&self) -> bool {
+        matches!(self.token, Ok(None) | Ok(Some(1..)))
+    }
+}
+
+/// Time since `at`, if it is set
+fn age(at: Option<SystemTime>) -> Option<Duration> {
+    at.map(|at| at.elapsed().unwrap_or_default())
+}
+
+fn metric_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+/// Cumulative `_bucket` lines plus `_sum` and `_count`, in seconds;
+/// `labels` are added to every line, e.g. `tool="query_entity"`
+fn histogram_lines(out: &mut String, name: &str, labels: &str, histogram: &HistogramSnapshot) {
+    let (bucket_labels, braced) = match labels {
+        "" => (String::new(), String::new()),
+        labels => (format!("{},", labels), format!("{{{}}}", labels)),
+    };
+    let bounds = LATENCY_BUCKETS_MS
+        .iter()
+        .map(|bound| (*bound as f64 / 1000.0).to_string())
+        .chain(["+Inf".to_string()]);
+    let mut cumulative = 0;
+    for (bound, observed) in bounds.zip(histogram.buckets) {
+        cumulative += observed;
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"{}\"}} {}",
+            name, bucket_labels, bound, cumulative
+        );
+    }
+    let _ = writeln!(
+        out,
+        "{}_sum{} {}",
+        name,
+        braced,
+        histogram.sum_ms as f64 / 1000.0
+    );
+    let _ = writeln!(out, "{}_count{} {}", name, braced, histogram.count());
+}
+
+/// `≤ N ms` for the bucket holding quantile `q`
+fn format_bound(histogram: &HistogramSnapshot, q: f64) -> String {
+    match histogram.quantile_bound_ms(q) {
+        _ if histogram.count() == 0 => "-".to_string(),
+        Some(bound) => format!("≤ {} ms", bound),
+        None => format!("> {} ms", LATENCY_BUCKETS_MS[LATENCY_BUCKETS_MS.len() - 1]),
+    }
+}
+
+/// `3h 12m`, `4m 5s` or `42s`
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tool_calls_render_as_prometheus_counters_and_histograms() {
+        let registry = MetricsRegistry::new(["query_entity", "get_record"]);
+        registry.record_tool_call("query_entity", Duration::from_millis(40), false);
+        registry.record_tool_call("query_entity", Duration::from_millis(700), true);
+        registry.record_tool_call("no_such_tool", Duration::ZERO, true);
+
+        let text = registry.prometheus(&RequestStats::default());
+        for line in [
+            "# TYPE d365_mcp_tool_calls_total counter",
+            "d365_mcp_tool_calls_total{tool=\"query_entity\"} 2",
+            "d365_mcp_tool_calls_total{tool=\"get_record\"} 0",
+            "d365_mcp_tool_errors_total{tool=\"query_entity\"} 1",
+            "d365_mcp_tool_errors_total{tool=\"unknown\"} 1",
+            "d365_mcp_tool_duration_seconds_bucket{tool=\"query_entity\",le=\"0.05\"} 1",
+            "d365_mcp_tool_duration_seconds_bucket{tool=\"query_entity\",le=\"1\"} 2",
+            "d365_mcp_tool_duration_seconds_bucket{tool=\"query_entity\",le=\"+Inf\"} 2",
+            "d365_mcp_tool_duration_seconds_sum{tool=\"query_entity\"} 0.74",
+            "d365_mcp_odata_requests_total 0",
+            "d365_mcp_odata_request_duration_seconds_bucket{le=\"+Inf\"} 0",
+            "d365_mcp_odata_request_duration_seconds_count 0",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {line}:\n{text}");
+        }
+        // No success yet, so no age
+        assert!(!text.contains("last_success_age"));
+
+        let summary = registry.summary(&RequestStats::default());
+        assert!(summary.contains("Tool calls: 3 (2 errors)"), "{summary}");
+        assert!(
+            summary.contains("- query_entity: 2 calls, 1 errors, avg 370 ms, p95 ≤ 1000 ms"),
+            "{summary}"
+        );
+        assert!(!summary.contains("get_record"), "{summary}");
+        assert!(
+            summary.contains("Last successful call: none yet"),
+            "{summary}"
+        );
+    }
+
+    #[test]
+    fn health_needs_a_token_and_a_service_that_is_not_stuck_failing() {
+        let now = SystemTime::now();
+        let report = |token, last_success, last_failure, consecutive_failures| HealthReport {
+            token,
+            last_success,
+            last_failure,
+            consecutive_failures,
+        };
+
+        assert!(report(Ok(Some(3000)), None, None, 0).healthy());
+        assert!(report(Ok(None), Some(now), None, 0).healthy());
+        assert!(!report(Ok(Some(0)), None, None, 0).healthy());
+        assert!(!report(Err("invalid_client".into()), None, None, 0).healthy());
+
+        // A brief outage is tolerated; a long one without any success is not
+        let long_ago = now - Duration::from_secs(600);
+        assert!(report(Ok(None), Some(now), Some(long_ago), 0).healthy());
+        assert!(report(Ok(None), Some(now - Duration::from_secs(60)), Some(now), 5).healthy());
+        assert!(!report(Ok(None), Some(long_ago), Some(now), 5).healthy());
+        assert!(!report(Ok(None), None, Some(now), 3).healthy());
+
+        // Nor is a single failure, even before any success
+        assert!(report(Ok(None), None, Some(now), 1).healthy());
+        assert!(report(Ok(None), Some(long_ago), Some(now), 2).healthy());
+
+        let json = report(Err("invalid_client".into()), None, None, 0).to_json();
+        assert_eq!(json["status"], "unhealthy");
+        assert_eq!(json["token"]["error"], "invalid_client");
+    }
+}
@@ -2,8 +2,30 @@
 //!
 //! Exposes tools for querying and interacting with Dynamics 365 data
 
+mod compare;
+mod distinct;
+mod export;
+mod format;
+mod history;
+mod labels;
+pub mod logging;
+mod lookups;
+pub mod metrics;
+mod output;
+pub mod permissions;
+mod policy;
+mod prompts;
 pub mod protocol;
+mod search;
 mod server;
+mod validation;
 
+pub use compare::DEFAULT_COMPARE_IGNORED_FIELDS;
+pub use format::OutputFormat;
+pub use history::{with_query_history, QueryHistory};
+pub use logging::{LogSink, LoggingLevel};
+pub use metrics::{HealthReport, MetricsRegistry};
+pub use permissions::{ToolPermissions, UnlistedTools};
+pub use policy::EntityPolicy;
 pub use protocol::*;
-pub use server::D365McpServer;
+pub use server::{D365McpServer, EnvironmentLoader};
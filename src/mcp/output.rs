@@ -0,0 +1,293 @@
+//! Structured tool results
+//!
+//! `query_entity`, `get_record` and `get_metadata` return `structuredContent`
+//! next to their text, so automation can read records, counts and page tokens
+//! without parsing prose. Each builder here has a matching `outputSchema`.
+
+use super::format::record_etag;
+use crate::odata::metadata::{EntityTypeInfo, WriteCapabilities};
+use crate::odata::short_type_name;
+use serde_json::{json, Value};
+
+/// Raw annotations `_entity_type` stands in for
+const TYPE_ANNOTATIONS: &[&str] = &["@odata.type", "@odata.context"];
+
+/// `outputSchema` of `query_entity`
+pub fn query_output_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "entity": {"type": "string"},
+            "records": {
+                "type": "array",
+                "items": {"type": "object"},
+                "description": "Each record carries _entity_type, e.g. 'account', when its type is known; expanded records of a stated type do too"
+            },
+            "etags": {
+                "type": "array",
+                "items": {"type": ["string", "null"]},
+                "description": "Each record's @odata.etag, in record order; pass one as if_match for a conditional write"
+            },
+            "count": {
+                "type": ["integer", "null"],
+                "description": "Total matching records, when count was requested; Dataverse stops counting at 5000"
+            },
+            "count_limit_exceeded": {
+                "type": "boolean",
+                "description": "Dataverse only: the count stopped at its 5000 limit, so the exact number is unavailable"
+            },
+            "table_count": {
+                "type": "integer",
+                "description": "Dataverse only, with count: rows in the whole table from RetrieveTotalRecordCount, unfiltered and up to 24 hours old"
+            },
+            "next_page_token": {
+                "type": ["string", "null"],
+                "description": "Pass as page_token to fetch the next page"
+            },
+            "truncated": {
+                "type": "boolean",
+                "description": "Records were left out to stay within the response size limit"
+            }
+        },
+        "required": ["entity", "records", "etags", "count", "next_page_token", "truncated"]
+    })
+}
+
+/// `structuredContent` of `query_entity`
+pub fn query_output(
+    entity: &str,
+    records: &[Value],
+    count: Option<i64>,
+    next_page_token: Option<String>,
+    truncated: bool,
+) -> Value {
+    json!({
+        "entity": entity,
+        "records": records,
+        "etags": records.iter().map(record_etag).collect::<Vec<_>>(),
+        "count": count,
+        "next_page_token": next_page_token,
+        "truncated": truncated,
+    })
+}
+
+/// Records for `structuredContent`, each with a short `_entity_type`: its own
+/// `@odata.type`, else `context_type` read from the response's
+/// `@odata.context`. Expanded records that state a type get one as well, so
+/// polymorphic lookups can be told apart; the raw type annotations are dropped.
+pub fn with_entity_types(records: &[Value], context_type: Option<&str>) -> Vec<Value> {
+    records
+        .iter()
+        .map(|record| {
+            let mut record = record.clone();
+            tag_entity_type(&mut record, context_type);
+            record
+        })
+        .collect()
+}
+
+fn tag_entity_type(value: &mut Value, context_type: Option<&str>) {
+    match value {
+        Value::Object(fields) => {
+            let entity_type = fields
+                .get("@odata.type")
+                .and_then(Value::as_str)
+                .map(short_type_name)
+                .or(context_type)
+                .map(String::from);
+            for annotation in TYPE_ANNOTATIONS {
+                fields.remove(*annotation);
+            }
+            fields
+                .values_mut()
+                .for_each(|nested| tag_entity_type(nested, None));
+            if let Some(entity_type) = entity_type {
+                fields.insert("_entity_type".to_string(), entity_type.into());
+            }
+        }
+        Value::Array(items) => items
+            .iter_mut()
+            .for_each(|item| tag_entity_type(item, None)),
+        _ => {}
+    }
+}
+
+/// `outputSchema` of `get_record`
+pub fn record_output_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "entity": {"type": "string"},
+            "record": {"type": "object"},
+            "etag": {
+                "type": ["string", "null"],
+                "description": "The record's @odata.etag; pass it as if_match for a conditional write"
+            }
+        },
+        "required": ["entity", "record", "etag"]
+    })
+}
+
+/// `structuredContent` of `get_record`
+pub fn record_output(entity: &str, record: &Value) -> Value {
+    json!({"entity": entity, "record": record, "etag": record_etag(record)})
+}
+
+/// `outputSchema` of `get_metadata`
+pub fn metadata_output_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "entity": {"type": "string"},
+            "entity_type": {"type": "string"},
+            "keys": {"type": "array", "items": {"type": "string"}},
+            "properties": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "name": {"type": "string"},
+                        "type": {"type": "string"}
+                    },
+                    "required": ["name", "type"]
+                }
+            },
+            "navigation_properties": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "name": {"type": "string"},
+                        "target": {"type": "string"},
+                        "collection": {"type": "boolean"}
+                    },
+                    "required": ["name", "target", "collection"]
+                }
+            },
+            "writable": {
+                "type": "object",
+                "properties": {
+                    "insert": {"type": "boolean"},
+                    "update": {"type": "boolean"},
+                    "delete": {"type": "boolean"}
+                },
+                "required": ["insert", "update", "delete"],
+                "description": "Writes the entity set accepts; false only where its capabilities annotations restrict them"
+            }
+        },
+        "required": ["entity", "entity_type", "keys", "properties", "navigation_properties", "writable"]
+    })
+}
+
+/// `structuredContent` of `get_metadata`
+pub fn metadata_output(entity: &str, info: &EntityTypeInfo, writable: &WriteCapabilities) -> Value {
+    json!({
+        "entity": entity,
+        "entity_type": info.name,
+        "keys": info.keys,
+        "properties": info
+            .properties
+            .iter()
+            .map(|property| json!({"name": property.name, "type": property.type_name}))
+            .collect::<Vec<_>>(),
+        "navigation_properties": info
+            .navigation
+            .iter()
+            .map(|navigation| json!({
+                "name": navigation.name,
+                "target": navigation.target_type,
+                "collection": navigation.collection,
+            }))
+            .collect::<Vec<_>>(),
+        "writable": {
+            "insert": writable.insertable,
+            "update": writable.updatable,
+            "delete": writable.deletable,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::odata::metadata::MetadataModel;
+
+    /// Required fields are present in the output and declared in the schema
+    fn assert_matches_schema(output: &Value, schema: &Value) {
+        for field in schema["required"].as_array().unwrap() {
+            let field = field.as_str().unwrap();
+            assert!(output.get(field).is_some(), "{field} missing in {output}");
+            assert!(schema["properties"].get(field).is_some(), "{field}");
+        }
+    }
+
+    #[test]
+    fn records_are_tagged_with_their_entity_type() {
+        let records = [
+            json!({
+                "@odata.etag": "W/\"1\"",
+                "name": "Contoso",
+                "primarycontactid": {"@odata.type": "#Microsoft.Dynamics.CRM.contact", "fullname": "Jane"},
+                "parentaccountid": {"name": "Fabrikam"}
+            }),
+            json!({"@odata.type": "#Microsoft.Dynamics.CRM.competitor", "name": "Litware"}),
+        ];
+
+        let tagged = with_entity_types(&records, Some("account"));
+        assert_eq!(
+            tagged[0],
+            json!({
+                "@odata.etag": "W/\"1\"",
+                "name": "Contoso",
+                "_entity_type": "account",
+                "primarycontactid": {"_entity_type": "contact", "fullname": "Jane"},
+                "parentaccountid": {"name": "Fabrikam"}
+            })
+        );
+        assert_eq!(
+            tagged[1],
+            json!({"_entity_type": "competitor", "name": "Litware"})
+        );
+        // Nothing known, nothing added
+        assert_eq!(
+            with_entity_types(&[json!({"name": "Contoso"})], None),
+            [json!({"name": "Contoso"})]
+        );
+    }
+
+    #[test]
+    fn outputs_carry_every_required_field() {
+        let query = query_output("accounts", &[json!({"name": "Contoso"})], None, None, false);
+        assert_matches_schema(&query, &query_output_schema());
+        assert_eq!(query["count"], Value::Null);
+
+        let record = record_output(
+            "accounts",
+            &json!({"accountid": "1", "@odata.etag": "W/\"42\""}),
+        );
+        assert_matches_schema(&record, &record_output_schema());
+        assert_eq!(record["etag"], "W/\"42\"");
+
+        let model = MetadataModel::parse(
+            r#"<edmx:Edmx><Schema Namespace="Microsoft.Dynamics.CRM">
+            <EntityType Name="account"><Key><PropertyRef Name="accountid" /></Key>
+            <Property Name="accountid" Type="Edm.Guid" />
+            <NavigationProperty Name="contact_customer_accounts" Type="Collection(Microsoft.Dynamics.CRM.contact)" />
+            </EntityType>
+            <EntitySet Name="accounts" EntityType="Microsoft.Dynamics.CRM.account" />
+            </Schema></edmx:Edmx>"#,
+        );
+        let metadata = metadata_output(
+            "accounts",
+            model.entity_type("accounts").unwrap(),
+            &WriteCapabilities::default(),
+        );
+        assert_matches_schema(&metadata, &metadata_output_schema());
+        assert_eq!(metadata["keys"], json!(["accountid"]));
+        assert_eq!(
+            metadata["navigation_properties"][0],
+            json!({"name": "contact_customer_accounts", "target": "contact", "collection": true})
+        );
+        assert_eq!(metadata["writable"]["delete"], true);
+    }
+}
@@ -0,0 +1,52 @@
+//! Per-tool authorization
+//!
+//! The `[tool_permissions]` config section maps tool names, or patterns with
+//! `*` wildcards such as `create_*`, to the roles allowed to call them.
+//! Sessions get their roles when they start: an HTTP session from its API key
+//! or from a header set by a trusted gateway, the stdio connection from the
+//! configured `stdio_role`. [`D365McpServer::authorize_tool`] decides each
+//! call; tools the session may not call are also left out of `tools/list`.
+//!
+//! [`D365McpServer::authorize_tool`]: super::D365McpServer::authorize_tool
+
+use std::future::Future;
+use std::sync::Arc;
+
+pub use crate::config::server::{ToolPermissions, UnlistedTools};
+
+tokio::task_local! {
+    static SESSION_ROLES: Arc<[String]>;
+}
+
+/// Run `future` on behalf of a session holding `roles`
+pub async fn with_session_roles<F: Future>(roles: Arc<[String]>, future: F) -> F::Output {
+    SESSION_ROLES.scope(roles, future).await
+}
+
+/// Roles of the session the current tool call comes from; none outside a session
+pub(crate) fn session_roles() -> Arc<[String]> {
+    SESSION_ROLES
+        .try_with(Arc::clone)
+        .unwrap_or_else(|_| Arc::from(Vec::new()))
+}
+
+/// Split a roles header value such as `reader, writer`
+pub fn parse_roles(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|role| !role.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_headers_are_split_on_commas() {
+        assert_eq!(parse_roles(" reader,, writer "), vec!["reader", "writer"]);
+        assert!(parse_roles("").is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+//! Entity access policy
+//!
+//! Restricts which entity sets tools may touch, based on the configured
+//! `allowed_entities` and `denied_entities` lists.
+
+/// Case-insensitive entity name pattern; a trailing `*` matches any suffix
+#[derive(Debug, Clone, PartialEq)]
+enum EntityPattern {
+    Exact(String),
+    Prefix(String),
+}
+
+impl EntityPattern {
+    fn parse(pattern: &str) -> Option<Self> {
+        let pattern = pattern.trim().to_lowercase();
+        if pattern.is_empty() {
+            return None;
+        }
+
+        Some(match pattern.strip_suffix('*') {
+            Some(prefix) => EntityPattern::Prefix(prefix.to_string()),
+            None => EntityPattern::Exact(pattern),
+        })
+    }
+
+    /// `entity` must already be lowercase
+    fn matches(&self, entity: &str) -> bool {
+        match self {
+            EntityPattern::Exact(name) => entity == name,
+            EntityPattern::Prefix(prefix) => entity.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+/// Allowlist / denylist of entity sets
+///
+/// An empty allowlist allows every entity. The denylist always wins.
+#[derive(Debug, Clone, Default)]
+pub struct EntityPolicy {
+    allowed: Vec<EntityPattern>,
+    denied: Vec<EntityPattern>,
+}
+
+impl EntityPolicy {
+    /// Build a policy from configured patterns such as `CustomersV3` or `Sales*`
+    pub fn new(allowed: &[String], denied: &[String]) -> Self {
+        Self {
+            allowed: allowed
+                .iter()
+                .filter_map(|p| EntityPattern::parse(p))
+                .collect(),
+            denied: denied
+                .iter()
+                .filter_map(|p| EntityPattern::parse(p))
+                .collect(),
+        }
+    }
+
+    /// Whether any restriction is configured
+    pub fn is_restricted(&self) -> bool {
+        !self.allowed.is_empty() || !self.denied.is_empty()
+    }
+
+    /// Check an entity set name, returning an error naming the policy that blocks it
+    pub fn check(&self, entity: &str) -> Result<(), String> {
+        let name = entity.trim().to_lowercase();
+
+        if self.denied.iter().any(|pattern| pattern.matches(&name)) {
+            return Err(format!(
+                "Access to entity '{}' is blocked by the denied_entities policy",
+                entity
+            ));
+        }
+
+        if !self.allowed.is_empty() && !self.allowed.iter().any(|pattern| pattern.matches(&name)) {
+            return Err(format!(
+                "Access to entity '{}' is blocked: it is not in the allowed_entities policy",
+                entity
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Whether tools may touch the entity
+    pub fn is_allowed(&self, entity: &str) -> bool {
+        self.check(entity).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn empty_policy_allows_everything() {
+        let policy = EntityPolicy::default();
+
+        assert!(!policy.is_restricted());
+        assert!(policy.is_allowed("HcmWorkers"));
+    }
+
+    #[test]
+    fn allowlist_matching_is_case_insensitive_with_wildcards() {
+        let policy = EntityPolicy::new(&patterns(&["customersv3", "Sales*"]), &[]);
+
+        assert!(policy.is_allowed("CustomersV3"));
+        assert!(policy.is_allowed("SalesOrderHeadersV2"));
+        assert!(policy.is_allowed("salesorderlines"));
+        assert!(!policy.is_allowed("CustomersV2"));
+        assert!(!policy.is_allowed("HcmWorkers"));
+
+        let err = policy.check("HcmWorkers").unwrap_err();
+        assert!(err.contains("allowed_entities"), "{err}");
+    }
+
+    #[test]
+    fn denylist_wins_over_allowlist() {
+        let policy = EntityPolicy::new(&patterns(&["*"]), &patterns(&["HCM*", " PayrollWorkers "]));
+
+        assert!(policy.is_allowed("CustomersV3"));
+        assert!(!policy.is_allowed("HcmWorkers"));
+        assert!(!policy.is_allowed("payrollworkers"));
+
+        let err = policy.check("HcmWorkers").unwrap_err();
+        assert!(err.contains("denied_entities"), "{err}");
+    }
+}
@@ -0,0 +1,244 @@
+//! Canned prompts
+//!
+//! Prompt text is built from the live configuration and, when it can be
+//! fetched, the cached metadata. Without metadata the prompts fall back to
+//! telling the model which tool to call first.
+
+use crate::config::ProductType;
+use crate::mcp::protocol::{Prompt, PromptArgument};
+
+/// Properties embedded in a prompt before the rest are left out
+const MAX_PROMPT_PROPERTIES: usize = 15;
+
+/// Schema facts a prompt can embed
+#[derive(Debug, Clone, Default)]
+pub struct EntitySummary {
+    pub keys: Vec<String>,
+    /// `Name: Type` entries as parsed from `$metadata`
+    pub properties: Vec<String>,
+}
+
+/// Prompts offered by `prompts/list`
+pub fn prompt_definitions() -> Vec<Prompt> {
+    let argument = |name: &str, description: &str, required: bool| PromptArgument {
+        name: name.to_string(),
+        description: Some(description.to_string()),
+        required,
+    };
+
+    vec![
+        Prompt {
+            name: "explore_entity".to_string(),
+            description: Some(
+                "Explore an entity: its keys, main properties and sample queries".to_string(),
+            ),
+            arguments: vec![argument(
+                "entity",
+                "Entity set name (e.g., 'CustomersV3', 'accounts')",
+                true,
+            )],
+        },
+        Prompt {
+            name: "build_filter".to_string(),
+            description: Some(
+                "Write an OData $filter expression using this environment's conventions"
+                    .to_string(),
+            ),
+            arguments: vec![
+                argument(
+                    "goal",
+                    "Which records to select, in plain words (e.g., 'open orders over 1000 from 2024')",
+                    true,
+                ),
+                argument(
+                    "entity",
+                    "Entity set the filter is for; its properties are included when known",
+                    false,
+                ),
+            ],
+        },
+    ]
+}
+
+fn product_name(product: &ProductType) -> &'static str {
+    match product {
+        ProductType::Dataverse => "Dataverse",
+        ProductType::Finops => "Finance & Operations",
+    }
+}
+
+/// Bullet list of at most `MAX_PROMPT_PROPERTIES` properties
+fn property_lines(properties: &[String]) -> String {
+    let mut text = if properties.len() > MAX_PROMPT_PROPERTIES {
+        format!(
+            "Properties (first {} of {}):\n",
+            MAX_PROMPT_PROPERTIES,
+            properties.len()
+        )
+    } else {
+        format!("Properties ({}):\n", properties.len())
+    };
+    for property in properties.iter().take(MAX_PROMPT_PROPERTIES) {
+        text.push_str(&format!("- {}\n", property));
+    }
+    text
+}
+
+/// Text of the `explore_entity` prompt
+pub fn explore_entity_text(
+    entity: &str,
+    product: &ProductType,
+    summary: Option<&EntitySummary>,
+) -> String {
+    let mut text = format!(
+        "Explore the D365 {} entity set `{}`.\n\n",
+        product_name(product),
+        entity
+    );
+
+    match summary {
+        Some(summary) => {
+            if !summary.keys.is_empty() {
+                text.push_str(&format!("Key fields: {}\n", summary.keys.join(", ")));
+            }
+            text.push_str(&property_lines(&summary.properties));
+            text.push('\n');
+        }
+        None => text.push_str(&format!(
+            "Schema details are not available right now; call `get_metadata` with entity \
+             `{}` first to see its key fields and properties.\n\n",
+            entity
+        )),
+    }
+
+    text.push_str(&format!(
+        "Then:\n\
+         1. Call `query_entity` with entity `{}`, `top` 5 and a `select` of the key fields \
+         plus a few descriptive properties to see sample data.\n\
+         2. Summarize what the entity represents and which fields look most useful for filtering.\n\
+         3. Suggest two or three follow-up queries using `filter`, `orderby` and, where \
+         navigation properties exist, `expand`.\n",
+        entity
+    ));
+
+    if *product == ProductType::Finops {
+        text.push_str(
+            "\nRecords are scoped to the default company; pass `cross_company` true to \
+             include all legal entities.\n",
+        );
+    }
+
+    text
+}
+
+/// Text of the `build_filter` prompt
+pub fn build_filter_text(
+    product: &ProductType,
+    goal: &str,
+    entity: Option<&str>,
+    summary: Option<&EntitySummary>,
+) -> String {
+    let mut text = format!(
+        "Write an OData `$filter` expression for D365 {} that selects: {}\n\n",
+        product_name(product),
+        goal
+    );
+
+    match (entity, summary) {
+        (Some(entity), Some(summary)) => {
+            text.push_str(&format!("Entity set: `{}`\n", entity));
+            text.push_str(&property_lines(&summary.properties));
+            text.push('\n');
+        }
+        (Some(entity), None) => text.push_str(&format!(
+            "Entity set: `{}`. Its properties are not available right now; call \
+             `get_metadata` for it to check property names and types.\n\n",
+            entity
+        )),
+        (None, _) => {}
+    }
+
+    text.push_str(
+        "Syntax:\n\
+         - Comparison: `eq`, `ne`, `gt`, `ge`, `lt`, `le`; combine with `and`, `or`, `not` and parentheses\n\
+         - Strings use single quotes; double a quote inside one: `'O''Brien'`\n\
+         - Text functions: `contains(Name, 'corp')`, `startswith(Name, 'ABC')`, `endswith(Name, 'Ltd')`\n\
+         - Dates are unquoted ISO 8601: `2024-01-01T00:00:00Z`\n",
+    );
+
+    text.push_str(match product {
+        ProductType::Dataverse => {
+            "\nDataverse conventions:\n\
+             - Property names are lowercase logical names: `createdon ge 2024-01-01T00:00:00Z`\n\
+             - Choice (option set) columns compare by integer value: `statecode eq 0`\n\
+             - Lookups are filtered through `_<name>_value` with an unquoted GUID: \
+             `_parentcustomerid_value eq 00000000-0000-0000-0000-000000000000`\n"
+        }
+        ProductType::Finops => {
+            "\nFinance & Operations conventions:\n\
+             - Property names are PascalCase: `OrderDate ge 2024-01-01T00:00:00Z`\n\
+             - Enums use the qualified form: \
+             `SalesOrderStatus eq Microsoft.Dynamics.DataEntities.SalesStatus'Backorder'`\n\
+             - Filter by legal entity with `dataAreaId eq 'usmf'`; set `cross_company` to \
+             query companies other than the default one\n"
+        }
+    });
+
+    text.push_str(
+        "\nCheck the expression with `query_entity` and `top` 1 before running larger \
+         queries. Reply with the filter and a one-line explanation.\n",
+    );
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(properties: usize) -> EntitySummary {
+        EntitySummary {
+            keys: vec!["dataAreaId".to_string(), "CustomerAccount".to_string()],
+            properties: (0..properties)
+                .map(|n| format!("Field{n}: String"))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn explore_entity_embeds_keys_and_caps_properties() {
+        let text = explore_entity_text("CustomersV3", &ProductType::Finops, Some(&summary(40)));
+
+        assert!(text.contains("Key fields: dataAreaId, CustomerAccount"));
+        assert!(text.contains("Properties (first 15 of 40):"));
+        assert!(text.contains("- Field14: String"));
+        assert!(!text.contains("Field15"));
+        assert!(text.contains("cross_company"));
+    }
+
+    #[test]
+    fn explore_entity_degrades_without_metadata() {
+        let text = explore_entity_text("accounts", &ProductType::Dataverse, None);
+
+        assert!(text.contains("call `get_metadata` with entity `accounts`"));
+        assert!(!text.contains("cross_company"));
+    }
+
+    #[test]
+    fn build_filter_follows_product_conventions() {
+        let dataverse = build_filter_text(&ProductType::Dataverse, "active accounts", None, None);
+        assert!(dataverse.contains("statecode eq 0"));
+        assert!(!dataverse.contains("dataAreaId"));
+
+        let finops = build_filter_text(
+            &ProductType::Finops,
+            "backordered sales orders",
+            Some("SalesOrderHeadersV2"),
+            Some(&summary(3)),
+        );
+        assert!(finops.contains("selects: backordered sales orders"));
+        assert!(finops.contains("Entity set: `SalesOrderHeadersV2`"));
+        assert!(finops.contains("Properties (3):"));
+        assert!(finops.contains("dataAreaId eq 'usmf'"));
+    }
+}
@@ -2,6 +2,7 @@
 //!
 //! Manual implementation of Model Context Protocol (JSON-RPC 2.0 over stdio)
 
+use super::logging::LoggingLevel;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
@@ -20,7 +21,7 @@ pub struct JsonRpcRequest {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JsonRpcResponse {
     pub jsonrpc: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    /// `null` when the request id could not be determined (parse errors)
     pub id: Option<Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub result: Option<Value>,
@@ -59,15 +60,74 @@ impl JsonRpcResponse {
             }),
         }
     }
+
+    /// An error carrying structured `data` for the client
+    pub fn error_with_data(id: Option<Value>, code: i32, message: &str, data: Value) -> Self {
+        let mut response = Self::error(id, code, message);
+        if let Some(error) = response.error.as_mut() {
+            error.data = Some(data);
+        }
+        response
+    }
 }
 
 // MCP Protocol Types
 
+/// Protocol revisions the server speaks, newest first
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[&str] = &["2025-06-18", "2025-03-26", "2024-11-05"];
+
+/// The version to answer an `initialize` asking for `requested`: the newest
+/// supported revision no later than it, or `None` when the client only speaks
+/// revisions older than all of ours. Revisions are dates, so a client newer
+/// than the server gets the server's latest.
+pub fn negotiate_protocol_version(requested: &str) -> Option<&'static str> {
+    SUPPORTED_PROTOCOL_VERSIONS
+        .iter()
+        .copied()
+        .find(|version| *version <= requested.trim())
+}
+
+/// Client name and version sent in `initialize`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub name: String,
+    #[serde(default)]
+    pub version: String,
+}
+
+impl ClientInfo {
+    /// `name/version`, or just the name when no version was sent
+    pub fn product(&self) -> String {
+        if self.version.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}/{}", self.name, self.version)
+        }
+    }
+}
+
+/// Initialize request params
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InitializeParams {
+    #[serde(rename = "protocolVersion")]
+    pub protocol_version: String,
+    #[serde(default)]
+    pub capabilities: Value,
+    #[serde(rename = "clientInfo", default)]
+    pub client_info: Option<ClientInfo>,
+}
+
 /// Server capabilities
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct ServerCapabilities {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tools: Option<ToolsCapability>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resources: Option<ResourcesCapability>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompts: Option<PromptsCapability>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logging: Option<LoggingCapability>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -76,6 +136,24 @@ pub struct ToolsCapability {
     pub list_changed: Option<bool>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ResourcesCapability {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscribe: Option<bool>,
+    #[serde(rename = "listChanged", skip_serializing_if = "Option::is_none")]
+    pub list_changed: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct PromptsCapability {
+    #[serde(rename = "listChanged", skip_serializing_if = "Option::is_none")]
+    pub list_changed: Option<bool>,
+}
+
+/// The server sends `notifications/message` and accepts `logging/setLevel`
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct LoggingCapability {}
+
 /// Server info for initialize response
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServerInfo {
@@ -100,6 +178,64 @@ pub struct Tool {
     pub description: String,
     #[serde(rename = "inputSchema")]
     pub input_schema: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<ToolAnnotations>,
+    /// JSON Schema of the result's `structuredContent`
+    #[serde(
+        rename = "outputSchema",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub output_schema: Option<Value>,
+}
+
+/// Behavior hints clients may use, e.g. to confirm destructive calls
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ToolAnnotations {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// The tool does not modify its environment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_only_hint: Option<bool>,
+    /// Modifications may delete or overwrite data (meaningful when not read-only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destructive_hint: Option<bool>,
+    /// Repeating the call with the same arguments has no further effect
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idempotent_hint: Option<bool>,
+}
+
+impl ToolAnnotations {
+    /// Hints for a tool that only reads data
+    pub fn read_only(title: &str) -> Self {
+        Self {
+            title: Some(title.to_string()),
+            read_only_hint: Some(true),
+            destructive_hint: None,
+            idempotent_hint: Some(true),
+        }
+    }
+
+    /// Hints for a tool that changes data without removing any
+    pub fn write(title: &str, idempotent: bool) -> Self {
+        Self {
+            title: Some(title.to_string()),
+            read_only_hint: Some(false),
+            destructive_hint: Some(false),
+            idempotent_hint: Some(idempotent),
+        }
+    }
+
+    /// Hints for a tool that deletes or overwrites data
+    pub fn destructive(title: &str, idempotent: bool) -> Self {
+        Self {
+            title: Some(title.to_string()),
+            read_only_hint: Some(false),
+            destructive_hint: Some(true),
+            idempotent_hint: Some(idempotent),
+        }
+    }
 }
 
 /// List tools result
@@ -114,6 +250,20 @@ pub struct CallToolParams {
     pub name: String,
     #[serde(default)]
     pub arguments: Option<HashMap<String, Value>>,
+    #[serde(rename = "_meta", default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<RequestMeta>,
+}
+
+/// Request metadata (`_meta`)
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestMeta {
+    /// Progress updates are sent as `notifications/progress` tagged with this token
+    #[serde(
+        rename = "progressToken",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub progress_token: Option<Value>,
 }
 
 /// Tool result content
@@ -128,6 +278,14 @@ pub struct TextContent {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CallToolResult {
     pub content: Vec<TextContent>,
+    /// Machine-readable result matching the tool's `outputSchema`; clients
+    /// that do not know the field still get `content`
+    #[serde(
+        rename = "structuredContent",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub structured_content: Option<Value>,
     #[serde(rename = "isError", skip_serializing_if = "Option::is_none")]
     pub is_error: Option<bool>,
 }
@@ -139,36 +297,233 @@ impl CallToolResult {
                 content_type: "text".to_string(),
                 text,
             }],
+            structured_content: None,
             is_error: None,
         }
     }
 
+    /// Text for people and older clients, plus `structured` for automation
+    pub fn structured(text: String, structured: Value) -> Self {
+        Self {
+            structured_content: Some(structured),
+            ..Self::text(text)
+        }
+    }
+
     pub fn error(message: String) -> Self {
         Self {
             content: vec![TextContent {
                 content_type: "text".to_string(),
                 text: message,
             }],
+            structured_content: None,
             is_error: Some(true),
         }
     }
 }
 
+/// Resource definition
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Resource {
+    pub uri: String,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(rename = "mimeType", skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+}
+
+/// List resources result
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListResourcesResult {
+    pub resources: Vec<Resource>,
+}
+
+/// `logging/setLevel` request params
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetLevelParams {
+    pub level: LoggingLevel,
+}
+
+/// Read resource request params
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadResourceParams {
+    pub uri: String,
+}
+
+/// Text contents of a resource
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TextResourceContents {
+    pub uri: String,
+    #[serde(rename = "mimeType", skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    pub text: String,
+}
+
+/// Read resource result
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadResourceResult {
+    pub contents: Vec<TextResourceContents>,
+}
+
+/// Prompt definition
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Prompt {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub arguments: Vec<PromptArgument>,
+}
+
+/// Argument accepted by a prompt
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PromptArgument {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// List prompts result
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListPromptsResult {
+    pub prompts: Vec<Prompt>,
+}
+
+/// Get prompt request params
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetPromptParams {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: Option<HashMap<String, String>>,
+}
+
+/// Message in a rendered prompt
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PromptMessage {
+    pub role: String,
+    pub content: TextContent,
+}
+
+/// Get prompt result
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetPromptResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub messages: Vec<PromptMessage>,
+}
+
+/// Type of a tool parameter
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamType {
+    String,
+    Integer,
+    Boolean,
+    /// String restricted to the given values
+    StringEnum(Vec<String>),
+    /// JSON object with free-form properties
+    Object,
+}
+
+/// Tool parameter definition for [`create_tool_schema`]
+#[derive(Debug, Clone)]
+pub struct ToolParam {
+    pub name: String,
+    pub description: String,
+    pub param_type: ParamType,
+    pub required: bool,
+    /// Inclusive bounds, for integers
+    pub minimum: Option<i64>,
+    pub maximum: Option<i64>,
+    /// Value used when the argument is omitted; advertised in the schema
+    pub default: Option<Value>,
+}
+
+impl ToolParam {
+    fn new(name: &str, description: &str, param_type: ParamType) -> Self {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            param_type,
+            required: false,
+            minimum: None,
+            maximum: None,
+            default: None,
+        }
+    }
+
+    pub fn string(name: &str, description: &str) -> Self {
+        Self::new(name, description, ParamType::String)
+    }
+
+    pub fn integer(name: &str, description: &str) -> Self {
+        Self::new(name, description, ParamType::Integer)
+    }
+
+    pub fn boolean(name: &str, description: &str) -> Self {
+        Self::new(name, description, ParamType::Boolean)
+    }
+
+    pub fn object(name: &str, description: &str) -> Self {
+        Self::new(name, description, ParamType::Object)
+    }
+
+    pub fn string_enum(name: &str, description: &str, values: &[&str]) -> Self {
+        let values = values.iter().map(|v| v.to_string()).collect();
+        Self::new(name, description, ParamType::StringEnum(values))
+    }
+
+    /// Mark the parameter as required
+    pub fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    /// Inclusive integer bounds
+    pub fn range(mut self, minimum: Option<i64>, maximum: Option<i64>) -> Self {
+        self.minimum = minimum;
+        self.maximum = maximum;
+        self
+    }
+
+    pub fn default_value(mut self, default: impl Into<Value>) -> Self {
+        self.default = Some(default.into());
+        self
+    }
+
+    pub fn schema(&self) -> Value {
+        let mut schema = match &self.param_type {
+            ParamType::String => serde_json::json!({"type": "string"}),
+            ParamType::Integer => serde_json::json!({"type": "integer"}),
+            ParamType::Boolean => serde_json::json!({"type": "boolean"}),
+            ParamType::StringEnum(values) => serde_json::json!({"type": "string", "enum": values}),
+            ParamType::Object => serde_json::json!({"type": "object"}),
+        };
+        schema["description"] = self.description.clone().into();
+        if let Some(minimum) = self.minimum {
+            schema["minimum"] = minimum.into();
+        }
+        if let Some(maximum) = self.maximum {
+            schema["maximum"] = maximum.into();
+        }
+        if let Some(default) = &self.default {
+            schema["default"] = default.clone();
+        }
+        schema
+    }
+}
+
 /// Create a JSON Schema for tool parameters
-pub fn create_tool_schema(properties: Vec<(&str, &str, bool)>) -> Value {
+pub fn create_tool_schema(params: Vec<ToolParam>) -> Value {
     let mut props = serde_json::Map::new();
     let mut required = Vec::new();
 
-    for (name, description, is_required) in properties {
-        props.insert(
-            name.to_string(),
-            serde_json::json!({
-                "type": "string",
-                "description": description
-            }),
-        );
-        if is_required {
-            required.push(name.to_string());
+    for param in params {
+        props.insert(param.name.clone(), param.schema());
+        if param.required {
+            required.push(param.name);
         }
     }
 
@@ -178,3 +533,165 @@ pub fn create_tool_schema(properties: Vec<(&str, &str, bool)>) -> Value {
         "required": required
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn tool_annotations_serialize_camel_case() {
+        let tool = Tool {
+            name: "delete_record".to_string(),
+            description: "Delete".to_string(),
+            input_schema: json!({}),
+            annotations: Some(ToolAnnotations::destructive("Delete Record", true)),
+            output_schema: None,
+        };
+
+        assert_eq!(
+            serde_json::to_value(&tool).unwrap(),
+            json!({
+                "name": "delete_record",
+                "description": "Delete",
+                "inputSchema": {},
+                "annotations": {
+                    "title": "Delete Record",
+                    "readOnlyHint": false,
+                    "destructiveHint": true,
+                    "idempotentHint": true
+                }
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(ToolAnnotations::read_only("List")).unwrap(),
+            json!({"title": "List", "readOnlyHint": true, "idempotentHint": true})
+        );
+    }
+
+    #[test]
+    fn structured_results_keep_text_for_older_clients() {
+        /// `CallToolResult` as a client predating `structuredContent` reads it
+        #[derive(Deserialize)]
+        struct OlderClientResult {
+            content: Vec<TextContent>,
+        }
+
+        let records = json!({"records": [{"name": "Contoso"}], "count": 1});
+        let result = CallToolResult::structured("Showing 1 records".to_string(), records.clone());
+        let value = serde_json::to_value(&result).unwrap();
+        assert_eq!(value["structuredContent"], records);
+        assert_eq!(value["content"][0]["type"], "text");
+
+        let older: OlderClientResult = serde_json::from_value(value).unwrap();
+        assert_eq!(older.content[0].text, "Showing 1 records");
+
+        let plain = serde_json::to_value(CallToolResult::text("ok".to_string())).unwrap();
+        assert!(plain.get("structuredContent").is_none(), "{plain}");
+
+        let tool = Tool {
+            name: "get_record".to_string(),
+            description: "Get".to_string(),
+            input_schema: json!({}),
+            annotations: None,
+            output_schema: Some(json!({"type": "object"})),
+        };
+        assert_eq!(
+            serde_json::to_value(&tool).unwrap()["outputSchema"],
+            json!({"type": "object"})
+        );
+    }
+
+    #[test]
+    fn protocol_versions_negotiate_down_to_the_newest_shared_revision() {
+        for (requested, expected) in [
+            ("2025-06-18", Some("2025-06-18")),
+            ("2025-03-26", Some("2025-03-26")),
+            ("2024-11-05", Some("2024-11-05")),
+            // Newer than the server: our latest, and the client decides
+            ("2026-01-01", Some("2025-06-18")),
+            // An unknown revision between two we know
+            ("2025-01-15", Some("2024-11-05")),
+            ("2024-10-07", None),
+            ("", None),
+        ] {
+            assert_eq!(
+                negotiate_protocol_version(requested),
+                expected,
+                "{requested}"
+            );
+        }
+    }
+
+    #[test]
+    fn initialize_params_carry_the_client_info() {
+        let params: InitializeParams = serde_json::from_value(json!({
+            "protocolVersion": "2025-03-26",
+            "capabilities": {"roots": {}},
+            "clientInfo": {"name": "claude-desktop", "version": "0.9.2"}
+        }))
+        .unwrap();
+        assert_eq!(params.protocol_version, "2025-03-26");
+        assert_eq!(
+            params.client_info.unwrap().product(),
+            "claude-desktop/0.9.2"
+        );
+
+        let bare: InitializeParams =
+            serde_json::from_value(json!({"protocolVersion": "2024-11-05"})).unwrap();
+        assert!(bare.client_info.is_none());
+        let unversioned = ClientInfo {
+            name: "inspector".to_string(),
+            version: String::new(),
+        };
+        assert_eq!(unversioned.product(), "inspector");
+
+        assert!(serde_json::from_value::<InitializeParams>(json!({"clientInfo": {}})).is_err());
+    }
+
+    #[test]
+    fn tools_without_annotations_omit_the_field() {
+        let tool = Tool {
+            name: "ping".to_string(),
+            description: "Ping".to_string(),
+            input_schema: json!({}),
+            annotations: None,
+            output_schema: None,
+        };
+
+        let value = serde_json::to_value(&tool).unwrap();
+        assert!(value.get("annotations").is_none(), "{value}");
+    }
+
+    #[test]
+    fn tool_schema_carries_types_bounds_and_defaults() {
+        let schema = create_tool_schema(vec![
+            ToolParam::string("entity", "Entity set").required(),
+            ToolParam::integer("top", "Page size")
+                .range(Some(1), Some(1000))
+                .default_value(50),
+            ToolParam::boolean("count", "Include count").default_value(false),
+            ToolParam::string_enum("format", "Output format", &["json", "csv"]),
+        ]);
+
+        assert_eq!(
+            schema,
+            json!({
+                "type": "object",
+                "properties": {
+                    "entity": {"type": "string", "description": "Entity set"},
+                    "top": {
+                        "type": "integer",
+                        "description": "Page size",
+                        "minimum": 1,
+                        "maximum": 1000,
+                        "default": 50
+                    },
+                    "count": {"type": "boolean", "description": "Include count", "default": false},
+                    "format": {"type": "string", "enum": ["json", "csv"], "description": "Output format"}
+                },
+                "required": ["entity"]
+            })
+        );
+    }
+}
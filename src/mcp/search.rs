@@ -0,0 +1,136 @@
+//! Fuzzy entity set search
+//!
+//! Ranks entity set names against a free-text query so that "sales order
+//! header" finds `SalesOrderHeadersV2` among thousands of F&O entity sets.
+
+use std::collections::HashSet;
+
+/// Lowercase letters and digits only, so spaces and underscores do not matter
+fn normalize(text: &str) -> String {
+    text.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Whether every character of `query` appears in `name` in order
+fn is_subsequence(query: &str, name: &str) -> bool {
+    let mut chars = name.chars();
+    query.chars().all(|q| chars.any(|c| c == q))
+}
+
+fn trigrams(text: &str) -> HashSet<[char; 3]> {
+    let chars: Vec<char> = text.chars().collect();
+    chars.windows(3).map(|w| [w[0], w[1], w[2]]).collect()
+}
+
+/// Share of the query's trigrams found in the name
+fn trigram_overlap(query: &str, name: &str) -> f64 {
+    let wanted = trigrams(query);
+    if wanted.is_empty() {
+        return 0.0;
+    }
+    let found = trigrams(name);
+    wanted.intersection(&found).count() as f64 / wanted.len() as f64
+}
+
+/// Relevance of `name` for a normalized query; `None` when it does not match
+fn score(query: &str, name: &str) -> Option<u32> {
+    let name = normalize(name);
+    // Shorter names win ties: `Customers` before `CustomersV3Extended`
+    let length_penalty = name.len().saturating_sub(query.len()).min(99) as u32;
+
+    if name == query {
+        Some(1000)
+    } else if name.starts_with(query) {
+        Some(900 - length_penalty)
+    } else if let Some(position) = name.find(query) {
+        Some(800 - (position.min(99) as u32) - length_penalty)
+    } else if is_subsequence(query, &name) {
+        Some(500 - length_penalty)
+    } else {
+        let overlap = trigram_overlap(query, &name);
+        (overlap >= 0.7).then_some((overlap * 400.0) as u32)
+    }
+}
+
+/// Best matches among `(entity set, entity type)` pairs, best first
+pub fn rank_entity_sets<'a>(
+    entity_sets: impl IntoIterator<Item = &'a (String, String)>,
+    query: &str,
+    limit: usize,
+) -> Vec<&'a (String, String)> {
+    let query = normalize(query);
+    if query.is_empty() {
+        return Vec::new();
+    }
+
+    let mut scored: Vec<(u32, &(String, String))> = entity_sets
+        .into_iter()
+        .filter_map(|pair| {
+            let by_type = score(&query, &pair.1).map(|s| s.saturating_sub(50));
+            score(&query, &pair.0).max(by_type).map(|s| (s, pair))
+        })
+        .collect();
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1 .0.cmp(&b.1 .0)));
+    scored
+        .into_iter()
+        .take(limit)
+        .map(|(_, pair)| pair)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sets(names: &[(&str, &str)]) -> Vec<(String, String)> {
+        names
+            .iter()
+            .map(|(set, entity_type)| (set.to_string(), entity_type.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn exact_prefix_and_substring_matches_rank_first() {
+        let sets = sets(&[
+            ("SalesOrderLines", "SalesOrderLine"),
+            ("SalesOrderHeadersV2", "SalesOrderHeaderV2"),
+            ("ReturnOrderHeaders", "ReturnOrderHeader"),
+            ("CustomersV3", "CustomerV3"),
+        ]);
+
+        let names: Vec<&str> = rank_entity_sets(&sets, "sales order header", 10)
+            .iter()
+            .map(|(set, _)| set.as_str())
+            .collect();
+        assert_eq!(names, vec!["SalesOrderHeadersV2"]);
+
+        let names: Vec<&str> = rank_entity_sets(&sets, "order", 2)
+            .iter()
+            .map(|(set, _)| set.as_str())
+            .collect();
+        assert_eq!(names, vec!["SalesOrderLines", "ReturnOrderHeaders"]);
+    }
+
+    #[test]
+    fn abbreviations_and_typos_still_match() {
+        let sets = sets(&[
+            ("SalesOrderHeadersV2", "SalesOrderHeaderV2"),
+            ("Vendors", "Vendor"),
+        ]);
+
+        // Subsequence
+        assert_eq!(
+            rank_entity_sets(&sets, "SOHdr", 5)[0].0,
+            "SalesOrderHeadersV2"
+        );
+        // Trigram overlap despite a typo
+        assert_eq!(
+            rank_entity_sets(&sets, "salesordrheaders", 5)[0].0,
+            "SalesOrderHeadersV2"
+        );
+        assert!(rank_entity_sets(&sets, "xyz", 5).is_empty());
+        assert!(rank_entity_sets(&sets, "  ", 5).is_empty());
+    }
+}
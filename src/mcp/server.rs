@@ -2,505 +2,9571 @@
 //!
 //! Exposes tools for querying and interacting with Dynamics 365 data
 
-use crate::config::RuntimeConfig;
+use crate::auth::decode_jwt_claims;
+use crate::config::{EntityConfig, ProductType, RuntimeConfig};
+use crate::mcp::compare::diff_records;
+use crate::mcp::distinct::{
+    groupby_apply, is_property_name, Deduplication, DistinctCollector, DistinctValue,
+};
+use crate::mcp::export::{create_unique, resolve_export_path, ExportFormat, ExportWriter};
+use crate::mcp::format::{record_etag, render_records, OutputFormat};
+use crate::mcp::history::{replay_arguments, rows_returned, session_history, QueryHistory};
+use crate::mcp::labels::{apply_enum_labels, fold_annotations, has_integer_values};
+use crate::mcp::lookups::bind_lookups;
+use crate::mcp::metrics::{HealthReport, MetricsRegistry};
+use crate::mcp::output::{
+    metadata_output, metadata_output_schema, query_output, query_output_schema, record_output,
+    record_output_schema, with_entity_types,
+};
+use crate::mcp::permissions::session_roles;
+use crate::mcp::policy::EntityPolicy;
+use crate::mcp::prompts::{
+    build_filter_text, explore_entity_text, prompt_definitions, EntitySummary,
+};
 use crate::mcp::protocol::*;
-use crate::odata::{ODataClient, QueryOptions};
+use crate::mcp::search::rank_entity_sets;
+use crate::mcp::validation::validate_arguments;
+use crate::mcp::UnlistedTools;
+use crate::odata::client::is_guid;
+use crate::odata::client::same_origin;
+use crate::odata::diagnostics::DEFAULT_STAGE_TIMEOUT;
+use crate::odata::dry_run::without_dry_run;
+use crate::odata::metadata::{
+    EnumTypeInfo, MetadataModel, NavigationInfo, WriteCapabilities, WriteOperation,
+};
+use crate::odata::record_count::DATAVERSE_COUNT_LIMIT;
+use crate::odata::request_log::{mcp_client, new_client_request_id, with_failed_request_ids};
+use crate::odata::views::{fetch_xml_tables, limit_fetch_xml};
+use crate::odata::{
+    format_entity_key, is_dry_run, known_language, parse_context_url, parse_language_tag,
+    short_type_name, validate_filter, with_caller, with_dry_run, with_language, with_progress,
+    with_timeout, ActionOutcome, AnnotationMode, AuditEntry, AuditStatus, BackpressureStats,
+    ChangesetError, CircuitState, CircuitStats, ConnectionReport, EntityKey, ODataClient,
+    ODataError, ODataResponse, OperationState, OperationStatus, PackageImport, PreparedRequest,
+    ProgressReporter, QueryOptions, RateLimiterStats, RequestStats, SearchHit, SearchResults,
+    StreamSummary, UpsertOutcome, ViewKind,
+};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use reqwest::Url;
 use serde_json::Value;
 use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::io::{ErrorKind, Write};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tracing::Instrument;
 
-/// MCP Server for D365 OData
-pub struct D365McpServer {
+/// Tools that modify data; hidden and rejected in read-only mode
+const MUTATING_TOOLS: &[&str] = &[
+    "delete_record",
+    "dmf_import_package",
+    "upsert_record",
+    "bulk_update",
+    "upload_file",
+    "associate_records",
+    "disassociate_records",
+    "execute_action",
+];
+
+/// Whether a tool modifies data
+fn is_mutating_tool(name: &str) -> bool {
+    MUTATING_TOOLS.contains(&name)
+}
+
+/// Writes a call may make to the records of its `entity`; any one of them
+/// being allowed lets the call through. Notes and links are records of their
+/// own, so attaching or linking does not count.
+fn write_operations(name: &str, args: &HashMap<String, Value>) -> Vec<WriteOperation> {
+    match name {
+        "delete_record" => vec![WriteOperation::Delete],
+        "bulk_update" => vec![WriteOperation::Update],
+        "upload_file" if get_str(args, "attribute").is_some_and(|a| !a.trim().is_empty()) => {
+            vec![WriteOperation::Update]
+        }
+        "upsert_record" => [
+            (WriteOperation::Insert, "prevent_create"),
+            (WriteOperation::Update, "prevent_update"),
+        ]
+        .into_iter()
+        .filter(|(_, prevented)| !get_bool(args, prevented).unwrap_or(false))
+        .map(|(operation, _)| operation)
+        .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Tools without the `timeout` argument: no OData request, or their own bound
+const UNTIMED_TOOLS: &[&str] = &[
+    "list_environments",
+    "switch_environment",
+    "test_connection",
+    "get_server_stats",
+    "query_history",
+    "replay_query",
+];
+
+/// Tools about the environments themselves, which take no `environment` argument
+const UNROUTED_TOOLS: &[&str] = &[
+    "list_environments",
+    "switch_environment",
+    "get_server_stats",
+    "query_history",
+    "replay_query",
+    "compare_record",
+];
+
+/// Longest per-call `timeout` a tool accepts, in seconds
+const MAX_CALL_TIMEOUT_SECS: i64 = 3600;
+
+/// Tools that only make sense for Finance & Operations
+const FINOPS_TOOLS: &[&str] = &[
+    "list_companies",
+    "dmf_import_package",
+    "dmf_get_execution_status",
+];
+
+/// Tools that only make sense for Dataverse
+const DATAVERSE_TOOLS: &[&str] = &[
+    "associate_records",
+    "disassociate_records",
+    "download_file",
+    "upload_file",
+    "search",
+    "get_record_audit",
+    "list_views",
+    "run_view",
+];
+
+/// Whether a tool applies to the given product
+fn is_available_for(name: &str, product: &ProductType) -> bool {
+    match product {
+        ProductType::Dataverse => !FINOPS_TOOLS.contains(&name),
+        ProductType::Finops => !DATAVERSE_TOOLS.contains(&name),
+    }
+}
+
+/// Arguments of `associate_records` / `disassociate_records`
+struct RecordLink<'a> {
+    entity: &'a str,
+    key: EntityKey,
+    navigation: &'a str,
+    target: Option<(&'a str, EntityKey)>,
+}
+
+/// Legal entities as (code, name), with the entity set they were read from
+struct CompanyList {
+    companies: Vec<(String, String)>,
+    source: &'static str,
+    /// Paging stopped at the call's timeout; never cached
+    timed_out: bool,
+}
+
+/// Entity sets holding legal entities, tried in order, with their code and name fields
+const COMPANY_SOURCES: &[(&str, &str, &str)] = &[
+    ("Companies", "DataArea", "Name"),
+    ("LegalEntities", "LegalEntityId", "Name"),
+    ("CompanyInfo", "DataArea", "Name"),
+];
+
+/// `query_entity` page size when `top` is omitted
+const DEFAULT_TOP: usize = 50;
+
+/// Largest `top` accepted by `query_entity`
+const MAX_TOP: usize = 1000;
+
+/// `top` bounds of `query_entity` for one entity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TopLimits {
+    /// Page size when the call passes no `top`
+    default: usize,
+    /// Largest `top` sent; larger ones are lowered to it
+    max: usize,
+}
+
+/// What the entity's configuration changed about a `query_entity` call
+#[derive(Debug, Default)]
+struct AppliedDefaults {
+    /// The configured `default_select` was used
+    default_select: bool,
+    /// Why `top` was lowered, when it was
+    top_note: Option<String>,
+}
+
+/// Entity sets per `list_entities` page when no limit is given
+const DEFAULT_ENTITY_PAGE: usize = 200;
+
+/// Matches returned by `search_entities` when no limit is given
+const DEFAULT_SEARCH_RESULTS: usize = 10;
+
+/// Records per `$batch` changeset when `bulk_update` gets no chunk_size
+const DEFAULT_BULK_CHUNK: usize = 100;
+
+/// Largest `bulk_update` chunk; Dataverse caps a `$batch` at 1000 requests
+const MAX_BULK_CHUNK: i64 = 1000;
+
+/// How often `dmf_get_execution_status` asks for the status while waiting
+const DMF_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest `wait_secs` of `dmf_get_execution_status`
+const MAX_DMF_WAIT_SECS: i64 = 600;
+
+/// How long `execute_action` waits for a long-running operation by default
+const DEFAULT_OPERATION_WAIT_SECS: usize = 60;
+
+/// Longest wait of `execute_action` and `check_operation`
+const MAX_OPERATION_WAIT_SECS: i64 = 600;
+
+/// Most recent changes `get_record_audit` lists when no top is given
+const DEFAULT_AUDIT_ENTRIES: usize = 50;
+
+/// Largest file `download_file` returns as base64 instead of writing to disk
+const INLINE_FILE_LIMIT: usize = 48 * 1024;
+
+/// Resource holding the raw EDMX `$metadata` document
+const METADATA_RESOURCE_URI: &str = "d365://metadata";
+
+/// JSON-RPC error code for an unknown resource (MCP spec)
+const RESOURCE_NOT_FOUND: i32 = -32002;
+
+/// URI of an entity's schema resource
+fn entity_schema_uri(entity: &str) -> String {
+    format!("d365://entity/{}/schema", entity)
+}
+
+fn rpc_error(code: i32, message: String) -> JsonRpcError {
+    JsonRpcError {
+        code,
+        message,
+        data: None,
+    }
+}
+
+/// Builds the client and runtime config of a named environment, for `switch_environment`
+pub type EnvironmentLoader =
+    Arc<dyn Fn(&str) -> Result<(Arc<ODataClient>, Arc<RuntimeConfig>), String> + Send + Sync>;
+
+/// Client, config and caches of one environment; tools run against the
+/// active one unless a call names another with `environment`
+#[derive(Clone)]
+struct ActiveEnvironment {
     client: Arc<ODataClient>,
     config: Arc<RuntimeConfig>,
+    entity_policy: Arc<EntityPolicy>,
+    /// Legal entities, kept for the session and cleared by `refresh_metadata`
+    companies: Arc<RwLock<Option<CompanyList>>>,
+}
+
+impl ActiveEnvironment {
+    fn new(client: Arc<ODataClient>, config: Arc<RuntimeConfig>) -> Self {
+        let entity_policy = EntityPolicy::new(&config.allowed_entities, &config.denied_entities);
+        Self {
+            client,
+            config,
+            entity_policy: Arc::new(entity_policy),
+            companies: Arc::new(RwLock::new(None)),
+        }
+    }
+}
+
+tokio::task_local! {
+    /// Environment the current call runs against: the one its `environment`
+    /// argument names, or the active one when the call started
+    static ROUTED: ActiveEnvironment;
+}
+
+/// MCP Server for D365 OData
+pub struct D365McpServer {
+    active: std::sync::RwLock<ActiveEnvironment>,
+    /// Environments loaded so far, so each keeps its client and caches
+    loaded: std::sync::Mutex<HashMap<String, ActiveEnvironment>>,
+    environment_loader: std::sync::RwLock<Option<EnvironmentLoader>>,
+    /// Tool call counters since startup
+    metrics: MetricsRegistry,
+    /// Recent tool calls for `query_history` and `replay_query`, when they
+    /// are made outside a session with its own history
+    history: Arc<QueryHistory>,
 }
 
 impl D365McpServer {
     /// Create a new MCP server instance
     pub fn new(client: Arc<ODataClient>, config: Arc<RuntimeConfig>) -> Self {
-        Self { client, config }
+        let active = ActiveEnvironment::new(client, config);
+        let loaded = active
+            .config
+            .environment
+            .clone()
+            .map(|name| (name, active.clone()))
+            .into_iter()
+            .collect();
+        let tools = Self::get_tools_static();
+        let history = Arc::new(QueryHistory::new(
+            active.config.history_size,
+            &active.config.history_excluded_entities,
+        ));
+        Self {
+            active: std::sync::RwLock::new(active),
+            loaded: std::sync::Mutex::new(loaded),
+            environment_loader: std::sync::RwLock::new(None),
+            metrics: MetricsRegistry::new(tools.iter().map(|tool| tool.name.as_str())),
+            history,
+        }
+    }
+
+    /// An empty query history for a new session, sized by the config
+    pub fn new_query_history(&self) -> Arc<QueryHistory> {
+        let config = self.config();
+        Arc::new(QueryHistory::new(
+            config.history_size,
+            &config.history_excluded_entities,
+        ))
+    }
+
+    /// History of the current session, or the server's own outside one
+    fn history(&self) -> Arc<QueryHistory> {
+        session_history().unwrap_or_else(|| self.history.clone())
+    }
+
+    /// Allow `switch_environment` to load the other configured environments
+    pub fn with_environment_loader(self, loader: EnvironmentLoader) -> Self {
+        *self.environment_loader.write().unwrap() = Some(loader);
+        self
+    }
+
+    /// Whether [`Self::reload`] with `config` keeps the active client, so
+    /// no new one needs to be built for it
+    pub fn keeps_client(&self, config: &RuntimeConfig) -> bool {
+        self.active
+            .read()
+            .unwrap()
+            .config
+            .same_client_settings(config)
+    }
+
+    /// Swap in a reloaded configuration: `client` and `config` for the active
+    /// environment, `loader` for the named ones. Environments loaded before
+    /// are loaded again right away. A client is only replaced when its
+    /// endpoint, credentials or connection settings changed, so unchanged
+    /// ones keep their token and metadata caches; `client` may be `None`
+    /// when [`Self::keeps_client`] says so. Calls already running finish
+    /// with the environment they started with.
+    pub fn reload(
+        &self,
+        client: Option<Arc<ODataClient>>,
+        config: Arc<RuntimeConfig>,
+        loader: Option<EnvironmentLoader>,
+    ) {
+        let keep_client = |old: &ActiveEnvironment, client, config: Arc<RuntimeConfig>| {
+            let client = match client {
+                Some(client) if !old.config.same_client_settings(&config) => client,
+                _ => old.client.clone(),
+            };
+            ActiveEnvironment::new(client, config)
+        };
+        let active = keep_client(&self.active.read().unwrap(), client, config);
+
+        let previous = self.loaded.lock().unwrap().clone();
+        let mut loaded = HashMap::new();
+        for (name, old) in &previous {
+            let configured = active.config.environments.iter().any(|e| &e.name == name);
+            if !configured || active.config.environment.as_ref() == Some(name) {
+                continue;
+            }
+            let Some(loader) = &loader else { continue };
+            match loader(name) {
+                Ok((client, config)) => {
+                    loaded.insert(name.clone(), keep_client(old, Some(client), config));
+                }
+                Err(e) => tracing::warn!("Could not reload environment '{}': {}", name, e),
+            }
+        }
+        if let Some(name) = &active.config.environment {
+            loaded.insert(name.clone(), active.clone());
+        }
+
+        *self.environment_loader.write().unwrap() = loader;
+        *self.loaded.lock().unwrap() = loaded;
+        *self.active.write().unwrap() = active;
+    }
+
+    /// Load every configured environment up front, so each has its client
+    /// before the first call; returns the environments that failed to load
+    pub fn load_environments(&self) -> Vec<(String, String)> {
+        let names: Vec<String> = self
+            .config()
+            .environments
+            .iter()
+            .map(|environment| environment.name.clone())
+            .collect();
+        names
+            .into_iter()
+            .filter_map(|name| self.environment_named(&name).err().map(|e| (name, e)))
+            .collect()
+    }
+
+    /// Environment the current call runs against: the one it names, or the active one
+    fn environment(&self) -> ActiveEnvironment {
+        ROUTED
+            .try_with(ActiveEnvironment::clone)
+            .unwrap_or_else(|_| self.active.read().unwrap().clone())
+    }
+
+    /// Client of the current call's environment
+    pub fn client(&self) -> Arc<ODataClient> {
+        self.environment().client
+    }
+
+    /// Runtime config of the current call's environment
+    pub fn config(&self) -> Arc<RuntimeConfig> {
+        self.environment().config
+    }
+
+    /// Entity allow/deny lists of the current call's environment
+    fn entity_policy(&self) -> Arc<EntityPolicy> {
+        self.environment().entity_policy
+    }
+
+    /// A configured environment by name, loading its client on first use
+    fn environment_named(&self, name: &str) -> Result<ActiveEnvironment, String> {
+        let config = self.config();
+        if !config.environments.iter().any(|e| e.name == name) {
+            return Err(unknown_environment(&config, name));
+        }
+        if let Some(environment) = self.loaded.lock().unwrap().get(name) {
+            return Ok(environment.clone());
+        }
+        let Some(loader) = self.environment_loader.read().unwrap().clone() else {
+            return Err("This server cannot switch environments".to_string());
+        };
+        let (client, config) =
+            loader(name).map_err(|e| format!("Could not load environment '{}': {}", name, e))?;
+        // Another call may have loaded it meanwhile; keep the first client
+        Ok(self
+            .loaded
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| ActiveEnvironment::new(client, config))
+            .clone())
+    }
+
+    /// OData request counters of every environment used so far
+    pub fn request_stats(&self) -> RequestStats {
+        let active = self.client();
+        let loaded = self.loaded.lock().unwrap();
+        loaded
+            .values()
+            .filter(|environment| !Arc::ptr_eq(&environment.client, &active))
+            .fold(active.request_stats(), |stats, environment| {
+                stats.merge(environment.client.request_stats())
+            })
+    }
+
+    /// Tool and OData metrics in the Prometheus text format, for `/metrics`
+    pub fn prometheus_metrics(&self) -> String {
+        self.metrics.prometheus(&self.request_stats())
+    }
+
+    /// Token validity and recent OData outcomes, for `/healthz`
+    pub async fn health(&self) -> HealthReport {
+        let token =
+            match tokio::time::timeout(DEFAULT_STAGE_TIMEOUT, self.client().access_token()).await {
+                Ok(Ok(token)) => Ok(decode_jwt_claims(&token)
+                    .ok()
+                    .and_then(|claims| claims.get("exp").and_then(Value::as_i64))
+                    .map(|exp| exp - chrono::Utc::now().timestamp())),
+                Ok(Err(e)) => Err(e.to_string()),
+                Err(_) => Err(format!(
+                    "No token within {} seconds",
+                    DEFAULT_STAGE_TIMEOUT.as_secs()
+                )),
+            };
+        let requests = self.request_stats();
+        HealthReport {
+            token,
+            last_success: requests.last_success,
+            last_failure: requests.last_failure,
+            consecutive_failures: requests.consecutive_failures,
+        }
     }
 
     /// Get list of available tools
     pub fn get_tools(&self) -> Vec<Tool> {
+        let config = self.config();
+        Self::tools_for_mode(config.read_only)
+            .into_iter()
+            .filter(|tool| is_available_for(&tool.name, &config.product))
+            .map(|tool| with_top_limits(tool, &config))
+            .map(|mut tool| {
+                // Without named environments there is nothing to route to
+                if config.environments.is_empty() {
+                    if let Some(properties) = tool.input_schema["properties"].as_object_mut() {
+                        properties.remove("environment");
+                    }
+                }
+                tool
+            })
+            .collect()
+    }
+
+    /// Tools a session holding `roles` may call, as `tools/list` advertises them
+    pub fn tools_for_roles(&self, roles: &[String]) -> Vec<Tool> {
+        self.get_tools()
+            .into_iter()
+            .filter(|tool| self.authorize_tool(&tool.name, roles).is_ok())
+            .collect()
+    }
+
+    /// Whether a session holding `roles` may call `tool` under the
+    /// `[tool_permissions]` section. Without the section every tool is
+    /// permitted; with it, the tool's most specific pattern names the roles
+    /// that may call it, and a tool no pattern matches follows `default`.
+    pub fn authorize_tool(&self, tool: &str, roles: &[String]) -> Result<(), String> {
+        let config = self.config();
+        let Some(permissions) = &config.tool_permissions else {
+            return Ok(());
+        };
+        let held = |role: &String| roles.iter().any(|r| r.eq_ignore_ascii_case(role));
+        match permissions.required_roles(tool) {
+            Some(required) if required.iter().any(held) => Ok(()),
+            Some(required) => Err(format!(
+                "Tool '{}' requires one of the roles [{}]; this session has [{}]",
+                tool,
+                required.join(", "),
+                roles.join(", ")
+            )),
+            None if permissions.default == UnlistedTools::Allow => Ok(()),
+            None => Err(format!(
+                "Tool '{}' is not listed in tool_permissions, which denies unlisted tools",
+                tool
+            )),
+        }
+    }
+
+    /// Tools exposed in the given mode; read-only mode omits mutating tools
+    pub fn tools_for_mode(read_only: bool) -> Vec<Tool> {
         Self::get_tools_static()
+            .into_iter()
+            .filter(|tool| !read_only || !is_mutating_tool(&tool.name))
+            .collect()
     }
 
     /// Get list of available tools (static version for unconfigured server)
     pub fn get_tools_static() -> Vec<Tool> {
-        vec![
+        let tools = vec![
             Tool {
                 name: "list_entities".to_string(),
-                description: "List all available D365 entities/tables that can be queried".to_string(),
-                input_schema: create_tool_schema(vec![]),
+                description: "List the D365 entity sets that can be queried, a page at a time, with their entity types and descriptions. Configured entities come first. Use search_entities to find an entity by approximate name".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("filter", "Only list entity sets whose name contains this text (case-insensitive)"),
+                    ToolParam::string("prefix", "Only list entity sets whose name starts with this text (case-insensitive)"),
+                    ToolParam::integer("offset", "Number of entity sets to skip (for pagination)")
+                        .range(Some(0), None)
+                        .default_value(0),
+                    ToolParam::integer("limit", "Maximum entity sets to list")
+                        .range(Some(1), Some(1000))
+                        .default_value(DEFAULT_ENTITY_PAGE),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("List Entities")),
+                output_schema: None,
+            },
+            Tool {
+                name: "search_entities".to_string(),
+                description: "Find entity sets by approximate name, e.g. 'sales order header' finds SalesOrderHeadersV2. Returns the best matches with their entity types".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("query", "Words or abbreviation to look for, e.g. 'customer' or 'SOHdr'").required(),
+                    ToolParam::integer("limit", "Maximum matches to return")
+                        .range(Some(1), Some(100))
+                        .default_value(DEFAULT_SEARCH_RESULTS),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("Search Entities")),
+                output_schema: None,
+            },
+            Tool {
+                name: "search".to_string(),
+                description: "Dataverse only: relevance (full-text) search across tables, e.g. everything mentioning 'Contoso' in accounts, contacts and cases. Returns hits grouped by table with scores and highlighted matches. Use query_entity for exact filters".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("query", "Search text, e.g. 'Contoso' or 'printer jam'").required(),
+                    ToolParam::string("entities", "Comma-separated table logical names to search, e.g. 'account,contact,incident' (default: all tables enabled for search)"),
+                    ToolParam::integer("top", "Maximum hits to return")
+                        .range(Some(1), Some(100))
+                        .default_value(DEFAULT_SEARCH_RESULTS),
+                    impersonate_param(),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("Relevance Search")),
+                output_schema: None,
+            },
+            Tool {
+                name: "get_record_audit".to_string(),
+                description: "Dataverse only: audit history of one record, oldest first: when it changed, who changed it and each field's old and new value. Says so when auditing is turned off instead of returning nothing".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("entity", "Entity set name, e.g., 'accounts'").required(),
+                    ToolParam::string("id", "Record ID/GUID. Required unless key is given"),
+                    ToolParam::object("key", "Key fields and values, for alternate or multi-part keys"),
+                    ToolParam::string("key_field", "Alternate key column(s) that id holds values for"),
+                    ToolParam::string("field", "Only show changes to this column (logical name), e.g., 'creditlimit'"),
+                    ToolParam::integer("top", "Most recent changes to list")
+                        .range(Some(1), Some(500))
+                        .default_value(DEFAULT_AUDIT_ENTRIES),
+                    impersonate_param(),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("Record Audit History")),
+                output_schema: None,
+            },
+            Tool {
+                name: "list_views".to_string(),
+                description: "Dataverse only: saved views of a table, e.g. 'My Active Accounts': system views curated by admins and the caller's personal views, with the IDs run_view takes".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("entity", "Entity set or table name, e.g., 'accounts' or 'account'").required(),
+                    impersonate_param(),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("List Saved Views")),
+                output_schema: None,
+            },
+            Tool {
+                name: "run_view".to_string(),
+                description: "Dataverse only: run a saved view (from list_views) with its own FetchXML filter and sort, returning its columns in the view's order. Prefer this over rebuilding a view's filter with query_entity".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("view_id", "View ID from list_views").required(),
+                    ToolParam::string_enum("view_type", "'system' (default) or 'personal', as shown by list_views", &["system", "personal"]),
+                    ToolParam::integer("top", "Maximum records to return")
+                        .range(Some(1), Some(MAX_TOP as i64))
+                        .default_value(DEFAULT_TOP),
+                    ToolParam::string_enum("format", "Output format: 'table' (markdown, default), 'json' or 'csv'", &["json", "table", "markdown", "csv"]),
+                    impersonate_param(),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("Run Saved View")),
+                output_schema: None,
             },
             Tool {
                 name: "query_entity".to_string(),
-                description: "Query data from a D365 entity with full OData support. Returns records matching the criteria.".to_string(),
+                description: "Query data from a D365 entity with full OData support. Returns records matching the criteria. F&O enum fields are filtered with qualified literals, e.g. \"SalesStatus eq Microsoft.Dynamics.DataEntities.SalesStatus'Invoiced'\" (see get_optionset); Dataverse choice columns by integer value. With count=true, F&O reports the exact number of matches; Dataverse stops counting at 5000, so the table's total row count (unfiltered, up to 24 hours old) is reported next to it.".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("entity", "Entity set name, e.g., 'CustomersV3', 'SalesOrderHeaders'").required(),
+                    ToolParam::string("select", "Comma-separated fields to select, e.g., 'Name,Id,Status'; '*' for all fields when the entity has a configured default"),
+                    ToolParam::string("filter", "OData filter expression, e.g., \"dataAreaId eq 'bc' and Status ne 'Closed'\""),
+                    ToolParam::string("orderby", "Sort order, e.g., 'CreatedDate desc' or 'Name asc'"),
+                    ToolParam::string("search", "OData $search expression, passed to the service as is, e.g. 'contoso'. Not every entity supports it; use the search tool for Dataverse relevance search"),
+                    ToolParam::integer("top", "Maximum records to return")
+                        .range(Some(1), None)
+                        .default_value(DEFAULT_TOP),
+                    ToolParam::integer("skip", "Number of records to skip (for pagination)").range(Some(0), None),
+                    ToolParam::string("expand", "Comma-separated navigation properties to expand"),
+                    ToolParam::boolean("cross_company", "Query across all companies (F&O only)").default_value(false),
+                    company_param(),
+                    impersonate_param(),
+                    dry_run_param(),
+                    ToolParam::boolean("count", "Include the total matching record count. Dataverse caps it at 5000 and adds the whole table's row count from RetrieveTotalRecordCount").default_value(false),
+                    ToolParam::string("page_token", "next_page_token from a previous query_entity result. When set, fetches the next page and ignores other query arguments"),
+                    ToolParam::string_enum("format", "Output format: 'json', 'table' (markdown) or 'csv'. Table and CSV use far fewer tokens for tabular data", &["json", "table", "markdown", "csv"]),
+                    ToolParam::boolean("resolve_labels", "Add a 'field_label' with the display text of choice, lookup and enum values").default_value(true),
+                    ToolParam::string_enum("annotations", "Dataverse annotations to request: 'all', 'formatted-only' (only display texts, folded into labels; much smaller records) or 'none'. Defaults to the server's ANNOTATIONS setting", &["all", "formatted-only", "none"]),
+                    ToolParam::boolean("validate", "Check select, orderby and expand fields against the cached $metadata before sending, suggesting the closest real names for typos").default_value(true),
+                    ToolParam::string("language", "Language of labels for this call, e.g. 'de-DE', sent as Accept-Language: LABEL_LANGUAGE or a language Dataverse ships. Defaults to the server's LABEL_LANGUAGE setting"),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("Query Entity")),
+                output_schema: Some(query_output_schema()),
+            },
+            Tool {
+                name: "query_related".to_string(),
+                description: "Query the records a collection navigation property of one record leads to, e.g. the SalesOrderLines of a sales order header, with filter, select and paging. Unlike expand, the related collection is not capped or silently truncated".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("entity", "Entity set of the parent record, e.g., 'SalesOrderHeadersV2' or 'accounts'").required(),
+                    ToolParam::string("id", "Parent record ID/GUID. Required unless key or page_token is given"),
+                    ToolParam::object("key", "Parent key fields and values, for alternate or multi-part keys, e.g. {\"dataAreaId\": \"usmf\", \"SalesOrderNumber\": \"SO-001\"}"),
+                    ToolParam::string("key_field", "Alternate key column(s) that id holds values for"),
+                    ToolParam::string("relationship", "Collection navigation property of the parent, e.g. 'SalesOrderLines' or 'contact_customer_accounts' (see describe_relationships)").required(),
+                    ToolParam::string("select", "Comma-separated fields of the related records to select"),
+                    ToolParam::string("filter", "OData filter on the related records"),
+                    ToolParam::string("orderby", "Sort order, e.g., 'LineNumber asc'"),
+                    ToolParam::integer("top", "Maximum related records to return")
+                        .range(Some(1), Some(MAX_TOP as i64))
+                        .default_value(DEFAULT_TOP),
+                    ToolParam::integer("skip", "Number of related records to skip").range(Some(0), None),
+                    ToolParam::string("expand", "Comma-separated navigation properties of the related records to expand"),
+                    ToolParam::boolean("count", "Include the total related record count").default_value(false),
+                    ToolParam::string("page_token", "next_page_token from a previous query_related result. When set, fetches the next page and ignores the other query arguments"),
+                    ToolParam::string_enum("format", "Output format: 'json', 'table' (markdown) or 'csv'", &["json", "table", "markdown", "csv"]),
+                    ToolParam::boolean("resolve_labels", "Add a 'field_label' with the display text of choice and enum values").default_value(true),
+                    ToolParam::boolean("validate", "Check the relationship and fields against the cached $metadata before sending").default_value(true),
+                    impersonate_param(),
+                    dry_run_param(),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("Query Related Records")),
+                output_schema: Some(query_output_schema()),
+            },
+            Tool {
+                name: "distinct_values".to_string(),
+                description: "List the distinct values of one column, e.g. the payment terms used across customers, sorted and counted, instead of fetching every row. Dataverse groups on the server with $apply; F&O reads the column page by page and de-duplicates up to DISTINCT_VALUES_LIMIT records, saying so when it stopped early".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("entity", "Entity set name, e.g., 'CustomersV3' or 'accounts'").required(),
+                    ToolParam::string("field", "Column to list the values of, e.g., 'PaymentTerms' or 'paymenttermscode'").required(),
+                    ToolParam::string("filter", "OData filter restricting the records, e.g., \"statecode eq 0\""),
+                    ToolParam::boolean("cross_company", "Read across all companies (F&O only)").default_value(false),
+                    company_param(),
+                    impersonate_param(),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("Distinct Values")),
+                output_schema: None,
+            },
+            Tool {
+                name: "export_entity".to_string(),
+                description: "Export every record matching a query to a CSV or JSON Lines file in the server's export directory, page by page, instead of returning them. Returns the file path, row count, size and elapsed time. Use it for results too large to read in chat.".to_string(),
                 input_schema: create_tool_schema(vec![
-                    ("entity", "Entity set name, e.g., 'CustomersV3', 'SalesOrderHeaders'", true),
-                    ("select", "Comma-separated fields to select, e.g., 'Name,Id,Status'", false),
-                    ("filter", "OData filter expression, e.g., \"dataAreaId eq 'bc' and Status ne 'Closed'\"", false),
-                    ("orderby", "Sort order, e.g., 'CreatedDate desc' or 'Name asc'", false),
-                    ("top", "Maximum records to return (default: 50, max: 1000)", false),
-                    ("skip", "Number of records to skip (for pagination)", false),
-                    ("expand", "Comma-separated navigation properties to expand", false),
-                    ("cross_company", "Set to 'true' for cross-company query (F&O only)", false),
-                    ("count", "Set to 'true' to include total record count in response", false),
+                    ToolParam::string("entity", "Entity set name, e.g., 'CustomersV3', 'SalesOrderHeaders'").required(),
+                    ToolParam::string("select", "Comma-separated fields to select; these are also the CSV columns. '*' for all fields when the entity has a configured default"),
+                    ToolParam::string("filter", "OData filter expression, e.g., \"dataAreaId eq 'bc' and Status ne 'Closed'\""),
+                    ToolParam::string("orderby", "Sort order, e.g., 'CreatedDate desc' or 'Name asc'"),
+                    ToolParam::string("search", "OData $search expression, passed to the service as is"),
+                    ToolParam::integer("top", "Maximum records to export (default: all)").range(Some(1), None),
+                    ToolParam::integer("skip", "Number of records to skip").range(Some(0), None),
+                    ToolParam::string("expand", "Comma-separated navigation properties to expand"),
+                    ToolParam::boolean("cross_company", "Query across all companies (F&O only)").default_value(false),
+                    company_param(),
+                    impersonate_param(),
+                    ToolParam::string_enum("format", "File format: 'csv' (flattened like the csv output format, default) or 'jsonl' (one JSON record per line)", &["csv", "jsonl"]),
+                    ToolParam::string("path", "File to create inside EXPORT_DIR (absolute or relative to it; its directory must exist). Existing files are never overwritten. Default: '<entity>.<format>'"),
+                    ToolParam::boolean("resolve_labels", "Add a 'field_label' with the display text of choice and enum values").default_value(true),
                 ]),
+                // Reads the service but writes a file, never replacing one
+                annotations: Some(ToolAnnotations::write("Export Entity", false)),
+                output_schema: None,
             },
             Tool {
                 name: "get_entity_schema".to_string(),
-                description: "Get entity schema by fetching a sample record. Shows available fields.".to_string(),
+                description: "Get entity schema by fetching a sample record, showing the fields it returns. Falls back to $metadata (field types and key fields) when the entity has no records.".to_string(),
                 input_schema: create_tool_schema(vec![
-                    ("entity", "Entity set name, e.g., 'contacts'", true),
+                    ToolParam::string("entity", "Entity set name, e.g., 'contacts'").required(),
+                    ToolParam::string_enum("source", "'sample' reads one record (falling back to metadata when there is none); 'metadata' skips the record", &["sample", "metadata"]),
                 ]),
+                annotations: Some(ToolAnnotations::read_only("Get Entity Schema")),
+                output_schema: None,
             },
             Tool {
                 name: "get_record".to_string(),
-                description: "Get a single record by its ID/primary key".to_string(),
+                description: "Get a single record by its ID/primary key or an alternate key".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("entity", "Entity set name, e.g., 'contacts'").required(),
+                    ToolParam::string("id", "Record ID/GUID, or the key value(s) for key_field. Required unless key is given"),
+                    ToolParam::object("key", "Key fields and values for multi-part keys, e.g. {\"dataAreaId\": \"usmf\", \"SalesOrderNumber\": \"SO-001\"}. Values are quoted by type"),
+                    ToolParam::string("key_field", "Key column(s) to look up by instead of the primary key, e.g. 'emailaddress1' (Dataverse alternate key) or 'dataAreaId,CustomerAccount' with id 'usmf,US-001'"),
+                    ToolParam::boolean("resolve_labels", "Add a 'field_label' with the display text of choice, lookup and enum values").default_value(true),
+                    impersonate_param(),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("Get Record")),
+                output_schema: Some(record_output_schema()),
+            },
+            Tool {
+                name: "download_file".to_string(),
+                description: "Dataverse only: download the contents of a file or image column (e.g. 'entityimage') to the server's download directory and return the saved path and size. Small files can be returned inline as base64.".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("entity", "Entity set name, e.g., 'accounts'").required(),
+                    ToolParam::string("attribute", "File or image column, e.g., 'entityimage' or 'new_contract'").required(),
+                    ToolParam::string("id", "Record ID/GUID. Required unless key is given"),
+                    ToolParam::object("key", "Key fields and values, for alternate or multi-part keys"),
+                    ToolParam::string("key_field", "Alternate key column(s) that id holds values for"),
+                    ToolParam::boolean("inline", "Return the contents as base64 instead of saving a file (files up to 48 KB only)").default_value(false),
+                    impersonate_param(),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("Download File")),
+                output_schema: None,
+            },
+            Tool {
+                name: "upload_file".to_string(),
+                description: "Dataverse only: upload a local file from the server's upload directory into a file or image column, or attach it to the record as a note (annotation). Large files are sent in chunks.".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("entity", "Entity set name, e.g., 'accounts'").required(),
+                    ToolParam::string("path", "File to upload, inside UPLOAD_DIR (absolute or relative to it)").required(),
+                    ToolParam::string("attribute", "File or image column to write, e.g., 'new_contract'. Omit when as_note is true"),
+                    ToolParam::boolean("as_note", "Attach the file to the record as a note (annotation) instead of writing a column").default_value(false),
+                    ToolParam::string("subject", "Note subject when as_note is true (default: the file name)"),
+                    ToolParam::string("file_name", "Name to store the file under (default: the local file name)"),
+                    ToolParam::string("id", "Record ID/GUID. Required unless key is given"),
+                    ToolParam::object("key", "Key fields and values, for alternate or multi-part keys"),
+                    ToolParam::string("key_field", "Alternate key column(s) that id holds values for"),
+                    impersonate_param(),
+                    dry_run_param(),
+                ]),
+                annotations: Some(ToolAnnotations::destructive("Upload File", true)),
+                output_schema: None,
+            },
+            Tool {
+                name: "dmf_import_package".to_string(),
+                description: "F&O only: import a data package (.zip from a data project export) through the Data management package API instead of OData writes; suited to large imports. The package is uploaded to blob storage, then imported into a data project. Returns the execution id for dmf_get_execution_status".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("path", "Package .zip to import, inside UPLOAD_DIR (absolute or relative to it)").required(),
+                    ToolParam::string("definition_group", "Data project (definition group) whose entity mappings the import uses, e.g. 'CustomerImport'").required(),
+                    company_param(),
+                    ToolParam::string("execution_id", "Id for the new execution (default: one the service makes up)"),
+                    ToolParam::boolean("execute", "Run the import now; false only loads it into staging").default_value(true),
+                    ToolParam::boolean("overwrite", "Replace the data project's entity mappings with those in the package").default_value(true),
+                    dry_run_param(),
+                ]),
+                annotations: Some(ToolAnnotations::write("Import Data Package", false)),
+                output_schema: None,
+            },
+            Tool {
+                name: "dmf_get_execution_status".to_string(),
+                description: "F&O only: status of a Data management execution such as a dmf_import_package import (Not run, Executing, Succeeded, Partially succeeded, Failed, Canceled). Can wait for it to finish; on failure gives the staging error log URL of entity_name".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("execution_id", "Execution id returned by dmf_import_package").required(),
+                    ToolParam::string("entity_name", "Data entity in the package, as named in the data project (e.g. 'Customers V3'), whose staging error log URL to return when records failed"),
+                    ToolParam::integer("wait_secs", "Poll until the execution finishes or this many seconds pass (limited by the call's timeout)").range(Some(0), Some(MAX_DMF_WAIT_SECS)).default_value(0),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("Get Data Package Execution Status")),
+                output_schema: None,
+            },
+            Tool {
+                name: "execute_action".to_string(),
+                description: "Call an OData action, e.g. Dataverse 'BulkDelete' or a custom API, with its parameters as the JSON body. Actions the service runs in the background (202 Accepted) are polled until they finish, up to max_wait_secs; with wait=false, or when the wait runs out, returns the monitor URL for check_operation".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("action", "Action name, e.g. 'BulkDelete', or the path of a bound action, e.g. 'accounts(<guid>)/Microsoft.Dynamics.CRM.new_Recalculate'").required(),
+                    ToolParam::object("parameters", "Action parameters, sent as the request body, e.g. {\"JobName\": \"Cleanup\"}"),
+                    ToolParam::boolean("wait", "Wait for a long-running operation to finish").default_value(true),
+                    ToolParam::integer("max_wait_secs", "Longest wait for a long-running operation (limited by the call's timeout)").range(Some(0), Some(MAX_OPERATION_WAIT_SECS)).default_value(DEFAULT_OPERATION_WAIT_SECS as i64),
+                    impersonate_param(),
+                    dry_run_param(),
+                ]),
+                annotations: Some(ToolAnnotations::destructive("Execute Action", false)),
+                output_schema: None,
+            },
+            Tool {
+                name: "check_operation".to_string(),
+                description: "Status of a long-running operation started by execute_action, from its monitor URL: Running, Succeeded or Failed, with the state and payload the service reported. Can wait for it to finish".to_string(),
                 input_schema: create_tool_schema(vec![
-                    ("entity", "Entity set name, e.g., 'contacts'", true),
-                    ("id", "Record ID/GUID", true),
+                    ToolParam::string("monitor_url", "Monitor URL returned by execute_action").required(),
+                    ToolParam::integer("wait_secs", "Poll until the operation finishes or this many seconds pass (limited by the call's timeout)").range(Some(0), Some(MAX_OPERATION_WAIT_SECS)).default_value(0),
                 ]),
+                annotations: Some(ToolAnnotations::read_only("Check Operation")),
+                output_schema: None,
             },
             Tool {
                 name: "delete_record".to_string(),
                 description: "Delete a single D365 record by OData key. Requires confirm='DELETE' to prevent accidental deletion.".to_string(),
                 input_schema: create_tool_schema(vec![
-                    ("entity", "Entity set name, e.g., 'CustomersV3', 'SalesOrderHeaders'", true),
-                    ("key", "OData key expression without parentheses, e.g., \"dataAreaId='bc',SalesOrderNumber='SO-001'\". Use this for composite keys.", false),
-                    ("id", "Simple record ID/key. Used only when key is not provided.", false),
-                    ("if_match", "Optional If-Match header value. Defaults to '*'.", false),
-                    ("confirm", "Must be exactly 'DELETE' to execute the deletion.", true),
+                    ToolParam::string("entity", "Entity set name, e.g., 'CustomersV3', 'SalesOrderHeaders'").required(),
+                    ToolParam::string("key", "OData key expression without parentheses, e.g., \"dataAreaId='bc',SalesOrderNumber='SO-001'\". Use this for composite keys."),
+                    ToolParam::string("id", "Simple record ID/key. Used only when key is not provided."),
+                    ToolParam::string("if_match", "Optional If-Match header value.").default_value("*"),
+                    ToolParam::string("confirm", "Must be exactly 'DELETE' to execute the deletion.").required(),
+                    company_param(),
+                    impersonate_param(),
+                    dry_run_param(),
+                ]),
+                annotations: Some(ToolAnnotations::destructive("Delete Record", true)),
+                output_schema: None,
+            },
+            Tool {
+                name: "upsert_record".to_string(),
+                description: "Create a record if no record has the given key, or update it if one does (PATCH to the keyed URL). Typically keyed on an alternate key such as an external ID column. Reports whether the record was created or updated.".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("entity", "Entity set name, e.g., 'accounts' or 'CustomersV3'").required(),
+                    ToolParam::object("data", "Field values to set, e.g. {\"name\": \"Contoso\"}. Set a lookup with {\"primarycontactid\": {\"@lookup\": {\"entity\": \"contacts\", \"id\": \"<guid>\"}}} (\"key\": {...} instead of \"id\" for alternate keys) or {\"primarycontactid@bind\": \"contacts(<guid>)\"}; a null lookup clears it. Both become primarycontactid@odata.bind").required(),
+                    ToolParam::string("key_field", "Alternate key column, e.g. 'accountnumber'; used with key_value"),
+                    ToolParam::string("key_value", "Value of key_field"),
+                    ToolParam::object("key", "Key fields and values, for multi-part keys, e.g. {\"dataAreaId\": \"usmf\", \"CustomerAccount\": \"US-001\"}"),
+                    ToolParam::string("id", "Primary key value (e.g. a Dataverse GUID) when no alternate key is used"),
+                    ToolParam::boolean("prevent_update", "Only create: fail if the record already exists (If-None-Match: *)").default_value(false),
+                    ToolParam::boolean("prevent_create", "Only update: fail if the record does not exist (If-Match: *)").default_value(false),
+                    company_param(),
+                    impersonate_param(),
+                    dry_run_param(),
+                ]),
+                annotations: Some(ToolAnnotations::destructive("Upsert Record", true)),
+                output_schema: None,
+            },
+            Tool {
+                name: "bulk_update".to_string(),
+                description: "Apply the same field values to every record matching a filter, e.g. set a hold status on all customers of a group. Matching keys are read first; the PATCHes are then sent in $batch changesets of chunk_size records, each applied all-or-nothing. Refuses to run when more than max_records match. Use dry_run to see how many records would change.".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("entity", "Entity set name, e.g., 'CustomersV3' or 'accounts'").required(),
+                    ToolParam::string("filter", "OData $filter selecting the records to update, e.g. \"CustomerGroupId eq '90'\"").required(),
+                    ToolParam::object("data", "Field values to set on every matching record, e.g. {\"OnHoldStatus\": \"Yes\"}. Lookups are set as in upsert_record").required(),
+                    ToolParam::boolean("confirm", "Must be true to write (not needed for dry_run); confirm the filter and change with the user first"),
+                    ToolParam::integer("max_records", "Most records the update may touch; nothing is written when more match").required().range(Some(1), None),
+                    ToolParam::integer("chunk_size", "Records per $batch changeset").range(Some(1), Some(MAX_BULK_CHUNK)).default_value(DEFAULT_BULK_CHUNK),
+                    company_param(),
+                    impersonate_param(),
+                    ToolParam::boolean("dry_run", "Only count the matching records and report the changesets that would be sent; nothing is written").default_value(false),
                 ]),
+                annotations: Some(ToolAnnotations::destructive("Bulk Update", true)),
+                output_schema: None,
+            },
+            Tool {
+                name: "associate_records".to_string(),
+                description: "Dataverse only: link two existing records through a navigation property, e.g. add a contact to an account's contact_customer_accounts or set its primarycontactid. Collection-valued properties gain the target; single-valued ones are replaced.".to_string(),
+                input_schema: create_tool_schema(record_link_params(true)),
+                annotations: Some(ToolAnnotations::write("Associate Records", true)),
+                output_schema: None,
+            },
+            Tool {
+                name: "disassociate_records".to_string(),
+                description: "Dataverse only: remove a link between records. Collection-valued navigation properties need the target record; single-valued ones are cleared.".to_string(),
+                input_schema: create_tool_schema(record_link_params(false)),
+                annotations: Some(ToolAnnotations::destructive("Disassociate Records", true)),
+                output_schema: None,
             },
             Tool {
                 name: "get_environment_info".to_string(),
                 description: "Get information about the connected D365 environment".to_string(),
                 input_schema: create_tool_schema(vec![]),
+                annotations: Some(ToolAnnotations::read_only("Get Environment Info")),
+                output_schema: None,
+            },
+            Tool {
+                name: "get_server_stats".to_string(),
+                description: "Get operational stats since the server started: calls, errors and durations per tool, and OData requests, retries, throttling (429), token refreshes, latency percentiles and the age of the last successful call".to_string(),
+                input_schema: create_tool_schema(vec![]),
+                annotations: Some(ToolAnnotations::read_only("Get Server Stats")),
+                output_schema: None,
+            },
+            Tool {
+                name: "query_history".to_string(),
+                description: "List this session's recent tool calls, oldest first, with their #number, time, arguments, outcome and row count (responses are not kept). Use replay_query to run one again".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("tool", "Only list calls of this tool, e.g. 'query_entity'"),
+                    ToolParam::integer("limit", "Only list the latest N calls").range(Some(1), None),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("Query History")),
+                output_schema: None,
+            },
+            Tool {
+                name: "replay_query".to_string(),
+                description: "Run a call from query_history again, optionally changing some of its arguments, e.g. index=12 with arguments={\"top\": 50}. Only reads are replayed. The replay runs with the same checks as the original tool and is itself added to the history".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::integer("index", "The #number of the query_history entry to run").required().range(Some(1), None),
+                    ToolParam::object("arguments", "Arguments to change; a null value removes the argument, e.g. {\"filter\": null}"),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("Replay Query")),
+                output_schema: None,
+            },
+            Tool {
+                name: "list_environments".to_string(),
+                description: "List the named environments (e.g. dev, uat, prod) this server can switch between, marking the active one and production environments".to_string(),
+                input_schema: create_tool_schema(vec![]),
+                annotations: Some(ToolAnnotations::read_only("List Environments")),
+                output_schema: None,
+            },
+            Tool {
+                name: "switch_environment".to_string(),
+                description: "Make another named environment the one all tools run against. Production environments require confirm=true; ask the user before confirming".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("name", "Environment name from list_environments, e.g. 'uat'").required(),
+                    ToolParam::boolean("confirm", "Confirm switching to a production environment").default_value(false),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("Switch Environment")),
+                output_schema: None,
+            },
+            Tool {
+                name: "compare_record".to_string(),
+                description: "Fetch the same record from two named environments (e.g. uat and prod) and list the fields whose values differ, with both values. System fields such as modifiedon, versionnumber and dataAreaId are ignored. A record missing on one side is reported, not an error".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("entity", "Entity set name, e.g., 'CustomersV3' or 'accounts'").required(),
+                    ToolParam::string("source_environment", "First environment from list_environments, e.g. 'uat'").required(),
+                    ToolParam::string("target_environment", "Second environment from list_environments, e.g. 'prod'").required(),
+                    ToolParam::string("id", "Record ID/GUID, or the key value(s) for key_field. Required unless key is given"),
+                    ToolParam::object("key", "Key fields and values for multi-part keys, e.g. {\"dataAreaId\": \"usmf\", \"CustomerAccount\": \"US-001\"}"),
+                    ToolParam::string("key_field", "Key column(s) to look up by instead of the primary key"),
+                    ToolParam::string("ignore_fields", "Comma-separated fields to ignore as well as the configured ones, e.g. 'createdon,ownerid'"),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("Compare Record")),
+                output_schema: None,
+            },
+            Tool {
+                name: "list_optionsets".to_string(),
+                description: "List option set / enum names: EnumTypes from $metadata, plus global choices on Dataverse. Use get_optionset for the members.".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("prefix", "Only names starting with this text (case-insensitive), e.g. 'Sales'"),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("List Option Sets")),
+                output_schema: None,
+            },
+            Tool {
+                name: "get_optionset".to_string(),
+                description: "Get the members of an option set / enum with their values and, for F&O, the literal to use in $filter".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("name", "Option set or enum name, e.g. 'SalesStatus' or 'budgetstatus'").required(),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("Get Option Set")),
+                output_schema: None,
+            },
+            Tool {
+                name: "list_companies".to_string(),
+                description: "List F&O legal entities (company code → name) to use as dataAreaId in filters. Cached for the session; refresh_metadata reloads it.".to_string(),
+                input_schema: create_tool_schema(vec![]),
+                annotations: Some(ToolAnnotations::read_only("List Companies")),
+                output_schema: None,
+            },
+            Tool {
+                name: "test_connection".to_string(),
+                description: "Check the connection step by step: token acquisition, the service root, and a 1-row query. Reports pass/fail per stage with a hint for common setup mistakes. Run this first when other tools fail.".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("entity", "Entity set for the sample query; defaults to the first configured entity"),
+                    ToolParam::integer("timeout_secs", "Timeout for each stage in seconds")
+                        .range(Some(1), Some(120))
+                        .default_value(DEFAULT_STAGE_TIMEOUT.as_secs()),
+                    ToolParam::boolean("reset_circuit", "Close the circuit breaker first, so the checks reach the service even while it is open after repeated failures").default_value(false),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("Test Connection")),
+                output_schema: None,
+            },
+            Tool {
+                name: "whoami".to_string(),
+                description: "Show the identity the server authenticates as: the mapped Dataverse user and business unit, or the F&O application id, tenant, roles and token expiry".to_string(),
+                input_schema: create_tool_schema(vec![]),
+                annotations: Some(ToolAnnotations::read_only("Who Am I")),
+                output_schema: None,
             },
             Tool {
                 name: "get_metadata".to_string(),
                 description: "Get entity metadata from $metadata including properties and navigation properties (expandable fields). Use this to understand entity schema and available joins. Results are cached for performance.".to_string(),
                 input_schema: create_tool_schema(vec![
-                    ("entity", "Entity name to get metadata for, e.g., 'CustomersV3'", true),
+                    ToolParam::string("entity", "Entity name to get metadata for, e.g., 'CustomersV3'").required(),
+                ]),
+                annotations: Some(ToolAnnotations::read_only("Get Metadata")),
+                output_schema: Some(metadata_output_schema()),
+            },
+            Tool {
+                name: "describe_relationships".to_string(),
+                description: "Describe how an entity relates to others: outbound navigation properties (expandable from this entity) and inbound ones from entities that reference it, with foreign-key fields and cardinality".to_string(),
+                input_schema: create_tool_schema(vec![
+                    ToolParam::string("entity", "Entity set name, e.g., 'CustomersV3'").required(),
                 ]),
+                annotations: Some(ToolAnnotations::read_only("Describe Relationships")),
+                output_schema: None,
             },
             Tool {
                 name: "refresh_metadata".to_string(),
                 description: "Force refresh the cached $metadata. Use this if entity schema has changed or if you need fresh metadata. Returns cache status after refresh.".to_string(),
                 input_schema: create_tool_schema(vec![]),
+                annotations: Some(ToolAnnotations::read_only("Refresh Metadata Cache")),
+                output_schema: None,
             },
-        ]
+        ];
+        tools
+            .into_iter()
+            .map(with_timeout_param)
+            .map(with_environment_param)
+            .collect()
+    }
+
+    /// Handle a tool call
+    pub async fn call_tool(&self, name: &str, args: &HashMap<String, Value>) -> CallToolResult {
+        self.call_tool_with_progress(name, args, None).await
+    }
+
+    /// Handle a tool call, sending metadata download and paging progress to `progress`
+    pub async fn call_tool_with_progress(
+        &self,
+        name: &str,
+        args: &HashMap<String, Value>,
+        progress: Option<Arc<dyn ProgressReporter>>,
+    ) -> CallToolResult {
+        let started = Instant::now();
+        let client = mcp_client();
+        let span = tracing::info_span!(
+            "tool_call",
+            tool = name,
+            mcp_client = client.as_deref().unwrap_or("-"),
+        );
+        // Checked here so calls made by `replay_query` are authorized too
+        if let Err(message) = self.authorize_tool(name, &session_roles()) {
+            self.metrics.record_tool_call(name, started.elapsed(), true);
+            return CallToolResult::error(message);
+        }
+        if name == "replay_query" {
+            let result = self.replay_query(args, progress).instrument(span).await;
+            self.metrics
+                .record_tool_call(name, started.elapsed(), result.is_error == Some(true));
+            return result;
+        }
+        let dispatch = async {
+            match progress {
+                Some(reporter) => with_progress(reporter, self.dispatch_tool(name, args)).await,
+                None => self.dispatch_tool(name, args).await,
+            }
+        };
+        let result = dispatch.instrument(span).await;
+        self.metrics
+            .record_tool_call(name, started.elapsed(), result.is_error == Some(true));
+        self.history().record(
+            name,
+            args,
+            result.is_error != Some(true),
+            rows_returned(result.structured_content.as_ref()),
+        );
+        result
+    }
+
+    /// Run history entry `index` again with the `arguments` overrides applied
+    async fn replay_query(
+        &self,
+        args: &HashMap<String, Value>,
+        progress: Option<Arc<dyn ProgressReporter>>,
+    ) -> CallToolResult {
+        if let Err(invalid) = check_arguments("replay_query", args, &self.config()) {
+            return invalid;
+        }
+        let index = get_usize(args, "index").unwrap_or_default();
+        let overrides = args.get("arguments").and_then(Value::as_object);
+        let entry = match self.history().get(index) {
+            Ok(entry) => entry,
+            Err(message) => return CallToolResult::error(message),
+        };
+        // Changes are made by calling the write tool itself, never by replay
+        if is_mutating_tool(&entry.tool) {
+            return CallToolResult::error(format!(
+                "History entry #{} is a {} call; writes are not replayed, call {} directly",
+                index, entry.tool, entry.tool
+            ));
+        }
+
+        let replayed = replay_arguments(&entry, overrides);
+        // Boxed: the replayed call runs through this same entry point
+        let mut result =
+            Box::pin(self.call_tool_with_progress(&entry.tool, &replayed, progress)).await;
+        let heading = format!(
+            "Replayed #{} {} {}",
+            index,
+            entry.tool,
+            Value::Object(replayed.into_iter().collect())
+        );
+        if let Some(content) = result.content.first_mut() {
+            content.text = format!("{}\n\n{}", heading, content.text);
+        }
+        result
+    }
+
+    /// Run the call in the environment it names, or the active one
+    ///
+    /// Either is captured once, so a `switch_environment` or reload while
+    /// the call runs cannot move its requests away from the environment its
+    /// checks passed in.
+    async fn dispatch_tool(&self, name: &str, args: &HashMap<String, Value>) -> CallToolResult {
+        let current = self.environment();
+        let routed = get_str(args, "environment")
+            .map(str::trim)
+            .filter(|environment| !environment.is_empty());
+        match routed {
+            Some(environment) if !UNROUTED_TOOLS.contains(&name) => {
+                let target = match self.environment_named(environment) {
+                    Ok(target) => target,
+                    Err(message) => return CallToolResult::error(message),
+                };
+                let active = current.config.environment.as_deref() == Some(environment);
+                if target.config.production && is_mutating_tool(name) && !active {
+                    return CallToolResult::error(format!(
+                        "'{}' is a production environment; changes there need switch_environment with confirm=true first",
+                        environment
+                    ));
+                }
+                ROUTED
+                    .scope(target, self.dispatch_in_environment(name, args))
+                    .await
+            }
+            None if name == "list_entities" && !current.config.environments.is_empty() => {
+                ROUTED
+                    .scope(current, self.list_entities_everywhere(args))
+                    .await
+            }
+            _ => {
+                ROUTED
+                    .scope(current, self.dispatch_in_environment(name, args))
+                    .await
+            }
+        }
     }
 
-    /// Handle a tool call
-    pub async fn call_tool(&self, name: &str, args: &HashMap<String, Value>) -> CallToolResult {
-        match name {
-            "list_entities" => self.list_entities().await,
-            "query_entity" => self.query_entity(args).await,
-            "get_entity_schema" => self.get_entity_schema(args).await,
-            "get_record" => self.get_record(args).await,
-            "delete_record" => self.delete_record(args).await,
-            "get_environment_info" => self.get_environment_info().await,
-            "get_metadata" => self.get_metadata(args).await,
-            "refresh_metadata" => self.refresh_metadata().await,
-            _ => CallToolResult::error(format!("Unknown tool: {}", name)),
-        }
+    /// `list_entities` in every configured environment, one section each
+    async fn list_entities_everywhere(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let environments = self.config().environments.clone();
+        let mut text = String::new();
+        let mut failed = 0;
+        for summary in &environments {
+            let result = match self.environment_named(&summary.name) {
+                Ok(environment) => {
+                    ROUTED
+                        .scope(
+                            environment,
+                            self.dispatch_in_environment("list_entities", args),
+                        )
+                        .await
+                }
+                Err(message) => CallToolResult::error(message),
+            };
+            if result.is_error == Some(true) {
+                failed += 1;
+            }
+            text.push_str(&format!(
+                "[{}] {:?} {}\n",
+                summary.name, summary.product, summary.endpoint
+            ));
+            for content in &result.content {
+                text.push_str(&content.text);
+            }
+            text.push_str("\n\n");
+        }
+        let text = text.trim_end().to_string();
+        if failed == environments.len() {
+            CallToolResult::error(text)
+        } else {
+            CallToolResult::text(text)
+        }
+    }
+
+    async fn dispatch_in_environment(
+        &self,
+        name: &str,
+        args: &HashMap<String, Value>,
+    ) -> CallToolResult {
+        // Enforced here as well, since clients may call tools that were not listed
+        if self.config().read_only && is_mutating_tool(name) {
+            return CallToolResult::error(format!(
+                "Tool '{}' is disabled: server is in read-only mode. \
+                 Set READ_ONLY=false to allow changes.",
+                name
+            ));
+        }
+
+        if let Err(invalid) = check_arguments(name, args, &self.config()) {
+            return invalid;
+        }
+
+        if !is_available_for(name, &self.config().product) {
+            let product = if FINOPS_TOOLS.contains(&name) {
+                "Finance & Operations"
+            } else {
+                "Dataverse"
+            };
+            return CallToolResult::error(format!(
+                "Tool '{}' is only available for {}",
+                name, product
+            ));
+        }
+
+        // Every tool that touches an entity names it in the `entity` argument,
+        // and tools linking two records name the other in `target_entity`
+        for entity in ["entity", "target_entity"]
+            .map(|arg| get_str(args, arg))
+            .into_iter()
+            .flatten()
+        {
+            if let Err(message) = self.entity_policy().check(entity) {
+                return CallToolResult::error(message);
+            }
+        }
+        if let Err(message) = self.check_expand_policy(name, args).await {
+            return CallToolResult::error(message);
+        }
+        if let Err(message) = self.check_write_capabilities(name, args).await {
+            return CallToolResult::error(message);
+        }
+
+        // The schema's range is enforced here too, for calls that skip it
+        let timeout = Duration::from_secs(
+            get_usize(args, "timeout")
+                .map(|secs| (secs as u64).clamp(1, MAX_CALL_TIMEOUT_SECS as u64))
+                .unwrap_or(self.config().request_timeout_secs),
+        );
+        let caller = match get_str(args, "impersonate_user_id").map(str::trim) {
+            Some(user_id) if !is_guid(user_id) => {
+                return CallToolResult::error(format!(
+                    "impersonate_user_id must be a GUID, got '{}'",
+                    user_id
+                ))
+            }
+            Some(user_id) => Some(user_id.trim_matches(['{', '}']).to_string()),
+            None => None,
+        };
+        let language = match get_str(args, "language").map(|tag| {
+            parse_language_tag(tag)
+                .and_then(|tag| supported_language(&tag, self.client().language().as_deref()))
+        }) {
+            Some(Err(message)) => return CallToolResult::error(message),
+            Some(Ok(language)) => Some(language),
+            None => None,
+        };
+        let run = with_timeout(timeout, self.run_tool_or_dry_run(name, args));
+        let run = async {
+            match language {
+                Some(language) => with_language(language, run).await,
+                None => run.await,
+            }
+        };
+        let (result, failed_request_ids) = match caller {
+            Some(user_id) => with_failed_request_ids(with_caller(user_id, run)).await,
+            None => with_failed_request_ids(run).await,
+        };
+
+        let result = quote_failed_request_ids(result, &failed_request_ids);
+        truncate_result(result, self.config().max_response_chars)
+    }
+
+    /// Run the tool, or only build its request when `dry_run` is set or
+    /// `dry_run_all_writes` covers it
+    async fn run_tool_or_dry_run(
+        &self,
+        name: &str,
+        args: &HashMap<String, Value>,
+    ) -> CallToolResult {
+        let dry_run = get_bool(args, "dry_run").unwrap_or(false)
+            || (is_mutating_tool(name) && self.config().dry_run_all_writes);
+        // Boxed: the combined tool futures are too large for the stack of a
+        // debug build's test thread
+        let run = Box::pin(self.run_tool(name, args));
+        if !dry_run {
+            return run.await;
+        }
+        let (result, requests) = with_dry_run(run).await;
+        dry_run_result(result, requests)
+    }
+
+    async fn run_tool(&self, name: &str, args: &HashMap<String, Value>) -> CallToolResult {
+        match name {
+            "list_entities" => self.list_entities(args).await,
+            "search_entities" => self.search_entities(args).await,
+            "search" => self.relevance_search(args).await,
+            "get_record_audit" => self.get_record_audit(args).await,
+            "list_views" => self.list_views(args).await,
+            "run_view" => self.run_view(args).await,
+            "query_entity" => self.query_entity(args).await,
+            "query_related" => self.query_related(args).await,
+            "distinct_values" => self.distinct_values(args).await,
+            "export_entity" => self.export_entity(args).await,
+            "get_entity_schema" => self.get_entity_schema(args).await,
+            "get_record" => self.get_record(args).await,
+            "compare_record" => self.compare_record(args).await,
+            "download_file" => self.download_file(args).await,
+            "upload_file" => self.upload_file(args).await,
+            "delete_record" => self.delete_record(args).await,
+            "upsert_record" => self.upsert_record(args).await,
+            "bulk_update" => self.bulk_update(args).await,
+            "dmf_import_package" => self.dmf_import_package(args).await,
+            "dmf_get_execution_status" => self.dmf_get_execution_status(args).await,
+            "execute_action" => self.execute_action(args).await,
+            "check_operation" => self.check_operation(args).await,
+            "associate_records" => self.associate_records(args).await,
+            "disassociate_records" => self.disassociate_records(args).await,
+            "get_environment_info" => self.get_environment_info().await,
+            "get_server_stats" => CallToolResult::text(self.metrics.summary(&self.request_stats())),
+            "query_history" => self.query_history(args),
+            "list_environments" => self.list_environments(),
+            "switch_environment" => self.switch_environment(args).await,
+            "list_optionsets" => self.list_optionsets(args).await,
+            "get_optionset" => self.get_optionset(args).await,
+            "list_companies" => self.list_companies().await,
+            "test_connection" => self.test_connection(args).await,
+            "whoami" => self.whoami().await,
+            "get_metadata" => self.get_metadata(args).await,
+            "describe_relationships" => self.describe_relationships(args).await,
+            "refresh_metadata" => self.refresh_metadata().await,
+            _ => CallToolResult::error(format!("Unknown tool: {}", name)),
+        }
+    }
+
+    /// Resources: one schema per entity plus the raw metadata document.
+    ///
+    /// Entities come from the `[[entities]]` config section when present,
+    /// otherwise from the (cached) metadata, filtered by the entity policy.
+    pub async fn list_resources(&self) -> Result<Vec<Resource>, JsonRpcError> {
+        let mut entities: Vec<String> = if self.config().entities.is_empty() {
+            self.allowed_entity_sets()
+                .await
+                .map_err(|message| rpc_error(-32603, message))?
+                .into_iter()
+                .map(|(set, _)| set)
+                .collect()
+        } else {
+            self.config()
+                .entities
+                .iter()
+                .map(|e| e.name.clone())
+                .collect()
+        };
+        entities.retain(|entity| self.entity_policy().is_allowed(entity));
+
+        let mut resources = Vec::with_capacity(entities.len() + 1);
+        // The full EDMX describes every entity, so it is withheld under a policy
+        if !self.entity_policy().is_restricted() {
+            resources.push(Resource {
+                uri: METADATA_RESOURCE_URI.to_string(),
+                name: "$metadata".to_string(),
+                description: Some("Raw EDMX metadata document for the environment".to_string()),
+                mime_type: Some("application/xml".to_string()),
+            });
+        }
+        resources.extend(entities.into_iter().map(|entity| Resource {
+            uri: entity_schema_uri(&entity),
+            description: Some(format!(
+                "Keys, properties and navigation properties of {}",
+                entity
+            )),
+            name: format!("{} schema", entity),
+            mime_type: Some("text/markdown".to_string()),
+        }));
+        Ok(resources)
+    }
+
+    /// Prompts offered by `prompts/list` (static, so they are listed before configuration)
+    pub fn list_prompts() -> Vec<Prompt> {
+        prompt_definitions()
+    }
+
+    /// Render a prompt, embedding schema details from the metadata cache when available
+    pub async fn get_prompt(
+        &self,
+        name: &str,
+        args: &HashMap<String, String>,
+    ) -> Result<GetPromptResult, JsonRpcError> {
+        let argument = |key: &str| args.get(key).map(|v| v.trim()).filter(|v| !v.is_empty());
+        let required = |key: &str| {
+            argument(key)
+                .ok_or_else(|| rpc_error(-32602, format!("Missing required argument: {}", key)))
+        };
+
+        let entity = argument("entity");
+        if let Some(entity) = entity {
+            self.entity_policy()
+                .check(entity)
+                .map_err(|message| rpc_error(-32602, message))?;
+        }
+
+        let (description, text) = match name {
+            "explore_entity" => {
+                let entity = required("entity")?;
+                let summary = self.entity_summary(entity).await;
+                (
+                    format!("Explore {}", entity),
+                    explore_entity_text(entity, &self.config().product, summary.as_ref()),
+                )
+            }
+            "build_filter" => {
+                let goal = required("goal")?;
+                let summary = match entity {
+                    Some(entity) => self.entity_summary(entity).await,
+                    None => None,
+                };
+                (
+                    "Build an OData filter".to_string(),
+                    build_filter_text(&self.config().product, goal, entity, summary.as_ref()),
+                )
+            }
+            _ => return Err(rpc_error(-32602, format!("Unknown prompt: {}", name))),
+        };
+
+        Ok(GetPromptResult {
+            description: Some(description),
+            messages: vec![PromptMessage {
+                role: "user".to_string(),
+                content: TextContent {
+                    content_type: "text".to_string(),
+                    text,
+                },
+            }],
+        })
+    }
+
+    /// Keys and properties of an entity, or `None` if metadata is unavailable
+    async fn entity_summary(&self, entity: &str) -> Option<EntitySummary> {
+        let model = match self.client().metadata_model().await {
+            Ok(model) => model,
+            Err(e) => {
+                tracing::warn!("Metadata unavailable for prompt: {}", e);
+                return None;
+            }
+        };
+        let (properties, _, keys) = ODataClient::parse_entity_from_metadata(&model, entity).ok()?;
+        Some(EntitySummary { keys, properties })
+    }
+
+    /// Read a resource returned by [`list_resources`](Self::list_resources)
+    pub async fn read_resource(&self, uri: &str) -> Result<ReadResourceResult, JsonRpcError> {
+        let not_found = || rpc_error(RESOURCE_NOT_FOUND, format!("Resource not found: {}", uri));
+
+        let entity = if uri == METADATA_RESOURCE_URI {
+            if self.entity_policy().is_restricted() {
+                return Err(rpc_error(
+                    -32602,
+                    "The raw metadata resource is unavailable while an entity policy is configured"
+                        .to_string(),
+                ));
+            }
+            None
+        } else {
+            let entity = uri
+                .strip_prefix("d365://entity/")
+                .and_then(|rest| rest.strip_suffix("/schema"))
+                .filter(|entity| !entity.is_empty() && !entity.contains('/'))
+                .ok_or_else(not_found)?;
+            self.entity_policy()
+                .check(entity)
+                .map_err(|message| rpc_error(-32602, message))?;
+            Some(entity)
+        };
+
+        let metadata = self
+            .client()
+            .fetch_metadata()
+            .await
+            .map_err(|e| rpc_error(-32603, format!("Error fetching metadata: {}", e)))?;
+
+        let (text, mime_type) = match entity {
+            Some(entity) => {
+                let markdown =
+                    format_entity_metadata(&metadata.model(), entity).map_err(|_| not_found())?;
+                (markdown, "text/markdown")
+            }
+            None => (metadata.xml().to_string(), "application/xml"),
+        };
+
+        Ok(ReadResourceResult {
+            contents: vec![TextResourceContents {
+                uri: uri.to_string(),
+                mime_type: Some(mime_type.to_string()),
+                text,
+            }],
+        })
+    }
+
+    /// Entity sets in the metadata, as (set, entity type), that the policy allows
+    async fn allowed_entity_sets(&self) -> Result<Vec<(String, String)>, String> {
+        let model = self
+            .client()
+            .metadata_model()
+            .await
+            .map_err(|e| format!("Error fetching metadata: {}", e))?;
+        if model.entity_sets.is_empty() {
+            return Err(format!(
+                "No entity sets found in $metadata from {}; check that ENDPOINT is the OData service root",
+                self.client().endpoint()
+            ));
+        }
+        Ok(model
+            .entity_sets
+            .iter()
+            .filter(|(set, _)| self.entity_policy().is_allowed(set))
+            .cloned()
+            .collect())
+    }
+
+    async fn list_entities(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let mut entities = match self.allowed_entity_sets().await {
+            Ok(entities) => entities,
+            Err(message) => return CallToolResult::error(message),
+        };
+
+        let filter = get_str(args, "filter").map(|f| f.trim().to_lowercase());
+        if let Some(filter) = filter.as_deref().filter(|f| !f.is_empty()) {
+            entities.retain(|(set, _)| set.to_lowercase().contains(filter));
+        }
+        let prefix = get_str(args, "prefix").map(|p| p.trim().to_lowercase());
+        if let Some(prefix) = prefix.as_deref().filter(|p| !p.is_empty()) {
+            entities.retain(|(set, _)| set.to_lowercase().starts_with(prefix));
+        }
+
+        // Entities from the config file lead, in the order they are configured
+        let configured_rank = |set: &str| {
+            self.config()
+                .entities
+                .iter()
+                .position(|entity| entity.name.eq_ignore_ascii_case(set))
+        };
+        entities.sort_by_key(|(set, _)| configured_rank(set).unwrap_or(usize::MAX));
+        let configured = entities
+            .iter()
+            .take_while(|(set, _)| configured_rank(set).is_some())
+            .count();
+
+        let total = entities.len();
+        let offset = get_usize(args, "offset").unwrap_or(0).min(total);
+        let limit = get_usize(args, "limit")
+            .unwrap_or(DEFAULT_ENTITY_PAGE)
+            .max(1);
+        let end = (offset + limit).min(total);
+
+        let mut matching = String::new();
+        if let Some(filter) = filter.as_deref().filter(|f| !f.is_empty()) {
+            matching.push_str(&format!(" matching '{}'", filter));
+        }
+        if let Some(prefix) = prefix.as_deref().filter(|p| !p.is_empty()) {
+            matching.push_str(&format!(" starting with '{}'", prefix));
+        }
+        if total == 0 {
+            return CallToolResult::text(format!(
+                "No entities{}. Try search_entities for approximate names.",
+                matching
+            ));
+        }
+
+        let mut text = format!(
+            "Entities {}-{} of {}{}:\n",
+            offset + 1,
+            end,
+            total,
+            matching
+        );
+        // The model is cached, so descriptions cost no extra request
+        let model = self.client().metadata_model().await.ok();
+        for (index, (set, entity_type)) in entities.iter().enumerate().take(end).skip(offset) {
+            if configured > 0 && index == offset && index < configured {
+                text.push_str("Configured:\n");
+            } else if configured > 0 && index == configured {
+                if index > offset {
+                    text.push('\n');
+                }
+                text.push_str("Other entities:\n");
+            }
+            text.push_str(&format!("{} ({})", set, entity_type));
+            if let Some(description) = model.as_ref().and_then(|model| model.description(set)) {
+                text.push_str(": ");
+                text.push_str(&short_description(description));
+            }
+            text.push('\n');
+        }
+        if end < total {
+            text.push_str(&format!("\nMore available: call again with offset={}", end));
+        }
+        CallToolResult::text(text.trim_end().to_string())
+    }
+
+    async fn search_entities(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(query) = get_str(args, "query") else {
+            return CallToolResult::error("Missing required parameter: query".to_string());
+        };
+        let limit = get_usize(args, "limit")
+            .unwrap_or(DEFAULT_SEARCH_RESULTS)
+            .clamp(1, 100);
+
+        let entities = match self.allowed_entity_sets().await {
+            Ok(entities) => entities,
+            Err(message) => return CallToolResult::error(message),
+        };
+
+        let matches = rank_entity_sets(&entities, query, limit);
+        if matches.is_empty() {
+            return CallToolResult::text(format!(
+                "No entities match '{}'. Use list_entities to browse all {} entity sets.",
+                query,
+                entities.len()
+            ));
+        }
+
+        let mut text = format!("Best matches for '{}':\n", query);
+        for (index, (set, entity_type)) in matches.iter().enumerate() {
+            text.push_str(&format!("{}. {} ({})\n", index + 1, set, entity_type));
+        }
+        CallToolResult::text(text.trim_end().to_string())
+    }
+
+    async fn relevance_search(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(query) = get_str(args, "query")
+            .map(str::trim)
+            .filter(|query| !query.is_empty())
+        else {
+            return CallToolResult::error("Missing required parameter: query".to_string());
+        };
+        let mut entities: Vec<String> = get_str(args, "entities")
+            .map(|entities| {
+                entities
+                    .split(',')
+                    .map(|entity| entity.trim().to_lowercase())
+                    .filter(|entity| !entity.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let top = get_usize(args, "top")
+            .unwrap_or(DEFAULT_SEARCH_RESULTS)
+            .clamp(1, 100);
+
+        // The entity policy names entity sets, while search uses logical names
+        let allowed_types = if self.entity_policy().is_restricted() {
+            match self.client().metadata_model().await {
+                Ok(model) => Some(
+                    model
+                        .entity_sets
+                        .iter()
+                        .filter(|(set, _)| self.entity_policy().is_allowed(set))
+                        .map(|(_, entity_type)| entity_type.to_lowercase())
+                        .collect::<Vec<_>>(),
+                ),
+                Err(e) => {
+                    return CallToolResult::error(format!(
+                        "Metadata is needed to apply the entity policy to search: {}",
+                        e
+                    ))
+                }
+            }
+        } else {
+            None
+        };
+        if let Some(allowed) = &allowed_types {
+            if let Some(denied) = entities.iter().find(|entity| !allowed.contains(entity)) {
+                return CallToolResult::error(format!(
+                    "Table '{}' is not allowed by the server's entity policy",
+                    denied
+                ));
+            }
+            if entities.is_empty() {
+                entities = allowed.clone();
+            }
+        }
+
+        match self.client().relevance_search(query, &entities, top).await {
+            Ok(mut results) => {
+                if let Some(allowed) = &allowed_types {
+                    results
+                        .hits
+                        .retain(|hit| allowed.contains(&hit.entity.to_lowercase()));
+                }
+                CallToolResult::text(format_search_results(query, &results))
+            }
+            Err(e) => CallToolResult::error(format!("Error searching for '{}': {}", query, e)),
+        }
+    }
+
+    async fn list_views(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(entity) = get_str(args, "entity") else {
+            return CallToolResult::error("Missing required parameter: entity".to_string());
+        };
+        // Views are keyed by the table's logical name, which is its entity type
+        let logical_name = match self.client().metadata_model().await {
+            Ok(model) => match model.entity_type(entity) {
+                Some(entity_type) => entity_type.name.clone(),
+                None => {
+                    return CallToolResult::error(format!(
+                        "Entity '{}' not found in $metadata",
+                        entity
+                    ))
+                }
+            },
+            Err(e) => return CallToolResult::error(format!("Error loading metadata: {}", e)),
+        };
+
+        let views = match self.client().list_views(&logical_name).await {
+            Ok(views) => views,
+            Err(e) => {
+                return CallToolResult::error(format!(
+                    "Error listing views of {}: {}",
+                    logical_name, e
+                ))
+            }
+        };
+        if views.is_empty() {
+            return CallToolResult::text(format!("No saved views found for '{}'", logical_name));
+        }
+
+        let mut result = format!("Views of {} ({}):\n", logical_name, views.len());
+        for view in &views {
+            let default = if view.is_default { ", default" } else { "" };
+            result.push_str(&format!(
+                "- {} [{}{}] view_id: {}\n",
+                view.name,
+                view.kind.as_str(),
+                default,
+                view.id
+            ));
+            if let Some(description) = &view.description {
+                result.push_str(&format!("  {}\n", description));
+            }
+        }
+        result.push_str("\nRun one with run_view, passing view_id and view_type.");
+        CallToolResult::text(result)
+    }
+
+    async fn run_view(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(view_id) = get_str(args, "view_id").map(str::trim) else {
+            return CallToolResult::error("Missing required parameter: view_id".to_string());
+        };
+        if !is_guid(view_id) {
+            return CallToolResult::error(format!("view_id must be a GUID, got '{}'", view_id));
+        }
+        let kind = match get_str(args, "view_type")
+            .unwrap_or("system")
+            .parse::<ViewKind>()
+        {
+            Ok(kind) => kind,
+            Err(message) => return CallToolResult::error(message),
+        };
+        let format = match get_str(args, "format") {
+            Some(format) => match format.parse::<OutputFormat>() {
+                Ok(format) => format,
+                Err(message) => return CallToolResult::error(message),
+            },
+            // A table keeps the view's column order; JSON objects do not
+            None => OutputFormat::Table,
+        };
+        let top = get_usize(args, "top")
+            .unwrap_or(DEFAULT_TOP)
+            .clamp(1, MAX_TOP);
+
+        let view = match self.client().get_view(kind, view_id).await {
+            Ok(view) => view,
+            Err(e) => {
+                return CallToolResult::error(format!(
+                    "Error loading {} view {}: {}",
+                    kind.as_str(),
+                    view_id,
+                    e
+                ))
+            }
+        };
+        let model = match self.client().metadata_model().await {
+            Ok(model) => model,
+            Err(e) => return CallToolResult::error(format!("Error loading metadata: {}", e)),
+        };
+        let tables = match fetch_xml_tables(&view.fetch_xml) {
+            Ok(tables) => tables,
+            Err(message) => return CallToolResult::error(message),
+        };
+        // The view names its tables only once loaded, so the policy is checked
+        // here: its own, and every one its FetchXML joins
+        let checked_set = |table: &str| {
+            let set = model
+                .resolve_entity_set(table)
+                .ok_or_else(|| format!("No entity set for table '{}' in $metadata", table))?;
+            self.entity_policy().check(set)?;
+            Ok::<_, String>(set.to_string())
+        };
+        let entity_set = match checked_set(&view.entity) {
+            Ok(set) => set,
+            Err(message) => return CallToolResult::error(message),
+        };
+        if let Err(message) = tables
+            .iter()
+            .try_for_each(|table| checked_set(table).map(drop))
+        {
+            return CallToolResult::error(message);
+        }
+
+        let fetch_xml = limit_fetch_xml(&view.fetch_xml, top);
+        let mut response = match self.client().fetch_xml(&entity_set, &fetch_xml).await {
+            Ok(response) => response,
+            Err(e) => {
+                return CallToolResult::error(format!("Error running view '{}': {}", view.name, e))
+            }
+        };
+        let more_records = response
+            .get("@Microsoft.Dynamics.CRM.morerecords")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let mut records = match response.get_mut("value").map(Value::take) {
+            Some(Value::Array(records)) => records,
+            _ => Vec::new(),
+        };
+        let annotations = self.config().annotations;
+        records
+            .iter_mut()
+            .for_each(|record| fold_annotations(record, annotations));
+        let columns = view_columns(&records, &view.columns);
+
+        let mut result = format!(
+            "View: {} ({} view of {})\nColumns: {}\n",
+            view.name,
+            kind.as_str(),
+            entity_set,
+            view.columns.join(", ")
+        );
+        if more_records {
+            result.push_str(&format!(
+                "Showing the first {} records (more available; raise top to see more):\n\n",
+                records.len()
+            ));
+        } else {
+            result.push_str(&format!("Showing {} records:\n\n", records.len()));
+        }
+
+        let budget = self
+            .config()
+            .max_response_chars
+            .saturating_sub(result.chars().count() + TRUNCATION_NOTE_RESERVE);
+        let rendered = render_records(&records, format, Some(&columns), budget);
+        result.push_str(&rendered.text);
+        if rendered.shown < records.len() {
+            result.push_str(&format!(
+                "\n\n[output truncated, {} of {} records shown; lower top]",
+                rendered.shown,
+                records.len()
+            ));
+        }
+        CallToolResult::text(result)
+    }
+
+    async fn get_record_audit(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(entity) = get_str(args, "entity") else {
+            return CallToolResult::error("Missing required parameter: entity".to_string());
+        };
+        let client = self.client();
+        let product = client.product();
+        let key = match parse_record_key(args, product, "") {
+            Ok(key) => key,
+            Err(message) => return CallToolResult::error(message),
+        };
+        let field = get_str(args, "field")
+            .map(|field| field.trim().to_lowercase())
+            .filter(|field| !field.is_empty());
+        let top = get_usize(args, "top")
+            .unwrap_or(DEFAULT_AUDIT_ENTRIES)
+            .clamp(1, 500);
+
+        // Audit settings and attribute labels are keyed by logical name
+        let logical_name = match self.client().metadata_model().await {
+            Ok(model) => match model
+                .entity_sets
+                .iter()
+                .find(|(set, _)| set.eq_ignore_ascii_case(entity))
+            {
+                Some((_, entity_type)) => entity_type.to_lowercase(),
+                None => {
+                    return CallToolResult::error(format!(
+                        "Entity set '{}' not found in $metadata",
+                        entity
+                    ))
+                }
+            },
+            Err(e) => return CallToolResult::error(format!("Error loading metadata: {}", e)),
+        };
+        let record = format!("{}({})", entity, key.expression(product, None));
+
+        let mut history = match self.client().record_change_history(&record).await {
+            Ok(history) => history,
+            Err(e) => {
+                return CallToolResult::error(format!(
+                    "Error reading audit history of {}: {}",
+                    record, e
+                ))
+            }
+        };
+        if history.is_empty() {
+            let status = self.client().audit_status(&logical_name).await;
+            return CallToolResult::text(no_audit_history(&record, &logical_name, status));
+        }
+
+        if let Some(field) = &field {
+            for entry in &mut history {
+                entry.changes.retain(|change| change.field == *field);
+            }
+            history.retain(|entry| !entry.changes.is_empty());
+            if history.is_empty() {
+                return CallToolResult::text(format!(
+                    "No audited changes to '{}' on {}",
+                    field, record
+                ));
+            }
+        }
+
+        let labels = self.client().attribute_labels(&logical_name).await;
+        CallToolResult::text(format_audit_history(&record, &history, top, &labels))
+    }
+
+    async fn query_entity(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let entity = match get_str(args, "entity") {
+            Some(e) => e,
+            None => return CallToolResult::error("Missing required parameter: entity".to_string()),
+        };
+
+        let format = match get_str(args, "format") {
+            Some(format) => match format.parse::<OutputFormat>() {
+                Ok(format) => format,
+                Err(message) => return CallToolResult::error(message),
+            },
+            None => self.config().default_format,
+        };
+        let annotations = match get_str(args, "annotations") {
+            Some(annotations) => match annotations.parse::<AnnotationMode>() {
+                Ok(annotations) => annotations,
+                Err(message) => return CallToolResult::error(message),
+            },
+            None => self.config().annotations,
+        };
+
+        // A page token replays the server's nextLink; other query arguments are ignored
+        let (next_link, mut options, applied) = match get_str(args, "page_token") {
+            Some(token) => match decode_page_token(token, self.client().endpoint()) {
+                Ok(link) => {
+                    // The token carries its own entity set; it must pass the policy too
+                    if let Some(linked) = entity_from_link(&link, self.client().endpoint()) {
+                        if let Err(message) = self.entity_policy().check(&linked) {
+                            return CallToolResult::error(message);
+                        }
+                    }
+                    (
+                        Some(link),
+                        QueryOptions::default(),
+                        AppliedDefaults::default(),
+                    )
+                }
+                Err(message) => return CallToolResult::error(message),
+            },
+            None => match self.entity_query_options(entity, args) {
+                Ok((options, applied)) => (None, options, applied),
+                Err(message) => return CallToolResult::error(message),
+            },
+        };
+
+        if next_link.is_none() && get_bool(args, "validate").unwrap_or(true) {
+            if let Err(message) = self.check_query_fields(entity, &options).await {
+                return CallToolResult::error(message);
+            }
+        }
+        options.annotations = Some(annotations);
+
+        let page = match &next_link {
+            Some(link) => self
+                .client()
+                .fetch_entity_page(entity, Some(link), &options)
+                .await
+                .map(|response| (entity.to_string(), response)),
+            None => self.fetch_page_resolving(entity, &options).await,
+        };
+
+        match page {
+            Ok((resolved, mut response)) => {
+                let entity = resolved.as_str();
+                if get_bool(args, "resolve_labels").unwrap_or(true) {
+                    self.resolve_labels(entity, &mut response.value, annotations)
+                        .await;
+                }
+                let mut header = String::new();
+                let table_count = match self.client().product() {
+                    ProductType::Dataverse if options.count && next_link.is_none() => {
+                        self.table_record_count(entity).await
+                    }
+                    _ => None,
+                };
+                if let Some(table_count) = table_count {
+                    header.push_str(&format!(
+                        "Rows in table: {} (RetrieveTotalRecordCount: whole table, ignores the filter, up to 24 hours old)\n",
+                        table_count
+                    ));
+                }
+                if let Some(note) = &applied.top_note {
+                    header.push_str(note);
+                    header.push('\n');
+                }
+                if applied.default_select {
+                    header.push_str(&format!(
+                        "Default projection from config: {} fields (pass select=* for all fields)\n",
+                        options.select.as_ref().map_or(0, Vec::len)
+                    ));
+                }
+                let mut result = self
+                    .query_page_result(entity, response, format, &options, header)
+                    .await;
+                if let (Some(table_count), Some(structured)) =
+                    (table_count, result.structured_content.as_mut())
+                {
+                    structured["table_count"] = table_count.into();
+                }
+                result
+            }
+            Err(e) => CallToolResult::error(format!("Error querying {}: {}", entity, e)),
+        }
+    }
+
+    async fn query_related(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(entity) = get_str(args, "entity") else {
+            return CallToolResult::error("Missing required parameter: entity".to_string());
+        };
+        let Some(relationship) = get_str(args, "relationship")
+            .map(str::trim)
+            .filter(|relationship| !relationship.is_empty())
+        else {
+            return CallToolResult::error("Missing required parameter: relationship".to_string());
+        };
+        let format = match get_str(args, "format") {
+            Some(format) => match format.parse::<OutputFormat>() {
+                Ok(format) => format,
+                Err(message) => return CallToolResult::error(message),
+            },
+            None => self.config().default_format,
+        };
+        let validate = get_bool(args, "validate").unwrap_or(true);
+        let target = match self
+            .related_entity_set(entity, relationship, validate)
+            .await
+        {
+            Ok(target) => target,
+            Err(message) => return CallToolResult::error(message),
+        };
+        let client = self.client();
+        let source = format!("{} of {}", relationship, entity);
+
+        let (page, options) = match get_str(args, "page_token") {
+            Some(token) => {
+                let link = match decode_page_token(token, client.endpoint()) {
+                    Ok(link) => link,
+                    Err(message) => return CallToolResult::error(message),
+                };
+                // The link must continue this relationship, or it could
+                // lead to any set the policy denies
+                match navigation_from_link(&link, client.endpoint()) {
+                    Some((linked, navigation))
+                        if linked.eq_ignore_ascii_case(entity) && navigation == relationship =>
+                    {
+                        if let Err(message) = self.entity_policy().check(&linked) {
+                            return CallToolResult::error(message);
+                        }
+                    }
+                    _ => {
+                        return CallToolResult::error(format!(
+                            "Invalid page_token: it does not continue {}",
+                            source
+                        ))
+                    }
+                }
+                let options = QueryOptions::default();
+                let page = client
+                    .fetch_entity_page(entity, Some(&link), &options)
+                    .await;
+                (page, options)
+            }
+            None => {
+                let key = match parse_record_key(args, client.product(), "") {
+                    Ok(key) => key,
+                    Err(message) => return CallToolResult::error(message),
+                };
+                let options = match parse_query_options(args) {
+                    Ok(options) => options,
+                    Err(message) => return CallToolResult::error(message),
+                };
+                if let (true, Some(target)) = (validate, &target) {
+                    if let Err(message) = self.check_query_fields(target, &options).await {
+                        return CallToolResult::error(message);
+                    }
+                }
+                let page = client
+                    .fetch_navigation(entity, &key, relationship, &options)
+                    .await;
+                (page, options)
+            }
+        };
+
+        match page {
+            Ok(mut response) => {
+                let related = target.as_deref().unwrap_or(relationship);
+                if get_bool(args, "resolve_labels").unwrap_or(true) {
+                    let annotations = self.config().annotations;
+                    self.resolve_labels(related, &mut response.value, annotations)
+                        .await;
+                }
+                let header = format!("Related records: {}\n", source);
+                self.query_page_result(related, response, format, &options, header)
+                    .await
+            }
+            Err(e) => CallToolResult::error(format!("Error querying {}: {}", source, e)),
+        }
+    }
+
+    /// Entity set a navigation property of `entity` leads to, when `$metadata`
+    /// is cached
+    ///
+    /// Unknown or single-valued navigation properties are rejected, and the
+    /// target has to pass the entity policy; a restricted policy fetches the
+    /// metadata so the target is always known.
+    async fn related_entity_set(
+        &self,
+        entity: &str,
+        relationship: &str,
+        validate: bool,
+    ) -> Result<Option<String>, String> {
+        let restricted = self.entity_policy().is_restricted();
+        let model = if restricted {
+            let model = self
+                .client()
+                .metadata_model()
+                .await
+                .map_err(|e| format!("Error fetching metadata: {}", e))?;
+            Some(model)
+        } else if validate {
+            self.client().cached_metadata_model().await
+        } else {
+            None
+        };
+        let Some(model) = model else {
+            return Ok(None);
+        };
+
+        let navigation = match model.navigation(entity, relationship) {
+            Ok(Some(navigation)) => navigation,
+            Ok(None) if restricted => {
+                return Err(format!(
+                    "Cannot check '{}' against the entity policy: {} is not in $metadata",
+                    relationship, entity
+                ))
+            }
+            Ok(None) => return Ok(None),
+            Err(suggestions) => {
+                let hint = if suggestions.is_empty() {
+                    String::new()
+                } else {
+                    format!(" (did you mean '{}'?)", suggestions.join("', '"))
+                };
+                return Err(format!(
+                    "{} has no navigation property '{}'{}. Use describe_relationships to list them",
+                    entity, relationship, hint
+                ));
+            }
+        };
+        if !navigation.collection {
+            return Err(format!(
+                "'{}' leads to a single {} record; use query_entity with expand={} instead",
+                relationship, navigation.target_type, relationship
+            ));
+        }
+        let target = model.entity_set_for_type(&navigation.target_type);
+        match target {
+            Some(target) => self.entity_policy().check(target)?,
+            None if restricted => {
+                return Err(format!(
+                    "Cannot check '{}' against the entity policy: {} has no entity set",
+                    relationship, navigation.target_type
+                ))
+            }
+            None => {}
+        }
+        Ok(target.map(String::from))
+    }
+
+    async fn distinct_values(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(entity) = get_str(args, "entity") else {
+            return CallToolResult::error("Missing required parameter: entity".to_string());
+        };
+        let Some(field) = get_str(args, "field").map(str::trim) else {
+            return CallToolResult::error("Missing required parameter: field".to_string());
+        };
+        if !is_property_name(field) {
+            return CallToolResult::error(format!(
+                "field must be a single column name, got '{}'",
+                field
+            ));
+        }
+        let mut filter = get_str(args, "filter").map(String::from);
+        if let Some(filter) = &filter {
+            if let Err(message) = validate_filter(filter) {
+                return CallToolResult::error(format!("Invalid filter: {}", message));
+            }
+        }
+        match self.company(args) {
+            Ok(Some(company)) => filter = Some(company_filter(filter, &company)),
+            Ok(None) => {}
+            Err(message) => return CallToolResult::error(message),
+        }
+        let options = QueryOptions {
+            select: Some(vec![field.to_string()]),
+            filter,
+            cross_company: get_bool(args, "cross_company").unwrap_or(false),
+            ..Default::default()
+        };
+        if let Err(message) = self.check_query_fields(entity, &options).await {
+            return CallToolResult::error(message);
+        }
+
+        // F&O entities often reject $apply, so only Dataverse groups on the server
+        let limit = self.config().distinct_values_limit;
+        let mut deduplication = Deduplication::Client;
+        if *self.client().product() == ProductType::Dataverse {
+            let grouped = QueryOptions {
+                apply: Some(groupby_apply(field, options.filter.as_deref())),
+                ..Default::default()
+            };
+            match self.collect_distinct(entity, field, &grouped).await {
+                Ok((values, summary)) => {
+                    return distinct_result(
+                        entity,
+                        field,
+                        values,
+                        &summary,
+                        limit,
+                        Deduplication::Server,
+                    )
+                }
+                Err(ODataError::ServerError(400, message)) => {
+                    tracing::info!(
+                        "$apply rejected for {}, de-duplicating client-side: {}",
+                        entity,
+                        message
+                    );
+                    deduplication = Deduplication::ApplyRejected;
+                }
+                Err(e) => {
+                    return CallToolResult::error(format!(
+                        "Error reading {} values of {}: {}",
+                        field, entity, e
+                    ))
+                }
+            }
+        }
+
+        match self.collect_distinct(entity, field, &options).await {
+            Ok((values, summary)) => {
+                distinct_result(entity, field, values, &summary, limit, deduplication)
+            }
+            Err(e) => CallToolResult::error(format!(
+                "Error reading {} values of {}: {}",
+                field, entity, e
+            )),
+        }
+    }
+
+    /// Distinct values of `field` over every page `options` returns, reading
+    /// at most `distinct_values_limit` records
+    async fn collect_distinct(
+        &self,
+        entity: &str,
+        field: &str,
+        options: &QueryOptions,
+    ) -> Result<(Vec<DistinctValue>, StreamSummary), ODataError> {
+        let mut collector = DistinctCollector::new(field);
+        let summary = self
+            .client()
+            .fetch_pages_streaming(
+                entity,
+                options,
+                Some(self.config().distinct_values_limit),
+                |records| {
+                    collector.add(&records);
+                    ControlFlow::Continue(())
+                },
+            )
+            .await?;
+        Ok((collector.finish(), summary))
+    }
+
+    /// Check the entity sets an `expand` argument reaches against a
+    /// restricted entity policy, following each navigation through $metadata
+    async fn check_expand_policy(
+        &self,
+        name: &str,
+        args: &HashMap<String, Value>,
+    ) -> Result<(), String> {
+        let (Some(entity), Some(expand)) = (get_str(args, "entity"), get_str(args, "expand"))
+        else {
+            return Ok(());
+        };
+        if !self.entity_policy().is_restricted() {
+            return Ok(());
+        }
+        let model = self
+            .client()
+            .metadata_model()
+            .await
+            .map_err(|e| format!("Error fetching metadata: {}", e))?;
+
+        // query_related expands from the related records; a bad relationship
+        // is reported by the tool itself
+        let source = match (name, get_str(args, "relationship")) {
+            ("query_related", Some(relationship)) => match model.navigation(entity, relationship) {
+                Ok(Some(navigation)) => navigation.target_type.clone(),
+                _ => return Ok(()),
+            },
+            _ => entity.to_string(),
+        };
+        for path in expand_paths(expand) {
+            let mut from = source.clone();
+            for navigation_name in &path {
+                let Ok(Some(navigation)) = model.navigation(&from, navigation_name) else {
+                    return Err(format!(
+                        "Cannot check expand '{}' against the entity policy: {} has no navigation property '{}' in $metadata",
+                        path.join("/"),
+                        from,
+                        navigation_name
+                    ));
+                };
+                match model.entity_set_for_type(&navigation.target_type) {
+                    Some(target) => self.entity_policy().check(target)?,
+                    None => {
+                        return Err(format!(
+                        "Cannot check expand '{}' against the entity policy: {} has no entity set",
+                        path.join("/"),
+                        navigation.target_type
+                    ))
+                    }
+                }
+                from = navigation.target_type.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// Dataverse row count of the table behind `entity`, from
+    /// `RetrieveTotalRecordCount`; `None` when it cannot be read
+    async fn table_record_count(&self, entity: &str) -> Option<i64> {
+        let model = self.client().metadata_model().await.ok()?;
+        let logical_name = model.entity_type(entity)?.name.clone();
+        match self.client().total_record_counts(&[&logical_name]).await {
+            Ok(counts) => counts.get(&logical_name).copied(),
+            Err(e) => {
+                tracing::warn!(
+                    "RetrieveTotalRecordCount failed for {}: {}",
+                    logical_name,
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// A page of query results as text in `format` plus structured content,
+    /// with the page token for the next page; `header` follows the total count
+    async fn query_page_result(
+        &self,
+        entity: &str,
+        response: ODataResponse,
+        format: OutputFormat,
+        options: &QueryOptions,
+        header: String,
+    ) -> CallToolResult {
+        let record_count = response.value.len();
+        let total_count = response.count;
+        let next_page_token = response.next_link.as_deref().map(encode_page_token);
+        let context_type = match &response.context {
+            Some(context) => self.context_entity_type(context).await,
+            None => None,
+        };
+
+        let mut result = String::new();
+
+        match total_count {
+            Some(total) if response.count_limit_exceeded => result.push_str(&format!(
+                "Total records: {}+ (exact count unavailable, limit exceeded)\n",
+                total
+            )),
+            // Without all annotations the limit goes unreported
+            Some(total)
+                if total >= DATAVERSE_COUNT_LIMIT
+                    && *self.client().product() == ProductType::Dataverse =>
+            {
+                result.push_str(&format!(
+                    "Total records: at least {} (Dataverse stops counting at {})\n",
+                    total, DATAVERSE_COUNT_LIMIT
+                ));
+            }
+            Some(total) => result.push_str(&format!("Total records: {}\n", total)),
+            None => {}
+        }
+        result.push_str(&header);
+
+        match &next_page_token {
+            Some(token) => result.push_str(&format!(
+                "Showing {} records (more available):\n\
+                 next_page_token: {}\n\
+                 (pass it as page_token to fetch the next page)\n\n",
+                record_count, token
+            )),
+            None => result.push_str(&format!("Showing {} records:\n\n", record_count)),
+        }
+
+        // Cut at a record boundary so the JSON or table stays parseable
+        let max_chars = self.config().max_response_chars;
+        let budget = max_chars.saturating_sub(result.chars().count() + TRUNCATION_NOTE_RESERVE);
+        let rendered = render_records(&response.value, format, options.select.as_deref(), budget);
+        let shown = rendered.shown;
+        result.push_str(&rendered.text);
+
+        if shown < record_count {
+            result.push_str(&format!(
+                "\n\n[output truncated at {} characters, {} of {} records shown; \
+                 narrow with $select or $top]",
+                max_chars, shown, record_count
+            ));
+        }
+
+        // Same records as the text, so the size limit holds for both
+        let mut structured = query_output(
+            entity,
+            &with_entity_types(&response.value[..shown], context_type.as_deref()),
+            total_count,
+            next_page_token,
+            shown < record_count,
+        );
+        if response.count_limit_exceeded {
+            structured["count_limit_exceeded"] = true.into();
+        }
+        CallToolResult::structured(result, structured)
+    }
+
+    /// Short entity type of the records an `@odata.context` URL describes,
+    /// following a path through its navigation properties; looked up in
+    /// `$metadata` only when it is already cached
+    async fn context_entity_type(&self, context: &str) -> Option<String> {
+        let context = parse_context_url(context)?;
+        let model = self.client().cached_metadata_model().await?;
+        model
+            .path_entity_type(&context.entity_set)
+            .map(|info| info.name.clone())
+    }
+
+    /// Query options from the arguments, merged with the entity's configured
+    /// defaults and `top` limits and scoped to the company; also what the
+    /// configuration changed
+    fn entity_query_options(
+        &self,
+        entity: &str,
+        args: &HashMap<String, Value>,
+    ) -> Result<(QueryOptions, AppliedDefaults), String> {
+        let mut options = parse_query_options(args)?;
+        let config = self.config();
+        let configured = configured_entity(&config, entity);
+        let default_select = match configured {
+            Some(defaults) => apply_entity_defaults(&mut options, defaults)?,
+            None => {
+                clear_select_all(&mut options);
+                false
+            }
+        };
+        let (top, top_note) = limit_top(
+            get_usize(args, "top"),
+            top_limits(&config, configured),
+            entity,
+        );
+        options.top = Some(top);
+        if let Some(company) = self.company(args)? {
+            options.filter = Some(company_filter(options.filter, &company));
+        }
+        Ok((
+            options,
+            AppliedDefaults {
+                default_select,
+                top_note,
+            },
+        ))
+    }
+
+    /// Reject a write the entity set's capabilities annotations forbid, such
+    /// as a delete on a read-only F&O data entity, before the service answers
+    /// with a 400
+    ///
+    /// Like [`Self::check_query_fields`], only consults `$metadata` that is
+    /// already cached; entity sets without restrictions accept every write.
+    async fn check_write_capabilities(
+        &self,
+        name: &str,
+        args: &HashMap<String, Value>,
+    ) -> Result<(), String> {
+        let operations = write_operations(name, args);
+        let Some(entity) = get_str(args, "entity").filter(|_| !operations.is_empty()) else {
+            return Ok(());
+        };
+        let Some(model) = self.client().cached_metadata_model().await else {
+            return Ok(());
+        };
+        let Some(writable) = model.write_capabilities(entity) else {
+            return Ok(());
+        };
+        if operations
+            .iter()
+            .any(|operation| writable.allows(*operation))
+        {
+            return Ok(());
+        }
+        let names: Vec<&str> = operations
+            .iter()
+            .map(|operation| operation.name())
+            .collect();
+        let terms: Vec<&str> = operations
+            .iter()
+            .map(|operation| operation.restriction_term())
+            .collect();
+        Err(format!(
+            "'{}' does not allow {}: its {} in $metadata forbid it (writable: {}). \
+             Read-only data entities and virtual tables without write support reject such calls; nothing was sent",
+            entity,
+            names.join(" or "),
+            terms.join(" and "),
+            writable.summary()
+        ))
+    }
+
+    /// Reject fields the entity does not declare before the service answers
+    /// with an opaque 400
+    ///
+    /// Only checks against `$metadata` that is already cached, so a query
+    /// never waits for the download just to be validated.
+    async fn check_query_fields(&self, entity: &str, options: &QueryOptions) -> Result<(), String> {
+        let Some(model) = self.client().cached_metadata_model().await else {
+            return Ok(());
+        };
+        let unknown = model.unknown_fields(entity, options);
+        if unknown.is_empty() {
+            return Ok(());
+        }
+        let mut message = format!("Unknown fields for {}:", entity);
+        for field in &unknown {
+            message.push_str(&format!("\n- '{}' in {}", field.name, field.option));
+            if !field.suggestions.is_empty() {
+                let names: Vec<String> = field
+                    .suggestions
+                    .iter()
+                    .map(|name| format!("'{}'", name))
+                    .collect();
+                message.push_str(&format!(" (did you mean {}?)", names.join(", ")));
+            }
+        }
+        message.push_str("\nUse get_entity_schema to list the fields, or pass validate=false to send the query as is.");
+        Err(message)
+    }
+
+    /// `fetch_entity_page`, retried under the `$metadata` spelling of `entity`
+    /// when the service does not know the name as typed
+    ///
+    /// Returns the entity set that was queried. `$metadata` is only consulted
+    /// after a 404, so correctly spelled names never wait for its download.
+    async fn fetch_page_resolving(
+        &self,
+        entity: &str,
+        options: &QueryOptions,
+    ) -> Result<(String, ODataResponse), ODataError> {
+        let error = match self.client().fetch_entity_page(entity, None, options).await {
+            Ok(response) => return Ok((entity.to_string(), response)),
+            Err(error @ ODataError::NotFound(_)) => error,
+            Err(error) => return Err(error),
+        };
+        match self.client().resolve_entity_set(entity).await {
+            // A type name may resolve to a set the policy blocks
+            Ok(resolved) if resolved != entity && self.entity_policy().check(&resolved).is_ok() => {
+                let response = self
+                    .client()
+                    .fetch_entity_page(&resolved, None, options)
+                    .await?;
+                Ok((resolved, response))
+            }
+            _ => Err(error),
+        }
+    }
+
+    /// Add `field_label` keys: from the Dataverse formatted-value annotations
+    /// requested with `annotations`, or from `$metadata` enum members for F&O
+    async fn resolve_labels(
+        &self,
+        entity: &str,
+        records: &mut [Value],
+        annotations: AnnotationMode,
+    ) {
+        match self.client().product() {
+            ProductType::Dataverse => records
+                .iter_mut()
+                .for_each(|record| fold_annotations(record, annotations)),
+            ProductType::Finops => {
+                // F&O usually sends enum member names already; only numeric
+                // values are worth the metadata lookup
+                if !has_integer_values(records) {
+                    return;
+                }
+                match self.client().metadata_model().await {
+                    Ok(model) => {
+                        let properties = model.enum_properties(entity);
+                        apply_enum_labels(records, &properties, &model.enums);
+                    }
+                    Err(e) => tracing::warn!("Metadata unavailable for enum labels: {}", e),
+                }
+            }
+        }
+    }
+
+    async fn get_entity_schema(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let entity = match get_str(args, "entity") {
+            Some(e) => e,
+            None => return CallToolResult::error("Missing required parameter: entity".to_string()),
+        };
+
+        if get_str(args, "source") == Some("metadata") {
+            return self
+                .entity_schema_from_metadata(entity, "Source: $metadata")
+                .await;
+        }
+
+        let options = QueryOptions {
+            top: Some(1),
+            ..Default::default()
+        };
+
+        match self.fetch_page_resolving(entity, &options).await {
+            Ok((resolved, response)) => {
+                let entity = resolved.as_str();
+                if let Some(sample) = response.value.into_iter().next() {
+                    if let Value::Object(map) = &sample {
+                        let fields: Vec<String> = map.keys().cloned().collect();
+                        let result = format!(
+                            "Source: sample record\nEntity: {}\nFields ({}):\n{}\n\nSample record:\n{}",
+                            entity,
+                            fields.len(),
+                            fields.join(", "),
+                            serde_json::to_string_pretty(&sample).unwrap_or_default()
+                        );
+                        CallToolResult::text(result)
+                    } else {
+                        CallToolResult::text(
+                            serde_json::to_string_pretty(&sample).unwrap_or_default(),
+                        )
+                    }
+                } else {
+                    // An empty table still has a schema
+                    let source = format!(
+                        "Source: $metadata (no records found in entity '{}')",
+                        entity
+                    );
+                    self.entity_schema_from_metadata(entity, &source).await
+                }
+            }
+            Err(e) => CallToolResult::error(format!("Error fetching schema for {}: {}", entity, e)),
+        }
+    }
+
+    /// `get_entity_schema` from `$metadata`: typed properties and key fields
+    async fn entity_schema_from_metadata(&self, entity: &str, source: &str) -> CallToolResult {
+        let model = match self.client().metadata_model().await {
+            Ok(model) => model,
+            Err(e) => return CallToolResult::error(format!("Failed to fetch metadata: {}", e)),
+        };
+        let entity = model.resolve_entity_set(entity).unwrap_or(entity);
+        if let Err(message) = self.entity_policy().check(entity) {
+            return CallToolResult::error(message);
+        }
+        match format_entity_metadata(&model, entity) {
+            Ok(output) => CallToolResult::text(format!("{}\n\n{}", source, output)),
+            Err(e) => CallToolResult::error(format!("Error fetching schema for {}: {}", entity, e)),
+        }
+    }
+
+    async fn get_record(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let entity = match get_str(args, "entity") {
+            Some(e) => e,
+            None => return CallToolResult::error("Missing required parameter: entity".to_string()),
+        };
+
+        let key = match parse_record_key(args, self.client().product(), "") {
+            Ok(key) => key,
+            Err(message) => return CallToolResult::error(message),
+        };
+
+        match self.client().get_entity(entity, &key).await {
+            Ok(mut record) => {
+                if get_bool(args, "resolve_labels").unwrap_or(true) {
+                    let annotations = self.config().annotations;
+                    self.resolve_labels(entity, std::slice::from_mut(&mut record), annotations)
+                        .await;
+                }
+                let entity_type = match (
+                    record.get("@odata.type").and_then(Value::as_str),
+                    record.get("@odata.context").and_then(Value::as_str),
+                ) {
+                    (Some(odata_type), _) => Some(short_type_name(odata_type).to_string()),
+                    (None, Some(context)) => self.context_entity_type(context).await,
+                    (None, None) => None,
+                };
+                let header: Vec<String> = [
+                    entity_type.map(|entity_type| format!("type: {}", entity_type)),
+                    record_etag(&record).map(|etag| format!("etag: {}", etag)),
+                ]
+                .into_iter()
+                .flatten()
+                .collect();
+                let mut text = serde_json::to_string_pretty(&record).unwrap_or_default();
+                if !header.is_empty() {
+                    text = format!("{}\n\n{}", header.join("\n"), text);
+                }
+                CallToolResult::structured(text, record_output(entity, &record))
+            }
+            Err(e) => CallToolResult::error(format!("Error: {}", e)),
+        }
+    }
+
+    async fn compare_record(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(entity) = get_str(args, "entity") else {
+            return CallToolResult::error("Missing required parameter: entity".to_string());
+        };
+        let (Some(source), Some(target)) = (
+            get_str(args, "source_environment").map(str::trim),
+            get_str(args, "target_environment").map(str::trim),
+        ) else {
+            return CallToolResult::error(
+                "Missing required parameters: source_environment and target_environment"
+                    .to_string(),
+            );
+        };
+        if source == target {
+            return CallToolResult::error(format!(
+                "Both sides are '{}'; name two different environments",
+                source
+            ));
+        }
+
+        let mut records = Vec::new();
+        let mut described = String::new();
+        for name in [source, target] {
+            let environment = match self.environment_named(name) {
+                Ok(environment) => environment,
+                Err(message) => return CallToolResult::error(message),
+            };
+            let fetched = ROUTED
+                .scope(environment, self.fetch_for_comparison(entity, args))
+                .await;
+            match fetched {
+                Ok((record, source)) => {
+                    described = source;
+                    records.push(record);
+                }
+                Err(message) => return CallToolResult::error(format!("[{}] {}", name, message)),
+            }
+        }
+
+        let (left, right) = match (&records[0], &records[1]) {
+            (Some(left), Some(right)) => (left, right),
+            (None, None) => {
+                return CallToolResult::error(format!(
+                    "{} exists in neither '{}' nor '{}'",
+                    described, source, target
+                ))
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                let (found, missing) = if records[0].is_some() {
+                    (source, target)
+                } else {
+                    (target, source)
+                };
+                return CallToolResult::text(format!(
+                    "{} exists only in '{}'; it was not found in '{}'",
+                    described, found, missing
+                ));
+            }
+        };
+
+        let mut ignored = self.config().compare_ignored_fields.clone();
+        ignored.extend(
+            get_str(args, "ignore_fields")
+                .into_iter()
+                .flat_map(|fields| fields.split(','))
+                .map(str::trim)
+                .filter(|field| !field.is_empty())
+                .map(String::from),
+        );
+        let differences = diff_records(left, right, &ignored);
+        let ignoring = if ignored.is_empty() {
+            String::new()
+        } else {
+            format!(" (ignoring {})", ignored.join(", "))
+        };
+        if differences.is_empty() {
+            return CallToolResult::text(format!(
+                "{} is the same in '{}' and '{}'{}",
+                described, source, target, ignoring
+            ));
+        }
+        let mut text = format!(
+            "{} differs in {} field(s), shown as '{}' | '{}'{}:\n",
+            described,
+            differences.len(),
+            source,
+            target,
+            ignoring
+        );
+        for difference in &differences {
+            text.push_str(&format!("- {}\n", difference.summary()));
+        }
+        CallToolResult::text(text.trim_end().to_string())
+    }
+
+    /// The record in the current call's environment, or `None` when it does
+    /// not exist, with the record's `entity(key)` reference
+    async fn fetch_for_comparison(
+        &self,
+        entity: &str,
+        args: &HashMap<String, Value>,
+    ) -> Result<(Option<Value>, String), String> {
+        self.entity_policy().check(entity)?;
+        let client = self.client();
+        let key = parse_record_key(args, client.product(), "")?;
+        let described = format!("{}({})", entity, key.expression(client.product(), None));
+        match client.get_entity(entity, &key).await {
+            Ok(record) => Ok((Some(record), described)),
+            Err(ODataError::NotFound(_)) => Ok((None, described)),
+            Err(e) => Err(format!("Error fetching {}: {}", described, e)),
+        }
+    }
+
+    async fn download_file(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(entity) = get_str(args, "entity") else {
+            return CallToolResult::error("Missing required parameter: entity".to_string());
+        };
+        let Some(attribute) = get_str(args, "attribute")
+            .map(str::trim)
+            .filter(|attribute| !attribute.is_empty())
+        else {
+            return CallToolResult::error("Missing required parameter: attribute".to_string());
+        };
+        let client = self.client();
+        let product = client.product();
+        let key = match parse_record_key(args, product, "") {
+            Ok(key) => key,
+            Err(message) => return CallToolResult::error(message),
+        };
+        let source = format!(
+            "{}({})/{}",
+            entity,
+            key.expression(product, None),
+            attribute
+        );
+
+        let download = match self
+            .client()
+            .download_file(entity, &key, attribute, self.config().max_download_bytes)
+            .await
+        {
+            Ok(Some(download)) => download,
+            Ok(None) => return CallToolResult::text(format!("{} is empty", source)),
+            Err(e) => return CallToolResult::error(format!("Error downloading {}: {}", source, e)),
+        };
+
+        let name = download_file_name(download.file_name.as_deref(), entity, attribute);
+        let size = download.bytes.len();
+        let mut text = format!("Downloaded '{}' ({} bytes) from {}\n", name, size, source);
+
+        if get_bool(args, "inline").unwrap_or(false) {
+            if size <= INLINE_FILE_LIMIT {
+                text.push_str(&format!("Base64:\n{}", STANDARD.encode(&download.bytes)));
+                return CallToolResult::text(text);
+            }
+            text.push_str(&format!(
+                "Too large to return inline (limit {} bytes); saved to a file instead\n",
+                INLINE_FILE_LIMIT
+            ));
+        }
+
+        let config = self.config();
+        let dir = Path::new(&config.download_dir);
+        match save_download(dir, &name, &download.bytes) {
+            Ok(path) => {
+                text.push_str(&format!("Saved to: {}", path.display()));
+                CallToolResult::text(text)
+            }
+            Err(e) => CallToolResult::error(format!(
+                "Downloaded {} but could not save it in {}: {}",
+                source,
+                dir.display(),
+                e
+            )),
+        }
+    }
+
+    async fn upload_file(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(entity) = get_str(args, "entity") else {
+            return CallToolResult::error("Missing required parameter: entity".to_string());
+        };
+        let Some(path) = get_str(args, "path") else {
+            return CallToolResult::error("Missing required parameter: path".to_string());
+        };
+        let config = self.config();
+        let Some(upload_dir) = config.upload_dir.as_deref() else {
+            return CallToolResult::error(
+                "Uploads are disabled: set UPLOAD_DIR to the directory files may be uploaded from"
+                    .to_string(),
+            );
+        };
+        let attribute = get_str(args, "attribute")
+            .map(str::trim)
+            .filter(|attribute| !attribute.is_empty());
+        let as_note = get_bool(args, "as_note").unwrap_or(false);
+        if as_note == attribute.is_some() {
+            return CallToolResult::error(
+                "Give either attribute (a file column) or as_note=true".to_string(),
+            );
+        }
+
+        let client = self.client();
+        let product = client.product();
+        let key = match parse_record_key(args, product, "") {
+            Ok(key) => key,
+            Err(message) => return CallToolResult::error(message),
+        };
+        let path = match resolve_upload_path(Path::new(upload_dir), path) {
+            Ok(path) => path,
+            Err(message) => return CallToolResult::error(message),
+        };
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                return CallToolResult::error(format!("Cannot read {}: {}", path.display(), e))
+            }
+        };
+        let file_name = get_str(args, "file_name")
+            .and_then(safe_file_name)
+            .or_else(|| {
+                path.file_name()
+                    .and_then(|name| safe_file_name(&name.to_string_lossy()))
+            })
+            .unwrap_or_else(|| "upload.bin".to_string());
+        let record = format!("{}({})", entity, key.expression(product, None));
+
+        match attribute {
+            Some(attribute) => match self
+                .client()
+                .upload_file(entity, &key, attribute, &bytes, &file_name)
+                .await
+            {
+                Ok(()) => CallToolResult::text(format!(
+                    "Uploaded '{}' ({} bytes) to {}/{}",
+                    file_name,
+                    bytes.len(),
+                    record,
+                    attribute
+                )),
+                Err(e) => CallToolResult::error(format!(
+                    "Error uploading to {}/{}: {}",
+                    record, attribute, e
+                )),
+            },
+            None => match self
+                .client()
+                .create_annotation(
+                    entity,
+                    &key,
+                    &file_name,
+                    mime_type(&file_name),
+                    &bytes,
+                    get_str(args, "subject"),
+                )
+                .await
+            {
+                Ok(id) => CallToolResult::text(format!(
+                    "Attached '{}' ({} bytes) to {} as a note{}",
+                    file_name,
+                    bytes.len(),
+                    record,
+                    id.map(|id| format!(" (annotationid {})", id))
+                        .unwrap_or_default()
+                )),
+                Err(e) => {
+                    CallToolResult::error(format!("Error attaching a note to {}: {}", record, e))
+                }
+            },
+        }
+    }
+
+    async fn export_entity(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(entity) = get_str(args, "entity") else {
+            return CallToolResult::error("Missing required parameter: entity".to_string());
+        };
+        let config = self.config();
+        let Some(export_dir) = config.export_dir.as_deref() else {
+            return CallToolResult::error(
+                "Exports are disabled: set EXPORT_DIR to the directory query results may be written to"
+                    .to_string(),
+            );
+        };
+        let export_dir = Path::new(export_dir);
+        let format = match get_str(args, "format")
+            .unwrap_or("csv")
+            .parse::<ExportFormat>()
+        {
+            Ok(format) => format,
+            Err(message) => return CallToolResult::error(message),
+        };
+        let mut options = match self.entity_query_options(entity, args) {
+            Ok((options, _)) => options,
+            Err(message) => return CallToolResult::error(message),
+        };
+        // `top` bounds the whole export, not each page
+        options.top = None;
+        let max_records = get_usize(args, "top");
+
+        let client = self.client();
+        let resolve_labels = get_bool(args, "resolve_labels").unwrap_or(true);
+        let fold_labels = resolve_labels && *client.product() == ProductType::Dataverse;
+        let annotations = self.config().annotations;
+        // Pages are written as they arrive, so F&O enum labels need the metadata up front
+        let enum_labels = match client.product() {
+            ProductType::Finops if resolve_labels => match client.metadata_model().await {
+                Ok(model) => Some((model.enum_properties(entity), model)),
+                Err(e) => {
+                    tracing::warn!("Metadata unavailable for enum labels: {}", e);
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        let select = options.select.as_deref();
+        let created = match get_str(args, "path") {
+            Some(path) => resolve_export_path(export_dir, path).and_then(|path| {
+                ExportWriter::create(&path, format, select)
+                    .map(|writer| (path.clone(), writer))
+                    .map_err(|e| format!("Cannot create {}: {}", path.display(), e))
+            }),
+            None => {
+                let stem = safe_file_name(entity).unwrap_or_else(|| "export".to_string());
+                create_unique(export_dir, &stem, format, select).map_err(|e| {
+                    format!("Cannot create an export in {}: {}", export_dir.display(), e)
+                })
+            }
+        };
+        let (path, mut writer) = match created {
+            Ok(created) => created,
+            Err(message) => return CallToolResult::error(message),
+        };
+
+        let started = Instant::now();
+        let mut write_error = None;
+        let fetched = client
+            .fetch_pages_streaming(entity, &options, max_records, |mut page| {
+                if fold_labels {
+                    page.iter_mut()
+                        .for_each(|record| fold_annotations(record, annotations));
+                } else if let Some((properties, model)) = &enum_labels {
+                    apply_enum_labels(&mut page, properties, &model.enums);
+                }
+                match writer.write_page(&page) {
+                    Ok(()) => ControlFlow::Continue(()),
+                    Err(e) => {
+                        write_error = Some(e);
+                        ControlFlow::Break(())
+                    }
+                }
+            })
+            .await;
+        let finished = match (fetched, write_error) {
+            (Err(e), _) => Err(format!("Error exporting {}: {}", entity, e)),
+            (Ok(_), Some(e)) => Err(format!("Error writing {}: {}", path.display(), e)),
+            (Ok(summary), None) => writer
+                .finish()
+                .map(|written| (summary, written))
+                .map_err(|e| format!("Error writing {}: {}", path.display(), e)),
+        };
+        let (summary, (rows, bytes)) = match finished {
+            Ok(finished) => finished,
+            Err(message) => {
+                // A half-written file would pass for a complete export
+                let _ = std::fs::remove_file(&path);
+                return CallToolResult::error(message);
+            }
+        };
+
+        let mut text = format!(
+            "Exported {} records from {} to {}\n\
+             Format: {}, {} bytes, {:.1} seconds\n",
+            rows,
+            entity,
+            path.display(),
+            format.extension(),
+            bytes,
+            started.elapsed().as_secs_f64()
+        );
+        if summary.truncated {
+            text.push_str(&format!(
+                "Stopped at top={}; more records match the query\n",
+                rows
+            ));
+        }
+        if summary.timed_out {
+            text.push_str(&partial_results_notice(rows));
+        }
+        CallToolResult::text(text.trim_end().to_string())
+    }
+
+    async fn upsert_record(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(entity) = get_str(args, "entity") else {
+            return CallToolResult::error("Missing required parameter: entity".to_string());
+        };
+        let Some(data) = args.get("data").filter(|data| data.is_object()) else {
+            return CallToolResult::error(
+                "Missing required parameter: data (an object)".to_string(),
+            );
+        };
+
+        // key_field/key_value keep the JSON type of the value, so an external
+        // id such as "00042" stays a string
+        let key = match (get_str(args, "key_field"), args.get("key_value")) {
+            (Some(field), Some(value)) if !value.is_null() => Ok(EntityKey::Composite(vec![(
+                field.trim().to_string(),
+                value.clone(),
+            )])),
+            (Some(_), _) | (None, Some(Value::String(_) | Value::Number(_))) => {
+                Err("key_field and key_value must be given together".to_string())
+            }
+            _ => parse_record_key(args, self.client().product(), ""),
+        };
+        let key = match key {
+            Ok(key) => key,
+            Err(message) => return CallToolResult::error(message),
+        };
+        let data = match bind_lookups(data, self.client().product(), self.client().endpoint()) {
+            Ok(data) => data,
+            Err(message) => return CallToolResult::error(message),
+        };
+        let (key, data) = match self.company(args) {
+            Ok(Some(company)) => (company_key(key, &company), company_payload(&data, &company)),
+            Ok(None) => (key, data),
+            Err(message) => return CallToolResult::error(message),
+        };
+
+        let prevent_update = get_bool(args, "prevent_update").unwrap_or(false);
+        let prevent_create = get_bool(args, "prevent_create").unwrap_or(false);
+        let target = format!(
+            "{}({})",
+            entity,
+            key.expression(self.client().product(), None)
+        );
+
+        match self
+            .client()
+            .upsert_entity(entity, &key, &data, prevent_update, prevent_create)
+            .await
+        {
+            Ok(UpsertOutcome::Created) => {
+                CallToolResult::text(format!("Created new record {}", target))
+            }
+            Ok(UpsertOutcome::Updated) => {
+                CallToolResult::text(format!("Updated existing record {}", target))
+            }
+            Err(e) => CallToolResult::error(format!("Error upserting {}: {}", target, e)),
+        }
+    }
+
+    async fn bulk_update(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(entity) = get_str(args, "entity") else {
+            return CallToolResult::error("Missing required parameter: entity".to_string());
+        };
+        let Some(filter) = get_str(args, "filter").filter(|f| !f.trim().is_empty()) else {
+            return CallToolResult::error(
+                "Missing required parameter: filter; bulk_update never updates a whole entity set"
+                    .to_string(),
+            );
+        };
+        if let Err(message) = validate_filter(filter) {
+            return CallToolResult::error(format!("Invalid filter: {}", message));
+        }
+        let Some(data) = args.get("data").filter(|data| data.is_object()) else {
+            return CallToolResult::error(
+                "Missing required parameter: data (an object)".to_string(),
+            );
+        };
+        let Some(max_records) = get_usize(args, "max_records").filter(|max| *max > 0) else {
+            return CallToolResult::error(
+                "Missing required parameter: max_records (the most records the update may touch)"
+                    .to_string(),
+            );
+        };
+        let dry_run = is_dry_run();
+        if !dry_run && !get_bool(args, "confirm").unwrap_or(false) {
+            return CallToolResult::error(
+                "Bulk update not executed. Confirm the filter and change with the user, then call again with confirm=true (or dry_run=true to count the records first)".to_string(),
+            );
+        }
+        let chunk_size = get_usize(args, "chunk_size")
+            .unwrap_or(DEFAULT_BULK_CHUNK)
+            .clamp(1, MAX_BULK_CHUNK as usize);
+
+        let client = self.client();
+        let data = match bind_lookups(data, client.product(), client.endpoint()) {
+            Ok(data) => data,
+            Err(message) => return CallToolResult::error(message),
+        };
+        let mut options = QueryOptions {
+            filter: Some(filter.to_string()),
+            ..Default::default()
+        };
+        let data = match self.company(args) {
+            Ok(Some(company)) => {
+                options.filter = Some(company_filter(options.filter, &company));
+                company_payload(&data, &company)
+            }
+            Ok(None) => data,
+            Err(message) => return CallToolResult::error(message),
+        };
+
+        let model = match client.metadata_model().await {
+            Ok(model) => model,
+            Err(e) => {
+                return CallToolResult::error(format!(
+                    "bulk_update needs the key fields of {} from $metadata: {}",
+                    entity, e
+                ))
+            }
+        };
+        let Some(entity_type) = model.entity_type(entity) else {
+            return CallToolResult::error(format!(
+                "Entity set '{}' not found in $metadata",
+                entity
+            ));
+        };
+        if entity_type.keys.is_empty() {
+            return CallToolResult::error(format!(
+                "{} declares no key fields, so its records cannot be addressed",
+                entity
+            ));
+        }
+        options.select = Some(entity_type.keys.clone());
+
+        // The keys are read even in a dry run, which reports how many would change
+        let mut records = Vec::new();
+        let read = without_dry_run(client.fetch_pages_streaming(
+            entity,
+            &options,
+            Some(max_records),
+            |page| {
+                records.extend(page);
+                ControlFlow::Continue(())
+            },
+        ))
+        .await;
+        match read {
+            Ok(summary) if summary.timed_out => {
+                return CallToolResult::error(format!(
+                    "Timed out reading the matching {} records; nothing was changed",
+                    entity
+                ))
+            }
+            Ok(summary) if summary.truncated => {
+                return CallToolResult::error(format!(
+                    "More than {} {} records match the filter; nothing was changed. Narrow the filter or raise max_records",
+                    max_records, entity
+                ))
+            }
+            Ok(_) => {}
+            Err(e) => {
+                return CallToolResult::error(format!(
+                    "Error reading the matching {} records: {}",
+                    entity, e
+                ))
+            }
+        }
+        if records.is_empty() {
+            return CallToolResult::text(format!(
+                "No {} records match the filter; nothing was changed",
+                entity
+            ));
+        }
+
+        let keys: Vec<EntityKey> = records
+            .iter()
+            .map(|record| {
+                EntityKey::Composite(
+                    entity_type
+                        .keys
+                        .iter()
+                        .map(|field| {
+                            (
+                                field.clone(),
+                                record.get(field).cloned().unwrap_or(Value::Null),
+                            )
+                        })
+                        .collect(),
+                )
+            })
+            .collect();
+        let chunks = keys.len().div_ceil(chunk_size);
+        let fields: Vec<&String> = data
+            .as_object()
+            .map(|data| data.keys().collect())
+            .unwrap_or_default();
+        if dry_run {
+            return CallToolResult::text(format!(
+                "Dry run: nothing was sent to the service.\n{} {} records match and would get {} in {} changeset(s) of up to {} records.",
+                keys.len(),
+                entity,
+                fields.iter().map(|field| field.as_str()).collect::<Vec<_>>().join(", "),
+                chunks,
+                chunk_size
+            ));
+        }
+
+        let id_of = |key: &EntityKey| key.expression(client.product(), Some(entity_type));
+        let mut chunk_reports = Vec::new();
+        let mut failures = Vec::new();
+        let mut updated = 0;
+        let mut stopped = None;
+        for (index, chunk) in keys.chunks(chunk_size).enumerate() {
+            match client.update_changeset(entity, chunk, &data).await {
+                Ok(()) => {
+                    updated += chunk.len();
+                    chunk_reports.push(serde_json::json!({"chunk": index + 1, "succeeded": chunk.len(), "failed": 0}));
+                }
+                Err(ChangesetError { failed, error }) => {
+                    chunk_reports.push(serde_json::json!({"chunk": index + 1, "succeeded": 0, "failed": chunk.len()}));
+                    let rejected = failed.map(|position| id_of(&chunk[position]));
+                    for (position, key) in chunk.iter().enumerate() {
+                        let message = match &rejected {
+                            Some(rejected) if failed != Some(position) => {
+                                format!("rolled back with the changeset after {} failed", rejected)
+                            }
+                            _ => error.to_string(),
+                        };
+                        failures.push(serde_json::json!({"id": id_of(key), "error": message}));
+                    }
+                    // Later changesets would fail the same way
+                    if matches!(error, ODataError::Timeout(_) | ODataError::Cancelled) {
+                        stopped = Some(error);
+                        break;
+                    }
+                }
+            }
+        }
+
+        let mut text = format!(
+            "Bulk update of {}: {} of {} matching records updated in {} changeset(s).",
+            entity,
+            updated,
+            keys.len(),
+            chunk_reports.len()
+        );
+        for report in &chunk_reports {
+            text.push_str(&format!(
+                "\n- Changeset {}: {} succeeded, {} failed",
+                report["chunk"], report["succeeded"], report["failed"]
+            ));
+        }
+        let attempted = updated + failures.len();
+        if let Some(error) = &stopped {
+            text.push_str(&format!(
+                "\nStopped: {}. {} records were not attempted.",
+                error,
+                keys.len() - attempted
+            ));
+        }
+        if !failures.is_empty() {
+            text.push_str("\nFailed records:");
+            for failure in &failures {
+                text.push_str(&format!(
+                    "\n- {}: {}",
+                    failure["id"].as_str().unwrap_or_default(),
+                    failure["error"].as_str().unwrap_or_default()
+                ));
+            }
+        }
+        let structured = serde_json::json!({
+            "entity": entity,
+            "matched": keys.len(),
+            "updated": updated,
+            "failed": failures.len(),
+            "not_attempted": keys.len() - attempted,
+            "chunks": chunk_reports,
+            "failures": failures,
+        });
+        let mut result = CallToolResult::structured(text, structured);
+        if updated == 0 {
+            result.is_error = Some(true);
+        }
+        result
+    }
+
+    async fn associate_records(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let link = match self.parse_link(args, true) {
+            Ok(link) => link,
+            Err(message) => return CallToolResult::error(message),
+        };
+        let Some((target_entity, target_key)) = &link.target else {
+            return CallToolResult::error("Missing required parameter: target_entity".to_string());
+        };
+
+        match self
+            .client()
+            .associate(
+                link.entity,
+                &link.key,
+                link.navigation,
+                target_entity,
+                target_key,
+            )
+            .await
+        {
+            Ok(()) => CallToolResult::text(format!(
+                "Linked {}({}) to {}({}) through '{}'",
+                link.entity,
+                link.key.expression(self.client().product(), None),
+                target_entity,
+                target_key.expression(self.client().product(), None),
+                link.navigation
+            )),
+            Err(e) => CallToolResult::error(format!("Error linking records: {}", e)),
+        }
+    }
+
+    async fn disassociate_records(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let link = match self.parse_link(args, false) {
+            Ok(link) => link,
+            Err(message) => return CallToolResult::error(message),
+        };
+        let target = link
+            .target
+            .as_ref()
+            .map(|(target_entity, target_key)| (*target_entity, target_key));
+
+        match self
+            .client()
+            .disassociate(link.entity, &link.key, link.navigation, target)
+            .await
+        {
+            Ok(()) => CallToolResult::text(format!(
+                "Removed the '{}' link from {}({})",
+                link.navigation,
+                link.entity,
+                link.key.expression(self.client().product(), None)
+            )),
+            Err(e) => CallToolResult::error(format!("Error unlinking records: {}", e)),
+        }
+    }
+
+    /// F&O legal entity the call targets: the `company` argument, else the
+    /// configured default. Always `None` for Dataverse and cross-company queries.
+    fn company(&self, args: &HashMap<String, Value>) -> Result<Option<String>, String> {
+        let company = get_str(args, "company")
+            .map(str::trim)
+            .filter(|company| !company.is_empty());
+        let cross_company = get_bool(args, "cross_company").unwrap_or(false);
+        if company.is_some() && cross_company {
+            return Err("company cannot be combined with cross_company=true".to_string());
+        }
+        if *self.client().product() != ProductType::Finops || cross_company {
+            return Ok(None);
+        }
+        Ok(company
+            .map(String::from)
+            .or_else(|| self.config().default_company.clone()))
+    }
+
+    /// Source record, navigation property and (optional) target of a link tool call
+    fn parse_link<'a>(
+        &self,
+        args: &'a HashMap<String, Value>,
+        target_required: bool,
+    ) -> Result<RecordLink<'a>, String> {
+        let entity = get_str(args, "entity")
+            .ok_or_else(|| "Missing required parameter: entity".to_string())?;
+        let navigation = get_str(args, "navigation_property")
+            .ok_or_else(|| "Missing required parameter: navigation_property".to_string())?;
+        let client = self.client();
+        let product = client.product();
+        let key = parse_record_key(args, product, "")?;
+
+        let target = match get_str(args, "target_entity") {
+            Some(target_entity) => {
+                Some((target_entity, parse_record_key(args, product, "target_")?))
+            }
+            None if target_required => {
+                return Err("Missing required parameter: target_entity".to_string())
+            }
+            None => None,
+        };
+
+        Ok(RecordLink {
+            entity,
+            key,
+            navigation,
+            target,
+        })
+    }
+
+    async fn delete_record(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let entity = match get_str(args, "entity") {
+            Some(e) => e,
+            None => return CallToolResult::error("Missing required parameter: entity".to_string()),
+        };
+
+        let confirm = get_str(args, "confirm").unwrap_or("");
+        if confirm != "DELETE" {
+            return CallToolResult::error(
+                "Deletion not executed. Set confirm to exactly 'DELETE'.".to_string(),
+            );
+        }
+
+        let key = match parse_delete_key(args, self.client().product()) {
+            Ok(key) => key,
+            Err(message) => return CallToolResult::error(message),
+        };
+        let key = match self.company(args) {
+            Ok(Some(company)) => company_key_expression(&key, &company),
+            Ok(None) => key,
+            Err(message) => return CallToolResult::error(message),
+        };
+
+        let if_match = get_str(args, "if_match");
+
+        match self
+            .client()
+            .delete_entity(entity, &EntityKey::Single(key.clone()), if_match)
+            .await
+        {
+            Ok(()) => CallToolResult::text(format!(
+                "Deleted record from entity '{}' with key ({})",
+                entity, key
+            )),
+            Err(e) => CallToolResult::error(format!("Error deleting {}({}): {}", entity, key, e)),
+        }
+    }
+
+    async fn get_environment_info(&self) -> CallToolResult {
+        let limiter = self.client().rate_limiter_stats();
+        let requests = self.client().request_stats();
+        let info = format!(
+            "D365 Environment Info:\n\
+             - Environment: {}\n\
+             - Endpoint: {}\n\
+             - Product: {:?}\n\
+             - Page Size: {}\n\
+             - Read-only: {}\n\
+             - Entity Policy: {}\n\
+             - Configured Entities: {}\n\
+             - Client Rate Limit: {} ({} requests in last minute, {} waiting)\n\
+             - Circuit Breaker: {}\n\
+             - Throttling: {}\n\
+             - Requests: {} sent, {} retries, {} throttled (429), avg {} ms\n\
+             - Impersonation: {}\n\
+             - Language: {}",
+            match &self.config().environment {
+                Some(name) if self.config().production => format!("{} (production)", name),
+                Some(name) => name.clone(),
+                None => "default".to_string(),
+            },
+            self.client().endpoint(),
+            self.client().product(),
+            self.config().page_size,
+            self.config().read_only,
+            format_entity_policy(&self.config()),
+            self.config()
+                .entities
+                .iter()
+                .map(|e| e.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            format_rate_limits(&limiter),
+            limiter.requests_last_minute,
+            limiter.waiting,
+            format_circuit(&self.client().circuit_stats()),
+            format_backpressure(&self.client().backpressure_stats()),
+            requests.total_requests,
+            requests.retries,
+            requests.throttled,
+            requests.average_latency_ms,
+            match self.client().impersonation() {
+                Some((header, user_id)) => format!("{} {}", header, user_id),
+                None => "off".to_string(),
+            },
+            self.client()
+                .language()
+                .unwrap_or_else(|| "service default".to_string())
+        );
+        CallToolResult::text(info)
+    }
+
+    async fn dmf_import_package(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(path) = get_str(args, "path") else {
+            return CallToolResult::error("Missing required parameter: path".to_string());
+        };
+        let Some(definition_group) = get_str(args, "definition_group")
+            .map(str::trim)
+            .filter(|group| !group.is_empty())
+        else {
+            return CallToolResult::error(
+                "Missing required parameter: definition_group".to_string(),
+            );
+        };
+        let config = self.config();
+        let Some(upload_dir) = config.upload_dir.as_deref() else {
+            return CallToolResult::error(
+                "Package imports are disabled: set UPLOAD_DIR to the directory packages may be read from"
+                    .to_string(),
+            );
+        };
+        let Some(company) = get_str(args, "company")
+            .or(config.default_company.as_deref())
+            .map(str::trim)
+            .filter(|company| !company.is_empty())
+        else {
+            return CallToolResult::error(
+                "company is required: the legal entity (dataAreaId) to import into, or set DEFAULT_COMPANY"
+                    .to_string(),
+            );
+        };
+        let path = match resolve_upload_path(Path::new(upload_dir), path) {
+            Ok(path) => path,
+            Err(message) => return CallToolResult::error(message),
+        };
+        let is_zip = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"));
+        if !is_zip {
+            return CallToolResult::error(format!(
+                "{} is not a data package: packages are .zip files exported from a data project",
+                path.display()
+            ));
+        }
+        let package = match std::fs::read(&path) {
+            Ok(package) => package,
+            Err(e) => {
+                return CallToolResult::error(format!("Cannot read {}: {}", path.display(), e))
+            }
+        };
+        let file_name = path
+            .file_name()
+            .and_then(|name| safe_file_name(&name.to_string_lossy()))
+            .unwrap_or_else(|| "package.zip".to_string());
+
+        // During a dry run each step is only recorded, so go on to the next
+        // one to show all three requests
+        let client = self.client();
+        let unique_name = format!("{}-{}", new_client_request_id(), file_name);
+        let blob_url = match client.dmf_write_url(&unique_name).await {
+            Ok(write_url) => write_url.blob_url,
+            Err(ODataError::DryRun) => "<BlobUrl returned by GetAzureWriteUrl>".to_string(),
+            Err(e) => {
+                return CallToolResult::error(format!(
+                    "Error getting an upload URL for the package: {}",
+                    e
+                ))
+            }
+        };
+        match client.upload_package(&blob_url, &package).await {
+            Ok(()) | Err(ODataError::DryRun) => {}
+            Err(e) => {
+                return CallToolResult::error(format!(
+                    "Error uploading {} to blob storage: {}",
+                    file_name, e
+                ))
+            }
+        }
+
+        let execute = get_bool(args, "execute").unwrap_or(true);
+        let import = PackageImport {
+            package_url: &blob_url,
+            definition_group,
+            execution_id: get_str(args, "execution_id").map_or("", str::trim),
+            execute,
+            overwrite: get_bool(args, "overwrite").unwrap_or(true),
+            legal_entity: company,
+        };
+        match client.import_from_package(&import).await {
+            Ok(execution_id) => CallToolResult::text(format!(
+                "{} package '{}' ({} bytes) into data project '{}' for {}.\nExecution id: {}\nCheck it with dmf_get_execution_status execution_id={}",
+                if execute { "Importing" } else { "Staging" },
+                file_name,
+                package.len(),
+                definition_group,
+                company,
+                execution_id,
+                execution_id
+            )),
+            Err(ODataError::DryRun) => CallToolResult::text(String::new()),
+            Err(e) => CallToolResult::error(format!(
+                "Error importing {} into data project '{}': {}",
+                file_name, definition_group, e
+            )),
+        }
+    }
+
+    async fn dmf_get_execution_status(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(execution_id) = get_str(args, "execution_id")
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+        else {
+            return CallToolResult::error("Missing required parameter: execution_id".to_string());
+        };
+        let wait = Duration::from_secs(get_usize(args, "wait_secs").unwrap_or(0) as u64);
+
+        let client = self.client();
+        let status = match client
+            .wait_for_execution(execution_id, wait, DMF_POLL_INTERVAL)
+            .await
+        {
+            Ok(status) => status,
+            Err(e) => {
+                return CallToolResult::error(format!(
+                    "Error reading the status of execution {}: {}",
+                    execution_id, e
+                ))
+            }
+        };
+
+        let mut text = format!("Execution {}: {}", execution_id, status);
+        if !status.is_finished() {
+            text.push_str("\nNot finished yet; call again (with wait_secs to wait for it)");
+        }
+        if status.has_errors() {
+            let entity_name = get_str(args, "entity_name")
+                .map(str::trim)
+                .filter(|name| !name.is_empty());
+            match entity_name {
+                Some(entity_name) => {
+                    match client.staging_error_file_url(execution_id, entity_name).await {
+                        Ok(Some(url)) => text.push_str(&format!(
+                            "\nStaging error log of {}: {}",
+                            entity_name, url
+                        )),
+                        Ok(None) => text.push_str(&format!(
+                            "\nNo staging error log for {} in this execution",
+                            entity_name
+                        )),
+                        Err(e) => text.push_str(&format!(
+                            "\nCould not get the staging error log of {}: {}",
+                            entity_name, e
+                        )),
+                    }
+                }
+                None => text.push_str(
+                    "\nPass entity_name (the data entity as named in the data project) to get its staging error log URL",
+                ),
+            }
+        }
+        CallToolResult::text(text)
+    }
+
+    async fn execute_action(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(action) = get_str(args, "action")
+            .map(|action| action.trim().trim_start_matches('/'))
+            .filter(|action| !action.is_empty())
+        else {
+            return CallToolResult::error("Missing required parameter: action".to_string());
+        };
+        let parameters = match args.get("parameters") {
+            None | Some(Value::Null) => Value::Object(Default::default()),
+            Some(parameters) if parameters.is_object() => parameters.clone(),
+            Some(_) => {
+                return CallToolResult::error("parameters must be a JSON object".to_string())
+            }
+        };
+        if let Err(message) = self.check_action_target(action).await {
+            return CallToolResult::error(message);
+        }
+
+        let client = self.client();
+        let pending = match client.start_action(action, &parameters).await {
+            Ok(ActionOutcome::Completed(Value::Null)) => {
+                return CallToolResult::text(format!("Action {} completed", action))
+            }
+            Ok(ActionOutcome::Completed(result)) => {
+                return CallToolResult::text(format!(
+                    "Action {} completed:\n{}",
+                    action,
+                    serde_json::to_string_pretty(&result).unwrap_or_default()
+                ))
+            }
+            Ok(ActionOutcome::Pending(pending)) => pending,
+            Err(e) => return CallToolResult::error(format!("Error executing {}: {}", action, e)),
+        };
+
+        let accepted = format!(
+            "Action {} was accepted and runs in the background.\nMonitor URL: {}",
+            action, pending.monitor_url
+        );
+        if !get_bool(args, "wait").unwrap_or(true) {
+            return CallToolResult::text(format!(
+                "{}\nCheck it with check_operation monitor_url={}",
+                accepted, pending.monitor_url
+            ));
+        }
+        let wait = Duration::from_secs(
+            get_usize(args, "max_wait_secs").unwrap_or(DEFAULT_OPERATION_WAIT_SECS) as u64,
+        );
+        match client.wait_for_operation(&pending.monitor_url, wait).await {
+            Ok(status) => operation_result(&accepted, &pending.monitor_url, &status),
+            Err(e) => {
+                CallToolResult::error(format!("{}\nError reading its status: {}", accepted, e))
+            }
+        }
+    }
+
+    /// Refuse an `action` that is really an entity set, which would create a
+    /// record, and bound actions on sets the entity policy denies
+    async fn check_action_target(&self, action: &str) -> Result<(), String> {
+        let bound = action_binding(action)?;
+        let model = self
+            .client()
+            .metadata_model()
+            .await
+            .map_err(|e| format!("Error fetching metadata: {}", e))?;
+        match bound {
+            Some(set) => {
+                let set = model.resolve_entity_set(&set).unwrap_or(&set);
+                self.entity_policy().check(set)
+            }
+            None if model
+                .entity_sets
+                .iter()
+                .any(|(set, _)| set.eq_ignore_ascii_case(action)) =>
+            {
+                Err(format!(
+                    "'{}' is an entity set, not an action; use create_record to create records",
+                    action
+                ))
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn check_operation(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(monitor_url) = get_str(args, "monitor_url")
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+        else {
+            return CallToolResult::error("Missing required parameter: monitor_url".to_string());
+        };
+        // The bearer token is sent along, so only to the service itself
+        let client = self.client();
+        let on_service = match (Url::parse(monitor_url), Url::parse(client.endpoint())) {
+            (Ok(monitor), Ok(endpoint)) => same_origin(&monitor, &endpoint),
+            _ => false,
+        };
+        if !on_service {
+            return CallToolResult::error(format!(
+                "monitor_url must be a URL on {}, as returned by execute_action",
+                client.endpoint()
+            ));
+        }
+
+        let wait = Duration::from_secs(get_usize(args, "wait_secs").unwrap_or(0) as u64);
+        match client.wait_for_operation(monitor_url, wait).await {
+            Ok(status) => operation_result("", monitor_url, &status),
+            Err(e) => CallToolResult::error(format!(
+                "Error reading the status of {}: {}",
+                monitor_url, e
+            )),
+        }
+    }
+
+    fn query_history(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let history = self.history();
+        if !history.is_enabled() {
+            return CallToolResult::text(
+                "The query history is off; set HISTORY_SIZE above 0 to keep one".to_string(),
+            );
+        }
+        let mut entries = history.entries();
+        if let Some(tool) = get_str(args, "tool").map(str::trim) {
+            entries.retain(|entry| entry.tool == tool);
+        }
+        let limit = get_usize(args, "limit").unwrap_or(entries.len());
+        let entries = &entries[entries.len().saturating_sub(limit)..];
+        if entries.is_empty() {
+            return CallToolResult::text("No tool calls recorded yet".to_string());
+        }
+
+        let mut text = String::from(
+            "Tool calls this session, oldest first (run one again with replay_query):\n",
+        );
+        for entry in entries {
+            text.push_str(&entry.summary());
+            text.push('\n');
+        }
+        CallToolResult::text(text.trim_end().to_string())
+    }
+
+    fn list_environments(&self) -> CallToolResult {
+        let config = self.config();
+        if config.environments.is_empty() {
+            return CallToolResult::text(
+                "No named environments are configured; add [environments.<name>] sections to the config file"
+                    .to_string(),
+            );
+        }
+
+        let mut text = String::from("Environments:\n");
+        for environment in &config.environments {
+            let mut marks = Vec::new();
+            if config.environment.as_deref() == Some(environment.name.as_str()) {
+                marks.push("active");
+            }
+            if environment.production {
+                marks.push("production");
+            }
+            let marks = if marks.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", marks.join(", "))
+            };
+            text.push_str(&format!(
+                "- {}{}: {:?} {}\n",
+                environment.name, marks, environment.product, environment.endpoint
+            ));
+        }
+        CallToolResult::text(text.trim_end().to_string())
+    }
+
+    async fn switch_environment(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(name) = get_str(args, "name")
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        else {
+            return CallToolResult::error("Missing required parameter: name".to_string());
+        };
+        let config = self.config();
+        let Some(target) = config.environments.iter().find(|e| e.name == name) else {
+            return CallToolResult::error(unknown_environment(&config, name));
+        };
+        if config.environment.as_deref() == Some(name) {
+            return CallToolResult::text(format!(
+                "Already using environment '{}' ({})",
+                name, target.endpoint
+            ));
+        }
+        if target.production && !get_bool(args, "confirm").unwrap_or(false) {
+            return CallToolResult::error(format!(
+                "'{}' is a production environment ({}). Confirm with the user, then call switch_environment again with confirm=true",
+                name, target.endpoint
+            ));
+        }
+
+        let environment = match self.environment_named(name) {
+            Ok(environment) => environment,
+            Err(message) => return CallToolResult::error(message),
+        };
+        *self.active.write().unwrap() = environment.clone();
+
+        CallToolResult::text(format!(
+            "Switched to environment '{}': {:?} {} (read-only: {})",
+            name,
+            environment.config.product,
+            environment.config.endpoint,
+            environment.config.read_only
+        ))
+    }
+}
+
+impl D365McpServer {
+    /// Entity for the sample query: the first configured one the policy allows
+    fn probe_entity(&self) -> Option<String> {
+        self.config()
+            .entities
+            .iter()
+            .map(|entity| entity.name.as_str())
+            .find(|name| self.entity_policy().is_allowed(name))
+            .map(String::from)
+    }
+
+    /// The `test_connection` checks, detached from the server so they can run in the background
+    pub fn connection_check(&self) -> impl Future<Output = ConnectionReport> + Send + 'static {
+        let client = self.client();
+        let probe = self.probe_entity();
+        let policy = self.entity_policy();
+        async move {
+            client
+                .check_connection(
+                    probe.as_deref(),
+                    |set| policy.is_allowed(set),
+                    DEFAULT_STAGE_TIMEOUT,
+                )
+                .await
+        }
+    }
+
+    async fn test_connection(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let probe = get_str(args, "entity")
+            .map(String::from)
+            .or_else(|| self.probe_entity());
+        let timeout = get_usize(args, "timeout_secs")
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(DEFAULT_STAGE_TIMEOUT);
+        let reset = get_bool(args, "reset_circuit").unwrap_or(false);
+        if reset {
+            self.client().reset_circuit();
+        }
+
+        let policy = self.entity_policy();
+        let report = self
+            .client()
+            .check_connection(probe.as_deref(), |set| policy.is_allowed(set), timeout)
+            .await;
+        let verdict = if report.passed() {
+            "Connection OK"
+        } else {
+            "Connection check failed"
+        };
+        let mut text = format!("{} ({})\n\n{}", verdict, self.client().endpoint(), report);
+        if reset {
+            text.push_str(&format!(
+                "\n\nCircuit breaker reset; now {}",
+                format_circuit(&self.client().circuit_stats())
+            ));
+        }
+        CallToolResult::text(text)
+    }
+
+    async fn describe_relationships(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(entity) = get_str(args, "entity") else {
+            return CallToolResult::error("Missing required parameter: entity".to_string());
+        };
+
+        let model = match self.client().metadata_model().await {
+            Ok(model) => model,
+            Err(e) => return CallToolResult::error(format!("Error fetching metadata: {}", e)),
+        };
+        let Some(entity_type) = model.entity_type(entity) else {
+            return CallToolResult::error(format!("Entity '{}' not found in metadata", entity));
+        };
+
+        // Relationships to entities outside the policy are left out
+        let visible = |type_name: &str| {
+            model
+                .entity_set_for_type(type_name)
+                .is_none_or(|set| self.entity_policy().is_allowed(set))
+        };
+
+        let outbound: Vec<&NavigationInfo> = entity_type
+            .navigation
+            .iter()
+            .filter(|navigation| visible(&navigation.target_type))
+            .collect();
+        let mut text = format!(
+            "Relationships of {} (type {})\n\nOutbound ({}), expandable with $expand=<name>:\n",
+            entity,
+            entity_type.name,
+            outbound.len()
+        );
+        for navigation in outbound {
+            text.push_str(&format!(
+                "- {} → {}{}\n",
+                navigation.name,
+                describe_target(&model, &navigation.target_type, navigation.collection),
+                describe_constraints(navigation, &entity_type.name, &navigation.target_type)
+            ));
+        }
+
+        let inbound: Vec<_> = model
+            .inbound_navigation(&entity_type.name)
+            .into_iter()
+            .filter(|(source, _)| visible(&source.name))
+            .collect();
+        text.push_str(&format!(
+            "\nInbound ({}), entities that reference {}:\n",
+            inbound.len(),
+            entity
+        ));
+        for (source, navigation) in inbound {
+            let expand = match model.entity_set_for_type(&source.name) {
+                Some(set) => format!("; expandable from {} with $expand={}", set, navigation.name),
+                None => "; no entity set, not queryable directly".to_string(),
+            };
+            text.push_str(&format!(
+                "- {}.{} ({}){}{}\n",
+                model
+                    .entity_set_for_type(&source.name)
+                    .unwrap_or(&source.name),
+                navigation.name,
+                if navigation.collection {
+                    "many"
+                } else {
+                    "single"
+                },
+                describe_constraints(navigation, &source.name, &entity_type.name),
+                expand
+            ));
+        }
+
+        CallToolResult::text(text)
+    }
+
+    async fn list_optionsets(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let prefix = get_str(args, "prefix").unwrap_or("").to_lowercase();
+
+        let model = match self.client().metadata_model().await {
+            Ok(model) => model,
+            Err(e) => return CallToolResult::error(format!("Error fetching metadata: {}", e)),
+        };
+        let mut names: Vec<String> = model.enums.iter().map(|info| info.name.clone()).collect();
+
+        // Dataverse choices live in the metadata API, not in the EDMX
+        let mut note = String::new();
+        if *self.client().product() == ProductType::Dataverse {
+            match self.client().fetch_global_option_set_names().await {
+                Ok(global) => names.extend(global),
+                Err(e) => {
+                    note = format!("\n(Global option sets unavailable: {})\n", e);
+                }
+            }
+        }
+
+        names.retain(|name| name.to_lowercase().starts_with(&prefix));
+        names.sort_by_key(|name| name.to_lowercase());
+        names.dedup_by(|a, b| a.eq_ignore_ascii_case(b));
+
+        let mut text = if prefix.is_empty() {
+            format!("{} option sets:\n", names.len())
+        } else {
+            format!("{} option sets starting with '{}':\n", names.len(), prefix)
+        };
+        for name in &names {
+            text.push_str(&format!("- {}\n", name));
+        }
+        text.push_str(&note);
+        CallToolResult::text(text)
+    }
+
+    async fn get_optionset(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let Some(name) = get_str(args, "name") else {
+            return CallToolResult::error("Missing required parameter: name".to_string());
+        };
+
+        let model = match self.client().metadata_model().await {
+            Ok(model) => model,
+            Err(e) => return CallToolResult::error(format!("Error fetching metadata: {}", e)),
+        };
+        if let Some(info) = model
+            .enums
+            .iter()
+            .find(|info| info.name.eq_ignore_ascii_case(name))
+        {
+            return CallToolResult::text(format_enum_type(info));
+        }
+
+        if *self.client().product() == ProductType::Dataverse {
+            return match self.client().fetch_global_option_set(name).await {
+                Ok(info) => CallToolResult::text(format_option_set(&info)),
+                Err(ODataError::NotFound(_)) => CallToolResult::error(format!(
+                    "Option set '{}' not found; use list_optionsets to see the names. \
+                     Local (non-global) choices are defined on their column instead.",
+                    name
+                )),
+                Err(e) => {
+                    CallToolResult::error(format!("Error fetching option set {}: {}", name, e))
+                }
+            };
+        }
+
+        CallToolResult::error(format!(
+            "Enum '{}' not found in metadata; use list_optionsets to see the names",
+            name
+        ))
+    }
+
+    async fn list_companies(&self) -> CallToolResult {
+        if let Some(list) = self.environment().companies.read().await.as_ref() {
+            return CallToolResult::text(format_companies(list, true));
+        }
+
+        match self.fetch_companies().await {
+            Ok(list) => {
+                let text = format_companies(&list, false);
+                if !list.timed_out {
+                    *self.environment().companies.write().await = Some(list);
+                }
+                CallToolResult::text(text)
+            }
+            Err(message) => CallToolResult::error(message),
+        }
+    }
+
+    /// Query the first company entity set this environment exposes, across companies
+    async fn fetch_companies(&self) -> Result<CompanyList, String> {
+        let mut tried = Vec::new();
+
+        for (entity, code_field, name_field) in COMPANY_SOURCES {
+            if !self.entity_policy().is_allowed(entity) {
+                continue;
+            }
+            tried.push(*entity);
+
+            let options = QueryOptions {
+                select: Some(vec![code_field.to_string(), name_field.to_string()]),
+                cross_company: true,
+                ..Default::default()
+            };
+            let mut records = Vec::new();
+            let fetched = self
+                .client()
+                .fetch_pages_streaming(entity, &options, None, |page| {
+                    records.extend(page);
+                    ControlFlow::Continue(())
+                })
+                .await;
+            match fetched {
+                Ok(summary) => {
+                    let field = |record: &Value, name: &str| {
+                        record
+                            .get(name)
+                            .and_then(Value::as_str)
+                            .unwrap_or_default()
+                            .to_string()
+                    };
+                    let mut companies: Vec<(String, String)> = records
+                        .iter()
+                        .map(|record| (field(record, code_field), field(record, name_field)))
+                        .filter(|(code, _)| !code.is_empty())
+                        .collect();
+                    companies.sort_by_key(|(code, _)| code.to_lowercase());
+                    return Ok(CompanyList {
+                        companies,
+                        source: entity,
+                        timed_out: summary.timed_out,
+                    });
+                }
+                // Entity set not exposed here; try the next name
+                Err(ODataError::NotFound(_)) => continue,
+                Err(e) => return Err(format!("Error listing companies from {}: {}", entity, e)),
+            }
+        }
+
+        if tried.is_empty() {
+            Err("No company entity set is allowed by the entity policy".to_string())
+        } else {
+            Err(format!(
+                "No company entity set found (tried {})",
+                tried.join(", ")
+            ))
+        }
+    }
+
+    async fn whoami(&self) -> CallToolResult {
+        match self.client().product() {
+            ProductType::Dataverse => self.whoami_dataverse().await,
+            ProductType::Finops => match self.client().access_token().await {
+                Ok(token) => match decode_jwt_claims(&token) {
+                    Ok(claims) => CallToolResult::text(format_token_claims(&claims)),
+                    Err(e) => CallToolResult::error(format!("Error reading access token: {}", e)),
+                },
+                Err(e) => CallToolResult::error(format!("Error acquiring access token: {}", e)),
+            },
+        }
+    }
+
+    /// `WhoAmI`, then the user and business unit records it points at
+    async fn whoami_dataverse(&self) -> CallToolResult {
+        let who = match self.client().execute_function("WhoAmI", &[]).await {
+            Ok(who) => who,
+            Err(e) => return CallToolResult::error(format!("Error calling WhoAmI: {}", e)),
+        };
+        let id = |name: &str| who.get(name).and_then(Value::as_str);
+        let field = |name: &str| id(name).unwrap_or("unknown");
+
+        let mut text = format!(
+            "Method: Dataverse WhoAmI function\n\
+             - User Id: {}\n\
+             - Business Unit Id: {}\n\
+             - Organization Id: {}\n",
+            field("UserId"),
+            field("BusinessUnitId"),
+            field("OrganizationId")
+        );
+
+        // The lookups are extras; the WhoAmI ids are useful on their own
+        if let Some(user) = self.lookup_record("systemusers", id("UserId")).await {
+            let user_field = |name: &str| user.get(name).and_then(Value::as_str);
+            text.push_str(&format!(
+                "\nUser (systemusers):\n- Name: {}\n- Domain Name: {}\n",
+                user_field("fullname").unwrap_or("unknown"),
+                user_field("domainname").unwrap_or("unknown")
+            ));
+            if let Some(application_id) = user_field("applicationid") {
+                text.push_str(&format!("- Application Id: {}\n", application_id));
+            }
+            if let Some(object_id) = user_field("azureactivedirectoryobjectid") {
+                text.push_str(&format!("- Entra Object Id: {}\n", object_id));
+            }
+        }
+        if let Some(unit) = self
+            .lookup_record("businessunits", id("BusinessUnitId"))
+            .await
+        {
+            if let Some(name) = unit.get("name").and_then(Value::as_str) {
+                text.push_str(&format!("\nBusiness Unit: {}\n", name));
+            }
+        }
+
+        CallToolResult::text(text)
+    }
+
+    /// Fetch a record by GUID when the entity policy allows it; failures are logged and skipped
+    async fn lookup_record(&self, entity: &str, id: Option<&str>) -> Option<Value> {
+        let id = id?;
+        if self.entity_policy().check(entity).is_err() {
+            return None;
+        }
+        match self.client().get_entity(entity, &id.into()).await {
+            Ok(record) => Some(record),
+            Err(e) => {
+                tracing::warn!("whoami: could not read {}({}): {}", entity, id, e);
+                None
+            }
+        }
+    }
+}
+
+/// Target of a navigation property: its entity set (or type) and cardinality
+fn describe_target(model: &MetadataModel, target_type: &str, collection: bool) -> String {
+    let name = match model.entity_set_for_type(target_type) {
+        Some(set) => format!("{} ({})", set, target_type),
+        None => target_type.to_string(),
+    };
+    if collection {
+        format!("[{}], many", name)
+    } else {
+        format!("{}, single", name)
+    }
+}
+
+/// Foreign-key fields of a navigation property, e.g. `; FK SalesOrderHeaderV2.CustAccount = CustomerV3.CustomerAccount`
+fn describe_constraints(navigation: &NavigationInfo, source: &str, target: &str) -> String {
+    if navigation.constraints.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = navigation
+        .constraints
+        .iter()
+        .map(|(property, referenced)| {
+            format!("{}.{} = {}.{}", source, property, target, referenced)
+        })
+        .collect();
+    format!("; FK {}", pairs.join(", "))
+}
+
+/// Members of a `$metadata` enum with their filter literals
+fn format_enum_type(info: &EnumTypeInfo) -> String {
+    let mut text = format!(
+        "Enum {} ({} members, from $metadata)\n\n| Member | Value | Filter literal |\n| --- | --- | --- |\n",
+        info.name,
+        info.members.len()
+    );
+    for (member, value) in &info.members {
+        text.push_str(&format!(
+            "| {} | {} | `{}` |\n",
+            member,
+            value,
+            info.literal(member)
+        ));
+    }
+    text
+}
+
+/// Members of a Dataverse global option set
+fn format_option_set(info: &EnumTypeInfo) -> String {
+    let mut text = format!(
+        "Option set {} ({} options, from GlobalOptionSetDefinitions)\n\n| Label | Value |\n| --- | --- |\n",
+        info.name,
+        info.members.len()
+    );
+    for (label, value) in &info.members {
+        text.push_str(&format!("| {} | {} |\n", label, value));
+    }
+    text.push_str("\nFilter choice columns by value, e.g. `column eq 1`.\n");
+    text
+}
+
+/// Company code → name table
+fn format_companies(list: &CompanyList, cached: bool) -> String {
+    let mut text = format!(
+        "{} companies (from {}{}):\n\n| Company | Name |\n| --- | --- |\n",
+        list.companies.len(),
+        list.source,
+        if cached { ", cached" } else { "" }
+    );
+    for (code, name) in &list.companies {
+        text.push_str(&format!("| {} | {} |\n", code, name.replace('|', "\\|")));
+    }
+    if list.timed_out {
+        text.push_str(&partial_results_notice(list.companies.len()));
+    }
+    text
+}
+
+/// Note for a multi-page read cut short by the call's timeout
+fn partial_results_notice(records: usize) -> String {
+    format!(
+        "\nPartial results: stopped after {} records due to timeout. Pass a larger timeout to fetch the rest.\n",
+        records
+    )
+}
+
+/// Describe the identity carried by decoded access token claims
+fn format_token_claims(claims: &Value) -> String {
+    let claim = |name: &str| claims.get(name).and_then(Value::as_str);
+    let roles = claims
+        .get("roles")
+        .and_then(Value::as_array)
+        .map(|roles| {
+            roles
+                .iter()
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .filter(|roles| !roles.is_empty())
+        .unwrap_or_else(|| "none".to_string());
+    let expires = claims
+        .get("exp")
+        .and_then(Value::as_i64)
+        .and_then(|exp| chrono::DateTime::from_timestamp(exp, 0))
+        .map(|at| {
+            let minutes = (at - chrono::Utc::now()).num_minutes();
+            let relative = if minutes >= 0 {
+                format!("in {} min", minutes)
+            } else {
+                format!("{} min ago", -minutes)
+            };
+            format!("{} ({})", at.format("%Y-%m-%d %H:%M:%S UTC"), relative)
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+
+    format!(
+        "Method: decoded access token claims (signature not verified)\n\
+         - Application Id: {}\n\
+         - Tenant Id: {}\n\
+         - Roles: {}\n\
+         - Expires: {}\n",
+        // v1 tokens carry `appid`, v2 tokens `azp`
+        claim("appid").or(claim("azp")).unwrap_or("unknown"),
+        claim("tid").unwrap_or("unknown"),
+        roles,
+        expires
+    )
+}
+
+/// First line of a metadata description, cut to fit a listing line
+fn short_description(description: &str) -> String {
+    const MAX_CHARS: usize = 160;
+    let line = description.lines().next().unwrap_or_default().trim();
+    if line.chars().count() <= MAX_CHARS {
+        return line.to_string();
+    }
+    let cut: String = line.chars().take(MAX_CHARS - 3).collect();
+    format!("{}...", cut.trim_end())
+}
+
+/// A long-running operation's status after `intro`; an error when it failed
+fn operation_result(intro: &str, monitor_url: &str, status: &OperationStatus) -> CallToolResult {
+    let mut text = intro.to_string();
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    text.push_str(&format!("Operation: {}", status.state));
+    if let Some(raw_state) = &status.raw_state {
+        text.push_str(&format!(" ({})", raw_state));
+    }
+    if !status.body.is_null() {
+        text.push_str(&format!(
+            "\n{}",
+            serde_json::to_string_pretty(&status.body).unwrap_or_default()
+        ));
+    }
+    match status.state {
+        OperationState::Running => {
+            text.push_str(&format!(
+                "\nNot finished yet; check it with check_operation monitor_url={} (with wait_secs to wait for it)",
+                monitor_url
+            ));
+            CallToolResult::text(text)
+        }
+        OperationState::Succeeded => CallToolResult::text(text),
+        OperationState::Failed => CallToolResult::error(text),
+    }
+}
+
+/// Append the `client-request-id` of the failed requests to an error, so
+/// the failure can be found in the local log and in a support case
+fn quote_failed_request_ids(mut result: CallToolResult, ids: &[String]) -> CallToolResult {
+    if result.is_error != Some(true) || ids.is_empty() {
+        return result;
+    }
+    let mut unique: Vec<&str> = Vec::new();
+    for id in ids {
+        if !unique.contains(&id.as_str()) {
+            unique.push(id);
+        }
+    }
+    if let Some(content) = result.content.first_mut() {
+        content
+            .text
+            .push_str(&format!("\n\nclient-request-id: {}", unique.join(", ")));
+    }
+    result
+}
+
+/// Room left for the truncation notice when cutting output
+const TRUNCATION_NOTE_RESERVE: usize = 200;
+
+/// Cap every text block of a tool result at `max_chars` characters
+fn truncate_result(mut result: CallToolResult, max_chars: usize) -> CallToolResult {
+    for content in &mut result.content {
+        if content.text.chars().count() <= max_chars {
+            continue;
+        }
+
+        let keep = max_chars.saturating_sub(TRUNCATION_NOTE_RESERVE);
+        let cut = content
+            .text
+            .char_indices()
+            .nth(keep)
+            .map(|(index, _)| index)
+            .unwrap_or(content.text.len());
+        content.text.truncate(cut);
+        content.text.push_str(&format!(
+            "\n\n[output truncated at {} characters; narrow the request with $select or $top]",
+            max_chars
+        ));
+    }
+    result
+}
+
+/// Describe the circuit breaker state
+fn format_circuit(stats: &CircuitStats) -> String {
+    if stats.threshold == 0 {
+        return "off".to_string();
+    }
+    let state = match stats.state {
+        CircuitState::Closed => format!(
+            "closed ({}/{} failures in a row)",
+            stats.consecutive_failures, stats.threshold
+        ),
+        CircuitState::Open => format!(
+            "OPEN, requests fail for another {}s",
+            stats.retry_after.unwrap_or_default().as_secs_f64().ceil()
+        ),
+        CircuitState::HalfOpen => "half-open, waiting for a probe request".to_string(),
+    };
+    match stats.opened {
+        0 => state,
+        opened => format!("{}; opened {} time(s) since startup", state, opened),
+    }
+}
+
+/// Sorted distinct values as a list plus structured content; `limit` is the
+/// record cap the values were read under
+fn distinct_result(
+    entity: &str,
+    field: &str,
+    values: Vec<DistinctValue>,
+    summary: &StreamSummary,
+    limit: usize,
+    deduplication: Deduplication,
+) -> CallToolResult {
+    let deduplicated = match deduplication {
+        Deduplication::Server => "by the server ($apply=groupby)".to_string(),
+        Deduplication::Client => format!("client-side from {} records", summary.records),
+        Deduplication::ApplyRejected => format!(
+            "client-side from {} records (the service rejected $apply=groupby)",
+            summary.records
+        ),
+    };
+    let mut text = format!(
+        "{} distinct value(s) of {} in {}, de-duplicated {}:\n",
+        values.len(),
+        field,
+        entity,
+        deduplicated
+    );
+    for value in &values {
+        text.push_str(&format!("- {}\n", value.display()));
+    }
+    if summary.truncated {
+        let read = match deduplication {
+            Deduplication::Server => "groups",
+            Deduplication::Client | Deduplication::ApplyRejected => "records",
+        };
+        text.push_str(&format!(
+            "\n[stopped after DISTINCT_VALUES_LIMIT = {} {}; there may be more values, narrow with filter]",
+            limit, read
+        ));
+    } else if summary.timed_out {
+        text.push_str(&partial_results_notice(summary.records));
+    }
+
+    let structured = serde_json::json!({
+        "entity": entity,
+        "field": field,
+        "count": values.len(),
+        "values": values.iter().map(|value| value.value.clone()).collect::<Vec<_>>(),
+        "deduplicated_by": match deduplication {
+            Deduplication::Server => "server",
+            Deduplication::Client | Deduplication::ApplyRejected => "client",
+        },
+        "records_read": summary.records,
+        "truncated": summary.truncated || summary.timed_out,
+    });
+    CallToolResult::structured(text.trim_end().to_string(), structured)
+}
+
+/// Describe the shared `Retry-After` cool-down
+fn format_backpressure(stats: &BackpressureStats) -> String {
+    let state = match stats.cooling_down {
+        Some(left) => format!(
+            "COOLING DOWN, requests wait another {}s",
+            left.as_secs_f64().ceil()
+        ),
+        None => "none".to_string(),
+    };
+    match stats.engaged {
+        0 => state,
+        engaged => format!(
+            "{}; engaged {} time(s), {} request(s) held back since startup",
+            state, engaged, stats.delayed
+        ),
+    }
+}
+
+/// Describe configured client-side limits
+fn format_rate_limits(stats: &RateLimiterStats) -> String {
+    let rpm = stats
+        .max_requests_per_minute
+        .map(|n| format!("{}/min", n))
+        .unwrap_or_else(|| "unlimited".to_string());
+    match stats.max_concurrent_requests {
+        Some(n) => format!("{}, max {} concurrent", rpm, n),
+        None => rpm,
+    }
+}
+
+/// Describe configured entity allow/deny lists
+fn format_entity_policy(config: &RuntimeConfig) -> String {
+    let allowed = if config.allowed_entities.is_empty() {
+        "all".to_string()
+    } else {
+        config.allowed_entities.join(", ")
+    };
+    if config.denied_entities.is_empty() {
+        format!("allowed: {}", allowed)
+    } else {
+        format!(
+            "allowed: {}; denied: {}",
+            allowed,
+            config.denied_entities.join(", ")
+        )
+    }
+}
+
+/// Markdown summary of an entity's keys, properties and navigation properties
+fn format_entity_metadata(model: &MetadataModel, entity: &str) -> Result<String, ODataError> {
+    let (properties, nav_properties, key_fields) =
+        ODataClient::parse_entity_from_metadata(model, entity)?;
+    let mut output = String::new();
+
+    output.push_str(&format!("## Entity: {}\n\n", entity));
+    match model.write_capabilities(entity) {
+        Some(writable) => output.push_str(&format!("Writable: {}\n\n", writable.summary())),
+        None => output.push_str(&format!(
+            "Writable: {} (no restrictions in $metadata)\n\n",
+            WriteCapabilities::default().summary()
+        )),
+    }
+
+    // Key fields
+    if !key_fields.is_empty() {
+        output.push_str("### Key Fields\n");
+        for key in &key_fields {
+            output.push_str(&format!("- {}\n", key));
+        }
+        output.push('\n');
+    }
+
+    // Properties
+    output.push_str(&format!("### Properties ({} fields)\n", properties.len()));
+    for prop in &properties {
+        output.push_str(&format!("- {}\n", prop));
+    }
+    output.push('\n');
+
+    // Navigation properties (expandable)
+    if !nav_properties.is_empty() {
+        output.push_str(&format!(
+            "### Navigation Properties (expandable via $expand) ({} fields)\n",
+            nav_properties.len()
+        ));
+        for nav in &nav_properties {
+            output.push_str(&format!("- {}\n", nav));
+        }
+    }
+
+    Ok(output)
+}
+
+/// Why a record has no audit history, from the audit settings
+fn no_audit_history(record: &str, logical_name: &str, status: AuditStatus) -> String {
+    match status {
+        AuditStatus {
+            organization: Some(false),
+            ..
+        } => format!(
+            "Auditing is turned off for this environment, so {} has no audit history. An administrator can enable it under Settings > Auditing",
+            record
+        ),
+        AuditStatus {
+            entity: Some(false),
+            ..
+        } => format!(
+            "Auditing is not enabled for table '{}', so {} has no audit history. Enable auditing on the table to start recording changes",
+            logical_name, record
+        ),
+        AuditStatus {
+            organization: Some(true),
+            entity: Some(true),
+        } => format!("No audit history recorded for {}", record),
+        _ => format!(
+            "No audit history found for {} (auditing may be disabled, or the audit settings are not readable with this account)",
+            record
+        ),
+    }
+}
+
+/// The `top` most recent audit entries, oldest first, with field labels
+fn format_audit_history(
+    record: &str,
+    history: &[AuditEntry],
+    top: usize,
+    labels: &HashMap<String, String>,
+) -> String {
+    let shown = &history[history.len().saturating_sub(top)..];
+    let mut text = if shown.len() < history.len() {
+        format!(
+            "Audit history of {} (last {} of {} changes):\n",
+            record,
+            shown.len(),
+            history.len()
+        )
+    } else {
+        format!("Audit history of {} ({} changes):\n", record, history.len())
+    };
+
+    for entry in shown {
+        text.push_str(&format!(
+            "\n{}  {} by {}\n",
+            entry.changed_on, entry.action, entry.changed_by
+        ));
+        for change in &entry.changes {
+            let name = match labels.get(&change.field) {
+                Some(label) => format!("{} ({})", label, change.field),
+                None => change.field.clone(),
+            };
+            text.push_str(&format!(
+                "  - {}: {} → {}\n",
+                name,
+                change.old.as_deref().unwrap_or("(empty)"),
+                change.new.as_deref().unwrap_or("(empty)")
+            ));
+        }
+    }
+    text.trim_end().to_string()
+}
+
+/// Search hits grouped by table, in the order each table first appears
+fn format_search_results(query: &str, results: &SearchResults) -> String {
+    if results.hits.is_empty() {
+        return format!("No results for '{}'", query);
+    }
+
+    let mut text = match results.total {
+        Some(total) => format!(
+            "Found {} results for '{}' (showing {}):\n",
+            total,
+            query,
+            results.hits.len()
+        ),
+        None => format!("Results for '{}':\n", query),
+    };
+    let mut groups: Vec<(&str, Vec<&SearchHit>)> = Vec::new();
+    for hit in &results.hits {
+        match groups.iter_mut().find(|(entity, _)| *entity == hit.entity) {
+            Some((_, hits)) => hits.push(hit),
+            None => groups.push((&hit.entity, vec![hit])),
+        }
+    }
+
+    for (entity, hits) in groups {
+        text.push_str(&format!("\n## {} ({})\n", entity, hits.len()));
+        for hit in hits {
+            let name = ["name", "fullname", "title", "subject", "ticketnumber"]
+                .iter()
+                .find_map(|field| hit.fields.get(*field).and_then(Value::as_str))
+                .unwrap_or(&hit.id);
+            text.push_str(&format!(
+                "- {} (score {:.2}) id {}\n",
+                name, hit.score, hit.id
+            ));
+            for (field, fragments) in &hit.highlights {
+                text.push_str(&format!("  {}: {}\n", field, fragments.join(" … ")));
+            }
+        }
+    }
+    text.trim_end().to_string()
+}
+
+/// Misspelled or mistyped arguments fail instead of being silently ignored
+fn check_arguments(
+    name: &str,
+    args: &HashMap<String, Value>,
+    config: &RuntimeConfig,
+) -> Result<(), CallToolResult> {
+    let Some(tool) = D365McpServer::get_tools_static()
+        .into_iter()
+        .find(|tool| tool.name == name)
+    else {
+        return Ok(());
+    };
+    // Checked against the limits the client was told about
+    let tool = with_top_limits(tool, config);
+    validate_arguments(&tool.input_schema, args).map_err(|problems| {
+        CallToolResult::error(format!(
+            "Invalid arguments for tool '{}':\n- {}",
+            name,
+            problems.join("\n- ")
+        ))
+    })
+}
+
+/// A per-call `language`: the configured one or one Dataverse ships. Each
+/// language gets its own `$metadata` download, so arbitrary tags are refused.
+fn supported_language(tag: &str, configured: Option<&str>) -> Result<String, String> {
+    match configured {
+        Some(configured) if configured.eq_ignore_ascii_case(tag) => Ok(configured.to_string()),
+        _ => known_language(tag).ok_or_else(|| {
+            format!(
+                "Unsupported language '{}': use LABEL_LANGUAGE{} or a language such as 'de-DE', 'fr-FR' or 'ja'",
+                tag,
+                configured
+                    .map(|configured| format!(" ('{}')", configured))
+                    .unwrap_or_default()
+            )
+        }),
+    }
+}
+
+/// String argument
+fn get_str<'a>(args: &'a HashMap<String, Value>, key: &str) -> Option<&'a str> {
+    args.get(key).and_then(Value::as_str)
+}
+
+/// Non-negative integer argument, given as a JSON number or a numeric string
+fn get_usize(args: &HashMap<String, Value>, key: &str) -> Option<usize> {
+    args.get(key).and_then(|v| match v {
+        Value::Number(n) => n.as_u64().and_then(|n| usize::try_from(n).ok()),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    })
+}
+
+/// Boolean argument, given as a JSON boolean or `"true"`/`"false"`
+fn get_bool(args: &HashMap<String, Value>, key: &str) -> Option<bool> {
+    args.get(key).and_then(|v| match v {
+        Value::Bool(b) => Some(*b),
+        Value::String(s) => s.trim().to_lowercase().parse().ok(),
+        _ => None,
+    })
+}
+
+/// The `company` argument shared by `query_entity` and the write tools
+fn company_param() -> ToolParam {
+    ToolParam::string(
+        "company",
+        "F&O legal entity (dataAreaId), e.g. 'usmf'. Scopes the query filter or the written key and data. Defaults to DEFAULT_COMPANY; ignored for Dataverse",
+    )
+}
+
+fn company_literal(company: &str) -> String {
+    format!("'{}'", company.replace('\'', "''"))
+}
+
+/// The `[[entities]]` entry for `entity`, if any
+fn configured_entity<'a>(config: &'a RuntimeConfig, entity: &str) -> Option<&'a EntityConfig> {
+    config
+        .entities
+        .iter()
+        .find(|configured| configured.name.eq_ignore_ascii_case(entity))
+}
+
+/// `top` limits of an entity: its own `default_top`/`max_top`, else the
+/// global ones, else the built-in 50 and 1000. A default above the max is
+/// lowered to it.
+fn top_limits(config: &RuntimeConfig, entity: Option<&EntityConfig>) -> TopLimits {
+    let max = entity
+        .and_then(|entity| entity.max_top)
+        .or(config.max_top)
+        .unwrap_or(MAX_TOP)
+        .max(1);
+    let default = entity
+        .and_then(|entity| entity.default_top)
+        .or(config.default_top)
+        .unwrap_or(DEFAULT_TOP)
+        .clamp(1, max);
+    TopLimits { default, max }
+}
+
+/// The `top` to send for a requested one, with a note when it was lowered
+fn limit_top(requested: Option<usize>, limits: TopLimits, entity: &str) -> (usize, Option<String>) {
+    match requested {
+        Some(top) if top > limits.max => (
+            limits.max,
+            Some(format!(
+                "Note: top={} lowered to {}, the most {} returns per page (max_top); use page_token for more",
+                top, limits.max, entity
+            )),
+        ),
+        Some(top) => (top.max(1), None),
+        None => (limits.default, None),
+    }
+}
+
+/// `query_entity` with its `top` bounds taken from the configuration: the
+/// global default and max, and each entity that overrides them named in the
+/// description. The schema has no `maximum`, since `limit_top` lowers a
+/// larger `top` to the entity's max with a note instead of rejecting it.
+fn with_top_limits(mut tool: Tool, config: &RuntimeConfig) -> Tool {
+    if tool.name != "query_entity" {
+        return tool;
+    }
+    let global = top_limits(config, None);
+    let overrides: Vec<(&str, TopLimits)> = config
+        .entities
+        .iter()
+        .filter(|entity| entity.default_top.is_some() || entity.max_top.is_some())
+        .map(|entity| (entity.name.as_str(), top_limits(config, Some(entity))))
+        .collect();
+    if let Some(top) = tool.input_schema["properties"].get_mut("top") {
+        top["default"] = global.default.into();
+        top["description"] = format!(
+            "Maximum records to return (default {}, at most {}{})",
+            global.default,
+            global.max,
+            if overrides.is_empty() {
+                ""
+            } else {
+                " unless the entity's own limits in the tool description differ"
+            }
+        )
+        .into();
+    }
+    if !overrides.is_empty() {
+        let listed: Vec<String> = overrides
+            .iter()
+            .map(|(name, limits)| {
+                format!("{} (default {}, max {})", name, limits.default, limits.max)
+            })
+            .collect();
+        tool.description.push_str(&format!(
+            " Entities with their own top limits: {}; a larger top is lowered to the max.",
+            listed.join(", ")
+        ));
+    }
+    tool
+}
+
+/// Apply an entity's `default_select` when no `select` was given and AND its
+/// `default_filter` onto the user's filter; returns whether the default
+/// projection was used. `select=*` asks for every field.
+fn apply_entity_defaults(
+    options: &mut QueryOptions,
+    defaults: &EntityConfig,
+) -> Result<bool, String> {
+    if let Some(default_filter) = defaults
+        .default_filter
+        .as_deref()
+        .filter(|filter| !filter.trim().is_empty())
+    {
+        validate_filter(default_filter).map_err(|message| {
+            format!(
+                "Invalid default_filter for entity '{}': {}",
+                defaults.name, message
+            )
+        })?;
+        options.filter = Some(match options.filter.take() {
+            Some(filter) if !filter.trim().is_empty() => {
+                format!("({}) and ({})", filter, default_filter)
+            }
+            _ => default_filter.to_string(),
+        });
+    }
+
+    if clear_select_all(options) || options.select.is_some() || defaults.default_select.is_empty() {
+        return Ok(false);
+    }
+    options.select = Some(defaults.default_select.clone());
+    Ok(true)
+}
+
+/// Drop a `select=*`, which means no projection; returns whether there was one
+fn clear_select_all(options: &mut QueryOptions) -> bool {
+    let select_all = options
+        .select
+        .as_ref()
+        .is_some_and(|select| select.iter().any(|field| field == "*"));
+    if select_all {
+        options.select = None;
+    }
+    select_all
+}
+
+/// Record keys for a view's layout columns, in layout order: the display
+/// label when there is one (choices, lookups, money), else the raw value
+fn view_columns(records: &[Value], layout: &[String]) -> Vec<String> {
+    layout
+        .iter()
+        .map(|column| {
+            let candidates = [
+                format!("{}_label", column),
+                format!("_{}_value_label", column),
+                column.clone(),
+                format!("_{}_value", column),
+            ];
+            candidates
+                .iter()
+                .find(|key| {
+                    records
+                        .iter()
+                        .any(|record| record.get(key.as_str()).is_some())
+                })
+                .cloned()
+                .unwrap_or_else(|| column.clone())
+        })
+        .collect()
+}
+
+/// AND a `dataAreaId` condition onto a filter
+fn company_filter(filter: Option<String>, company: &str) -> String {
+    let condition = format!("dataAreaId eq {}", company_literal(company));
+    match filter {
+        Some(filter) if !filter.trim().is_empty() => format!("({}) and {}", filter, condition),
+        _ => condition,
+    }
+}
+
+/// Add `dataAreaId` to a record key unless it already names one
+fn company_key(key: EntityKey, company: &str) -> EntityKey {
+    match key {
+        EntityKey::Composite(mut fields) => {
+            if !fields
+                .iter()
+                .any(|(field, _)| field.eq_ignore_ascii_case("dataAreaId"))
+            {
+                fields.insert(0, ("dataAreaId".to_string(), Value::from(company)));
+            }
+            EntityKey::Composite(fields)
+        }
+        EntityKey::Single(expression) => {
+            EntityKey::Single(company_key_expression(&expression, company))
+        }
+    }
+}
+
+/// Prefix a named key expression with `dataAreaId`. A bare key value has no
+/// field names to join, so it is left alone.
+fn company_key_expression(expression: &str, company: &str) -> String {
+    let names_company = split_top_level(expression, ',').into_iter().any(|part| {
+        part.split_once('=')
+            .is_some_and(|(field, _)| field.trim().eq_ignore_ascii_case("dataAreaId"))
+    });
+    if !expression.contains('=') || names_company {
+        return expression.to_string();
+    }
+    format!("dataAreaId={},{}", company_literal(company), expression)
+}
+
+/// Payload with `dataAreaId` set unless the caller already gave one
+fn company_payload(data: &Value, company: &str) -> Value {
+    let mut data = data.clone();
+    if let Value::Object(fields) = &mut data {
+        if !fields
+            .keys()
+            .any(|field| field.eq_ignore_ascii_case("dataAreaId"))
+        {
+            fields.insert("dataAreaId".to_string(), Value::from(company));
+        }
+    }
+    data
+}
+
+/// The last component of a server-supplied file name, with characters that
+/// are unsafe in file names replaced; `None` if nothing usable is left
+fn safe_file_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or(name).trim();
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            c if c.is_alphanumeric() || matches!(c, '.' | '-' | '_' | ' ') => c,
+            _ => '_',
+        })
+        .collect();
+    let name = name.trim_start_matches('.');
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Name to save a download under: the server's, else `<entity>-<attribute>`,
+/// both made safe since either may hold path separators
+fn download_file_name(file_name: Option<&str>, entity: &str, attribute: &str) -> String {
+    file_name
+        .and_then(safe_file_name)
+        .or_else(|| safe_file_name(&format!("{}-{}", entity, attribute).replace(['/', '\\'], "_")))
+        .unwrap_or_else(|| "download".to_string())
+}
+
+/// Resolve `path` (absolute, or relative to `upload_dir`) to a file inside
+/// `upload_dir`, following symlinks so they cannot point elsewhere
+fn resolve_upload_path(upload_dir: &Path, path: &str) -> Result<PathBuf, String> {
+    let base = upload_dir
+        .canonicalize()
+        .map_err(|e| format!("UPLOAD_DIR {} is not usable: {}", upload_dir.display(), e))?;
+    let resolved = base
+        .join(path.trim())
+        .canonicalize()
+        .map_err(|e| format!("Cannot read '{}': {}", path, e))?;
+
+    if !resolved.starts_with(&base) {
+        return Err(format!(
+            "'{}' is outside UPLOAD_DIR ({}); only files in that directory can be uploaded",
+            path,
+            base.display()
+        ));
+    }
+    if !resolved.is_file() {
+        return Err(format!("'{}' is not a file", path));
+    }
+    Ok(resolved)
+}
+
+/// MIME type for a note attachment, from the file extension
+fn mime_type(file_name: &str) -> &'static str {
+    let extension = file_name
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "pdf" => "application/pdf",
+        "txt" | "log" => "text/plain",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "html" | "htm" => "text/html",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "zip" => "application/zip",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Write `bytes` as `name` in `dir`, adding a counter instead of overwriting
+fn save_download(dir: &Path, name: &str, bytes: &[u8]) -> std::io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+
+    for attempt in 1.. {
+        let path = match attempt {
+            1 => dir.join(name),
+            n => dir.join(format!("{} ({}){}", stem, n, extension)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                file.write_all(bytes)?;
+                return Ok(path);
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("the attempt counter is unbounded")
+}
+
+/// Per-call override of the impersonated Dataverse user
+fn impersonate_param() -> ToolParam {
+    ToolParam::string(
+        "impersonate_user_id",
+        "Dataverse only: GUID of the user to act as (MSCRMCallerID/CallerObjectId), overriding IMPERSONATE_USER_ID for this call",
+    )
+}
+
+/// Error for an environment name that is not configured
+fn unknown_environment(config: &RuntimeConfig, name: &str) -> String {
+    let names: Vec<&str> = config
+        .environments
+        .iter()
+        .map(|e| e.name.as_str())
+        .collect();
+    if names.is_empty() {
+        "No named environments are configured".to_string()
+    } else {
+        format!(
+            "Unknown environment '{}'. Configured environments: {}",
+            name,
+            names.join(", ")
+        )
+    }
+}
+
+/// Let a tool run against another configured environment than the active one
+fn with_environment_param(mut tool: Tool) -> Tool {
+    if !UNROUTED_TOOLS.contains(&tool.name.as_str()) {
+        let param = ToolParam::string(
+            "environment",
+            "Named environment to run this call against instead of the active one, e.g. 'fno-prod' (see list_environments)",
+        );
+        tool.input_schema["properties"][&param.name] = param.schema();
+    }
+    tool
+}
+
+/// Add the per-call `timeout` argument to a tool that calls the service
+fn with_timeout_param(mut tool: Tool) -> Tool {
+    if !UNTIMED_TOOLS.contains(&tool.name.as_str()) {
+        let param = ToolParam::integer(
+            "timeout",
+            "Seconds this call's requests may take, overriding REQUEST_TIMEOUT_SECS; multi-page reads return what was fetched in time",
+        )
+        .range(Some(1), Some(MAX_CALL_TIMEOUT_SECS));
+        tool.input_schema["properties"][&param.name] = param.schema();
+    }
+    tool
+}
+
+/// Return the request instead of sending it
+fn dry_run_param() -> ToolParam {
+    ToolParam::boolean(
+        "dry_run",
+        "Return the HTTP request (method, URL, headers without the token, body) instead of sending it",
+    )
+    .default_value(false)
+}
+
+/// What a dry run would have sent, or the tool's own result when it
+/// stopped before building a request (e.g. on a missing argument)
+fn dry_run_result(result: CallToolResult, requests: Vec<PreparedRequest>) -> CallToolResult {
+    if requests.is_empty() {
+        return result;
+    }
+    let mut text = String::from("Dry run: nothing was sent to the service.\n");
+    for request in &requests {
+        text.push('\n');
+        text.push_str(&request.describe());
+    }
+    CallToolResult::text(text.trim_end().to_string())
+}
+
+/// Build query options from `query_entity` arguments
+fn parse_query_options(args: &HashMap<String, Value>) -> Result<QueryOptions, String> {
+    // Parse select
+    let select =
+        get_str(args, "select").map(|s| s.split(',').map(|f| f.trim().to_string()).collect());
+
+    // Parse filter
+    let filter = get_str(args, "filter").map(String::from);
+
+    if let Some(ref filter) = filter {
+        validate_filter(filter).map_err(|message| format!("Invalid filter: {}", message))?;
+    }
+
+    // Parse orderby
+    let orderby = get_str(args, "orderby").map(String::from);
+
+    // Parse search
+    let search = get_str(args, "search").map(String::from);
+
+    // Parse top (with max limit 1000)
+    let top = get_usize(args, "top").unwrap_or(DEFAULT_TOP).min(MAX_TOP);
+
+    // Parse skip
+    let skip = get_usize(args, "skip");
+
+    // Parse expand
+    let expand =
+        get_str(args, "expand").map(|s| s.split(',').map(|f| f.trim().to_string()).collect());
+
+    // Parse cross_company (boolean)
+    let cross_company = get_bool(args, "cross_company").unwrap_or(false);
+
+    // Parse count (boolean)
+    let count = get_bool(args, "count").unwrap_or(false);
+
+    Ok(QueryOptions {
+        select,
+        filter,
+        top: Some(top),
+        skip,
+        orderby,
+        expand,
+        cross_company,
+        count,
+        search,
+        ..Default::default()
+    })
+}
+
+/// Navigation paths an `$expand` value reaches, including `a/b` paths and
+/// nested `$expand` options: `a($expand=b),c/d` gives `[a]`, `[a, b]`, `[c, d]`
+fn expand_paths(expand: &str) -> Vec<Vec<String>> {
+    let mut paths = Vec::new();
+    for item in split_top_level(expand, ',') {
+        let (path, options) = match item.find('(') {
+            Some(open) => {
+                let options = &item[open + 1..];
+                (&item[..open], options.strip_suffix(')').unwrap_or(options))
+            }
+            None => (item, ""),
+        };
+        let path: Vec<String> = path
+            .split('/')
+            .map(str::trim)
+            .filter(|segment| !segment.is_empty())
+            .map(String::from)
+            .collect();
+        if path.is_empty() {
+            continue;
+        }
+        for option in split_top_level(options, ';') {
+            let Some((key, value)) = option.split_once('=') else {
+                continue;
+            };
+            if key.trim().eq_ignore_ascii_case("$expand") {
+                for nested in expand_paths(value) {
+                    paths.push(path.iter().cloned().chain(nested).collect());
+                }
+            }
+        }
+        paths.push(path);
+    }
+    paths
+}
+
+/// Split on `separator` outside parentheses and quoted literals
+fn split_top_level(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut quoted = false;
+    let mut start = 0;
+    for (index, c) in text.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth = depth.saturating_sub(1),
+            c if c == separator && !quoted && depth == 0 => {
+                parts.push(&text[start..index]);
+                start = index + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// Encode a server nextLink as an opaque page token
+fn encode_page_token(next_link: &str) -> String {
+    URL_SAFE_NO_PAD.encode(next_link)
+}
+
+/// Decode a page token, rejecting links that point outside the configured endpoint
+fn decode_page_token(token: &str, endpoint: &str) -> Result<String, String> {
+    let invalid = || "Invalid page_token: pass the next_page_token value unchanged".to_string();
+
+    let bytes = URL_SAFE_NO_PAD
+        .decode(token.trim())
+        .map_err(|_| invalid())?;
+    let link = String::from_utf8(bytes).map_err(|_| invalid())?;
+
+    let link_url = Url::parse(&link).map_err(|_| invalid())?;
+    let endpoint_url = Url::parse(endpoint).map_err(|_| invalid())?;
+
+    if !same_origin(&link_url, &endpoint_url) || !link_url.path().starts_with(endpoint_url.path()) {
+        return Err("Invalid page_token: link does not belong to the configured endpoint".into());
+    }
+
+    Ok(link)
+}
+
+/// Entity set addressed by a link under the endpoint, e.g. `accounts` in
+/// `{endpoint}accounts?$skiptoken=...`
+fn entity_from_link(link: &str, endpoint: &str) -> Option<String> {
+    let link_url = Url::parse(link).ok()?;
+    let endpoint_url = Url::parse(endpoint).ok()?;
+    let relative = link_url.path().strip_prefix(endpoint_url.path())?;
+
+    let entity = relative
+        .split(['/', '('])
+        .next()
+        .filter(|segment| !segment.is_empty())?;
+    Some(
+        percent_encoding::percent_decode_str(entity)
+            .decode_utf8_lossy()
+            .into_owned(),
+    )
+}
+
+/// Entity set a bound action path is bound to, e.g. `accounts` in
+/// `accounts(<id>)/Microsoft.Dynamics.CRM.Merge`; `None` for an unbound
+/// action name
+fn action_binding(action: &str) -> Result<Option<String>, String> {
+    let invalid = || {
+        format!(
+            "action must be an action name or a bound action path such as \
+             'accounts(<guid>)/Microsoft.Dynamics.CRM.new_Recalculate', got '{}'",
+            action
+        )
+    };
+    if action.contains(['?', '#']) {
+        return Err(invalid());
+    }
+    let Some((target, name)) = action.split_once('/') else {
+        return if action.contains('(') {
+            Err(invalid())
+        } else {
+            Ok(None)
+        };
+    };
+    // One segment for the set or record, then the qualified action name
+    if name.contains(['/', '(']) || !name.contains('.') {
+        return Err(invalid());
+    }
+    let set = match target.split_once('(') {
+        Some((set, key)) if key.ends_with(')') => set,
+        Some(_) => return Err(invalid()),
+        None => target,
+    };
+    if set.is_empty() || set.contains(')') {
+        return Err(invalid());
+    }
+    Ok(Some(
+        percent_encoding::percent_decode_str(set)
+            .decode_utf8_lossy()
+            .into_owned(),
+    ))
+}
+
+/// Entity set and navigation property of a link to related records, e.g.
+/// `accounts` and `contacts` in `{endpoint}accounts(1)/contacts?$skiptoken=...`
+fn navigation_from_link(link: &str, endpoint: &str) -> Option<(String, String)> {
+    let link_url = Url::parse(link).ok()?;
+    let endpoint_url = Url::parse(endpoint).ok()?;
+    let relative = link_url.path().strip_prefix(endpoint_url.path())?;
+
+    let (record, navigation) = relative.rsplit_once('/')?;
+    let (entity, key) = record.split_once('(')?;
+    if entity.is_empty()
+        || record.contains('/')
+        || !key.ends_with(')')
+        || navigation.is_empty()
+        || navigation.contains('(')
+    {
+        return None;
+    }
+    let decode = |segment: &str| {
+        percent_encoding::percent_decode_str(segment)
+            .decode_utf8_lossy()
+            .into_owned()
+    };
+    Some((decode(entity), decode(navigation)))
+}
+
+/// Record key from `{prefix}key`, an object of field values, or from
+/// `{prefix}id` with optional `{prefix}key_field` names
+fn parse_record_key(
+    args: &HashMap<String, Value>,
+    product: &ProductType,
+    prefix: &str,
+) -> Result<EntityKey, String> {
+    let key_arg = format!("{}key", prefix);
+    if let Some(fields) = args.get(&key_arg).and_then(Value::as_object) {
+        if fields.is_empty() {
+            return Err(format!(
+                "Argument '{}' must name at least one key field",
+                key_arg
+            ));
+        }
+        if let Some((field, _)) = fields
+            .iter()
+            .find(|(_, value)| value.is_array() || value.is_object())
+        {
+            return Err(format!(
+                "Key field '{}' must be a string, number or boolean",
+                field
+            ));
+        }
+        return Ok(EntityKey::Composite(
+            fields
+                .iter()
+                .map(|(field, value)| (field.clone(), value.clone()))
+                .collect(),
+        ));
+    }
+
+    let id = get_str(args, &format!("{}id", prefix))
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .ok_or_else(|| format!("Missing required parameter: {0}id or {0}key", prefix))?;
+    let key_fields: Vec<&str> = get_str(args, &format!("{}key_field", prefix))
+        .map(|fields| fields.split(',').filter(|f| !f.trim().is_empty()).collect())
+        .unwrap_or_default();
+    format_entity_key(product, id, &key_fields)
+        .map(EntityKey::Single)
+        .map_err(|e| e.to_string())
+}
+
+/// Parameters of the link tools; the target is optional when unlinking
+fn record_link_params(target_required: bool) -> Vec<ToolParam> {
+    let target_entity = ToolParam::string(
+        "target_entity",
+        "Entity set of the record to link, e.g., 'contacts'",
+    );
+    vec![
+        ToolParam::string("entity", "Entity set of the record owning the navigation property, e.g., 'accounts'").required(),
+        ToolParam::string("id", "Record ID/GUID. Required unless key is given"),
+        ToolParam::object("key", "Key fields and values, for alternate or multi-part keys"),
+        ToolParam::string("key_field", "Alternate key column(s) that id holds values for"),
+        ToolParam::string("navigation_property", "Navigation property on entity, e.g., 'contact_customer_accounts' or 'primarycontactid' (see describe_relationships)").required(),
+        if target_required { target_entity.required() } else { target_entity },
+        ToolParam::string("target_id", "Target record ID/GUID. Required unless target_key is given"),
+        ToolParam::object("target_key", "Target key fields and values"),
+        ToolParam::string("target_key_field", "Alternate key column(s) that target_id holds values for"),
+        impersonate_param(),
+        dry_run_param(),
+    ]
+}
+
+fn parse_delete_key(
+    args: &HashMap<String, Value>,
+    product: &ProductType,
+) -> Result<String, String> {
+    if let Some(key) = get_str(args, "key") {
+        let key = key.trim();
+        if !key.is_empty() {
+            return Ok(key.trim_matches(['(', ')']).to_string());
+        }
+    }
+
+    if let Some(id) = get_str(args, "id") {
+        let id = id.trim();
+        if !id.is_empty() {
+            return format_entity_key(product, id, &[]).map_err(|e| e.to_string());
+        }
+    }
+
+    Err("Missing required parameter: key or id".to_string())
+}
+
+impl D365McpServer {
+    /// Force refresh metadata cache
+    async fn refresh_metadata(&self) -> CallToolResult {
+        // Invalidate caches
+        self.client().invalidate_metadata_cache().await;
+        *self.environment().companies.write().await = None;
+
+        // Fetch fresh metadata
+        match self.client().fetch_metadata().await {
+            Ok(metadata) => {
+                let size_kb = metadata.xml().len() / 1024;
+                let entity_count = metadata.model().entity_sets.len();
+
+                CallToolResult::text(format!(
+                    "Metadata cache refreshed successfully.\n\
+                     - Size: {} KB\n\
+                     - Entities found: {}",
+                    size_kb, entity_count
+                ))
+            }
+            Err(e) => CallToolResult::error(format!("Failed to refresh metadata: {}", e)),
+        }
+    }
+
+    /// Get metadata for a specific entity including properties and navigation properties
+    async fn get_metadata(&self, args: &HashMap<String, Value>) -> CallToolResult {
+        let entity = match get_str(args, "entity") {
+            Some(e) => e,
+            None => return CallToolResult::error("Missing required argument: entity".to_string()),
+        };
+
+        // Fetch metadata, parsed once per cached document
+        let model = match self.client().metadata_model().await {
+            Ok(m) => m,
+            Err(e) => return CallToolResult::error(format!("Failed to fetch metadata: {}", e)),
+        };
+
+        // Shown and returned under the set name the service uses
+        let entity = model.resolve_entity_set(entity).unwrap_or(entity);
+        // The name given may be a type whose set the policy blocks
+        if let Err(message) = self.entity_policy().check(entity) {
+            return CallToolResult::error(message);
+        }
+        let Some(info) = model.entity_type(entity) else {
+            return CallToolResult::error(format!("Entity '{}' not found in metadata", entity));
+        };
+        match format_entity_metadata(&model, entity) {
+            Ok(output) => {
+                let writable = model.write_capabilities(entity).unwrap_or_default();
+                CallToolResult::structured(output, metadata_output(entity, info, &writable))
+            }
+            Err(e) => CallToolResult::error(format!("Failed to parse entity metadata: {}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::StaticTokenProvider;
+    use crate::config::AuthMode;
+    use crate::mcp::permissions::with_session_roles;
+    use serde_json::json;
+
+    fn test_server(read_only: bool) -> D365McpServer {
+        server_at("https://org.crm.dynamics.com/api/data/v9.2/", read_only)
+    }
+
+    fn server_at(endpoint: &str, read_only: bool) -> D365McpServer {
+        let endpoint = endpoint.to_string();
+        let client = ODataClient::new(
+            Arc::new(StaticTokenProvider::new("test-token")),
+            endpoint.clone(),
+            ProductType::Dataverse,
+            0,
+            10,
+            false,
+        );
+        let config = RuntimeConfig {
+            product: ProductType::Dataverse,
+            endpoint,
+            tenant_id: String::new(),
+            client_id: String::new(),
+            auth_mode: AuthMode::ClientCredentials,
+            managed_identity_client_id: None,
+            cloud: Default::default(),
+            authority_host: String::new(),
+            client_secret: None,
+            client_certificate_path: None,
+            client_certificate_password: None,
+            client_secret_keyvault_uri: None,
+            token_cache_path: None,
+            record_dir: None,
+            replay_dir: None,
+            proxy: Default::default(),
+            ca_cert_path: None,
+            compression: true,
+            rewrite_next_link_host: false,
+            user_agent_suffix: None,
+            language: None,
+            dry_run_all_writes: false,
+            auth_type: "azure".to_string(),
+            token_url: None,
+            resource: None,
+            insecure_ssl: false,
+            page_size: 500,
+            concurrency: 4,
+            max_retries: 0,
+            auth_max_retries: 0,
+            retry_delay_ms: 10,
+            log_level: "info".to_string(),
+            enable_tracing: false,
+            delta_storage_path: String::new(),
+            entities: Vec::new(),
+            metadata_cache_ttl_secs: 900,
+            max_requests_per_minute: None,
+            max_concurrent_requests: None,
+            max_retry_wait_secs: 60,
+            circuit_breaker_threshold: 5,
+            circuit_breaker_cooldown_secs: 30,
+            request_timeout_secs: 120,
+            shutdown_grace_secs: 10,
+            session_idle_secs: 1800,
+            read_only,
+            allowed_entities: Vec::new(),
+            denied_entities: Vec::new(),
+            max_response_chars: 100_000,
+            default_top: None,
+            max_top: None,
+            default_format: OutputFormat::Json,
+            default_company: None,
+            impersonate_user_id: None,
+            impersonation_header: Default::default(),
+            download_dir: std::env::temp_dir()
+                .join("d365-odata-mcp-tests")
+                .to_string_lossy()
+                .into_owned(),
+            max_download_bytes: 1024 * 1024,
+            upload_dir: None,
+            export_dir: None,
+            metadata_file: None,
+            history_size: 50,
+            history_excluded_entities: Vec::new(),
+            compare_ignored_fields: vec!["modifiedon".to_string()],
+            distinct_values_limit: 10_000,
+            config_reload_secs: 0,
+            test_connection_on_startup: false,
+            tool_permissions: None,
+            annotations: AnnotationMode::All,
+            environment: None,
+            production: false,
+            environments: Vec::new(),
+        };
+
+        D365McpServer::new(Arc::new(client), Arc::new(config))
+    }
+
+    /// Server answering from `src/mcp/testdata/recordings/<name>` instead of
+    /// the network; record new fixtures by pointing RECORD_DIR at a service
+    fn replay_server(name: &str) -> D365McpServer {
+        let server = test_server(true);
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/mcp/testdata/recordings")
+            .join(name);
+        let client = ODataClient::new(
+            Arc::new(StaticTokenProvider::new("test-token")),
+            server.config().endpoint.clone(),
+            ProductType::Dataverse,
+            0,
+            10,
+            false,
+        )
+        .with_recording(crate::odata::recording::Recording::replay(dir).unwrap());
+        D365McpServer::new(Arc::new(client), server.config())
+    }
+
+    fn finops_server_at(endpoint: &str) -> D365McpServer {
+        let mut config = (*server_at(endpoint, true).config()).clone();
+        config.product = ProductType::Finops;
+        let client = ODataClient::new(
+            Arc::new(StaticTokenProvider::new("test-token")),
+            endpoint.to_string(),
+            ProductType::Finops,
+            0,
+            10,
+            false,
+        );
+        D365McpServer::new(Arc::new(client), Arc::new(config))
+    }
+
+    /// Loader building an F&O test server for each of `environments`, named
+    /// and marked production as its summary says
+    fn environment_loader_for(
+        environments: Vec<crate::config::EnvironmentSummary>,
+    ) -> EnvironmentLoader {
+        Arc::new(move |name| {
+            let summary = environments.iter().find(|e| e.name == name).unwrap();
+            // Not through `config()` and `client()`, which give the calling
+            // environment while a call runs
+            let ActiveEnvironment { client, config, .. } = finops_server_at(&summary.endpoint)
+                .active
+                .into_inner()
+                .unwrap();
+            let mut config = (*config).clone();
+            config.environment = Some(name.to_string());
+            config.production = summary.production;
+            config.environments = environments.clone();
+            Ok((client, Arc::new(config)))
+        })
+    }
+
+    fn restricted_server(allowed: &[&str], denied: &[&str]) -> D365McpServer {
+        let server = test_server(true);
+        let mut config = (*server.config()).clone();
+        config.allowed_entities = allowed.iter().map(|s| s.to_string()).collect();
+        config.denied_entities = denied.iter().map(|s| s.to_string()).collect();
+
+        D365McpServer::new(server.client(), Arc::new(config))
+    }
+
+    fn permissioned_server(default: UnlistedTools, tools: &[(&str, &[&str])]) -> D365McpServer {
+        let server = test_server(false);
+        let mut config = (*server.config()).clone();
+        config.tool_permissions = Some(crate::mcp::ToolPermissions {
+            default,
+            tools: tools
+                .iter()
+                .map(|(pattern, roles)| {
+                    let roles = roles.iter().map(|role| role.to_string()).collect();
+                    (pattern.to_string(), roles)
+                })
+                .collect(),
+            ..Default::default()
+        });
+
+        D365McpServer::new(server.client(), Arc::new(config))
+    }
+
+    fn result_text(result: &CallToolResult) -> String {
+        serde_json::to_string(&result.content).unwrap()
+    }
+
+    #[test]
+    fn static_tools_include_delete_record() {
+        let tools = D365McpServer::get_tools_static();
+
+        assert!(tools.iter().any(|tool| tool.name == "delete_record"));
+    }
+
+    #[test]
+    fn read_only_mode_hides_mutating_tools() {
+        let read_only = test_server(true).get_tools();
+        let writable = test_server(false).get_tools();
+
+        assert!(!read_only.iter().any(|tool| is_mutating_tool(&tool.name)));
+        assert!(writable.iter().any(|tool| tool.name == "delete_record"));
+        let dataverse_writes = MUTATING_TOOLS
+            .iter()
+            .filter(|name| is_available_for(name, &ProductType::Dataverse))
+            .count();
+        assert_eq!(writable.len() - read_only.len(), dataverse_writes);
+    }
+
+    #[tokio::test]
+    async fn read_only_mode_rejects_mutating_calls_by_name() {
+        let mut args = HashMap::new();
+        args.insert("entity".to_string(), json!("accounts"));
+        args.insert("id".to_string(), json!("1"));
+        args.insert("confirm".to_string(), json!("DELETE"));
+
+        let result = test_server(true).call_tool("delete_record", &args).await;
+
+        assert_eq!(result.is_error, Some(true));
+        let text = result_text(&result);
+        assert!(text.contains("read-only mode"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn blocked_entities_are_rejected_before_any_request() {
+        let server = restricted_server(&["CustomersV3", "Sales*"], &["SalesSecret*"]);
+
+        for (tool, entity, policy) in [
+            ("query_entity", "hcmworkers", "allowed_entities"),
+            ("get_record", "HcmWorkers", "allowed_entities"),
+            ("get_metadata", "SALESSECRETLINES", "denied_entities"),
+            ("get_entity_schema", "SalesSecretHeaders", "denied_entities"),
+        ] {
+            let mut args = HashMap::new();
+            args.insert("entity".to_string(), json!(entity));
+            if tool == "get_record" {
+                args.insert("id".to_string(), json!("1"));
+            }
+
+            let result = server.call_tool(tool, &args).await;
+            assert_eq!(result.is_error, Some(true), "{tool}");
+            let text = result_text(&result);
+            assert!(text.contains(policy), "{tool}: {text}");
+        }
+    }
+
+    #[test]
+    fn tools_are_authorized_by_their_most_specific_pattern() {
+        let tools: &[(&str, &[&str])] = &[
+            ("*", &["admin"]),
+            ("get_*", &["reader", "admin"]),
+            ("get_record", &["auditor"]),
+            ("*_entity", &["analyst"]),
+            ("delete_*", &[]),
+        ];
+        let cases: &[(UnlistedTools, &str, &[&str], bool)] = &[
+            // `*` covers every tool, so the default never applies
+            (UnlistedTools::Deny, "list_entities", &["admin"], true),
+            (UnlistedTools::Deny, "list_entities", &["reader"], false),
+            (UnlistedTools::Allow, "list_entities", &[], false),
+            (UnlistedTools::Deny, "get_metadata", &["READER"], true),
+            // An exact name beats `get_*`
+            (UnlistedTools::Deny, "get_record", &["reader"], false),
+            (UnlistedTools::Deny, "get_record", &["auditor"], true),
+            (UnlistedTools::Deny, "get_record_audit", &["auditor"], false),
+            (UnlistedTools::Deny, "query_entity", &["analyst"], true),
+            (UnlistedTools::Deny, "query_entity", &["admin"], false),
+            // An empty role list permits no one
+            (UnlistedTools::Deny, "delete_record", &["admin"], false),
+        ];
+        for (default, tool, roles, permitted) in cases {
+            let server = permissioned_server(*default, tools);
+            let roles: Vec<String> = roles.iter().map(|role| role.to_string()).collect();
+            assert_eq!(
+                server.authorize_tool(tool, &roles).is_ok(),
+                *permitted,
+                "{tool} with {roles:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn unlisted_tools_follow_the_default() {
+        let tools: &[(&str, &[&str])] = &[("upsert_*", &["writer"])];
+        let cases: &[(UnlistedTools, &str, &[&str], bool)] = &[
+            (UnlistedTools::Allow, "query_entity", &[], true),
+            (UnlistedTools::Allow, "upsert_record", &[], false),
+            (UnlistedTools::Allow, "upsert_record", &["writer"], true),
+            (UnlistedTools::Deny, "query_entity", &["writer"], false),
+            (UnlistedTools::Deny, "upsert_record", &["writer"], true),
+        ];
+        for (default, tool, roles, permitted) in cases {
+            let server = permissioned_server(*default, tools);
+            let roles: Vec<String> = roles.iter().map(|role| role.to_string()).collect();
+            assert_eq!(
+                server.authorize_tool(tool, &roles).is_ok(),
+                *permitted,
+                "{default:?}: {tool} with {roles:?}"
+            );
+        }
+
+        let unrestricted = test_server(false);
+        assert!(unrestricted.authorize_tool("delete_record", &[]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn sessions_only_see_and_call_tools_their_roles_permit() {
+        let tools: &[(&str, &[&str])] = &[("query_entity", &["reader"]), ("upsert_*", &["writer"])];
+        let server = permissioned_server(UnlistedTools::Deny, tools);
+        let reader: Arc<[String]> = Arc::from(vec!["reader".to_string()]);
+
+        let listed: Vec<String> = server
+            .tools_for_roles(&reader)
+            .into_iter()
+            .map(|tool| tool.name)
+            .collect();
+        assert_eq!(listed, vec!["query_entity"]);
+
+        let args = HashMap::from([("entity".to_string(), json!("accounts"))]);
+        let result = with_session_roles(reader, server.call_tool("upsert_record", &args)).await;
+        assert_eq!(result.is_error, Some(true));
+        let text = &result.content[0].text;
+        assert_eq!(
+            text,
+            "Tool 'upsert_record' requires one of the roles [writer]; this session has [reader]"
+        );
+    }
+
+    #[tokio::test]
+    async fn page_tokens_cannot_bypass_entity_policy() {
+        let server = restricted_server(&["CustomersV3"], &[]);
+        let token =
+            encode_page_token("https://org.crm.dynamics.com/api/data/v9.2/HcmWorkers?$skip=50");
+
+        let mut args = HashMap::new();
+        args.insert("entity".to_string(), json!("CustomersV3"));
+        args.insert("page_token".to_string(), json!(token));
+
+        let result = server.call_tool("query_entity", &args).await;
+        assert_eq!(result.is_error, Some(true));
+        assert!(result_text(&result).contains("HcmWorkers"));
+    }
+
+    #[test]
+    fn expand_paths_follow_nested_expands() {
+        assert_eq!(
+            expand_paths("a($select=x,y;$expand=b($filter=n eq 'c,d)')),c/d"),
+            vec![
+                vec!["a".to_string(), "b".to_string()],
+                vec!["a".to_string()],
+                vec!["c".to_string(), "d".to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn entity_from_link_reads_first_segment() {
+        let endpoint = "https://org.crm.dynamics.com/api/data/v9.2/";
+
+        assert_eq!(
+            entity_from_link(
+                "https://org.crm.dynamics.com/api/data/v9.2/accounts?$skiptoken=x",
+                endpoint
+            )
+            .as_deref(),
+            Some("accounts")
+        );
+        assert_eq!(
+            entity_from_link(
+                "https://org.crm.dynamics.com/api/data/v9.2/accounts(1)/contacts",
+                endpoint
+            )
+            .as_deref(),
+            Some("accounts")
+        );
+    }
+
+    #[test]
+    fn navigation_from_link_needs_a_record_and_one_relationship() {
+        let endpoint = "https://org.crm.dynamics.com/api/data/v9.2/";
+        let navigation = |path: &str| navigation_from_link(&format!("{endpoint}{path}"), endpoint);
+
+        assert_eq!(
+            navigation("accounts(1)/contact_customer_accounts?$skiptoken=x"),
+            Some((
+                "accounts".to_string(),
+                "contact_customer_accounts".to_string()
+            ))
+        );
+        assert_eq!(navigation("accounts?$skiptoken=x"), None);
+        assert_eq!(navigation("accounts/contacts"), None);
+        assert_eq!(navigation("accounts(1)/contacts(2)/opportunities"), None);
+    }
+
+    #[tokio::test]
+    async fn related_page_tokens_must_continue_the_relationship() {
+        let server = test_server(true);
+        let endpoint = server.client().endpoint().to_string();
+        let next_page = |path: &str| {
+            HashMap::from([
+                ("entity".to_string(), json!("accounts")),
+                (
+                    "relationship".to_string(),
+                    json!("contact_customer_accounts"),
+                ),
+                (
+                    "page_token".to_string(),
+                    json!(encode_page_token(&format!("{endpoint}{path}"))),
+                ),
+            ])
+        };
+
+        for path in [
+            "accounts(1)/opportunity_customer_accounts?$skiptoken=2",
+            "opportunities?$skiptoken=2",
+        ] {
+            let result = server.call_tool("query_related", &next_page(path)).await;
+            assert_eq!(result.is_error, Some(true));
+            assert_eq!(
+                result.content[0].text,
+                "Invalid page_token: it does not continue contact_customer_accounts of accounts"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_delete_key_prefers_raw_key_expression() {
+        let mut args = HashMap::new();
+        args.insert(
+            "key".to_string(),
+            json!("dataAreaId='bc',SalesOrderNumber='SO-001'"),
+        );
+        args.insert("id".to_string(), json!("ignored"));
+
+        assert_eq!(
+            parse_delete_key(&args, &ProductType::Finops).unwrap(),
+            "dataAreaId='bc',SalesOrderNumber='SO-001'"
+        );
+    }
+
+    #[test]
+    fn parse_delete_key_formats_simple_string_id() {
+        let mut args = HashMap::new();
+        args.insert("id".to_string(), json!("CUS-001"));
+
+        assert_eq!(
+            parse_delete_key(&args, &ProductType::Finops).unwrap(),
+            "'CUS-001'"
+        );
+    }
+
+    #[test]
+    fn parse_delete_key_keeps_numeric_id_unquoted() {
+        let mut args = HashMap::new();
+        args.insert("id".to_string(), json!("5637144576"));
+
+        assert_eq!(
+            parse_delete_key(&args, &ProductType::Finops).unwrap(),
+            "5637144576"
+        );
+    }
+
+    #[test]
+    fn page_token_round_trips_for_configured_endpoint() {
+        let endpoint = "https://org.crm.dynamics.com/api/data/v9.2/";
+        let link = "https://org.crm.dynamics.com/api/data/v9.2/accounts?$skiptoken=%3Ccookie%3E";
+
+        let token = encode_page_token(link);
+        assert_eq!(decode_page_token(&token, endpoint).unwrap(), link);
+    }
+
+    #[test]
+    fn page_token_rejects_foreign_hosts_and_paths() {
+        let endpoint = "https://org.crm.dynamics.com/api/data/v9.2/";
+
+        for link in [
+            "https://evil.example.com/api/data/v9.2/accounts",
+            "http://org.crm.dynamics.com/api/data/v9.2/accounts",
+            "https://org.crm.dynamics.com:8443/api/data/v9.2/accounts",
+            "https://org.crm.dynamics.com/other/accounts",
+        ] {
+            let err = decode_page_token(&encode_page_token(link), endpoint).unwrap_err();
+            assert!(err.contains("does not belong"), "{link}: {err}");
+        }
+
+        assert!(decode_page_token("not base64!", endpoint).is_err());
+    }
+
+    fn numbered_records(count: usize) -> Vec<Value> {
+        (0..count)
+            .map(|n| json!({"accountid": n, "name": format!("Account {n}"), "address": {"city": "Oslo"}}))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn query_output_is_cut_at_record_boundaries() {
+        let mock = wiremock::MockServer::start().await;
+        wiremock::Mock::given(wiremock::matchers::method("GET"))
+            .and(wiremock::matchers::path("/data/accounts"))
+            .respond_with(
+                wiremock::ResponseTemplate::new(200)
+                    .set_body_json(json!({"value": numbered_records(200)})),
+            )
+            .mount(&mock)
+            .await;
+
+        let server = test_server(true);
+        let client = ODataClient::new(
+            Arc::new(StaticTokenProvider::new("test-token")),
+            format!("{}/data/", mock.uri()),
+            ProductType::Dataverse,
+            0,
+            10,
+            false,
+        );
+        let mut config = (*server.config()).clone();
+        config.max_response_chars = 2_000;
+        let server = D365McpServer::new(Arc::new(client), Arc::new(config));
+
+        let mut args = HashMap::new();
+        args.insert("entity".to_string(), json!("accounts"));
+        let result = server.call_tool("query_entity", &args).await;
+        let text = &result.content[0].text;
+
+        assert!(text.chars().count() <= 2_000, "{}", text.len());
+        let (body, note) = text.split_once("\n\n[output truncated").unwrap();
+        assert!(note.contains("of 200 records shown"), "{note}");
+
+        let json = &body[body.find('[').unwrap()..];
+        let records: Vec<Value> = serde_json::from_str(json).unwrap();
+        assert!(!records.is_empty());
+        assert!(
+            note.contains(&format!("{} of 200", records.len())),
+            "{note}"
+        );
+
+        let structured = result.structured_content.unwrap();
+        assert_eq!(structured["records"], json!(records));
+        assert_eq!(structured["truncated"], true);
+        assert_eq!(structured["next_page_token"], Value::Null);
+    }
+
+    #[test]
+    fn other_output_is_truncated_with_notice() {
+        let result = truncate_result(CallToolResult::text("é".repeat(1_000)), 500);
+        let text = &result.content[0].text;
+
+        assert!(text.chars().count() <= 500);
+        assert!(text.starts_with("éé"));
+        assert!(text.ends_with("narrow the request with $select or $top]"));
+
+        let short = truncate_result(CallToolResult::text("short".to_string()), 500);
+        assert_eq!(short.content[0].text, "short");
+    }
+
+    const METADATA: &str = r#"<edmx:Edmx>
+<Schema Namespace="Microsoft.Dynamics.CRM">
+<EnumType Name="ComponentState">
+<Member Name="Published" Value="0" />
+<Member Name="Deleted" Value="2" />
+</EnumType>
+<EntityType Name="account">
+<Key>
+<PropertyRef Name="accountid" />
+</Key>
+<Annotation Term="Org.OData.Core.V1.Description" String="Business that represents a customer" />
+<Property Name="accountid" Type="Edm.Guid" />
+<Property Name="name" Type="Edm.String" />
+</EntityType>
+<EntityType Name="contact">
+<Key>
+<PropertyRef Name="contactid" />
+</Key>
+<Property Name="contactid" Type="Edm.Guid" />
+<Property Name="_parentcustomerid_value" Type="Edm.Guid" />
+<NavigationProperty Name="parentcustomerid_account" Type="Microsoft.Dynamics.CRM.account">
+<ReferentialConstraint Property="_parentcustomerid_value" ReferencedProperty="accountid" />
+</NavigationProperty>
+</EntityType>
+<EntitySet Name="accounts" EntityType="Microsoft.Dynamics.CRM.account" />
+<EntitySet Name="contacts" EntityType="Microsoft.Dynamics.CRM.contact" />
+</Schema>
+</edmx:Edmx>"#;
+
+    async fn metadata_server() -> wiremock::MockServer {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/$metadata"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(METADATA))
+            .mount(&server)
+            .await;
+        server
+    }
+
+    #[tokio::test]
+    async fn results_name_the_entity_type_of_each_record() {
+        use wiremock::matchers::{method, path, path_regex};
+        use wiremock::{Mock, ResponseTemplate};
+
+        let d365 = metadata_server().await;
+        let context = format!(
+            "{}/data/$metadata#accounts(name,parentcustomerid)",
+            d365.uri()
+        );
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "@odata.context": context,
+                "value": [
+                    {"name": "Contoso", "parentcustomerid": {
+                        "@odata.type": "#Microsoft.Dynamics.CRM.contact", "fullname": "Jane Doe"}},
+                    {"@odata.type": "#Microsoft.Dynamics.CRM.competitor", "name": "Litware"}
+                ]
+            })))
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path_regex("accounts\\(.*\\)$"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "@odata.context": format!("{}/data/$metadata#accounts/$entity", d365.uri()),
+                "@odata.etag": "W/\"7\"",
+                "name": "Contoso"
+            })))
+            .mount(&d365)
+            .await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+        let args = HashMap::from([("entity".to_string(), json!("accounts"))]);
+
+        // Before $metadata is cached only stated types are known
+        let result = server.call_tool("query_entity", &args).await;
+        let records = result.structured_content.unwrap()["records"].clone();
+        assert_eq!(records[0].get("_entity_type"), None);
+        assert_eq!(records[0]["parentcustomerid"]["_entity_type"], "contact");
+        assert_eq!(records[1]["_entity_type"], "competitor");
+        assert_eq!(records[1].get("@odata.type"), None);
+
+        server.client().metadata_model().await.unwrap();
+        let result = server.call_tool("query_entity", &args).await;
+        let records = result.structured_content.unwrap()["records"].clone();
+        assert_eq!(records[0]["_entity_type"], "account");
+        assert_eq!(records[1]["_entity_type"], "competitor");
+
+        let args = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            (
+                "id".to_string(),
+                json!("00000000-0000-0000-0000-000000000001"),
+            ),
+        ]);
+        let result = server.call_tool("get_record", &args).await;
+        assert!(
+            result.content[0]
+                .text
+                .starts_with("type: account\netag: W/\"7\"\n\n{"),
+            "{}",
+            result.content[0].text
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_restricted_in_metadata_are_rejected_before_sending() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/$metadata"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(include_str!(
+                "../odata/testdata/capability-restrictions.xml"
+            )))
+            .mount(&d365)
+            .await;
+        Mock::given(method("DELETE"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(2)
+            .mount(&d365)
+            .await;
+        let finops = finops_server_at(&format!("{}/data/", d365.uri()));
+        let mut config = (*finops.config()).clone();
+        config.read_only = false;
+        let server = D365McpServer::new(finops.client(), Arc::new(config));
+        let delete = |entity: &str, key: &str| {
+            HashMap::from([
+                ("entity".to_string(), json!(entity)),
+                ("key".to_string(), json!(key)),
+                ("confirm".to_string(), json!("DELETE")),
+            ])
+        };
+
+        // Without cached $metadata nothing is known, so the write goes out
+        let result = server
+            .call_tool(
+                "delete_record",
+                &delete("InventOnhandV2", "ItemNumber='A1'"),
+            )
+            .await;
+        assert_ne!(result.is_error, Some(true), "{:?}", result.content);
+
+        server.client().metadata_model().await.unwrap();
+        let result = server
+            .call_tool(
+                "delete_record",
+                &delete("InventOnhandV2", "ItemNumber='A1'"),
+            )
+            .await;
+        assert_eq!(result.is_error, Some(true));
+        let text = &result.content[0].text;
+        assert!(
+            text.starts_with("'InventOnhandV2' does not allow delete: its DeleteRestrictions"),
+            "{}",
+            text
+        );
+        assert!(
+            text.contains("writable: insert ✖ update ✖ delete ✖"),
+            "{}",
+            text
+        );
+
+        // Unrestricted sets accept every write
+        let result = server
+            .call_tool(
+                "delete_record",
+                &delete("CustomersV3", "CustomerAccount='C1'"),
+            )
+            .await;
+        assert_ne!(result.is_error, Some(true), "{:?}", result.content);
+
+        // An upsert that may only insert is rejected where inserts are not allowed
+        let mut upsert = HashMap::from([
+            ("entity".to_string(), json!("msdyn_externalorders")),
+            (
+                "id".to_string(),
+                json!("00000000-0000-0000-0000-000000000001"),
+            ),
+            ("data".to_string(), json!({"msdyn_name": "Order"})),
+            ("prevent_update".to_string(), json!(true)),
+        ]);
+        let result = server.call_tool("upsert_record", &upsert).await;
+        assert!(
+            result.content[0]
+                .text
+                .contains("does not allow insert: its InsertRestrictions"),
+            "{}",
+            result.content[0].text
+        );
+        upsert.remove("prevent_update");
+        upsert.insert("dry_run".to_string(), json!(true));
+        let result = server.call_tool("upsert_record", &upsert).await;
+        assert_ne!(result.is_error, Some(true), "{:?}", result.content);
+
+        let args = HashMap::from([("entity".to_string(), json!("inventonhandv2"))]);
+        let result = server.call_tool("get_metadata", &args).await;
+        assert!(result.content[0]
+            .text
+            .contains("Writable: insert ✖ update ✖ delete ✖\n"));
+        assert_eq!(
+            result.structured_content.unwrap()["writable"],
+            json!({"insert": false, "update": false, "delete": false})
+        );
+        let args = HashMap::from([("entity".to_string(), json!("CustomersV3"))]);
+        let result = server.call_tool("get_metadata", &args).await;
+        assert!(result.content[0]
+            .text
+            .contains("Writable: insert ✔ update ✔ delete ✔ (no restrictions in $metadata)"));
+    }
+
+    #[tokio::test]
+    async fn unknown_entities_have_no_metadata() {
+        let d365 = metadata_server().await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+
+        let args = HashMap::from([("entity".to_string(), json!("invoices"))]);
+        let result = server.call_tool("get_metadata", &args).await;
+        assert_eq!(result.is_error, Some(true));
+        assert!(result.structured_content.is_none());
+        assert_eq!(
+            result.content[0].text,
+            "Entity 'invoices' not found in metadata"
+        );
+    }
+
+    #[tokio::test]
+    async fn metadata_is_checked_under_the_resolved_set_name() {
+        let d365 = metadata_server().await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+        let mut config = (*server.config()).clone();
+        config.denied_entities = vec!["accounts".to_string()];
+        let server = D365McpServer::new(server.client(), Arc::new(config));
+
+        let mut args = HashMap::from([("entity".to_string(), json!("account"))]);
+        let result = server.call_tool("get_metadata", &args).await;
+        assert_eq!(result.is_error, Some(true));
+        assert!(result.content[0].text.contains("denied_entities"));
+
+        args.insert("source".to_string(), json!("metadata"));
+        let result = server.call_tool("get_entity_schema", &args).await;
+        assert_eq!(result.is_error, Some(true));
+        assert!(result.content[0].text.contains("denied_entities"));
+    }
+
+    #[tokio::test]
+    async fn empty_entities_get_their_schema_from_metadata() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, ResponseTemplate};
+
+        let d365 = metadata_server().await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+            .mount(&d365)
+            .await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+
+        let mut args = HashMap::new();
+        args.insert("entity".to_string(), json!("accounts"));
+        let text = server.call_tool("get_entity_schema", &args).await.content[0]
+            .text
+            .clone();
+        assert!(
+            text.starts_with("Source: $metadata (no records found in entity 'accounts')"),
+            "{text}"
+        );
+        assert!(text.contains("### Key Fields\n- accountid"), "{text}");
+        assert!(text.contains("- name: String"), "{text}");
+
+        // No request for contacts records is made, so none is mocked
+        args.insert("entity".to_string(), json!("contacts"));
+        args.insert("source".to_string(), json!("metadata"));
+        let text = server.call_tool("get_entity_schema", &args).await.content[0]
+            .text
+            .clone();
+        assert!(text.starts_with("Source: $metadata\n"), "{text}");
+        assert!(
+            text.contains("parentcustomerid_account -> account"),
+            "{text}"
+        );
+    }
+
+    #[tokio::test]
+    async fn saved_views_run_with_their_fetch_xml_and_column_order() {
+        use wiremock::matchers::{method, path, query_param, query_param_contains};
+        use wiremock::{Mock, ResponseTemplate};
+
+        const VIEW_ID: &str = "00000000-0000-0000-00aa-000010001002";
+        let d365 = metadata_server().await;
+        Mock::given(method("GET"))
+            .and(path("/data/savedqueries"))
+            .and(query_param_contains(
+                "$filter",
+                "returnedtypecode eq 'account'",
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": [{
+                "savedqueryid": VIEW_ID,
+                "name": "My Active Accounts",
+                "returnedtypecode": "account",
+                "isdefault": true
+            }]})))
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/userqueries"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(format!("/data/savedqueries({})", VIEW_ID)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "savedqueryid": VIEW_ID,
+                "name": "My Active Accounts",
+                "returnedtypecode": "account",
+                "fetchxml": "<fetch version=\"1.0\"><entity name=\"account\"><attribute name=\"name\" /></entity></fetch>",
+                "layoutxml": "<grid><row><cell name=\"telephone1\" /><cell name=\"name\" /><cell name=\"primarycontactid\" /></row></grid>"
+            })))
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .and(query_param(
+                "fetchXml",
+                "<fetch count=\"2\" version=\"1.0\"><entity name=\"account\"><attribute name=\"name\" /></entity></fetch>",
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "@Microsoft.Dynamics.CRM.morerecords": true,
+                "value": [
+                    {"name": "Contoso", "telephone1": "555-0100", "_primarycontactid_value": "c1",
+                     "_primarycontactid_value@OData.Community.Display.V1.FormattedValue": "Jane Doe"},
+                    {"name": "Fabrikam", "telephone1": null}
+                ]
+            })))
+            .mount(&d365)
+            .await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+
+        let mut args = HashMap::new();
+        args.insert("entity".to_string(), json!("accounts"));
+        let text = result_text(&server.call_tool("list_views", &args).await);
+        assert!(
+            text.contains(&format!(
+                "My Active Accounts [system, default] view_id: {}",
+                VIEW_ID
+            )),
+            "{text}"
+        );
+
+        let mut args = HashMap::new();
+        args.insert("view_id".to_string(), json!(VIEW_ID));
+        args.insert("top".to_string(), json!(2));
+        let text = server.call_tool("run_view", &args).await.content[0]
+            .text
+            .clone();
+        assert!(
+            text.starts_with("View: My Active Accounts (system view of accounts)\n"),
+            "{text}"
+        );
+        assert!(text.contains("more available"), "{text}");
+        assert!(
+            text.contains("| telephone1 | name | _primarycontactid_value_label |"),
+            "{text}"
+        );
+        assert!(text.contains("| 555-0100 | Contoso | Jane Doe |"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn views_cannot_join_denied_tables() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, ResponseTemplate};
+
+        const VIEW_ID: &str = "00000000-0000-0000-00aa-000010001003";
+        let d365 = metadata_server().await;
+        Mock::given(method("GET"))
+            .and(path(format!("/data/savedqueries({})", VIEW_ID)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "savedqueryid": VIEW_ID,
+                "name": "Contacts with Account Names",
+                "returnedtypecode": "contact",
+                "fetchxml": "<fetch><entity name=\"contact\"><link-entity name=\"account\" from=\"accountid\" \
+                             to=\"parentcustomerid\"><attribute name=\"name\" /></link-entity></entity></fetch>",
+                "layoutxml": "<grid><row><cell name=\"fullname\" /></row></grid>"
+            })))
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/contacts"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+            .expect(0)
+            .mount(&d365)
+            .await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+        let mut config = (*server.config()).clone();
+        config.denied_entities = vec!["accounts".to_string()];
+        let server = D365McpServer::new(server.client(), Arc::new(config));
+
+        let args = HashMap::from([("view_id".to_string(), json!(VIEW_ID))]);
+        let result = server.call_tool("run_view", &args).await;
+        assert_eq!(result.is_error, Some(true));
+        assert!(
+            result.content[0].text.contains("'accounts' is blocked"),
+            "{}",
+            result.content[0].text
+        );
+    }
+
+    #[tokio::test]
+    async fn distinct_values_are_grouped_by_dataverse() {
+        use wiremock::matchers::{method, path, query_param, query_param_is_missing};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .and(query_param(
+                "$apply",
+                "filter(statecode eq 0)/groupby((paymenttermscode))",
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": [
+                    {"paymenttermscode": 2,
+                     "paymenttermscode@OData.Community.Display.V1.FormattedValue": "2% 10, Net 30"},
+                    {"paymenttermscode": null},
+                    {"paymenttermscode": 1,
+                     "paymenttermscode@OData.Community.Display.V1.FormattedValue": "Net 30"}
+                ]
+            })))
+            .mount(&d365)
+            .await;
+        // A table $apply cannot group falls back to reading the column
+        Mock::given(method("GET"))
+            .and(path("/data/incidents"))
+            .and(query_param("$apply", "groupby((prioritycode))"))
+            .respond_with(ResponseTemplate::new(400).set_body_string("$apply is not supported"))
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/incidents"))
+            .and(query_param_is_missing("$apply"))
+            .and(query_param("$select", "prioritycode"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": [{"prioritycode": 3}, {"prioritycode": 1}, {"prioritycode": 3}]
+            })))
+            .mount(&d365)
+            .await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+
+        let args: HashMap<String, Value> = serde_json::from_value(json!({
+            "entity": "accounts",
+            "field": "paymenttermscode",
+            "filter": "statecode eq 0"
+        }))
+        .unwrap();
+        let result = server.call_tool("distinct_values", &args).await;
+        assert_eq!(
+            result.content[0].text,
+            "3 distinct value(s) of paymenttermscode in accounts, de-duplicated by the server ($apply=groupby):\n\
+             - (empty)\n\
+             - 1 (Net 30)\n\
+             - 2 (2% 10, Net 30)"
+        );
+        let structured = result.structured_content.unwrap();
+        assert_eq!(structured["values"], json!([null, 1, 2]));
+        assert_eq!(structured["deduplicated_by"], "server");
+
+        let args: HashMap<String, Value> = serde_json::from_value(json!({
+            "entity": "incidents",
+            "field": "prioritycode"
+        }))
+        .unwrap();
+        let result = server.call_tool("distinct_values", &args).await;
+        assert_eq!(
+            result.content[0].text,
+            "2 distinct value(s) of prioritycode in incidents, de-duplicated client-side from 3 records \
+             (the service rejected $apply=groupby):\n- 1\n- 3"
+        );
+        assert_eq!(
+            result.structured_content.unwrap()["deduplicated_by"],
+            "client"
+        );
+
+        let args: HashMap<String, Value> = serde_json::from_value(json!({
+            "entity": "accounts",
+            "field": "name) or (1 eq 1"
+        }))
+        .unwrap();
+        let result = server.call_tool("distinct_values", &args).await;
+        assert!(result.is_error.unwrap_or(false));
+    }
+
+    #[tokio::test]
+    async fn distinct_values_stop_at_the_record_cap_for_finops() {
+        use wiremock::matchers::{method, path, query_param, query_param_is_missing};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let fno = MockServer::start().await;
+        let endpoint = format!("{}/data/", fno.uri());
+        Mock::given(method("GET"))
+            .and(path("/data/CustomersV3"))
+            .and(query_param("$select", "PaymentTerms"))
+            .and(query_param("$filter", "dataAreaId eq 'usmf'"))
+            .and(query_param_is_missing("$skiptoken"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "@odata.nextLink": format!("{}CustomersV3?$select=PaymentTerms&$skiptoken=2", endpoint),
+                "value": [{"PaymentTerms": "Net30"}, {"PaymentTerms": "COD"}]
+            })))
+            .mount(&fno)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/CustomersV3"))
+            .and(query_param("$skiptoken", "2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": [{"PaymentTerms": "Net30"}, {"PaymentTerms": "Net10"}]
+            })))
+            .mount(&fno)
+            .await;
+        let finops = finops_server_at(&endpoint);
+        let mut config = (*finops.config()).clone();
+        config.distinct_values_limit = 3;
+        let server = D365McpServer::new(finops.client(), Arc::new(config));
+
+        let args: HashMap<String, Value> = serde_json::from_value(json!({
+            "entity": "CustomersV3",
+            "field": "PaymentTerms",
+            "company": "usmf"
+        }))
+        .unwrap();
+        let result = server.call_tool("distinct_values", &args).await;
+        assert_eq!(
+            result.content[0].text,
+            "2 distinct value(s) of PaymentTerms in CustomersV3, de-duplicated client-side from 3 records:\n\
+             - COD\n\
+             - Net30\n\n\
+             [stopped after DISTINCT_VALUES_LIMIT = 3 records; there may be more values, narrow with filter]"
+        );
+        let structured = result.structured_content.unwrap();
+        assert_eq!(structured["values"], json!(["COD", "Net30"]));
+        assert_eq!(structured["records_read"], 3);
+        assert_eq!(structured["truncated"], true);
+        // The second page was cut to fit, so no third page is asked for
+        assert_eq!(fno.received_requests().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn dry_runs_show_the_request_without_sending_it() {
+        let d365 = wiremock::MockServer::start().await;
+        let endpoint = format!("{}/data/", d365.uri());
+        let server = server_at(&endpoint, false);
+
+        let args: HashMap<String, Value> = serde_json::from_value(json!({
+            "entity": "accounts",
+            "filter": "name eq 'Contoso'",
+            "top": 5,
+            "dry_run": true
+        }))
+        .unwrap();
+        let text = server.call_tool("query_entity", &args).await.content[0]
+            .text
+            .clone();
+        assert!(
+            text.contains(&format!(
+                "GET {}accounts?$filter=name%20eq%20'Contoso'&$top=5\n",
+                endpoint
+            )),
+            "{text}"
+        );
+        assert!(text.contains("OData-Version: 4.0"), "{text}");
+        assert!(!text.contains("Bearer"), "{text}");
+
+        // dry_run_all_writes covers writes even without the argument
+        let mut config = (*server.config()).clone();
+        config.dry_run_all_writes = true;
+        let server = D365McpServer::new(server.client(), Arc::new(config));
+        let args: HashMap<String, Value> = serde_json::from_value(json!({
+            "entity": "accounts",
+            "key_field": "accountnumber",
+            "key_value": "A-1",
+            "data": {"name": "Contoso"},
+            "prevent_update": true
+        }))
+        .unwrap();
+        let text = server.call_tool("upsert_record", &args).await.content[0]
+            .text
+            .clone();
+        assert!(
+            text.contains(&format!(
+                "PATCH {}accounts(accountnumber='A-1')\n",
+                endpoint
+            )),
+            "{text}"
+        );
+        assert!(text.contains("If-None-Match: *\n"), "{text}");
+        assert!(text.contains("\"name\": \"Contoso\""), "{text}");
+
+        assert!(d365.received_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn etags_are_surfaced_for_conditional_writes() {
+        // The second record has no ETag, and the single record's body omits
+        // it while the header carries it
+        let server = replay_server("etags_are_surfaced_for_conditional_writes");
+
+        let mut args = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            ("format".to_string(), json!("table")),
+        ]);
+        let result = server.call_tool("query_entity", &args).await;
+        let text = &result.content[0].text;
+        assert!(text.contains("| name | etag |"), "{text}");
+        assert!(text.contains("| Contoso | W/\"1\" |"), "{text}");
+        assert_eq!(
+            result.structured_content.unwrap()["etags"],
+            json!(["W/\"1\"", null])
+        );
+
+        args.remove("format");
+        args.insert(
+            "id".to_string(),
+            json!("5f2a1c3e-8b7d-4e6f-9a0b-1c2d3e4f5a6b"),
+        );
+        let result = server.call_tool("get_record", &args).await;
+        assert!(
+            result.content[0].text.starts_with("etag: W/\"9\"\n"),
+            "{}",
+            result.content[0].text
+        );
+        assert_eq!(result.structured_content.unwrap()["etag"], "W/\"9\"");
+    }
+
+    #[tokio::test]
+    async fn entity_names_resolve_through_metadata_in_any_case() {
+        // Anything but the exact set name is a 404, as on the real service
+        let server = replay_server("entity_names_resolve_through_metadata_in_any_case");
+
+        let mut args = HashMap::new();
+        args.insert("entity".to_string(), json!("Account"));
+        let result = server.call_tool("query_entity", &args).await;
+        assert!(
+            !result.is_error.unwrap_or(false),
+            "{}",
+            result_text(&result)
+        );
+        assert_eq!(result.structured_content.unwrap()["entity"], "accounts");
+
+        args.insert("entity".to_string(), json!("ACCOUNTS"));
+        let result = server.call_tool("get_metadata", &args).await;
+        assert!(result.content[0].text.starts_with("## Entity: accounts\n"));
+        assert_eq!(result.structured_content.unwrap()["entity_type"], "account");
+
+        args.insert("entity".to_string(), json!("Contacts"));
+        args.insert("source".to_string(), json!("metadata"));
+        let result = server.call_tool("get_entity_schema", &args).await;
+        assert!(result.content[0].text.contains("## Entity: contacts"));
+
+        args.remove("source");
+        args.insert("entity".to_string(), json!("leads"));
+        let result = server.call_tool("query_entity", &args).await;
+        assert!(result.is_error.unwrap_or(false));
+        assert!(
+            result_text(&result).contains("Not found"),
+            "{}",
+            result_text(&result)
+        );
+    }
+
+    #[tokio::test]
+    async fn resources_list_entities_within_the_policy() {
+        let d365 = metadata_server().await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+
+        let uris: Vec<String> = server
+            .list_resources()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|resource| resource.uri)
+            .collect();
+        assert_eq!(
+            uris,
+            vec![
+                "d365://metadata",
+                "d365://entity/accounts/schema",
+                "d365://entity/contacts/schema"
+            ]
+        );
+
+        let mut config = (*server.config()).clone();
+        config.allowed_entities = vec!["account*".to_string()];
+        let restricted = D365McpServer::new(server.client(), Arc::new(config));
+        let uris: Vec<String> = restricted
+            .list_resources()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|resource| resource.uri)
+            .collect();
+        assert_eq!(uris, vec!["d365://entity/accounts/schema"]);
+        assert_eq!(
+            restricted
+                .read_resource("d365://metadata")
+                .await
+                .unwrap_err()
+                .code,
+            -32602
+        );
+    }
+
+    #[tokio::test]
+    async fn read_resource_returns_schema_markdown_or_raw_metadata() {
+        let d365 = metadata_server().await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+
+        let schema = server
+            .read_resource("d365://entity/account/schema")
+            .await
+            .unwrap();
+        let contents = &schema.contents[0];
+        assert_eq!(contents.mime_type.as_deref(), Some("text/markdown"));
+        assert!(contents.text.starts_with("## Entity: account"));
+        assert!(contents.text.contains("- name: String"));
+
+        let raw = server.read_resource("d365://metadata").await.unwrap();
+        assert_eq!(raw.contents[0].text, METADATA);
+
+        let missing = server.read_resource("d365://entity/").await.unwrap_err();
+        assert_eq!(missing.code, RESOURCE_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn every_tool_rejects_unknown_and_missing_arguments() {
+        let server = test_server(false);
+
+        for tool in D365McpServer::get_tools_static() {
+            let args = HashMap::from([("bogus".to_string(), json!("x"))]);
+            let result = server.call_tool(&tool.name, &args).await;
+            let text = result_text(&result);
+
+            assert_eq!(result.is_error, Some(true), "{}", tool.name);
+            assert!(text.contains("unknown argument 'bogus'"), "{text}");
+            for required in tool.input_schema["required"].as_array().unwrap() {
+                let expected =
+                    format!("missing required argument '{}'", required.as_str().unwrap());
+                assert!(text.contains(&expected), "{}: {text}", tool.name);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn misspelled_arguments_get_a_suggestion() {
+        let server = test_server(true);
+        let args = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            ("filters".to_string(), json!("name eq 'x'")),
+            ("top".to_string(), json!({"value": 5})),
+        ]);
+
+        let result = server.call_tool("query_entity", &args).await;
+        let text = result_text(&result);
+
+        assert_eq!(result.is_error, Some(true));
+        assert!(
+            text.contains("unknown argument 'filters' (did you mean 'filter'?)"),
+            "{text}"
+        );
+        assert!(text.contains("argument 'top' must be an integer"), "{text}");
+    }
+
+    #[test]
+    fn argument_helpers_accept_native_and_stringly_values() {
+        let args: HashMap<String, Value> = serde_json::from_value(json!({
+            "top": 25,
+            "skip": " 10 ",
+            "count": "TRUE",
+            "cross_company": false,
+            "search": "contoso",
+            "bad": -1
+        }))
+        .unwrap();
+
+        assert_eq!(get_usize(&args, "top"), Some(25));
+        assert_eq!(get_usize(&args, "skip"), Some(10));
+        assert_eq!(get_usize(&args, "bad"), None);
+        assert_eq!(get_bool(&args, "count"), Some(true));
+        assert_eq!(get_bool(&args, "cross_company"), Some(false));
+        assert_eq!(get_bool(&args, "missing"), None);
+
+        let options = parse_query_options(&args).unwrap();
+        assert_eq!(options.top, Some(25));
+        assert!(options.count);
+        assert_eq!(options.search.as_deref(), Some("contoso"));
+    }
+
+    #[tokio::test]
+    async fn expand_cannot_reach_denied_entities() {
+        let d365 = metadata_server().await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+        let mut config = (*server.config()).clone();
+        config.denied_entities = vec!["accounts".to_string()];
+        let restricted = D365McpServer::new(server.client(), Arc::new(config));
+
+        let args = HashMap::from([
+            ("entity".to_string(), json!("contacts")),
+            (
+                "expand".to_string(),
+                json!("parentcustomerid_account($select=name)"),
+            ),
+        ]);
+        let result = restricted.call_tool("query_entity", &args).await;
+        assert_eq!(result.is_error, Some(true));
+        assert!(
+            result_text(&result).contains("accounts"),
+            "{}",
+            result_text(&result)
+        );
+
+        let args = HashMap::from([
+            ("entity".to_string(), json!("contacts")),
+            ("expand".to_string(), json!("unknown_nav")),
+        ]);
+        let result = restricted.call_tool("query_entity", &args).await;
+        assert_eq!(result.is_error, Some(true));
+        assert!(
+            result_text(&result).contains("Cannot check expand 'unknown_nav'"),
+            "{}",
+            result_text(&result)
+        );
+    }
+
+    #[tokio::test]
+    async fn relationships_are_listed_in_both_directions_within_the_policy() {
+        let d365 = metadata_server().await;
+        let endpoint = format!("{}/data/", d365.uri());
+        let server = server_at(&endpoint, true);
+
+        let args = HashMap::from([("entity".to_string(), json!("accounts"))]);
+        let accounts = result_text(&server.call_tool("describe_relationships", &args).await);
+        assert!(accounts.contains("Outbound (0)"), "{accounts}");
+        assert!(accounts.contains("Inbound (1)"), "{accounts}");
+        assert!(
+            accounts.contains(
+                "contacts.parentcustomerid_account (single); \
+                 FK contact._parentcustomerid_value = account.accountid; \
+                 expandable from contacts with $expand=parentcustomerid_account"
+            ),
+            "{accounts}"
+        );
+
+        let args = HashMap::from([("entity".to_string(), json!("contacts"))]);
+        let contacts = result_text(&server.call_tool("describe_relationships", &args).await);
+        assert!(
+            contacts.contains("parentcustomerid_account → accounts (account), single"),
+            "{contacts}"
+        );
+
+        let mut config = (*server.config()).clone();
+        config.denied_entities = vec!["contacts".to_string()];
+        let restricted = D365McpServer::new(server.client(), Arc::new(config));
+        let args = HashMap::from([("entity".to_string(), json!("accounts"))]);
+        let text = result_text(&restricted.call_tool("describe_relationships", &args).await);
+        assert!(text.contains("Inbound (0)"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn dataverse_records_are_read_by_bare_guid_or_alternate_key() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/contacts(5f2a1c3e-8b7d-4e6f-9a0b-1c2d3e4f5a6b)"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"fullname": "By id"})))
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/contacts(emailaddress1='a@b.com')"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"fullname": "By email"})))
+            .mount(&d365)
+            .await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+
+        let args = HashMap::from([
+            ("entity".to_string(), json!("contacts")),
+            (
+                "id".to_string(),
+                json!("5f2a1c3e-8b7d-4e6f-9a0b-1c2d3e4f5a6b"),
+            ),
+        ]);
+        let text = result_text(&server.call_tool("get_record", &args).await);
+        assert!(text.contains("By id"), "{text}");
+
+        let args = HashMap::from([
+            ("entity".to_string(), json!("contacts")),
+            ("id".to_string(), json!("a@b.com")),
+            ("key_field".to_string(), json!("emailaddress1")),
+        ]);
+        let text = result_text(&server.call_tool("get_record", &args).await);
+        assert!(text.contains("By email"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn associate_links_records_within_the_policy() {
+        use wiremock::matchers::{body_json, method, path};
+        use wiremock::{Mock, ResponseTemplate};
+
+        let d365 = metadata_server().await;
+        let endpoint = format!("{}/data/", d365.uri());
+        Mock::given(method("PUT"))
+            .and(path("/data/contacts(00000000-0000-0000-0000-0000000000c1)/parentcustomerid_account/$ref"))
+            .and(body_json(json!({
+                "@odata.id": format!("{}accounts(00000000-0000-0000-0000-0000000000a1)", endpoint)
+            })))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&d365)
+            .await;
+        let server = server_at(&endpoint, false);
+
+        let args = HashMap::from([
+            ("entity".to_string(), json!("contacts")),
+            (
+                "id".to_string(),
+                json!("00000000-0000-0000-0000-0000000000c1"),
+            ),
+            (
+                "navigation_property".to_string(),
+                json!("parentcustomerid_account"),
+            ),
+            ("target_entity".to_string(), json!("accounts")),
+            (
+                "target_id".to_string(),
+                json!("00000000-0000-0000-0000-0000000000a1"),
+            ),
+        ]);
+        let text = result_text(&server.call_tool("associate_records", &args).await);
+        assert!(text.contains("Linked contacts("), "{text}");
+
+        let mut config = (*server.config()).clone();
+        config.denied_entities = vec!["accounts".to_string()];
+        let restricted = D365McpServer::new(server.client(), Arc::new(config));
+        let result = restricted.call_tool("associate_records", &args).await;
+        assert_eq!(result.is_error, Some(true));
+
+        let finops = finops_server_at(&endpoint);
+        let mut config = (*finops.config()).clone();
+        config.read_only = false;
+        let finops = D365McpServer::new(finops.client(), Arc::new(config));
+        let text = result_text(&finops.call_tool("associate_records", &args).await);
+        assert!(text.contains("only available for Dataverse"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn upsert_output_says_whether_the_record_was_created() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("PATCH"))
+            .and(path("/data/accounts(accountnumber='00042')"))
+            .respond_with(ResponseTemplate::new(201))
+            .up_to_n_times(1)
+            .mount(&d365)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path("/data/accounts(accountnumber='00042')"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&d365)
+            .await;
+        let server = server_at(&format!("{}/data/", d365.uri()), false);
+
+        let args = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            ("key_field".to_string(), json!("accountnumber")),
+            ("key_value".to_string(), json!("00042")),
+            ("data".to_string(), json!({"name": "Contoso"})),
+        ]);
+        let first = result_text(&server.call_tool("upsert_record", &args).await);
+        assert!(
+            first.contains("Created new record accounts(accountnumber='00042')"),
+            "{first}"
+        );
+        let second = result_text(&server.call_tool("upsert_record", &args).await);
+        assert!(second.contains("Updated existing record"), "{second}");
+
+        let mut missing_value = args.clone();
+        missing_value.remove("key_value");
+        let result = server.call_tool("upsert_record", &missing_value).await;
+        assert_eq!(result.is_error, Some(true));
+    }
+
+    #[tokio::test]
+    async fn dataverse_counts_add_the_table_total() {
+        use wiremock::matchers::{method, path, path_regex, query_param};
+        use wiremock::{Mock, ResponseTemplate};
+
+        let d365 = metadata_server().await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "@odata.count": 5000,
+                "@Microsoft.Dynamics.CRM.totalrecordcountlimitexceeded": true,
+                "value": [{"name": "Contoso"}]
+            })))
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path_regex("RetrieveTotalRecordCount"))
+            .and(query_param("@EntityNames", "[\"account\"]"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "EntityRecordCountCollection": {"Count": 1, "Keys": ["account"], "Values": [12873]}
+            })))
+            .expect(1)
+            .mount(&d365)
+            .await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+
+        let mut args = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            ("count".to_string(), json!(true)),
+        ]);
+        let result = server.call_tool("query_entity", &args).await;
+        let text = &result.content[0].text;
+        assert!(
+            text.starts_with("Total records: 5000+ (exact count unavailable, limit exceeded)\nRows in table: 12873 (RetrieveTotalRecordCount"),
+            "{text}"
+        );
+        let structured = result.structured_content.unwrap();
+        assert_eq!(
+            (
+                structured["count"].clone(),
+                structured["table_count"].clone()
+            ),
+            (json!(5000), json!(12873))
+        );
+        assert_eq!(structured["count_limit_exceeded"], true);
+
+        // Without count the function is not called
+        args.remove("count");
+        let result = server.call_tool("query_entity", &args).await;
+        assert!(result
+            .structured_content
+            .unwrap()
+            .get("table_count")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn history_lists_calls_and_replays_them_with_overrides() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, ResponseTemplate};
+
+        let d365 = metadata_server().await;
+        for top in ["2", "3"] {
+            Mock::given(method("GET"))
+                .and(path("/data/accounts"))
+                .and(query_param("$top", top))
+                .and(query_param("$filter", "name eq 'Contoso'"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "value": vec![json!({"name": "Contoso"}); top.parse().unwrap()]
+                })))
+                .expect(1)
+                .mount(&d365)
+                .await;
+        }
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+
+        let args = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            ("filter".to_string(), json!("name eq 'Contoso'")),
+            ("top".to_string(), json!(2)),
+        ]);
+        assert_eq!(server.call_tool("query_entity", &args).await.is_error, None);
+        let missing = HashMap::from([("entity".to_string(), json!("nosuchset"))]);
+        server.call_tool("get_entity_schema", &missing).await;
+
+        let history = server.call_tool("query_history", &HashMap::new()).await;
+        let lines: Vec<&str> = history.content[0].text.lines().collect();
+        assert_eq!(lines.len(), 3, "{lines:?}");
+        assert!(lines[1].starts_with("#1 "), "{}", lines[1]);
+        assert!(
+            lines[1].ends_with(r#" query_entity (ok, 2 rows) {"entity":"accounts","filter":"name eq 'Contoso'","top":2}"#),
+            "{}",
+            lines[1]
+        );
+        assert!(
+            lines[2].contains(" get_entity_schema (error) "),
+            "{}",
+            lines[2]
+        );
+
+        let replay = HashMap::from([
+            ("index".to_string(), json!(1)),
+            ("arguments".to_string(), json!({"top": 3})),
+        ]);
+        let result = server.call_tool("replay_query", &replay).await;
+        assert_eq!(result.is_error, None);
+        assert!(result.content[0].text.starts_with(
+            r#"Replayed #1 query_entity {"entity":"accounts","filter":"name eq 'Contoso'","top":3}"#
+        ));
+        assert_eq!(
+            result.structured_content.unwrap()["records"]
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+
+        // The replay is recorded as the call it ran; history tools are not
+        let only_queries = HashMap::from([("tool".to_string(), json!("query_entity"))]);
+        let history = server.call_tool("query_history", &only_queries).await;
+        let text = &history.content[0].text;
+        assert!(
+            text.contains("\n#3 ") && text.contains("(ok, 3 rows)"),
+            "{text}"
+        );
+        assert!(!text.contains("#2 ") && !text.contains("#4 "), "{text}");
+
+        let gone = HashMap::from([("index".to_string(), json!(9))]);
+        let result = server.call_tool("replay_query", &gone).await;
+        assert_eq!(result.is_error, Some(true));
+        assert!(result_text(&result).contains("No history entry #9; the latest is #3"));
+    }
+
+    #[tokio::test]
+    async fn bulk_updates_patch_matching_records_in_changesets() {
+        use wiremock::matchers::{body_string_contains, header, method, path, query_param};
+        use wiremock::{Mock, ResponseTemplate};
+
+        let ids = [
+            "00000000-0000-0000-0000-0000000000a1",
+            "00000000-0000-0000-0000-0000000000a2",
+            "00000000-0000-0000-0000-0000000000a3",
+        ];
+        let d365 = metadata_server().await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .and(query_param("$select", "accountid"))
+            .and(query_param("$filter", "name eq 'Hold'"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": ids.iter().map(|id| json!({"accountid": id})).collect::<Vec<_>>()
+            })))
+            .mount(&d365)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/data/$batch"))
+            .and(header("Accept", "multipart/mixed"))
+            .and(body_string_contains(format!("PATCH {}/data/accounts(accountid={}) HTTP/1.1", d365.uri(), ids[0])))
+            .and(body_string_contains("{\"creditonhold\":true}"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                "--batchresponse_1\r\nContent-Type: multipart/mixed; boundary=changesetresponse_1\r\n\r\n\
+                 --changesetresponse_1\r\nContent-Type: application/http\r\nContent-ID: 1\r\n\r\n\
+                 HTTP/1.1 204 No Content\r\n\r\n\r\n\
+                 --changesetresponse_1\r\nContent-Type: application/http\r\nContent-ID: 2\r\n\r\n\
+                 HTTP/1.1 204 No Content\r\n\r\n\r\n\
+                 --changesetresponse_1--\r\n--batchresponse_1--\r\n",
+            ))
+            .expect(1)
+            .mount(&d365)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/data/$batch"))
+            .and(body_string_contains(ids[2]))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                "--batchresponse_2\r\nContent-Type: application/http\r\n\r\n\
+                 HTTP/1.1 400 Bad Request\r\nContent-Type: application/json\r\n\r\n\
+                 {\"error\":{\"code\":\"0x80040265\",\"message\":\"Credit hold is locked\"}}\r\n\
+                 --batchresponse_2--\r\n",
+            ))
+            .expect(1)
+            .mount(&d365)
+            .await;
+        let server = server_at(&format!("{}/data/", d365.uri()), false);
+
+        let mut args = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            ("filter".to_string(), json!("name eq 'Hold'")),
+            ("data".to_string(), json!({"creditonhold": true})),
+            ("max_records".to_string(), json!(3)),
+            ("chunk_size".to_string(), json!(2)),
+        ]);
+        let unconfirmed = server.call_tool("bulk_update", &args).await;
+        assert_eq!(unconfirmed.is_error, Some(true));
+        assert!(result_text(&unconfirmed).contains("confirm=true"));
+
+        args.insert("dry_run".to_string(), json!(true));
+        let dry_run = result_text(&server.call_tool("bulk_update", &args).await);
+        assert!(
+            dry_run
+                .contains("3 accounts records match and would get creditonhold in 2 changeset(s)"),
+            "{dry_run}"
+        );
+
+        args.insert("dry_run".to_string(), json!(false));
+        args.insert("confirm".to_string(), json!(true));
+        args.insert("max_records".to_string(), json!(2));
+        let too_many = server.call_tool("bulk_update", &args).await;
+        assert_eq!(too_many.is_error, Some(true));
+        assert!(result_text(&too_many).contains("More than 2 accounts records match"));
+
+        args.insert("max_records".to_string(), json!(3));
+        let result = server.call_tool("bulk_update", &args).await;
+        assert_ne!(result.is_error, Some(true));
+        let text = &result.content[0].text;
+        assert!(
+            text.contains("2 of 3 matching records updated in 2 changeset(s).\n- Changeset 1: 2 succeeded, 0 failed\n- Changeset 2: 0 succeeded, 1 failed"),
+            "{text}"
+        );
+        let structured = result.structured_content.unwrap();
+        assert_eq!(
+            structured["failures"],
+            json!([{"id": format!("accountid={}", ids[2]), "error": "Server error (400): Credit hold is locked (0x80040265)"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn finops_calls_are_scoped_to_the_company() {
+        use wiremock::matchers::{body_json, method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/CustomersV3"))
+            .and(query_param(
+                "$filter",
+                "(CustomerGroupId eq '10') and dataAreaId eq 'usmf'",
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+            .mount(&d365)
+            .await;
+        Mock::given(method("PATCH"))
+            .and(path(
+                "/data/CustomersV3(dataAreaId='usmf',CustomerAccount='US-001')",
+            ))
+            .and(body_json(json!({"Name": "Contoso", "dataAreaId": "usmf"})))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&d365)
+            .await;
+
+        let finops = finops_server_at(&format!("{}/data/", d365.uri()));
+        let mut config = (*finops.config()).clone();
+        config.read_only = false;
+        config.default_company = Some("usmf".to_string());
+        let finops = D365McpServer::new(finops.client(), Arc::new(config));
+
+        let query = HashMap::from([
+            ("entity".to_string(), json!("CustomersV3")),
+            ("filter".to_string(), json!("CustomerGroupId eq '10'")),
+        ]);
+        let text = result_text(&finops.call_tool("query_entity", &query).await);
+        assert!(text.contains("Showing 0 records"), "{text}");
+
+        let upsert = HashMap::from([
+            ("entity".to_string(), json!("CustomersV3")),
+            ("key".to_string(), json!({"CustomerAccount": "US-001"})),
+            ("data".to_string(), json!({"Name": "Contoso"})),
+        ]);
+        let text = result_text(&finops.call_tool("upsert_record", &upsert).await);
+        assert!(text.contains("Updated existing record"), "{text}");
+
+        let mut conflicting = query.clone();
+        conflicting.insert("company".to_string(), json!("usmf"));
+        conflicting.insert("cross_company".to_string(), json!(true));
+        let text = result_text(&finops.call_tool("query_entity", &conflicting).await);
+        assert!(text.contains("cannot be combined"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn label_language_is_reported_and_overridden_per_call() {
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        for language in ["de-DE", "fr-FR"] {
+            Mock::given(method("GET"))
+                .and(path("/data/accounts"))
+                .and(header("Accept-Language", language))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+                .expect(1)
+                .mount(&d365)
+                .await;
+        }
+
+        let endpoint = format!("{}/data/", d365.uri());
+        let plain = server_at(&endpoint, true);
+        let client = ODataClient::new(
+            Arc::new(StaticTokenProvider::new("test-token")),
+            endpoint,
+            ProductType::Dataverse,
+            0,
+            10,
+            false,
+        )
+        .with_language(Some("de-DE".to_string()));
+        let server = D365McpServer::new(Arc::new(client), plain.config());
+
+        let text = result_text(
+            &server
+                .call_tool("get_environment_info", &HashMap::new())
+                .await,
+        );
+        assert!(text.contains("Language: de-DE"), "{text}");
+        let text = result_text(
+            &plain
+                .call_tool("get_environment_info", &HashMap::new())
+                .await,
+        );
+        assert!(text.contains("Language: service default"), "{text}");
+
+        let mut args = HashMap::from([("entity".to_string(), json!("accounts"))]);
+        let result = server.call_tool("query_entity", &args).await;
+        assert_ne!(result.is_error, Some(true), "{:?}", result.content);
+        args.insert("language".to_string(), json!("fr-FR"));
+        let result = server.call_tool("query_entity", &args).await;
+        assert_ne!(result.is_error, Some(true), "{:?}", result.content);
+
+        args.insert("language".to_string(), json!("french"));
+        let result = server.call_tool("query_entity", &args).await;
+        assert!(
+            result.content[0]
+                .text
+                .starts_with("Invalid language 'french'"),
+            "{}",
+            result.content[0].text
+        );
+        // Well-formed but unknown tags would each download $metadata
+        args.insert("language".to_string(), json!("aa-x1"));
+        let result = server.call_tool("query_entity", &args).await;
+        assert_eq!(
+            result.content[0].text,
+            "Unsupported language 'aa-x1': use LABEL_LANGUAGE ('de-DE') or a language such as 'de-DE', 'fr-FR' or 'ja'"
+        );
+    }
+
+    #[test]
+    fn call_languages_are_configured_or_known() {
+        assert_eq!(supported_language("TLH", Some("tlh")).unwrap(), "tlh");
+        assert_eq!(supported_language("fr-fr", None).unwrap(), "fr-FR");
+        assert!(supported_language("aa", None).is_err());
+    }
+
+    #[tokio::test]
+    async fn impersonation_is_reported_and_validated_per_call() {
+        use crate::odata::CallerIdHeader;
+        use wiremock::matchers::{header, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .and(header(
+                "CallerObjectId",
+                "11111111-2222-3333-4444-555555555555",
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+            .expect(1)
+            .mount(&d365)
+            .await;
+
+        let endpoint = format!("{}/data/", d365.uri());
+        let plain = server_at(&endpoint, true);
+        let client = ODataClient::new(
+            Arc::new(StaticTokenProvider::new("test-token")),
+            endpoint,
+            ProductType::Dataverse,
+            0,
+            10,
+            false,
+        )
+        .with_impersonation(
+            Some("0c4e1a2b-3d5f-4a6b-8c7d-9e0f1a2b3c4d".to_string()),
+            CallerIdHeader::ObjectId,
+        );
+        let server = D365McpServer::new(Arc::new(client), plain.config());
+
+        let text = result_text(
+            &server
+                .call_tool("get_environment_info", &HashMap::new())
+                .await,
+        );
+        assert!(
+            text.contains("Impersonation: CallerObjectId 0c4e1a2b-3d5f-4a6b-8c7d-9e0f1a2b3c4d"),
+            "{text}"
+        );
+        let text = result_text(
+            &plain
+                .call_tool("get_environment_info", &HashMap::new())
+                .await,
+        );
+        assert!(text.contains("Impersonation: off"), "{text}");
+
+        let mut args = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            (
+                "impersonate_user_id".to_string(),
+                json!("{11111111-2222-3333-4444-555555555555}"),
+            ),
+        ]);
+        let text = result_text(&server.call_tool("query_entity", &args).await);
+        assert!(text.contains("Showing 0 records"), "{text}");
+
+        args.insert("impersonate_user_id".to_string(), json!("jane@contoso.com"));
+        let text = result_text(&server.call_tool("query_entity", &args).await);
+        assert!(text.contains("must be a GUID"), "{text}");
+    }
+
+    #[test]
+    fn download_names_stay_inside_the_download_dir() {
+        assert_eq!(
+            download_file_name(Some("../../contract.txt"), "accounts", "new_contract"),
+            "contract.txt"
+        );
+        assert_eq!(
+            download_file_name(None, "accounts", "new_contract"),
+            "accounts-new_contract"
+        );
+        assert_eq!(
+            download_file_name(None, "../../etc", "passwd"),
+            "_.._etc-passwd"
+        );
+    }
+
+    #[tokio::test]
+    async fn downloads_are_saved_without_overwriting_or_returned_inline() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts(1)/new_contract/$value"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("x-ms-file-name", "../../contract.txt")
+                    .set_body_bytes(b"signed".to_vec()),
+            )
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts(2)/new_contract/$value"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&d365)
+            .await;
+
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+        let mut config = (*server.config()).clone();
+        let dir = std::env::temp_dir().join(format!("d365-download-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        config.download_dir = dir.to_string_lossy().into_owned();
+        let server = D365McpServer::new(server.client(), Arc::new(config));
+
+        let mut args = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            ("id".to_string(), json!("1")),
+            ("attribute".to_string(), json!("new_contract")),
+        ]);
+        for expected in ["contract.txt", "contract (2).txt"] {
+            let text = result_text(&server.call_tool("download_file", &args).await);
+            assert!(text.contains("(6 bytes)"), "{text}");
+            assert_eq!(std::fs::read(dir.join(expected)).unwrap(), b"signed");
+        }
+
+        args.insert("inline".to_string(), json!(true));
+        let text = result_text(&server.call_tool("download_file", &args).await);
+        assert!(text.contains("c2lnbmVk"), "{text}");
+        assert!(!dir.join("contract (3).txt").exists());
+
+        args.insert("id".to_string(), json!("2"));
+        let text = result_text(&server.call_tool("download_file", &args).await);
+        assert!(text.contains("accounts(2)/new_contract is empty"), "{text}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn actions_run_in_the_background_are_checked_by_monitor_url() {
+        use wiremock::matchers::{body_json, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/data/v9.2/WinOpportunity"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(&d365)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/data/v9.2/BulkDelete"))
+            .and(body_json(json!({"JobName": "Cleanup"})))
+            .respond_with(
+                ResponseTemplate::new(202).insert_header("Location", "asyncoperations(7)"),
+            )
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/data/v9.2/asyncoperations(7)"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "statecode": 3,
+                "statuscode": 31,
+                "statuscode@OData.Community.Display.V1.FormattedValue": "Failed",
+                "message": "The job was canceled by the system"
+            })))
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/data/v9.2/$metadata"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(METADATA))
+            .mount(&d365)
+            .await;
+        let endpoint = format!("{}/api/data/v9.2/", d365.uri());
+        let server = server_at(&endpoint, false);
+
+        let args = HashMap::from([("action".to_string(), json!("WinOpportunity"))]);
+        let result = server.call_tool("execute_action", &args).await;
+        assert_eq!(result.content[0].text, "Action WinOpportunity completed");
+
+        let args = HashMap::from([
+            ("action".to_string(), json!("BulkDelete")),
+            ("parameters".to_string(), json!({"JobName": "Cleanup"})),
+            ("wait".to_string(), json!(false)),
+        ]);
+        let result = server.call_tool("execute_action", &args).await;
+        let monitor_url = format!("{}asyncoperations(7)", endpoint);
+        assert_eq!(result.is_error, None);
+        assert!(
+            result.content[0]
+                .text
+                .ends_with(&format!("check_operation monitor_url={}", monitor_url)),
+            "{}",
+            result.content[0].text
+        );
+
+        let check = HashMap::from([("monitor_url".to_string(), json!(monitor_url))]);
+        let result = server.call_tool("check_operation", &check).await;
+        assert_eq!(result.is_error, Some(true));
+        let text = &result.content[0].text;
+        assert!(
+            text.starts_with("Operation: Failed (statuscode: 31 (Failed))"),
+            "{text}"
+        );
+        assert!(text.contains("canceled by the system"), "{text}");
+
+        // The bearer token only goes to the service
+        let elsewhere = HashMap::from([(
+            "monitor_url".to_string(),
+            json!("https://attacker.example.com/api/data/v9.2/asyncoperations(7)"),
+        )]);
+        let text = result_text(&server.call_tool("check_operation", &elsewhere).await);
+        assert!(text.contains("monitor_url must be a URL on"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn actions_cannot_reach_entity_sets_the_policy_denies() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, ResponseTemplate};
+
+        let d365 = metadata_server().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&d365)
+            .await;
+        let server = server_at(&format!("{}/data/", d365.uri()), false);
+        let mut config = (*server.config()).clone();
+        config.denied_entities = vec!["accounts".to_string()];
+        let server = D365McpServer::new(server.client(), Arc::new(config));
+        let execute = |action: &str| HashMap::from([("action".to_string(), json!(action))]);
+
+        for (action, error) in [
+            (
+                "Accounts",
+                "'Accounts' is an entity set, not an action; use create_record to create records",
+            ),
+            (
+                "accounts(00000000-0000-0000-0000-0000000000a1)/Microsoft.Dynamics.CRM.Merge",
+                "Access to entity 'accounts' is blocked by the denied_entities policy",
+            ),
+            (
+                "account/Microsoft.Dynamics.CRM.Recalculate",
+                "Access to entity 'accounts' is blocked by the denied_entities policy",
+            ),
+            (
+                "contacts(1)/parentcustomerid_account/Microsoft.Dynamics.CRM.Merge",
+                "action must be an action name or a bound action path such as \
+                 'accounts(<guid>)/Microsoft.Dynamics.CRM.new_Recalculate', got \
+                 'contacts(1)/parentcustomerid_account/Microsoft.Dynamics.CRM.Merge'",
+            ),
+        ] {
+            let result = server.call_tool("execute_action", &execute(action)).await;
+            assert_eq!(result.is_error, Some(true), "{action}");
+            assert_eq!(result.content[0].text, error, "{action}");
+        }
+
+        let bound = "contacts(00000000-0000-0000-0000-0000000000c1)/Microsoft.Dynamics.CRM.Merge";
+        let result = server.call_tool("execute_action", &execute(bound)).await;
+        assert_eq!(result.is_error, None, "{}", result.content[0].text);
+    }
+
+    #[tokio::test]
+    async fn offline_servers_answer_from_the_snapshot_and_refuse_data_requests() {
+        use crate::odata::MetadataSnapshot;
+        use wiremock::MockServer;
+
+        let d365 = MockServer::start().await;
+        let endpoint = format!("{}/data/", d365.uri());
+        let snapshot =
+            MetadataSnapshot::from_xml("snapshots/metadata.xml", METADATA.to_string()).unwrap();
+        let client = ODataClient::new(
+            Arc::new(StaticTokenProvider::new("")),
+            endpoint.clone(),
+            ProductType::Dataverse,
+            0,
+            10,
+            false,
+        )
+        .with_offline_metadata(snapshot);
+        let server = D365McpServer::new(Arc::new(client), server_at(&endpoint, true).config());
+
+        let text = result_text(&server.call_tool("list_entities", &HashMap::new()).await);
+        assert!(text.contains("accounts"), "{text}");
+
+        let query = HashMap::from([("entity".to_string(), json!("accounts"))]);
+        let result = server.call_tool("query_entity", &query).await;
+        assert_eq!(result.is_error, Some(true));
+        let text = &result.content[0].text;
+        assert!(
+            text.contains("Offline: only $metadata is available, read from snapshots/metadata.xml"),
+            "{text}"
+        );
+        assert!(!text.contains("client-request-id"), "{text}");
+        assert!(d365.received_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_calls_quote_the_client_request_id() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+                "error": {"code": "0x0", "message": "Could not find a property named 'nme'"}
+            })))
+            .mount(&d365)
+            .await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+
+        let query = HashMap::from([("entity".to_string(), json!("accounts"))]);
+        let result = server.call_tool("query_entity", &query).await;
+        assert_eq!(result.is_error, Some(true));
+
+        let requests = d365.received_requests().await.unwrap();
+        let id = requests[0].headers.get("client-request-id").unwrap();
+        let text = &result.content[0].text;
+        assert!(
+            text.ends_with(&format!("\n\nclient-request-id: {}", id.to_str().unwrap())),
+            "{text}"
+        );
+        assert!(requests[0]
+            .headers
+            .get("user-agent")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("d365-odata-mcp/"));
+    }
+
+    #[tokio::test]
+    async fn the_circuit_breaker_is_reported_and_reset_by_test_connection() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+            .mount(&d365)
+            .await;
+        let endpoint = format!("{}/data/", d365.uri());
+        let client = ODataClient::new(
+            Arc::new(StaticTokenProvider::new("test-token")),
+            endpoint.clone(),
+            ProductType::Dataverse,
+            0,
+            10,
+            false,
+        )
+        .with_circuit_breaker(1, Duration::from_secs(60));
+        let server = D365McpServer::new(Arc::new(client), server_at(&endpoint, true).config());
+        let none = HashMap::new();
+
+        let text = result_text(&server.call_tool("get_environment_info", &none).await);
+        assert!(
+            text.contains("Circuit Breaker: closed (0/1 failures in a row)"),
+            "{text}"
+        );
+
+        let query = HashMap::from([("entity".to_string(), json!("accounts"))]);
+        server.call_tool("query_entity", &query).await;
+        let text = result_text(&server.call_tool("query_entity", &query).await);
+        assert!(
+            text.contains("requests are paused: retry after 60 seconds"),
+            "{text}"
+        );
+        let text = result_text(&server.call_tool("get_environment_info", &none).await);
+        assert!(
+            text.contains("Circuit Breaker: OPEN, requests fail for another 60s; opened 1 time(s) since startup"),
+            "{text}"
+        );
+
+        let reset = HashMap::from([("reset_circuit".to_string(), json!(true))]);
+        let text = server.call_tool("test_connection", &reset).await.content[0]
+            .text
+            .clone();
+        assert!(
+            text.ends_with("Circuit breaker reset; now closed (0/1 failures in a row); opened 1 time(s) since startup"),
+            "{text}"
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_calls_wait_behind_one_retry_after() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+            .mount(&d365)
+            .await;
+        let endpoint = format!("{}/data/", d365.uri());
+        let client = ODataClient::new(
+            Arc::new(StaticTokenProvider::new("test-token")),
+            endpoint.clone(),
+            ProductType::Dataverse,
+            2,
+            10,
+            false,
+        );
+        let server = D365McpServer::new(Arc::new(client), server_at(&endpoint, true).config());
+        let query = HashMap::from([("entity".to_string(), json!("accounts"))]);
+        let none = HashMap::new();
+
+        let first = server.call_tool("query_entity", &query);
+        let second = async {
+            while server.client().backpressure_stats().engaged == 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            let text = result_text(&server.call_tool("get_environment_info", &none).await);
+            assert!(
+                text.contains(
+                    "Throttling: COOLING DOWN, requests wait another 1s; engaged 1 time(s)"
+                ),
+                "{text}"
+            );
+            // Sent after the 429, it still waits for the Retry-After
+            let started = std::time::Instant::now();
+            let result = server.call_tool("query_entity", &query).await;
+            (result, started.elapsed())
+        };
+        let (first, (second, waited)) = tokio::join!(first, second);
+
+        assert!(!first.is_error.unwrap_or(false), "{}", result_text(&first));
+        assert!(
+            !second.is_error.unwrap_or(false),
+            "{}",
+            result_text(&second)
+        );
+        assert!(waited >= Duration::from_millis(500), "{waited:?}");
+        assert_eq!(d365.received_requests().await.unwrap().len(), 3);
+        let text = result_text(&server.call_tool("get_environment_info", &none).await);
+        assert!(
+            text.contains("Throttling: none; engaged 1 time(s)"),
+            "{text}"
+        );
+    }
+
+    #[tokio::test]
+    async fn data_packages_are_uploaded_imported_and_followed() {
+        use wiremock::matchers::{body_bytes, body_partial_json, method, path, path_regex};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        let blob_url = format!("{}/blob/customers.zip?sv=2014-02-14&sig=abc", d365.uri());
+        let write_url = json!({"BlobId": "{B1}", "BlobUrl": blob_url}).to_string();
+        Mock::given(method("POST"))
+            .and(path_regex("GetAzureWriteUrl$"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": write_url})))
+            .expect(1)
+            .mount(&d365)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path("/blob/customers.zip"))
+            .and(body_bytes(b"PK-package".to_vec()))
+            .respond_with(ResponseTemplate::new(201))
+            .expect(1)
+            .mount(&d365)
+            .await;
+        Mock::given(method("POST"))
+            .and(path_regex("ImportFromPackage$"))
+            .and(body_partial_json(json!({
+                "packageUrl": blob_url,
+                "definitionGroupId": "CustomerImport",
+                "legalEntityId": "usmf"
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": "exec-1"})))
+            .expect(1)
+            .mount(&d365)
+            .await;
+        Mock::given(method("POST"))
+            .and(path_regex("GetExecutionSummaryStatus$"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": 4})))
+            .mount(&d365)
+            .await;
+        Mock::given(method("POST"))
+            .and(path_regex("GetImportStagingErrorFileUrl$"))
+            .and(body_partial_json(
+                json!({"executionId": "exec-1", "entityName": "Customers V3"}),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": "https://store.blob.core.windows.net/dmf/errors.xlsx"
+            })))
+            .mount(&d365)
+            .await;
+
+        let uploads = std::env::temp_dir().join(format!("d365-dmf-{}", std::process::id()));
+        std::fs::create_dir_all(&uploads).unwrap();
+        std::fs::write(uploads.join("customers.zip"), b"PK-package").unwrap();
+        std::fs::write(uploads.join("customers.csv"), b"a,b").unwrap();
+        let server = finops_server_at(&format!("{}/data/", d365.uri()));
+        let mut config = (*server.config()).clone();
+        config.read_only = false;
+        config.upload_dir = Some(uploads.to_string_lossy().into_owned());
+        let server = D365McpServer::new(server.client(), Arc::new(config));
+
+        let mut args = HashMap::from([
+            ("path".to_string(), json!("customers.zip")),
+            ("definition_group".to_string(), json!("CustomerImport")),
+        ]);
+        let text = result_text(&server.call_tool("dmf_import_package", &args).await);
+        assert!(text.contains("company is required"), "{text}");
+        args.insert("company".to_string(), json!("usmf"));
+
+        args.insert("dry_run".to_string(), json!(true));
+        let text = server.call_tool("dmf_import_package", &args).await.content[0]
+            .text
+            .clone();
+        assert!(text.starts_with("Dry run: nothing was sent"), "{text}");
+        assert!(text.contains("GetAzureWriteUrl"), "{text}");
+        assert!(
+            text.contains("PUT <BlobUrl returned by GetAzureWriteUrl>"),
+            "{text}"
+        );
+        assert!(text.contains("ImportFromPackage"), "{text}");
+        args.remove("dry_run");
+
+        let result = server.call_tool("dmf_import_package", &args).await;
+        assert_eq!(result.is_error, None, "{}", result_text(&result));
+        let text = &result.content[0].text;
+        assert!(
+            text.starts_with("Importing package 'customers.zip' (10 bytes) into data project 'CustomerImport' for usmf.\nExecution id: exec-1"),
+            "{text}"
+        );
+
+        args.insert("path".to_string(), json!("customers.csv"));
+        let text = result_text(&server.call_tool("dmf_import_package", &args).await);
+        assert!(text.contains("is not a data package"), "{text}");
+
+        let mut status = HashMap::from([("execution_id".to_string(), json!("exec-1"))]);
+        let text = server
+            .call_tool("dmf_get_execution_status", &status)
+            .await
+            .content[0]
+            .text
+            .clone();
+        assert!(
+            text.starts_with("Execution exec-1: Partially succeeded\nPass entity_name"),
+            "{text}"
+        );
+        status.insert("entity_name".to_string(), json!("Customers V3"));
+        let text = server
+            .call_tool("dmf_get_execution_status", &status)
+            .await
+            .content[0]
+            .text
+            .clone();
+        assert_eq!(
+            text,
+            "Execution exec-1: Partially succeeded\nStaging error log of Customers V3: https://store.blob.core.windows.net/dmf/errors.xlsx"
+        );
+
+        // Dataverse has no package API
+        let dataverse = server_at(&format!("{}/data/", d365.uri()), false);
+        let text = result_text(
+            &dataverse
+                .call_tool("dmf_get_execution_status", &status)
+                .await,
+        );
+        assert!(
+            text.contains("only available for Finance & Operations"),
+            "{text}"
+        );
+        std::fs::remove_dir_all(&uploads).unwrap();
+    }
+
+    #[tokio::test]
+    async fn uploads_only_read_files_inside_the_upload_dir() {
+        use wiremock::matchers::{body_bytes, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("PATCH"))
+            .and(path("/data/accounts(1)/new_contract/$value"))
+            .and(body_bytes(b"signed".to_vec()))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&d365)
+            .await;
+
+        let root = std::env::temp_dir().join(format!("d365-upload-{}", std::process::id()));
+        let uploads = root.join("uploads");
+        std::fs::create_dir_all(&uploads).unwrap();
+        std::fs::write(uploads.join("contract.txt"), b"signed").unwrap();
+        std::fs::write(root.join("secret.txt"), b"secret").unwrap();
+
+        let server = server_at(&format!("{}/data/", d365.uri()), false);
+        let mut args = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            ("id".to_string(), json!("1")),
+            ("attribute".to_string(), json!("new_contract")),
+            ("path".to_string(), json!("contract.txt")),
+        ]);
+        let text = result_text(&server.call_tool("upload_file", &args).await);
+        assert!(text.contains("Uploads are disabled"), "{text}");
+
+        let mut config = (*server.config()).clone();
+        config.upload_dir = Some(uploads.to_string_lossy().into_owned());
+        let server = D365McpServer::new(server.client(), Arc::new(config));
+
+        let text = result_text(&server.call_tool("upload_file", &args).await);
+        assert!(
+            text.contains("Uploaded 'contract.txt' (6 bytes) to accounts(1)/new_contract"),
+            "{text}"
+        );
+
+        args.insert("path".to_string(), json!("../secret.txt"));
+        let text = result_text(&server.call_tool("upload_file", &args).await);
+        assert!(text.contains("outside UPLOAD_DIR"), "{text}");
+
+        args.insert("as_note".to_string(), json!(true));
+        let text = result_text(&server.call_tool("upload_file", &args).await);
+        assert!(text.contains("either attribute"), "{text}");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn query_entity_asks_for_and_prunes_the_chosen_annotations() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+        let prefer = |request: &Request| {
+            request
+                .headers
+                .get("Prefer")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .and(move |request: &Request| {
+                prefer(request).contains(
+                    "odata.include-annotations=\"OData.Community.Display.V1.FormattedValue\"",
+                )
+            })
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": [{
+                "@odata.etag": "W/\"7\"",
+                "@odata.editLink": "accounts(a1)",
+                "name": "Contoso",
+                "_ownerid_value": "u1",
+                "_ownerid_value@OData.Community.Display.V1.FormattedValue": "Alex Wu",
+                "primarycontactid@odata.navigationLink": "contacts(c1)"
+            }]})))
+            .expect(1)
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .and(move |request: &Request| !prefer(request).contains("include-annotations"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": [{
+                "name": "Contoso",
+                "_ownerid_value": "u1"
+            }]})))
+            .expect(1)
+            .mount(&d365)
+            .await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+
+        let mut args = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            ("validate".to_string(), json!(false)),
+            ("annotations".to_string(), json!("formatted-only")),
+        ]);
+        let result = server.call_tool("query_entity", &args).await;
+        assert_eq!(
+            result.structured_content.unwrap()["records"],
+            json!([{
+                "@odata.etag": "W/\"7\"",
+                "name": "Contoso",
+                "_ownerid_value": "u1",
+                "_ownerid_value_label": "Alex Wu"
+            }])
+        );
+
+        args.insert("annotations".to_string(), json!("none"));
+        let result = server.call_tool("query_entity", &args).await;
+        assert_eq!(
+            result.structured_content.unwrap()["records"],
+            json!([{"name": "Contoso", "_ownerid_value": "u1"}])
+        );
+
+        args.insert("annotations".to_string(), json!("some"));
+        let text = result_text(&server.call_tool("query_entity", &args).await);
+        assert!(
+            text.contains("argument 'annotations' must be one of"),
+            "{text}"
+        );
+    }
+
+    #[tokio::test]
+    async fn exports_stream_every_page_into_the_export_dir() {
+        use wiremock::matchers::{method, path, query_param, query_param_is_missing};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .and(query_param("$select", "name,statecode"))
+            .and(query_param_is_missing("$top"))
+            .and(query_param_is_missing("page"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": [{
+                    "name": "Contoso, Ltd",
+                    "statecode": 0,
+                    "statecode@OData.Community.Display.V1.FormattedValue": "Active"
+                }],
+                "@odata.nextLink": format!("{}/data/accounts?page=2", d365.uri())
+            })))
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .and(query_param("page", "2"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({"value": [{"name": "Fabrikam", "statecode": 1}]})),
+            )
+            .mount(&d365)
+            .await;
+
+        let root = std::env::temp_dir().join(format!("d365-export-{}", std::process::id()));
+        let exports = root.join("exports");
+        std::fs::create_dir_all(&exports).unwrap();
+
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+        let mut args = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            ("select".to_string(), json!("name,statecode")),
+        ]);
+        let text = result_text(&server.call_tool("export_entity", &args).await);
+        assert!(text.contains("Exports are disabled"), "{text}");
+
+        let mut config = (*server.config()).clone();
+        config.export_dir = Some(exports.to_string_lossy().into_owned());
+        let server = D365McpServer::new(server.client(), Arc::new(config));
+
+        let text = result_text(&server.call_tool("export_entity", &args).await);
+        let file = exports.canonicalize().unwrap().join("accounts.csv");
+        let csv = std::fs::read_to_string(&file).unwrap();
+        assert_eq!(
+            csv,
+            "name,statecode,statecode_label\n\"Contoso, Ltd\",0,Active\nFabrikam,1,\n"
+        );
+        assert!(
+            text.contains(&format!(
+                "Exported 2 records from accounts to {}",
+                exports.join("accounts.csv").display()
+            )),
+            "{text}"
+        );
+        assert!(
+            text.contains(&format!("Format: csv, {} bytes", csv.len())),
+            "{text}"
+        );
+
+        args.insert("format".to_string(), json!("jsonl"));
+        args.insert("top".to_string(), json!(1));
+        args.insert("path".to_string(), json!("first.jsonl"));
+        let text = result_text(&server.call_tool("export_entity", &args).await);
+        assert!(text.contains("Stopped at top=1"), "{text}");
+        let jsonl = std::fs::read_to_string(exports.join("first.jsonl")).unwrap();
+        assert_eq!(
+            jsonl,
+            "{\"name\":\"Contoso, Ltd\",\"statecode\":0,\"statecode_label\":\"Active\"}\n"
+        );
+
+        // Existing files are kept, and nothing is written outside EXPORT_DIR
+        let text = result_text(&server.call_tool("export_entity", &args).await);
+        assert!(text.contains("Cannot create"), "{text}");
+        args.insert("path".to_string(), json!("../escaped.jsonl"));
+        let text = result_text(&server.call_tool("export_entity", &args).await);
+        assert!(text.contains("outside EXPORT_DIR"), "{text}");
+        assert!(!root.join("escaped.jsonl").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn search_groups_hits_and_respects_the_entity_policy() {
+        use wiremock::matchers::{body_partial_json, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/data/v9.2/$metadata"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(METADATA))
+            .mount(&d365)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/search/v1.0/query"))
+            .and(body_partial_json(json!({"search": "Contoso", "entities": ["account"]})))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": [
+                    {"@search.score": 3.2, "@search.entityname": "account", "@search.objectid": "a1",
+                     "@search.highlights": {"name": ["{crmhit}Contoso{/crmhit}"]}, "name": "Contoso"},
+                    {"@search.score": 2.0, "@search.entityname": "contact", "@search.objectid": "c1",
+                     "fullname": "Jo Contoso"},
+                    {"@search.score": 1.5, "@search.entityname": "account", "@search.objectid": "a2"}
+                ],
+                "totalrecordcount": 3
+            })))
+            .mount(&d365)
+            .await;
+
+        let server = server_at(&format!("{}/api/data/v9.2/", d365.uri()), true);
+        let mut config = (*server.config()).clone();
+        config.denied_entities = vec!["contacts".to_string()];
+        let server = D365McpServer::new(server.client(), Arc::new(config));
+
+        let args = HashMap::from([("query".to_string(), json!("Contoso"))]);
+        let text = result_text(&server.call_tool("search", &args).await);
+        assert!(text.contains("## account (2)"), "{text}");
+        assert!(text.contains("- Contoso (score 3.20) id a1"), "{text}");
+        assert!(text.contains("name: **Contoso**"), "{text}");
+        assert!(!text.contains("Jo Contoso"), "{text}");
+
+        let args = HashMap::from([
+            ("query".to_string(), json!("Contoso")),
+            ("entities".to_string(), json!("account, Contact")),
+        ]);
+        let text = result_text(&server.call_tool("search", &args).await);
+        assert!(text.contains("'contact' is not allowed"), "{text}");
+
+        let finops = finops_server_at(&format!("{}/data/", d365.uri()));
+        assert!(!finops.get_tools().iter().any(|tool| tool.name == "search"));
+    }
+
+    #[tokio::test]
+    async fn environments_switch_with_confirmation_and_keep_their_clients() {
+        use crate::config::EnvironmentSummary;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let environments = vec![
+            EnvironmentSummary {
+                name: "dev".to_string(),
+                endpoint: "https://dev.crm.dynamics.com/api/data/v9.2/".to_string(),
+                product: ProductType::Dataverse,
+                production: false,
+            },
+            EnvironmentSummary {
+                name: "prod".to_string(),
+                endpoint: "https://prod.operations.dynamics.com/data/".to_string(),
+                product: ProductType::Finops,
+                production: true,
+            },
+        ];
+        let dev = server_at(&environments[0].endpoint, true);
+        let mut config = (*dev.config()).clone();
+        config.environment = Some("dev".to_string());
+        config.environments = environments.clone();
+
+        let loads = Arc::new(AtomicUsize::new(0));
+        let counter = loads.clone();
+        let load = environment_loader_for(environments);
+        let loader: EnvironmentLoader = Arc::new(move |name| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            load(name)
+        });
+        let server =
+            D365McpServer::new(dev.client(), Arc::new(config)).with_environment_loader(loader);
+
+        let none = HashMap::new();
+        let text = result_text(&server.call_tool("list_environments", &none).await);
+        assert!(text.contains("- dev (active): Dataverse"), "{text}");
+        assert!(text.contains("- prod (production): Finops"), "{text}");
+
+        let mut args = HashMap::from([("name".to_string(), json!("prod"))]);
+        let text = result_text(&server.call_tool("switch_environment", &args).await);
+        assert!(text.contains("confirm=true"), "{text}");
+        assert_eq!(server.config().environment.as_deref(), Some("dev"));
+
+        args.insert("confirm".to_string(), json!(true));
+        let text = result_text(&server.call_tool("switch_environment", &args).await);
+        assert!(text.contains("Switched to environment 'prod'"), "{text}");
+        let text = result_text(&server.call_tool("get_environment_info", &none).await);
+        assert!(text.contains("Environment: prod (production)"), "{text}");
+        assert!(text.contains("prod.operations.dynamics.com"), "{text}");
+        // The tool list follows the active environment's product
+        assert!(server
+            .get_tools()
+            .iter()
+            .any(|t| t.name == "list_companies"));
+
+        // Clients are reused, so each environment keeps its metadata cache
+        let back = HashMap::from([("name".to_string(), json!("dev"))]);
+        server.call_tool("switch_environment", &back).await;
+        server.call_tool("switch_environment", &args).await;
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+
+        let unknown = HashMap::from([("name".to_string(), json!("qa"))]);
+        let text = result_text(&server.call_tool("switch_environment", &unknown).await);
+        assert!(
+            text.contains("Configured environments: dev, prod"),
+            "{text}"
+        );
+    }
+
+    #[tokio::test]
+    async fn related_records_page_like_entity_sets() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/$metadata"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"<Schema Namespace="Microsoft.Dynamics.CRM"><EntityType Name="account"><Key><PropertyRef Name="accountid" /></Key><Property Name="accountid" Type="Edm.Guid" /><NavigationProperty Name="primarycontactid" Type="Microsoft.Dynamics.CRM.contact" /><NavigationProperty Name="contact_customer_accounts" Type="Collection(Microsoft.Dynamics.CRM.contact)" /></EntityType><EntityType Name="contact"><Key><PropertyRef Name="contactid" /></Key><Property Name="contactid" Type="Edm.Guid" /><Property Name="fullname" Type="Edm.String" /></EntityType><EntityContainer Name="System"><EntitySet Name="accounts" EntityType="Microsoft.Dynamics.CRM.account" /><EntitySet Name="contacts" EntityType="Microsoft.Dynamics.CRM.contact" /></EntityContainer></Schema>"#,
+            ))
+            .mount(&d365)
+            .await;
+        let related =
+            "/data/accounts(00000000-0000-0000-0000-0000000000a1)/contact_customer_accounts";
+        Mock::given(method("GET"))
+            .and(path(related))
+            .and(query_param("$skiptoken", "2"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": [{"fullname": "Second Page"}]
+            })))
+            .with_priority(1)
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(related))
+            .and(query_param("$select", "fullname"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": [{"fullname": "First Page"}],
+                "@odata.nextLink": format!("{}{}?$skiptoken=2", d365.uri(), related)
+            })))
+            .mount(&d365)
+            .await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+
+        let mut args = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            (
+                "id".to_string(),
+                json!("00000000-0000-0000-0000-0000000000a1"),
+            ),
+            (
+                "relationship".to_string(),
+                json!("contact_customer_accounts"),
+            ),
+            ("select".to_string(), json!("fullname")),
+        ]);
+        let result = server.call_tool("query_related", &args).await;
+        let text = &result.content[0].text;
+        assert!(text.contains("First Page"), "{text}");
+        let token = result.structured_content.unwrap()["next_page_token"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let next = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            (
+                "relationship".to_string(),
+                json!("contact_customer_accounts"),
+            ),
+            ("page_token".to_string(), json!(token)),
+        ]);
+        let text = result_text(&server.call_tool("query_related", &next).await);
+        assert!(text.contains("Second Page"), "{text}");
+
+        // With $metadata cached, the relationship and fields are checked first
+        server.client().metadata_model().await.unwrap();
+        args.insert(
+            "relationship".to_string(),
+            json!("contact_customer_account"),
+        );
+        let text = result_text(&server.call_tool("query_related", &args).await);
+        assert!(
+            text.contains("did you mean 'contact_customer_accounts'?"),
+            "{text}"
+        );
+        args.insert("relationship".to_string(), json!("primarycontactid"));
+        let text = result_text(&server.call_tool("query_related", &args).await);
+        assert!(text.contains("single contact record"), "{text}");
+        args.insert(
+            "relationship".to_string(),
+            json!("contact_customer_accounts"),
+        );
+        args.insert("select".to_string(), json!("FullName"));
+        let text = result_text(&server.call_tool("query_related", &args).await);
+        assert!(text.contains("did you mean 'fullname'?"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn tools_route_to_the_environment_they_name() {
+        use crate::config::EnvironmentSummary;
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let crm = metadata_server().await;
+        let fno = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/$metadata"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(
+                r#"<Schema Namespace="Microsoft.Dynamics.DataEntities"><EntityType Name="CustomerV3"><Property Name="CustomerAccount" Type="Edm.String" /></EntityType><EntityType Name="SalesOrderHeaderV2"><Property Name="SalesOrderNumber" Type="Edm.String" /></EntityType><EntityContainer Name="Resources"><EntitySet Name="CustomersV3" EntityType="Microsoft.Dynamics.DataEntities.CustomerV3" /><EntitySet Name="SalesOrderHeadersV2" EntityType="Microsoft.Dynamics.DataEntities.SalesOrderHeaderV2" /></EntityContainer></Schema>"#,
+            ))
+            .mount(&fno)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/CustomersV3"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": [{"CustomerAccount": "US-001"}]
+            })))
+            .mount(&fno)
+            .await;
+
+        let environments = vec![
+            EnvironmentSummary {
+                name: "crm".to_string(),
+                endpoint: format!("{}/data/", crm.uri()),
+                product: ProductType::Dataverse,
+                production: false,
+            },
+            EnvironmentSummary {
+                name: "fno".to_string(),
+                endpoint: format!("{}/data/", fno.uri()),
+                product: ProductType::Finops,
+                production: true,
+            },
+        ];
+        let primary = server_at(&environments[0].endpoint, true);
+        let mut config = (*primary.config()).clone();
+        config.environment = Some("crm".to_string());
+        config.environments = environments.clone();
+        let load = environment_loader_for(environments);
+        let loader: EnvironmentLoader = Arc::new(move |name| {
+            let (client, config) = load(name)?;
+            let mut config = (*config).clone();
+            config.read_only = false;
+            config.denied_entities = vec!["SalesOrderHeadersV2".to_string()];
+            Ok((client, Arc::new(config)))
+        });
+        let server =
+            D365McpServer::new(primary.client(), Arc::new(config)).with_environment_loader(loader);
+        assert!(server.load_environments().is_empty());
+
+        let args = HashMap::from([
+            ("entity".to_string(), json!("CustomersV3")),
+            ("environment".to_string(), json!("fno")),
+        ]);
+        let text = result_text(&server.call_tool("query_entity", &args).await);
+        assert!(text.contains("US-001"), "{text}");
+        // The call did not switch the active environment
+        assert_eq!(server.config().environment.as_deref(), Some("crm"));
+
+        // Without an environment, entities are listed for each, under its own policy
+        let result = server.call_tool("list_entities", &HashMap::new()).await;
+        let text = &result.content[0].text;
+        assert!(text.contains("[crm] Dataverse"), "{text}");
+        assert!(text.contains("accounts (account)"), "{text}");
+        assert!(text.contains("[fno] Finops"), "{text}");
+        assert!(text.contains("CustomersV3 (CustomerV3)"), "{text}");
+        assert!(!text.contains("SalesOrderHeadersV2"), "{text}");
+
+        let args = HashMap::from([
+            ("entity".to_string(), json!("CustomersV3")),
+            ("data".to_string(), json!({"CustomerAccount": "US-002"})),
+            ("environment".to_string(), json!("fno")),
+        ]);
+        let result = server.call_tool("upsert_record", &args).await;
+        assert_eq!(result.is_error, Some(true));
+        assert!(
+            result.content[0].text.contains("production environment"),
+            "{}",
+            result_text(&result)
+        );
+
+        let args = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            ("environment".to_string(), json!("qa")),
+        ]);
+        let text = result_text(&server.call_tool("query_entity", &args).await);
+        assert!(text.contains("Configured environments: crm, fno"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn reloads_apply_to_new_calls_without_dropping_running_ones() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, ResponseTemplate};
+
+        let d365 = metadata_server().await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({"value": [{"name": "Contoso"}]}))
+                    .set_delay(Duration::from_millis(300)),
+            )
+            .mount(&d365)
+            .await;
+        let endpoint = format!("{}/data/", d365.uri());
+        let server = server_at(&endpoint, true);
+        let client = server.client();
+        let args = HashMap::from([("entity".to_string(), json!("accounts"))]);
+
+        let mut denied = (*server.config()).clone();
+        denied.denied_entities = vec!["accounts".to_string()];
+        let (running, ()) = tokio::join!(server.call_tool("query_entity", &args), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let fresh = server_at(&endpoint, true).client();
+            server.reload(Some(fresh), Arc::new(denied.clone()), None);
+        });
+        assert_ne!(running.is_error, Some(true), "{}", result_text(&running));
+        assert!(result_text(&running).contains("Contoso"));
+
+        let result = server.call_tool("query_entity", &args).await;
+        assert!(result_text(&result).contains("blocked by the denied_entities policy"));
+        // Only the entity policy changed, so the client and its caches stay
+        assert!(Arc::ptr_eq(&server.client(), &client));
+
+        let mut moved = denied;
+        moved.endpoint = format!("{}/other/", d365.uri());
+        let fresh = server_at(&moved.endpoint, true).client();
+        server.reload(Some(fresh.clone()), Arc::new(moved), None);
+        assert!(Arc::ptr_eq(&server.client(), &fresh));
+    }
+
+    #[tokio::test]
+    async fn calls_keep_their_environment_across_requests_when_reloaded() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        // The expand check downloads $metadata before the query is sent
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/$metadata"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(METADATA)
+                    .set_delay(Duration::from_millis(300)),
+            )
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/contacts"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": [
+                {"contactid": "1", "parentcustomerid_account": {"name": "Contoso"}}
+            ]})))
+            .mount(&d365)
+            .await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+        let mut config = (*server.config()).clone();
+        config.denied_entities = vec!["leads".to_string()];
+        let server = D365McpServer::new(server.client(), Arc::new(config.clone()));
+        let args = HashMap::from([
+            ("entity".to_string(), json!("contacts")),
+            ("expand".to_string(), json!("parentcustomerid_account")),
+        ]);
+
+        let mut moved = config;
+        moved.endpoint = format!("{}/other/", d365.uri());
+        let fresh = server_at(&moved.endpoint, true).client();
+        let (running, ()) = tokio::join!(server.call_tool("query_entity", &args), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            server.reload(Some(fresh.clone()), Arc::new(moved), None);
+        });
+
+        assert_ne!(running.is_error, Some(true), "{}", result_text(&running));
+        assert!(result_text(&running).contains("Contoso"));
+        assert!(Arc::ptr_eq(&server.client(), &fresh));
     }
 
-    async fn list_entities(&self) -> CallToolResult {
-        match self.client.fetch_metadata().await {
-            Ok(metadata) => {
-                let entities = extract_entity_sets_from_metadata(&metadata);
-                let text = format!("Available entities:\n{}", entities.join("\n"));
-                CallToolResult::text(text)
-            }
-            Err(e) => CallToolResult::error(format!("Error fetching metadata: {}", e)),
+    #[tokio::test]
+    async fn compare_record_lists_differing_fields_across_environments() {
+        use crate::config::EnvironmentSummary;
+        use wiremock::matchers::{method, path, path_regex};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let mut mocks = Vec::new();
+        for (credit_limit, new_customer) in [(1000, 200), (2500, 404)] {
+            let fno = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/data/$metadata"))
+                .respond_with(ResponseTemplate::new(200).set_body_string(
+                    r#"<Schema Namespace="Microsoft.Dynamics.DataEntities"><EntityType Name="CustomerV3"><Key><PropertyRef Name="dataAreaId" /><PropertyRef Name="CustomerAccount" /></Key><Property Name="dataAreaId" Type="Edm.String" /><Property Name="CustomerAccount" Type="Edm.String" /></EntityType><EntityContainer Name="Resources"><EntitySet Name="CustomersV3" EntityType="Microsoft.Dynamics.DataEntities.CustomerV3" /></EntityContainer></Schema>"#,
+                ))
+                .mount(&fno)
+                .await;
+            Mock::given(method("GET"))
+                .and(path_regex("CustomersV3\\(.*US-001"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "@odata.etag": format!("W/\"{}\"", credit_limit),
+                    "dataAreaId": if credit_limit == 1000 { "usmf" } else { "USMF" },
+                    "CustomerAccount": "US-001",
+                    "CreditLimit": credit_limit,
+                    "CustomerGroupId": "10",
+                })))
+                .mount(&fno)
+                .await;
+            Mock::given(method("GET"))
+                .and(path_regex("CustomersV3\\(.*US-002"))
+                .respond_with(
+                    ResponseTemplate::new(new_customer)
+                        .set_body_json(json!({"CustomerAccount": "US-002"})),
+                )
+                .mount(&fno)
+                .await;
+            Mock::given(method("GET"))
+                .and(path_regex("CustomersV3\\(.*US-404"))
+                .respond_with(ResponseTemplate::new(404))
+                .mount(&fno)
+                .await;
+            mocks.push(fno);
         }
-    }
+        let environments: Vec<EnvironmentSummary> = ["uat", "prod"]
+            .iter()
+            .zip(&mocks)
+            .map(|(name, mock)| EnvironmentSummary {
+                name: name.to_string(),
+                endpoint: format!("{}/data/", mock.uri()),
+                product: ProductType::Finops,
+                production: *name == "prod",
+            })
+            .collect();
+        let primary = finops_server_at(&environments[0].endpoint);
+        let mut config = (*primary.config()).clone();
+        config.environment = Some("uat".to_string());
+        config.environments = environments.clone();
+        config.compare_ignored_fields = vec!["dataAreaId".to_string()];
+        let server = D365McpServer::new(primary.client(), Arc::new(config))
+            .with_environment_loader(environment_loader_for(environments));
 
-    async fn query_entity(&self, args: &HashMap<String, Value>) -> CallToolResult {
-        let entity = match args.get("entity").and_then(|v| v.as_str()) {
-            Some(e) => e,
-            None => return CallToolResult::error("Missing required parameter: entity".to_string()),
-        };
+        let mut args = HashMap::from([
+            ("entity".to_string(), json!("CustomersV3")),
+            (
+                "key".to_string(),
+                json!({"dataAreaId": "usmf", "CustomerAccount": "US-001"}),
+            ),
+            ("source_environment".to_string(), json!("uat")),
+            ("target_environment".to_string(), json!("prod")),
+        ]);
+        let result = server.call_tool("compare_record", &args).await;
+        assert_ne!(result.is_error, Some(true), "{}", result_text(&result));
+        let text = &result.content[0].text;
+        assert!(
+            text.contains("differs in 1 field(s), shown as 'uat' | 'prod' (ignoring dataAreaId)"),
+            "{text}"
+        );
+        assert!(text.ends_with("\n- CreditLimit: 1000 | 2500"), "{text}");
 
-        // Parse select
-        let select = args
-            .get("select")
-            .and_then(|v| v.as_str())
-            .map(|s| s.split(',').map(|f| f.trim().to_string()).collect());
-
-        // Parse filter
-        let filter = args
-            .get("filter")
-            .and_then(|v| v.as_str())
-            .map(String::from);
-
-        // Parse orderby
-        let orderby = args
-            .get("orderby")
-            .and_then(|v| v.as_str())
-            .map(String::from);
-
-        // Parse top (with max limit 1000)
-        let top = parse_number_arg(args, "top").unwrap_or(50).min(1000);
-
-        // Parse skip
-        let skip = parse_number_arg(args, "skip");
-
-        // Parse expand
-        let expand = args
-            .get("expand")
-            .and_then(|v| v.as_str())
-            .map(|s| s.split(',').map(|f| f.trim().to_string()).collect());
-
-        // Parse cross_company (boolean)
-        let cross_company = args
-            .get("cross_company")
-            .and_then(|v| v.as_str().map(|s| s == "true").or_else(|| v.as_bool()))
-            .unwrap_or(false);
+        args.insert("ignore_fields".to_string(), json!("CreditLimit"));
+        let text = result_text(&server.call_tool("compare_record", &args).await);
+        assert!(text.contains("is the same in 'uat' and 'prod'"), "{text}");
 
-        // Parse count (boolean)
-        let count = args
-            .get("count")
-            .and_then(|v| v.as_str().map(|s| s == "true").or_else(|| v.as_bool()))
-            .unwrap_or(false);
+        args.insert(
+            "key".to_string(),
+            json!({"dataAreaId": "usmf", "CustomerAccount": "US-002"}),
+        );
+        let result = server.call_tool("compare_record", &args).await;
+        assert_ne!(result.is_error, Some(true));
+        assert!(
+            result_text(&result).contains("exists only in 'uat'; it was not found in 'prod'"),
+            "{}",
+            result_text(&result)
+        );
 
-        let options = QueryOptions {
-            select,
-            filter,
-            top: Some(top),
-            skip,
-            orderby,
-            expand,
-            cross_company,
-            count,
-        };
+        args.insert(
+            "key".to_string(),
+            json!({"dataAreaId": "usmf", "CustomerAccount": "US-404"}),
+        );
+        let result = server.call_tool("compare_record", &args).await;
+        assert_eq!(result.is_error, Some(true));
+        assert!(result_text(&result).contains("exists in neither 'uat' nor 'prod'"));
 
-        match self.client.fetch_entity_page(entity, None, &options).await {
-            Ok(response) => {
-                let record_count = response.value.len();
-                let has_more = response.next_link.is_some();
-                let total_count = response.count;
-                let json = serde_json::to_string_pretty(&response.value)
-                    .unwrap_or_else(|_| "[]".to_string());
+        args.insert("target_environment".to_string(), json!("uat"));
+        let text = result_text(&server.call_tool("compare_record", &args).await);
+        assert!(text.contains("name two different environments"), "{text}");
+    }
 
-                let mut result = String::new();
+    #[tokio::test]
+    async fn audit_history_is_labelled_and_explains_disabled_auditing() {
+        use wiremock::matchers::{method, path, path_regex, query_param_contains};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
 
-                if let Some(total) = total_count {
-                    result.push_str(&format!("Total records: {}\n", total));
-                }
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/data/v9.2/$metadata"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(METADATA))
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path_regex("RetrieveRecordChangeHistory"))
+            .and(query_param_contains(
+                "@Target",
+                "accounts(5f2a1c3e-8b7d-4e6f-9a0b-1c2d3e4f5a6b)",
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "AuditDetailCollection": {"AuditDetails": [
+                    {"AuditRecord": {
+                        "createdon": "2024-05-02T09:00:00Z",
+                        "action@OData.Community.Display.V1.FormattedValue": "Update",
+                        "_userid_value@OData.Community.Display.V1.FormattedValue": "Jane Doe"},
+                     "OldValue": {"creditlimit": 1000, "name": "Contoso"},
+                     "NewValue": {"creditlimit": 5000, "name": "Contoso Ltd"}},
+                    {"AuditRecord": {
+                        "createdon": "2024-05-01T08:00:00Z",
+                        "action@OData.Community.Display.V1.FormattedValue": "Create",
+                        "_userid_value@OData.Community.Display.V1.FormattedValue": "Sam Lee"}}
+                ]}
+            })))
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path_regex("RetrieveRecordChangeHistory"))
+            .and(query_param_contains(
+                "@Target",
+                "contacts(9c8b7a6d-5e4f-4a3b-8c2d-1e0f9a8b7c6d)",
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "AuditDetailCollection": {"AuditDetails": []}
+            })))
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path_regex("/Attributes$"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": [
+                {"LogicalName": "creditlimit",
+                 "DisplayName": {"UserLocalizedLabel": {"Label": "Credit Limit"}}}
+            ]})))
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/data/v9.2/organizations"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({"value": [{"isauditenabled": true}]})),
+            )
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/data/v9.2/EntityDefinitions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": [
+                {"IsAuditEnabled": {"Value": false}}
+            ]})))
+            .mount(&d365)
+            .await;
 
-                result.push_str(&format!(
-                    "Showing {} records{}:\n\n{}",
-                    record_count,
-                    if has_more { " (more available)" } else { "" },
-                    json
-                ));
+        let server = server_at(&format!("{}/api/data/v9.2/", d365.uri()), true);
+        let record = |entity: &str, id: &str| {
+            HashMap::from([
+                ("entity".to_string(), json!(entity)),
+                ("id".to_string(), json!(id)),
+            ])
+        };
 
-                CallToolResult::text(result)
-            }
-            Err(e) => CallToolResult::error(format!("Error querying {}: {}", entity, e)),
-        }
+        let text = result_text(
+            &server
+                .call_tool(
+                    "get_record_audit",
+                    &record("accounts", "5f2a1c3e-8b7d-4e6f-9a0b-1c2d3e4f5a6b"),
+                )
+                .await,
+        );
+        assert!(text.contains("(2 changes)"), "{text}");
+        let created = text.find("Create by Sam Lee").expect(&text);
+        let updated = text.find("Update by Jane Doe").expect(&text);
+        assert!(created < updated, "{text}");
+        assert!(
+            text.contains("- Credit Limit (creditlimit): 1000 → 5000"),
+            "{text}"
+        );
+        assert!(text.contains("- name: Contoso → Contoso Ltd"), "{text}");
+
+        let mut args = record("accounts", "5f2a1c3e-8b7d-4e6f-9a0b-1c2d3e4f5a6b");
+        args.insert("field".to_string(), json!("creditlimit"));
+        args.insert("top".to_string(), json!(1));
+        let text = result_text(&server.call_tool("get_record_audit", &args).await);
+        assert!(text.contains("(1 changes)"), "{text}");
+        assert!(!text.contains("Contoso Ltd"), "{text}");
+
+        let text = result_text(
+            &server
+                .call_tool(
+                    "get_record_audit",
+                    &record("contacts", "9c8b7a6d-5e4f-4a3b-8c2d-1e0f9a8b7c6d"),
+                )
+                .await,
+        );
+        assert!(
+            text.contains("Auditing is not enabled for table 'contact'"),
+            "{text}"
+        );
     }
 
-    async fn get_entity_schema(&self, args: &HashMap<String, Value>) -> CallToolResult {
-        let entity = match args.get("entity").and_then(|v| v.as_str()) {
-            Some(e) => e,
-            None => return CallToolResult::error("Missing required parameter: entity".to_string()),
+    #[test]
+    fn top_limits_prefer_the_entity_then_global_then_built_in() {
+        let mut config = (*test_server(true).config()).clone();
+        config.entities = [
+            "name = 'GeneralJournalAccountEntries'\nmax_top = 20",
+            "name = 'PaymentTerms'\ndefault_top = 500\nmax_top = 5000",
+            "name = 'accounts'",
+        ]
+        .iter()
+        .map(|entry| toml::from_str(entry).unwrap())
+        .collect();
+        let limits = |config: &RuntimeConfig, entity: &str| {
+            top_limits(config, configured_entity(config, entity))
         };
 
-        let options = QueryOptions {
-            top: Some(1),
-            ..Default::default()
+        // Nothing configured: the built-in 50 and 1000
+        let built_in = TopLimits {
+            default: 50,
+            max: 1000,
         };
+        assert_eq!(limits(&config, "accounts"), built_in);
+        assert_eq!(limits(&config, "contacts"), built_in);
 
-        match self.client.fetch_entity_page(entity, None, &options).await {
-            Ok(response) => {
-                if let Some(sample) = response.value.into_iter().next() {
-                    if let Value::Object(map) = &sample {
-                        let fields: Vec<String> = map.keys().cloned().collect();
-                        let result = format!(
-                            "Entity: {}\nFields ({}):\n{}\n\nSample record:\n{}",
-                            entity,
-                            fields.len(),
-                            fields.join(", "),
-                            serde_json::to_string_pretty(&sample).unwrap_or_default()
-                        );
-                        CallToolResult::text(result)
-                    } else {
-                        CallToolResult::text(
-                            serde_json::to_string_pretty(&sample).unwrap_or_default(),
-                        )
-                    }
-                } else {
-                    CallToolResult::text(format!("No records found in entity '{}'", entity))
-                }
+        config.default_top = Some(100);
+        config.max_top = Some(300);
+        assert_eq!(
+            limits(&config, "accounts"),
+            TopLimits {
+                default: 100,
+                max: 300
             }
-            Err(e) => CallToolResult::error(format!("Error fetching schema for {}: {}", entity, e)),
-        }
+        );
+        // The entity's max wins and pulls the global default under it
+        assert_eq!(
+            limits(&config, "generaljournalaccountentries"),
+            TopLimits {
+                default: 20,
+                max: 20
+            }
+        );
+        assert_eq!(
+            limits(&config, "PaymentTerms"),
+            TopLimits {
+                default: 500,
+                max: 5000
+            }
+        );
     }
 
-    async fn get_record(&self, args: &HashMap<String, Value>) -> CallToolResult {
-        let entity = match args.get("entity").and_then(|v| v.as_str()) {
-            Some(e) => e,
-            None => return CallToolResult::error("Missing required parameter: entity".to_string()),
+    #[test]
+    fn a_top_over_the_max_is_lowered_with_a_note() {
+        let limits = TopLimits {
+            default: 20,
+            max: 200,
         };
+        assert_eq!(limit_top(None, limits, "LedgerEntries"), (20, None));
+        assert_eq!(limit_top(Some(150), limits, "LedgerEntries"), (150, None));
+        assert_eq!(
+            limit_top(Some(1000), limits, "LedgerEntries"),
+            (
+                200,
+                Some(
+                    "Note: top=1000 lowered to 200, the most LedgerEntries returns per page (max_top); use page_token for more"
+                        .to_string()
+                )
+            )
+        );
+    }
 
-        let id = match args.get("id").and_then(|v| v.as_str()) {
-            Some(i) => i,
-            None => return CallToolResult::error("Missing required parameter: id".to_string()),
-        };
+    #[tokio::test]
+    async fn configured_top_limits_are_advertised_and_enforced() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
 
-        // Format key - GUIDs should be wrapped in quotes for OData
-        let key = if id.contains('-') && !id.starts_with('\'') {
-            format!("'{}'", id)
-        } else {
-            id.to_string()
-        };
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .and(query_param("$top", "20"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+            .expect(2)
+            .mount(&d365)
+            .await;
+        let base = server_at(&format!("{}/data/", d365.uri()), true);
+        let mut config = (*base.config()).clone();
+        config.default_top = Some(10);
+        config.entities = vec![toml::from_str(
+            r#"
+            name = "accounts"
+            max_top = 20
+            "#,
+        )
+        .unwrap()];
+        config.entities.push(
+            toml::from_str(
+                r#"
+                name = "transactioncurrencies"
+                default_top = 2000
+                max_top = 2000
+                "#,
+            )
+            .unwrap(),
+        );
+        let server = D365McpServer::new(base.client(), Arc::new(config));
 
-        match self.client.get_entity(entity, &key).await {
-            Ok(record) => {
-                let json = serde_json::to_string_pretty(&record).unwrap_or_default();
-                CallToolResult::text(json)
-            }
-            Err(e) => CallToolResult::error(format!("Error: {}", e)),
-        }
+        let tool = server
+            .get_tools()
+            .into_iter()
+            .find(|tool| tool.name == "query_entity")
+            .unwrap();
+        let top = &tool.input_schema["properties"]["top"];
+        assert_eq!(top["default"], json!(10));
+        assert!(top.get("maximum").is_none(), "{}", top);
+        assert!(
+            tool.description.ends_with(
+                "Entities with their own top limits: accounts (default 10, max 20), \
+                 transactioncurrencies (default 2000, max 2000); a larger top is lowered to the max."
+            ),
+            "{}",
+            tool.description
+        );
+
+        let args = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            ("top".to_string(), json!(500)),
+        ]);
+        let result = server.call_tool("query_entity", &args).await;
+        assert!(
+            result.content[0].text.starts_with(
+                "Note: top=500 lowered to 20, the most accounts returns per page (max_top)"
+            ),
+            "{}",
+            result.content[0].text
+        );
+        // Beyond every configured max it is still lowered, not rejected
+        let args = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            ("top".to_string(), json!(5000)),
+        ]);
+        let result = server.call_tool("query_entity", &args).await;
+        assert!(
+            result.content[0].text.starts_with(
+                "Note: top=5000 lowered to 20, the most accounts returns per page (max_top)"
+            ),
+            "{}",
+            result.content[0].text
+        );
     }
 
-    async fn delete_record(&self, args: &HashMap<String, Value>) -> CallToolResult {
-        let entity = match args.get("entity").and_then(|v| v.as_str()) {
-            Some(e) => e,
-            None => return CallToolResult::error("Missing required parameter: entity".to_string()),
+    #[test]
+    fn entity_defaults_merge_with_the_arguments() {
+        let defaults: EntityConfig = toml::from_str(
+            r#"
+            name = "SalesOrderHeadersV2"
+            default_select = ["SalesOrderNumber", "OrderingCustomerAccountNumber"]
+            default_filter = "SalesOrderStatus ne 'Cancelled'"
+            "#,
+        )
+        .unwrap();
+        let options = |args: Value| {
+            let args: HashMap<String, Value> = serde_json::from_value(args).unwrap();
+            parse_query_options(&args).unwrap()
         };
 
-        let confirm = args.get("confirm").and_then(|v| v.as_str()).unwrap_or("");
-        if confirm != "DELETE" {
-            return CallToolResult::error(
-                "Deletion not executed. Set confirm to exactly 'DELETE'.".to_string(),
-            );
-        }
+        let mut plain = options(json!({}));
+        assert!(apply_entity_defaults(&mut plain, &defaults).unwrap());
+        assert_eq!(plain.select.unwrap().len(), 2);
+        assert_eq!(plain.filter.unwrap(), "SalesOrderStatus ne 'Cancelled'");
 
-        let key = match parse_delete_key(args) {
-            Ok(key) => key,
-            Err(message) => return CallToolResult::error(message),
-        };
+        // An explicit filter is ANDed, an explicit select wins
+        let mut explicit = options(json!({
+            "select": "SalesOrderNumber",
+            "filter": "dataAreaId eq 'usmf' or dataAreaId eq 'usrt'"
+        }));
+        assert!(!apply_entity_defaults(&mut explicit, &defaults).unwrap());
+        assert_eq!(explicit.select.unwrap(), vec!["SalesOrderNumber"]);
+        assert_eq!(
+            explicit.filter.unwrap(),
+            "(dataAreaId eq 'usmf' or dataAreaId eq 'usrt') and (SalesOrderStatus ne 'Cancelled')"
+        );
 
-        let if_match = args.get("if_match").and_then(|v| v.as_str());
+        let mut all = options(json!({"select": "*"}));
+        assert!(!apply_entity_defaults(&mut all, &defaults).unwrap());
+        assert_eq!(all.select, None);
 
-        match self.client.delete_entity(entity, &key, if_match).await {
-            Ok(()) => CallToolResult::text(format!(
-                "Deleted record from entity '{}' with key ({})",
-                entity, key
-            )),
-            Err(e) => CallToolResult::error(format!("Error deleting {}({}): {}", entity, key, e)),
-        }
+        let broken = EntityConfig {
+            default_filter: Some("SalesOrderStatus eq 'Open".to_string()),
+            ..defaults
+        };
+        let error = apply_entity_defaults(&mut options(json!({})), &broken).unwrap_err();
+        assert!(error.contains("default_filter"), "{error}");
     }
 
-    async fn get_environment_info(&self) -> CallToolResult {
-        let info = format!(
-            "D365 Environment Info:\n\
-             - Endpoint: {}\n\
-             - Product: {:?}\n\
-             - Page Size: {}\n\
-             - Configured Entities: {}",
-            self.client.endpoint(),
-            self.client.product(),
-            self.config.page_size,
-            self.config
-                .entities
-                .iter()
-                .map(|e| e.name.as_str())
-                .collect::<Vec<_>>()
-                .join(", ")
+    #[test]
+    fn company_scoping_keeps_explicit_values() {
+        assert_eq!(company_filter(None, "o'hara"), "dataAreaId eq 'o''hara'");
+        assert_eq!(
+            company_key_expression("CustomerAccount='US-001'", "usmf"),
+            "dataAreaId='usmf',CustomerAccount='US-001'"
+        );
+        // Already scoped, or a bare value with no field names to join
+        assert_eq!(
+            company_key_expression("DataAreaId='dat',CustomerAccount='US-001'", "usmf"),
+            "DataAreaId='dat',CustomerAccount='US-001'"
+        );
+        assert_eq!(company_key_expression("'US-001'", "usmf"), "'US-001'");
+        // Only a field named dataAreaId counts, not one containing it or a value
+        assert_eq!(
+            company_key_expression("PrimaryDataAreaId='dat',Name='dataAreaId=x'", "usmf"),
+            "dataAreaId='usmf',PrimaryDataAreaId='dat',Name='dataAreaId=x'"
+        );
+        assert_eq!(
+            company_payload(&json!({"dataareaid": "dat"}), "usmf"),
+            json!({"dataareaid": "dat"})
         );
-        CallToolResult::text(info)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde_json::json;
+    #[tokio::test]
+    async fn entities_are_paged_filtered_and_searched() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
 
-    #[test]
-    fn static_tools_include_delete_record() {
-        let tools = D365McpServer::get_tools_static();
+        let d365 = metadata_server().await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
 
-        assert!(tools.iter().any(|tool| tool.name == "delete_record"));
-    }
+        let args = HashMap::from([("limit".to_string(), json!(1))]);
+        let text = result_text(&server.call_tool("list_entities", &args).await);
+        assert!(text.contains("Entities 1-1 of 2"), "{text}");
+        assert!(text.contains("offset=1"), "{text}");
 
-    #[test]
-    fn parse_delete_key_prefers_raw_key_expression() {
-        let mut args = HashMap::new();
-        args.insert(
-            "key".to_string(),
-            json!("dataAreaId='bc',SalesOrderNumber='SO-001'"),
+        let args = HashMap::from([("filter".to_string(), json!("CONT"))]);
+        let text = result_text(&server.call_tool("list_entities", &args).await);
+        assert!(text.contains("Entities 1-1 of 1 matching 'cont'"), "{text}");
+        assert!(!text.contains("offset="), "{text}");
+
+        let args = HashMap::from([("prefix".to_string(), json!("Acc"))]);
+        let text = result_text(&server.call_tool("list_entities", &args).await);
+        assert!(
+            text.contains("Entities 1-1 of 1 starting with 'acc'"),
+            "{text}"
+        );
+        assert!(
+            text.contains("accounts (account): Business that represents a customer"),
+            "{text}"
         );
-        args.insert("id".to_string(), json!("ignored"));
 
-        assert_eq!(
-            parse_delete_key(&args).unwrap(),
-            "dataAreaId='bc',SalesOrderNumber='SO-001'"
+        // Configured entities lead under their own heading
+        let mut config = (*server.config()).clone();
+        config.entities = vec![toml::from_str("name = \"contacts\"").unwrap()];
+        let configured = D365McpServer::new(server.client(), Arc::new(config));
+        let result = configured.call_tool("list_entities", &HashMap::new()).await;
+        assert!(
+            result.content[0]
+                .text
+                .contains("Configured:\ncontacts (contact)\n\nOther entities:\naccounts (account)"),
+            "{}",
+            result_text(&result)
         );
-    }
 
-    #[test]
-    fn parse_delete_key_formats_simple_string_id() {
-        let mut args = HashMap::new();
-        args.insert("id".to_string(), json!("CUS-001"));
+        let args = HashMap::from([("query".to_string(), json!("acount"))]);
+        let text = result_text(&server.call_tool("search_entities", &args).await);
+        assert!(text.contains("1. accounts (account)"), "{text}");
 
-        assert_eq!(parse_delete_key(&args).unwrap(), "'CUS-001'");
+        // No silent fallback to example entity names
+        let empty = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/$metadata"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("<edmx:Edmx />"))
+            .mount(&empty)
+            .await;
+        let server = server_at(&format!("{}/data/", empty.uri()), true);
+        let result = server.call_tool("list_entities", &HashMap::new()).await;
+        assert_eq!(result.is_error, Some(true));
+        assert!(result_text(&result).contains("No entity sets found"));
     }
 
-    #[test]
-    fn parse_delete_key_keeps_numeric_id_unquoted() {
-        let mut args = HashMap::new();
-        args.insert("id".to_string(), json!("5637144576"));
+    #[tokio::test]
+    async fn query_fields_are_checked_against_cached_metadata() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, ResponseTemplate};
 
-        assert_eq!(parse_delete_key(&args).unwrap(), "5637144576");
-    }
-}
+        let d365 = metadata_server().await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+            .mount(&d365)
+            .await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+        let args = HashMap::from([
+            ("entity".to_string(), json!("accounts")),
+            ("select".to_string(), json!("Name,acountid")),
+        ]);
 
-/// Extract entity set names from EDMX metadata XML
-fn extract_entity_sets_from_metadata(metadata: &str) -> Vec<String> {
-    let mut entities = Vec::new();
+        // Nothing cached yet: the query goes out unchecked
+        let result = server.call_tool("query_entity", &args).await;
+        assert_ne!(result.is_error, Some(true), "{}", result_text(&result));
+        assert!(d365
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .all(|request| !request.url.path().ends_with("$metadata")));
 
-    for line in metadata.lines() {
-        if line.contains("EntitySet") && line.contains("Name=") {
-            if let Some(start) = line.find("Name=\"") {
-                let rest = &line[start + 6..];
-                if let Some(end) = rest.find('"') {
-                    entities.push(rest[..end].to_string());
-                }
-            }
-        }
-    }
+        server.client().metadata_model().await.unwrap();
+        let result = server.call_tool("query_entity", &args).await;
+        assert_eq!(result.is_error, Some(true));
+        let text = &result.content[0].text;
+        assert!(
+            text.contains("- 'Name' in select (did you mean 'name'?)"),
+            "{text}"
+        );
+        assert!(
+            text.contains("- 'acountid' in select (did you mean 'accountid'?)"),
+            "{text}"
+        );
 
-    if entities.is_empty() {
-        entities = vec![
-            "accounts".to_string(),
-            "contacts".to_string(),
-            "leads".to_string(),
-            "opportunities".to_string(),
-            "(try specific entity name)".to_string(),
-        ];
+        let mut unchecked = args.clone();
+        unchecked.insert("validate".to_string(), json!(false));
+        let result = server.call_tool("query_entity", &unchecked).await;
+        assert_ne!(result.is_error, Some(true), "{}", result_text(&result));
     }
 
-    entities
-}
+    #[tokio::test]
+    async fn option_sets_come_from_metadata_and_global_definitions() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, ResponseTemplate};
 
-/// Parse a number argument from JSON (handles both string and number types)
-fn parse_number_arg(args: &HashMap<String, Value>, key: &str) -> Option<usize> {
-    args.get(key).and_then(|v| {
-        v.as_i64()
-            .map(|n| n as usize)
-            .or_else(|| v.as_str().and_then(|s| s.parse().ok()))
-    })
-}
+        let d365 = metadata_server().await;
+        Mock::given(method("GET"))
+            .and(path("/data/GlobalOptionSetDefinitions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": [{"Name": "budgetstatus"}, {"Name": "componentstate"}]
+            })))
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path(
+                "/data/GlobalOptionSetDefinitions(Name='budgetstatus')",
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "Name": "budgetstatus",
+                "Options": [{"Value": 1, "Label": {"UserLocalizedLabel": {"Label": "May Buy"}}}]
+            })))
+            .mount(&d365)
+            .await;
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
 
-fn parse_delete_key(args: &HashMap<String, Value>) -> Result<String, String> {
-    if let Some(key) = args.get("key").and_then(|v| v.as_str()) {
-        let key = key.trim();
-        if !key.is_empty() {
-            return Ok(key.trim_matches(['(', ')']).to_string());
-        }
-    }
+        let all = result_text(&server.call_tool("list_optionsets", &HashMap::new()).await);
+        assert!(all.contains("2 option sets"), "{all}");
+        let args = HashMap::from([("prefix".to_string(), json!("BUDGET"))]);
+        let filtered = result_text(&server.call_tool("list_optionsets", &args).await);
+        assert!(
+            filtered.contains("1 option sets starting with 'budget'"),
+            "{filtered}"
+        );
 
-    if let Some(id) = args.get("id").and_then(|v| v.as_str()) {
-        let id = id.trim();
-        if !id.is_empty() {
-            return Ok(format_simple_key(id));
-        }
+        let args = HashMap::from([("name".to_string(), json!("componentstate"))]);
+        let from_edmx = result_text(&server.call_tool("get_optionset", &args).await);
+        assert!(
+            from_edmx
+                .contains("| Deleted | 2 | `Microsoft.Dynamics.CRM.ComponentState'Deleted'` |"),
+            "{from_edmx}"
+        );
+
+        let args = HashMap::from([("name".to_string(), json!("budgetstatus"))]);
+        let global = result_text(&server.call_tool("get_optionset", &args).await);
+        assert!(global.contains("| May Buy | 1 |"), "{global}");
     }
 
-    Err("Missing required parameter: key or id".to_string())
-}
+    #[tokio::test]
+    async fn list_companies_falls_back_and_caches_until_refresh() {
+        use wiremock::matchers::{method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
 
-fn format_simple_key(id: &str) -> String {
-    if id.starts_with('\'') || id.contains('=') || id.parse::<i64>().is_ok() {
-        id.to_string()
-    } else {
-        format!("'{}'", id)
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/Companies"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/LegalEntities"))
+            .and(query_param("cross-company", "true"))
+            .and(query_param("$select", "LegalEntityId,Name"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": [
+                {"LegalEntityId": "usmf", "Name": "Contoso USA"},
+                {"LegalEntityId": "DEMF", "Name": "Contoso Germany"}
+            ]})))
+            .expect(2)
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/$metadata"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(METADATA))
+            .mount(&d365)
+            .await;
+
+        let server = finops_server_at(&format!("{}/data/", d365.uri()));
+        assert!(server
+            .get_tools()
+            .iter()
+            .any(|tool| tool.name == "list_companies"));
+
+        let args = HashMap::new();
+        let first = result_text(&server.call_tool("list_companies", &args).await);
+        assert!(
+            first.contains("2 companies (from LegalEntities)"),
+            "{first}"
+        );
+        assert!(
+            first.contains("| DEMF | Contoso Germany |\\n| usmf | Contoso USA |"),
+            "{first}"
+        );
+
+        let cached = result_text(&server.call_tool("list_companies", &args).await);
+        assert!(cached.contains("(from LegalEntities, cached)"), "{cached}");
+
+        server.call_tool("refresh_metadata", &args).await;
+        let reloaded = result_text(&server.call_tool("list_companies", &args).await);
+        assert!(!reloaded.contains("cached"), "{reloaded}");
     }
-}
 
-impl D365McpServer {
-    /// Force refresh metadata cache
-    async fn refresh_metadata(&self) -> CallToolResult {
-        // Invalidate cache
-        self.client.invalidate_metadata_cache().await;
+    #[tokio::test]
+    async fn timed_out_paging_returns_the_pages_already_fetched() {
+        use wiremock::matchers::{method, path, query_param, query_param_is_missing};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
 
-        // Fetch fresh metadata
-        match self.client.fetch_metadata().await {
-            Ok(metadata) => {
-                let size_kb = metadata.len() / 1024;
-                let entity_count = extract_entity_sets_from_metadata(&metadata).len();
+        let d365 = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/Companies"))
+            .and(query_param_is_missing("page"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": [{"DataArea": "usmf", "Name": "Contoso USA"}],
+                "@odata.nextLink": format!("{}/data/Companies?page=2", d365.uri())
+            })))
+            .mount(&d365)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/Companies"))
+            .and(query_param("page", "2"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(
+                        json!({"value": [{"DataArea": "demf", "Name": "Contoso Germany"}]}),
+                    )
+                    .set_delay(Duration::from_secs(5)),
+            )
+            .mount(&d365)
+            .await;
+        let server = finops_server_at(&format!("{}/data/", d365.uri()));
 
-                CallToolResult::text(format!(
-                    "Metadata cache refreshed successfully.\n\
-                     - Size: {} KB\n\
-                     - Entities found: {}",
-                    size_kb, entity_count
-                ))
-            }
-            Err(e) => CallToolResult::error(format!("Failed to refresh metadata: {}", e)),
-        }
+        let args = HashMap::from([("timeout".to_string(), json!(1))]);
+        let partial = result_text(&server.call_tool("list_companies", &args).await);
+        assert!(partial.contains("| usmf | Contoso USA |"), "{partial}");
+        assert!(!partial.contains("demf"), "{partial}");
+        assert!(
+            partial.contains("Partial results: stopped after 1 records due to timeout"),
+            "{partial}"
+        );
+
+        // A partial list is not cached, and a single request that runs out of
+        // time is an error
+        let result = server
+            .call_tool(
+                "query_entity",
+                &HashMap::from([
+                    ("entity".to_string(), json!("Companies")),
+                    (
+                        "page_token".to_string(),
+                        json!(encode_page_token(&format!(
+                            "{}/data/Companies?page=2",
+                            d365.uri()
+                        ))),
+                    ),
+                    ("timeout".to_string(), json!(1)),
+                ]),
+            )
+            .await;
+        assert_eq!(result.is_error, Some(true));
+        assert!(
+            result_text(&result).contains("Timed out after 1 seconds"),
+            "{}",
+            result_text(&result)
+        );
+        assert!(server.environment().companies.read().await.is_none());
     }
 
-    /// Get metadata for a specific entity including properties and navigation properties
-    async fn get_metadata(&self, args: &HashMap<String, Value>) -> CallToolResult {
-        let entity = match args.get("entity").and_then(|v| v.as_str()) {
-            Some(e) => e,
-            None => return CallToolResult::error("Missing required argument: entity".to_string()),
-        };
+    #[tokio::test]
+    async fn list_companies_is_finops_only() {
+        let server = test_server(true);
+        assert!(!server
+            .get_tools()
+            .iter()
+            .any(|tool| tool.name == "list_companies"));
 
-        // Fetch metadata
-        let metadata = match self.client.fetch_metadata().await {
-            Ok(m) => m,
-            Err(e) => return CallToolResult::error(format!("Failed to fetch metadata: {}", e)),
-        };
+        let result = server.call_tool("list_companies", &HashMap::new()).await;
+        assert_eq!(result.is_error, Some(true));
+        assert!(result_text(&result).contains("only available for Finance & Operations"));
+    }
 
-        // Parse entity information
-        match crate::odata::ODataClient::parse_entity_from_metadata(&metadata, entity) {
-            Ok((properties, nav_properties, key_fields)) => {
-                let mut output = String::new();
+    #[tokio::test]
+    async fn whoami_resolves_the_dataverse_user_and_business_unit() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
 
-                output.push_str(&format!("## Entity: {}\n\n", entity));
+        let d365 = MockServer::start().await;
+        let user_id = "11111111-1111-1111-1111-111111111111";
+        let unit_id = "22222222-2222-2222-2222-222222222222";
+        let records = [
+            (
+                "/data/WhoAmI()".to_string(),
+                json!({"UserId": user_id, "BusinessUnitId": unit_id, "OrganizationId": "org"}),
+            ),
+            (
+                format!("/data/systemusers({})", user_id),
+                json!({"fullname": "# Integration", "domainname": "app@contoso.com",
+                       "applicationid": "app-1"}),
+            ),
+            (
+                format!("/data/businessunits({})", unit_id),
+                json!({"name": "Contoso"}),
+            ),
+        ];
+        for (record_path, body) in records {
+            Mock::given(method("GET"))
+                .and(path(record_path))
+                .respond_with(ResponseTemplate::new(200).set_body_json(body))
+                .mount(&d365)
+                .await;
+        }
 
-                // Key fields
-                if !key_fields.is_empty() {
-                    output.push_str("### Key Fields\n");
-                    for key in &key_fields {
-                        output.push_str(&format!("- {}\n", key));
-                    }
-                    output.push('\n');
-                }
+        let server = server_at(&format!("{}/data/", d365.uri()), true);
+        let text = result_text(&server.call_tool("whoami", &HashMap::new()).await);
 
-                // Properties
-                output.push_str(&format!("### Properties ({} fields)\n", properties.len()));
-                for prop in &properties {
-                    output.push_str(&format!("- {}\n", prop));
-                }
-                output.push('\n');
+        assert!(text.contains("Method: Dataverse WhoAmI function"), "{text}");
+        assert!(text.contains(&format!("- User Id: {}", user_id)), "{text}");
+        assert!(text.contains("- Name: # Integration"), "{text}");
+        assert!(text.contains("- Application Id: app-1"), "{text}");
+        assert!(text.contains("Business Unit: Contoso"), "{text}");
+    }
 
-                // Navigation properties (expandable)
-                if !nav_properties.is_empty() {
-                    output.push_str(&format!(
-                        "### Navigation Properties (expandable via $expand) ({} fields)\n",
-                        nav_properties.len()
-                    ));
-                    for nav in &nav_properties {
-                        output.push_str(&format!("- {}\n", nav));
-                    }
-                }
+    #[test]
+    fn token_claims_show_application_and_roles() {
+        let text = format_token_claims(&json!({
+            "azp": "app-2",
+            "tid": "tenant-1",
+            "roles": ["Data.Read", "Data.Write"],
+            "exp": 0
+        }));
 
-                CallToolResult::text(output)
-            }
-            Err(e) => CallToolResult::error(format!("Failed to parse entity metadata: {}", e)),
+        assert!(
+            text.starts_with("Method: decoded access token claims"),
+            "{text}"
+        );
+        assert!(text.contains("- Application Id: app-2"), "{text}");
+        assert!(text.contains("- Tenant Id: tenant-1"), "{text}");
+        assert!(text.contains("- Roles: Data.Read, Data.Write"), "{text}");
+        assert!(
+            text.contains("- Expires: 1970-01-01 00:00:00 UTC ("),
+            "{text}"
+        );
+        assert!(format_token_claims(&json!({})).contains("- Roles: none"));
+    }
+
+    #[test]
+    fn every_tool_declares_behavior_hints() {
+        for tool in D365McpServer::get_tools_static() {
+            let annotations = tool.annotations.as_ref().expect(&tool.name);
+
+            assert!(annotations.title.is_some(), "{}", tool.name);
+            // export_entity changes nothing in the service, but writes files
+            assert_eq!(
+                annotations.read_only_hint,
+                Some(!is_mutating_tool(&tool.name) && tool.name != "export_entity"),
+                "{}",
+                tool.name
+            );
         }
+
+        let tools = D365McpServer::get_tools_static();
+        let delete = tools
+            .iter()
+            .find(|tool| tool.name == "delete_record")
+            .unwrap();
+        assert_eq!(
+            delete.annotations.as_ref().unwrap().destructive_hint,
+            Some(true)
+        );
     }
 }
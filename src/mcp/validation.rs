@@ -0,0 +1,253 @@
+//! Tool argument validation
+//!
+//! Checks `tools/call` arguments against the tool's declared input schema so
+//! a misspelled argument fails loudly instead of being ignored.
+
+use crate::odata::metadata::levenshtein;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Check arguments against a JSON schema of the form built by `create_tool_schema`.
+///
+/// Returns one line per problem: missing required arguments, unknown
+/// arguments (with a suggestion when one is a close match) and values that
+/// cannot be coerced to the declared type.
+pub fn validate_arguments(
+    schema: &Value,
+    args: &HashMap<String, Value>,
+) -> Result<(), Vec<String>> {
+    let empty = serde_json::Map::new();
+    let properties = schema
+        .get("properties")
+        .and_then(Value::as_object)
+        .unwrap_or(&empty);
+    let required = schema
+        .get("required")
+        .and_then(Value::as_array)
+        .map(|names| names.iter().filter_map(Value::as_str).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let mut problems = Vec::new();
+
+    for name in &required {
+        if args.get(*name).is_none_or(Value::is_null) {
+            problems.push(format!("missing required argument '{}'", name));
+        }
+    }
+
+    // Sorted so the message does not depend on HashMap order
+    let mut names: Vec<&String> = args.keys().collect();
+    names.sort();
+
+    for name in names {
+        let value = &args[name];
+        match properties.get(name) {
+            Some(property) => {
+                if let Err(problem) = check_value(property, value) {
+                    problems.push(format!("argument '{}' {}", name, problem));
+                }
+            }
+            None => {
+                let suggestion = closest_match(name, properties.keys().map(String::as_str))
+                    .map(|close| format!(" (did you mean '{}'?)", close))
+                    .unwrap_or_default();
+                problems.push(format!("unknown argument '{}'{}", name, suggestion));
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems)
+    }
+}
+
+/// Check a value against a property schema; stringly numbers and booleans are accepted
+fn check_value(property: &Value, value: &Value) -> Result<(), String> {
+    // An explicit null is treated like an omitted optional argument
+    if value.is_null() {
+        return Ok(());
+    }
+
+    let expected = property
+        .get("type")
+        .and_then(Value::as_str)
+        .unwrap_or("string");
+    if expected == "object" {
+        return match value {
+            Value::Object(_) => Ok(()),
+            _ => Err(format!("must be an object, got {}", value)),
+        };
+    }
+    let text = match value {
+        Value::String(s) => Some(s.trim().to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    };
+
+    let coerced = match expected {
+        "integer" => text
+            .as_deref()
+            .and_then(|t| t.parse::<i64>().ok())
+            .map(Value::from),
+        "number" => text
+            .as_deref()
+            .and_then(|t| t.parse::<f64>().ok())
+            .map(Value::from),
+        "boolean" => match text.as_deref().map(str::to_lowercase).as_deref() {
+            Some("true") => Some(Value::Bool(true)),
+            Some("false") => Some(Value::Bool(false)),
+            _ => None,
+        },
+        _ => text.clone().map(Value::String),
+    };
+
+    let Some(coerced) = coerced else {
+        return Err(format!(
+            "must be {}, got {}",
+            describe_type(expected),
+            value
+        ));
+    };
+
+    if let Some(allowed) = property.get("enum").and_then(Value::as_array) {
+        let matches = allowed.iter().any(|option| match (option, &coerced) {
+            (Value::String(option), Value::String(given)) => option.eq_ignore_ascii_case(given),
+            (option, given) => option == given,
+        });
+        if !matches {
+            let options: Vec<String> = allowed.iter().map(Value::to_string).collect();
+            return Err(format!(
+                "must be one of {}, got {}",
+                options.join(", "),
+                value
+            ));
+        }
+    }
+
+    if let Some(number) = coerced.as_f64() {
+        if let Some(min) = property.get("minimum").and_then(Value::as_f64) {
+            if number < min {
+                return Err(format!("must be at least {}, got {}", min, value));
+            }
+        }
+        if let Some(max) = property.get("maximum").and_then(Value::as_f64) {
+            if number > max {
+                return Err(format!("must be at most {}, got {}", max, value));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn describe_type(expected: &str) -> &'static str {
+    match expected {
+        "integer" => "an integer",
+        "number" => "a number",
+        "boolean" => "true or false",
+        _ => "a string",
+    }
+}
+
+/// The known name closest to `name`, if it is close enough to be a likely typo
+fn closest_match<'a>(name: &str, known: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let lowered = name.to_lowercase();
+    known
+        .map(|candidate| (levenshtein(&lowered, &candidate.to_lowercase()), candidate))
+        .filter(|(distance, candidate)| *distance <= (candidate.len() / 3).max(2))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "entity": {"type": "string"},
+                "filter": {"type": "string"},
+                "top": {"type": "integer", "minimum": 1, "maximum": 1000},
+                "count": {"type": "boolean"},
+                "format": {"type": "string", "enum": ["json", "table", "csv"]}
+            },
+            "required": ["entity"]
+        })
+    }
+
+    fn args(value: Value) -> HashMap<String, Value> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn levenshtein_distances() {
+        assert_eq!(levenshtein("filters", "filter"), 1);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("", "top"), 3);
+    }
+
+    #[test]
+    fn stringly_values_are_coerced() {
+        let given =
+            args(json!({"entity": "accounts", "top": "10", "count": "TRUE", "format": "CSV"}));
+        assert!(validate_arguments(&schema(), &given).is_ok());
+
+        let given = args(json!({"entity": "accounts", "top": 10, "count": true, "filter": null}));
+        assert!(validate_arguments(&schema(), &given).is_ok());
+    }
+
+    #[test]
+    fn reports_every_problem_with_suggestions() {
+        let given =
+            args(json!({"filters": "name eq 'x'", "top": "ten", "count": [1], "format": "xml"}));
+
+        let problems = validate_arguments(&schema(), &given).unwrap_err();
+        assert_eq!(
+            problems,
+            vec![
+                "missing required argument 'entity'",
+                "argument 'count' must be true or false, got [1]",
+                "unknown argument 'filters' (did you mean 'filter'?)",
+                "argument 'format' must be one of \"json\", \"table\", \"csv\", got \"xml\"",
+                "argument 'top' must be an integer, got \"ten\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn range_and_distant_names() {
+        let given = args(json!({"entity": "accounts", "top": 5000, "zzz": 1}));
+
+        let problems = validate_arguments(&schema(), &given).unwrap_err();
+        assert_eq!(
+            problems,
+            vec![
+                "argument 'top' must be at most 1000, got 5000",
+                "unknown argument 'zzz'",
+            ]
+        );
+    }
+
+    #[test]
+    fn object_arguments_must_be_objects() {
+        let schema = json!({
+            "type": "object",
+            "properties": {"key": {"type": "object"}}
+        });
+
+        let given = args(json!({"key": {"dataAreaId": "usmf"}}));
+        assert!(validate_arguments(&schema, &given).is_ok());
+
+        let given = args(json!({"key": "x"}));
+        assert_eq!(
+            validate_arguments(&schema, &given).unwrap_err(),
+            vec!["argument 'key' must be an object, got \"x\""]
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! Outbound HTTP settings
+//!
+//! The token, Key Vault, managed identity and OData clients are all built
+//! from `ProxySettings::apply`, so a corporate proxy covers every request the
+//! server makes. An explicit proxy replaces reqwest's own `HTTP_PROXY`
+//! handling, and requests that fail to connect name the proxy they went
+//! through.
+//!
+//! On-premise environments signed by an internal CA are trusted through
+//! `load_root_certificates` and `apply_tls` rather than by switching
+//! certificate verification off.
+//!
+//! Every client identifies itself with [`USER_AGENT`] rather than reqwest's
+//! default, so Microsoft's telemetry can tell this server's traffic apart.
+
+use reqwest::{Certificate, Client, ClientBuilder, NoProxy, Proxy, Url};
+use std::fs;
+
+/// `User-Agent` of every request the server makes; OData requests add the
+/// MCP client and the configured suffix
+pub const USER_AGENT: &str = concat!("d365-odata-mcp/", env!("CARGO_PKG_VERSION"));
+
+/// Root certificates from a PEM file holding one or more certificates.
+/// Each is checked here, so a bad bundle fails at startup with its path.
+pub fn load_root_certificates(path: &str) -> Result<Vec<Certificate>, String> {
+    let pem = fs::read(path).map_err(|e| format!("Cannot read CA bundle {}: {}", path, e))?;
+    let certificates = Certificate::from_pem_bundle(&pem)
+        .map_err(|e| format!("Invalid PEM in CA bundle {}: {}", path, e))?;
+    if certificates.is_empty() {
+        return Err(format!("No certificates found in CA bundle {}", path));
+    }
+    for certificate in &certificates {
+        Client::builder()
+            .add_root_certificate(certificate.clone())
+            .build()
+            .map_err(|e| format!("Invalid certificate in CA bundle {}: {}", path, e))?;
+    }
+    Ok(certificates)
+}
+
+/// Trust `certificates` on top of the built-in roots. `insecure_ssl` only
+/// applies without them: a CA bundle always keeps verification on.
+pub fn apply_tls(
+    mut builder: ClientBuilder,
+    certificates: &[Certificate],
+    insecure_ssl: bool,
+) -> ClientBuilder {
+    for certificate in certificates {
+        builder = builder.add_root_certificate(certificate.clone());
+    }
+    builder.danger_accept_invalid_certs(insecure_ssl && certificates.is_empty())
+}
+
+/// Explicit proxy configuration (`http_proxy`, `https_proxy`, `no_proxy`)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxySettings {
+    /// Proxy for `http://` URLs
+    pub http_proxy: Option<String>,
+    /// Proxy for `https://` URLs (the D365 and Entra ID endpoints)
+    pub https_proxy: Option<String>,
+    /// Sent as `Proxy-Authorization: Basic`
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Comma-separated hosts, domains (`.corp.local`) or IPs that bypass the proxy
+    pub no_proxy: Option<String>,
+}
+
+impl ProxySettings {
+    /// Whether any proxy is configured
+    pub fn is_configured(&self) -> bool {
+        self.http_proxy.is_some() || self.https_proxy.is_some()
+    }
+
+    /// Check the proxy URLs, so a typo fails at startup
+    pub fn validate(&self) -> Result<(), String> {
+        for url in [&self.http_proxy, &self.https_proxy].into_iter().flatten() {
+            Proxy::all(url.as_str()).map_err(|e| format!("Invalid proxy URL '{}': {}", url, e))?;
+        }
+        Ok(())
+    }
+
+    /// Copy that also sends `host` directly, e.g. the IMDS address
+    pub fn bypassing(&self, host: &str) -> Self {
+        let no_proxy = match self.no_proxy.as_deref().map(str::trim) {
+            Some(list) if !list.is_empty() => format!("{},{}", list, host),
+            _ => host.to_string(),
+        };
+        Self {
+            no_proxy: Some(no_proxy),
+            ..self.clone()
+        }
+    }
+
+    /// Route `builder`'s requests through the configured proxies
+    pub fn apply(&self, mut builder: ClientBuilder) -> Result<ClientBuilder, reqwest::Error> {
+        let no_proxy = NoProxy::from_string(self.no_proxy.as_deref().unwrap_or_default());
+        let proxies = [
+            self.http_proxy.as_deref().map(Proxy::http),
+            self.https_proxy.as_deref().map(Proxy::https),
+        ];
+        for proxy in proxies.into_iter().flatten() {
+            let mut proxy = proxy?.no_proxy(no_proxy.clone());
+            if let Some(username) = &self.username {
+                proxy = proxy.basic_auth(username, self.password.as_deref().unwrap_or_default());
+            }
+            builder = builder.proxy(proxy);
+        }
+        Ok(builder)
+    }
+
+    /// `host:port` of the proxy a request to `url` goes through, if any
+    pub fn proxy_for(&self, url: &str) -> Option<String> {
+        let url = Url::parse(url).ok()?;
+        let proxy = match url.scheme() {
+            "https" => self.https_proxy.as_deref(),
+            _ => self.http_proxy.as_deref(),
+        }?;
+        let host = url.host_str()?;
+        if self.bypasses(host) {
+            return None;
+        }
+        let proxy = Url::parse(proxy).ok()?;
+        Some(match proxy.port_or_known_default() {
+            Some(port) => format!("{}:{}", proxy.host_str()?, port),
+            None => proxy.host_str()?.to_string(),
+        })
+    }
+
+    /// `no_proxy` matching: `*`, exact hosts and domain suffixes
+    fn bypasses(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        self.no_proxy
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(|entry| entry.trim().trim_start_matches('.').to_ascii_lowercase())
+            .filter(|entry| !entry.is_empty())
+            .any(|entry| entry == "*" || host == entry || host.ends_with(&format!(".{}", entry)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn corporate() -> ProxySettings {
+        ProxySettings {
+            http_proxy: Some("http://proxy.corp.local:3128".to_string()),
+            https_proxy: Some("http://secure-proxy.corp.local:8080".to_string()),
+            username: Some("svc-d365".to_string()),
+            password: Some("secret".to_string()),
+            no_proxy: Some("localhost, .corp.local".to_string()),
+        }
+    }
+
+    #[test]
+    fn proxy_host_follows_scheme_and_no_proxy() {
+        let proxy = corporate();
+        assert_eq!(
+            proxy.proxy_for("https://org.crm.dynamics.com/api/data/v9.2/"),
+            Some("secure-proxy.corp.local:8080".to_string())
+        );
+        assert_eq!(
+            proxy.proxy_for("http://example.com/"),
+            Some("proxy.corp.local:3128".to_string())
+        );
+        assert_eq!(proxy.proxy_for("https://adfs.corp.local/adfs"), None);
+        assert_eq!(proxy.proxy_for("http://localhost:8080/"), None);
+
+        let imds = proxy.bypassing("169.254.169.254");
+        assert_eq!(imds.proxy_for("http://169.254.169.254/metadata"), None);
+        assert!(ProxySettings::default()
+            .proxy_for("https://org.crm.dynamics.com/")
+            .is_none());
+    }
+
+    #[test]
+    fn ca_bundle_errors_name_the_file() {
+        let dir = std::env::temp_dir().join(format!("d365-ca-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str, content: &str| {
+            let path = dir.join(name);
+            fs::write(&path, content).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+
+        let bundle = file("bundle.pem", include_str!("auth/testdata/client-cert.pem"));
+        assert_eq!(load_root_certificates(&bundle).unwrap().len(), 1);
+
+        let missing = dir.join("missing.pem").to_string_lossy().into_owned();
+        assert!(load_root_certificates(&missing)
+            .unwrap_err()
+            .contains(&missing));
+
+        let empty = file("empty.pem", "not a certificate");
+        let error = load_root_certificates(&empty).unwrap_err();
+        assert!(
+            error.contains("No certificates") && error.contains(&empty),
+            "{error}"
+        );
+
+        let garbage = file(
+            "garbage.pem",
+            "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n",
+        );
+        assert!(load_root_certificates(&garbage)
+            .unwrap_err()
+            .contains(&garbage));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_proxy_urls_are_rejected() {
+        assert!(corporate().validate().is_ok());
+        let proxy = ProxySettings {
+            https_proxy: Some("http://[not a host".to_string()),
+            ..Default::default()
+        };
+        assert!(proxy.validate().unwrap_err().contains("not a host"));
+        assert!(proxy.apply(reqwest::Client::builder()).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+//! Instance annotations requested from the service
+//!
+//! `Prefer: odata.include-annotations=*` makes Dataverse send a formatted
+//! value, a lookup logical name and a navigation property name next to every
+//! lookup and choice column, which can triple the size of a record. The
+//! preference can be narrowed to formatted values only, or left out.
+
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+/// Which instance annotations requests ask for
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum AnnotationMode {
+    /// No preference: values only
+    None,
+    /// Formatted values only, for `field_label` keys
+    #[serde(alias = "formatted_only")]
+    FormattedOnly,
+    /// Every annotation the service has
+    #[default]
+    All,
+}
+
+impl AnnotationMode {
+    /// The `Prefer` preference asking for these annotations, if any
+    pub fn preference(self) -> Option<&'static str> {
+        match self {
+            AnnotationMode::None => None,
+            AnnotationMode::FormattedOnly => {
+                Some("odata.include-annotations=\"OData.Community.Display.V1.FormattedValue\"")
+            }
+            AnnotationMode::All => Some("odata.include-annotations=*"),
+        }
+    }
+}
+
+impl fmt::Display for AnnotationMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AnnotationMode::None => "none",
+            AnnotationMode::FormattedOnly => "formatted-only",
+            AnnotationMode::All => "all",
+        })
+    }
+}
+
+impl FromStr for AnnotationMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "none" => Ok(AnnotationMode::None),
+            "formatted-only" | "formatted_only" | "formatted" => Ok(AnnotationMode::FormattedOnly),
+            "all" | "*" => Ok(AnnotationMode::All),
+            other => Err(format!(
+                "Unknown annotations: '{}'. Use 'none', 'formatted-only' or 'all'",
+                other
+            )),
+        }
+    }
+}
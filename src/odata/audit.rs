@@ -0,0 +1,255 @@
+//! Dataverse audit history
+//!
+//! Reads a record's changes with the `RetrieveRecordChangeHistory` function
+//! and tells "nothing was changed" apart from "nothing is being audited",
+//! which Dataverse reports the same way (an empty list).
+
+use super::client::{ODataClient, ODataError, QueryOptions};
+use super::language::localized_label;
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+
+const FORMATTED_VALUE: &str = "@OData.Community.Display.V1.FormattedValue";
+
+/// One changed attribute, with display text for both values
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldChange {
+    /// Attribute logical name, e.g. `creditlimit`
+    pub field: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// One audited operation on the record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// `createdon` of the audit record (ISO 8601)
+    pub changed_on: String,
+    /// Who made the change (display name when available)
+    pub changed_by: String,
+    /// Action label, e.g. `Update`
+    pub action: String,
+    pub changes: Vec<FieldChange>,
+}
+
+/// Whether auditing is switched on, for the environment and for the table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditStatus {
+    pub organization: Option<bool>,
+    pub entity: Option<bool>,
+}
+
+/// Formatted value of `field` if the service sent one, else the raw value
+fn display_value(record: &Value, field: &str) -> Option<String> {
+    if let Some(formatted) = record
+        .get(format!("{}{}", field, FORMATTED_VALUE))
+        .and_then(Value::as_str)
+    {
+        return Some(formatted.to_string());
+    }
+    match record.get(field)? {
+        Value::Null => None,
+        Value::String(text) => Some(text.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+/// `_parentaccountid_value` is the `parentaccountid` attribute
+fn attribute_name(field: &str) -> &str {
+    field
+        .strip_prefix('_')
+        .and_then(|field| field.strip_suffix("_value"))
+        .unwrap_or(field)
+}
+
+/// Attributes changed between `old` and `new` (annotations skipped)
+fn field_changes(old: &Value, new: &Value) -> Vec<FieldChange> {
+    let fields: BTreeSet<&str> = [old, new]
+        .into_iter()
+        .filter_map(Value::as_object)
+        .flat_map(|values| values.keys())
+        .map(String::as_str)
+        .filter(|field| !field.contains('@'))
+        .collect();
+
+    fields
+        .into_iter()
+        .map(|field| FieldChange {
+            field: attribute_name(field).to_string(),
+            old: display_value(old, field),
+            new: display_value(new, field),
+        })
+        .filter(|change| change.old != change.new)
+        .collect()
+}
+
+/// Entries of a `RetrieveRecordChangeHistory` response, oldest first
+pub fn parse_change_history(response: &Value) -> Vec<AuditEntry> {
+    let details = response
+        .pointer("/AuditDetailCollection/AuditDetails")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+
+    let mut entries: Vec<AuditEntry> = details
+        .iter()
+        .map(|detail| {
+            let record = detail.get("AuditRecord").unwrap_or(&Value::Null);
+            let missing = Value::Null;
+            AuditEntry {
+                changed_on: display_value(record, "createdon").unwrap_or_default(),
+                changed_by: display_value(record, "_userid_value")
+                    .unwrap_or_else(|| "unknown user".to_string()),
+                action: display_value(record, "action").unwrap_or_else(|| "Change".to_string()),
+                changes: field_changes(
+                    detail.get("OldValue").unwrap_or(&missing),
+                    detail.get("NewValue").unwrap_or(&missing),
+                ),
+            }
+        })
+        .collect();
+    // `createdon` is raw ISO 8601, so text order is time order
+    entries.sort_by(|a, b| a.changed_on.cmp(&b.changed_on));
+    entries
+}
+
+impl ODataClient {
+    /// Audited changes to the record at `record_path` (e.g. `accounts(<id>)`),
+    /// oldest first
+    pub async fn record_change_history(
+        &self,
+        record_path: &str,
+    ) -> Result<Vec<AuditEntry>, ODataError> {
+        let target = json!({ "@odata.id": record_path }).to_string();
+        let response = self
+            .execute_function("RetrieveRecordChangeHistory", &[("Target", &target)])
+            .await?;
+        Ok(parse_change_history(&response))
+    }
+
+    /// Display names of a table's attributes, keyed by logical name; empty
+    /// when the attribute metadata cannot be read
+    pub async fn attribute_labels(&self, logical_name: &str) -> HashMap<String, String> {
+        let options = QueryOptions {
+            select: Some(vec!["LogicalName".to_string(), "DisplayName".to_string()]),
+            ..Default::default()
+        };
+        let path = format!(
+            "EntityDefinitions(LogicalName='{}')/Attributes",
+            logical_name.replace('\'', "''")
+        );
+        let Ok(attributes) = self.fetch_all_pages(&path, &options).await else {
+            return HashMap::new();
+        };
+        let language_code = self.language_code();
+        attributes
+            .iter()
+            .filter_map(|attribute| {
+                let name = attribute.get("LogicalName")?.as_str()?;
+                let label = localized_label(attribute.get("DisplayName")?, language_code)?;
+                Some((name.to_string(), label.to_string()))
+            })
+            .collect()
+    }
+
+    /// Whether the environment and the table `logical_name` have auditing on;
+    /// `None` where the setting could not be read
+    pub async fn audit_status(&self, logical_name: &str) -> AuditStatus {
+        let organization = QueryOptions {
+            select: Some(vec!["isauditenabled".to_string()]),
+            top: Some(1),
+            ..Default::default()
+        };
+        let organization = self
+            .fetch_entity_page("organizations", None, &organization)
+            .await
+            .ok()
+            .and_then(|page| page.value.first()?.get("isauditenabled")?.as_bool());
+
+        let entity = QueryOptions {
+            select: Some(vec!["IsAuditEnabled".to_string()]),
+            filter: Some(format!(
+                "LogicalName eq '{}'",
+                logical_name.replace('\'', "''")
+            )),
+            ..Default::default()
+        };
+        let entity = self
+            .fetch_entity_page("EntityDefinitions", None, &entity)
+            .await
+            .ok()
+            .and_then(|page| {
+                page.value
+                    .first()?
+                    .pointer("/IsAuditEnabled/Value")?
+                    .as_bool()
+            });
+
+        AuditStatus {
+            organization,
+            entity,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn change_history_lists_changed_fields_oldest_first() {
+        let response = json!({"AuditDetailCollection": {"AuditDetails": [
+            {
+                "@odata.type": "#Microsoft.Dynamics.CRM.AttributeAuditDetail",
+                "AuditRecord": {
+                    "createdon": "2024-05-02T09:00:00Z",
+                    "action": 2,
+                    "action@OData.Community.Display.V1.FormattedValue": "Update",
+                    "_userid_value": "u1",
+                    "_userid_value@OData.Community.Display.V1.FormattedValue": "Jane Doe"
+                },
+                "OldValue": {
+                    "@odata.type": "#Microsoft.Dynamics.CRM.account",
+                    "creditlimit": 1000,
+                    "creditlimit@OData.Community.Display.V1.FormattedValue": "$1,000.00",
+                    "name": "Contoso"
+                },
+                "NewValue": {
+                    "creditlimit": 5000,
+                    "creditlimit@OData.Community.Display.V1.FormattedValue": "$5,000.00",
+                    "name": "Contoso",
+                    "_primarycontactid_value": "c1"
+                }
+            },
+            {
+                "AuditRecord": {"createdon": "2024-05-01T08:00:00Z", "action": 1}
+            }
+        ]}});
+
+        let entries = parse_change_history(&response);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].changed_on, "2024-05-01T08:00:00Z");
+        assert_eq!(entries[0].action, "1");
+        assert!(entries[0].changes.is_empty());
+
+        let update = &entries[1];
+        assert_eq!(update.changed_by, "Jane Doe");
+        assert_eq!(update.action, "Update");
+        // Unchanged `name` is left out; lookups use the attribute name
+        assert_eq!(
+            update.changes,
+            vec![
+                FieldChange {
+                    field: "primarycontactid".to_string(),
+                    old: None,
+                    new: Some("c1".to_string()),
+                },
+                FieldChange {
+                    field: "creditlimit".to_string(),
+                    old: Some("$1,000.00".to_string()),
+                    new: Some("$5,000.00".to_string()),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! Shared `Retry-After` backpressure
+//!
+//! A 429 tells the client to hold off, not just the request that got it.
+//! Each `ODataClient`, and so each environment's endpoint, has one
+//! [`Backpressure`] gate: a 429 sets a "do not send before" instant, and
+//! every request, in flight or new, waits for it before sending. The gate
+//! lifts by itself once that instant has passed; later 429s can only push
+//! it further out.
+
+use super::client::ODataError;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Snapshot of the gate for diagnostics
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackpressureStats {
+    /// Time left before requests are sent again; `None` when not cooling down
+    pub cooling_down: Option<Duration>,
+    /// Times a 429 closed the gate since startup
+    pub engaged: u64,
+    /// Requests held back by the gate since startup
+    pub delayed: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    not_before: Option<Instant>,
+    engaged: u64,
+    delayed: u64,
+}
+
+/// "Do not send before" gate shared by every request of a client
+#[derive(Debug, Default)]
+pub struct Backpressure {
+    inner: Mutex<Inner>,
+}
+
+impl Backpressure {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The service answered 429 asking to wait `retry_after`
+    pub fn throttled(&self, retry_after: Duration) {
+        if retry_after.is_zero() {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let until = now + retry_after;
+        match inner.not_before {
+            Some(not_before) if not_before > now => {
+                inner.not_before = Some(not_before.max(until));
+            }
+            _ => {
+                // The 429 itself is already logged as a warning
+                tracing::debug!(
+                    retry_after_secs = retry_after.as_secs_f64(),
+                    "Throttled: holding every request back until Retry-After has passed"
+                );
+                inner.engaged += 1;
+                inner.not_before = Some(until);
+            }
+        }
+    }
+
+    /// How long a request must wait before sending; `None` when it may go now
+    pub fn wait(&self) -> Option<Duration> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        match inner.not_before {
+            Some(not_before) if not_before > now => {
+                inner.delayed += 1;
+                Some(not_before - now)
+            }
+            Some(_) => {
+                inner.not_before = None;
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Wait for the gate, or fail at once when it stays closed for longer
+    /// than the `remaining` time of the call
+    pub async fn ready(&self, remaining: Duration) -> Result<(), ODataError> {
+        while let Some(wait) = self.wait() {
+            if wait > remaining {
+                return Err(ODataError::Throttled {
+                    retry_after: wait,
+                    remaining,
+                });
+            }
+            tracing::debug!(wait_ms = wait.as_millis() as u64, "Waiting out throttling");
+            tokio::time::sleep(wait).await;
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> BackpressureStats {
+        let inner = self.inner.lock().unwrap();
+        BackpressureStats {
+            cooling_down: inner
+                .not_before
+                .map(|not_before| not_before.saturating_duration_since(Instant::now()))
+                .filter(|left| !left.is_zero()),
+            engaged: inner.engaged,
+            delayed: inner.delayed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::advance;
+
+    #[tokio::test(start_paused = true)]
+    async fn the_gate_holds_requests_until_retry_after_and_then_lifts() {
+        let gate = Backpressure::new();
+        gate.throttled(Duration::ZERO);
+        assert_eq!(gate.wait(), None);
+
+        gate.throttled(Duration::from_secs(30));
+        // A shorter Retry-After does not shorten the cool-down; a longer one extends it
+        gate.throttled(Duration::from_secs(10));
+        assert_eq!(gate.wait(), Some(Duration::from_secs(30)));
+        advance(Duration::from_secs(20)).await;
+        gate.throttled(Duration::from_secs(15));
+        assert_eq!(gate.wait(), Some(Duration::from_secs(15)));
+
+        let error = gate.ready(Duration::from_secs(5)).await.unwrap_err();
+        assert!(matches!(
+            error,
+            ODataError::Throttled { retry_after, .. } if retry_after == Duration::from_secs(15)
+        ));
+        let started = Instant::now();
+        gate.ready(Duration::from_secs(60)).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::from_secs(15));
+
+        let stats = gate.stats();
+        assert_eq!(
+            (stats.cooling_down, stats.engaged, stats.delayed),
+            (None, 1, 4)
+        );
+        gate.throttled(Duration::from_secs(1));
+        assert_eq!(gate.stats().engaged, 2);
+    }
+}
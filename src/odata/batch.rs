@@ -0,0 +1,179 @@
+//! OData `$batch` changesets
+//!
+//! A changeset is a `multipart/mixed` part of a `$batch` request holding
+//! several write requests that the service applies atomically: they all
+//! succeed, or the first failure rolls the others back. Each request carries
+//! a `Content-ID` so the failing one can be told apart in the response.
+
+use super::client::ODataError;
+use serde_json::Value;
+
+/// One response inside a `$batch` response body
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchPartResponse {
+    /// The `Content-ID` of the request it answers, when the service echoes it
+    pub content_id: Option<usize>,
+    pub status: u16,
+    /// The response body, usually empty or an OData error
+    pub body: String,
+}
+
+/// A changeset the service rolled back
+#[derive(Debug)]
+pub struct ChangesetError {
+    /// Position of the record the service rejected, when the response says
+    pub failed: Option<usize>,
+    pub error: ODataError,
+}
+
+impl From<ODataError> for ChangesetError {
+    fn from(error: ODataError) -> Self {
+        Self {
+            failed: None,
+            error,
+        }
+    }
+}
+
+/// `$batch` request body with one changeset of PATCH requests, one per URL,
+/// each guarded by `If-Match: *` so a record that vanished is not recreated.
+/// `Content-ID`s count from 1 in the order of `urls`. Returns the request's
+/// `Content-Type` header and body.
+pub(crate) fn patch_changeset(
+    batch_id: &str,
+    urls: &[String],
+    payload: &Value,
+) -> (String, String) {
+    let batch = format!("batch_{}", batch_id);
+    let changeset = format!("changeset_{}", batch_id);
+    let json = serde_json::to_string(payload).unwrap_or_default();
+
+    let mut body = format!(
+        "--{}\r\nContent-Type: multipart/mixed; boundary={}\r\n\r\n",
+        batch, changeset
+    );
+    for (index, url) in urls.iter().enumerate() {
+        body.push_str(&format!(
+            "--{}\r\nContent-Type: application/http\r\nContent-Transfer-Encoding: binary\r\nContent-ID: {}\r\n\r\n",
+            changeset,
+            index + 1
+        ));
+        body.push_str(&format!(
+            "PATCH {} HTTP/1.1\r\nContent-Type: application/json\r\nIf-Match: *\r\n\r\n{}\r\n",
+            url, json
+        ));
+    }
+    body.push_str(&format!("--{}--\r\n--{}--\r\n", changeset, batch));
+    (format!("multipart/mixed; boundary={}", batch), body)
+}
+
+/// The HTTP responses in a `$batch` response body, in order
+///
+/// Parts are found by their `HTTP/1.1 <status>` lines rather than by
+/// boundary, so nested changesets and either line ending are read alike.
+pub(crate) fn parse_batch_response(body: &str) -> Vec<BatchPartResponse> {
+    let lines: Vec<&str> = body
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .collect();
+    let mut parts = Vec::new();
+    let mut content_id = None;
+    let mut index = 0;
+    while index < lines.len() {
+        let line = lines[index];
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-ID") {
+                content_id = value.trim().parse().ok();
+            }
+        }
+        let status = line
+            .strip_prefix("HTTP/1.1 ")
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|code| code.parse::<u16>().ok());
+        let Some(status) = status else {
+            index += 1;
+            continue;
+        };
+
+        // Skip the response headers, then read the body up to the next boundary
+        index += 1;
+        while index < lines.len() && !lines[index].is_empty() {
+            index += 1;
+        }
+        let mut part_body = Vec::new();
+        while index < lines.len() && !lines[index].starts_with("--") {
+            part_body.push(lines[index]);
+            index += 1;
+        }
+        parts.push(BatchPartResponse {
+            content_id: content_id.take(),
+            status,
+            body: part_body.join("\n").trim().to_string(),
+        });
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn changesets_patch_each_record_with_a_content_id() {
+        let urls = vec![
+            "https://org/api/data/v9.2/accounts(1)".to_string(),
+            "https://org/api/data/v9.2/accounts(2)".to_string(),
+        ];
+        let (content_type, body) = patch_changeset("b1", &urls, &json!({"creditonhold": true}));
+
+        assert_eq!(content_type, "multipart/mixed; boundary=batch_b1");
+        assert!(body.starts_with(
+            "--batch_b1\r\nContent-Type: multipart/mixed; boundary=changeset_b1\r\n\r\n"
+        ));
+        assert!(body.contains(
+            "Content-ID: 2\r\n\r\nPATCH https://org/api/data/v9.2/accounts(2) HTTP/1.1\r\nContent-Type: application/json\r\nIf-Match: *\r\n\r\n{\"creditonhold\":true}\r\n"
+        ));
+        assert_eq!(body.matches("--changeset_b1\r\n").count(), 2);
+        assert!(body.ends_with("--changeset_b1--\r\n--batch_b1--\r\n"));
+    }
+
+    #[test]
+    fn batch_responses_are_split_into_parts() {
+        let body = "--batchresponse_1\r\n\
+            Content-Type: multipart/mixed; boundary=changesetresponse_1\r\n\r\n\
+            --changesetresponse_1\r\n\
+            Content-Type: application/http\r\n\
+            Content-Transfer-Encoding: binary\r\n\
+            Content-ID: 1\r\n\r\n\
+            HTTP/1.1 204 No Content\r\n\
+            OData-Version: 4.0\r\n\r\n\r\n\
+            --changesetresponse_1\r\n\
+            Content-Type: application/http\r\n\
+            Content-Transfer-Encoding: binary\r\n\
+            Content-ID: 2\r\n\r\n\
+            HTTP/1.1 400 Bad Request\r\n\
+            Content-Type: application/json; odata.metadata=minimal\r\n\r\n\
+            {\"error\":{\"code\":\"0x80040203\",\"message\":\"Invalid value\"}}\r\n\
+            --changesetresponse_1--\r\n\
+            --batchresponse_1--\r\n";
+
+        assert_eq!(
+            parse_batch_response(body),
+            vec![
+                BatchPartResponse {
+                    content_id: Some(1),
+                    status: 204,
+                    body: String::new(),
+                },
+                BatchPartResponse {
+                    content_id: Some(2),
+                    status: 400,
+                    body: "{\"error\":{\"code\":\"0x80040203\",\"message\":\"Invalid value\"}}"
+                        .to_string(),
+                },
+            ]
+        );
+        assert!(parse_batch_response("not a batch").is_empty());
+    }
+}
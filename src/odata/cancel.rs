@@ -0,0 +1,74 @@
+//! Per-call cancellation
+//!
+//! A tool call runs inside [`with_cancellation`]; every `ODataClient` request
+//! made from that call checks the token between pages and retries and stops
+//! waiting on the network once it is cancelled, so no signatures need to
+//! carry the token.
+
+use super::client::ODataError;
+use std::future::Future;
+use tokio_util::sync::CancellationToken;
+
+tokio::task_local! {
+    static CANCELLATION: CancellationToken;
+}
+
+/// Run `future` with `token` as the cancellation token for the OData calls it makes
+pub async fn with_cancellation<F: Future>(token: CancellationToken, future: F) -> F::Output {
+    CANCELLATION.scope(token, future).await
+}
+
+/// Fail with [`ODataError::Cancelled`] if the current call was cancelled
+pub(crate) fn check_cancelled() -> Result<(), ODataError> {
+    let cancelled = CANCELLATION
+        .try_with(CancellationToken::is_cancelled)
+        .unwrap_or(false);
+    if cancelled {
+        Err(ODataError::Cancelled)
+    } else {
+        Ok(())
+    }
+}
+
+/// Await `future` unless the current call is cancelled first
+pub(crate) async fn cancellable<F: Future>(future: F) -> Result<F::Output, ODataError> {
+    let Ok(token) = CANCELLATION.try_with(CancellationToken::clone) else {
+        return Ok(future.await);
+    };
+
+    tokio::select! {
+        biased;
+        _ = token.cancelled() => Err(ODataError::Cancelled),
+        output = future => Ok(output),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn calls_outside_a_scope_are_never_cancelled() {
+        assert!(check_cancelled().is_ok());
+        assert_eq!(cancellable(async { 42 }).await.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn cancelling_interrupts_pending_work() {
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            canceller.cancel();
+        });
+
+        let result = with_cancellation(token, async {
+            cancellable(tokio::time::sleep(Duration::from_secs(30))).await?;
+            check_cancelled()
+        })
+        .await;
+
+        assert!(matches!(result, Err(ODataError::Cancelled)));
+    }
+}
@@ -0,0 +1,256 @@
+//! Circuit breaker around the service
+//!
+//! While an environment is down, e.g. for F&O servicing, every request would
+//! otherwise spend its retries and backoff before failing. After
+//! `threshold` consecutive connection failures or 502/503/504 responses,
+//! across all tool calls, the circuit opens: requests fail at once with
+//! [`ODataError::CircuitOpen`] for the cool-down period. The first request
+//! after it is sent as a probe (half-open); its success closes the circuit
+//! and its failure opens it for another cool-down.
+
+use super::client::ODataError;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Consecutive failures that open the circuit unless configured otherwise
+pub const DEFAULT_CIRCUIT_THRESHOLD: u32 = 5;
+
+/// How long an open circuit rejects requests unless configured otherwise
+pub const DEFAULT_CIRCUIT_COOLDOWN: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A probe was let through at `since`; others wait for its outcome
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+/// Whether requests go through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Snapshot of the breaker for diagnostics
+#[derive(Debug, Clone, PartialEq)]
+pub struct CircuitStats {
+    pub state: CircuitState,
+    /// Failures in a row so far while closed
+    pub consecutive_failures: u32,
+    /// Failures in a row that open the circuit; 0 when disabled
+    pub threshold: u32,
+    pub cooldown: Duration,
+    /// When an open circuit lets the next probe through
+    pub retry_after: Option<Duration>,
+    /// Times the circuit opened since startup
+    pub opened: u64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: State,
+    opened: u64,
+}
+
+/// Consecutive-failure circuit breaker shared by every request of a client
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl CircuitBreaker {
+    /// Open after `threshold` failures in a row (0 never opens) for `cooldown`
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            inner: Mutex::new(Inner {
+                state: State::Closed { failures: 0 },
+                opened: 0,
+            }),
+        }
+    }
+
+    /// A breaker that never opens
+    pub fn disabled() -> Self {
+        Self::new(0, DEFAULT_CIRCUIT_COOLDOWN)
+    }
+
+    /// Whether a request may be sent now; after the cool-down the first
+    /// caller becomes the probe
+    pub fn check(&self) -> Result<(), ODataError> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        match inner.state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } if now < until => Err(ODataError::CircuitOpen {
+                retry_after: until - now,
+            }),
+            // A probe that never reported back (cancelled, timed out) is
+            // replaced after another cool-down
+            State::HalfOpen { since } if now < since + self.cooldown => {
+                Err(ODataError::CircuitOpen {
+                    retry_after: since + self.cooldown - now,
+                })
+            }
+            State::Open { .. } | State::HalfOpen { .. } => {
+                tracing::info!("Circuit half-open: sending a probe request");
+                inner.state = State::HalfOpen { since: now };
+                Ok(())
+            }
+        }
+    }
+
+    /// The service answered; closes the circuit
+    pub fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if !matches!(inner.state, State::Closed { .. }) {
+            tracing::info!("Circuit closed: the service is answering again");
+        }
+        inner.state = State::Closed { failures: 0 };
+    }
+
+    /// A connection failure or gateway/unavailable response; may open the circuit
+    pub fn record_failure(&self) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let failures = match inner.state {
+            State::Closed { failures } => failures + 1,
+            // A failed probe opens the circuit again
+            State::HalfOpen { .. } => self.threshold,
+            State::Open { .. } => return,
+        };
+        inner.state = if failures >= self.threshold {
+            tracing::warn!(
+                failures,
+                cooldown_secs = self.cooldown.as_secs(),
+                "Circuit open: failing requests immediately until the cool-down ends"
+            );
+            inner.opened += 1;
+            State::Open {
+                until: Instant::now() + self.cooldown,
+            }
+        } else {
+            State::Closed { failures }
+        };
+    }
+
+    /// Close the circuit and forget the failures, e.g. once the service is
+    /// known to be back
+    pub fn reset(&self) {
+        self.inner.lock().unwrap().state = State::Closed { failures: 0 };
+    }
+
+    pub fn stats(&self) -> CircuitStats {
+        let inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let (state, consecutive_failures, retry_after) = match inner.state {
+            State::Closed { failures } => (CircuitState::Closed, failures, None),
+            State::Open { until } => (
+                CircuitState::Open,
+                self.threshold,
+                Some(until.saturating_duration_since(now)),
+            ),
+            State::HalfOpen { .. } => (CircuitState::HalfOpen, self.threshold, None),
+        };
+        CircuitStats {
+            state,
+            consecutive_failures,
+            threshold: self.threshold,
+            cooldown: self.cooldown,
+            retry_after,
+            opened: inner.opened,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::advance;
+
+    fn retry_after(result: Result<(), ODataError>) -> Duration {
+        match result {
+            Err(ODataError::CircuitOpen { retry_after }) => retry_after,
+            other => panic!("expected an open circuit, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_circuit_opens_probes_and_closes() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+
+        // A success in between starts the count again
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.check().is_ok());
+        assert_eq!(breaker.stats().consecutive_failures, 2);
+
+        breaker.record_failure();
+        assert_eq!(breaker.stats().state, CircuitState::Open);
+        assert_eq!(retry_after(breaker.check()), Duration::from_secs(30));
+        advance(Duration::from_secs(20)).await;
+        assert_eq!(retry_after(breaker.check()), Duration::from_secs(10));
+
+        // After the cool-down one probe goes through; the rest wait for it
+        advance(Duration::from_secs(10)).await;
+        assert!(breaker.check().is_ok());
+        assert_eq!(breaker.stats().state, CircuitState::HalfOpen);
+        assert!(breaker.check().is_err());
+
+        // A failed probe opens the circuit for another cool-down
+        breaker.record_failure();
+        assert_eq!(retry_after(breaker.check()), Duration::from_secs(30));
+        assert_eq!(breaker.stats().opened, 2);
+
+        advance(Duration::from_secs(30)).await;
+        assert!(breaker.check().is_ok());
+        breaker.record_success();
+        assert_eq!(breaker.stats().state, CircuitState::Closed);
+        assert!(breaker.check().is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_lost_probe_is_replaced_and_reset_closes_the_circuit() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(10));
+        breaker.record_failure();
+        advance(Duration::from_secs(10)).await;
+        assert!(breaker.check().is_ok());
+
+        // The probe never reports back
+        advance(Duration::from_secs(9)).await;
+        assert_eq!(retry_after(breaker.check()), Duration::from_secs(1));
+        advance(Duration::from_secs(1)).await;
+        assert!(breaker.check().is_ok());
+
+        breaker.reset();
+        let stats = breaker.stats();
+        assert_eq!(
+            (stats.state, stats.consecutive_failures, stats.retry_after),
+            (CircuitState::Closed, 0, None)
+        );
+
+        let disabled = CircuitBreaker::disabled();
+        for _ in 0..100 {
+            disabled.record_failure();
+        }
+        assert!(disabled.check().is_ok());
+    }
+}
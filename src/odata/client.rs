@@ -3,16 +3,45 @@
 //! HTTP client for Microsoft Dynamics 365 OData APIs
 //! Supports both Dataverse and Finance & Operations endpoints
 
-use crate::auth::AzureAdAuth;
+use crate::auth::{AzureAdAuth, TokenProvider};
 use crate::config::config::ProductType;
-use reqwest::{Client, Method, Response, StatusCode};
+use crate::network::{apply_tls, ProxySettings, USER_AGENT};
+use crate::odata::annotations::AnnotationMode;
+use crate::odata::backpressure::{Backpressure, BackpressureStats};
+use crate::odata::batch::{parse_batch_response, patch_changeset, ChangesetError};
+use crate::odata::cancel::{cancellable, check_cancelled};
+use crate::odata::circuit::{
+    CircuitBreaker, CircuitStats, DEFAULT_CIRCUIT_COOLDOWN, DEFAULT_CIRCUIT_THRESHOLD,
+};
+use crate::odata::dry_run::{record_dry_run, without_dry_run, PreparedRequest};
+use crate::odata::filter::{FilterExpr, FilterValue};
+use crate::odata::impersonation::{caller_override, CallerIdHeader};
+use crate::odata::language::{language_code, language_override};
+use crate::odata::metadata::{EntityTypeInfo, MetadataModel, NavigationInfo};
+use crate::odata::metadata_cache::{MetadataCache, MetadataDocument};
+use crate::odata::progress::report_progress;
+use crate::odata::rate_limit::{RateLimiter, RateLimiterStats};
+use crate::odata::recording::{Recording, RecordingMode};
+use crate::odata::request_log::{
+    mcp_client, new_client_request_id, record_failed_request, redact_url, user_agent,
+    RequestCounters, RequestStats,
+};
+use crate::odata::snapshot::MetadataSnapshot;
+use crate::odata::timeout::Deadline;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use reqwest::{Certificate, Client, Method, Response, StatusCode, Url};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
-use tokio::sync::RwLock;
+use tokio::sync::Semaphore;
 use tokio::time::sleep;
+use tracing::Instrument;
 
 /// OData client errors
 #[derive(Error, Debug)]
@@ -23,6 +52,18 @@ pub enum ODataError {
     #[error("HTTP error: {0}")]
     HttpError(#[from] reqwest::Error),
 
+    #[error("{0}")]
+    InvalidProxy(String),
+
+    #[error("HTTP error via proxy {proxy}: {source}")]
+    Proxy {
+        proxy: String,
+        source: reqwest::Error,
+    },
+
+    #[error("Unauthorized (401) after token refresh: {0}")]
+    Unauthorized(String),
+
     #[error("Rate limited (429): retry after {0} seconds")]
     RateLimited(u64),
 
@@ -34,19 +75,374 @@ pub enum ODataError {
 
     #[error("Not found: {0}")]
     NotFound(String),
+
+    #[error("Request cancelled by the client")]
+    Cancelled,
+
+    #[error("File is {size} bytes, over the {limit} byte download limit")]
+    TooLarge { size: u64, limit: u64 },
+
+    #[error("Dry run: the request was not sent")]
+    DryRun,
+
+    #[error("No recorded response for {0}")]
+    NotRecorded(String),
+
+    #[error("Offline: only $metadata is available, read from {0}; data requests need a connection to the service")]
+    Offline(String),
+
+    #[error("Timed out after {0} seconds")]
+    Timeout(u64),
+
+    #[error(
+        "The service kept failing, so requests are paused: retry after {} seconds",
+        .retry_after.as_secs_f64().ceil()
+    )]
+    CircuitOpen { retry_after: Duration },
+
+    #[error(
+        "The service is throttling requests for another {} seconds, longer than the {} seconds left for this call",
+        .retry_after.as_secs_f64().ceil(),
+        .remaining.as_secs()
+    )]
+    Throttled {
+        retry_after: Duration,
+        remaining: Duration,
+    },
+}
+
+impl ODataError {
+    /// Likely fix for a failure seen while setting up a connection
+    pub fn remediation_hint(&self) -> Option<&'static str> {
+        match self {
+            ODataError::AuthError(e) => e.remediation_hint(),
+            ODataError::HttpError(e) => crate::auth::network_hint(e),
+            ODataError::Proxy { source, .. } => crate::auth::network_hint(source),
+            ODataError::Unauthorized(_) => Some(
+                "The token was rejected: check that ENDPOINT (and RESOURCE, if set) is the environment the app was granted access to.",
+            ),
+            ODataError::ServerError(403, _) => Some(
+                "The app has no access to the environment: create an application user for CLIENT_ID \
+                 (Dataverse: Power Platform admin center > Application users; F&O: System administration > \
+                 Setup > Microsoft Entra applications) and give it a security role.",
+            ),
+            ODataError::NotFound(_) => Some(
+                "Not found: check that ENDPOINT is the service root (/data/ for F&O, /api/data/v9.2/ for Dataverse) \
+                 and that the entity set name is spelled correctly.",
+            ),
+            ODataError::RateLimited(_) | ODataError::Throttled { .. } => {
+                Some("The environment is throttling requests: retry later or lower MAX_REQUESTS_PER_MINUTE.")
+            }
+            ODataError::CircuitOpen { .. } => Some(
+                "The environment failed repeatedly (connection errors or 5xx), e.g. during servicing: wait for the cool-down, \
+                 or run test_connection with reset_circuit=true once it is back.",
+            ),
+            ODataError::Timeout(_) => Some(
+                "The service did not answer in time: narrow the query, or raise REQUEST_TIMEOUT_SECS (or the tool's timeout argument).",
+            ),
+            ODataError::Offline(_) => Some(
+                "The server was started with --metadata-file: restart it without the option, and with credentials, to reach the service.",
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// Characters percent-encoded in query option values.
+///
+/// Spaces become `%20` (not `+`) and characters that would terminate or
+/// corrupt the query string are escaped, while OData-significant characters
+/// such as single quotes, parentheses and commas are kept literal.
+const QUERY_VALUE_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'+')
+    .add(b'<')
+    .add(b'>')
+    .add(b'=')
+    .add(b'?')
+    .add(b'[')
+    .add(b']')
+    .add(b'\\')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// Percent-encode a single query option value
+fn encode_query_value(value: &str) -> String {
+    utf8_percent_encode(value, QUERY_VALUE_ENCODE_SET).to_string()
+}
+
+/// `link` moved onto the scheme, host and port of `endpoint`; the path and
+/// query (including an encoded `$skiptoken`) are kept byte for byte.
+/// `None` for links without a scheme and host.
+fn rebase_link(link: &str, endpoint: &str) -> Option<String> {
+    // Index just past `scheme://host[:port]`
+    let origin_end = |url: &str| {
+        let start = url.find("://")? + 3;
+        Some(
+            url[start..]
+                .find(['/', '?', '#'])
+                .map_or(url.len(), |i| start + i),
+        )
+    };
+    let link_origin = origin_end(link)?;
+    let endpoint_origin = origin_end(endpoint)?;
+    Some(format!(
+        "{}{}",
+        &endpoint[..endpoint_origin],
+        &link[link_origin..]
+    ))
+}
+
+/// Whether two URLs share scheme, host and port
+pub(crate) fn same_origin(url: &Url, other: &Url) -> bool {
+    url.scheme() == other.scheme()
+        && url.host_str() == other.host_str()
+        && url.port_or_known_default() == other.port_or_known_default()
+}
+
+/// Whether `value` is a GUID, optionally wrapped in braces
+pub(crate) fn is_guid(value: &str) -> bool {
+    let value = value.trim_matches(['{', '}']);
+    value.len() == 36
+        && value.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
+/// Literal for one key value: integers bare, GUIDs bare on Dataverse and
+/// quoted on F&O, everything else as an escaped string
+fn key_literal(product: &ProductType, value: &str) -> String {
+    let value = value.trim();
+    if value.parse::<i64>().is_ok() {
+        value.to_string()
+    } else if is_guid(value) {
+        FilterValue::guid(value).render(product)
+    } else {
+        FilterValue::String(value.to_string()).render(product)
+    }
+}
+
+/// Key segment for `Entity(<key>)` from a user-supplied id
+///
+/// Without `key_fields`, `id` is the primary key value, e.g. a bare GUID
+/// for Dataverse (`contacts(00000000-...)`) or a quoted string for F&O.
+/// With `key_fields`, `id` holds one comma-separated value per field:
+/// `emailaddress1` and `a@b.com` give `emailaddress1='a@b.com'`. An `id`
+/// that is already a key expression (quoted, or containing `=`) is kept as is.
+pub fn format_entity_key(
+    product: &ProductType,
+    id: &str,
+    key_fields: &[&str],
+) -> Result<String, ODataError> {
+    let id = id.trim();
+    // One enclosing pair, as in `('CUS-001')`; a value may end in `)` itself
+    let id = id
+        .strip_prefix('(')
+        .and_then(|id| id.strip_suffix(')'))
+        .unwrap_or(id);
+    if key_fields.is_empty() {
+        if id.starts_with('\'') || id.contains('=') {
+            return Ok(id.to_string());
+        }
+        return Ok(key_literal(product, id));
+    }
+
+    let values: Vec<&str> = if key_fields.len() == 1 {
+        vec![id]
+    } else {
+        id.split(',').collect()
+    };
+    if values.len() != key_fields.len() {
+        return Err(ODataError::ParseError(format!(
+            "Expected {} comma-separated key values for {}, got {}",
+            key_fields.len(),
+            key_fields.join(","),
+            values.len()
+        )));
+    }
+
+    Ok(key_fields
+        .iter()
+        .zip(values)
+        .map(|(field, value)| format!("{}={}", field.trim(), key_literal(product, value)))
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+/// Characters percent-encoded in the key segment of a record URL; quotes,
+/// `=` and `,` stay literal so the key expression keeps its shape
+const KEY_SEGMENT_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'\\')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// Key of a single record
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntityKey {
+    /// A formatted key value or expression, e.g. `'CUS-001'` or a bare GUID
+    /// (see [`format_entity_key`])
+    Single(String),
+    /// Key properties and their values, formatted by type
+    Composite(Vec<(String, Value)>),
+}
+
+impl EntityKey {
+    /// Key expression for `Entity(<key>)`, before URL encoding
+    ///
+    /// Composite values are formatted by the property types of `entity_type`
+    /// when given, otherwise by their JSON type: numbers and booleans bare,
+    /// GUIDs by product, ISO 8601 timestamps bare, other strings quoted.
+    pub fn expression(
+        &self,
+        product: &ProductType,
+        entity_type: Option<&EntityTypeInfo>,
+    ) -> String {
+        match self {
+            EntityKey::Single(key) => key.clone(),
+            EntityKey::Composite(fields) => fields
+                .iter()
+                .map(|(field, value)| {
+                    let type_name = entity_type.and_then(|entity| {
+                        entity
+                            .properties
+                            .iter()
+                            .find(|property| &property.name == field)
+                            .map(|property| property.type_name.as_str())
+                    });
+                    format!("{}={}", field, typed_key_literal(product, value, type_name))
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+        }
+    }
+
+    /// Key properties named by a composite key
+    pub fn fields(&self) -> Vec<&str> {
+        match self {
+            EntityKey::Single(_) => Vec::new(),
+            EntityKey::Composite(fields) => {
+                fields.iter().map(|(field, _)| field.as_str()).collect()
+            }
+        }
+    }
+}
+
+impl From<&str> for EntityKey {
+    fn from(key: &str) -> Self {
+        EntityKey::Single(key.to_string())
+    }
+}
+
+impl From<String> for EntityKey {
+    fn from(key: String) -> Self {
+        EntityKey::Single(key)
+    }
+}
+
+/// Whether `value` looks like an ISO 8601 timestamp with a time zone, e.g. `2024-08-21T07:28:00Z`
+fn is_timestamp(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    bytes.len() >= 20
+        && bytes[4] == b'-'
+        && bytes[7] == b'-'
+        && bytes[10] == b'T'
+        && bytes[..4].iter().all(u8::is_ascii_digit)
+        && (value.ends_with('Z') || bytes[19..].iter().any(|b| matches!(b, b'+' | b'-')))
+}
+
+/// Literal for one composite key value, by declared `Edm` type when known
+fn typed_key_literal(product: &ProductType, value: &Value, type_name: Option<&str>) -> String {
+    let quoted = |text: &str| FilterValue::String(text.to_string()).render(product);
+    match (value, type_name) {
+        (Value::Null, _) => "null".to_string(),
+        (Value::String(text), Some("Edm.String")) => quoted(text),
+        (Value::Number(number), Some("Edm.String")) => quoted(&number.to_string()),
+        (Value::Bool(flag), _) => flag.to_string(),
+        (Value::Number(number), _) => number.to_string(),
+        (Value::String(text), Some("Edm.Guid")) => FilterValue::guid(text.as_str()).render(product),
+        (
+            Value::String(text),
+            Some(
+                "Edm.Int16" | "Edm.Int32" | "Edm.Int64" | "Edm.Byte" | "Edm.Decimal" | "Edm.Double"
+                | "Edm.Single" | "Edm.Date" | "Edm.DateTimeOffset" | "Edm.TimeOfDay"
+                | "Edm.Boolean",
+            ),
+        ) => text.trim().to_string(),
+        // F&O enums: `Microsoft.Dynamics.DataEntities.NoYes'Yes'`
+        (Value::String(text), Some(enum_type)) if !enum_type.starts_with("Edm.") => {
+            format!("{}{}", enum_type, quoted(text))
+        }
+        (Value::String(text), _) if is_guid(text) => {
+            FilterValue::guid(text.as_str()).render(product)
+        }
+        (Value::String(text), _) if is_timestamp(text) => text.clone(),
+        (Value::String(text), _) => quoted(text),
+        (other, _) => quoted(&other.to_string()),
+    }
 }
 
 /// Query options for OData requests
+///
+/// Fields may be added in any release, so code outside this crate builds
+/// options with [`QueryOptions::builder`]:
+///
+/// ```
+/// use d365_odata_mcp::{ProductType, QueryOptions};
+///
+/// let options = QueryOptions::builder()
+///     .select(["name", "accountid"])
+///     .filter("statecode eq 0")
+///     .top(10)
+///     .build();
+/// assert_eq!(
+///     options.to_query_string(&ProductType::Dataverse),
+///     "?$select=name,accountid&$filter=statecode%20eq%200&$top=10"
+/// );
+/// ```
 #[derive(Debug, Clone, Default)]
+#[non_exhaustive]
 pub struct QueryOptions {
     pub select: Option<Vec<String>>,
     pub filter: Option<String>,
+    /// Typed filter expression; takes precedence over `filter` when set
+    pub filter_expr: Option<FilterExpr>,
     pub top: Option<usize>,
     pub skip: Option<usize>,
     pub orderby: Option<String>,
     pub expand: Option<Vec<String>>,
     pub cross_company: bool, // F&O only
     pub count: bool,         // Include @odata.count in response
+    /// Overrides the client's `Prefer: odata.maxpagesize` for this request
+    pub max_page_size: Option<usize>,
+    /// Free-text `$search` expression
+    pub search: Option<String>,
+    /// `$format`, e.g. `json`
+    pub format: Option<String>,
+    /// `$skiptoken` from a server-driven page
+    pub skiptoken: Option<String>,
+    /// Instance annotations to ask for; the client's default when `None`
+    pub annotations: Option<AnnotationMode>,
+    /// `$apply` aggregation, e.g. `groupby((paymenttermscode))`
+    pub apply: Option<String>,
 }
 
 impl QueryOptions {
@@ -55,11 +451,16 @@ impl QueryOptions {
         let mut params = Vec::new();
 
         if let Some(ref select) = self.select {
-            params.push(format!("$select={}", select.join(",")));
+            params.push(format!("$select={}", encode_query_value(&select.join(","))));
         }
 
-        if let Some(ref filter) = self.filter {
-            params.push(format!("$filter={}", filter));
+        if let Some(ref filter_expr) = self.filter_expr {
+            params.push(format!(
+                "$filter={}",
+                encode_query_value(&filter_expr.render(product))
+            ));
+        } else if let Some(ref filter) = self.filter {
+            params.push(format!("$filter={}", encode_query_value(filter)));
         }
 
         if let Some(top) = self.top {
@@ -71,11 +472,11 @@ impl QueryOptions {
         }
 
         if let Some(ref orderby) = self.orderby {
-            params.push(format!("$orderby={}", orderby));
+            params.push(format!("$orderby={}", encode_query_value(orderby)));
         }
 
         if let Some(ref expand) = self.expand {
-            params.push(format!("$expand={}", expand.join(",")));
+            params.push(format!("$expand={}", encode_query_value(&expand.join(","))));
         }
 
         // Include count in response
@@ -83,6 +484,22 @@ impl QueryOptions {
             params.push("$count=true".to_string());
         }
 
+        if let Some(ref search) = self.search {
+            params.push(format!("$search={}", encode_query_value(search)));
+        }
+
+        if let Some(ref format) = self.format {
+            params.push(format!("$format={}", encode_query_value(format)));
+        }
+
+        if let Some(ref skiptoken) = self.skiptoken {
+            params.push(format!("$skiptoken={}", encode_query_value(skiptoken)));
+        }
+
+        if let Some(ref apply) = self.apply {
+            params.push(format!("$apply={}", encode_query_value(apply)));
+        }
+
         // F&O specific: cross-company query
         if self.cross_company && *product == ProductType::Finops {
             params.push("cross-company=true".to_string());
@@ -94,6 +511,111 @@ impl QueryOptions {
             format!("?{}", params.join("&"))
         }
     }
+
+    /// Start building options; every option is unset until given
+    pub fn builder() -> QueryOptionsBuilder {
+        QueryOptionsBuilder::default()
+    }
+}
+
+/// Builder for [`QueryOptions`], see [`QueryOptions::builder`]
+#[derive(Debug, Clone, Default)]
+pub struct QueryOptionsBuilder {
+    options: QueryOptions,
+}
+
+impl QueryOptionsBuilder {
+    pub fn select<I, S>(mut self, fields: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.options.select = Some(fields.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn filter(mut self, filter: impl Into<String>) -> Self {
+        self.options.filter = Some(filter.into());
+        self
+    }
+
+    /// Typed filter; takes precedence over [`filter`](Self::filter)
+    pub fn filter_expr(mut self, filter: FilterExpr) -> Self {
+        self.options.filter_expr = Some(filter);
+        self
+    }
+
+    pub fn top(mut self, top: usize) -> Self {
+        self.options.top = Some(top);
+        self
+    }
+
+    pub fn skip(mut self, skip: usize) -> Self {
+        self.options.skip = Some(skip);
+        self
+    }
+
+    pub fn orderby(mut self, orderby: impl Into<String>) -> Self {
+        self.options.orderby = Some(orderby.into());
+        self
+    }
+
+    pub fn expand<I, S>(mut self, navigation: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.options.expand = Some(navigation.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Query every legal entity (F&O only)
+    pub fn cross_company(mut self, cross_company: bool) -> Self {
+        self.options.cross_company = cross_company;
+        self
+    }
+
+    /// Ask for `@odata.count`
+    pub fn count(mut self, count: bool) -> Self {
+        self.options.count = count;
+        self
+    }
+
+    pub fn max_page_size(mut self, max_page_size: usize) -> Self {
+        self.options.max_page_size = Some(max_page_size);
+        self
+    }
+
+    pub fn search(mut self, search: impl Into<String>) -> Self {
+        self.options.search = Some(search.into());
+        self
+    }
+
+    pub fn format(mut self, format: impl Into<String>) -> Self {
+        self.options.format = Some(format.into());
+        self
+    }
+
+    pub fn skiptoken(mut self, skiptoken: impl Into<String>) -> Self {
+        self.options.skiptoken = Some(skiptoken.into());
+        self
+    }
+
+    /// Instance annotations to ask for, instead of the client's default
+    pub fn annotations(mut self, annotations: AnnotationMode) -> Self {
+        self.options.annotations = Some(annotations);
+        self
+    }
+
+    /// `$apply` transformation, e.g. `groupby((statecode))`
+    pub fn apply(mut self, apply: impl Into<String>) -> Self {
+        self.options.apply = Some(apply.into());
+        self
+    }
+
+    pub fn build(self) -> QueryOptions {
+        self.options
+    }
 }
 
 /// OData response with paging support
@@ -111,8 +633,157 @@ pub struct ODataResponse {
     #[serde(rename = "@odata.deltaLink")]
     pub delta_link: Option<String>,
 
+    /// Dataverse stopped counting at 5000, so `count` is only a lower bound.
+    /// Sent when all annotations are requested; F&O counts are always exact
+    #[serde(
+        rename = "@Microsoft.Dynamics.CRM.totalrecordcountlimitexceeded",
+        default
+    )]
+    pub count_limit_exceeded: bool,
+
     #[serde(default)]
     pub value: Vec<Value>,
+
+    /// Every other top-level annotation, e.g. `@Microsoft.Dynamics.CRM.morerecords`
+    #[serde(flatten)]
+    pub annotations: serde_json::Map<String, Value>,
+}
+
+impl ODataResponse {
+    /// What `@odata.context` says about the records, see [`parse_context_url`]
+    pub fn context_url(&self) -> Option<ContextUrl> {
+        parse_context_url(self.context.as_deref()?)
+    }
+}
+
+/// What an `@odata.context` URL says about a payload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextUrl {
+    /// Entity set or path the records come from, e.g. `accounts` or
+    /// `accounts(<id>)/contact_customer_accounts`; the element type of a
+    /// `Collection(...)`
+    pub entity_set: String,
+    /// Fields of the select list, in order; empty when all fields are sent.
+    /// Expanded fields keep their own list, e.g. `primarycontactid(fullname)`
+    pub select: Vec<String>,
+    /// `/$entity`: a single record rather than a collection
+    pub single_entity: bool,
+}
+
+impl ContextUrl {
+    /// The entity set, or for a path the navigation property at its end,
+    /// without a key
+    pub fn last_segment(&self) -> &str {
+        let last = self.entity_set.rsplit('/').next().unwrap_or_default();
+        last.split('(').next().unwrap_or_default()
+    }
+}
+
+/// Parse the fragment of an `@odata.context` URL, e.g.
+/// `https://host/data/$metadata#CustomersV3(dataAreaId,CustomerAccount)/$entity`;
+/// `None` when it has no fragment
+pub fn parse_context_url(context: &str) -> Option<ContextUrl> {
+    let (_, fragment) = context.split_once('#')?;
+    let (fragment, single_entity) = match fragment.strip_suffix("/$entity") {
+        Some(fragment) => (fragment, true),
+        None => (fragment, false),
+    };
+    if fragment.is_empty() {
+        return None;
+    }
+
+    // A trailing parenthesis holds the select list, unless it is a key
+    // inside a path such as `accounts(<id>)/contacts`
+    let (path, select) = match fragment
+        .strip_suffix(')')
+        .and_then(|inner| Some((inner, matching_open_paren(inner)?)))
+    {
+        Some((inner, open)) => (&inner[..open], &inner[open + 1..]),
+        None => (fragment, ""),
+    };
+    if path == "Collection" {
+        return Some(ContextUrl {
+            entity_set: select.to_string(),
+            select: Vec::new(),
+            single_entity,
+        });
+    }
+    Some(ContextUrl {
+        entity_set: path.to_string(),
+        select: split_top_level(select),
+        single_entity,
+    })
+}
+
+/// Index of the `(` closing at the end of `text` (whose final `)` was cut off)
+fn matching_open_paren(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (index, c) in text.char_indices().rev() {
+        match c {
+            ')' => depth += 1,
+            '(' if depth == 0 => return Some(index),
+            '(' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// `text` split at commas outside parentheses, e.g. an expanded select list
+fn split_top_level(text: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, c) in text.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                fields.push(text[start..index].trim().to_string());
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    fields.push(text[start..].trim().to_string());
+    fields.retain(|field| !field.is_empty());
+    fields
+}
+
+/// `account` for an `@odata.type` of `#Microsoft.Dynamics.CRM.account`
+pub fn short_type_name(odata_type: &str) -> &str {
+    let name = odata_type.trim_start_matches('#');
+    name.rsplit('.').next().unwrap_or(name)
+}
+
+/// Outcome of a streaming multi-page fetch
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamSummary {
+    /// Pages requested from the server
+    pub pages: usize,
+    /// Records handed to the page callback
+    pub records: usize,
+    /// More records were available but `max_records` was reached
+    pub truncated: bool,
+    /// The page callback asked to stop
+    pub aborted: bool,
+    /// The call's deadline passed after at least one page
+    pub timed_out: bool,
+}
+
+/// What an upsert did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpsertOutcome {
+    Created,
+    Updated,
+}
+
+/// Contents of a Dataverse file or image column
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDownload {
+    /// Name from the `x-ms-file-name` header, when the server sends one
+    pub file_name: Option<String>,
+    pub bytes: Vec<u8>,
 }
 
 /// Entity metadata information
@@ -124,42 +795,199 @@ pub struct EntityInfo {
     pub description: Option<String>,
 }
 
-/// Cached metadata with timestamp for TTL-based expiry
-#[derive(Debug)]
-struct CachedMetadata {
-    xml: String,
-    fetched_at: Instant,
-}
-
 /// Default metadata cache TTL in seconds (15 minutes)
 const DEFAULT_METADATA_CACHE_TTL_SECS: u64 = 900;
 
+/// Default upper bound for a single retry wait in seconds
+pub const DEFAULT_MAX_RETRY_WAIT_SECS: u64 = 60;
+
+/// Default time a request, with its retries, may take in seconds
+pub const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 120;
+
+/// Longest a response may go without sending data; the overall bound is the
+/// request timeout
+const READ_STALL_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Bytes requested per `Range` when downloading a file column (Dataverse
+/// serves file contents in chunks of at most 4 MB)
+const FILE_CHUNK_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Metadata bytes downloaded between progress reports
+const METADATA_PROGRESS_INTERVAL: usize = 256 * 1024;
+
+/// Report records fetched so far, out of `@odata.count` when known
+fn report_page_progress(pages: usize, records: usize, total: Option<u64>) {
+    let message = match total {
+        Some(total) => format!(
+            "Fetched {} page(s), {} of {} records",
+            pages, records, total
+        ),
+        None => format!("Fetched {} page(s), {} records", pages, records),
+    };
+    report_progress(&message);
+}
+
+/// Parse a `Retry-After` header given as delta-seconds or an HTTP-date
+pub(crate) fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let at = httpdate::parse_http_date(value).ok()?;
+    // A date in the past means "retry now"
+    Some(at.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+/// HTTP client for OData requests, accepting gzip, deflate and brotli
+/// responses unless `compression` is off. Requests are bounded by the
+/// client's request timeout (or the call's deadline) rather than here; this
+/// only cuts off stalled reads. `proxy` must have passed
+/// [`ProxySettings::validate`].
+fn build_http_client(
+    insecure_ssl: bool,
+    root_certificates: &[Certificate],
+    proxy: &ProxySettings,
+    compression: bool,
+) -> Client {
+    let builder = Client::builder()
+        .user_agent(USER_AGENT)
+        .read_timeout(READ_STALL_TIMEOUT)
+        .gzip(compression)
+        .deflate(compression)
+        .brotli(compression);
+    let builder = apply_tls(builder, root_certificates, insecure_ssl);
+    proxy
+        .apply(builder)
+        .expect("proxy settings are validated before use")
+        .build()
+        .unwrap()
+}
+
+/// Exponential backoff delay plus up to 50% random jitter
+fn backoff_with_jitter(delay_ms: u64) -> Duration {
+    let jitter = rand::random_range(0..=delay_ms / 2);
+    Duration::from_millis(delay_ms + jitter)
+}
+
 /// OData client for D365 APIs
 pub struct ODataClient {
-    auth: Arc<AzureAdAuth>,
+    auth: Arc<dyn TokenProvider>,
     endpoint: String,
     product: ProductType,
     http_client: Client,
     max_retries: u32,
     retry_delay_ms: u64,
-    /// Cached metadata XML with TTL
-    metadata_cache: Arc<RwLock<Option<CachedMetadata>>>,
-    /// Cache TTL duration
-    cache_ttl: Duration,
+    /// Cached metadata document and parsed model, with TTL
+    metadata_cache: Arc<MetadataCache>,
+    /// Page size requested via `Prefer: odata.maxpagesize`
+    page_size: Option<usize>,
+    /// Client-side limiter applied before every request
+    rate_limiter: Arc<RateLimiter>,
+    /// Fails requests fast while the service keeps failing
+    circuit: Arc<CircuitBreaker>,
+    /// Holds every request back while a 429's `Retry-After` runs
+    backpressure: Arc<Backpressure>,
+    /// Upper bound for any single retry wait
+    max_retry_wait: Duration,
+    /// Bound for a request and its retries outside a call with its own deadline
+    request_timeout: Duration,
+    /// Dataverse user that requests are made on behalf of
+    impersonate_user_id: Option<String>,
+    /// Header carrying the impersonated user
+    caller_id_header: CallerIdHeader,
+    /// Skip TLS certificate verification (ignored with `root_certificates`)
+    insecure_ssl: bool,
+    /// Extra trusted roots, e.g. an on-premise CA
+    root_certificates: Vec<Certificate>,
+    /// Outbound proxy, named in connection errors
+    proxy: ProxySettings,
+    /// Accept compressed responses
+    compression: bool,
+    /// Move nextLinks onto the endpoint's host before following them
+    rewrite_next_link_host: bool,
+    /// Appended to the `User-Agent`, to tell deployments apart
+    user_agent_suffix: Option<String>,
+    /// Instance annotations requests ask for unless they say otherwise
+    annotations: AnnotationMode,
+    /// `Accept-Language` of requests outside a call asking for another
+    language: Option<String>,
+    /// Requests, retries, throttling and latency since creation
+    request_counters: Arc<RequestCounters>,
+    /// Directory that exchanges are recorded to or replayed from
+    recording: Option<Arc<Recording>>,
+    /// `$metadata` served from a snapshot, with every other request refused
+    offline: Option<Arc<MetadataSnapshot>>,
+}
+
+/// Per-request settings layered on top of the default headers
+#[derive(Debug, Default, Clone, Copy)]
+struct RequestOptions<'a> {
+    if_match: Option<&'a str>,
+    if_none_match: Option<&'a str>,
+    max_page_size: Option<usize>,
+    /// Overrides the client's annotation preference
+    annotations: Option<AnnotationMode>,
+    /// `Accept` header override (defaults to `application/json`)
+    accept: Option<&'a str>,
+    /// JSON request body
+    body: Option<&'a Value>,
+    /// `Range` header, e.g. `bytes=0-4194303`
+    range: Option<&'a str>,
+    /// Binary request body, sent instead of `body`
+    raw_body: Option<&'a [u8]>,
+    /// Additional headers, e.g. `x-ms-file-name`
+    headers: &'a [(&'a str, &'a str)],
+    /// Send no bearer token: the URL carries its own authorization (a SAS)
+    anonymous: bool,
+}
+
+/// The message of an OData error body (`{"error": {"code", "message"}}`),
+/// or the body unchanged when it has none
+fn error_message(body: String) -> String {
+    let parsed: Option<Value> = serde_json::from_str(&body).ok();
+    let error = parsed.as_ref().and_then(|value| value.get("error"));
+    let message = error
+        .and_then(|error| error.get("message"))
+        .and_then(Value::as_str);
+    let code = error
+        .and_then(|error| error.get("code"))
+        .and_then(Value::as_str)
+        .filter(|code| !code.is_empty());
+    match (code, message) {
+        (Some(code), Some(message)) => format!("{} ({})", message, code),
+        (None, Some(message)) => message.to_string(),
+        _ => body,
+    }
+}
+
+/// Build a single `Prefer` header value, or `None` when nothing is
+/// preferred; several `Prefer` headers may be collapsed by proxies, so all
+/// preferences are comma-joined
+fn prefer_header(max_page_size: Option<usize>, annotations: AnnotationMode) -> Option<String> {
+    let mut preferences: Vec<String> = annotations
+        .preference()
+        .map(String::from)
+        .into_iter()
+        .collect();
+    if let Some(size) = max_page_size {
+        preferences.push(format!("odata.maxpagesize={}", size));
+    }
+    (!preferences.is_empty()).then(|| preferences.join(","))
 }
 
 impl ODataClient {
     /// Create a new OData client
     ///
     /// # Arguments
-    /// * `auth` - Azure AD auth helper
+    /// * `auth` - Token provider (e.g. `AzureAdAuth` or `ManagedIdentityAuth`)
     /// * `endpoint` - Service root URL (e.g., "https://org.crm.dynamics.com/api/data/v9.2/")
     /// * `product` - Product type (Dataverse or F&O)
     /// * `max_retries` - Maximum retry attempts for failed requests
     /// * `retry_delay_ms` - Initial delay between retries in milliseconds
     /// * `insecure_ssl` - Skip SSL certificate verification
     pub fn new(
-        auth: Arc<AzureAdAuth>,
+        auth: Arc<dyn TokenProvider>,
         endpoint: String,
         product: ProductType,
         max_retries: u32,
@@ -180,7 +1008,7 @@ impl ODataClient {
     /// Create a new OData client with custom cache TTL
     ///
     /// # Arguments
-    /// * `auth` - Azure AD auth helper
+    /// * `auth` - Token provider (e.g. `AzureAdAuth` or `ManagedIdentityAuth`)
     /// * `endpoint` - Service root URL
     /// * `product` - Product type (Dataverse or F&O)
     /// * `max_retries` - Maximum retry attempts for failed requests
@@ -188,7 +1016,7 @@ impl ODataClient {
     /// * `insecure_ssl` - Skip SSL certificate verification
     /// * `cache_ttl` - Metadata cache TTL duration
     pub fn with_cache_ttl(
-        auth: Arc<AzureAdAuth>,
+        auth: Arc<dyn TokenProvider>,
         endpoint: String,
         product: ProductType,
         max_retries: u32,
@@ -203,186 +1031,663 @@ impl ODataClient {
             format!("{}/", endpoint)
         };
 
-        let http_client = if insecure_ssl {
-            Client::builder()
-                .timeout(Duration::from_secs(120)) // Longer timeout for large $metadata
-                .danger_accept_invalid_certs(true)
-                .build()
-                .unwrap()
-        } else {
-            Client::builder()
-                .timeout(Duration::from_secs(120)) // Longer timeout for large $metadata
-                .build()
-                .unwrap()
-        };
-
+        let proxy = ProxySettings::default();
         Self {
             auth,
             endpoint,
             product,
-            http_client,
+            http_client: build_http_client(insecure_ssl, &[], &proxy, true),
             max_retries,
             retry_delay_ms,
-            metadata_cache: Arc::new(RwLock::new(None)),
-            cache_ttl,
+            metadata_cache: Arc::new(MetadataCache::new(cache_ttl)),
+            page_size: None,
+            rate_limiter: Arc::new(RateLimiter::unlimited()),
+            circuit: Arc::new(CircuitBreaker::new(
+                DEFAULT_CIRCUIT_THRESHOLD,
+                DEFAULT_CIRCUIT_COOLDOWN,
+            )),
+            backpressure: Arc::new(Backpressure::new()),
+            max_retry_wait: Duration::from_secs(DEFAULT_MAX_RETRY_WAIT_SECS),
+            request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT_SECS),
+            impersonate_user_id: None,
+            caller_id_header: CallerIdHeader::default(),
+            insecure_ssl,
+            root_certificates: Vec::new(),
+            proxy,
+            compression: true,
+            rewrite_next_link_host: false,
+            user_agent_suffix: None,
+            annotations: AnnotationMode::default(),
+            language: None,
+            request_counters: Arc::new(RequestCounters::default()),
+            recording: None,
+            offline: None,
         }
     }
 
-    /// Get the resource URL for token acquisition
-    fn resource(&self) -> String {
-        AzureAdAuth::resource_from_endpoint(&self.endpoint)
+    /// Send requests through an explicit proxy; a malformed proxy URL is
+    /// an error
+    pub fn with_proxy(mut self, proxy: ProxySettings) -> Result<Self, ODataError> {
+        proxy.validate().map_err(ODataError::InvalidProxy)?;
+        self.proxy = proxy;
+        self.rebuild_http_client();
+        Ok(self)
     }
 
-    /// Execute HTTP request with retry logic
-    async fn execute_with_retry(
-        &self,
-        method: Method,
-        url: &str,
-        token: &str,
-        if_match: Option<&str>,
-    ) -> Result<Response, ODataError> {
-        let mut attempt = 0;
-        let mut delay = self.retry_delay_ms;
-
-        loop {
-            attempt += 1;
-
-            let mut request = self
-                .http_client
-                .request(method.clone(), url)
-                .header("Authorization", format!("Bearer {}", token))
-                .header("Accept", "application/json")
-                .header("OData-MaxVersion", "4.0")
-                .header("OData-Version", "4.0")
-                .header("Prefer", "odata.include-annotations=*");
-
-            if let Some(if_match) = if_match {
-                request = request.header("If-Match", if_match);
-            }
+    /// Trust these root certificates as well; verification stays on even
+    /// when the client was created with `insecure_ssl`
+    pub fn with_root_certificates(mut self, certificates: Vec<Certificate>) -> Self {
+        self.root_certificates = certificates;
+        self.rebuild_http_client();
+        self
+    }
 
-            let response = request.send().await?;
+    /// Ask for gzip, deflate or brotli responses (the default); disabling it
+    /// helps when inspecting raw traffic
+    pub fn with_compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self.rebuild_http_client();
+        self
+    }
 
-            match response.status() {
-                StatusCode::OK | StatusCode::CREATED | StatusCode::NO_CONTENT => {
-                    return Ok(response);
-                }
-                StatusCode::TOO_MANY_REQUESTS => {
-                    // Get Retry-After header if available
-                    let retry_after = response
-                        .headers()
-                        .get("Retry-After")
-                        .and_then(|v| v.to_str().ok())
-                        .and_then(|v| v.parse::<u64>().ok())
-                        .unwrap_or(delay / 1000);
+    /// Follow nextLinks on the configured endpoint's host, keeping their path
+    /// and `$skiptoken`; for F&O reached through a private endpoint whose
+    /// nextLinks name the public host
+    pub fn with_next_link_rewrite(mut self, rewrite: bool) -> Self {
+        self.rewrite_next_link_host = rewrite;
+        self
+    }
 
-                    if attempt >= self.max_retries {
-                        return Err(ODataError::RateLimited(retry_after));
-                    }
+    /// Append `suffix` to the `User-Agent` of every request, e.g. a
+    /// deployment name
+    pub fn with_user_agent_suffix(mut self, suffix: Option<String>) -> Self {
+        self.user_agent_suffix = suffix;
+        self
+    }
 
-                    tracing::warn!(
-                        "Rate limited (429), attempt {}/{}, retrying after {} seconds",
-                        attempt,
-                        self.max_retries,
-                        retry_after
-                    );
+    /// Ask for `annotations` on every request that does not choose its own
+    pub fn with_annotations(mut self, annotations: AnnotationMode) -> Self {
+        self.annotations = annotations;
+        self
+    }
 
-                    sleep(Duration::from_secs(retry_after)).await;
-                    delay *= 2; // Exponential backoff
-                }
-                StatusCode::NOT_FOUND => {
-                    let body = response.text().await.unwrap_or_default();
-                    return Err(ODataError::NotFound(body));
-                }
-                status if status.is_server_error() => {
-                    if attempt >= self.max_retries {
-                        let body = response.text().await.unwrap_or_default();
-                        return Err(ODataError::ServerError(status.as_u16(), body));
-                    }
+    /// Ask for labels in `language`, e.g. `de-DE`, via `Accept-Language`
+    pub fn with_language(mut self, language: Option<String>) -> Self {
+        self.language = language;
+        self
+    }
 
-                    tracing::warn!(
-                        "Server error ({}), attempt {}/{}, retrying...",
-                        status,
-                        attempt,
-                        self.max_retries
-                    );
+    /// Language of this request: the tool call's override, else the
+    /// configured one; `None` leaves it to the service
+    pub fn language(&self) -> Option<String> {
+        language_override().or_else(|| self.language.clone())
+    }
 
-                    sleep(Duration::from_millis(delay)).await;
-                    delay *= 2;
-                }
-                status => {
-                    let body = response.text().await.unwrap_or_default();
-                    return Err(ODataError::ServerError(status.as_u16(), body));
-                }
-            }
-        }
+    /// Dataverse LCID of [`Self::language`]
+    pub(crate) fn language_code(&self) -> Option<u32> {
+        language_code(&self.language()?)
     }
 
-    /// Fetch $metadata XML with caching
-    ///
-    /// Returns cached metadata if available and not expired.
-    /// Otherwise fetches from server and updates cache.
-    pub async fn fetch_metadata(&self) -> Result<String, ODataError> {
-        // 1. Check cache first (read lock)
-        {
-            let cache = self.metadata_cache.read().await;
-            if let Some(ref cached) = *cache {
-                if cached.fetched_at.elapsed() < self.cache_ttl {
-                    tracing::debug!(
-                        "Metadata cache hit (age: {:?}, ttl: {:?})",
-                        cached.fetched_at.elapsed(),
-                        self.cache_ttl
-                    );
-                    return Ok(cached.xml.clone());
-                }
-                tracing::debug!("Metadata cache expired, will refresh");
+    /// `link` on the endpoint's host when the rewrite is on, else as given
+    fn next_link_url(&self, link: &str) -> String {
+        if !self.rewrite_next_link_host {
+            return link.to_string();
+        }
+        match rebase_link(link, &self.endpoint) {
+            Some(rebased) if rebased != link => {
+                tracing::debug!("Rewrote nextLink {} to {}", link, rebased);
+                rebased
             }
+            _ => link.to_string(),
         }
+    }
 
-        // 2. Cache miss or expired - fetch from server
-        tracing::debug!("Fetching metadata from server...");
-        let xml = self.fetch_metadata_from_server().await?;
+    fn rebuild_http_client(&mut self) {
+        self.http_client = build_http_client(
+            self.insecure_ssl,
+            &self.root_certificates,
+            &self.proxy,
+            self.compression,
+        );
+    }
 
-        // 3. Update cache (write lock)
-        {
-            let mut cache = self.metadata_cache.write().await;
-            *cache = Some(CachedMetadata {
-                xml: xml.clone(),
-                fetched_at: Instant::now(),
-            });
-            tracing::debug!(
-                "Metadata cached (size: {} bytes, ttl: {:?})",
-                xml.len(),
-                self.cache_ttl
-            );
-        }
+    /// Record every exchange to a directory, or answer from one instead of
+    /// the service; see [`Recording`]
+    pub fn with_recording(mut self, recording: Recording) -> Self {
+        self.recording = Some(Arc::new(recording));
+        self
+    }
 
-        Ok(xml)
+    /// Work offline from a `$metadata` snapshot: metadata-derived tools
+    /// keep working, and nothing is sent to the service
+    pub fn with_offline_metadata(mut self, snapshot: MetadataSnapshot) -> Self {
+        self.offline = Some(Arc::new(snapshot));
+        self
     }
 
-    /// Fetch $metadata XML directly from server (bypasses cache)
-    async fn fetch_metadata_from_server(&self) -> Result<String, ODataError> {
-        let url = format!("{}$metadata", self.endpoint);
-        let token = self.auth.get_token(&self.resource()).await?;
+    /// The snapshot an offline client reads `$metadata` from
+    pub fn offline_metadata(&self) -> Option<&MetadataSnapshot> {
+        self.offline.as_deref()
+    }
 
-        let response = self
-            .http_client
-            .get(&url)
-            .header("Authorization", format!("Bearer {}", token))
-            .header("Accept", "application/xml")
-            .send()
-            .await?;
+    /// Cap how long a single retry (including `Retry-After`) may wait
+    pub fn with_max_retry_wait(mut self, max_retry_wait: Duration) -> Self {
+        self.max_retry_wait = max_retry_wait;
+        self
+    }
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            return Err(ODataError::ServerError(status.as_u16(), body));
-        }
+    /// Bound each request, retries included, when the call sets no deadline
+    /// of its own (see [`with_timeout`](crate::odata::with_timeout))
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
 
-        // Get response as bytes to handle large XML and encoding issues
-        let bytes = response
-            .bytes()
+    /// Limit outgoing requests per minute and in flight; `None` disables a limit
+    pub fn with_rate_limit(
+        mut self,
+        max_requests_per_minute: Option<u32>,
+        max_concurrent_requests: Option<usize>,
+    ) -> Self {
+        self.rate_limiter = Arc::new(RateLimiter::new(
+            max_requests_per_minute,
+            max_concurrent_requests,
+        ));
+        self
+    }
+
+    /// Open the circuit after `threshold` consecutive connection failures or
+    /// 502/503/504 responses (0 disables it) and keep it open for `cooldown`
+    pub fn with_circuit_breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+        self.circuit = Arc::new(CircuitBreaker::new(threshold, cooldown));
+        self
+    }
+
+    /// Current circuit breaker state
+    pub fn circuit_stats(&self) -> CircuitStats {
+        self.circuit.stats()
+    }
+
+    /// Current `Retry-After` cool-down shared by all requests
+    pub fn backpressure_stats(&self) -> BackpressureStats {
+        self.backpressure.stats()
+    }
+
+    /// Close the circuit breaker so requests are sent again at once
+    pub fn reset_circuit(&self) {
+        self.circuit.reset();
+    }
+
+    /// Make Dataverse requests on behalf of `user_id`; F&O ignores this
+    pub fn with_impersonation(mut self, user_id: Option<String>, header: CallerIdHeader) -> Self {
+        self.impersonate_user_id = user_id;
+        self.caller_id_header = header;
+        self
+    }
+
+    /// The configured impersonation header and user; `None` for F&O
+    pub fn impersonation(&self) -> Option<(CallerIdHeader, &str)> {
+        if self.product != ProductType::Dataverse {
+            return None;
+        }
+        self.impersonate_user_id
+            .as_deref()
+            .map(|user_id| (self.caller_id_header, user_id))
+    }
+
+    /// Impersonation header for this request: the tool call's override, else
+    /// the configured user. Never sent to F&O.
+    fn caller_header(&self) -> Option<(&'static str, String)> {
+        if self.product != ProductType::Dataverse {
+            return None;
+        }
+        caller_override()
+            .or_else(|| self.impersonate_user_id.clone())
+            .map(|user_id| (self.caller_id_header.header_name(), user_id))
+    }
+
+    /// Current client-side rate limiter statistics
+    pub fn rate_limiter_stats(&self) -> RateLimiterStats {
+        self.rate_limiter.stats()
+    }
+
+    /// Requests sent so far, with retries, 429s, token refreshes and latencies
+    pub fn request_stats(&self) -> RequestStats {
+        self.request_counters.stats()
+    }
+
+    /// Request pages of at most `page_size` records via `Prefer: odata.maxpagesize`
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = (page_size > 0).then_some(page_size);
+        self
+    }
+
+    /// Get the resource URL for token acquisition
+    fn resource(&self) -> String {
+        AzureAdAuth::resource_from_endpoint(&self.endpoint)
+    }
+
+    /// Execute HTTP request with retry logic
+    ///
+    /// Every request goes through here so that token acquisition, throttling
+    /// and retries behave the same for reads, writes and `$metadata`. A 401 is
+    /// retried exactly once with a freshly acquired token, in case the cached
+    /// one was revoked.
+    ///
+    /// Each call sends one `client-request-id` on every attempt and is traced
+    /// in an `odata_request` span, with the MCP client the call came from;
+    /// filter values are masked at info level. The id of a call that fails
+    /// is logged and kept for the tool's error.
+    async fn execute_with_retry(
+        &self,
+        method: Method,
+        url: &str,
+        options: RequestOptions<'_>,
+    ) -> Result<Response, ODataError> {
+        let client_request_id = new_client_request_id();
+        let span = tracing::info_span!(
+            "odata_request",
+            method = %method,
+            url = %redact_url(url),
+            client_request_id = %client_request_id,
+            mcp_client = mcp_client().as_deref().unwrap_or("-"),
+        );
+        tracing::debug!(parent: &span, url, "OData request");
+        let result = self
+            .send_with_retry(method, url, options, &client_request_id)
+            .instrument(span.clone())
+            .await;
+        match &result {
+            Err(ODataError::DryRun | ODataError::Cancelled | ODataError::Offline(_)) | Ok(_) => {}
+            Err(e) => {
+                // Named on the event too: MCP log notifications carry no span fields
+                tracing::info!(
+                    parent: &span,
+                    error = %e,
+                    client_request_id = %client_request_id,
+                    mcp_client = mcp_client().as_deref().unwrap_or("-"),
+                    "OData request failed"
+                );
+                record_failed_request(&client_request_id);
+            }
+        }
+        result
+    }
+
+    /// The request `send_with_retry` sends, without the bearer token
+    fn prepare_request(
+        &self,
+        method: &Method,
+        url: &str,
+        options: &RequestOptions<'_>,
+        client_request_id: &str,
+    ) -> PreparedRequest {
+        let mut headers = vec![
+            (
+                "Accept",
+                options.accept.unwrap_or("application/json").to_string(),
+            ),
+            ("OData-MaxVersion", "4.0".to_string()),
+            ("OData-Version", "4.0".to_string()),
+            ("client-request-id", client_request_id.to_string()),
+            ("User-Agent", user_agent(self.user_agent_suffix.as_deref())),
+        ];
+        let annotations = options.annotations.unwrap_or(self.annotations);
+        if let Some(prefer) = prefer_header(options.max_page_size, annotations) {
+            headers.push(("Prefer", prefer));
+        }
+        if let Some(if_match) = options.if_match {
+            headers.push(("If-Match", if_match.to_string()));
+        }
+        if let Some(if_none_match) = options.if_none_match {
+            headers.push(("If-None-Match", if_none_match.to_string()));
+        }
+        if let Some((header, user_id)) = self.caller_header() {
+            headers.push((header, user_id));
+        }
+        if let Some(language) = self.language() {
+            headers.push(("Accept-Language", language));
+        }
+        if options.body.is_some() {
+            headers.push(("Content-Type", "application/json".to_string()));
+        }
+        if let Some(range) = options.range {
+            headers.push(("Range", range.to_string()));
+        }
+        headers.extend(
+            options
+                .headers
+                .iter()
+                .map(|(name, value)| (*name, value.to_string())),
+        );
+
+        let body = match (options.body, options.raw_body) {
+            (Some(body), _) => Some(body.to_string()),
+            (None, Some(raw_body)) => Some(format!("<{} bytes of binary data>", raw_body.len())),
+            (None, None) => None,
+        };
+        PreparedRequest {
+            method: method.clone(),
+            url: url.to_string(),
+            headers: headers
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+            body,
+        }
+    }
+
+    async fn send_with_retry(
+        &self,
+        method: Method,
+        url: &str,
+        options: RequestOptions<'_>,
+        client_request_id: &str,
+    ) -> Result<Response, ODataError> {
+        let prepared = self.prepare_request(&method, url, &options, client_request_id);
+        if record_dry_run(&prepared) {
+            return Err(ODataError::DryRun);
+        }
+        if let Some(snapshot) = &self.offline {
+            return Err(ODataError::Offline(snapshot.path().display().to_string()));
+        }
+
+        // A replay needs no token, and a recorded 401 is final
+        let replay = self
+            .recording
+            .as_deref()
+            .filter(|recording| recording.mode() == RecordingMode::Replay);
+        let deadline = Deadline::current_or(self.request_timeout);
+        let resource = self.resource();
+        let tokenless = replay.is_some() || options.anonymous;
+        let mut token = match tokenless {
+            true => String::new(),
+            false => deadline.run(self.auth.get_token(&resource)).await??,
+        };
+        if !tokenless {
+            self.request_counters.observe_token(&token);
+        }
+        let mut token_refreshed = tokenless;
+        // Recordings and storage uploads say nothing about the service's health
+        let circuit = (replay.is_none() && !options.anonymous).then_some(&*self.circuit);
+        let backpressure = circuit.is_some().then_some(&*self.backpressure);
+        let mut attempt = 0;
+        // Unlike `attempt`, this also counts the 401 refresh retry
+        let mut sent = 0;
+        let mut delay = self.retry_delay_ms;
+
+        loop {
+            check_cancelled()?;
+            if let Some(circuit) = circuit {
+                circuit.check()?;
+            }
+            if let Some(backpressure) = backpressure {
+                deadline
+                    .run(cancellable(backpressure.ready(deadline.remaining())))
+                    .await???;
+            }
+            attempt += 1;
+            sent += 1;
+
+            let mut request = self.http_client.request(method.clone(), url);
+            if !options.anonymous {
+                request = request.header("Authorization", format!("Bearer {}", token));
+            }
+            for (name, value) in &prepared.headers {
+                request = request.header(name, value);
+            }
+            if let Some(body) = options.body {
+                request = request.json(body);
+            }
+            if let Some(raw_body) = options.raw_body {
+                request = request.body(raw_body.to_vec());
+            }
+
+            let (response, elapsed) = match replay {
+                Some(recording) => {
+                    let response = recording
+                        .respond(&self.endpoint, &method, url)
+                        .ok_or_else(|| ODataError::NotRecorded(format!("{} {}", method, url)))?;
+                    (response, Duration::ZERO)
+                }
+                None => {
+                    let (response, elapsed) = deadline
+                        .run(cancellable(async {
+                            let _permit = self.rate_limiter.acquire().await;
+                            let started = Instant::now();
+                            (request.send().await, started.elapsed())
+                        }))
+                        .await??;
+                    let mut response = response.map_err(|e| {
+                        self.request_counters.record_failure();
+                        if let Some(circuit) = circuit {
+                            circuit.record_failure();
+                        }
+                        match self.proxy.proxy_for(url) {
+                            Some(proxy) => ODataError::Proxy { proxy, source: e },
+                            None => ODataError::HttpError(e),
+                        }
+                    })?;
+                    if let Some(recording) = &self.recording {
+                        response = recording
+                            .capture(&self.endpoint, &prepared, response)
+                            .await?;
+                    }
+                    (response, elapsed)
+                }
+            };
+
+            let status = response.status();
+            self.request_counters.record(sent, status.as_u16(), elapsed);
+            if let Some(circuit) = circuit {
+                // A 500 is usually the request's fault, e.g. an F&O query the
+                // service cannot run, rather than the service being down
+                match status {
+                    StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT => circuit.record_failure(),
+                    _ => circuit.record_success(),
+                }
+            }
+            let correlation = |name: &str| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("-")
+                    .to_string()
+            };
+            tracing::info!(
+                attempt = sent,
+                status = status.as_u16(),
+                elapsed_ms = elapsed.as_millis() as u64,
+                service_request_id = %correlation("x-ms-service-request-id"),
+                ms_cv = %correlation("ms-cv"),
+                "OData response"
+            );
+
+            match status {
+                // 202: a long-running operation was queued, see `operation.rs`
+                StatusCode::OK
+                | StatusCode::CREATED
+                | StatusCode::ACCEPTED
+                | StatusCode::NO_CONTENT
+                | StatusCode::PARTIAL_CONTENT => {
+                    return Ok(response);
+                }
+                StatusCode::TOO_MANY_REQUESTS => {
+                    // Get Retry-After header if available (seconds or HTTP-date)
+                    let retry_after = response
+                        .headers()
+                        .get("Retry-After")
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| parse_retry_after(v, SystemTime::now()))
+                        .unwrap_or_else(|| backoff_with_jitter(delay))
+                        .min(self.max_retry_wait);
+                    if let Some(backpressure) = backpressure {
+                        backpressure.throttled(retry_after);
+                    }
+
+                    if attempt >= self.max_retries {
+                        return Err(ODataError::RateLimited(retry_after.as_secs()));
+                    }
+
+                    tracing::warn!(
+                        "Rate limited (429), attempt {}/{}, retrying after {:?}",
+                        attempt,
+                        self.max_retries,
+                        retry_after
+                    );
+
+                    deadline.run(cancellable(sleep(retry_after))).await??;
+                    delay *= 2; // Exponential backoff
+                }
+                StatusCode::UNAUTHORIZED => {
+                    if token_refreshed {
+                        let challenge = response
+                            .headers()
+                            .get("WWW-Authenticate")
+                            .and_then(|v| v.to_str().ok())
+                            .unwrap_or("no WWW-Authenticate challenge")
+                            .to_string();
+                        return Err(ODataError::Unauthorized(challenge));
+                    }
+
+                    tracing::warn!("Unauthorized (401), refreshing access token and retrying once");
+
+                    self.auth.clear_resource(&resource).await;
+                    token = deadline.run(self.auth.get_token(&resource)).await??;
+                    self.request_counters.observe_token(&token);
+                    token_refreshed = true;
+                    // The refresh retry does not count against max_retries
+                    attempt -= 1;
+                }
+                StatusCode::NOT_FOUND => {
+                    let body = response.text().await.unwrap_or_default();
+                    return Err(ODataError::NotFound(error_message(body)));
+                }
+                status if status.is_server_error() => {
+                    if attempt >= self.max_retries {
+                        let body = response.text().await.unwrap_or_default();
+                        return Err(ODataError::ServerError(
+                            status.as_u16(),
+                            error_message(body),
+                        ));
+                    }
+
+                    tracing::warn!(
+                        "Server error ({}), attempt {}/{}, retrying...",
+                        status,
+                        attempt,
+                        self.max_retries
+                    );
+
+                    let wait = backoff_with_jitter(delay).min(self.max_retry_wait);
+                    deadline.run(cancellable(sleep(wait))).await??;
+                    delay *= 2;
+                }
+                status => {
+                    let body = response.text().await.unwrap_or_default();
+                    return Err(ODataError::ServerError(
+                        status.as_u16(),
+                        error_message(body),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Fetch the $metadata document with caching
+    ///
+    /// Returns the cached document if available and not expired. Otherwise
+    /// fetches it from the server; concurrent callers share one download.
+    #[tracing::instrument(skip_all)]
+    pub async fn fetch_metadata(&self) -> Result<Arc<MetadataDocument>, ODataError> {
+        // Writes may need $metadata to build their request, even in a dry run
+        self.metadata_cache
+            .get_or_fetch(self.language(), || {
+                without_dry_run(self.fetch_metadata_from_server())
+            })
             .await
-            .map_err(|e| ODataError::ParseError(format!("Failed to read metadata bytes: {}", e)))?;
+    }
+
+    /// The parsed `$metadata`, built once per cached document
+    pub async fn metadata_model(&self) -> Result<Arc<MetadataModel>, ODataError> {
+        Ok(self.fetch_metadata().await?.model())
+    }
+
+    /// The parsed `$metadata` if a fresh copy is already cached; never downloads
+    ///
+    /// A call in another language falls back to the default language's
+    /// document, since entity sets, types and fields are the same in every
+    /// language.
+    pub async fn cached_metadata_model(&self) -> Option<Arc<MetadataModel>> {
+        Some(self.cached_metadata().await?.model())
+    }
+
+    async fn cached_metadata(&self) -> Option<Arc<MetadataDocument>> {
+        let language = self.language();
+        if let Some(document) = self.metadata_cache.get(language.clone()).await {
+            return Some(document);
+        }
+        if language == self.language {
+            return None;
+        }
+        self.metadata_cache.get(self.language.clone()).await
+    }
+
+    /// Entity set name as the service spells it, from a set or type name in any case
+    ///
+    /// Set names are case-sensitive in the URL, so `customersv3` has to become
+    /// `CustomersV3` before it is requested.
+    pub async fn resolve_entity_set(&self, name: &str) -> Result<String, ODataError> {
+        let model = self.metadata_model().await?;
+        model
+            .resolve_entity_set(name)
+            .map(String::from)
+            .ok_or_else(|| {
+                ODataError::NotFound(format!("Entity set '{}' not found in $metadata", name))
+            })
+    }
+
+    /// Fetch $metadata XML directly from server (bypasses cache)
+    async fn fetch_metadata_from_server(&self) -> Result<String, ODataError> {
+        if let Some(snapshot) = &self.offline {
+            return Ok(snapshot.xml().to_string());
+        }
+        let url = format!("{}$metadata", self.endpoint);
+        let options = RequestOptions {
+            accept: Some("application/xml"),
+            ..Default::default()
+        };
+        let mut response = self.execute_with_retry(Method::GET, &url, options).await?;
+
+        // Read as bytes to handle large XML and encoding issues, reporting
+        // progress since F&O metadata can take a minute to download
+        let total = response.content_length();
+        let mut bytes = Vec::with_capacity(total.unwrap_or(0) as usize);
+        let mut reported = 0;
+        let report = |received: usize| {
+            report_progress(&match total {
+                Some(total) => format!("Downloaded {} of {} bytes of metadata", received, total),
+                None => format!("Downloaded {} bytes of metadata", received),
+            })
+        };
+        while let Some(chunk) = cancellable(response.chunk())
+            .await?
+            .map_err(|e| ODataError::ParseError(format!("Failed to read metadata bytes: {}", e)))?
+        {
+            bytes.extend_from_slice(&chunk);
+            if bytes.len() - reported >= METADATA_PROGRESS_INTERVAL {
+                reported = bytes.len();
+                report(reported);
+            }
+        }
+        if bytes.len() != reported {
+            report(bytes.len());
+        }
+        // Compressed responses are decoded on the fly, so this is the XML size
+        tracing::info!(bytes = bytes.len(), "Downloaded $metadata");
 
         // Convert bytes to string, handling potential encoding issues
         let xml = String::from_utf8_lossy(&bytes).to_string();
@@ -392,17 +1697,12 @@ impl ODataClient {
 
     /// Invalidate metadata cache, forcing next fetch to retrieve from server
     pub async fn invalidate_metadata_cache(&self) {
-        let mut cache = self.metadata_cache.write().await;
-        *cache = None;
-        tracing::debug!("Metadata cache invalidated");
+        self.metadata_cache.invalidate().await;
     }
 
     /// Get metadata cache status for diagnostics
     pub async fn metadata_cache_status(&self) -> Option<(usize, Duration)> {
-        let cache = self.metadata_cache.read().await;
-        cache
-            .as_ref()
-            .map(|c| (c.xml.len(), c.fetched_at.elapsed()))
+        self.metadata_cache.status(self.language()).await
     }
 
     /// Fetch entity data with paging support
@@ -418,23 +1718,62 @@ impl ODataClient {
         options: &QueryOptions,
     ) -> Result<ODataResponse, ODataError> {
         let url = match next_link {
-            Some(link) => link.to_string(),
+            // Server-supplied links are already encoded; use them verbatim
+            // (apart from the host, when rewriting)
+            Some(link) => self.next_link_url(link),
             None => {
                 let query = options.to_query_string(&self.product);
                 format!("{}{}{}", self.endpoint, entity, query)
             }
         };
+        self.fetch_page_at(&url, options).await
+    }
+
+    /// Fetch the first page of the records a navigation property leads to,
+    /// as `{entity}({key})/{nav_property}` rather than through `$expand`
+    ///
+    /// Unlike an expanded collection the result is not capped: it pages like
+    /// an entity set, and later pages come from [`Self::fetch_entity_page`]
+    /// with the response's `next_link`.
+    pub async fn fetch_navigation(
+        &self,
+        entity: &str,
+        key: &EntityKey,
+        nav_property: &str,
+        options: &QueryOptions,
+    ) -> Result<ODataResponse, ODataError> {
+        let url = format!(
+            "{}/{}{}",
+            self.entity_url(entity, key).await,
+            nav_property,
+            options.to_query_string(&self.product)
+        );
+        self.fetch_page_at(&url, options).await
+    }
 
+    async fn fetch_page_at(
+        &self,
+        url: &str,
+        options: &QueryOptions,
+    ) -> Result<ODataResponse, ODataError> {
         tracing::debug!("Fetching: {}", url);
 
-        let token = self.auth.get_token(&self.resource()).await?;
+        let request_options = RequestOptions {
+            max_page_size: options.max_page_size.or(self.page_size),
+            annotations: options.annotations,
+            ..Default::default()
+        };
         let response = self
-            .execute_with_retry(Method::GET, &url, &token, None)
+            .execute_with_retry(Method::GET, url, request_options)
             .await?;
 
-        let odata_response: ODataResponse = response.json().await.map_err(|e| {
+        let mut odata_response: ODataResponse = response.json().await.map_err(|e| {
             ODataError::ParseError(format!("Failed to parse OData response: {}", e))
         })?;
+        // Page tokens and callers see the link that will actually be requested
+        odata_response.next_link = odata_response
+            .next_link
+            .map(|link| self.next_link_url(&link));
 
         tracing::debug!(
             "Fetched {} records, next_link: {:?}",
@@ -445,6 +1784,31 @@ impl ODataClient {
         Ok(odata_response)
     }
 
+    /// Run a FetchXML query against an entity set (Dataverse only)
+    ///
+    /// Returns the raw response; FetchXML pages by its own `count`/`page`
+    /// attributes, and `@Microsoft.Dynamics.CRM.morerecords` says whether
+    /// another page exists.
+    pub async fn fetch_xml(&self, entity_set: &str, fetch_xml: &str) -> Result<Value, ODataError> {
+        if self.product != ProductType::Dataverse {
+            return Err(ODataError::ParseError(
+                "FetchXML queries are only available for Dataverse".to_string(),
+            ));
+        }
+        let url = format!(
+            "{}{}?fetchXml={}",
+            self.endpoint,
+            entity_set,
+            encode_query_value(fetch_xml)
+        );
+        let response = self
+            .execute_with_retry(Method::GET, &url, RequestOptions::default())
+            .await?;
+        response.json().await.map_err(|e| {
+            ODataError::ParseError(format!("Failed to parse FetchXML response: {}", e))
+        })
+    }
+
     /// Fetch all pages for an entity
     pub async fn fetch_all_pages(
         &self,
@@ -452,18 +1816,100 @@ impl ODataClient {
         options: &QueryOptions,
     ) -> Result<Vec<Value>, ODataError> {
         let mut all_records = Vec::new();
+
+        let summary = self
+            .fetch_pages_streaming(entity, options, None, |records| {
+                all_records.extend(records);
+                ControlFlow::Continue(())
+            })
+            .await?;
+        // Callers expect every record; use fetch_pages_streaming to keep a partial result
+        if summary.timed_out {
+            return Err(ODataError::Timeout(
+                Deadline::current_or(self.request_timeout).timeout_secs(),
+            ));
+        }
+
+        Ok(all_records)
+    }
+
+    /// Fetch pages one at a time, handing each page to `on_page` without buffering
+    ///
+    /// # Arguments
+    /// * `entity` - Entity set name
+    /// * `options` - Query options for the first page
+    /// * `max_records` - Stop after this many records; the last page is cut to fit
+    /// * `on_page` - Receives each page; return `ControlFlow::Break(())` to stop early
+    pub async fn fetch_pages_streaming<F>(
+        &self,
+        entity: &str,
+        options: &QueryOptions,
+        max_records: Option<usize>,
+        mut on_page: F,
+    ) -> Result<StreamSummary, ODataError>
+    where
+        F: FnMut(Vec<Value>) -> ControlFlow<()>,
+    {
+        let mut summary = StreamSummary::default();
         let mut next_link: Option<String> = None;
-        let mut page = 0;
+        let mut total = None;
 
         loop {
-            page += 1;
-            let response = self
+            // Stop between pages once the client cancels the call
+            check_cancelled()?;
+            let response = match self
                 .fetch_entity_page(entity, next_link.as_deref(), options)
-                .await?;
+                .await
+            {
+                // Keep what was fetched; the first page has nothing to keep
+                Err(ODataError::Timeout(secs)) if summary.pages > 0 => {
+                    tracing::warn!(
+                        "Timed out after {} seconds, stopping after {} records",
+                        secs,
+                        summary.records
+                    );
+                    summary.timed_out = true;
+                    break;
+                }
+                response => response?,
+            };
+
+            let mut records = response.value;
+            summary.pages += 1;
+            // Only the first page carries @odata.count
+            total = total.or(response.count.map(|count| count.max(0) as u64));
+
+            if let Some(max) = max_records {
+                let remaining = max.saturating_sub(summary.records);
+                if records.len() > remaining
+                    || (records.len() == remaining && response.next_link.is_some())
+                {
+                    records.truncate(remaining);
+                    summary.truncated = true;
+                }
+            }
+
+            summary.records += records.len();
+            tracing::info!(
+                "Page {}: fetched {} records ({} total)",
+                summary.pages,
+                records.len(),
+                summary.records
+            );
+            report_page_progress(summary.pages, summary.records, total);
 
-            tracing::info!("Page {}: fetched {} records", page, response.value.len());
+            if on_page(records).is_break() {
+                summary.aborted = true;
+                break;
+            }
 
-            all_records.extend(response.value);
+            if summary.truncated {
+                tracing::info!(
+                    "Record cap of {} reached, stopping",
+                    max_records.unwrap_or_default()
+                );
+                break;
+            }
 
             match response.next_link {
                 Some(link) => next_link = Some(link),
@@ -471,197 +1917,1068 @@ impl ODataClient {
             }
         }
 
-        tracing::info!("Total records fetched: {}", all_records.len());
-        Ok(all_records)
+        tracing::info!("Total records fetched: {}", summary.records);
+        Ok(summary)
+    }
+
+    /// Fetch all pages, prefetching up to `concurrency` pages in parallel
+    ///
+    /// Parallel prefetch needs `options.count` so the total is known up front;
+    /// the remaining pages are then requested with `$skip`/`$top` in parallel
+    /// and returned in order. Dataverse (which rejects `$skip`), unknown totals,
+    /// and servers that reject the skip requests fall back to sequential
+    /// `@odata.nextLink` following.
+    pub async fn fetch_all_pages_concurrent(
+        &self,
+        entity: &str,
+        options: &QueryOptions,
+        concurrency: usize,
+    ) -> Result<Vec<Value>, ODataError> {
+        let first = self.fetch_entity_page(entity, None, options).await?;
+        let page_size = first.value.len();
+        let mut records = first.value;
+        let total = first.count.map(|count| count.max(0) as u64);
+        report_page_progress(1, records.len(), total);
+
+        let next_link = match first.next_link {
+            Some(link) => link,
+            None => return Ok(records),
+        };
+
+        let parallel_total = match first.count {
+            Some(total)
+                if concurrency > 1
+                    && page_size > 0
+                    && options.count
+                    && self.product == ProductType::Finops =>
+            {
+                Some(total.max(0) as usize)
+            }
+            _ => None,
+        };
+
+        if let Some(total) = parallel_total {
+            let start = options.skip.unwrap_or(0);
+            let available = total.saturating_sub(start);
+            let wanted = options.top.map_or(available, |top| top.min(available));
+
+            match self
+                .fetch_skip_pages(entity, options, start, page_size, wanted, concurrency)
+                .await
+            {
+                Ok(pages) => {
+                    for page in pages {
+                        records.extend(page);
+                    }
+                    tracing::info!("Total records fetched: {}", records.len());
+                    return Ok(records);
+                }
+                Err(ODataError::ServerError(status, body)) if status == 400 || status == 501 => {
+                    tracing::warn!(
+                        "Skip-based paging rejected ({}), falling back to nextLink: {}",
+                        status,
+                        body
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut next_link = Some(next_link);
+        let mut pages = 1;
+        while let Some(link) = next_link {
+            let response = self.fetch_entity_page(entity, Some(&link), options).await?;
+            records.extend(response.value);
+            next_link = response.next_link;
+            pages += 1;
+            report_page_progress(pages, records.len(), total);
+        }
+
+        tracing::info!("Total records fetched: {}", records.len());
+        Ok(records)
+    }
+
+    /// Fetch the pages after the first one via `$skip`, bounded by a semaphore
+    async fn fetch_skip_pages(
+        &self,
+        entity: &str,
+        options: &QueryOptions,
+        start: usize,
+        page_size: usize,
+        wanted: usize,
+        concurrency: usize,
+    ) -> Result<Vec<Vec<Value>>, ODataError> {
+        let semaphore = Semaphore::new(concurrency);
+        // The first page is already in hand
+        let fetched = AtomicUsize::new(page_size);
+
+        let requests = (page_size..wanted).step_by(page_size).map(|offset| {
+            let page_options = QueryOptions {
+                skip: Some(start + offset),
+                top: Some(page_size.min(wanted - offset)),
+                count: false,
+                ..options.clone()
+            };
+            let semaphore = &semaphore;
+            let fetched = &fetched;
+            async move {
+                let _permit = semaphore
+                    .acquire()
+                    .await
+                    .map_err(|e| ODataError::ParseError(e.to_string()))?;
+                let response = self.fetch_entity_page(entity, None, &page_options).await?;
+                tracing::info!(
+                    "Prefetched page at $skip={}: {} records",
+                    start + offset,
+                    response.value.len()
+                );
+                let done = fetched.fetch_add(response.value.len(), Ordering::Relaxed)
+                    + response.value.len();
+                report_progress(&format!("Prefetched {} of {} records", done, wanted));
+                Ok::<_, ODataError>(response.value)
+            }
+        });
+
+        futures::future::try_join_all(requests).await
+    }
+
+    /// URL of a single record
+    ///
+    /// Composite key values are typed from the cached `$metadata` when it
+    /// has already been downloaded; a record lookup never waits for it.
+    async fn entity_url(&self, entity: &str, key: &EntityKey) -> String {
+        let model = match key {
+            EntityKey::Composite(_) => self
+                .cached_metadata()
+                .await
+                .map(|document| document.model()),
+            EntityKey::Single(_) => None,
+        };
+        let entity_type = model.as_ref().and_then(|model| model.entity_type(entity));
+        let expression = key.expression(&self.product, entity_type);
+        format!(
+            "{}{}({})",
+            self.endpoint,
+            entity,
+            utf8_percent_encode(&expression, KEY_SEGMENT_ENCODE_SET)
+        )
     }
 
     /// Get single entity by key
-    pub async fn get_entity(&self, entity: &str, key: &str) -> Result<Value, ODataError> {
-        let url = format!("{}{}({})", self.endpoint, entity, key);
-        let token = self.auth.get_token(&self.resource()).await?;
+    pub async fn get_entity(&self, entity: &str, key: &EntityKey) -> Result<Value, ODataError> {
+        let url = self.entity_url(entity, key).await;
         let response = self
-            .execute_with_retry(Method::GET, &url, &token, None)
+            .execute_with_retry(Method::GET, &url, RequestOptions::default())
             .await?;
 
-        let value: Value = response
+        // Some services only send the ETag as a header
+        let etag = response
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let mut value: Value = response
             .json()
             .await
             .map_err(|e| ODataError::ParseError(format!("Failed to parse entity: {}", e)))?;
 
+        if let (Some(etag), Value::Object(fields)) = (etag, &mut value) {
+            fields
+                .entry("@odata.etag")
+                .or_insert_with(|| Value::String(etag));
+        }
         Ok(value)
     }
 
-    /// Delete a single entity by key expression.
+    /// Download the contents of a file or image column
+    ///
+    /// Fetches `entity(key)/attribute/$value` in `Range` chunks, failing once
+    /// the file is known to exceed `max_bytes`. Returns `None` when the column
+    /// is empty; a missing record or column is `NotFound`.
+    pub async fn download_file(
+        &self,
+        entity: &str,
+        key: &EntityKey,
+        attribute: &str,
+        max_bytes: u64,
+    ) -> Result<Option<FileDownload>, ODataError> {
+        let url = format!(
+            "{}/{}/$value",
+            self.entity_url(entity, key).await,
+            attribute
+        );
+        let too_large = |size: u64| ODataError::TooLarge {
+            size,
+            limit: max_bytes,
+        };
+        let mut file_name = None;
+        let mut bytes = Vec::new();
+
+        loop {
+            let start = bytes.len() as u64;
+            let range = format!("bytes={}-{}", start, start + FILE_CHUNK_BYTES - 1);
+            let options = RequestOptions {
+                accept: Some("*/*"),
+                range: Some(&range),
+                ..Default::default()
+            };
+            let response = match self.execute_with_retry(Method::GET, &url, options).await {
+                // An empty file has no byte 0 to serve
+                Err(ODataError::ServerError(416, _)) if start == 0 => break,
+                result => result?,
+            };
+            if response.status() == StatusCode::NO_CONTENT {
+                break;
+            }
+
+            let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+            let header = |name: &str| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(String::from)
+            };
+            if file_name.is_none() {
+                file_name = header("x-ms-file-name").filter(|name| !name.is_empty());
+            }
+            // `Content-Range: bytes 0-4194303/10485760`, or the whole body's length
+            let total = if partial {
+                header("Content-Range")
+                    .and_then(|range| range.rsplit('/').next()?.trim().parse::<u64>().ok())
+            } else {
+                response.content_length()
+            };
+            if let Some(total) = total.filter(|total| *total > max_bytes) {
+                return Err(too_large(total));
+            }
+
+            // Read piece by piece, so a server ignoring `Range` and the
+            // limit is cut off instead of buffered whole
+            let mut response = response;
+            let mut received = 0;
+            while let Some(piece) = cancellable(response.chunk()).await?? {
+                received += piece.len();
+                bytes.extend_from_slice(&piece);
+                if bytes.len() as u64 > max_bytes {
+                    return Err(too_large(total.unwrap_or(bytes.len() as u64)));
+                }
+            }
+
+            let complete = match total {
+                Some(total) => bytes.len() as u64 >= total,
+                None => (received as u64) < FILE_CHUNK_BYTES,
+            };
+            if !partial || received == 0 || complete {
+                break;
+            }
+        }
+
+        Ok((!bytes.is_empty()).then_some(FileDownload { file_name, bytes }))
+    }
+
+    /// Upload `bytes` as the contents of a file or image column
+    ///
+    /// Files up to 4 MB go in one PATCH to `attribute/$value`. Larger ones
+    /// open a chunked session (`x-ms-transfer-mode: chunked`) and PATCH each
+    /// chunk with a `Content-Range`; every chunk is retried like any other
+    /// request. Dataverse answers 204 only once the whole file is committed,
+    /// so any other answer to the last chunk is an error.
+    #[tracing::instrument(skip_all, fields(entity = %entity, attribute = %attribute, size = bytes.len()))]
+    pub async fn upload_file(
+        &self,
+        entity: &str,
+        key: &EntityKey,
+        attribute: &str,
+        bytes: &[u8],
+        file_name: &str,
+    ) -> Result<(), ODataError> {
+        let record = self.entity_url(entity, key).await;
+        let octet_stream = ("Content-Type", "application/octet-stream");
+
+        if bytes.len() as u64 <= FILE_CHUNK_BYTES {
+            let url = format!("{}/{}/$value", record, attribute);
+            let options = RequestOptions {
+                raw_body: Some(bytes),
+                headers: &[("x-ms-file-name", file_name), octet_stream],
+                ..Default::default()
+            };
+            self.execute_with_retry(Method::PATCH, &url, options)
+                .await?;
+            return Ok(());
+        }
+
+        let url = format!(
+            "{}/{}?x-ms-file-name={}",
+            record,
+            attribute,
+            encode_query_value(file_name)
+        );
+        let options = RequestOptions {
+            headers: &[("x-ms-transfer-mode", "chunked")],
+            ..Default::default()
+        };
+        let session = self
+            .execute_with_retry(Method::PATCH, &url, options)
+            .await?;
+        let header = |name: &str| {
+            session
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        let location = header("Location").ok_or_else(|| {
+            ODataError::ParseError(
+                "Chunked upload was not started: no Location header in the response".to_string(),
+            )
+        })?;
+        let session_url = self.service_url(location)?;
+        let chunk_size = header("x-ms-chunk-size")
+            .and_then(|size| size.trim().parse::<usize>().ok())
+            .filter(|size| *size > 0)
+            .unwrap_or(FILE_CHUNK_BYTES as usize);
+
+        let mut last_status = None;
+        for (index, chunk) in bytes.chunks(chunk_size).enumerate() {
+            let start = index * chunk_size;
+            let content_range = format!(
+                "bytes {}-{}/{}",
+                start,
+                start + chunk.len() - 1,
+                bytes.len()
+            );
+            report_progress(&format!(
+                "Uploaded {} of {} bytes",
+                start + chunk.len(),
+                bytes.len()
+            ));
+            let options = RequestOptions {
+                raw_body: Some(chunk),
+                headers: &[
+                    ("Content-Range", &content_range),
+                    ("x-ms-file-name", file_name),
+                    octet_stream,
+                ],
+                ..Default::default()
+            };
+            let response = self
+                .execute_with_retry(Method::PATCH, session_url.as_str(), options)
+                .await?;
+            last_status = Some(response.status());
+        }
+
+        match last_status {
+            Some(StatusCode::NO_CONTENT | StatusCode::OK) => Ok(()),
+            status => Err(ODataError::ParseError(format!(
+                "Upload of '{}' was not committed: the last chunk returned {}",
+                file_name,
+                status.map_or_else(|| "nothing".to_string(), |s| s.to_string())
+            ))),
+        }
+    }
+
+    /// Attach a file to a record as a note (`annotation`) with a base64
+    /// `documentbody`; returns the new annotation's id when the server sends it
+    #[tracing::instrument(skip_all, fields(entity = %entity, file_name = %file_name))]
+    pub async fn create_annotation(
+        &self,
+        entity: &str,
+        key: &EntityKey,
+        file_name: &str,
+        mime_type: &str,
+        bytes: &[u8],
+        subject: Option<&str>,
+    ) -> Result<Option<String>, ODataError> {
+        // The lookup to the record is named after its entity type: objectid_account
+        let model = self.metadata_model().await?;
+        let entity_type = model
+            .entity_sets
+            .iter()
+            .find(|(set, _)| set.eq_ignore_ascii_case(entity))
+            .map(|(_, entity_type)| entity_type.clone())
+            .ok_or_else(|| {
+                ODataError::NotFound(format!("Entity set '{}' not found in $metadata", entity))
+            })?;
+
+        let payload = serde_json::json!({
+            "subject": subject.unwrap_or(file_name),
+            "filename": file_name,
+            "mimetype": mime_type,
+            "isdocument": true,
+            "documentbody": STANDARD.encode(bytes),
+            format!("objectid_{}@odata.bind", entity_type): self.entity_url(entity, key).await,
+        });
+        let url = format!("{}annotations", self.endpoint);
+        let options = RequestOptions {
+            body: Some(&payload),
+            ..Default::default()
+        };
+        let response = self.execute_with_retry(Method::POST, &url, options).await?;
+
+        // OData-EntityId: https://org.crm.dynamics.com/api/data/v9.2/annotations(<id>)
+        Ok(response
+            .headers()
+            .get("OData-EntityId")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|id| id.rsplit_once('(')?.1.strip_suffix(')'))
+            .map(String::from))
+    }
+
+    /// POST a JSON body to `url` (which may lie outside the service root) and
+    /// parse the JSON response
+    pub(crate) async fn post_json(&self, url: &str, body: &Value) -> Result<Value, ODataError> {
+        let options = RequestOptions {
+            body: Some(body),
+            ..Default::default()
+        };
+        let response = self.execute_with_retry(Method::POST, url, options).await?;
+        response
+            .json()
+            .await
+            .map_err(|e| ODataError::ParseError(format!("Failed to parse response: {}", e)))
+    }
+
+    /// POST a JSON body to `url` and return the response unread, for callers
+    /// that look at its status and headers
+    pub(crate) async fn post_for_response(
+        &self,
+        url: &str,
+        body: &Value,
+    ) -> Result<Response, ODataError> {
+        let options = RequestOptions {
+            body: Some(body),
+            ..Default::default()
+        };
+        self.execute_with_retry(Method::POST, url, options).await
+    }
+
+    /// GET `url` (which may lie outside the service root) and return the
+    /// response unread
+    pub(crate) async fn get_response(&self, url: &str) -> Result<Response, ODataError> {
+        self.execute_with_retry(Method::GET, url, RequestOptions::default())
+            .await
+    }
+
+    /// PUT `bytes` as an Azure Storage block blob at a SAS URL, such as the
+    /// one F&O's `GetAzureWriteUrl` returns; the SAS is the authorization, so
+    /// no bearer token is sent
+    pub(crate) async fn put_block_blob(
+        &self,
+        sas_url: &str,
+        bytes: &[u8],
+        content_type: &str,
+    ) -> Result<(), ODataError> {
+        let options = RequestOptions {
+            raw_body: Some(bytes),
+            headers: &[
+                ("x-ms-blob-type", "BlockBlob"),
+                ("Content-Type", content_type),
+            ],
+            anonymous: true,
+            ..Default::default()
+        };
+        self.execute_with_retry(Method::PUT, sas_url, options)
+            .await?;
+        Ok(())
+    }
+
+    /// Delete a single entity by key.
+    #[tracing::instrument(skip_all, fields(entity = %entity))]
     pub async fn delete_entity(
         &self,
         entity: &str,
-        key: &str,
+        key: &EntityKey,
         if_match: Option<&str>,
     ) -> Result<(), ODataError> {
-        let url = format!("{}{}({})", self.endpoint, entity, key);
-        let token = self.auth.get_token(&self.resource()).await?;
-        self.execute_with_retry(Method::DELETE, &url, &token, if_match.or(Some("*")))
+        let url = self.entity_url(entity, key).await;
+        let options = RequestOptions {
+            if_match: if_match.or(Some("*")),
+            ..Default::default()
+        };
+        self.execute_with_retry(Method::DELETE, &url, options)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Create the record with `key`, or update it if it exists (PATCH to the keyed URL)
+    ///
+    /// `prevent_update` sends `If-None-Match: *` so an existing record is left
+    /// alone; `prevent_create` sends `If-Match: *` so a missing one is not
+    /// created. A blocked precondition is reported as such.
+    #[tracing::instrument(skip_all, fields(entity = %entity))]
+    pub async fn upsert_entity(
+        &self,
+        entity: &str,
+        key: &EntityKey,
+        payload: &Value,
+        prevent_update: bool,
+        prevent_create: bool,
+    ) -> Result<UpsertOutcome, ODataError> {
+        if prevent_update && prevent_create {
+            return Err(ODataError::ParseError(
+                "prevent_update and prevent_create together would block every upsert".to_string(),
+            ));
+        }
+
+        let url = self.entity_url(entity, key).await;
+        let options = RequestOptions {
+            if_match: prevent_create.then_some("*"),
+            if_none_match: prevent_update.then_some("*"),
+            body: Some(payload),
+            ..Default::default()
+        };
+        match self.execute_with_retry(Method::PATCH, &url, options).await {
+            Ok(response) if response.status() == StatusCode::CREATED => Ok(UpsertOutcome::Created),
+            Ok(_) => Ok(UpsertOutcome::Updated),
+            Err(ODataError::ServerError(412, message)) if prevent_update => Err(ODataError::ServerError(
+                412,
+                format!(
+                    "A record with this key already exists and prevent_update (If-None-Match: *) blocked the update: {}",
+                    message
+                ),
+            )),
+            // Dataverse answers If-Match: * on a missing record with 404 rather than 412
+            Err(ODataError::ServerError(412, message) | ODataError::NotFound(message))
+                if prevent_create =>
+            {
+                Err(ODataError::ServerError(
+                    412,
+                    format!(
+                        "No record with this key exists and prevent_create (If-Match: *) blocked the create: {}",
+                        message
+                    ),
+                ))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// PATCH `payload` onto every record in `keys` in one `$batch` changeset
+    ///
+    /// The changeset is atomic: when it fails nothing was changed, and the
+    /// error names the record the service rejected if the response says which.
+    /// Records are only updated, never created.
+    #[tracing::instrument(skip_all, fields(entity = %entity, records = keys.len()))]
+    pub async fn update_changeset(
+        &self,
+        entity: &str,
+        keys: &[EntityKey],
+        payload: &Value,
+    ) -> Result<(), ChangesetError> {
+        let mut urls = Vec::with_capacity(keys.len());
+        for key in keys {
+            urls.push(self.entity_url(entity, key).await);
+        }
+        let (content_type, body) = patch_changeset(&new_client_request_id(), &urls, payload);
+        let url = format!("{}$batch", self.endpoint);
+        let options = RequestOptions {
+            accept: Some("multipart/mixed"),
+            raw_body: Some(body.as_bytes()),
+            headers: &[("Content-Type", &content_type)],
+            ..Default::default()
+        };
+
+        // A failed changeset comes back inside a 200, or as the batch's own error
+        let parts = match self.execute_with_retry(Method::POST, &url, options).await {
+            Ok(response) => parse_batch_response(&response.text().await.unwrap_or_default()),
+            Err(ODataError::ServerError(status, body)) => {
+                let parts = parse_batch_response(&body);
+                if parts.is_empty() {
+                    return Err(ODataError::ServerError(status, body).into());
+                }
+                parts
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match parts.into_iter().find(|part| part.status >= 400) {
+            None => Ok(()),
+            Some(part) => Err(ChangesetError {
+                failed: part
+                    .content_id
+                    .and_then(|id| id.checked_sub(1))
+                    .filter(|index| *index < keys.len()),
+                error: match part.status {
+                    404 => ODataError::NotFound(error_message(part.body)),
+                    status => ODataError::ServerError(status, error_message(part.body)),
+                },
+            }),
+        }
+    }
+
+    /// A navigation property of an entity set's type, from `$metadata`
+    async fn navigation(
+        &self,
+        entity: &str,
+        nav_property: &str,
+    ) -> Result<NavigationInfo, ODataError> {
+        let model = self.metadata_model().await?;
+        model
+            .entity_type(entity)
+            .and_then(|entity_type| {
+                entity_type
+                    .navigation
+                    .iter()
+                    .find(|navigation| navigation.name == nav_property)
+            })
+            .cloned()
+            .ok_or_else(|| {
+                ODataError::NotFound(format!(
+                    "Navigation property '{}' not found on '{}'",
+                    nav_property, entity
+                ))
+            })
+    }
+
+    /// Link a record to another through a navigation property
+    ///
+    /// A collection-valued property gets the target added (POST to `$ref`);
+    /// a single-valued one is set to it (PUT of `$ref`). The target goes in
+    /// `@odata.id` as an absolute URL.
+    #[tracing::instrument(skip_all, fields(entity = %entity, navigation = %nav_property))]
+    pub async fn associate(
+        &self,
+        entity: &str,
+        key: &EntityKey,
+        nav_property: &str,
+        target_entity: &str,
+        target_key: &EntityKey,
+    ) -> Result<(), ODataError> {
+        let navigation = self.navigation(entity, nav_property).await?;
+        let url = format!(
+            "{}/{}/$ref",
+            self.entity_url(entity, key).await,
+            nav_property
+        );
+        let body = serde_json::json!({
+            "@odata.id": self.entity_url(target_entity, target_key).await
+        });
+        let method = if navigation.collection {
+            Method::POST
+        } else {
+            Method::PUT
+        };
+        let options = RequestOptions {
+            body: Some(&body),
+            ..Default::default()
+        };
+        self.execute_with_retry(method, &url, options).await?;
+
+        Ok(())
+    }
+
+    /// Remove a link made by [`associate`](Self::associate)
+    ///
+    /// A collection-valued property needs the `target` to remove; a
+    /// single-valued one is cleared and `target` is ignored.
+    #[tracing::instrument(skip_all, fields(entity = %entity, navigation = %nav_property))]
+    pub async fn disassociate(
+        &self,
+        entity: &str,
+        key: &EntityKey,
+        nav_property: &str,
+        target: Option<(&str, &EntityKey)>,
+    ) -> Result<(), ODataError> {
+        let navigation = self.navigation(entity, nav_property).await?;
+        let mut url = format!(
+            "{}/{}/$ref",
+            self.entity_url(entity, key).await,
+            nav_property
+        );
+        if navigation.collection {
+            let Some((target_entity, target_key)) = target else {
+                return Err(ODataError::ParseError(format!(
+                    "'{}' is collection-valued: name the target record to unlink",
+                    nav_property
+                )));
+            };
+            let id = self.entity_url(target_entity, target_key).await;
+            url = format!("{}?$id={}", url, encode_query_value(&id));
+        }
+        self.execute_with_retry(Method::DELETE, &url, RequestOptions::default())
             .await?;
 
         Ok(())
     }
 
+    /// Fetch the service document at the endpoint root, listing the entity sets
+    pub async fn fetch_service_document(&self) -> Result<Value, ODataError> {
+        let response = self
+            .execute_with_retry(Method::GET, &self.endpoint, RequestOptions::default())
+            .await?;
+
+        response
+            .json()
+            .await
+            .map_err(|e| ODataError::ParseError(format!("Failed to parse service document: {}", e)))
+    }
+
+    /// Call an unbound OData function such as Dataverse `WhoAmI`
+    ///
+    /// Parameters are passed as aliases (`Name(p=@p)?@p=value`); values are
+    /// OData literals, so strings must already carry their single quotes.
+    pub async fn execute_function(
+        &self,
+        name: &str,
+        params: &[(&str, &str)],
+    ) -> Result<Value, ODataError> {
+        let aliases: Vec<String> = params
+            .iter()
+            .map(|(param, _)| format!("{}=@{}", param, param))
+            .collect();
+        let mut url = format!("{}{}({})", self.endpoint, name, aliases.join(","));
+        if !params.is_empty() {
+            let values: Vec<String> = params
+                .iter()
+                .map(|(param, value)| format!("@{}={}", param, encode_query_value(value)))
+                .collect();
+            url.push('?');
+            url.push_str(&values.join("&"));
+        }
+
+        let response = self
+            .execute_with_retry(Method::GET, &url, RequestOptions::default())
+            .await?;
+
+        response
+            .json()
+            .await
+            .map_err(|e| ODataError::ParseError(format!("Failed to parse {} result: {}", name, e)))
+    }
+
+    /// Call an unbound OData action such as Dataverse `RetrieveAuditDetails`;
+    /// parameters go in the JSON body
+    #[tracing::instrument(skip_all, fields(action = %name))]
+    pub async fn execute_action(&self, name: &str, params: &Value) -> Result<Value, ODataError> {
+        let url = format!("{}{}", self.endpoint, name);
+        self.post_json(&url, params).await
+    }
+
+    /// Bearer token the client sends, for inspecting its claims
+    pub async fn access_token(&self) -> Result<String, ODataError> {
+        Ok(self.auth.get_token(&self.resource()).await?)
+    }
+
     /// Get endpoint URL
     pub fn endpoint(&self) -> &str {
         &self.endpoint
     }
 
+    /// A `Location` the service returned, resolved against the endpoint.
+    /// The bearer token goes along to it, so other origins are refused.
+    pub(crate) fn service_url(&self, location: &str) -> Result<Url, ODataError> {
+        let endpoint = Url::parse(&self.endpoint)
+            .map_err(|e| ODataError::ParseError(format!("Invalid endpoint: {}", e)))?;
+        let url = endpoint.join(location).map_err(|e| {
+            ODataError::ParseError(format!("Invalid Location '{}': {}", location, e))
+        })?;
+        if !same_origin(&url, &endpoint) {
+            return Err(ODataError::ParseError(format!(
+                "Refusing to follow Location {} outside {}",
+                url, self.endpoint
+            )));
+        }
+        Ok(url)
+    }
+
     /// Get product type
     pub fn product(&self) -> &ProductType {
         &self.product
     }
 
-    /// Parse $metadata XML to extract entity information for a specific entity
+    /// Look up an entity set (or entity type) in the parsed $metadata
     /// Returns: (properties, navigation_properties, key_fields)
+    #[allow(clippy::type_complexity)]
     pub fn parse_entity_from_metadata(
-        metadata_xml: &str,
+        model: &MetadataModel,
         entity_name: &str,
     ) -> Result<(Vec<String>, Vec<String>, Vec<String>), ODataError> {
-        let mut properties = Vec::new();
-        let mut nav_properties = Vec::new();
-        let mut key_fields = Vec::new();
-        let mut in_entity = false;
-        let mut in_key = false;
-
-        // Simple XML parsing for entity properties
-        for line in metadata_xml.lines() {
-            let trimmed = line.trim();
-
-            // Look for EntityType definition
-            if trimmed.contains("<EntityType ")
-                && trimmed.contains(&format!("Name=\"{}\"", entity_name))
-            {
-                in_entity = true;
-            }
-            // Also check for EntityType that matches without exact name (for partial matches)
-            if !in_entity && trimmed.contains("<EntityType ") {
-                if let Some(start) = trimmed.find("Name=\"") {
-                    let name_start = start + 6;
-                    if let Some(end) = trimmed[name_start..].find('"') {
-                        let name = &trimmed[name_start..name_start + end];
-                        // Match entity name at start (e.g., "CustomersV3" matches "CustomersV3Type")
-                        if name.starts_with(entity_name) || entity_name.starts_with(name) {
-                            in_entity = true;
-                        }
-                    }
-                }
-            }
+        let entity = model.entity_type(entity_name).ok_or_else(|| {
+            ODataError::NotFound(format!("Entity '{}' not found in metadata", entity_name))
+        })?;
 
-            if in_entity {
-                // Parse Key fields
-                if trimmed.contains("<Key>") {
-                    in_key = true;
-                }
-                if trimmed.contains("</Key>") {
-                    in_key = false;
-                }
-                if in_key && trimmed.contains("<PropertyRef ") {
-                    if let Some(start) = trimmed.find("Name=\"") {
-                        let name_start = start + 6;
-                        if let Some(end) = trimmed[name_start..].find('"') {
-                            let name = &trimmed[name_start..name_start + end];
-                            key_fields.push(name.to_string());
-                        }
-                    }
+        let properties = entity
+            .properties
+            .iter()
+            .map(|property| match property.type_name.as_str() {
+                "" => property.name.clone(),
+                type_name => format!("{}: {}", property.name, type_name.replace("Edm.", "")),
+            })
+            .collect();
+        let nav_properties = entity
+            .navigation
+            .iter()
+            .map(|navigation| {
+                if navigation.collection {
+                    format!("{} -> [{}]", navigation.name, navigation.target_type)
+                } else {
+                    format!("{} -> {}", navigation.name, navigation.target_type)
                 }
+            })
+            .collect();
 
-                // Parse Property fields
-                if trimmed.starts_with("<Property ") && trimmed.contains("Name=\"") {
-                    if let Some(start) = trimmed.find("Name=\"") {
-                        let name_start = start + 6;
-                        if let Some(end) = trimmed[name_start..].find('"') {
-                            let name = &trimmed[name_start..name_start + end];
-                            // Get type if available
-                            let prop_type = if let Some(type_start) = trimmed.find("Type=\"") {
-                                let ts = type_start + 6;
-                                if let Some(te) = trimmed[ts..].find('"') {
-                                    Some(trimmed[ts..ts + te].to_string())
-                                } else {
-                                    None
-                                }
-                            } else {
-                                None
-                            };
-
-                            let prop_str = match prop_type {
-                                Some(t) => format!("{}: {}", name, t.replace("Edm.", "")),
-                                None => name.to_string(),
-                            };
-                            properties.push(prop_str);
-                        }
-                    }
-                }
+        Ok((properties, nav_properties, entity.keys.clone()))
+    }
+}
 
-                // Parse NavigationProperty fields (expandable)
-                if trimmed.starts_with("<NavigationProperty ") && trimmed.contains("Name=\"") {
-                    if let Some(start) = trimmed.find("Name=\"") {
-                        let name_start = start + 6;
-                        if let Some(end) = trimmed[name_start..].find('"') {
-                            let name = &trimmed[name_start..name_start + end];
-                            // Get type/target if available
-                            let nav_type = if let Some(type_start) = trimmed.find("Type=\"") {
-                                let ts = type_start + 6;
-                                if let Some(te) = trimmed[ts..].find('"') {
-                                    Some(trimmed[ts..ts + te].to_string())
-                                } else {
-                                    None
-                                }
-                            } else {
-                                None
-                            };
-
-                            let nav_str = match nav_type {
-                                Some(t) => {
-                                    // Clean up type string
-                                    let clean_type = t
-                                        .replace("Collection(", "")
-                                        .replace(")", "")
-                                        .split('.')
-                                        .last()
-                                        .unwrap_or(&t)
-                                        .to_string();
-                                    if t.contains("Collection") {
-                                        format!("{} -> [{}]", name, clean_type)
-                                    } else {
-                                        format!("{} -> {}", name, clean_type)
-                                    }
-                                }
-                                None => name.to_string(),
-                            };
-                            nav_properties.push(nav_str);
-                        }
-                    }
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                // End of EntityType
-                if trimmed == "</EntityType>" {
-                    if !properties.is_empty() || !nav_properties.is_empty() {
-                        break; // Found the entity, stop parsing
-                    }
-                    in_entity = false;
-                }
+    const GUID: &str = "5f2a1c3e-8b7d-4e6f-9a0b-1c2d3e4f5a6b";
+
+    #[test]
+    fn next_links_are_rebased_onto_the_endpoint_host() {
+        let private = "https://contoso-private.internal:8443/data/";
+        assert_eq!(
+            rebase_link(
+                "https://contoso.operations.dynamics.com/data/CustomersV3?$skiptoken=abc",
+                private
+            )
+            .unwrap(),
+            "https://contoso-private.internal:8443/data/CustomersV3?$skiptoken=abc"
+        );
+        // An explicit port on the link is replaced; none on the endpoint means its default
+        assert_eq!(
+            rebase_link(
+                "https://contoso.operations.dynamics.com:443/data/CustomersV3?cross-company=true&$skiptoken=%27US-001%27%2C5637144576",
+                "http://10.0.0.4/data/"
+            )
+            .unwrap(),
+            "http://10.0.0.4/data/CustomersV3?cross-company=true&$skiptoken=%27US-001%27%2C5637144576"
+        );
+        assert_eq!(rebase_link("CustomersV3?$skiptoken=abc", private), None);
+    }
+
+    #[test]
+    fn dataverse_guid_keys_are_bare() {
+        let dataverse = ProductType::Dataverse;
+        assert_eq!(format_entity_key(&dataverse, GUID, &[]).unwrap(), GUID);
+        assert_eq!(
+            format_entity_key(&dataverse, &format!("{{{}}}", GUID), &[]).unwrap(),
+            GUID
+        );
+        assert_eq!(
+            format_entity_key(&dataverse, &GUID.to_uppercase(), &[]).unwrap(),
+            GUID.to_uppercase()
+        );
+        // Not quite a GUID, so it is a string
+        assert_eq!(
+            format_entity_key(&dataverse, "5f2a1c3e-8b7d", &[]).unwrap(),
+            "'5f2a1c3e-8b7d'"
+        );
+    }
+
+    #[test]
+    fn finops_keys_are_quoted_unless_numeric() {
+        let finops = ProductType::Finops;
+        assert_eq!(
+            format_entity_key(&finops, "CUS-001", &[]).unwrap(),
+            "'CUS-001'"
+        );
+        assert_eq!(
+            format_entity_key(&finops, GUID, &[]).unwrap(),
+            format!("'{}'", GUID)
+        );
+        assert_eq!(
+            format_entity_key(&finops, "5637144576", &[]).unwrap(),
+            "5637144576"
+        );
+        assert_eq!(
+            format_entity_key(&finops, "O'Brien", &[]).unwrap(),
+            "'O''Brien'"
+        );
+        assert_eq!(
+            format_entity_key(&finops, "ACME (EU)", &[]).unwrap(),
+            "'ACME (EU)'"
+        );
+        assert_eq!(
+            format_entity_key(&finops, "(ACME (EU))", &["CustomerAccount"]).unwrap(),
+            "CustomerAccount='ACME (EU)'"
+        );
+    }
+
+    #[test]
+    fn key_expressions_pass_through() {
+        let finops = ProductType::Finops;
+        assert_eq!(
+            format_entity_key(&finops, "dataAreaId='usmf',SalesOrderNumber='SO-001'", &[]).unwrap(),
+            "dataAreaId='usmf',SalesOrderNumber='SO-001'"
+        );
+        assert_eq!(
+            format_entity_key(&finops, "('CUS-001')", &[]).unwrap(),
+            "'CUS-001'"
+        );
+    }
+
+    #[test]
+    fn composite_keys_are_typed_by_json_value() {
+        let key = EntityKey::Composite(vec![
+            ("dataAreaId".to_string(), Value::from("usmf")),
+            ("RecId".to_string(), Value::from(5637144576_i64)),
+            ("ParentId".to_string(), Value::from(GUID)),
+            ("ValidFrom".to_string(), Value::from("2024-08-21T07:28:00Z")),
+            ("IsActive".to_string(), Value::from(true)),
+            ("Name".to_string(), Value::from("O'Brien & Co")),
+        ]);
+
+        assert_eq!(
+            key.expression(&ProductType::Dataverse, None),
+            format!(
+                "dataAreaId='usmf',RecId=5637144576,ParentId={},\
+                 ValidFrom=2024-08-21T07:28:00Z,IsActive=true,Name='O''Brien & Co'",
+                GUID
+            )
+        );
+        assert!(key
+            .expression(&ProductType::Finops, None)
+            .contains(&format!("ParentId='{}'", GUID)));
+    }
+
+    #[test]
+    fn composite_keys_follow_declared_types() {
+        use crate::odata::metadata::PropertyInfo;
+
+        let property = |name: &str, type_name: &str| PropertyInfo {
+            name: name.to_string(),
+            type_name: type_name.to_string(),
+        };
+        let entity_type = EntityTypeInfo {
+            name: "SalesOrderHeaderV2".to_string(),
+            properties: vec![
+                property("SalesOrderNumber", "Edm.String"),
+                property(
+                    "SalesOrderStatus",
+                    "Microsoft.Dynamics.DataEntities.SalesStatus",
+                ),
+                property("RequestedShippingDate", "Edm.Date"),
+            ],
+            ..Default::default()
+        };
+        let key = EntityKey::Composite(vec![
+            ("SalesOrderNumber".to_string(), Value::from(123)),
+            ("SalesOrderStatus".to_string(), Value::from("Invoiced")),
+            (
+                "RequestedShippingDate".to_string(),
+                Value::from("2024-08-21"),
+            ),
+        ]);
+
+        assert_eq!(
+            key.expression(&ProductType::Finops, Some(&entity_type)),
+            "SalesOrderNumber='123',\
+             SalesOrderStatus=Microsoft.Dynamics.DataEntities.SalesStatus'Invoiced',\
+             RequestedShippingDate=2024-08-21"
+        );
+        // Without metadata a date-only string cannot be told from a code
+        assert!(key
+            .expression(&ProductType::Finops, None)
+            .ends_with("RequestedShippingDate='2024-08-21'"));
+    }
+
+    #[test]
+    fn named_key_fields_pair_with_values() {
+        let dataverse = ProductType::Dataverse;
+        assert_eq!(
+            format_entity_key(&dataverse, "a@b.com", &["emailaddress1"]).unwrap(),
+            "emailaddress1='a@b.com'"
+        );
+        // A single named value may itself contain commas
+        assert_eq!(
+            format_entity_key(&dataverse, "Contoso, Ltd", &["name"]).unwrap(),
+            "name='Contoso, Ltd'"
+        );
+
+        let finops = ProductType::Finops;
+        assert_eq!(
+            format_entity_key(&finops, "usmf, SO-001", &["dataAreaId", "SalesOrderNumber"])
+                .unwrap(),
+            "dataAreaId='usmf',SalesOrderNumber='SO-001'"
+        );
+        assert!(format_entity_key(&finops, "usmf", &["dataAreaId", "SalesOrderNumber"]).is_err());
+    }
+
+    #[test]
+    fn context_urls_name_the_entity_set_and_select_list() {
+        let parse = |context: &str| parse_context_url(context).unwrap();
+
+        let finops = parse("https://contoso.operations.dynamics.com/data/$metadata#CustomersV3(dataAreaId,CustomerAccount)");
+        assert_eq!(
+            finops,
+            ContextUrl {
+                entity_set: "CustomersV3".to_string(),
+                select: vec!["dataAreaId".to_string(), "CustomerAccount".to_string()],
+                single_entity: false,
             }
-        }
+        );
 
-        if properties.is_empty() && nav_properties.is_empty() {
-            return Err(ODataError::NotFound(format!(
-                "Entity '{}' not found in metadata",
-                entity_name
-            )));
-        }
+        let record = parse("https://org.crm.dynamics.com/api/data/v9.2/$metadata#accounts(name,primarycontactid(fullname,emailaddress1))/$entity");
+        assert_eq!(record.entity_set, "accounts");
+        assert_eq!(
+            record.select,
+            ["name", "primarycontactid(fullname,emailaddress1)"]
+        );
+        assert!(record.single_entity);
+
+        let whole = parse("https://org.crm.dynamics.com/api/data/v9.2/$metadata#accounts/$entity");
+        assert_eq!(
+            (
+                whole.entity_set.as_str(),
+                whole.select.len(),
+                whole.single_entity
+            ),
+            ("accounts", 0, true)
+        );
 
-        Ok((properties, nav_properties, key_fields))
+        // A key inside a path is not a select list
+        let related = parse(&format!(
+            "https://org.crm.dynamics.com/api/data/v9.2/$metadata#accounts({})/contact_customer_accounts",
+            GUID
+        ));
+        assert_eq!(
+            related.entity_set,
+            format!("accounts({})/contact_customer_accounts", GUID)
+        );
+        assert!(related.select.is_empty());
+        assert_eq!(related.last_segment(), "contact_customer_accounts");
+
+        let strings = parse("https://host/data/$metadata#Collection(Edm.String)");
+        assert_eq!(strings.entity_set, "Edm.String");
+
+        assert_eq!(parse_context_url("https://host/data/$metadata"), None);
+        assert_eq!(
+            short_type_name("#Microsoft.Dynamics.CRM.contact"),
+            "contact"
+        );
+        assert_eq!(short_type_name("CustomerV3"), "CustomerV3");
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn count_limit_annotation_is_read_and_other_annotations_kept() {
+        let dataverse: ODataResponse =
+            serde_json::from_str(include_str!("testdata/dataverse-count-limit-exceeded.json"))
+                .unwrap();
+        assert_eq!(dataverse.count, Some(5000));
+        assert!(dataverse.count_limit_exceeded);
+        assert_eq!(dataverse.value.len(), 1);
+        assert!(dataverse.next_link.is_some());
+        let mut kept: Vec<&String> = dataverse.annotations.keys().collect();
+        kept.sort();
+        assert_eq!(
+            kept,
+            [
+                "@Microsoft.Dynamics.CRM.globalmetadataversion",
+                "@Microsoft.Dynamics.CRM.totalrecordcount"
+            ]
+        );
+
+        // F&O counts every match, with no limit to report
+        let finops: ODataResponse =
+            serde_json::from_str(include_str!("testdata/finops-count.json")).unwrap();
+        assert_eq!(finops.count, Some(12873));
+        assert!(!finops.count_limit_exceeded);
+        assert!(finops.annotations.is_empty());
+    }
 
     #[test]
     fn test_query_options_empty() {
@@ -674,25 +2991,73 @@ mod tests {
         let options = QueryOptions {
             select: Some(vec!["name".to_string(), "email".to_string()]),
             filter: Some("status eq 'active'".to_string()),
+            filter_expr: None,
             top: Some(10),
             skip: None,
             orderby: Some("name asc".to_string()),
             expand: None,
             cross_company: false,
             count: false,
+            max_page_size: None,
+            search: Some("contoso & co".to_string()),
+            format: Some("json".to_string()),
+            skiptoken: Some("Id=5".to_string()),
+            annotations: None,
+            apply: Some("groupby((statecode))".to_string()),
         };
 
         let query = options.to_query_string(&ProductType::Dataverse);
         assert!(query.contains("$select=name,email"));
-        assert!(query.contains("$filter=status eq 'active'"));
+        assert!(query.contains("$filter=status%20eq%20'active'"));
         assert!(query.contains("$top=10"));
-        assert!(query.contains("$orderby=name asc"));
+        assert!(query.contains("$orderby=name%20asc"));
+        assert!(query.contains("$search=contoso%20%26%20co"));
+        assert!(query.contains("$format=json"));
+        assert!(query.contains("$skiptoken=Id%3D5"));
+        assert!(query.contains("$apply=groupby((statecode))"));
     }
 
     #[test]
-    fn test_cross_company_finops_only() {
-        let options = QueryOptions {
-            cross_company: true,
+    fn test_query_options_builder_matches_struct_literal() {
+        let built = QueryOptions::builder()
+            .select(["name"])
+            .filter("status eq 'active'")
+            .top(10)
+            .skip(20)
+            .orderby("name asc")
+            .expand(["primarycontactid"])
+            .count(true)
+            .search("contoso")
+            .skiptoken("Id=5")
+            .annotations(AnnotationMode::FormattedOnly)
+            .apply("groupby((statecode))")
+            .build();
+        let literal = QueryOptions {
+            select: Some(vec!["name".to_string()]),
+            filter: Some("status eq 'active'".to_string()),
+            top: Some(10),
+            skip: Some(20),
+            orderby: Some("name asc".to_string()),
+            expand: Some(vec!["primarycontactid".to_string()]),
+            count: true,
+            search: Some("contoso".to_string()),
+            skiptoken: Some("Id=5".to_string()),
+            annotations: Some(AnnotationMode::FormattedOnly),
+            apply: Some("groupby((statecode))".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            built.to_query_string(&ProductType::Dataverse),
+            literal.to_query_string(&ProductType::Dataverse)
+        );
+        // Annotations are asked for in a header, not in the query string
+        assert_eq!(built.annotations, literal.annotations);
+    }
+
+    #[test]
+    fn test_cross_company_finops_only() {
+        let options = QueryOptions {
+            cross_company: true,
             ..Default::default()
         };
 
@@ -704,4 +3069,1661 @@ mod tests {
         let query = options.to_query_string(&ProductType::Dataverse);
         assert!(!query.contains("cross-company"));
     }
+
+    #[test]
+    fn test_filter_expr_takes_precedence_over_raw_filter() {
+        use crate::odata::filter::FilterBuilder;
+
+        let options = QueryOptions {
+            filter: Some("ignored eq 1".to_string()),
+            filter_expr: Some(FilterBuilder::eq("Name", "O'Brien")),
+            ..Default::default()
+        };
+
+        let query = options.to_query_string(&ProductType::Dataverse);
+        assert_eq!(query, "?$filter=Name%20eq%20'O''Brien'");
+    }
+
+    #[test]
+    fn test_prefer_header_merges_preferences() {
+        assert_eq!(
+            prefer_header(None, AnnotationMode::All).as_deref(),
+            Some("odata.include-annotations=*")
+        );
+        assert_eq!(
+            prefer_header(Some(500), AnnotationMode::All).as_deref(),
+            Some("odata.include-annotations=*,odata.maxpagesize=500")
+        );
+        assert_eq!(
+            prefer_header(Some(50), AnnotationMode::FormattedOnly).as_deref(),
+            Some("odata.include-annotations=\"OData.Community.Display.V1.FormattedValue\",odata.maxpagesize=50")
+        );
+        assert_eq!(prefer_header(None, AnnotationMode::None), None);
+    }
+
+    #[test]
+    fn test_retry_after_delta_seconds() {
+        let now = SystemTime::now();
+        assert_eq!(parse_retry_after("30", now), Some(Duration::from_secs(30)));
+        assert_eq!(parse_retry_after(" 0 ", now), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_retry_after_http_date() {
+        let now = httpdate::parse_http_date("Wed, 21 Aug 2024 07:27:30 GMT").unwrap();
+
+        assert_eq!(
+            parse_retry_after("Wed, 21 Aug 2024 07:28:00 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        // Dates in the past mean retry immediately
+        assert_eq!(
+            parse_retry_after("Wed, 21 Aug 2024 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn test_retry_wait_is_clamped() {
+        let now = httpdate::parse_http_date("Wed, 21 Aug 2024 07:00:00 GMT").unwrap();
+        let max_wait = Duration::from_secs(DEFAULT_MAX_RETRY_WAIT_SECS);
+
+        let far_future = parse_retry_after("Wed, 21 Aug 2024 09:00:00 GMT", now).unwrap();
+        assert_eq!(far_future.min(max_wait), max_wait);
+
+        let huge_delta = parse_retry_after("86400", now).unwrap();
+        assert_eq!(huge_delta.min(max_wait), max_wait);
+    }
+
+    #[test]
+    fn test_backoff_jitter_bounds() {
+        for _ in 0..100 {
+            let wait = backoff_with_jitter(1000);
+            assert!(wait >= Duration::from_millis(1000));
+            assert!(wait <= Duration::from_millis(1500));
+        }
+    }
+
+    fn decoded_param(query: &str, name: &str) -> String {
+        let raw = query
+            .trim_start_matches('?')
+            .split('&')
+            .find_map(|p| p.strip_prefix(&format!("{}=", name)))
+            .unwrap();
+        percent_encoding::percent_decode_str(raw)
+            .decode_utf8()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_filter_with_ampersand_round_trips() {
+        let filter = "contains(Name,'A&B GmbH')";
+        let options = QueryOptions {
+            filter: Some(filter.to_string()),
+            top: Some(5),
+            ..Default::default()
+        };
+
+        let query = options.to_query_string(&ProductType::Finops);
+        assert!(query.contains("$filter=contains(Name,'A%26B%20GmbH')"));
+        assert!(query.ends_with("&$top=5"));
+        assert_eq!(decoded_param(&query, "$filter"), filter);
+    }
+
+    #[test]
+    fn test_non_ascii_filter_round_trips() {
+        let filter = "Name eq '株式会社トヨタ' or Name eq 'Müller #1+2'";
+        let options = QueryOptions {
+            filter: Some(filter.to_string()),
+            orderby: Some("Name desc".to_string()),
+            ..Default::default()
+        };
+
+        let query = options.to_query_string(&ProductType::Dataverse);
+        assert!(query.is_ascii());
+        assert!(!query.contains('#'));
+        assert!(!query.contains('+'));
+        assert_eq!(decoded_param(&query, "$filter"), filter);
+        assert_eq!(decoded_param(&query, "$orderby"), "Name desc");
+    }
+
+    mod mock {
+        use super::*;
+        use crate::auth::{AuthConfig, AuthType, Credential, StaticTokenProvider};
+        use serde_json::json;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Instant;
+        use wiremock::matchers::{
+            header, header_regex, method, path, query_param, query_param_is_missing,
+        };
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        /// Client against a mock server with a fixed token (no token endpoint)
+        fn mock_client(server: &MockServer) -> ODataClient {
+            ODataClient::new(
+                Arc::new(StaticTokenProvider::new("test-token")),
+                format!("{}/data/", server.uri()),
+                ProductType::Finops,
+                3,
+                10,
+                false,
+            )
+        }
+
+        /// Client using ADFS-style auth; the test mounts the token endpoint
+        fn adfs_client(server: &MockServer) -> ODataClient {
+            let auth = Arc::new(AzureAdAuth::new(AuthConfig {
+                auth_type: AuthType::Adfs,
+                tenant_id: "adfs".to_string(),
+                client_id: "client-id".to_string(),
+                credential: Credential::Secret("secret".to_string()),
+                token_url: Some(format!("{}/token", server.uri())),
+                resource: Some(server.uri()),
+                authority_host: None,
+                insecure_ssl: false,
+            }));
+
+            ODataClient::new(
+                auth,
+                format!("{}/data/", server.uri()),
+                ProductType::Finops,
+                3,
+                10,
+                false,
+            )
+        }
+
+        /// Mount three pages of two records each, linked via @odata.nextLink
+        async fn mount_pages(server: &MockServer, third_page_calls: u64) {
+            let page = |n: u64, next: Option<u64>| {
+                let mut body = json!({
+                    "value": [{"Id": n * 2 - 1}, {"Id": n * 2}]
+                });
+                if let Some(next) = next {
+                    body["@odata.nextLink"] =
+                        json!(format!("{}/data/Customers?page={}", server.uri(), next));
+                }
+                ResponseTemplate::new(200).set_body_json(body)
+            };
+
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .and(query_param_is_missing("page"))
+                .respond_with(page(1, Some(2)))
+                .mount(server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .and(query_param("page", "2"))
+                .respond_with(page(2, Some(3)))
+                .mount(server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .and(query_param("page", "3"))
+                .respond_with(page(3, None))
+                .expect(third_page_calls)
+                .mount(server)
+                .await;
+        }
+
+        #[tokio::test]
+        async fn cancellation_stops_paging_before_the_next_page() {
+            use crate::odata::with_cancellation;
+            use tokio_util::sync::CancellationToken;
+
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .and(query_param_is_missing("page"))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .set_body_json(json!({
+                            "value": [{"Id": 1}],
+                            "@odata.nextLink": format!("{}/data/Customers?page=2", server.uri())
+                        }))
+                        .set_delay(Duration::from_millis(300)),
+                )
+                .expect(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .and(query_param("page", "2"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+                .expect(0)
+                .mount(&server)
+                .await;
+
+            let client = mock_client(&server);
+            let token = CancellationToken::new();
+            let canceller = token.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                canceller.cancel();
+            });
+
+            let started = Instant::now();
+            let result = with_cancellation(
+                token,
+                client.fetch_all_pages("Customers", &QueryOptions::default()),
+            )
+            .await;
+
+            assert!(matches!(result, Err(ODataError::Cancelled)), "{result:?}");
+            assert!(started.elapsed() < Duration::from_millis(300));
+            // Let the slow first response finish so a follow-up request would be seen
+            tokio::time::sleep(Duration::from_millis(400)).await;
+        }
+
+        #[tokio::test]
+        async fn functions_pass_parameters_as_aliases() {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/data/WhoAmI()"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({"UserId": "u-1"})))
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/RetrieveVersion(Name=@Name)"))
+                .and(query_param("@Name", "'a b'"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({"Version": "9"})))
+                .mount(&server)
+                .await;
+
+            let client = mock_client(&server);
+            let whoami = client.execute_function("WhoAmI", &[]).await.unwrap();
+            assert_eq!(whoami["UserId"], "u-1");
+            let version = client
+                .execute_function("RetrieveVersion", &[("Name", "'a b'")])
+                .await
+                .unwrap();
+            assert_eq!(version["Version"], "9");
+        }
+
+        #[tokio::test]
+        async fn paging_reports_records_fetched_so_far() {
+            use crate::odata::progress::tests::RecordingReporter;
+            use crate::odata::with_progress;
+
+            let server = MockServer::start().await;
+            mount_pages(&server, 1).await;
+            let client = mock_client(&server);
+
+            let reporter = Arc::new(RecordingReporter::default());
+            with_progress(
+                reporter.clone(),
+                client.fetch_all_pages("Customers", &QueryOptions::default()),
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(
+                *reporter.updates.lock().unwrap(),
+                vec![
+                    "Fetched 1 page(s), 2 records",
+                    "Fetched 2 page(s), 4 records",
+                    "Fetched 3 page(s), 6 records",
+                ]
+            );
+        }
+
+        #[tokio::test]
+        async fn metadata_download_reports_bytes_received() {
+            use crate::odata::progress::tests::RecordingReporter;
+            use crate::odata::with_progress;
+
+            let server = MockServer::start().await;
+            let xml = format!(
+                "<edmx>{}</edmx>",
+                "x".repeat(METADATA_PROGRESS_INTERVAL * 2)
+            );
+            Mock::given(method("GET"))
+                .and(path("/data/$metadata"))
+                .respond_with(ResponseTemplate::new(200).set_body_string(xml.clone()))
+                .mount(&server)
+                .await;
+            let client = mock_client(&server);
+
+            let reporter = Arc::new(RecordingReporter::default());
+            let metadata = with_progress(reporter.clone(), client.fetch_metadata())
+                .await
+                .unwrap();
+            assert_eq!(metadata.xml(), xml);
+
+            let updates = reporter.updates.lock().unwrap();
+            let size = xml.len();
+            assert!(updates.len() >= 2, "{updates:?}");
+            assert_eq!(
+                updates.last().unwrap(),
+                &format!("Downloaded {} of {} bytes of metadata", size, size)
+            );
+        }
+
+        #[tokio::test]
+        async fn fetch_all_pages_follows_next_links() {
+            let server = MockServer::start().await;
+            mount_pages(&server, 1).await;
+            let client = mock_client(&server);
+
+            let records = client
+                .fetch_all_pages("Customers", &QueryOptions::default())
+                .await
+                .unwrap();
+
+            let ids: Vec<i64> = records.iter().map(|r| r["Id"].as_i64().unwrap()).collect();
+            assert_eq!(ids, vec![1, 2, 3, 4, 5, 6]);
+        }
+
+        #[tokio::test]
+        async fn next_links_on_the_public_host_are_followed_on_the_endpoint() {
+            let server = MockServer::start().await;
+            let public =
+                "https://contoso.operations.dynamics.com/data/Customers?$skiptoken=%27US-001%27";
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .and(query_param_is_missing("$skiptoken"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "value": [{"Id": 1}],
+                    "@odata.nextLink": public
+                })))
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .and(query_param("$skiptoken", "'US-001'"))
+                .respond_with(
+                    ResponseTemplate::new(200).set_body_json(json!({"value": [{"Id": 2}]})),
+                )
+                .mount(&server)
+                .await;
+
+            let client = mock_client(&server).with_next_link_rewrite(true);
+            let first = client
+                .fetch_entity_page("Customers", None, &QueryOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(
+                first.next_link.as_deref(),
+                Some(format!("{}/data/Customers?$skiptoken=%27US-001%27", server.uri()).as_str())
+            );
+            // Links from elsewhere are rebased too
+            let second = client
+                .fetch_entity_page("Customers", Some(public), &QueryOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(second.value, vec![json!({"Id": 2})]);
+
+            // Off by default: the link is kept as the service sent it
+            let first = mock_client(&server)
+                .fetch_entity_page("Customers", None, &QueryOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(first.next_link.as_deref(), Some(public));
+        }
+
+        #[tokio::test]
+        async fn streaming_stops_at_record_cap() {
+            let server = MockServer::start().await;
+            mount_pages(&server, 0).await;
+            let client = mock_client(&server);
+
+            let mut pages = Vec::new();
+            let summary = client
+                .fetch_pages_streaming("Customers", &QueryOptions::default(), Some(3), |page| {
+                    pages.push(page.len());
+                    ControlFlow::Continue(())
+                })
+                .await
+                .unwrap();
+
+            assert_eq!(pages, vec![2, 1]);
+            assert_eq!(summary.records, 3);
+            assert_eq!(summary.pages, 2);
+            assert!(summary.truncated);
+            assert!(!summary.aborted);
+        }
+
+        #[tokio::test]
+        async fn streaming_callback_can_abort() {
+            let server = MockServer::start().await;
+            mount_pages(&server, 0).await;
+            let client = mock_client(&server);
+
+            let summary = client
+                .fetch_pages_streaming("Customers", &QueryOptions::default(), None, |_| {
+                    ControlFlow::Break(())
+                })
+                .await
+                .unwrap();
+
+            assert_eq!(summary.pages, 1);
+            assert_eq!(summary.records, 2);
+            assert!(summary.aborted);
+            assert!(!summary.truncated);
+        }
+
+        /// Responds to `$skip` page requests after a delay, recording arrival times
+        struct SkipPageResponder {
+            arrivals: Arc<std::sync::Mutex<Vec<Instant>>>,
+        }
+
+        impl wiremock::Respond for SkipPageResponder {
+            fn respond(&self, request: &wiremock::Request) -> ResponseTemplate {
+                self.arrivals.lock().unwrap().push(Instant::now());
+
+                let param = |name: &str| {
+                    request
+                        .url
+                        .query_pairs()
+                        .find(|(k, _)| k == name)
+                        .and_then(|(_, v)| v.parse::<i64>().ok())
+                        .unwrap()
+                };
+                let (skip, top) = (param("$skip"), param("$top"));
+                let value: Vec<Value> = (skip..skip + top).map(|id| json!({"Id": id})).collect();
+
+                // Earlier pages answer slower, so completion order differs from request order
+                let delay = 400 - (skip as u64 * 20);
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "value": value }))
+                    .set_delay(Duration::from_millis(delay))
+            }
+        }
+
+        #[tokio::test]
+        async fn concurrent_prefetch_preserves_order_and_bounds_parallelism() {
+            let server = MockServer::start().await;
+            let client = mock_client(&server);
+            let arrivals = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .and(query_param_is_missing("$skip"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "@odata.count": 11,
+                    "@odata.nextLink": format!("{}/data/Customers?page=2", server.uri()),
+                    "value": [{"Id": 0}, {"Id": 1}]
+                })))
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .and(query_param("$count", "true"))
+                .and(query_param("$skip", "2"))
+                .respond_with(ResponseTemplate::new(500))
+                .expect(0)
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .and(query_param_is_missing("$count"))
+                .respond_with(SkipPageResponder {
+                    arrivals: arrivals.clone(),
+                })
+                .expect(5)
+                .mount(&server)
+                .await;
+
+            let options = QueryOptions {
+                count: true,
+                ..Default::default()
+            };
+            let records = client
+                .fetch_all_pages_concurrent("Customers", &options, 2)
+                .await
+                .unwrap();
+
+            let ids: Vec<i64> = records.iter().map(|r| r["Id"].as_i64().unwrap()).collect();
+            assert_eq!(ids, (0..11).collect::<Vec<_>>());
+
+            // With two permits, the third request can only start once one of the
+            // first two (each delayed >= 300ms) has completed
+            let mut arrivals = arrivals.lock().unwrap().clone();
+            arrivals.sort();
+            assert_eq!(arrivals.len(), 5);
+            assert!(arrivals[2].duration_since(arrivals[0]) >= Duration::from_millis(250));
+        }
+
+        #[tokio::test]
+        async fn concurrent_prefetch_falls_back_when_skip_is_rejected() {
+            let server = MockServer::start().await;
+            let client = mock_client(&server);
+
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .and(query_param_is_missing("$skip"))
+                .and(query_param_is_missing("page"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "@odata.count": 4,
+                    "@odata.nextLink": format!("{}/data/Customers?page=2", server.uri()),
+                    "value": [{"Id": 1}, {"Id": 2}]
+                })))
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .and(query_param("$skip", "2"))
+                .respond_with(ResponseTemplate::new(400).set_body_string("Skip not supported"))
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .and(query_param("page", "2"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "value": [{"Id": 3}, {"Id": 4}]
+                })))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let options = QueryOptions {
+                count: true,
+                ..Default::default()
+            };
+            let records = client
+                .fetch_all_pages_concurrent("Customers", &options, 4)
+                .await
+                .unwrap();
+
+            let ids: Vec<i64> = records.iter().map(|r| r["Id"].as_i64().unwrap()).collect();
+            assert_eq!(ids, vec![1, 2, 3, 4]);
+        }
+
+        /// Match the raw Prefer header value (wiremock's header matcher splits on commas)
+        fn prefer_is(expected: &'static str) -> impl wiremock::Match {
+            move |request: &wiremock::Request| {
+                request.headers.get("Prefer").and_then(|v| v.to_str().ok()) == Some(expected)
+            }
+        }
+
+        #[tokio::test]
+        async fn page_requests_send_one_merged_prefer_header() {
+            let server = MockServer::start().await;
+            let client = mock_client(&server).with_page_size(500);
+
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .and(prefer_is(
+                    "odata.include-annotations=*,odata.maxpagesize=500",
+                ))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+                .expect(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/Vendors"))
+                .and(prefer_is(
+                    "odata.include-annotations=*,odata.maxpagesize=25",
+                ))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            client
+                .fetch_entity_page("Customers", None, &QueryOptions::default())
+                .await
+                .unwrap();
+
+            let options = QueryOptions {
+                max_page_size: Some(25),
+                ..Default::default()
+            };
+            client
+                .fetch_entity_page("Vendors", None, &options)
+                .await
+                .unwrap();
+
+            let requests = server.received_requests().await.unwrap();
+            for request in requests.iter().filter(|r| r.method.as_str() == "GET") {
+                assert_eq!(request.headers.get_all("Prefer").iter().count(), 1);
+            }
+        }
+
+        /// Hands out `token-1`, `token-2`, ... on successive token requests
+        struct SequentialTokens(AtomicUsize);
+
+        impl wiremock::Respond for SequentialTokens {
+            fn respond(&self, _request: &wiremock::Request) -> ResponseTemplate {
+                let n = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+                ResponseTemplate::new(200).set_body_json(json!({
+                    "access_token": format!("token-{}", n),
+                    "token_type": "Bearer",
+                    "expires_in": 3600
+                }))
+            }
+        }
+
+        async fn mount_sequential_tokens(server: &MockServer, expected_calls: u64) {
+            Mock::given(method("POST"))
+                .and(path("/token"))
+                .respond_with(SequentialTokens(AtomicUsize::new(0)))
+                .expect(expected_calls)
+                .mount(server)
+                .await;
+        }
+
+        #[tokio::test]
+        async fn revoked_token_is_refreshed_once() {
+            let server = MockServer::start().await;
+            mount_sequential_tokens(&server, 2).await;
+            let client = adfs_client(&server);
+
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .and(header("Authorization", "Bearer token-1"))
+                .respond_with(ResponseTemplate::new(401))
+                .expect(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .and(header("Authorization", "Bearer token-2"))
+                .respond_with(
+                    ResponseTemplate::new(200).set_body_json(json!({"value": [{"Id": 1}]})),
+                )
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let page = client
+                .fetch_entity_page("Customers", None, &QueryOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(page.value.len(), 1);
+        }
+
+        #[tokio::test]
+        async fn persistent_401_reports_challenge() {
+            let server = MockServer::start().await;
+            mount_sequential_tokens(&server, 2).await;
+            let client = adfs_client(&server);
+
+            Mock::given(method("GET"))
+                .and(path("/data/$metadata"))
+                .respond_with(
+                    ResponseTemplate::new(401)
+                        .insert_header("WWW-Authenticate", "Bearer error=\"invalid_token\""),
+                )
+                .expect(2)
+                .mount(&server)
+                .await;
+
+            match client.fetch_metadata().await {
+                Err(ODataError::Unauthorized(challenge)) => {
+                    assert_eq!(challenge, "Bearer error=\"invalid_token\"");
+                }
+                other => panic!("expected Unauthorized, got {:?}", other.map(|_| ())),
+            }
+        }
+
+        #[tokio::test]
+        async fn delete_refreshes_token_on_401() {
+            let server = MockServer::start().await;
+            mount_sequential_tokens(&server, 2).await;
+            let client = adfs_client(&server);
+
+            Mock::given(method("DELETE"))
+                .and(path("/data/Customers(1)"))
+                .and(header("Authorization", "Bearer token-1"))
+                .respond_with(ResponseTemplate::new(401))
+                .mount(&server)
+                .await;
+            Mock::given(method("DELETE"))
+                .and(path("/data/Customers(1)"))
+                .and(header("Authorization", "Bearer token-2"))
+                .respond_with(ResponseTemplate::new(204))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            client
+                .delete_entity("Customers", &"1".into(), None)
+                .await
+                .unwrap();
+            assert_eq!(client.request_stats().token_refreshes, 1);
+        }
+
+        const REF_METADATA: &str = r#"<Schema Namespace="Microsoft.Dynamics.CRM">
+<EntityType Name="account">
+<Key><PropertyRef Name="accountid" /></Key>
+<Property Name="accountid" Type="Edm.Guid" />
+<NavigationProperty Name="contact_customer_accounts" Type="Collection(Microsoft.Dynamics.CRM.contact)" Partner="parentcustomerid_account" />
+<NavigationProperty Name="primarycontactid" Type="Microsoft.Dynamics.CRM.contact" />
+</EntityType>
+<EntityContainer Name="System">
+<EntitySet Name="accounts" EntityType="Microsoft.Dynamics.CRM.account" />
+<EntitySet Name="contacts" EntityType="Microsoft.Dynamics.CRM.contact" />
+</EntityContainer>
+</Schema>"#;
+
+        const ACCOUNT: &str = "00000000-0000-0000-0000-0000000000a1";
+        const CONTACT: &str = "00000000-0000-0000-0000-0000000000c1";
+
+        #[tokio::test]
+        async fn requests_go_through_the_proxy_which_errors_name() {
+            use wiremock::matchers::header;
+
+            let proxy_server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/data/accounts"))
+                .and(header("proxy-authorization", "Basic c3ZjOnB3"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+                .expect(1)
+                .mount(&proxy_server)
+                .await;
+
+            let client = |proxy: String| {
+                ODataClient::new(
+                    Arc::new(StaticTokenProvider::new("token")),
+                    "http://org.example.com/data/".to_string(),
+                    ProductType::Dataverse,
+                    0,
+                    10,
+                    false,
+                )
+                .with_proxy(ProxySettings {
+                    http_proxy: Some(proxy),
+                    username: Some("svc".to_string()),
+                    password: Some("pw".to_string()),
+                    ..Default::default()
+                })
+            };
+
+            let page = client(proxy_server.uri())
+                .unwrap()
+                .fetch_entity_page("accounts", None, &QueryOptions::default())
+                .await
+                .unwrap();
+            assert!(page.value.is_empty());
+
+            // Nothing listens on port 9 of the loopback address
+            let error = client("http://127.0.0.1:9".to_string())
+                .unwrap()
+                .fetch_entity_page("accounts", None, &QueryOptions::default())
+                .await
+                .unwrap_err();
+            assert!(
+                error.to_string().contains("via proxy 127.0.0.1:9"),
+                "{error}"
+            );
+
+            let Err(error) = client("http://[bad".to_string()) else {
+                panic!("a malformed proxy URL was accepted");
+            };
+            assert!(error.to_string().contains("Invalid proxy URL"), "{error}");
+        }
+
+        async fn ref_client() -> (MockServer, ODataClient) {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/data/$metadata"))
+                .respond_with(ResponseTemplate::new(200).set_body_string(REF_METADATA))
+                .mount(&server)
+                .await;
+            let client = ODataClient::new(
+                Arc::new(StaticTokenProvider::new("token")),
+                format!("{}/data/", server.uri()),
+                ProductType::Dataverse,
+                0,
+                10,
+                false,
+            );
+            (server, client)
+        }
+
+        #[tokio::test]
+        async fn associate_posts_or_puts_the_ref_by_cardinality() {
+            use wiremock::matchers::body_json;
+
+            let (server, client) = ref_client().await;
+            let target =
+                json!({"@odata.id": format!("{}/data/contacts({})", server.uri(), CONTACT)});
+            Mock::given(method("POST"))
+                .and(path(format!(
+                    "/data/accounts({})/contact_customer_accounts/$ref",
+                    ACCOUNT
+                )))
+                .and(body_json(&target))
+                .respond_with(ResponseTemplate::new(204))
+                .expect(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("PUT"))
+                .and(path(format!(
+                    "/data/accounts({})/primarycontactid/$ref",
+                    ACCOUNT
+                )))
+                .and(body_json(&target))
+                .respond_with(ResponseTemplate::new(204))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            for navigation in ["contact_customer_accounts", "primarycontactid"] {
+                client
+                    .associate(
+                        "accounts",
+                        &ACCOUNT.into(),
+                        navigation,
+                        "contacts",
+                        &CONTACT.into(),
+                    )
+                    .await
+                    .unwrap();
+            }
+
+            let missing = client
+                .associate(
+                    "accounts",
+                    &ACCOUNT.into(),
+                    "nope",
+                    "contacts",
+                    &CONTACT.into(),
+                )
+                .await;
+            assert!(matches!(missing, Err(ODataError::NotFound(m)) if m.contains("'nope'")));
+        }
+
+        #[tokio::test]
+        async fn disassociate_deletes_the_ref_and_surfaces_odata_errors() {
+            let (server, client) = ref_client().await;
+            let target = format!("{}/data/contacts({})", server.uri(), CONTACT);
+            Mock::given(method("DELETE"))
+                .and(path(format!(
+                    "/data/accounts({})/contact_customer_accounts/$ref",
+                    ACCOUNT
+                )))
+                .and(query_param("$id", target.as_str()))
+                .respond_with(ResponseTemplate::new(204))
+                .expect(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("DELETE"))
+                .and(path(format!(
+                    "/data/accounts({})/primarycontactid/$ref",
+                    ACCOUNT
+                )))
+                .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+                    "error": {"code": "0x80060888", "message": "The relationship was not found"}
+                })))
+                .mount(&server)
+                .await;
+
+            let contact = EntityKey::from(CONTACT);
+            client
+                .disassociate(
+                    "accounts",
+                    &ACCOUNT.into(),
+                    "contact_customer_accounts",
+                    Some(("contacts", &contact)),
+                )
+                .await
+                .unwrap();
+            assert!(client
+                .disassociate(
+                    "accounts",
+                    &ACCOUNT.into(),
+                    "contact_customer_accounts",
+                    None
+                )
+                .await
+                .is_err());
+
+            match client
+                .disassociate("accounts", &ACCOUNT.into(), "primarycontactid", None)
+                .await
+            {
+                Err(ODataError::ServerError(400, message)) => {
+                    assert_eq!(message, "The relationship was not found (0x80060888)");
+                }
+                other => panic!("expected a 400, got {:?}", other),
+            }
+        }
+
+        #[tokio::test]
+        async fn compressed_and_plain_responses_parse_identically() {
+            use flate2::write::GzEncoder;
+            use std::io::Write;
+
+            let page = json!({"value": [{"Id": 1, "Name": "Contoso"}]});
+            let metadata =
+                r#"<edmx><EntitySet Name="Customers" EntityType="NS.Customer" /></edmx>"#;
+            let gzip = |body: &[u8]| {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body).unwrap();
+                encoder.finish().unwrap()
+            };
+
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .and(header_regex("accept-encoding", "gzip"))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .insert_header("content-encoding", "gzip")
+                        .insert_header("content-type", "application/json")
+                        .set_body_bytes(gzip(page.to_string().as_bytes())),
+                )
+                .expect(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/$metadata"))
+                .and(header_regex("accept-encoding", "gzip"))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .insert_header("content-encoding", "gzip")
+                        .set_body_bytes(gzip(metadata.as_bytes())),
+                )
+                .expect(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(&page))
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/$metadata"))
+                .respond_with(ResponseTemplate::new(200).set_body_string(metadata))
+                .mount(&server)
+                .await;
+
+            let options = QueryOptions::default();
+            let mut results = Vec::new();
+            for compression in [true, false] {
+                let client = mock_client(&server).with_compression(compression);
+                let page = client
+                    .fetch_entity_page("Customers", None, &options)
+                    .await
+                    .unwrap();
+                let document = client.fetch_metadata().await.unwrap();
+                results.push((page.value, document.xml().to_string()));
+            }
+            assert_eq!(results[0], results[1]);
+            assert_eq!(results[0].1, metadata);
+
+            let requests = server.received_requests().await.unwrap();
+            assert!(requests[2].headers.get("accept-encoding").is_none());
+        }
+
+        #[tokio::test]
+        async fn the_label_language_is_sent_and_keys_the_metadata_cache() {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/data/$metadata"))
+                .respond_with(ResponseTemplate::new(200).set_body_string("<edmx:Edmx />"))
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+                .mount(&server)
+                .await;
+
+            let client = mock_client(&server).with_language(Some("de-DE".to_string()));
+            client.metadata_model().await.unwrap();
+            client
+                .fetch_entity_page("Customers", None, &QueryOptions::default())
+                .await
+                .unwrap();
+            crate::odata::with_language("fr-FR".to_string(), async {
+                // Checks against the cached model fall back to de-DE
+                assert!(client.cached_metadata_model().await.is_some());
+                client.metadata_model().await.unwrap();
+            })
+            .await;
+            // Both languages stay cached
+            client.metadata_model().await.unwrap();
+
+            let requests = server.received_requests().await.unwrap();
+            let languages: Vec<_> = requests
+                .iter()
+                .map(|r| {
+                    let language = r.headers.get("accept-language").unwrap();
+                    (r.url.path(), language.to_str().unwrap().to_string())
+                })
+                .collect();
+            assert_eq!(
+                languages,
+                [
+                    ("/data/$metadata", "de-DE".to_string()),
+                    ("/data/Customers", "de-DE".to_string()),
+                    ("/data/$metadata", "fr-FR".to_string()),
+                ]
+            );
+            let plain = mock_client(&server);
+            assert_eq!(plain.language(), None);
+            assert!(plain
+                .prepare_request(&Method::GET, "x", &RequestOptions::default(), "id")
+                .headers
+                .iter()
+                .all(|(name, _)| name != "Accept-Language"));
+        }
+
+        #[tokio::test]
+        async fn client_request_id_is_kept_across_retries_and_counted() {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+                .up_to_n_times(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .insert_header("x-ms-service-request-id", "svc-1")
+                        .set_body_json(json!({"value": []})),
+                )
+                .mount(&server)
+                .await;
+
+            let client =
+                mock_client(&server).with_user_agent_suffix(Some("contoso-uat".to_string()));
+            client
+                .fetch_entity_page("Customers", None, &QueryOptions::default())
+                .await
+                .unwrap();
+
+            let requests = server.received_requests().await.unwrap();
+            let ids: Vec<_> = requests
+                .iter()
+                .map(|r| r.headers.get("client-request-id").unwrap().clone())
+                .collect();
+            assert_eq!(ids.len(), 2);
+            assert_eq!(ids[0], ids[1]);
+            assert_eq!(
+                requests[0].headers.get("user-agent").unwrap(),
+                format!("{} contoso-uat", USER_AGENT).as_str()
+            );
+
+            let stats = client.request_stats();
+            assert_eq!(stats.total_requests, 2);
+            assert_eq!(stats.retries, 1);
+            assert_eq!(stats.throttled, 1);
+        }
+
+        #[tokio::test]
+        async fn server_errors_are_retried_up_to_max_retries() {
+            // Two failures leave the third and last attempt to succeed
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .respond_with(ResponseTemplate::new(500))
+                .up_to_n_times(2)
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .respond_with(
+                    ResponseTemplate::new(200).set_body_json(json!({"value": [{"Id": 1}]})),
+                )
+                .mount(&server)
+                .await;
+            let client = mock_client(&server);
+            let page = client
+                .fetch_entity_page("Customers", None, &QueryOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(page.value.len(), 1);
+            assert_eq!(client.request_stats().total_requests, 3);
+
+            // A third failure exhausts the retries with the service's message
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .respond_with(ResponseTemplate::new(503).set_body_json(
+                    json!({"error": {"code": "0x0", "message": "Service unavailable"}}),
+                ))
+                .expect(3)
+                .mount(&server)
+                .await;
+            let error = mock_client(&server)
+                .fetch_entity_page("Customers", None, &QueryOptions::default())
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(error, ODataError::ServerError(503, ref message) if message == "Service unavailable (0x0)"),
+                "{error:?}"
+            );
+        }
+
+        #[tokio::test]
+        async fn an_open_circuit_fails_calls_without_sending_them() {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .respond_with(ResponseTemplate::new(503))
+                .expect(2)
+                .mount(&server)
+                .await;
+            let client = mock_client(&server).with_circuit_breaker(2, Duration::from_secs(60));
+            let options = QueryOptions::default();
+
+            // The second 503 opens the circuit, which ends the retries
+            let error = client
+                .fetch_entity_page("Customers", None, &options)
+                .await
+                .unwrap_err();
+            assert!(matches!(error, ODataError::CircuitOpen { .. }), "{error:?}");
+            let error = client
+                .fetch_entity_page("Customers", None, &options)
+                .await
+                .unwrap_err();
+            assert!(
+                error.to_string().starts_with(
+                    "The service kept failing, so requests are paused: retry after 60 seconds"
+                ),
+                "{error}"
+            );
+            assert_eq!(client.request_stats().total_requests, 2);
+            assert_eq!(client.circuit_stats().opened, 1);
+
+            // A 500 is an answer, not an outage
+            server.reset().await;
+            client.reset_circuit();
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .respond_with(ResponseTemplate::new(500))
+                .mount(&server)
+                .await;
+            for _ in 0..2 {
+                let error = client
+                    .fetch_entity_page("Customers", None, &options)
+                    .await
+                    .unwrap_err();
+                assert!(
+                    matches!(error, ODataError::ServerError(500, _)),
+                    "{error:?}"
+                );
+            }
+            assert_eq!(
+                client.circuit_stats().state,
+                crate::odata::CircuitState::Closed
+            );
+        }
+
+        #[tokio::test]
+        async fn throttling_gives_up_after_max_retries() {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+                .expect(3)
+                .mount(&server)
+                .await;
+
+            let error = mock_client(&server)
+                .fetch_entity_page("Customers", None, &QueryOptions::default())
+                .await
+                .unwrap_err();
+            assert!(matches!(error, ODataError::RateLimited(0)), "{error:?}");
+        }
+
+        #[tokio::test]
+        async fn not_found_carries_the_service_message_without_retrying() {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/data/Customers"))
+                .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+                    "error": {"code": "", "message": "Resource not found for the segment 'Customers'."}
+                })))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let error = mock_client(&server)
+                .fetch_entity_page("Customers", None, &QueryOptions::default())
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(error, ODataError::NotFound(ref message)
+                    if message == "Resource not found for the segment 'Customers'."),
+                "{error:?}"
+            );
+        }
+
+        #[tokio::test]
+        async fn impersonation_header_survives_retries_and_skips_finops() {
+            const CALLER: &str = "0c4e1a2b-3d5f-4a6b-8c7d-9e0f1a2b3c4d";
+            const OVERRIDE: &str = "11111111-2222-3333-4444-555555555555";
+
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/data/accounts"))
+                .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+                .up_to_n_times(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/accounts"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+                .mount(&server)
+                .await;
+
+            let dataverse = ODataClient::new(
+                Arc::new(StaticTokenProvider::new("test-token")),
+                format!("{}/data/", server.uri()),
+                ProductType::Dataverse,
+                3,
+                10,
+                false,
+            )
+            .with_impersonation(Some(CALLER.to_string()), CallerIdHeader::SystemUserId);
+            let options = QueryOptions::default();
+            dataverse
+                .fetch_entity_page("accounts", None, &options)
+                .await
+                .unwrap();
+            crate::odata::with_caller(
+                OVERRIDE.to_string(),
+                dataverse.fetch_entity_page("accounts", None, &options),
+            )
+            .await
+            .unwrap();
+
+            let finops = mock_client(&server)
+                .with_impersonation(Some(CALLER.to_string()), CallerIdHeader::SystemUserId);
+            assert!(finops.impersonation().is_none());
+            finops
+                .fetch_entity_page("accounts", None, &options)
+                .await
+                .unwrap();
+
+            let callers: Vec<Option<String>> = server
+                .received_requests()
+                .await
+                .unwrap()
+                .iter()
+                .map(|request| {
+                    request
+                        .headers
+                        .get("MSCRMCallerID")
+                        .and_then(|v| v.to_str().ok())
+                        .map(String::from)
+                })
+                .collect();
+            // The throttled attempt and its retry both carry the caller
+            assert_eq!(
+                callers,
+                vec![
+                    Some(CALLER.to_string()),
+                    Some(CALLER.to_string()),
+                    Some(OVERRIDE.to_string()),
+                    None
+                ]
+            );
+        }
+
+        #[tokio::test]
+        async fn file_downloads_follow_ranges_and_respect_the_cap() {
+            let server = MockServer::start().await;
+            let file = "/data/accounts(1)/contract/$value";
+            Mock::given(method("GET"))
+                .and(path(file))
+                .and(header("Range", "bytes=0-4194303"))
+                .respond_with(
+                    ResponseTemplate::new(206)
+                        .insert_header("x-ms-file-name", "contract.pdf")
+                        .insert_header("Content-Range", "bytes 0-4/10")
+                        .set_body_bytes(b"hello".to_vec()),
+                )
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path(file))
+                .and(header("Range", "bytes=5-4194308"))
+                .respond_with(
+                    ResponseTemplate::new(206)
+                        .insert_header("Content-Range", "bytes 5-9/10")
+                        .set_body_bytes(b"world".to_vec()),
+                )
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/accounts(2)/contract/$value"))
+                .respond_with(ResponseTemplate::new(204))
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/data/accounts(3)/contract/$value"))
+                .respond_with(ResponseTemplate::new(404).set_body_json(
+                    json!({"error": {"code": "0x80040217", "message": "account With Id = 3 Does Not Exist"}}),
+                ))
+                .mount(&server)
+                .await;
+            let client = mock_client(&server);
+            let key = |id: &str| EntityKey::Single(id.to_string());
+
+            let download = client
+                .download_file("accounts", &key("1"), "contract", 1024)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(download.file_name.as_deref(), Some("contract.pdf"));
+            assert_eq!(download.bytes, b"helloworld");
+
+            let too_large = client
+                .download_file("accounts", &key("1"), "contract", 8)
+                .await;
+            assert!(matches!(
+                too_large,
+                Err(ODataError::TooLarge { size: 10, limit: 8 })
+            ));
+
+            let empty = client
+                .download_file("accounts", &key("2"), "contract", 1024)
+                .await
+                .unwrap();
+            assert!(empty.is_none());
+
+            let missing = client
+                .download_file("accounts", &key("3"), "contract", 1024)
+                .await;
+            assert!(
+                matches!(&missing, Err(ODataError::NotFound(m)) if m.contains("Does Not Exist")),
+                "{missing:?}"
+            );
+        }
+
+        #[tokio::test]
+        async fn small_uploads_patch_the_value_in_one_request() {
+            let server = MockServer::start().await;
+            Mock::given(method("PATCH"))
+                .and(path("/data/accounts(1)/new_contract/$value"))
+                .and(header("x-ms-file-name", "contract.txt"))
+                .and(header("Content-Type", "application/octet-stream"))
+                .and(wiremock::matchers::body_bytes(b"signed".to_vec()))
+                .respond_with(ResponseTemplate::new(204))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            mock_client(&server)
+                .upload_file(
+                    "accounts",
+                    &EntityKey::Single("1".to_string()),
+                    "new_contract",
+                    b"signed",
+                    "contract.txt",
+                )
+                .await
+                .unwrap();
+        }
+
+        #[tokio::test]
+        async fn upload_sessions_on_other_origins_are_refused() {
+            let server = MockServer::start().await;
+            Mock::given(method("PATCH"))
+                .and(path("/data/accounts(1)/new_contract"))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .insert_header("Location", "https://collector.example.com/upload"),
+                )
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let error = mock_client(&server)
+                .upload_file(
+                    "accounts",
+                    &EntityKey::Single("1".to_string()),
+                    "new_contract",
+                    &vec![0u8; FILE_CHUNK_BYTES as usize + 1],
+                    "big file.bin",
+                )
+                .await
+                .unwrap_err();
+            assert!(
+                error.to_string().contains("Refusing to follow Location"),
+                "{error}"
+            );
+        }
+
+        /// Mount a chunked upload session for `accounts(id)/new_contract` whose
+        /// chunks are `chunk_size` bytes of a `total`-byte file; the first chunk
+        /// fails once with 503 and the last one answers `final_status`
+        async fn mount_upload_session(
+            server: &MockServer,
+            id: u32,
+            chunk_size: usize,
+            total: usize,
+            final_status: u16,
+        ) {
+            let record = format!("/data/accounts({})/new_contract", id);
+            Mock::given(method("PATCH"))
+                .and(path(record.as_str()))
+                .and(header("x-ms-transfer-mode", "chunked"))
+                .and(query_param("x-ms-file-name", "big file.bin"))
+                .respond_with(
+                    ResponseTemplate::new(200)
+                        .insert_header(
+                            "Location",
+                            format!("{}{}?sessiontoken=s{}", server.uri(), record, id).as_str(),
+                        )
+                        .insert_header("x-ms-chunk-size", chunk_size.to_string().as_str()),
+                )
+                .mount(server)
+                .await;
+
+            let first = format!("bytes 0-{}/{}", chunk_size - 1, total);
+            let last = format!("bytes {}-{}/{}", chunk_size, total - 1, total);
+            Mock::given(method("PATCH"))
+                .and(query_param("sessiontoken", format!("s{}", id).as_str()))
+                .and(header("Content-Range", first.as_str()))
+                .respond_with(ResponseTemplate::new(503))
+                .up_to_n_times(1)
+                .mount(server)
+                .await;
+            Mock::given(method("PATCH"))
+                .and(query_param("sessiontoken", format!("s{}", id).as_str()))
+                .and(header("Content-Range", first.as_str()))
+                .respond_with(ResponseTemplate::new(206))
+                .mount(server)
+                .await;
+            Mock::given(method("PATCH"))
+                .and(query_param("sessiontoken", format!("s{}", id).as_str()))
+                .and(header("Content-Range", last.as_str()))
+                .respond_with(ResponseTemplate::new(final_status))
+                .mount(server)
+                .await;
+        }
+
+        #[tokio::test]
+        async fn large_uploads_are_chunked_retried_and_committed() {
+            let server = MockServer::start().await;
+            let chunk_size = FILE_CHUNK_BYTES as usize;
+            let total = chunk_size + 1000;
+            mount_upload_session(&server, 1, chunk_size, total, 204).await;
+            mount_upload_session(&server, 2, chunk_size, total, 206).await;
+            let client = mock_client(&server);
+            let bytes = vec![7u8; total];
+
+            client
+                .upload_file(
+                    "accounts",
+                    &EntityKey::Single("1".to_string()),
+                    "new_contract",
+                    &bytes,
+                    "big file.bin",
+                )
+                .await
+                .unwrap();
+            let chunks: Vec<usize> = server
+                .received_requests()
+                .await
+                .unwrap()
+                .iter()
+                .filter(|request| request.url.query().unwrap_or("").contains("sessiontoken"))
+                .map(|request| request.body.len())
+                .collect();
+            // The first chunk is sent again after the 503
+            assert_eq!(chunks, vec![chunk_size, chunk_size, 1000]);
+
+            // The last chunk was not acknowledged as the end of the file
+            let uncommitted = client
+                .upload_file(
+                    "accounts",
+                    &EntityKey::Single("2".to_string()),
+                    "new_contract",
+                    &bytes,
+                    "big file.bin",
+                )
+                .await;
+            assert!(
+                matches!(&uncommitted, Err(ODataError::ParseError(m)) if m.contains("not committed")),
+                "{uncommitted:?}"
+            );
+        }
+
+        #[tokio::test]
+        async fn annotations_bind_to_the_record_by_entity_type() {
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/data/$metadata"))
+                .respond_with(ResponseTemplate::new(200).set_body_string(
+                    r#"<EntityContainer><EntitySet Name="accounts" EntityType="Microsoft.Dynamics.CRM.account" /></EntityContainer>"#,
+                ))
+                .mount(&server)
+                .await;
+            Mock::given(method("POST"))
+                .and(path("/data/annotations"))
+                .and(wiremock::matchers::body_partial_json(json!({
+                    "filename": "note.txt",
+                    "mimetype": "text/plain",
+                    "documentbody": "aGk=",
+                    "objectid_account@odata.bind": format!("{}/data/accounts(1)", server.uri()),
+                })))
+                .respond_with(ResponseTemplate::new(204).insert_header(
+                    "OData-EntityId",
+                    format!("{}/data/annotations(abc-123)", server.uri()).as_str(),
+                ))
+                .mount(&server)
+                .await;
+
+            let id = mock_client(&server)
+                .create_annotation(
+                    "accounts",
+                    &EntityKey::Single("1".to_string()),
+                    "note.txt",
+                    "text/plain",
+                    b"hi",
+                    None,
+                )
+                .await
+                .unwrap();
+            assert_eq!(id.as_deref(), Some("abc-123"));
+        }
+
+        #[tokio::test]
+        async fn upsert_reports_created_updated_and_blocked_preconditions() {
+            use wiremock::matchers::{body_json, header_exists};
+
+            let (server, client) = ref_client().await;
+            let key = EntityKey::Composite(vec![("accountnumber".to_string(), json!("A-1"))]);
+            let payload = json!({"name": "Contoso"});
+            Mock::given(method("PATCH"))
+                .and(path("/data/accounts(accountnumber='A-1')"))
+                .and(header("If-None-Match", "*"))
+                .and(body_json(&payload))
+                .respond_with(ResponseTemplate::new(201))
+                .up_to_n_times(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("PATCH"))
+                .and(path("/data/accounts(accountnumber='A-1')"))
+                .and(header("If-None-Match", "*"))
+                .respond_with(ResponseTemplate::new(412).set_body_json(json!({
+                    "error": {"code": "0x80040237", "message": "A record with matching key values already exists."}
+                })))
+                .mount(&server)
+                .await;
+            Mock::given(method("PATCH"))
+                .and(path("/data/accounts(accountnumber='A-1')"))
+                .and(header("If-Match", "*"))
+                .respond_with(ResponseTemplate::new(204))
+                .mount(&server)
+                .await;
+            Mock::given(method("PATCH"))
+                .and(path("/data/accounts(accountnumber='A-2')"))
+                .and(header_exists("If-Match"))
+                .respond_with(ResponseTemplate::new(404))
+                .mount(&server)
+                .await;
+
+            let created = client
+                .upsert_entity("accounts", &key, &payload, true, false)
+                .await;
+            assert_eq!(created.unwrap(), UpsertOutcome::Created);
+
+            match client
+                .upsert_entity("accounts", &key, &payload, true, false)
+                .await
+            {
+                Err(ODataError::ServerError(412, message)) => {
+                    assert!(
+                        message.contains("already exists and prevent_update"),
+                        "{message}"
+                    );
+                }
+                other => panic!("expected 412, got {:?}", other),
+            }
+
+            let updated = client
+                .upsert_entity("accounts", &key, &payload, false, true)
+                .await;
+            assert_eq!(updated.unwrap(), UpsertOutcome::Updated);
+
+            let missing = EntityKey::Composite(vec![("accountnumber".to_string(), json!("A-2"))]);
+            match client
+                .upsert_entity("accounts", &missing, &payload, false, true)
+                .await
+            {
+                Err(ODataError::ServerError(412, message)) => {
+                    assert!(message.contains("prevent_create"), "{message}");
+                }
+                other => panic!("expected 412, got {:?}", other),
+            }
+
+            assert!(client
+                .upsert_entity("accounts", &key, &payload, true, true)
+                .await
+                .is_err());
+        }
+
+        #[tokio::test]
+        async fn key_values_are_encoded_in_the_record_url() {
+            let server = MockServer::start().await;
+            let client = ODataClient::new(
+                Arc::new(StaticTokenProvider::new("token")),
+                format!("{}/data/", server.uri()),
+                ProductType::Finops,
+                0,
+                10,
+                false,
+            );
+
+            Mock::given(method("GET"))
+                .and(path(
+                    "/data/CustomersV3(dataAreaId='usmf',CustomerAccount='A%2FB%23%3F%25')",
+                ))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({"Name": "Slash"})))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let key = EntityKey::Composite(vec![
+                ("dataAreaId".to_string(), json!("usmf")),
+                ("CustomerAccount".to_string(), json!("A/B#?%")),
+            ]);
+            let record = client.get_entity("CustomersV3", &key).await.unwrap();
+            assert_eq!(record["Name"], "Slash");
+        }
+    }
 }
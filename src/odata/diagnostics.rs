@@ -0,0 +1,372 @@
+//! Connection diagnostics
+//!
+//! Staged checks behind the `test_connection` tool and the startup check:
+//! acquire a token, read the service root, then query a single row. Every
+//! stage has its own timeout, and failures carry a remediation hint for the
+//! common setup mistakes.
+
+use super::client::{ODataClient, ODataError, QueryOptions};
+use crate::auth::decode_jwt_claims;
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// Per-stage timeout when the caller does not choose one
+pub const DEFAULT_STAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A step of the connection check, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStage {
+    Token,
+    ServiceRoot,
+    Query,
+}
+
+impl CheckStage {
+    pub fn label(&self) -> &'static str {
+        match self {
+            CheckStage::Token => "Token acquisition",
+            CheckStage::ServiceRoot => "Service root",
+            CheckStage::Query => "Sample query",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Passed,
+    Failed,
+    /// Not attempted because an earlier stage it depends on failed
+    Skipped,
+}
+
+/// Outcome of one stage
+#[derive(Debug, Clone)]
+pub struct StageReport {
+    pub stage: CheckStage,
+    pub status: CheckStatus,
+    pub detail: String,
+    pub hint: Option<&'static str>,
+    pub elapsed: Duration,
+}
+
+/// Outcome of every stage of a connection check
+#[derive(Debug, Clone)]
+pub struct ConnectionReport {
+    pub stages: Vec<StageReport>,
+}
+
+impl ConnectionReport {
+    /// Whether no stage failed
+    pub fn passed(&self) -> bool {
+        self.stages
+            .iter()
+            .all(|stage| stage.status != CheckStatus::Failed)
+    }
+}
+
+impl fmt::Display for ConnectionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for report in &self.stages {
+            let status = match report.status {
+                CheckStatus::Passed => "PASS",
+                CheckStatus::Failed => "FAIL",
+                CheckStatus::Skipped => "SKIP",
+            };
+            writeln!(
+                f,
+                "[{}] {} ({} ms): {}",
+                status,
+                report.stage.label(),
+                report.elapsed.as_millis(),
+                report.detail
+            )?;
+            if let Some(hint) = report.hint {
+                writeln!(f, "       Hint: {}", hint)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run one stage under its own timeout
+async fn run_stage<T, F>(
+    stage: CheckStage,
+    timeout: Duration,
+    future: F,
+) -> (StageReport, Option<T>)
+where
+    F: Future<Output = Result<(T, String), ODataError>>,
+{
+    let started = Instant::now();
+    let outcome = tokio::time::timeout(timeout, future).await;
+    let elapsed = started.elapsed();
+
+    let (status, detail, hint, value) = match outcome {
+        Ok(Ok((value, detail))) => (CheckStatus::Passed, detail, None, Some(value)),
+        Ok(Err(e)) => (
+            CheckStatus::Failed,
+            error_chain(&e),
+            e.remediation_hint(),
+            None,
+        ),
+        Err(_) => (
+            CheckStatus::Failed,
+            format!("no response within {} s", timeout.as_secs()),
+            Some("The stage hung: check network access, proxy settings and that ENDPOINT is reachable from this machine."),
+            None,
+        ),
+    };
+
+    let report = StageReport {
+        stage,
+        status,
+        detail,
+        hint,
+        elapsed,
+    };
+    (report, value)
+}
+
+fn skipped(stage: CheckStage, detail: &str) -> StageReport {
+    StageReport {
+        stage,
+        status: CheckStatus::Skipped,
+        detail: detail.to_string(),
+        hint: None,
+        elapsed: Duration::ZERO,
+    }
+}
+
+/// An error's message followed by its causes, which hold the DNS or TLS details
+fn error_chain(error: &ODataError) -> String {
+    let mut text = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        let cause_text = cause.to_string();
+        if !text.contains(&cause_text) {
+            text.push_str(": ");
+            text.push_str(&cause_text);
+        }
+        source = cause.source();
+    }
+    text
+}
+
+impl ODataClient {
+    /// Check that the client can authenticate, reach the endpoint and read data
+    ///
+    /// The sample query uses `probe_entity`, or the first entity set in the
+    /// service document that `allowed` accepts when none is given. Stages
+    /// after a failed token acquisition are skipped, since they would fail
+    /// the same way.
+    pub async fn check_connection(
+        &self,
+        probe_entity: Option<&str>,
+        allowed: impl Fn(&str) -> bool,
+        stage_timeout: Duration,
+    ) -> ConnectionReport {
+        let mut stages = Vec::new();
+
+        let (report, token) = run_stage(CheckStage::Token, stage_timeout, async {
+            let token = self.access_token().await?;
+            let audience = decode_jwt_claims(&token).ok().and_then(|claims| {
+                claims
+                    .get("aud")
+                    .and_then(|aud| aud.as_str())
+                    .map(String::from)
+            });
+            let detail = match audience {
+                Some(audience) => format!("token acquired for {}", audience),
+                None => "token acquired".to_string(),
+            };
+            Ok(((), detail))
+        })
+        .await;
+        stages.push(report);
+
+        if token.is_none() {
+            stages.push(skipped(CheckStage::ServiceRoot, "requires a token"));
+            stages.push(skipped(CheckStage::Query, "requires a token"));
+            return ConnectionReport { stages };
+        }
+
+        let (report, first_entity) = run_stage(CheckStage::ServiceRoot, stage_timeout, async {
+            let document = self.fetch_service_document().await?;
+            let entity_sets = document
+                .get("value")
+                .and_then(|value| value.as_array())
+                .cloned()
+                .unwrap_or_default();
+            let first = entity_sets
+                .iter()
+                .filter_map(|set| set.get("name").and_then(|name| name.as_str()))
+                .find(|name| allowed(name))
+                .map(String::from);
+            let detail = format!(
+                "{} responded with {} entity sets",
+                self.endpoint(),
+                entity_sets.len()
+            );
+            Ok((first, detail))
+        })
+        .await;
+        stages.push(report);
+
+        let entity = probe_entity
+            .map(String::from)
+            .or_else(|| first_entity.flatten());
+        let Some(entity) = entity else {
+            stages.push(skipped(
+                CheckStage::Query,
+                "no entity configured and none the entity policy allows listed by the service root",
+            ));
+            return ConnectionReport { stages };
+        };
+
+        let (report, _) = run_stage(CheckStage::Query, stage_timeout, async {
+            let options = QueryOptions {
+                top: Some(1),
+                ..Default::default()
+            };
+            let page = self.fetch_entity_page(&entity, None, &options).await?;
+            let detail = format!("{} returned {} row(s)", entity, page.value.len());
+            Ok(((), detail))
+        })
+        .await;
+        stages.push(report);
+
+        ConnectionReport { stages }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::StaticTokenProvider;
+    use crate::config::ProductType;
+    use serde_json::json;
+    use std::sync::Arc;
+    use wiremock::matchers::{method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn client(endpoint: String) -> ODataClient {
+        ODataClient::new(
+            Arc::new(StaticTokenProvider::new("test-token")),
+            endpoint,
+            ProductType::Dataverse,
+            0,
+            10,
+            false,
+        )
+    }
+
+    #[tokio::test]
+    async fn every_stage_passes_against_a_healthy_endpoint() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": [{"name": "accounts", "kind": "EntitySet", "url": "accounts"}]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .and(query_param("$top", "1"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({"value": [{"name": "A"}]})),
+            )
+            .mount(&server)
+            .await;
+
+        let report = client(format!("{}/data/", server.uri()))
+            .check_connection(None, |_| true, Duration::from_secs(5))
+            .await;
+
+        assert!(report.passed(), "{report}");
+        let text = report.to_string();
+        assert!(text.contains("[PASS] Service root"), "{text}");
+        assert!(text.contains("accounts returned 1 row(s)"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn fallback_probe_skips_sets_that_are_not_allowed() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "value": [{"name": "systemusers"}, {"name": "accounts"}]
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": []})))
+            .mount(&server)
+            .await;
+
+        let report = client(format!("{}/data/", server.uri()))
+            .check_connection(None, |set| set != "systemusers", Duration::from_secs(5))
+            .await;
+        assert!(
+            report.to_string().contains("accounts returned 0 row(s)"),
+            "{report}"
+        );
+
+        let report = client(format!("{}/data/", server.uri()))
+            .check_connection(None, |_| false, Duration::from_secs(5))
+            .await;
+        assert_eq!(report.stages[2].status, CheckStatus::Skipped);
+    }
+
+    #[tokio::test]
+    async fn forbidden_and_hanging_stages_are_reported_separately() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/data/"))
+            .respond_with(ResponseTemplate::new(403))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/data/accounts"))
+            .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(5)))
+            .mount(&server)
+            .await;
+
+        let report = client(format!("{}/data/", server.uri()))
+            .check_connection(Some("accounts"), |_| true, Duration::from_millis(200))
+            .await;
+
+        let statuses: Vec<_> = report.stages.iter().map(|s| s.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                CheckStatus::Passed,
+                CheckStatus::Failed,
+                CheckStatus::Failed
+            ]
+        );
+        assert!(report.stages[1]
+            .hint
+            .unwrap()
+            .contains("create an application user"));
+        assert!(report.stages[2].detail.contains("no response within"));
+    }
+
+    #[tokio::test]
+    async fn unresolvable_hosts_point_at_the_endpoint() {
+        let report = client("https://does-not-exist.invalid/data/".to_string())
+            .check_connection(Some("accounts"), |_| true, Duration::from_secs(10))
+            .await;
+
+        let root = &report.stages[1];
+        assert_eq!(root.status, CheckStatus::Failed);
+        // Sandboxes without a resolver fail to connect instead of failing DNS
+        let hint = root.hint.unwrap_or_default();
+        assert!(
+            hint.contains("could not be resolved") || hint.contains("Could not connect"),
+            "{hint}"
+        );
+    }
+}
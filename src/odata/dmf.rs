@@ -0,0 +1,386 @@
+//! Finance & Operations data management (DMF) package API
+//!
+//! Large F&O imports go through data packages rather than OData writes. The
+//! zipped package is uploaded to blob storage through a SAS URL from
+//! `GetAzureWriteUrl`, `ImportFromPackage` imports it into a data project
+//! (definition group), and `GetExecutionSummaryStatus` reports how the
+//! execution is going. The actions are bound to the
+//! `DataManagementDefinitionGroups` entity set.
+
+use super::cancel::cancellable;
+use super::client::{ODataClient, ODataError};
+use super::progress::report_progress;
+use super::timeout::Deadline;
+use serde_json::{json, Value};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Path of the package actions below the service root
+const ACTIONS: &str = "DataManagementDefinitionGroups/Microsoft.Dynamics.DataEntities.";
+
+/// Where `GetAzureWriteUrl` lets a package be uploaded
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlobWriteUrl {
+    pub blob_id: String,
+    /// SAS URL the package is PUT to, and later passed to `ImportFromPackage`
+    pub blob_url: String,
+}
+
+/// State of a DMF execution (`DMFExecutionSummaryStatus`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionStatus {
+    Unknown,
+    NotRun,
+    Executing,
+    Succeeded,
+    PartiallySucceeded,
+    Failed,
+    Canceled,
+}
+
+impl ExecutionStatus {
+    const ALL: [ExecutionStatus; 7] = [
+        ExecutionStatus::Unknown,
+        ExecutionStatus::NotRun,
+        ExecutionStatus::Executing,
+        ExecutionStatus::Succeeded,
+        ExecutionStatus::PartiallySucceeded,
+        ExecutionStatus::Failed,
+        ExecutionStatus::Canceled,
+    ];
+
+    /// Status from its enum value (`0`-`6`) or member name, as the service
+    /// may return either
+    pub fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(number) => Self::ALL
+                .get(usize::try_from(number.as_u64()?).ok()?)
+                .copied(),
+            Value::String(name) => match name.trim().parse::<usize>() {
+                Ok(index) => Self::ALL.get(index).copied(),
+                Err(_) => Self::ALL
+                    .into_iter()
+                    .find(|status| format!("{:?}", status).eq_ignore_ascii_case(name.trim())),
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether the execution has stopped, one way or another
+    pub fn is_finished(self) -> bool {
+        matches!(
+            self,
+            Self::Succeeded | Self::PartiallySucceeded | Self::Failed | Self::Canceled
+        )
+    }
+
+    /// Whether some records were not imported, so staging has errors to show
+    pub fn has_errors(self) -> bool {
+        matches!(self, Self::PartiallySucceeded | Self::Failed)
+    }
+}
+
+impl fmt::Display for ExecutionStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Unknown => "Unknown",
+            Self::NotRun => "Not run",
+            Self::Executing => "Executing",
+            Self::Succeeded => "Succeeded",
+            Self::PartiallySucceeded => "Partially succeeded",
+            Self::Failed => "Failed",
+            Self::Canceled => "Canceled",
+        })
+    }
+}
+
+/// An `ImportFromPackage` call
+#[derive(Debug, Clone)]
+pub struct PackageImport<'a> {
+    /// The blob URL the package was uploaded to
+    pub package_url: &'a str,
+    /// Data project (definition group) whose entity mappings are used
+    pub definition_group: &'a str,
+    /// Id for the new execution; the service makes one up when empty
+    pub execution_id: &'a str,
+    /// Run the import now rather than only staging it
+    pub execute: bool,
+    /// Replace the data project's existing mappings with the package's
+    pub overwrite: bool,
+    /// Company (`dataAreaId`) the records are imported into
+    pub legal_entity: &'a str,
+}
+
+/// The `value` string of an action's response
+fn string_value(action: &str, response: &Value) -> Result<String, ODataError> {
+    response["value"].as_str().map(String::from).ok_or_else(|| {
+        ODataError::ParseError(format!("{} returned no value: {}", action, response))
+    })
+}
+
+/// `GetAzureWriteUrl` answers with a JSON object serialized into a string
+fn parse_write_url(value: &str) -> Result<BlobWriteUrl, ODataError> {
+    let parsed: Value = serde_json::from_str(value).map_err(|e| {
+        ODataError::ParseError(format!("GetAzureWriteUrl returned no JSON object: {}", e))
+    })?;
+    let field = |name: &str| {
+        parsed[name]
+            .as_str()
+            .filter(|value| !value.is_empty())
+            .map(String::from)
+            .ok_or_else(|| ODataError::ParseError(format!("GetAzureWriteUrl returned no {}", name)))
+    };
+    Ok(BlobWriteUrl {
+        blob_id: field("BlobId")?,
+        blob_url: field("BlobUrl")?,
+    })
+}
+
+impl ODataClient {
+    async fn dmf_action(&self, action: &str, params: Value) -> Result<Value, ODataError> {
+        self.execute_action(&format!("{}{}", ACTIONS, action), &params)
+            .await
+    }
+
+    /// A blob URL to upload the package named `file_name` to
+    pub async fn dmf_write_url(&self, file_name: &str) -> Result<BlobWriteUrl, ODataError> {
+        let response = self
+            .dmf_action("GetAzureWriteUrl", json!({"uniqueFileName": file_name}))
+            .await?;
+        parse_write_url(&string_value("GetAzureWriteUrl", &response)?)
+    }
+
+    /// Upload a zipped data package to the blob URL from [`Self::dmf_write_url`]
+    pub async fn upload_package(&self, blob_url: &str, package: &[u8]) -> Result<(), ODataError> {
+        self.put_block_blob(blob_url, package, "application/zip")
+            .await
+    }
+
+    /// Queue the import of an uploaded package; returns the execution id
+    pub async fn import_from_package(
+        &self,
+        import: &PackageImport<'_>,
+    ) -> Result<String, ODataError> {
+        let response = self
+            .dmf_action(
+                "ImportFromPackage",
+                json!({
+                    "packageUrl": import.package_url,
+                    "definitionGroupId": import.definition_group,
+                    "executionId": import.execution_id,
+                    "execute": import.execute,
+                    "overwrite": import.overwrite,
+                    "legalEntityId": import.legal_entity,
+                }),
+            )
+            .await?;
+        string_value("ImportFromPackage", &response)
+    }
+
+    /// Current state of an execution
+    pub async fn execution_status(
+        &self,
+        execution_id: &str,
+    ) -> Result<ExecutionStatus, ODataError> {
+        let response = self
+            .dmf_action(
+                "GetExecutionSummaryStatus",
+                json!({"executionId": execution_id}),
+            )
+            .await?;
+        ExecutionStatus::from_value(&response["value"]).ok_or_else(|| {
+            ODataError::ParseError(format!(
+                "GetExecutionSummaryStatus returned an unknown status: {}",
+                response["value"]
+            ))
+        })
+    }
+
+    /// Poll an execution every `interval` until it finishes or `wait` has
+    /// passed, returning the last status. Polling also stops when the next
+    /// poll would not fit before the call's deadline.
+    pub async fn wait_for_execution(
+        &self,
+        execution_id: &str,
+        wait: Duration,
+        interval: Duration,
+    ) -> Result<ExecutionStatus, ODataError> {
+        let started = Instant::now();
+        let deadline = Deadline::current_or(wait);
+        loop {
+            let status = self.execution_status(execution_id).await?;
+            let next_poll = started.elapsed() + interval;
+            if status.is_finished() || next_poll > wait || interval >= deadline.remaining() {
+                return Ok(status);
+            }
+            report_progress(&format!(
+                "Execution {}: {} after {}s of {}s",
+                execution_id,
+                status,
+                started.elapsed().as_secs(),
+                wait.as_secs()
+            ));
+            cancellable(tokio::time::sleep(interval)).await?;
+        }
+    }
+
+    /// Download URL of the staging error log of `entity` (the data entity's
+    /// label, e.g. `Customers V3`) in an execution, when it has one
+    pub async fn staging_error_file_url(
+        &self,
+        execution_id: &str,
+        entity: &str,
+    ) -> Result<Option<String>, ODataError> {
+        let response = self
+            .dmf_action(
+                "GetImportStagingErrorFileUrl",
+                json!({"executionId": execution_id, "entityName": entity}),
+            )
+            .await?;
+        Ok(response["value"]
+            .as_str()
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(String::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn execution_states_are_read_from_numbers_or_names() {
+        assert_eq!(
+            ExecutionStatus::from_value(&json!(3)),
+            Some(ExecutionStatus::Succeeded)
+        );
+        assert_eq!(
+            ExecutionStatus::from_value(&json!("5")),
+            Some(ExecutionStatus::Failed)
+        );
+        assert_eq!(
+            ExecutionStatus::from_value(&json!("PartiallySucceeded")),
+            Some(ExecutionStatus::PartiallySucceeded)
+        );
+        assert_eq!(ExecutionStatus::from_value(&json!(7)), None);
+        assert_eq!(ExecutionStatus::from_value(&json!("Done")), None);
+
+        assert_eq!(ExecutionStatus::NotRun.to_string(), "Not run");
+        assert!(!ExecutionStatus::Executing.is_finished());
+        assert!(ExecutionStatus::Canceled.is_finished());
+        assert!(ExecutionStatus::PartiallySucceeded.has_errors());
+        assert!(!ExecutionStatus::Succeeded.has_errors());
+    }
+
+    #[test]
+    fn write_urls_are_parsed_from_the_serialized_value() {
+        let response = json!({
+            "@odata.context": "https://usnconeboxax1aos.cloud.onebox.dynamics.com/data/$metadata#Edm.String",
+            "value": "{\"BlobId\":\"{A8A5C1E8-0E5B-4E0B-8D8B-3C1C2B0F9E11}\",\"BlobUrl\":\"https://store.blob.core.windows.net/dmf/p.zip?sv=2014-02-14&sig=abc\"}"
+        });
+        let url = parse_write_url(&string_value("GetAzureWriteUrl", &response).unwrap()).unwrap();
+
+        assert_eq!(url.blob_id, "{A8A5C1E8-0E5B-4E0B-8D8B-3C1C2B0F9E11}");
+        assert_eq!(
+            url.blob_url,
+            "https://store.blob.core.windows.net/dmf/p.zip?sv=2014-02-14&sig=abc"
+        );
+        assert!(parse_write_url("{\"BlobId\":\"x\"}").is_err());
+        assert!(string_value("GetAzureWriteUrl", &json!({})).is_err());
+    }
+
+    #[tokio::test]
+    async fn packages_upload_without_the_token_and_executions_are_polled() {
+        use crate::auth::StaticTokenProvider;
+        use crate::config::ProductType;
+        use std::sync::Arc;
+        use wiremock::matchers::{body_json, header, method, path};
+        use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        let client = ODataClient::new(
+            Arc::new(StaticTokenProvider::new("test-token")),
+            format!("{}/data/", server.uri()),
+            ProductType::Finops,
+            3,
+            10,
+            false,
+        );
+        let actions = format!("/data/{}", ACTIONS);
+        Mock::given(method("PUT"))
+            .and(path("/blob/package.zip"))
+            .and(header("x-ms-blob-type", "BlockBlob"))
+            .and(|request: &Request| !request.headers.contains_key("authorization"))
+            .respond_with(ResponseTemplate::new(201))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(format!("{}ImportFromPackage", actions)))
+            .and(body_json(json!({
+                "packageUrl": format!("{}/blob/package.zip?sig=abc", server.uri()),
+                "definitionGroupId": "Customers",
+                "executionId": "",
+                "execute": true,
+                "overwrite": true,
+                "legalEntityId": "usmf"
+            })))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({"value": "Customers-2026"})),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(format!("{}GetExecutionSummaryStatus", actions)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": "Executing"})))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(format!("{}GetExecutionSummaryStatus", actions)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": 5})))
+            .mount(&server)
+            .await;
+
+        let blob_url = format!("{}/blob/package.zip?sig=abc", server.uri());
+        client
+            .upload_package(&blob_url, b"PK\x03\x04")
+            .await
+            .unwrap();
+        let execution_id = client
+            .import_from_package(&PackageImport {
+                package_url: &blob_url,
+                definition_group: "Customers",
+                execution_id: "",
+                execute: true,
+                overwrite: true,
+                legal_entity: "usmf",
+            })
+            .await
+            .unwrap();
+        assert_eq!(execution_id, "Customers-2026");
+
+        let interval = Duration::from_millis(10);
+        let status = client
+            .wait_for_execution(&execution_id, Duration::from_secs(5), interval)
+            .await
+            .unwrap();
+        assert_eq!(status, ExecutionStatus::Failed);
+
+        // Without time for another poll, the first status is returned
+        server.reset().await;
+        Mock::given(method("POST"))
+            .and(path(format!("{}GetExecutionSummaryStatus", actions)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"value": 2})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let status = client
+            .wait_for_execution(&execution_id, Duration::ZERO, interval)
+            .await
+            .unwrap();
+        assert_eq!(status, ExecutionStatus::Executing);
+    }
+}
@@ -0,0 +1,121 @@
+//! Dry runs: requests are built as usual but not sent
+//!
+//! Inside [`with_dry_run`] the client records each request it would send and
+//! fails it with [`ODataError::DryRun`](super::ODataError::DryRun) instead of
+//! acquiring a token and calling the service. `$metadata` is the exception:
+//! some writes need it to build their URL or body (whether a navigation
+//! property is a collection, the lookup name of a note), so it is still
+//! downloaded when it is not cached.
+
+use reqwest::Method;
+use serde_json::Value;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+tokio::task_local! {
+    static DRY_RUN: Option<Arc<Mutex<Vec<PreparedRequest>>>>;
+}
+
+/// A request as it would go on the wire, minus the bearer token
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreparedRequest {
+    pub method: Method,
+    /// Full URL with the encoded query string
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    /// JSON body as sent, or a note on the size of a binary one
+    pub body: Option<String>,
+}
+
+impl PreparedRequest {
+    /// Request line, headers and body, in HTTP message layout, with a JSON
+    /// body pretty-printed
+    pub fn describe(&self) -> String {
+        let mut text = format!("{} {}\n", self.method, self.url);
+        for (name, value) in &self.headers {
+            text.push_str(&format!("{}: {}\n", name, value));
+        }
+        if let Some(body) = &self.body {
+            let body = serde_json::from_str::<Value>(body)
+                .and_then(|json| serde_json::to_string_pretty(&json))
+                .unwrap_or_else(|_| body.clone());
+            text.push('\n');
+            text.push_str(&body);
+            text.push('\n');
+        }
+        text
+    }
+}
+
+/// Run `future` without sending any request it makes; returns its output
+/// along with the requests it tried to send, in order
+pub async fn with_dry_run<F: Future>(future: F) -> (F::Output, Vec<PreparedRequest>) {
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let output = DRY_RUN.scope(Some(requests.clone()), future).await;
+    let requests = std::mem::take(&mut *requests.lock().unwrap());
+    (output, requests)
+}
+
+/// Run `future` with its requests sent even inside a dry run
+pub(crate) async fn without_dry_run<F: Future>(future: F) -> F::Output {
+    DRY_RUN.scope(None, future).await
+}
+
+/// Whether the current task is a dry run
+pub fn is_dry_run() -> bool {
+    DRY_RUN.try_with(Option::is_some).unwrap_or(false)
+}
+
+/// Keep `request` if this is a dry run; `true` means it must not be sent
+pub(crate) fn record_dry_run(request: &PreparedRequest) -> bool {
+    DRY_RUN
+        .try_with(|requests| match requests {
+            Some(requests) => {
+                requests.lock().unwrap().push(request.clone());
+                true
+            }
+            None => false,
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn requests_are_recorded_only_inside_a_dry_run() {
+        let request = PreparedRequest {
+            method: Method::DELETE,
+            url: "https://org.crm.dynamics.com/api/data/v9.2/accounts(1)".to_string(),
+            headers: vec![("If-Match".to_string(), "*".to_string())],
+            body: None,
+        };
+        assert!(!record_dry_run(&request));
+
+        let (inner, requests) = with_dry_run(async {
+            assert!(is_dry_run());
+            let recorded = record_dry_run(&request);
+            let sent = without_dry_run(async { record_dry_run(&request) }).await;
+            (recorded, sent)
+        })
+        .await;
+        assert_eq!(inner, (true, false));
+        assert_eq!(requests, vec![request.clone()]);
+        assert_eq!(
+            request.describe(),
+            "DELETE https://org.crm.dynamics.com/api/data/v9.2/accounts(1)\nIf-Match: *\n"
+        );
+
+        let create = PreparedRequest {
+            method: Method::POST,
+            url: "https://org.crm.dynamics.com/api/data/v9.2/accounts".to_string(),
+            headers: Vec::new(),
+            body: Some(r#"{"name":"Contoso"}"#.to_string()),
+        };
+        assert_eq!(
+            create.describe(),
+            "POST https://org.crm.dynamics.com/api/data/v9.2/accounts\n\n{\n  \"name\": \"Contoso\"\n}\n"
+        );
+    }
+}
@@ -0,0 +1,452 @@
+//! OData filter builder
+//!
+//! Typed construction of `$filter` expressions so values are always quoted,
+//! escaped and formatted the way the D365 OData endpoints expect.
+
+use crate::config::config::ProductType;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A typed literal used on the right-hand side of a filter comparison
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterValue {
+    String(String),
+    /// GUID literal - bare for Dataverse, quoted for F&O
+    Guid(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    /// Rendered as an ISO 8601 UTC timestamp, e.g. `2024-08-21T07:28:00Z`
+    DateTime(SystemTime),
+    Null,
+}
+
+impl FilterValue {
+    /// Create a GUID literal
+    pub fn guid(value: impl Into<String>) -> Self {
+        FilterValue::Guid(value.into())
+    }
+
+    /// Render the literal for the given product
+    pub fn render(&self, product: &ProductType) -> String {
+        match self {
+            FilterValue::String(s) => quote_string(s),
+            FilterValue::Guid(g) => {
+                let g = g.trim().trim_matches(['{', '}', '\'']);
+                match product {
+                    ProductType::Dataverse => g.to_string(),
+                    ProductType::Finops => format!("'{}'", g),
+                }
+            }
+            FilterValue::Int(n) => n.to_string(),
+            FilterValue::Float(f) => f.to_string(),
+            FilterValue::Bool(b) => b.to_string(),
+            FilterValue::DateTime(t) => format_iso8601(*t),
+            FilterValue::Null => "null".to_string(),
+        }
+    }
+}
+
+impl From<&str> for FilterValue {
+    fn from(value: &str) -> Self {
+        FilterValue::String(value.to_string())
+    }
+}
+
+impl From<String> for FilterValue {
+    fn from(value: String) -> Self {
+        FilterValue::String(value)
+    }
+}
+
+impl From<i64> for FilterValue {
+    fn from(value: i64) -> Self {
+        FilterValue::Int(value)
+    }
+}
+
+impl From<i32> for FilterValue {
+    fn from(value: i32) -> Self {
+        FilterValue::Int(value as i64)
+    }
+}
+
+impl From<f64> for FilterValue {
+    fn from(value: f64) -> Self {
+        FilterValue::Float(value)
+    }
+}
+
+impl From<bool> for FilterValue {
+    fn from(value: bool) -> Self {
+        FilterValue::Bool(value)
+    }
+}
+
+impl From<SystemTime> for FilterValue {
+    fn from(value: SystemTime) -> Self {
+        FilterValue::DateTime(value)
+    }
+}
+
+/// Comparison operators supported by OData `$filter`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl fmt::Display for CompareOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self {
+            CompareOp::Eq => "eq",
+            CompareOp::Ne => "ne",
+            CompareOp::Gt => "gt",
+            CompareOp::Ge => "ge",
+            CompareOp::Lt => "lt",
+            CompareOp::Le => "le",
+        };
+        f.write_str(op)
+    }
+}
+
+/// A filter expression tree
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterExpr {
+    Compare {
+        field: String,
+        op: CompareOp,
+        value: FilterValue,
+    },
+    /// String functions such as `contains(Name,'Corp')`
+    Function {
+        name: &'static str,
+        field: String,
+        value: FilterValue,
+    },
+    And(Box<FilterExpr>, Box<FilterExpr>),
+    Or(Box<FilterExpr>, Box<FilterExpr>),
+    Not(Box<FilterExpr>),
+}
+
+impl FilterExpr {
+    /// Combine with another expression using `and`
+    pub fn and(self, other: FilterExpr) -> FilterExpr {
+        FilterExpr::And(Box::new(self), Box::new(other))
+    }
+
+    /// Combine with another expression using `or`
+    pub fn or(self, other: FilterExpr) -> FilterExpr {
+        FilterExpr::Or(Box::new(self), Box::new(other))
+    }
+
+    /// Render the expression as an OData `$filter` value
+    pub fn render(&self, product: &ProductType) -> String {
+        match self {
+            FilterExpr::Compare { field, op, value } => {
+                format!("{} {} {}", field, op, value.render(product))
+            }
+            FilterExpr::Function { name, field, value } => {
+                format!("{}({},{})", name, field, value.render(product))
+            }
+            FilterExpr::And(left, right) => format!(
+                "{} and {}",
+                left.render_operand(product),
+                right.render_operand(product)
+            ),
+            FilterExpr::Or(left, right) => format!(
+                "{} or {}",
+                left.render_operand(product),
+                right.render_operand(product)
+            ),
+            FilterExpr::Not(inner) => format!("not ({})", inner.render(product)),
+        }
+    }
+
+    /// Render as an operand of `and`/`or`, parenthesizing compound expressions
+    fn render_operand(&self, product: &ProductType) -> String {
+        match self {
+            FilterExpr::And(..) | FilterExpr::Or(..) => format!("({})", self.render(product)),
+            _ => self.render(product),
+        }
+    }
+}
+
+/// Entry points for building filter expressions
+///
+/// ```
+/// use d365_odata_mcp::odata::filter::FilterBuilder;
+/// use d365_odata_mcp::ProductType;
+///
+/// let filter = FilterBuilder::eq("dataAreaId", "usmf")
+///     .and(FilterBuilder::contains("Name", "O'Brien"));
+/// assert_eq!(
+///     filter.render(&ProductType::Finops),
+///     "dataAreaId eq 'usmf' and contains(Name,'O''Brien')"
+/// );
+/// ```
+pub struct FilterBuilder;
+
+impl FilterBuilder {
+    pub fn eq(field: impl Into<String>, value: impl Into<FilterValue>) -> FilterExpr {
+        Self::compare(field, CompareOp::Eq, value)
+    }
+
+    pub fn ne(field: impl Into<String>, value: impl Into<FilterValue>) -> FilterExpr {
+        Self::compare(field, CompareOp::Ne, value)
+    }
+
+    pub fn gt(field: impl Into<String>, value: impl Into<FilterValue>) -> FilterExpr {
+        Self::compare(field, CompareOp::Gt, value)
+    }
+
+    pub fn ge(field: impl Into<String>, value: impl Into<FilterValue>) -> FilterExpr {
+        Self::compare(field, CompareOp::Ge, value)
+    }
+
+    pub fn lt(field: impl Into<String>, value: impl Into<FilterValue>) -> FilterExpr {
+        Self::compare(field, CompareOp::Lt, value)
+    }
+
+    pub fn le(field: impl Into<String>, value: impl Into<FilterValue>) -> FilterExpr {
+        Self::compare(field, CompareOp::Le, value)
+    }
+
+    pub fn contains(field: impl Into<String>, value: impl Into<String>) -> FilterExpr {
+        Self::function("contains", field, value)
+    }
+
+    pub fn startswith(field: impl Into<String>, value: impl Into<String>) -> FilterExpr {
+        Self::function("startswith", field, value)
+    }
+
+    pub fn endswith(field: impl Into<String>, value: impl Into<String>) -> FilterExpr {
+        Self::function("endswith", field, value)
+    }
+
+    pub fn and(left: FilterExpr, right: FilterExpr) -> FilterExpr {
+        left.and(right)
+    }
+
+    pub fn or(left: FilterExpr, right: FilterExpr) -> FilterExpr {
+        left.or(right)
+    }
+
+    pub fn not(expr: FilterExpr) -> FilterExpr {
+        FilterExpr::Not(Box::new(expr))
+    }
+
+    fn compare(
+        field: impl Into<String>,
+        op: CompareOp,
+        value: impl Into<FilterValue>,
+    ) -> FilterExpr {
+        FilterExpr::Compare {
+            field: field.into(),
+            op,
+            value: value.into(),
+        }
+    }
+
+    fn function(
+        name: &'static str,
+        field: impl Into<String>,
+        value: impl Into<String>,
+    ) -> FilterExpr {
+        FilterExpr::Function {
+            name,
+            field: field.into(),
+            value: FilterValue::String(value.into()),
+        }
+    }
+}
+
+/// Check a raw `$filter` string for unbalanced quotes and parentheses
+///
+/// Returns a human-readable description of the first problem found so the
+/// caller can report it instead of sending the request and getting a 400.
+pub fn validate_filter(filter: &str) -> Result<(), String> {
+    let mut depth: usize = 0;
+    let mut in_string = false;
+    let mut string_start = 0;
+    let mut chars = filter.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        if in_string {
+            if c == '\'' {
+                // '' is an escaped quote inside a string literal
+                if matches!(chars.peek(), Some((_, '\''))) {
+                    chars.next();
+                } else {
+                    in_string = false;
+                }
+            }
+            continue;
+        }
+
+        match c {
+            '\'' => {
+                in_string = true;
+                string_start = i;
+            }
+            '(' => depth += 1,
+            ')' => {
+                if depth == 0 {
+                    return Err(format!(
+                        "Unbalanced parentheses in filter: unexpected ')' at position {}",
+                        i
+                    ));
+                }
+                depth -= 1;
+            }
+            _ => {}
+        }
+    }
+
+    if in_string {
+        return Err(format!(
+            "Unterminated string literal in filter starting at position {}. \
+             Escape single quotes inside values by doubling them, e.g. 'O''Brien'",
+            string_start
+        ));
+    }
+
+    if depth > 0 {
+        return Err(format!(
+            "Unbalanced parentheses in filter: {} unclosed '('",
+            depth
+        ));
+    }
+
+    Ok(())
+}
+
+/// Quote a string literal, doubling embedded single quotes
+fn quote_string(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Format a timestamp as ISO 8601 in UTC (second precision)
+fn format_iso8601(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let days = secs.div_euclid(86_400);
+    let rem = secs.rem_euclid(86_400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+/// Convert days since 1970-01-01 to a (year, month, day) civil date
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn string_values_are_quoted_and_escaped() {
+        let expr = FilterBuilder::eq("Name", "O'Brien & Sons");
+        assert_eq!(
+            expr.render(&ProductType::Dataverse),
+            "Name eq 'O''Brien & Sons'"
+        );
+    }
+
+    #[test]
+    fn guid_quoting_depends_on_product() {
+        let expr = FilterBuilder::eq(
+            "_parentcustomerid_value",
+            FilterValue::guid("00000000-0000-0000-0000-000000000001"),
+        );
+        assert_eq!(
+            expr.render(&ProductType::Dataverse),
+            "_parentcustomerid_value eq 00000000-0000-0000-0000-000000000001"
+        );
+        assert_eq!(
+            expr.render(&ProductType::Finops),
+            "_parentcustomerid_value eq '00000000-0000-0000-0000-000000000001'"
+        );
+    }
+
+    #[test]
+    fn scalar_values_render_unquoted() {
+        assert_eq!(
+            FilterBuilder::eq("IsActive", true).render(&ProductType::Finops),
+            "IsActive eq true"
+        );
+        assert_eq!(
+            FilterBuilder::ge("Amount", 1000).render(&ProductType::Finops),
+            "Amount ge 1000"
+        );
+        assert_eq!(
+            FilterBuilder::ne("ParentId", FilterValue::Null).render(&ProductType::Dataverse),
+            "ParentId ne null"
+        );
+    }
+
+    #[test]
+    fn datetimes_render_as_iso8601() {
+        // 2024-08-21T07:28:00Z
+        let time = UNIX_EPOCH + Duration::from_secs(1_724_225_280);
+        assert_eq!(
+            FilterBuilder::gt("modifiedon", time).render(&ProductType::Dataverse),
+            "modifiedon gt 2024-08-21T07:28:00Z"
+        );
+    }
+
+    #[test]
+    fn compound_expressions_are_parenthesized() {
+        let expr = FilterBuilder::eq("dataAreaId", "usmf").and(FilterBuilder::or(
+            FilterBuilder::startswith("Name", "A"),
+            FilterBuilder::not(FilterBuilder::contains("Name", "test")),
+        ));
+        assert_eq!(
+            expr.render(&ProductType::Finops),
+            "dataAreaId eq 'usmf' and (startswith(Name,'A') or not (contains(Name,'test')))"
+        );
+    }
+
+    #[test]
+    fn validate_filter_accepts_well_formed_input() {
+        assert!(validate_filter("Name eq 'O''Brien' and (Amount gt 5)").is_ok());
+        assert!(validate_filter("contains(Name,'(draft')").is_ok());
+    }
+
+    #[test]
+    fn validate_filter_reports_unbalanced_quotes_and_parens() {
+        let err = validate_filter("Name eq 'O'Brien'").unwrap_err();
+        assert!(err.contains("Unterminated string literal"));
+
+        let err = validate_filter("(Status eq 'Open'").unwrap_err();
+        assert!(err.contains("unclosed"));
+
+        let err = validate_filter("Status eq 'Open')").unwrap_err();
+        assert!(err.contains("unexpected ')'"));
+    }
+}
@@ -0,0 +1,94 @@
+//! Dataverse caller impersonation
+//!
+//! Dataverse attributes a request to another user when it carries an
+//! `MSCRMCallerID` (systemuserid) or `CallerObjectId` (Entra object id)
+//! header. The client adds the configured caller to every Dataverse request;
+//! a tool call can override it by running inside [`with_caller`]. F&O has no
+//! equivalent, so the header is never sent there.
+
+use serde::Deserialize;
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+
+tokio::task_local! {
+    static CALLER: String;
+}
+
+/// Which header identifies the impersonated user
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CallerIdHeader {
+    /// `MSCRMCallerID`: the user's `systemuserid`
+    #[default]
+    SystemUserId,
+    /// `CallerObjectId`: the user's Entra ID object id
+    ObjectId,
+}
+
+impl CallerIdHeader {
+    pub fn header_name(self) -> &'static str {
+        match self {
+            CallerIdHeader::SystemUserId => "MSCRMCallerID",
+            CallerIdHeader::ObjectId => "CallerObjectId",
+        }
+    }
+}
+
+impl fmt::Display for CallerIdHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.header_name())
+    }
+}
+
+impl FromStr for CallerIdHeader {
+    type Err = String;
+
+    /// Accepts the config names and the header names, case-insensitively
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "system_user_id" | "mscrmcallerid" => Ok(CallerIdHeader::SystemUserId),
+            "object_id" | "callerobjectid" => Ok(CallerIdHeader::ObjectId),
+            other => Err(format!(
+                "Invalid impersonation header '{}': expected 'system_user_id' (MSCRMCallerID) or 'object_id' (CallerObjectId)",
+                other
+            )),
+        }
+    }
+}
+
+/// Run `future` with `user_id` impersonated on the Dataverse calls it makes
+pub async fn with_caller<F: Future>(user_id: String, future: F) -> F::Output {
+    CALLER.scope(user_id, future).await
+}
+
+/// The user impersonated by the current tool call, if it overrides the default
+pub(crate) fn caller_override() -> Option<String> {
+    CALLER.try_with(String::clone).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn override_applies_only_inside_the_scope() {
+        assert_eq!(caller_override(), None);
+        let inside = with_caller("user-1".to_string(), async { caller_override() }).await;
+        assert_eq!(inside.as_deref(), Some("user-1"));
+        assert_eq!(caller_override(), None);
+    }
+
+    #[test]
+    fn header_kind_parses_config_and_header_names() {
+        assert_eq!(
+            "MSCRMCallerID".parse::<CallerIdHeader>().unwrap(),
+            CallerIdHeader::SystemUserId
+        );
+        assert_eq!(
+            "object_id".parse::<CallerIdHeader>().unwrap(),
+            CallerIdHeader::ObjectId
+        );
+        assert!("upn".parse::<CallerIdHeader>().is_err());
+    }
+}
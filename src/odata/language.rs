@@ -0,0 +1,180 @@
+//! Label language
+//!
+//! The client sends the configured language as `Accept-Language` on data and
+//! `$metadata` requests, so F&O returns labels and descriptions in it. A tool
+//! call can ask for another language by running inside [`with_language`].
+//! Dataverse renders formatted values in the calling user's UI language
+//! whatever the header says; its option set definitions carry every installed
+//! language, and [`localized_label`] picks the configured one by LCID.
+
+use serde_json::Value;
+use std::future::Future;
+
+tokio::task_local! {
+    static LANGUAGE: String;
+}
+
+/// Windows locale ids (LCIDs) Dataverse labels its languages with; a bare
+/// language such as `de` takes the first entry for it
+const LANGUAGE_CODES: &[(&str, u32)] = &[
+    ("en-US", 1033),
+    ("en-GB", 2057),
+    ("de-DE", 1031),
+    ("de-AT", 3079),
+    ("de-CH", 2055),
+    ("fr-FR", 1036),
+    ("fr-CA", 3084),
+    ("es-ES", 3082),
+    ("es-MX", 2058),
+    ("it-IT", 1040),
+    ("nl-NL", 1043),
+    ("pt-BR", 1046),
+    ("pt-PT", 2070),
+    ("da-DK", 1030),
+    ("sv-SE", 1053),
+    ("nb-NO", 1044),
+    ("fi-FI", 1035),
+    ("pl-PL", 1045),
+    ("cs-CZ", 1029),
+    ("hu-HU", 1038),
+    ("ru-RU", 1049),
+    ("tr-TR", 1055),
+    ("ja-JP", 1041),
+    ("ko-KR", 1042),
+    ("zh-CN", 2052),
+    ("zh-TW", 1028),
+];
+
+/// A BCP 47 language tag such as `de-DE` or `de`, trimmed
+pub fn parse_language_tag(tag: &str) -> Result<String, String> {
+    let tag = tag.trim();
+    let mut subtags = tag.split('-');
+    let primary = subtags.next().unwrap_or_default();
+    let valid = (2..=3).contains(&primary.len())
+        && primary.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+        });
+    if valid {
+        Ok(tag.to_string())
+    } else {
+        Err(format!(
+            "Invalid language '{}': expected a language tag such as 'de-DE' or 'de'",
+            tag
+        ))
+    }
+}
+
+/// `tag` spelled as Dataverse ships it, e.g. `de-ch` as `de-CH`, or its bare
+/// language such as `de`; `None` for any other tag
+pub fn known_language(tag: &str) -> Option<String> {
+    if let Some((known, _)) = LANGUAGE_CODES
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(tag))
+    {
+        return Some(known.to_string());
+    }
+    LANGUAGE_CODES
+        .iter()
+        .filter_map(|(known, _)| known.split('-').next())
+        .find(|language| language.eq_ignore_ascii_case(tag))
+        .map(String::from)
+}
+
+/// The LCID of a language tag, if it is one Dataverse ships
+pub fn language_code(tag: &str) -> Option<u32> {
+    let exact = LANGUAGE_CODES
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(tag));
+    let primary = tag.split('-').next().unwrap_or_default();
+    exact
+        .or_else(|| {
+            LANGUAGE_CODES.iter().find(|(known, _)| {
+                known
+                    .split('-')
+                    .next()
+                    .is_some_and(|language| language.eq_ignore_ascii_case(primary))
+            })
+        })
+        .map(|(_, code)| *code)
+}
+
+/// Text of a Dataverse `Label`: its `LocalizedLabels` entry for
+/// `language_code`, else the `UserLocalizedLabel`
+pub fn localized_label(label: &Value, language_code: Option<u32>) -> Option<&str> {
+    let localized = language_code.and_then(|code| {
+        label
+            .get("LocalizedLabels")?
+            .as_array()?
+            .iter()
+            .find(|localized| {
+                localized.get("LanguageCode").and_then(Value::as_u64) == Some(code.into())
+            })?
+            .get("Label")?
+            .as_str()
+    });
+    localized.or_else(|| label.pointer("/UserLocalizedLabel/Label")?.as_str())
+}
+
+/// Run `future` with its requests asking for labels in `language`
+pub async fn with_language<F: Future>(language: String, future: F) -> F::Output {
+    LANGUAGE.scope(language, future).await
+}
+
+/// The language asked for by the current tool call, if it overrides the default
+pub(crate) fn language_override() -> Option<String> {
+    LANGUAGE.try_with(String::clone).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn language_tags_are_checked_and_mapped_to_lcids() {
+        assert_eq!(parse_language_tag(" de-DE ").unwrap(), "de-DE");
+        assert_eq!(parse_language_tag("zh-Hans-CN").unwrap(), "zh-Hans-CN");
+        assert!(parse_language_tag("en_US:en").is_err());
+        assert!(parse_language_tag("german").is_err());
+
+        assert_eq!(language_code("de-DE"), Some(1031));
+        assert_eq!(language_code("de-ch"), Some(2055));
+        assert_eq!(language_code("de"), Some(1031));
+        assert_eq!(language_code("xx-YY"), None);
+
+        assert_eq!(known_language("de-ch").as_deref(), Some("de-CH"));
+        assert_eq!(known_language("DE").as_deref(), Some("de"));
+        assert_eq!(known_language("de-XX"), None);
+        assert_eq!(known_language("aa"), None);
+    }
+
+    #[test]
+    fn labels_prefer_the_requested_language() {
+        let label = json!({
+            "LocalizedLabels": [
+                {"Label": "Preferred Customer", "LanguageCode": 1033},
+                {"Label": "Bevorzugter Kunde", "LanguageCode": 1031}
+            ],
+            "UserLocalizedLabel": {"Label": "Preferred Customer", "LanguageCode": 1033}
+        });
+        assert_eq!(
+            localized_label(&label, Some(1031)),
+            Some("Bevorzugter Kunde")
+        );
+        // A language without a label, or none asked for, gets the user's
+        assert_eq!(
+            localized_label(&label, Some(1036)),
+            Some("Preferred Customer")
+        );
+        assert_eq!(localized_label(&label, None), Some("Preferred Customer"));
+    }
+
+    #[tokio::test]
+    async fn override_applies_only_inside_the_scope() {
+        assert_eq!(language_override(), None);
+        let inside = with_language("fr-FR".to_string(), async { language_override() }).await;
+        assert_eq!(inside.as_deref(), Some("fr-FR"));
+        assert_eq!(language_override(), None);
+    }
+}
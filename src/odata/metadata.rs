@@ -0,0 +1,1110 @@
+//! `$metadata` element scanning
+//!
+//! Works on the raw EDMX tag by tag rather than line by line, since some
+//! environments serve the document without line breaks.
+
+use super::client::{EntityKey, ODataClient, ODataError, QueryOptions};
+use super::language::localized_label;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+/// An `EnumType` and its members as `(name, value)`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EnumTypeInfo {
+    /// Unqualified type name, e.g. `SalesStatus`
+    pub name: String,
+    /// Namespace of the declaring schema, e.g. `Microsoft.Dynamics.DataEntities`
+    pub namespace: String,
+    pub members: Vec<(String, i64)>,
+}
+
+impl EnumTypeInfo {
+    /// Member name for a numeric value
+    pub fn member_name(&self, value: i64) -> Option<&str> {
+        self.members
+            .iter()
+            .find(|(_, member_value)| *member_value == value)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Filter literal for a member, e.g. `Microsoft.Dynamics.DataEntities.SalesStatus'Invoiced'`
+    pub fn literal(&self, member: &str) -> String {
+        if self.namespace.is_empty() {
+            format!("{}'{}'", self.name, member)
+        } else {
+            format!("{}.{}'{}'", self.namespace, self.name, member)
+        }
+    }
+}
+
+/// Start tags and end tags of the document, without the angle brackets
+pub(crate) fn tags(xml: &str) -> impl Iterator<Item = &str> {
+    xml.split('<')
+        .skip(1)
+        .filter_map(|chunk| chunk.split_once('>').map(|(tag, _)| tag))
+}
+
+/// Value of attribute `name` in a start tag
+pub(crate) fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let marker = format!(" {}=\"", name);
+    let start = tag.find(&marker)? + marker.len();
+    let end = tag[start..].find('"')?;
+    Some(&tag[start..start + end])
+}
+
+/// Edit distance between two strings
+pub(crate) fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// Last segment of a qualified name, without any `Collection(...)` wrapper
+pub(crate) fn unqualified(type_name: &str) -> &str {
+    let inner = type_name
+        .strip_prefix("Collection(")
+        .and_then(|rest| rest.strip_suffix(')'))
+        .unwrap_or(type_name);
+    inner.rsplit('.').next().unwrap_or(inner)
+}
+
+/// Qualified names of the entity containers, by schema namespace and by
+/// alias, as `Annotations Target` paths start with them
+fn entity_containers(metadata_xml: &str) -> Vec<String> {
+    let mut containers = Vec::new();
+    let mut qualifiers: Vec<&str> = Vec::new();
+
+    for tag in tags(metadata_xml) {
+        if tag.starts_with("Schema ") {
+            qualifiers = ["Namespace", "Alias"]
+                .into_iter()
+                .filter_map(|name| attribute(tag, name))
+                .collect();
+        } else if tag.starts_with("EntityContainer ") {
+            if let Some(name) = attribute(tag, "Name") {
+                containers.extend(
+                    qualifiers
+                        .iter()
+                        .map(|qualifier| format!("{}.{}", qualifier, name)),
+                );
+            }
+        }
+    }
+    containers
+}
+
+/// Every `EnumType` in the document, in document order
+pub fn parse_enum_types(metadata_xml: &str) -> Vec<EnumTypeInfo> {
+    let mut enums = Vec::new();
+    let mut current: Option<EnumTypeInfo> = None;
+    let mut namespace = "";
+
+    for tag in tags(metadata_xml) {
+        if tag.starts_with("Schema ") {
+            namespace = attribute(tag, "Namespace").unwrap_or_default();
+        } else if tag.starts_with("EnumType ") {
+            if let Some(name) = attribute(tag, "Name") {
+                let info = EnumTypeInfo {
+                    name: name.to_string(),
+                    namespace: namespace.to_string(),
+                    members: Vec::new(),
+                };
+                // A self-closing EnumType has no members
+                if tag.ends_with('/') {
+                    enums.push(info);
+                } else {
+                    current = Some(info);
+                }
+            }
+        } else if tag.starts_with("Member ") {
+            if let Some(info) = current.as_mut() {
+                if let Some(name) = attribute(tag, "Name") {
+                    // Members without an explicit value are numbered from 0
+                    let value = attribute(tag, "Value")
+                        .and_then(|v| v.parse().ok())
+                        .unwrap_or(info.members.len() as i64);
+                    info.members.push((name.to_string(), value));
+                }
+            }
+        } else if tag.starts_with("/EnumType") {
+            enums.extend(current.take());
+        }
+    }
+
+    enums
+}
+
+/// A typed property of an entity type
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PropertyInfo {
+    pub name: String,
+    /// Type as declared, e.g. `Edm.String` or `Microsoft.Dynamics.DataEntities.SalesStatus`
+    pub type_name: String,
+}
+
+/// A `NavigationProperty` and the foreign keys behind it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NavigationInfo {
+    pub name: String,
+    /// Unqualified target entity type
+    pub target_type: String,
+    pub collection: bool,
+    pub partner: Option<String>,
+    /// `ReferentialConstraint`s as (property on this type, property on the target)
+    pub constraints: Vec<(String, String)>,
+}
+
+/// An `EntityType` with its keys, properties and navigation properties
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EntityTypeInfo {
+    pub name: String,
+    pub keys: Vec<String>,
+    pub properties: Vec<PropertyInfo>,
+    pub navigation: Vec<NavigationInfo>,
+    /// Text of a `Core.V1.Description` (or, failing that, label) annotation
+    pub description: Option<String>,
+    /// Unqualified `BaseType`, whose properties this type inherits
+    pub base_type: Option<String>,
+}
+
+/// A change to an entity set's records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteOperation {
+    Insert,
+    Update,
+    Delete,
+}
+
+impl WriteOperation {
+    /// Lower-case name, as in `insert`
+    pub fn name(self) -> &'static str {
+        match self {
+            WriteOperation::Insert => "insert",
+            WriteOperation::Update => "update",
+            WriteOperation::Delete => "delete",
+        }
+    }
+
+    /// `Org.OData.Capabilities.V1` term restricting the operation
+    pub fn restriction_term(self) -> &'static str {
+        match self {
+            WriteOperation::Insert => "InsertRestrictions",
+            WriteOperation::Update => "UpdateRestrictions",
+            WriteOperation::Delete => "DeleteRestrictions",
+        }
+    }
+
+    /// Operation restricted by a capabilities term, and the term's flag
+    fn from_term(term: &str) -> Option<(Self, &'static str)> {
+        match term.rsplit('.').next()? {
+            "InsertRestrictions" => Some((WriteOperation::Insert, "Insertable")),
+            "UpdateRestrictions" => Some((WriteOperation::Update, "Updatable")),
+            "DeleteRestrictions" => Some((WriteOperation::Delete, "Deletable")),
+            _ => None,
+        }
+    }
+}
+
+/// Writes an entity set accepts, from its capabilities annotations; an
+/// operation without a restriction is allowed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteCapabilities {
+    pub insertable: bool,
+    pub updatable: bool,
+    pub deletable: bool,
+}
+
+impl Default for WriteCapabilities {
+    fn default() -> Self {
+        Self {
+            insertable: true,
+            updatable: true,
+            deletable: true,
+        }
+    }
+}
+
+impl WriteCapabilities {
+    pub fn allows(&self, operation: WriteOperation) -> bool {
+        match operation {
+            WriteOperation::Insert => self.insertable,
+            WriteOperation::Update => self.updatable,
+            WriteOperation::Delete => self.deletable,
+        }
+    }
+
+    fn forbid(&mut self, operation: WriteOperation) {
+        match operation {
+            WriteOperation::Insert => self.insertable = false,
+            WriteOperation::Update => self.updatable = false,
+            WriteOperation::Delete => self.deletable = false,
+        }
+    }
+
+    /// e.g. `insert ✔ update ✔ delete ✖`
+    pub fn summary(&self) -> String {
+        [
+            WriteOperation::Insert,
+            WriteOperation::Update,
+            WriteOperation::Delete,
+        ]
+        .iter()
+        .map(|operation| {
+            let mark = if self.allows(*operation) {
+                '✔'
+            } else {
+                '✖'
+            };
+            format!("{} {}", operation.name(), mark)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+    }
+}
+
+/// A field named in a query that the entity type does not declare
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownField {
+    /// Query option it was named in: `select`, `orderby` or `expand`
+    pub option: &'static str,
+    pub name: String,
+    /// Closest declared names, best first
+    pub suggestions: Vec<String>,
+}
+
+/// Suggestions offered for an unknown field
+const MAX_FIELD_SUGGESTIONS: usize = 3;
+
+/// Declared names close to `name`: the same name in another case first,
+/// then likely typos by edit distance
+fn closest_names(name: &str, known: &[&str]) -> Vec<String> {
+    let lowered = name.to_lowercase();
+    let mut ranked: Vec<(usize, &str)> = known
+        .iter()
+        .map(|candidate| (levenshtein(&lowered, &candidate.to_lowercase()), *candidate))
+        .filter(|(distance, candidate)| *distance <= (candidate.len() / 3).max(2))
+        .collect();
+    ranked.sort();
+    ranked.dedup();
+    ranked
+        .into_iter()
+        .take(MAX_FIELD_SUGGESTIONS)
+        .map(|(_, candidate)| candidate.to_string())
+        .collect()
+}
+
+/// Property named at the start of a query path such as `Address/City`,
+/// unless it is a function call, a wildcard or a qualified name
+fn field_root(path: &str) -> Option<&str> {
+    let root = path.trim().split('/').next()?.trim();
+    let plain = !root.is_empty() && root.chars().all(|c| c.is_alphanumeric() || c == '_');
+    plain.then_some(root)
+}
+
+/// Element an `Annotation` describes
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Annotated {
+    Set(String),
+    Type(String),
+}
+
+/// Priority and text of a description or label annotation; descriptions win
+fn description_annotation(tag: &str) -> Option<(u8, String)> {
+    let priority = match attribute(tag, "Term")?.rsplit('.').next()? {
+        "Description" => 2,
+        "Label" => 1,
+        _ => return None,
+    };
+    let text = unescape(attribute(tag, "String")?.trim());
+    (!text.is_empty()).then_some((priority, text))
+}
+
+/// Attribute text with the predefined XML entities replaced
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// The whole `$metadata` document, indexed once so lookups do not rescan the XML;
+/// serializes to the JSON model `dump-metadata` writes, with map keys sorted
+/// so the same document always gives the same file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MetadataModel {
+    /// Entity sets as (set name, unqualified entity type), in document order
+    pub entity_sets: Vec<(String, String)>,
+    /// Entity types by unqualified name
+    #[serde(serialize_with = "sorted")]
+    pub entity_types: HashMap<String, EntityTypeInfo>,
+    pub enums: Vec<EnumTypeInfo>,
+    /// Description annotations on entity sets, by set name
+    #[serde(serialize_with = "sorted")]
+    pub set_descriptions: HashMap<String, String>,
+    /// Capabilities of the entity sets with insert, update or delete
+    /// restrictions, by set name
+    #[serde(default, serialize_with = "sorted")]
+    pub set_capabilities: HashMap<String, WriteCapabilities>,
+}
+
+/// A map serialized in key order rather than hash order
+fn sorted<S: Serializer, V: Serialize>(
+    map: &HashMap<String, V>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
+impl MetadataModel {
+    pub fn parse(metadata_xml: &str) -> Self {
+        let mut model = MetadataModel {
+            enums: parse_enum_types(metadata_xml),
+            ..Default::default()
+        };
+        let mut current: Option<EntityTypeInfo> = None;
+        let mut in_key = false;
+        // Annotations apply to the innermost open EntitySet, EntityType or
+        // `Annotations Target`, but not to properties inside a type
+        let mut open_set: Option<String> = None;
+        let mut targeted: Option<Annotated> = None;
+        let mut in_member = false;
+        let mut descriptions: HashMap<Annotated, (u8, String)> = HashMap::new();
+        // Open restriction annotation on an entity set, with the flag that
+        // turns its operation off
+        let mut restriction: Option<(String, WriteOperation, &str)> = None;
+        let containers = entity_containers(metadata_xml);
+
+        for tag in tags(metadata_xml) {
+            if tag.starts_with("PropertyValue ") {
+                if let Some((set, operation, flag)) = &restriction {
+                    if attribute(tag, "Property") == Some(*flag)
+                        && attribute(tag, "Bool") == Some("false")
+                    {
+                        model
+                            .set_capabilities
+                            .entry(set.clone())
+                            .or_default()
+                            .forbid(*operation);
+                    }
+                }
+            } else if tag.trim_end() == "/Annotation" {
+                restriction = None;
+            } else if tag.starts_with("Annotation ") {
+                let annotated = match (&targeted, &open_set, &current) {
+                    (Some(annotated), _, _) => Some(annotated.clone()),
+                    (None, Some(set), _) => Some(Annotated::Set(set.clone())),
+                    (None, None, Some(entity)) if !in_member => {
+                        Some(Annotated::Type(entity.name.clone()))
+                    }
+                    _ => None,
+                };
+                if let (Some(Annotated::Set(set)), Some((operation, flag))) = (
+                    &annotated,
+                    attribute(tag, "Term").and_then(WriteOperation::from_term),
+                ) {
+                    if !tag.ends_with('/') {
+                        restriction = Some((set.clone(), operation, flag));
+                    }
+                }
+                if let (Some(annotated), Some((priority, text))) =
+                    (annotated, description_annotation(tag))
+                {
+                    if descriptions
+                        .get(&annotated)
+                        .is_none_or(|(seen, _)| priority > *seen)
+                    {
+                        descriptions.insert(annotated, (priority, text));
+                    }
+                }
+            } else if tag.starts_with("Annotations ") {
+                // `Namespace.Type` or `Namespace.Container/Set`; anything
+                // more specific (`Namespace.Type/Property`) is skipped
+                targeted =
+                    attribute(tag, "Target").and_then(|target| match target.split_once('/') {
+                        Some((container, set))
+                            if !set.contains('/')
+                                && containers.iter().any(|name| name == container) =>
+                        {
+                            Some(Annotated::Set(set.to_string()))
+                        }
+                        Some(_) => None,
+                        None => Some(Annotated::Type(unqualified(target).to_string())),
+                    });
+            } else if tag.starts_with("/Annotations") {
+                targeted = None;
+            } else if tag.starts_with("EntitySet ") {
+                if let (Some(name), Some(entity_type)) =
+                    (attribute(tag, "Name"), attribute(tag, "EntityType"))
+                {
+                    model
+                        .entity_sets
+                        .push((name.to_string(), unqualified(entity_type).to_string()));
+                    if !tag.ends_with('/') {
+                        open_set = Some(name.to_string());
+                    }
+                }
+            } else if tag.starts_with("/EntitySet") {
+                open_set = None;
+            } else if tag.starts_with("EntityType ") {
+                current = attribute(tag, "Name").map(|name| EntityTypeInfo {
+                    name: name.to_string(),
+                    base_type: attribute(tag, "BaseType").map(|base| unqualified(base).to_string()),
+                    ..Default::default()
+                });
+                if tag.ends_with('/') {
+                    model.insert_type(current.take());
+                }
+            } else if tag.starts_with("/EntityType") {
+                model.insert_type(current.take());
+            } else if let Some(entity) = current.as_mut() {
+                if tag.starts_with("Key") && !tag.ends_with('/') {
+                    in_key = true;
+                } else if tag.starts_with("/Key") {
+                    in_key = false;
+                } else if in_key && tag.starts_with("PropertyRef ") {
+                    entity.keys.extend(attribute(tag, "Name").map(String::from));
+                } else if tag.starts_with("/Property") || tag.starts_with("/NavigationProperty") {
+                    in_member = false;
+                } else if tag.starts_with("Property ") {
+                    in_member = !tag.ends_with('/');
+                    if let Some(name) = attribute(tag, "Name") {
+                        entity.properties.push(PropertyInfo {
+                            name: name.to_string(),
+                            type_name: attribute(tag, "Type").unwrap_or_default().to_string(),
+                        });
+                    }
+                } else if tag.starts_with("NavigationProperty ") {
+                    in_member = !tag.ends_with('/');
+                    if let Some(name) = attribute(tag, "Name") {
+                        let type_name = attribute(tag, "Type").unwrap_or_default();
+                        entity.navigation.push(NavigationInfo {
+                            name: name.to_string(),
+                            target_type: unqualified(type_name).to_string(),
+                            collection: type_name.starts_with("Collection("),
+                            partner: attribute(tag, "Partner").map(String::from),
+                            constraints: Vec::new(),
+                        });
+                    }
+                } else if tag.starts_with("ReferentialConstraint ") {
+                    if let (Some(navigation), Some(property), Some(referenced)) = (
+                        entity.navigation.last_mut(),
+                        attribute(tag, "Property"),
+                        attribute(tag, "ReferencedProperty"),
+                    ) {
+                        navigation
+                            .constraints
+                            .push((property.to_string(), referenced.to_string()));
+                    }
+                }
+            }
+        }
+
+        for (annotated, (_, text)) in descriptions {
+            match annotated {
+                Annotated::Set(set) => {
+                    model.set_descriptions.insert(set, text);
+                }
+                Annotated::Type(name) => {
+                    if let Some(entity) = model.entity_types.get_mut(&name) {
+                        entity.description = Some(text);
+                    }
+                }
+            }
+        }
+        model
+    }
+
+    fn insert_type(&mut self, entity: Option<EntityTypeInfo>) {
+        if let Some(entity) = entity {
+            self.entity_types.insert(entity.name.clone(), entity);
+        }
+    }
+
+    /// Entity set named by `name`, with the casing the service expects
+    ///
+    /// Tries the set names exactly, then ignoring case, then the entity type
+    /// names the same way, so `customersv3`, `CustomerV3` and `account` all
+    /// resolve to their sets (`CustomersV3`, `accounts`).
+    pub fn resolve_entity_set(&self, name: &str) -> Option<&str> {
+        let name = name.trim();
+        let find = |matches: &dyn Fn(&(String, String)) -> bool| {
+            self.entity_sets
+                .iter()
+                .find(|entry| matches(entry))
+                .map(|(set, _)| set.as_str())
+        };
+        find(&|(set, _)| set == name)
+            .or_else(|| find(&|(set, _)| set.eq_ignore_ascii_case(name)))
+            .or_else(|| find(&|(_, entity_type)| entity_type == name))
+            .or_else(|| find(&|(_, entity_type)| entity_type.eq_ignore_ascii_case(name)))
+    }
+
+    /// Entity type of an entity set; the name may also be a type name itself,
+    /// and is matched as in [`Self::resolve_entity_set`]
+    pub fn entity_type(&self, entity_set: &str) -> Option<&EntityTypeInfo> {
+        if let Some(set) = self.resolve_entity_set(entity_set) {
+            let (_, type_name) = self.entity_sets.iter().find(|(name, _)| name == set)?;
+            return self.entity_types.get(type_name);
+        }
+        // Types without an entity set, e.g. those only reached by navigation
+        self.entity_types.get(entity_set).or_else(|| {
+            self.entity_types
+                .values()
+                .find(|entity| entity.name.eq_ignore_ascii_case(entity_set))
+        })
+    }
+
+    /// Description of an entity set, from its own annotation or its type's
+    pub fn description(&self, entity_set: &str) -> Option<&str> {
+        self.set_descriptions
+            .get(entity_set)
+            .map(String::as_str)
+            .or_else(|| self.entity_type(entity_set)?.description.as_deref())
+    }
+
+    /// Capabilities of an entity set, matched as in [`Self::resolve_entity_set`];
+    /// `None` when `$metadata` restricts none of its writes
+    pub fn write_capabilities(&self, entity_set: &str) -> Option<WriteCapabilities> {
+        let set = self.resolve_entity_set(entity_set)?;
+        self.set_capabilities.get(set).copied()
+    }
+
+    /// A type followed by the base types it inherits from
+    fn type_chain<'a>(&'a self, entity: &'a EntityTypeInfo) -> Vec<&'a EntityTypeInfo> {
+        let mut chain = vec![entity];
+        // Bounded, so a cyclic BaseType cannot loop forever
+        while chain.len() < 16 {
+            let base = chain[chain.len() - 1]
+                .base_type
+                .as_deref()
+                .and_then(|base| self.entity_types.get(base));
+            match base {
+                Some(base) => chain.push(base),
+                None => break,
+            }
+        }
+        chain
+    }
+
+    /// Property and navigation property names of a type, including those
+    /// inherited from its base types
+    fn member_names<'a>(&'a self, entity: &'a EntityTypeInfo) -> (Vec<&'a str>, Vec<&'a str>) {
+        let chain = self.type_chain(entity);
+        let properties = chain
+            .iter()
+            .flat_map(|entity| entity.properties.iter().map(|p| p.name.as_str()))
+            .collect();
+        let navigation = chain
+            .iter()
+            .flat_map(|entity| entity.navigation.iter().map(|n| n.name.as_str()))
+            .collect();
+        (properties, navigation)
+    }
+
+    /// Navigation property `name` of an entity set, inherited ones included
+    ///
+    /// `Ok(None)` when the model does not know the entity set; `Err` holds
+    /// the closest declared navigation properties when it has no such one.
+    pub fn navigation(
+        &self,
+        entity_set: &str,
+        name: &str,
+    ) -> Result<Option<&NavigationInfo>, Vec<String>> {
+        let Some(entity) = self.entity_type(entity_set) else {
+            return Ok(None);
+        };
+        let declared: Vec<&NavigationInfo> = self
+            .type_chain(entity)
+            .into_iter()
+            .flat_map(|entity| &entity.navigation)
+            .collect();
+        if let Some(navigation) = declared.iter().find(|navigation| navigation.name == name) {
+            return Ok(Some(navigation));
+        }
+        let names: Vec<&str> = declared.iter().map(|n| n.name.as_str()).collect();
+        Err(closest_names(name, &names))
+    }
+
+    /// Entity type a resource path leads to, e.g. `contact` for
+    /// `accounts(<id>)/contact_customer_accounts`: the entity set's type, then
+    /// the target of each navigation property in turn. A qualified segment is
+    /// a type cast. `None` when any segment is unknown.
+    pub fn path_entity_type(&self, path: &str) -> Option<&EntityTypeInfo> {
+        let mut segments = path
+            .split('/')
+            .map(|segment| segment.split('(').next().unwrap_or_default());
+        let mut entity = self.entity_type(unqualified(segments.next()?))?;
+        for segment in segments {
+            let target = if segment.contains('.') {
+                unqualified(segment)
+            } else {
+                self.type_chain(entity)
+                    .into_iter()
+                    .flat_map(|entity| &entity.navigation)
+                    .find(|navigation| navigation.name == segment)?
+                    .target_type
+                    .as_str()
+            };
+            entity = self.entity_types.get(target)?;
+        }
+        Some(entity)
+    }
+
+    /// Fields in the `select`, `orderby` and `expand` of a query that the
+    /// entity set's type does not declare, with the closest declared names
+    ///
+    /// Names are case-sensitive, as they are to the service. An entity set
+    /// the model does not know yields nothing, leaving the error to the service.
+    pub fn unknown_fields(&self, entity_set: &str, options: &QueryOptions) -> Vec<UnknownField> {
+        let Some(entity) = self.entity_type(entity_set) else {
+            return Vec::new();
+        };
+        let (properties, navigation) = self.member_names(entity);
+        let fields: Vec<&str> = properties.iter().chain(&navigation).copied().collect();
+
+        let select = options
+            .select
+            .iter()
+            .flatten()
+            .map(|field| ("select", field.as_str()));
+        let orderby = options
+            .orderby
+            .iter()
+            .flat_map(|orderby| orderby.split(','))
+            .filter_map(|clause| clause.split_whitespace().next())
+            .map(|field| ("orderby", field));
+        let expand = options
+            .expand
+            .iter()
+            .flatten()
+            .map(|item| ("expand", item.split('(').next().unwrap_or_default()));
+
+        let mut unknown: Vec<UnknownField> = Vec::new();
+        for (option, path) in select.chain(orderby).chain(expand) {
+            let Some(name) = field_root(path) else {
+                continue;
+            };
+            let known = if option == "expand" {
+                &navigation
+            } else {
+                &fields
+            };
+            if known.contains(&name) || unknown.iter().any(|field| field.name == name) {
+                continue;
+            }
+            unknown.push(UnknownField {
+                option,
+                name: name.to_string(),
+                suggestions: closest_names(name, known),
+            });
+        }
+        unknown
+    }
+
+    /// Properties of an entity set whose type is an `EnumType`, mapped to that type
+    pub fn enum_properties(&self, entity_set: &str) -> HashMap<String, String> {
+        let Some(entity) = self.entity_type(entity_set) else {
+            return HashMap::new();
+        };
+        entity
+            .properties
+            .iter()
+            .filter(|property| !property.type_name.starts_with("Edm."))
+            .filter_map(|property| {
+                let enum_name = unqualified(&property.type_name);
+                self.enums
+                    .iter()
+                    .any(|info| info.name == enum_name)
+                    .then(|| (property.name.clone(), enum_name.to_string()))
+            })
+            .collect()
+    }
+
+    /// First entity set exposing a type
+    pub fn entity_set_for_type(&self, type_name: &str) -> Option<&str> {
+        self.entity_sets
+            .iter()
+            .find(|(_, entity_type)| entity_type == type_name)
+            .map(|(set, _)| set.as_str())
+    }
+
+    /// Navigation properties of other types that point at `type_name`, as (source type, navigation)
+    pub fn inbound_navigation(&self, type_name: &str) -> Vec<(&EntityTypeInfo, &NavigationInfo)> {
+        let mut inbound: Vec<_> = self
+            .entity_types
+            .values()
+            .flat_map(|entity| {
+                entity
+                    .navigation
+                    .iter()
+                    .filter(|navigation| navigation.target_type == type_name)
+                    .map(move |navigation| (entity, navigation))
+            })
+            .collect();
+        inbound.sort_by(|a, b| (&a.0.name, &a.1.name).cmp(&(&b.0.name, &b.1.name)));
+        inbound
+    }
+}
+
+/// Dataverse option set definition from the metadata API, as an enum of
+/// `(label, value)` members; boolean option sets have two members. Labels are
+/// in the language with LCID `language_code` where the definition has one.
+pub fn parse_option_set_definition(
+    definition: &Value,
+    language_code: Option<u32>,
+) -> Option<EnumTypeInfo> {
+    let name = definition.get("Name")?.as_str()?;
+    let label =
+        |option: &Value| localized_label(option.get("Label")?, language_code).map(String::from);
+    let member = |option: &Value| Some((label(option)?, option.get("Value")?.as_i64()?));
+
+    let members = match definition.get("Options").and_then(Value::as_array) {
+        Some(options) => options.iter().filter_map(member).collect(),
+        None => ["FalseOption", "TrueOption"]
+            .iter()
+            .filter_map(|key| definition.get(*key).and_then(member))
+            .collect(),
+    };
+
+    Some(EnumTypeInfo {
+        name: name.to_string(),
+        namespace: String::new(),
+        members,
+    })
+}
+
+impl ODataClient {
+    /// Names of the Dataverse global option sets (choices)
+    pub async fn fetch_global_option_set_names(&self) -> Result<Vec<String>, ODataError> {
+        let options = QueryOptions {
+            select: Some(vec!["Name".to_string()]),
+            ..Default::default()
+        };
+        let definitions = self
+            .fetch_all_pages("GlobalOptionSetDefinitions", &options)
+            .await?;
+        Ok(definitions
+            .iter()
+            .filter_map(|definition| definition.get("Name").and_then(Value::as_str))
+            .map(String::from)
+            .collect())
+    }
+
+    /// A Dataverse global option set with its labels
+    pub async fn fetch_global_option_set(&self, name: &str) -> Result<EnumTypeInfo, ODataError> {
+        let key = EntityKey::Composite(vec![("Name".to_string(), Value::from(name))]);
+        let definition = self.get_entity("GlobalOptionSetDefinitions", &key).await?;
+        parse_option_set_definition(&definition, self.language_code()).ok_or_else(|| {
+            ODataError::ParseError(format!("Unexpected definition for option set '{}'", name))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // On one line, as some environments serve it
+    const METADATA: &str = r#"<edmx:Edmx><edmx:DataServices><Schema Namespace="Microsoft.Dynamics.DataEntities"><EnumType Name="SalesStatus"><Member Name="None" Value="0" /><Member Name="Backorder" Value="1" /><Member Name="Invoiced" Value="3" /></EnumType><EnumType Name="NoYes"><Member Name="No" /><Member Name="Yes" /></EnumType><EntityType Name="SalesOrderHeaderV2"><Key><PropertyRef Name="SalesOrderNumber" /></Key><Property Name="SalesOrderNumber" Type="Edm.String" /><Property Name="SalesOrderStatus" Type="Microsoft.Dynamics.DataEntities.SalesStatus" /><Property Name="IsDeliveryAddressPrivate" Type="Microsoft.Dynamics.DataEntities.NoYes" /></EntityType><EntityContainer Name="Resources"><EntitySet Name="SalesOrderHeadersV2" EntityType="Microsoft.Dynamics.DataEntities.SalesOrderHeaderV2" /></EntityContainer></Schema></edmx:DataServices></edmx:Edmx>"#;
+
+    #[test]
+    fn enum_types_and_members_are_parsed() {
+        let enums = parse_enum_types(METADATA);
+
+        assert_eq!(enums.len(), 2);
+        assert_eq!(enums[0].name, "SalesStatus");
+        assert_eq!(enums[0].member_name(3), Some("Invoiced"));
+        assert_eq!(enums[0].member_name(2), None);
+        assert_eq!(
+            enums[0].literal("Invoiced"),
+            "Microsoft.Dynamics.DataEntities.SalesStatus'Invoiced'"
+        );
+        // Implicit values count up from zero
+        assert_eq!(
+            enums[1].members,
+            vec![("No".to_string(), 0), ("Yes".to_string(), 1)]
+        );
+    }
+
+    const RELATIONSHIPS: &str = r#"<Schema Namespace="Microsoft.Dynamics.DataEntities">
+<EntityType Name="CustomerV3">
+<Key><PropertyRef Name="dataAreaId" /><PropertyRef Name="CustomerAccount" /></Key>
+<Property Name="dataAreaId" Type="Edm.String" />
+<Property Name="CustomerAccount" Type="Edm.String" />
+<NavigationProperty Name="SalesOrders" Type="Collection(Microsoft.Dynamics.DataEntities.SalesOrderHeaderV2)" Partner="Customer" />
+</EntityType>
+<EntityType Name="SalesOrderHeaderV2">
+<Key><PropertyRef Name="SalesOrderNumber" /></Key>
+<Property Name="SalesOrderNumber" Type="Edm.String" />
+<Property Name="OrderingCustomerAccountNumber" Type="Edm.String" />
+<NavigationProperty Name="Customer" Type="Microsoft.Dynamics.DataEntities.CustomerV3" Partner="SalesOrders">
+<ReferentialConstraint Property="dataAreaId" ReferencedProperty="dataAreaId" />
+<ReferentialConstraint Property="OrderingCustomerAccountNumber" ReferencedProperty="CustomerAccount" />
+</NavigationProperty>
+</EntityType>
+<EntityContainer Name="Resources">
+<EntitySet Name="CustomersV3" EntityType="Microsoft.Dynamics.DataEntities.CustomerV3" />
+<EntitySet Name="SalesOrderHeadersV2" EntityType="Microsoft.Dynamics.DataEntities.SalesOrderHeaderV2" />
+</EntityContainer>
+</Schema>"#;
+
+    #[test]
+    fn model_indexes_types_sets_and_relationships() {
+        let model = MetadataModel::parse(RELATIONSHIPS);
+
+        let customer = model.entity_type("CustomersV3").unwrap();
+        assert_eq!(customer.keys, vec!["dataAreaId", "CustomerAccount"]);
+        assert_eq!(customer.properties.len(), 2);
+        assert!(customer.navigation[0].collection);
+        assert_eq!(customer.navigation[0].target_type, "SalesOrderHeaderV2");
+
+        let inbound = model.inbound_navigation("CustomerV3");
+        assert_eq!(inbound.len(), 1);
+        let (source, navigation) = inbound[0];
+        assert_eq!(source.name, "SalesOrderHeaderV2");
+        assert!(!navigation.collection);
+        assert_eq!(
+            navigation.constraints[1],
+            (
+                "OrderingCustomerAccountNumber".to_string(),
+                "CustomerAccount".to_string()
+            )
+        );
+        assert_eq!(
+            model.entity_set_for_type("SalesOrderHeaderV2"),
+            Some("SalesOrderHeadersV2")
+        );
+    }
+
+    #[test]
+    fn descriptions_come_from_annotations_when_present() {
+        let xml = r#"<Schema Namespace="Microsoft.Dynamics.CRM">
+<EntityType Name="account">
+<Annotation Term="Org.OData.Core.V1.Description" String="Business &amp; customer" />
+<Property Name="name" Type="Edm.String">
+<Annotation Term="Org.OData.Core.V1.Description" String="Company name" />
+</Property>
+</EntityType>
+<EntityType Name="contact"><Property Name="fullname" Type="Edm.String" /></EntityType>
+<EntityType Name="lead"><Property Name="subject" Type="Edm.String" /></EntityType>
+<EntityType Name="opportunity"><Property Name="leads" Type="Edm.String" /></EntityType>
+<EntityContainer Name="System">
+<EntitySet Name="accounts" EntityType="Microsoft.Dynamics.CRM.account" />
+<EntitySet Name="contacts" EntityType="Microsoft.Dynamics.CRM.contact">
+<Annotation Term="Org.OData.Core.V1.Description" String="People" />
+</EntitySet>
+<EntitySet Name="leads" EntityType="Microsoft.Dynamics.CRM.lead" />
+<EntitySet Name="opportunities" EntityType="Microsoft.Dynamics.CRM.opportunity" />
+</EntityContainer>
+<Annotations Target="Microsoft.Dynamics.CRM.System/opportunities">
+<Annotation Term="Org.OData.Core.V1.Description" String="Potential revenue" />
+</Annotations>
+<Annotations Target="Microsoft.Dynamics.CRM.opportunity/leads">
+<Annotation Term="Org.OData.Core.V1.Description" String="Originating leads" />
+</Annotations>
+<Annotations Target="Microsoft.Dynamics.CRM.lead">
+<Annotation Term="Common.Label" String="Prospect" />
+</Annotations>
+<Annotations Target="Microsoft.Dynamics.CRM.lead/subject">
+<Annotation Term="Org.OData.Core.V1.Description" String="Topic" />
+</Annotations>
+</Schema>"#;
+        let model = MetadataModel::parse(xml);
+
+        assert_eq!(model.description("accounts"), Some("Business & customer"));
+        assert_eq!(model.description("contacts"), Some("People"));
+        assert_eq!(model.description("leads"), Some("Prospect"));
+        assert_eq!(
+            model.description("opportunities"),
+            Some("Potential revenue")
+        );
+        // Without annotations there is simply no description
+        assert_eq!(
+            MetadataModel::parse(RELATIONSHIPS).description("CustomersV3"),
+            None
+        );
+    }
+
+    #[test]
+    fn unknown_query_fields_get_the_closest_real_names() {
+        let model = MetadataModel::parse(RELATIONSHIPS);
+        let options = QueryOptions::builder()
+            .select([
+                "customeraccount",
+                "dataAreaId",
+                "CustmerAccount",
+                "SalesOrders/SalesOrderNumber",
+            ])
+            .orderby("dataareaid desc, CustomerAccount")
+            .expand(["SalesOrder($select=SalesOrderNumber)", "dataAreaId"])
+            .build();
+
+        let unknown = model.unknown_fields("CustomersV3", &options);
+        let named: Vec<_> = unknown
+            .iter()
+            .map(|field| (field.option, field.name.as_str(), field.suggestions.clone()))
+            .collect();
+        assert_eq!(
+            named,
+            vec![
+                (
+                    "select",
+                    "customeraccount",
+                    vec!["CustomerAccount".to_string()]
+                ),
+                (
+                    "select",
+                    "CustmerAccount",
+                    vec!["CustomerAccount".to_string()]
+                ),
+                ("orderby", "dataareaid", vec!["dataAreaId".to_string()]),
+                ("expand", "SalesOrder", vec!["SalesOrders".to_string()]),
+                // Only navigation properties can be expanded
+                ("expand", "dataAreaId", vec![]),
+            ]
+        );
+
+        // Inherited properties are declared too; unknown sets are not checked
+        let xml = r#"<EntityType Name="activitypointer"><Property Name="activityid" Type="Edm.Guid" /></EntityType><EntityType Name="email" BaseType="Microsoft.Dynamics.CRM.activitypointer"><Property Name="sender" Type="Edm.String" /></EntityType><EntitySet Name="emails" EntityType="Microsoft.Dynamics.CRM.email" />"#;
+        let model = MetadataModel::parse(xml);
+        let options = QueryOptions::builder()
+            .select(["activityid", "sender"])
+            .build();
+        assert!(model.unknown_fields("emails", &options).is_empty());
+        assert!(model.unknown_fields("letters", &options).is_empty());
+    }
+
+    #[test]
+    fn write_restrictions_are_read_from_capabilities_annotations() {
+        let model = MetadataModel::parse(include_str!("testdata/capability-restrictions.xml"));
+
+        let read_only = model.write_capabilities("inventonhandv2").unwrap();
+        assert!(!read_only.insertable && !read_only.updatable && !read_only.deletable);
+        assert_eq!(read_only.summary(), "insert ✖ update ✖ delete ✖");
+
+        let invoices = model.write_capabilities("SalesInvoiceHeadersV2").unwrap();
+        assert_eq!(invoices.summary(), "insert ✔ update ✔ delete ✖");
+        assert!(invoices.allows(WriteOperation::Update));
+
+        // Out-of-line annotations with an aliased term count as well
+        let virtual_table = model.write_capabilities("msdyn_externalorders").unwrap();
+        assert!(!virtual_table.allows(WriteOperation::Insert));
+        assert!(virtual_table.allows(WriteOperation::Delete));
+        assert_eq!(
+            model.description("msdyn_externalorders"),
+            Some("Orders from the external system")
+        );
+
+        // Restrictions that restrict nothing, and none at all, leave writes allowed
+        assert_eq!(model.write_capabilities("CustomersV3"), None);
+        assert_eq!(model.write_capabilities("Unknown"), None);
+    }
+
+    #[test]
+    fn entity_sets_resolve_by_set_or_type_name_in_any_case() {
+        let model = MetadataModel::parse(RELATIONSHIPS);
+
+        assert_eq!(model.resolve_entity_set("CustomersV3"), Some("CustomersV3"));
+        assert_eq!(model.resolve_entity_set("customersv3"), Some("CustomersV3"));
+        assert_eq!(model.resolve_entity_set("CustomerV3"), Some("CustomersV3"));
+        assert_eq!(
+            model.resolve_entity_set("salesorderheaderv2"),
+            Some("SalesOrderHeadersV2")
+        );
+        // No prefix matching: a partial name is not an entity set
+        assert_eq!(model.resolve_entity_set("SalesOrder"), None);
+
+        assert_eq!(model.entity_type("customersv3").unwrap().name, "CustomerV3");
+        assert_eq!(
+            model.entity_type("salesorderheaderv2").unwrap().name,
+            "SalesOrderHeaderV2"
+        );
+    }
+
+    #[test]
+    fn paths_resolve_through_navigation_properties() {
+        let model = MetadataModel::parse(RELATIONSHIPS);
+        let type_of = |path| {
+            model
+                .path_entity_type(path)
+                .map(|entity| entity.name.as_str())
+        };
+
+        assert_eq!(type_of("CustomersV3"), Some("CustomerV3"));
+        assert_eq!(
+            type_of("CustomersV3(dataAreaId='usmf',CustomerAccount='US-001')/SalesOrders"),
+            Some("SalesOrderHeaderV2")
+        );
+        assert_eq!(
+            type_of("SalesOrderHeadersV2('SO-1')/Customer/SalesOrders"),
+            Some("SalesOrderHeaderV2")
+        );
+        assert_eq!(
+            type_of("CustomersV3('US-001')/Microsoft.Dynamics.DataEntities.CustomerV3"),
+            Some("CustomerV3")
+        );
+        // A navigation name is not looked up as a set or type of its own
+        assert_eq!(type_of("CustomersV3('US-001')/SalesOrderHeaderV2"), None);
+        assert_eq!(type_of("CustomersV3('US-001')/Missing"), None);
+    }
+
+    #[test]
+    fn dataverse_option_sets_use_localized_labels() {
+        let picklist = serde_json::json!({
+            "Name": "budgetstatus",
+            "Options": [
+                {"Value": 0, "Label": {"UserLocalizedLabel": {"Label": "No Committed Budget"}}},
+                {"Value": 1, "Label": {"UserLocalizedLabel": {"Label": "May Buy"}}},
+                {"Value": 2, "Label": {"UserLocalizedLabel": null}}
+            ]
+        });
+        let info = parse_option_set_definition(&picklist, None).unwrap();
+        assert_eq!(info.name, "budgetstatus");
+        assert_eq!(info.member_name(1), Some("May Buy"));
+        assert_eq!(info.members.len(), 2);
+
+        let boolean = serde_json::json!({
+            "Name": "donotemail",
+            "TrueOption": {"Value": 1, "Label": {"UserLocalizedLabel": {"Label": "Do Not Allow"}}},
+            "FalseOption": {"Value": 0, "Label": {"UserLocalizedLabel": {"Label": "Allow"}}}
+        });
+        let info = parse_option_set_definition(&boolean, None).unwrap();
+        assert_eq!(
+            info.members,
+            vec![("Allow".to_string(), 0), ("Do Not Allow".to_string(), 1)]
+        );
+
+        let translated = serde_json::json!({
+            "Name": "budgetstatus",
+            "Options": [{"Value": 1, "Label": {
+                "LocalizedLabels": [{"Label": "Kauft eventuell", "LanguageCode": 1031}],
+                "UserLocalizedLabel": {"Label": "May Buy", "LanguageCode": 1033}
+            }}]
+        });
+        let info = parse_option_set_definition(&translated, Some(1031)).unwrap();
+        assert_eq!(info.member_name(1), Some("Kauft eventuell"));
+    }
+
+    #[test]
+    fn enum_properties_follow_the_entity_set_type() {
+        let model = MetadataModel::parse(METADATA);
+        let properties = model.enum_properties("SalesOrderHeadersV2");
+
+        assert_eq!(properties.len(), 2);
+        assert_eq!(properties["SalesOrderStatus"], "SalesStatus");
+        assert_eq!(properties["IsDeliveryAddressPrivate"], "NoYes");
+        assert!(model.enum_properties("Missing").is_empty());
+    }
+}
@@ -0,0 +1,260 @@
+//! `$metadata` cache
+//!
+//! Holds the downloaded EDMX for a TTL together with its parsed
+//! [`MetadataModel`], which is built on first use and dropped with the
+//! document. Concurrent cache misses share a single download. Documents are
+//! kept per `Accept-Language`, since labels and descriptions differ by it, for
+//! at most [`MAX_LANGUAGES`] languages at a time.
+
+use super::metadata::MetadataModel;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+
+/// Languages kept cached at once; F&O documents run to tens of MB, so the
+/// one used least recently is dropped to make room for another
+pub const MAX_LANGUAGES: usize = 4;
+
+/// A downloaded `$metadata` document
+#[derive(Debug)]
+pub struct MetadataDocument {
+    xml: String,
+    fetched_at: Instant,
+    /// Last time the cache handed the document out, for eviction
+    used_at: std::sync::Mutex<Instant>,
+    model: OnceLock<Arc<MetadataModel>>,
+}
+
+impl MetadataDocument {
+    fn new(xml: String) -> Self {
+        Self {
+            xml,
+            fetched_at: Instant::now(),
+            used_at: std::sync::Mutex::new(Instant::now()),
+            model: OnceLock::new(),
+        }
+    }
+
+    /// The raw EDMX
+    pub fn xml(&self) -> &str {
+        &self.xml
+    }
+
+    /// The parsed document, built on the first call
+    pub fn model(&self) -> Arc<MetadataModel> {
+        self.model
+            .get_or_init(|| Arc::new(MetadataModel::parse(&self.xml)))
+            .clone()
+    }
+
+    pub fn age(&self) -> Duration {
+        self.fetched_at.elapsed()
+    }
+
+    fn used_at(&self) -> Instant {
+        *self.used_at.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn mark_used(&self) {
+        *self.used_at.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+}
+
+/// Language a document was downloaded in; `None` when none was asked for
+type Language = Option<String>;
+
+/// TTL cache for the `$metadata` document
+#[derive(Debug)]
+pub struct MetadataCache {
+    ttl: Duration,
+    documents: RwLock<HashMap<Language, Arc<MetadataDocument>>>,
+    /// Held while downloading so concurrent misses wait for one fetch
+    fetching: Mutex<()>,
+}
+
+impl MetadataCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            documents: RwLock::new(HashMap::new()),
+            fetching: Mutex::new(()),
+        }
+    }
+
+    /// The cached document in `language` if it is younger than the TTL
+    pub async fn get(&self, language: Language) -> Option<Arc<MetadataDocument>> {
+        let documents = self.documents.read().await;
+        let document = documents
+            .get(&language)
+            .filter(|document| document.age() < self.ttl)?;
+        document.mark_used();
+        Some(document.clone())
+    }
+
+    /// The cached document in `language`, or one downloaded with `fetch` on
+    /// a miss
+    ///
+    /// Only one caller downloads at a time; the others wait and then take
+    /// the fresh document from the cache.
+    pub async fn get_or_fetch<F, Fut, E>(
+        &self,
+        language: Language,
+        fetch: F,
+    ) -> Result<Arc<MetadataDocument>, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<String, E>>,
+    {
+        if let Some(document) = self.get(language.clone()).await {
+            tracing::debug!(
+                "Metadata cache hit (age: {:?}, ttl: {:?})",
+                document.age(),
+                self.ttl
+            );
+            return Ok(document);
+        }
+
+        let _fetching = self.fetching.lock().await;
+        // Another caller may have filled the cache while we waited
+        if let Some(document) = self.get(language.clone()).await {
+            return Ok(document);
+        }
+
+        tracing::debug!(language = ?language, "Fetching metadata from server...");
+        let document = Arc::new(MetadataDocument::new(fetch().await?));
+        tracing::debug!(
+            "Metadata cached (size: {} bytes, ttl: {:?})",
+            document.xml.len(),
+            self.ttl
+        );
+        let mut documents = self.documents.write().await;
+        if !documents.contains_key(&language) && documents.len() >= MAX_LANGUAGES {
+            let least_used = documents
+                .iter()
+                .min_by_key(|(_, document)| document.used_at())
+                .map(|(language, _)| language.clone());
+            if let Some(evicted) = least_used {
+                tracing::debug!(language = ?evicted, "Metadata evicted from the cache");
+                documents.remove(&evicted);
+            }
+        }
+        documents.insert(language, document.clone());
+        Ok(document)
+    }
+
+    /// Drop the cached documents so the next call downloads them again
+    pub async fn invalidate(&self) {
+        self.documents.write().await.clear();
+        tracing::debug!("Metadata cache invalidated");
+    }
+
+    /// Size in bytes and age of the cached document in `language`
+    pub async fn status(&self, language: Language) -> Option<(usize, Duration)> {
+        let documents = self.documents.read().await;
+        documents
+            .get(&language)
+            .map(|document| (document.xml.len(), document.age()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const XML: &str = r#"<EntityContainer><EntitySet Name="accounts" EntityType="Microsoft.Dynamics.CRM.account" /></EntityContainer>"#;
+
+    #[tokio::test]
+    async fn concurrent_misses_share_one_download() {
+        let cache = MetadataCache::new(Duration::from_secs(60));
+        let downloads = AtomicUsize::new(0);
+        let fetch = || async {
+            downloads.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok::<_, ()>(XML.to_string())
+        };
+
+        let (a, b) = tokio::join!(
+            cache.get_or_fetch(None, fetch),
+            cache.get_or_fetch(None, fetch)
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+
+        assert_eq!(downloads.load(Ordering::SeqCst), 1);
+        assert!(Arc::ptr_eq(&a, &b));
+        // The model is parsed once and shared
+        assert!(Arc::ptr_eq(&a.model(), &b.model()));
+        assert_eq!(a.model().entity_sets[0].0, "accounts");
+
+        cache.invalidate().await;
+        assert!(cache.status(None).await.is_none());
+        cache.get_or_fetch(None, fetch).await.unwrap();
+        assert_eq!(downloads.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn expired_documents_are_downloaded_again() {
+        let cache = MetadataCache::new(Duration::ZERO);
+        let first = cache
+            .get_or_fetch(None, || async { Ok::<_, ()>(XML.to_string()) })
+            .await
+            .unwrap();
+        let second = cache
+            .get_or_fetch(None, || async { Ok::<_, ()>(String::new()) })
+            .await
+            .unwrap();
+
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert!(second.model().entity_sets.is_empty());
+        // A failed download leaves the cache as it was
+        let failed = cache.get_or_fetch(None, || async { Err("offline") }).await;
+        assert_eq!(failed.unwrap_err(), "offline");
+    }
+
+    #[tokio::test]
+    async fn documents_are_cached_per_language() {
+        let cache = MetadataCache::new(Duration::from_secs(60));
+        let german = Some("de-DE".to_string());
+        cache
+            .get_or_fetch(None, || async { Ok::<_, ()>(XML.to_string()) })
+            .await
+            .unwrap();
+        assert!(cache.get(german.clone()).await.is_none());
+
+        let fetched = cache
+            .get_or_fetch(german.clone(), || async { Ok::<_, ()>(String::new()) })
+            .await
+            .unwrap();
+        assert!(fetched.model().entity_sets.is_empty());
+        // Neither language evicts the other
+        assert_eq!(cache.get(None).await.unwrap().model().entity_sets.len(), 1);
+        assert_eq!(cache.status(german).await.map(|(size, _)| size), Some(0));
+    }
+
+    #[tokio::test]
+    async fn the_least_recently_used_language_is_evicted() {
+        let cache = MetadataCache::new(Duration::from_secs(60));
+        let fetch = || async { Ok::<_, ()>(XML.to_string()) };
+        cache.get_or_fetch(None, fetch).await.unwrap();
+        for language in ["de-DE", "fr-FR", "it-IT"] {
+            tokio::time::sleep(Duration::from_millis(2)).await;
+            cache
+                .get_or_fetch(Some(language.to_string()), fetch)
+                .await
+                .unwrap();
+        }
+        // Reading the default language keeps it over the older de-DE
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        assert!(cache.get(None).await.is_some());
+
+        cache
+            .get_or_fetch(Some("nl-NL".to_string()), fetch)
+            .await
+            .unwrap();
+        assert_eq!(cache.documents.read().await.len(), MAX_LANGUAGES);
+        assert!(cache.status(Some("de-DE".to_string())).await.is_none());
+        assert!(cache.status(None).await.is_some());
+    }
+}
@@ -2,6 +2,60 @@
 //!
 //! HTTP client and schema utilities for D365 OData APIs
 
+pub mod annotations;
+pub mod audit;
+pub mod backpressure;
+pub mod batch;
+pub mod cancel;
+pub mod circuit;
 pub mod client;
+pub mod diagnostics;
+pub mod dmf;
+pub mod dry_run;
+pub mod filter;
+pub mod impersonation;
+pub mod language;
+pub mod metadata;
+pub mod metadata_cache;
+pub mod operation;
+pub mod progress;
+pub mod rate_limit;
+pub mod record_count;
+pub mod recording;
+pub mod relevance;
+pub mod request_log;
+pub mod snapshot;
+pub mod timeout;
+pub mod typed;
+pub mod views;
 
-pub use client::{EntityInfo, ODataClient, ODataError, ODataResponse, QueryOptions};
+pub use annotations::AnnotationMode;
+pub use audit::{AuditEntry, AuditStatus, FieldChange};
+pub use backpressure::{Backpressure, BackpressureStats};
+pub use batch::ChangesetError;
+pub use cancel::with_cancellation;
+pub use circuit::{CircuitBreaker, CircuitState, CircuitStats};
+pub use client::{
+    format_entity_key, parse_context_url, short_type_name, ContextUrl, EntityInfo, EntityKey,
+    FileDownload, ODataClient, ODataError, ODataResponse, QueryOptions, QueryOptionsBuilder,
+    StreamSummary, UpsertOutcome,
+};
+pub use diagnostics::{CheckStage, CheckStatus, ConnectionReport, StageReport};
+pub use dmf::{BlobWriteUrl, ExecutionStatus, PackageImport};
+pub use dry_run::{is_dry_run, with_dry_run, PreparedRequest};
+pub use filter::{validate_filter, FilterBuilder, FilterExpr, FilterValue};
+pub use impersonation::{with_caller, CallerIdHeader};
+pub use language::{known_language, parse_language_tag, with_language};
+pub use metadata::{
+    parse_enum_types, parse_option_set_definition, EntityTypeInfo, EnumTypeInfo, MetadataModel,
+    NavigationInfo, PropertyInfo, UnknownField, WriteCapabilities, WriteOperation,
+};
+pub use metadata_cache::{MetadataCache, MetadataDocument};
+pub use operation::{ActionOutcome, OperationState, OperationStatus, PendingOperation};
+pub use progress::{with_progress, ProgressReporter};
+pub use rate_limit::{RateLimiter, RateLimiterStats};
+pub use relevance::{SearchHit, SearchResults};
+pub use request_log::{with_mcp_client, HistogramSnapshot, RequestStats, LATENCY_BUCKETS_MS};
+pub use snapshot::MetadataSnapshot;
+pub use timeout::with_timeout;
+pub use views::{SavedView, ViewKind};
@@ -0,0 +1,417 @@
+//! Long-running operations
+//!
+//! Some Dataverse actions, such as `BulkDelete` or custom APIs running in
+//! the background, answer `202 Accepted` with a `Location` header instead of
+//! a result. The URL there is a monitor: polling it answers 202 while the
+//! operation runs, then 200 with its outcome. Status payloads differ between
+//! APIs (`asyncoperations` have `statuscode`, background operations and
+//! Azure-style monitors a `status` string), so the state is read from
+//! whichever field is present and kept raw alongside the interpretation.
+
+use super::cancel::cancellable;
+use super::client::{parse_retry_after, ODataClient, ODataError};
+use super::progress::report_progress;
+use super::timeout::Deadline;
+use reqwest::{Response, StatusCode};
+use serde_json::Value;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Poll interval when the monitor suggests none
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Bounds on a suggested poll interval, so a monitor can neither make us
+/// hammer it nor sleep past any reasonable wait
+const MIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Fields that may hold an operation's state, in the order they are read
+const STATE_FIELDS: &[&str] = &[
+    "status",
+    "statuscode",
+    "backgroundoperationstatuscode",
+    "state",
+    "statecode",
+];
+
+/// An operation the service accepted but has not finished
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingOperation {
+    /// Where to poll for the outcome (the `Location` header)
+    pub monitor_url: String,
+    /// How long the service asks to wait before polling (`Retry-After`)
+    pub retry_after: Option<Duration>,
+}
+
+/// Result of [`ODataClient::start_action`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ActionOutcome {
+    /// The action ran; its response body, `Null` when it had none
+    Completed(Value),
+    /// The action runs in the background
+    Pending(PendingOperation),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperationState {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl fmt::Display for OperationState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Running => "Running",
+            Self::Succeeded => "Succeeded",
+            Self::Failed => "Failed",
+        })
+    }
+}
+
+/// What the monitor said on the last poll
+#[derive(Debug, Clone, PartialEq)]
+pub struct OperationStatus {
+    pub state: OperationState,
+    /// The state as the service gave it, e.g. `statuscode: 31 (Failed)`
+    pub raw_state: Option<String>,
+    /// The monitor's response body, `Null` when it had none
+    pub body: Value,
+    pub retry_after: Option<Duration>,
+}
+
+impl OperationStatus {
+    pub fn is_finished(&self) -> bool {
+        self.state != OperationState::Running
+    }
+}
+
+/// `asyncoperation` status reasons: 30 Succeeded, 31 Failed, 32 Canceled
+fn state_from_number(field: &str, number: i64) -> OperationState {
+    match (field, number) {
+        ("statecode", 3) => OperationState::Succeeded,
+        (_, 30) => OperationState::Succeeded,
+        (_, 31) | (_, 32) => OperationState::Failed,
+        _ => OperationState::Running,
+    }
+}
+
+fn state_from_name(name: &str) -> OperationState {
+    let name = name.to_ascii_lowercase();
+    if ["succeed", "success", "complete", "done"]
+        .iter()
+        .any(|word| name.contains(word))
+    {
+        OperationState::Succeeded
+    } else if ["fail", "cancel", "error", "abort"]
+        .iter()
+        .any(|word| name.contains(word))
+    {
+        OperationState::Failed
+    } else {
+        OperationState::Running
+    }
+}
+
+/// Interpret a monitor response: a 202 is still running whatever the body
+/// says, and a 200 without any state field is the finished result
+pub(crate) fn parse_status(status: StatusCode, body: &Value) -> (OperationState, Option<String>) {
+    let found = body.as_object().and_then(|object| {
+        STATE_FIELDS.iter().find_map(|field| {
+            object
+                .iter()
+                .find(|(name, value)| name.eq_ignore_ascii_case(field) && !value.is_null())
+                .map(|(name, value)| (*field, name, value, object))
+        })
+    });
+    let Some((field, name, value, object)) = found else {
+        let state = if status == StatusCode::ACCEPTED {
+            OperationState::Running
+        } else {
+            OperationState::Succeeded
+        };
+        return (state, None);
+    };
+
+    let formatted = object
+        .get(&format!(
+            "{}@OData.Community.Display.V1.FormattedValue",
+            name
+        ))
+        .and_then(Value::as_str);
+    let raw_state = match (value.as_str(), formatted) {
+        (Some(text), _) => format!("{}: {}", name, text),
+        (None, Some(formatted)) => format!("{}: {} ({})", name, value, formatted),
+        (None, None) => format!("{}: {}", name, value),
+    };
+    let state = match (status, value) {
+        (StatusCode::ACCEPTED, _) => OperationState::Running,
+        (_, Value::String(name)) => state_from_name(name),
+        (_, value) => match value.as_i64() {
+            Some(number) => state_from_number(field, number),
+            None => state_from_name(&value.to_string()),
+        },
+    };
+    (state, Some(raw_state))
+}
+
+fn retry_after(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get("Retry-After")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_retry_after(value, SystemTime::now()))
+}
+
+/// The body as JSON, `Null` when empty and a string when not JSON
+async fn body_json(response: Response) -> Result<Value, ODataError> {
+    let text = response
+        .text()
+        .await
+        .map_err(|e| ODataError::ParseError(format!("Failed to read response: {}", e)))?;
+    if text.trim().is_empty() {
+        return Ok(Value::Null);
+    }
+    Ok(serde_json::from_str(&text).unwrap_or(Value::String(text)))
+}
+
+impl ODataClient {
+    /// Call an unbound action (or a bound one by its path, e.g.
+    /// `accounts(<id>)/Microsoft.Dynamics.CRM.Merge`), telling a finished
+    /// call apart from one queued as a long-running operation
+    #[tracing::instrument(skip_all, fields(action = %name))]
+    pub async fn start_action(
+        &self,
+        name: &str,
+        params: &Value,
+    ) -> Result<ActionOutcome, ODataError> {
+        let url = format!("{}{}", self.endpoint(), name);
+        let response = self.post_for_response(&url, params).await?;
+        if response.status() != StatusCode::ACCEPTED {
+            return Ok(ActionOutcome::Completed(body_json(response).await?));
+        }
+
+        let location = response
+            .headers()
+            .get("Location")
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| {
+                ODataError::ParseError(format!(
+                    "{} was accepted (202) without a Location to poll",
+                    name
+                ))
+            })?;
+        // A relative Location is resolved against the action's URL; it is
+        // polled with the bearer token, so only on the service itself
+        let monitor = response.url().join(location).map_err(|e| {
+            ODataError::ParseError(format!("Invalid Location '{}': {}", location, e))
+        })?;
+        let monitor_url = self.service_url(monitor.as_str())?.to_string();
+        Ok(ActionOutcome::Pending(PendingOperation {
+            monitor_url,
+            retry_after: retry_after(&response),
+        }))
+    }
+
+    /// Poll the monitor of a long-running operation once; monitors outside
+    /// the service are refused
+    pub async fn operation_status(&self, monitor_url: &str) -> Result<OperationStatus, ODataError> {
+        let monitor_url = self.service_url(monitor_url)?;
+        let response = self.get_response(monitor_url.as_str()).await?;
+        let status = response.status();
+        let retry_after = retry_after(&response);
+        let body = body_json(response).await?;
+        let (state, raw_state) = parse_status(status, &body);
+        Ok(OperationStatus {
+            state,
+            raw_state,
+            body,
+            retry_after,
+        })
+    }
+
+    /// Poll an operation at the interval its monitor suggests until it
+    /// finishes or `timeout` has passed, returning the last status. Polling
+    /// also stops when the next poll would not fit before the call's deadline.
+    pub async fn wait_for_operation(
+        &self,
+        monitor_url: &str,
+        timeout: Duration,
+    ) -> Result<OperationStatus, ODataError> {
+        let started = Instant::now();
+        let deadline = Deadline::current_or(timeout);
+        loop {
+            let status = self.operation_status(monitor_url).await?;
+            let interval = status
+                .retry_after
+                .unwrap_or(DEFAULT_POLL_INTERVAL)
+                .clamp(MIN_POLL_INTERVAL, MAX_POLL_INTERVAL);
+            let next_poll = started.elapsed() + interval;
+            if status.is_finished() || next_poll > timeout || interval >= deadline.remaining() {
+                return Ok(status);
+            }
+            report_progress(&format!(
+                "Operation {} after {}s of {}s",
+                status.raw_state.as_deref().unwrap_or("running"),
+                started.elapsed().as_secs(),
+                timeout.as_secs()
+            ));
+            cancellable(tokio::time::sleep(interval)).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn states_are_read_from_whichever_field_the_monitor_has() {
+        let cases = [
+            (json!({"status": "Running"}), OperationState::Running),
+            (json!({"Status": "Succeeded"}), OperationState::Succeeded),
+            (json!({"status": "Canceled"}), OperationState::Failed),
+            (
+                json!({"statecode": 3, "statuscode": 31, "message": "Timeout"}),
+                OperationState::Failed,
+            ),
+            (json!({"statecode": 3}), OperationState::Succeeded),
+            (
+                json!({"backgroundoperationstatuscode": 20}),
+                OperationState::Running,
+            ),
+            // A plain result means the operation is done
+            (json!({"JobId": "42"}), OperationState::Succeeded),
+            (Value::Null, OperationState::Succeeded),
+        ];
+        for (body, expected) in cases {
+            assert_eq!(parse_status(StatusCode::OK, &body).0, expected, "{body}");
+        }
+
+        let (state, raw) = parse_status(
+            StatusCode::OK,
+            &json!({
+                "statuscode": 30,
+                "statuscode@OData.Community.Display.V1.FormattedValue": "Succeeded"
+            }),
+        );
+        assert_eq!(state, OperationState::Succeeded);
+        assert_eq!(raw.as_deref(), Some("statuscode: 30 (Succeeded)"));
+
+        // Still running while the monitor answers 202
+        let (state, raw) = parse_status(StatusCode::ACCEPTED, &json!({"status": "Succeeded"}));
+        assert_eq!(state, OperationState::Running);
+        assert_eq!(raw.as_deref(), Some("status: Succeeded"));
+    }
+
+    #[tokio::test]
+    async fn accepted_actions_are_polled_until_they_finish() {
+        use crate::auth::StaticTokenProvider;
+        use crate::config::ProductType;
+        use std::sync::Arc;
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/data/v9.2/BulkDelete"))
+            .respond_with(
+                ResponseTemplate::new(202)
+                    .insert_header("Location", "asyncoperations(7)")
+                    .insert_header("Retry-After", "1"),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/data/v9.2/asyncoperations(7)"))
+            .respond_with(ResponseTemplate::new(202).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/data/v9.2/asyncoperations(7)"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({"statecode": 3, "statuscode": 30})),
+            )
+            .mount(&server)
+            .await;
+        let client = ODataClient::new(
+            Arc::new(StaticTokenProvider::new("test-token")),
+            format!("{}/api/data/v9.2/", server.uri()),
+            ProductType::Dataverse,
+            0,
+            10,
+            false,
+        );
+
+        let ActionOutcome::Pending(pending) = client
+            .start_action("BulkDelete", &json!({"JobName": "cleanup"}))
+            .await
+            .unwrap()
+        else {
+            panic!("expected a pending operation");
+        };
+        assert_eq!(
+            pending.monitor_url,
+            format!("{}/api/data/v9.2/asyncoperations(7)", server.uri())
+        );
+        assert_eq!(pending.retry_after, Some(Duration::from_secs(1)));
+
+        let status = client
+            .wait_for_operation(&pending.monitor_url, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(status.state, OperationState::Succeeded);
+        assert_eq!(status.raw_state.as_deref(), Some("statuscode: 30"));
+        assert_eq!(server.received_requests().await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn monitors_on_other_origins_are_never_polled() {
+        use crate::auth::StaticTokenProvider;
+        use crate::config::ProductType;
+        use std::sync::Arc;
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/data/v9.2/BulkDelete"))
+            .respond_with(ResponseTemplate::new(202).insert_header(
+                "Location",
+                "https://attacker.example.com/api/data/v9.2/asyncoperations(7)",
+            ))
+            .mount(&server)
+            .await;
+        let client = ODataClient::new(
+            Arc::new(StaticTokenProvider::new("test-token")),
+            format!("{}/api/data/v9.2/", server.uri()),
+            ProductType::Dataverse,
+            0,
+            10,
+            false,
+        );
+
+        let error = client
+            .start_action("BulkDelete", &json!({}))
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("Refusing to follow Location"),
+            "{error}"
+        );
+        let error = client
+            .wait_for_operation(
+                "https://attacker.example.com/api/data/v9.2/asyncoperations(7)",
+                Duration::from_secs(1),
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("Refusing to follow Location"),
+            "{error}"
+        );
+        assert_eq!(server.received_requests().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,63 @@
+//! Progress reporting for long-running calls
+//!
+//! Like cancellation, the reporter is scoped to a call with [`with_progress`]
+//! so the OData client can report metadata download and paging progress
+//! without threading it through every signature.
+//!
+//! A call moves through phases counted in different units (metadata bytes,
+//! records, seconds spent polling), so updates carry no numbers of their
+//! own: each one is a step, and its message says how far the phase got.
+
+use std::future::Future;
+use std::sync::Arc;
+
+/// Receives progress updates for the current call
+pub trait ProgressReporter: Send + Sync {
+    /// One more step of the current call, described by `message`
+    fn report(&self, message: &str);
+}
+
+tokio::task_local! {
+    static PROGRESS: Arc<dyn ProgressReporter>;
+}
+
+/// Run `future` with `reporter` receiving the progress of the OData calls it makes
+pub async fn with_progress<F: Future>(reporter: Arc<dyn ProgressReporter>, future: F) -> F::Output {
+    PROGRESS.scope(reporter, future).await
+}
+
+/// Report progress to the current call's reporter, if any
+pub(crate) fn report_progress(message: &str) {
+    let _ = PROGRESS.try_with(|reporter| reporter.report(message));
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Records every update it receives
+    #[derive(Default)]
+    pub(crate) struct RecordingReporter {
+        pub updates: Mutex<Vec<String>>,
+    }
+
+    impl ProgressReporter for RecordingReporter {
+        fn report(&self, message: &str) {
+            self.updates.lock().unwrap().push(message.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_reach_the_scoped_reporter_only() {
+        report_progress("outside any scope");
+
+        let reporter = Arc::new(RecordingReporter::default());
+        with_progress(reporter.clone(), async {
+            report_progress("half way");
+        })
+        .await;
+
+        assert_eq!(*reporter.updates.lock().unwrap(), vec!["half way"]);
+    }
+}
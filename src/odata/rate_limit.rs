@@ -0,0 +1,244 @@
+//! Client-side rate limiting
+//!
+//! Token bucket plus an optional concurrency cap, used by `ODataClient` to stay
+//! under the Dataverse service protection limits instead of relying on 429s.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep, Instant};
+
+/// Window used for the "requests in the last window" statistic
+const STATS_WINDOW: Duration = Duration::from_secs(60);
+
+/// Token bucket state
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Counts a caller out of `waiting` when its `acquire` ends or is dropped
+struct WaitingGuard<'a>(&'a AtomicUsize);
+
+impl Drop for WaitingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Snapshot of limiter activity for diagnostics
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimiterStats {
+    /// Configured requests-per-minute limit, if any
+    pub max_requests_per_minute: Option<u32>,
+    /// Configured concurrent request limit, if any
+    pub max_concurrent_requests: Option<usize>,
+    /// Requests sent during the last 60 seconds
+    pub requests_last_minute: usize,
+    /// Callers currently waiting for a token or a concurrency slot
+    pub waiting: usize,
+}
+
+/// Held for the duration of a request; releases the concurrency slot on drop
+#[derive(Debug)]
+pub struct RateLimitPermit {
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+/// Token-bucket rate limiter with an optional concurrency cap
+#[derive(Debug)]
+pub struct RateLimiter {
+    max_requests_per_minute: Option<u32>,
+    max_concurrent_requests: Option<usize>,
+    bucket: Mutex<Bucket>,
+    slots: Option<Arc<Semaphore>>,
+    waiting: AtomicUsize,
+    recent: Mutex<VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    /// Create a limiter; `None` disables the corresponding limit
+    pub fn new(
+        max_requests_per_minute: Option<u32>,
+        max_concurrent_requests: Option<usize>,
+    ) -> Self {
+        let max_requests_per_minute = max_requests_per_minute.filter(|n| *n > 0);
+        let max_concurrent_requests = max_concurrent_requests.filter(|n| *n > 0);
+
+        Self {
+            max_requests_per_minute,
+            max_concurrent_requests,
+            bucket: Mutex::new(Bucket {
+                tokens: max_requests_per_minute.unwrap_or_default() as f64,
+                last_refill: Instant::now(),
+            }),
+            slots: max_concurrent_requests.map(|n| Arc::new(Semaphore::new(n))),
+            waiting: AtomicUsize::new(0),
+            recent: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// A limiter that never waits
+    pub fn unlimited() -> Self {
+        Self::new(None, None)
+    }
+
+    /// Wait until a request may be sent
+    ///
+    /// Never errors: when the bucket is empty the caller sleeps until a token
+    /// has been refilled.
+    pub async fn acquire(&self) -> RateLimitPermit {
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        // Counted down on drop, so a caller cancelled while waiting is too
+        let _waiting = WaitingGuard(&self.waiting);
+
+        let slot = match self.slots {
+            Some(ref slots) => slots.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+
+        if let Some(rpm) = self.max_requests_per_minute {
+            loop {
+                match self.try_take_token(rpm) {
+                    None => break,
+                    Some(wait) => {
+                        tracing::debug!("Rate limiter: waiting {:?} for a token", wait);
+                        sleep(wait).await;
+                    }
+                }
+            }
+        }
+
+        self.record_request();
+
+        RateLimitPermit { _slot: slot }
+    }
+
+    /// Take a token, or return how long until one becomes available
+    fn try_take_token(&self, rpm: u32) -> Option<Duration> {
+        let capacity = rpm as f64;
+        let per_second = capacity / 60.0;
+
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+
+    fn record_request(&self) {
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap();
+        recent.push_back(now);
+        while recent
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > STATS_WINDOW)
+        {
+            recent.pop_front();
+        }
+    }
+
+    /// Current limiter statistics
+    pub fn stats(&self) -> RateLimiterStats {
+        let now = Instant::now();
+        let requests_last_minute = self
+            .recent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|t| now.duration_since(**t) <= STATS_WINDOW)
+            .count();
+
+        RateLimiterStats {
+            max_requests_per_minute: self.max_requests_per_minute,
+            max_concurrent_requests: self.max_concurrent_requests,
+            requests_last_minute,
+            waiting: self.waiting.load(Ordering::SeqCst),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn unlimited_limiter_never_waits() {
+        let limiter = RateLimiter::unlimited();
+        let start = Instant::now();
+        for _ in 0..1000 {
+            limiter.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!(limiter.stats().requests_last_minute, 1000);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn empty_bucket_waits_for_refill() {
+        let limiter = RateLimiter::new(Some(60), None);
+        let start = Instant::now();
+
+        for _ in 0..60 {
+            limiter.acquire().await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // 60/min refills one token per second
+        limiter.acquire().await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(990), "{:?}", elapsed);
+        assert!(elapsed <= Duration::from_millis(1010), "{:?}", elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrency_cap_queues_waiters() {
+        let limiter = Arc::new(RateLimiter::new(None, Some(1)));
+        let first = limiter.acquire().await;
+
+        let waiter = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move {
+                limiter.acquire().await;
+            })
+        };
+        tokio::task::yield_now().await;
+
+        let stats = limiter.stats();
+        assert_eq!(stats.waiting, 1);
+        assert_eq!(stats.max_concurrent_requests, Some(1));
+        assert!(!waiter.is_finished());
+
+        drop(first);
+        waiter.await.unwrap();
+        assert_eq!(limiter.stats().waiting, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_waiters_are_not_counted() {
+        let limiter = Arc::new(RateLimiter::new(None, Some(1)));
+        let _first = limiter.acquire().await;
+
+        let waiter = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move {
+                limiter.acquire().await;
+            })
+        };
+        tokio::task::yield_now().await;
+        assert_eq!(limiter.stats().waiting, 1);
+
+        waiter.abort();
+        assert!(waiter.await.unwrap_err().is_cancelled());
+        assert_eq!(limiter.stats().waiting, 0);
+    }
+}
@@ -0,0 +1,67 @@
+//! Dataverse table row counts
+//!
+//! `$count=true` on Dataverse stops counting at 5000. The
+//! `RetrieveTotalRecordCount` function returns the whole table's row count
+//! instead, from a snapshot the platform refreshes about once a day: it is
+//! not filtered and may be up to 24 hours old, but it is not capped.
+
+use super::client::{ODataClient, ODataError};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Largest `@odata.count` Dataverse reports; a count this high may be capped
+pub const DATAVERSE_COUNT_LIMIT: i64 = 5000;
+
+/// Row counts by logical name from a `RetrieveTotalRecordCount` response
+/// (`EntityRecordCountCollection` of parallel `Keys` and `Values`)
+fn parse_record_counts(response: &Value) -> HashMap<String, i64> {
+    let collection = &response["EntityRecordCountCollection"];
+    let keys = collection["Keys"].as_array().into_iter().flatten();
+    let values = collection["Values"].as_array().into_iter().flatten();
+    keys.zip(values)
+        .filter_map(|(key, value)| Some((key.as_str()?.to_string(), value.as_i64()?)))
+        .collect()
+}
+
+impl ODataClient {
+    /// Row counts of Dataverse tables by logical name (e.g. `account`), as of
+    /// the platform's last snapshot; tables it has no count for are left out
+    pub async fn total_record_counts(
+        &self,
+        logical_names: &[&str],
+    ) -> Result<HashMap<String, i64>, ODataError> {
+        let names = Value::from(logical_names.to_vec()).to_string();
+        let response = self
+            .execute_function("RetrieveTotalRecordCount", &[("EntityNames", &names)])
+            .await?;
+        Ok(parse_record_counts(&response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn counts_pair_keys_with_values() {
+        let response = json!({
+            "@odata.context": "$metadata#Microsoft.Dynamics.CRM.RetrieveTotalRecordCountResponse",
+            "EntityRecordCountCollection": {
+                "Count": 2,
+                "IsReadOnly": false,
+                "Keys": ["account", "contact"],
+                "Values": [12873, 40211]
+            }
+        });
+
+        assert_eq!(
+            parse_record_counts(&response),
+            HashMap::from([
+                ("account".to_string(), 12873),
+                ("contact".to_string(), 40211)
+            ])
+        );
+        assert!(parse_record_counts(&json!({})).is_empty());
+    }
+}
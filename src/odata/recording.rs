@@ -0,0 +1,356 @@
+//! Request/response recording and replay
+//!
+//! In record mode every exchange with the service is written to a directory
+//! as a numbered JSON file: the request (method, URL relative to the service
+//! root with secrets masked, body) and the response (status, a subset of
+//! headers, body). In replay mode the client answers from such a directory
+//! instead of the network, matching on method and URL, which makes recorded
+//! sessions usable as offline test fixtures.
+
+use super::dry_run::PreparedRequest;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use reqwest::{Method, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Response headers worth keeping; the rest are per-request noise
+const RECORDED_HEADERS: &[&str] = &[
+    "content-type",
+    "content-range",
+    "content-disposition",
+    "etag",
+    "location",
+    "odata-entityid",
+    "preference-applied",
+    "retry-after",
+    "www-authenticate",
+    "x-ms-file-name",
+];
+
+/// Query parameters whose values never reach a recording
+const SECRET_PARAMETERS: &[&str] = &[
+    "sig",
+    "code",
+    "token",
+    "access_token",
+    "key",
+    "secret",
+    "client_secret",
+    "password",
+];
+
+/// Whether the client writes exchanges or serves them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordingMode {
+    Record,
+    Replay,
+}
+
+/// One request and the response it got
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Exchange {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// Relative to the service root when under it, with secrets masked
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// JSON body
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<Value>,
+    /// Any other text body, e.g. `$metadata` XML
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// Binary body
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base64: Option<String>,
+}
+
+impl RecordedResponse {
+    fn from_parts(status: u16, headers: BTreeMap<String, String>, bytes: &[u8]) -> Self {
+        let mut response = Self {
+            status,
+            headers,
+            body: None,
+            text: None,
+            base64: None,
+        };
+        match std::str::from_utf8(bytes) {
+            Ok("") => {}
+            Ok(text) => match serde_json::from_str(text) {
+                Ok(json) => response.body = Some(json),
+                Err(_) => response.text = Some(text.to_string()),
+            },
+            Err(_) => response.base64 = Some(STANDARD.encode(bytes)),
+        }
+        response
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        if let Some(ref body) = self.body {
+            serde_json::to_vec(body).unwrap_or_default()
+        } else if let Some(ref text) = self.text {
+            text.clone().into_bytes()
+        } else {
+            self.base64
+                .as_deref()
+                .and_then(|encoded| STANDARD.decode(encoded).ok())
+                .unwrap_or_default()
+        }
+    }
+
+    fn to_response(&self) -> Response {
+        let mut builder = http::Response::builder().status(self.status);
+        for (name, value) in &self.headers {
+            builder = builder.header(name, value);
+        }
+        builder
+            .body(self.bytes())
+            .map(Response::from)
+            .unwrap_or_else(|_| Response::from(http::Response::new(Vec::new())))
+    }
+}
+
+/// A directory of recorded exchanges, written or served by `ODataClient`
+pub struct Recording {
+    dir: PathBuf,
+    mode: RecordingMode,
+    /// Number of the next file written
+    next: AtomicUsize,
+    /// Replay fixtures in recorded order, with whether each was served
+    fixtures: Mutex<Vec<(Exchange, bool)>>,
+}
+
+impl std::fmt::Debug for Recording {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recording")
+            .field("dir", &self.dir)
+            .field("mode", &self.mode)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Recording {
+    /// Write exchanges to `dir`, numbering after any files already there
+    pub fn record(dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let existing = exchange_files(&dir)?.len();
+        Ok(Self {
+            dir,
+            mode: RecordingMode::Record,
+            next: AtomicUsize::new(existing + 1),
+            fixtures: Mutex::default(),
+        })
+    }
+
+    /// Serve the exchanges recorded in `dir`
+    pub fn replay(dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        let mut fixtures = Vec::new();
+        for file in exchange_files(&dir)? {
+            let exchange: Exchange = serde_json::from_slice(&fs::read(&file)?).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{}: {}", file.display(), e),
+                )
+            })?;
+            fixtures.push((exchange, false));
+        }
+        Ok(Self {
+            dir,
+            mode: RecordingMode::Replay,
+            next: AtomicUsize::new(1),
+            fixtures: Mutex::new(fixtures),
+        })
+    }
+
+    pub fn mode(&self) -> RecordingMode {
+        self.mode
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Write the exchange and hand back an equivalent, unread response
+    pub(crate) async fn capture(
+        &self,
+        endpoint: &str,
+        request: &PreparedRequest,
+        response: Response,
+    ) -> Result<Response, reqwest::Error> {
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .filter(|(name, _)| RECORDED_HEADERS.contains(&name.as_str()))
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        let bytes = response.bytes().await?;
+        let exchange = Exchange {
+            request: RecordedRequest {
+                method: request.method.to_string(),
+                url: recorded_url(endpoint, &request.url),
+                body: request
+                    .body
+                    .as_deref()
+                    .map(|body| serde_json::from_str(body).unwrap_or_else(|_| body.into())),
+            },
+            response: RecordedResponse::from_parts(status, headers, &bytes),
+        };
+
+        let number = self.next.fetch_add(1, Ordering::Relaxed);
+        let file = self.dir.join(format!(
+            "{:04}-{}.json",
+            number,
+            request.method.as_str().to_lowercase()
+        ));
+        let written = serde_json::to_vec_pretty(&exchange)
+            .map_err(std::io::Error::from)
+            .and_then(|json| fs::write(&file, json));
+        if let Err(e) = written {
+            tracing::warn!("Could not record {}: {}", file.display(), e);
+        }
+        Ok(exchange.response.to_response())
+    }
+
+    /// The recorded response to `method url`: the first one not served yet,
+    /// or the last one once all were
+    pub(crate) fn respond(&self, endpoint: &str, method: &Method, url: &str) -> Option<Response> {
+        let url = recorded_url(endpoint, url);
+        let mut fixtures = self.fixtures.lock().unwrap();
+        let mut matching = fixtures.iter_mut().filter(|(exchange, _)| {
+            exchange
+                .request
+                .method
+                .eq_ignore_ascii_case(method.as_str())
+                && exchange.request.url == url
+        });
+        let mut last = None;
+        for (exchange, served) in &mut matching {
+            if !*served {
+                *served = true;
+                return Some(exchange.response.to_response());
+            }
+            last = Some(&exchange.response);
+        }
+        last.map(RecordedResponse::to_response)
+    }
+}
+
+/// Recorded files in `dir`, in recording order
+fn exchange_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// URL as recorded: relative to the service root, secret parameters masked
+fn recorded_url(endpoint: &str, url: &str) -> String {
+    let relative = url.strip_prefix(endpoint).unwrap_or(url);
+    let Some((path, query)) = relative.split_once('?') else {
+        return relative.to_string();
+    };
+    let query = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if SECRET_PARAMETERS.contains(&name.to_lowercase().as_str()) => {
+                format!("{}=REDACTED", name)
+            }
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    format!("{}?{}", path, query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_are_relative_with_secrets_masked() {
+        let endpoint = "https://org.crm.dynamics.com/api/data/v9.2/";
+        assert_eq!(
+            recorded_url(endpoint, &format!("{}accounts?$top=5", endpoint)),
+            "accounts?$top=5"
+        );
+        assert_eq!(
+            recorded_url(
+                endpoint,
+                "https://files.example.com/blob?sv=2022&sig=abc%3D&se=1"
+            ),
+            "https://files.example.com/blob?sv=2022&sig=REDACTED&se=1"
+        );
+    }
+
+    #[tokio::test]
+    async fn recorded_exchanges_replay_in_order() {
+        let dir = std::env::temp_dir().join(format!(
+            "d365-recording-{}-{}",
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        let endpoint = "https://org.example.com/data/";
+        let request = PreparedRequest {
+            method: Method::GET,
+            url: format!("{}accounts", endpoint),
+            headers: Vec::new(),
+            body: None,
+        };
+        let recording = Recording::record(&dir).unwrap();
+        for name in ["first", "second"] {
+            let response = http::Response::builder()
+                .status(200)
+                .header("etag", "W/\"1\"")
+                .header("date", "today")
+                .body(format!(r#"{{"value":[{{"name":"{}"}}]}}"#, name))
+                .unwrap();
+            let response = recording
+                .capture(endpoint, &request, Response::from(response))
+                .await
+                .unwrap();
+            // The caller still gets the whole body
+            assert!(response.text().await.unwrap().contains(name));
+        }
+
+        let replay = Recording::replay(&dir).unwrap();
+        let other_endpoint = "http://127.0.0.1:1/data/";
+        let url = format!("{}accounts", other_endpoint);
+        let first = replay.respond(other_endpoint, &Method::GET, &url).unwrap();
+        assert_eq!(first.headers()["etag"], "W/\"1\"");
+        assert!(first.headers().get("date").is_none());
+        assert!(first.text().await.unwrap().contains("first"));
+        for _ in 0..2 {
+            let again = replay.respond(other_endpoint, &Method::GET, &url).unwrap();
+            assert!(again.text().await.unwrap().contains("second"));
+        }
+        assert!(replay
+            .respond(other_endpoint, &Method::DELETE, &url)
+            .is_none());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,196 @@
+//! Dataverse relevance search
+//!
+//! Full-text search across tables through the Dataverse search API
+//! (`/api/search/v1.0/query`), which lives beside the Web API rather than
+//! under it, so its URL is derived from the configured service root.
+
+use super::client::{ODataClient, ODataError};
+use crate::config::ProductType;
+use reqwest::Url;
+use serde_json::{json, Map, Value};
+
+/// One search hit
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    /// Logical name of the table, e.g. `account`
+    pub entity: String,
+    /// Primary key of the record
+    pub id: String,
+    pub score: f64,
+    /// Matched fragments per field, with hits marked `**like this**`
+    pub highlights: Vec<(String, Vec<String>)>,
+    /// Remaining (non-annotation) fields returned for the record
+    pub fields: Map<String, Value>,
+}
+
+/// Search results, best first
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SearchResults {
+    pub hits: Vec<SearchHit>,
+    /// Total number of matches, when the service reports it
+    pub total: Option<u64>,
+}
+
+/// Search API URL for a Web API service root:
+/// `https://org.crm.dynamics.com/api/data/v9.2/` becomes
+/// `https://org.crm.dynamics.com/api/search/v1.0/query`
+pub fn search_url(endpoint: &str) -> Result<String, ODataError> {
+    let not_web_api = || {
+        ODataError::ParseError(format!(
+            "Relevance search needs the Dataverse Web API root (https://org.crm.dynamics.com/api/data/v9.2/), got '{}'",
+            endpoint
+        ))
+    };
+    let mut url = Url::parse(endpoint).map_err(|_| not_web_api())?;
+    let path = url.path().to_string();
+    let prefix = path
+        .to_ascii_lowercase()
+        .find("/api/data/")
+        .map(|index| &path[..index])
+        .ok_or_else(not_web_api)?;
+    url.set_path(&format!("{}/api/search/v1.0/query", prefix));
+    url.set_query(None);
+    Ok(url.to_string())
+}
+
+/// Highlights come back as `{crmhit}Contoso{/crmhit}`
+fn mark_hits(fragment: &str) -> String {
+    fragment
+        .replace("{crmhit}", "**")
+        .replace("{/crmhit}", "**")
+}
+
+fn parse_hit(value: &Value) -> Option<SearchHit> {
+    let record = value.as_object()?;
+    let text = |name: &str| record.get(name).and_then(Value::as_str).map(String::from);
+
+    let highlights = record
+        .get("@search.highlights")
+        .and_then(Value::as_object)
+        .map(|fields| {
+            fields
+                .iter()
+                .map(|(field, fragments)| {
+                    let fragments = fragments
+                        .as_array()
+                        .map(|fragments| {
+                            fragments
+                                .iter()
+                                .filter_map(Value::as_str)
+                                .map(mark_hits)
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    (field.clone(), fragments)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Some(SearchHit {
+        entity: text("@search.entityname")?,
+        id: text("@search.objectid").unwrap_or_default(),
+        score: record
+            .get("@search.score")
+            .and_then(Value::as_f64)
+            .unwrap_or_default(),
+        highlights,
+        fields: record
+            .iter()
+            .filter(|(name, _)| !name.starts_with('@'))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect(),
+    })
+}
+
+/// Parse a search API response body
+pub fn parse_search_response(body: &Value) -> SearchResults {
+    SearchResults {
+        hits: body
+            .get("value")
+            .and_then(Value::as_array)
+            .map(|hits| hits.iter().filter_map(parse_hit).collect())
+            .unwrap_or_default(),
+        total: body
+            .get("totalrecordcount")
+            .and_then(Value::as_i64)
+            .and_then(|total| u64::try_from(total).ok()),
+    }
+}
+
+impl ODataClient {
+    /// Relevance search for `query`, optionally limited to some tables
+    /// (logical names such as `account`); Dataverse only
+    pub async fn relevance_search(
+        &self,
+        query: &str,
+        entities: &[String],
+        top: usize,
+    ) -> Result<SearchResults, ODataError> {
+        if *self.product() != ProductType::Dataverse {
+            return Err(ODataError::ParseError(
+                "Relevance search is only available for Dataverse".to_string(),
+            ));
+        }
+
+        let mut body = json!({
+            "search": query,
+            "top": top,
+            "returntotalrecordcount": true,
+        });
+        if !entities.is_empty() {
+            body["entities"] = json!(entities);
+        }
+        let response = self.post_json(&search_url(self.endpoint())?, &body).await?;
+        Ok(parse_search_response(&response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_url_replaces_the_web_api_path() {
+        assert_eq!(
+            search_url("https://org.crm4.dynamics.com/api/data/v9.2/").unwrap(),
+            "https://org.crm4.dynamics.com/api/search/v1.0/query"
+        );
+        // Behind a reverse proxy prefix, without a trailing slash
+        assert_eq!(
+            search_url("http://localhost:8080/crm/API/data/v9.1").unwrap(),
+            "http://localhost:8080/crm/api/search/v1.0/query"
+        );
+        assert!(search_url("https://org.operations.dynamics.com/data/").is_err());
+    }
+
+    #[test]
+    fn hits_keep_scores_highlights_and_fields() {
+        let body = json!({
+            "value": [{
+                "@search.score": 4.5,
+                "@search.entityname": "account",
+                "@search.objectid": "5f2a1c3e-8b7d-4e6f-9a0b-1c2d3e4f5a6b",
+                "@search.highlights": {"name": ["{crmhit}Contoso{/crmhit} Ltd"]},
+                "name": "Contoso Ltd"
+            }, {
+                "@search.score": 1.0
+            }],
+            "totalrecordcount": 7
+        });
+
+        let results = parse_search_response(&body);
+        assert_eq!(results.total, Some(7));
+        // A hit without an entity name is unusable
+        assert_eq!(results.hits.len(), 1);
+        let hit = &results.hits[0];
+        assert_eq!(hit.entity, "account");
+        assert_eq!(hit.score, 4.5);
+        assert_eq!(
+            hit.highlights,
+            vec![("name".to_string(), vec!["**Contoso** Ltd".to_string()])]
+        );
+        assert_eq!(hit.fields.get("name"), Some(&json!("Contoso Ltd")));
+        assert_eq!(hit.fields.len(), 1);
+    }
+}
@@ -0,0 +1,462 @@
+//! Request logging and statistics
+//!
+//! Every OData request is traced with its method, URL, status, attempt and
+//! elapsed time, plus the correlation ids Microsoft support asks for: the
+//! `client-request-id` we send and the `x-ms-service-request-id` / `ms-cv`
+//! the service returns. Filter values are personal data more often than not,
+//! so the URL logged at info level has them masked; the full URL is logged
+//! at debug level.
+//!
+//! Requests made for a tool call also name the MCP client behind it (from
+//! `initialize`), in the span and in the `User-Agent` header, so service-side
+//! telemetry can tell one assistant's traffic from another's. The ids of the
+//! requests that failed during a call are collected with
+//! [`with_failed_request_ids`], so the tool's error can quote them.
+
+use crate::network::USER_AGENT;
+use percent_encoding::percent_decode_str;
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+tokio::task_local! {
+    static MCP_CLIENT: String;
+    static FAILED_REQUESTS: Arc<Mutex<Vec<String>>>;
+}
+
+/// Query options whose values are masked in info-level logs
+const REDACTED_OPTIONS: &[&str] = &["$filter", "$apply"];
+
+/// Upper bounds of the latency histogram buckets, in milliseconds; slower
+/// observations land in a final overflow bucket
+pub const LATENCY_BUCKETS_MS: [u64; 12] = [
+    10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000, 60_000,
+];
+
+/// Lock-free latency histogram over [`LATENCY_BUCKETS_MS`]
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// Observations per bucket, the overflow bucket last
+    buckets: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+    sum_ms: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let ms = elapsed.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_ms.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+            sum_ms: self.sum_ms.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Point-in-time copy of a [`Histogram`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HistogramSnapshot {
+    /// Observations per bucket of [`LATENCY_BUCKETS_MS`], the overflow bucket last
+    pub buckets: [u64; LATENCY_BUCKETS_MS.len() + 1],
+    /// Sum of all observations in milliseconds
+    pub sum_ms: u64,
+}
+
+impl HistogramSnapshot {
+    /// Number of observations
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Mean observation in milliseconds; 0 when empty
+    pub fn mean_ms(&self) -> u64 {
+        self.sum_ms.checked_div(self.count()).unwrap_or(0)
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile (0.0 to 1.0);
+    /// `None` when empty or when it falls in the overflow bucket
+    pub fn quantile_bound_ms(&self, q: f64) -> Option<u64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((count as f64) * q).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bound, observed) in LATENCY_BUCKETS_MS.iter().zip(self.buckets) {
+            seen += observed;
+            if seen >= rank {
+                return Some(*bound);
+            }
+        }
+        None
+    }
+
+    /// Both snapshots' observations together
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            buckets: std::array::from_fn(|i| self.buckets[i] + other.buckets[i]),
+            sum_ms: self.sum_ms + other.sum_ms,
+        }
+    }
+}
+
+/// Request counters since the client was created
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RequestStats {
+    /// HTTP requests sent, retries included
+    pub total_requests: u64,
+    /// Requests that were retries of an earlier attempt
+    pub retries: u64,
+    /// Responses with status 429
+    pub throttled: u64,
+    /// Mean time from send to response headers
+    pub average_latency_ms: u64,
+    /// Times the access token changed after the first one
+    pub token_refreshes: u64,
+    /// Time from send to response headers
+    pub latency: HistogramSnapshot,
+    /// Last successful (2xx) response
+    pub last_success: Option<SystemTime>,
+    /// Last 429, server error or request that got no response
+    pub last_failure: Option<SystemTime>,
+    /// Such failures since the last successful response
+    pub consecutive_failures: u64,
+}
+
+impl RequestStats {
+    /// Counters of two clients together, e.g. across environments
+    pub fn merge(self, other: Self) -> Self {
+        let latency = self.latency.merge(other.latency);
+        Self {
+            total_requests: self.total_requests + other.total_requests,
+            retries: self.retries + other.retries,
+            throttled: self.throttled + other.throttled,
+            average_latency_ms: latency.mean_ms(),
+            token_refreshes: self.token_refreshes + other.token_refreshes,
+            latency,
+            last_success: self.last_success.max(other.last_success),
+            last_failure: self.last_failure.max(other.last_failure),
+            consecutive_failures: self.consecutive_failures.max(other.consecutive_failures),
+        }
+    }
+}
+
+/// Lock-free counters behind `RequestStats`, cheap enough to update on every request
+#[derive(Debug, Default)]
+pub struct RequestCounters {
+    requests: AtomicU64,
+    retries: AtomicU64,
+    throttled: AtomicU64,
+    latency: Histogram,
+    token_refreshes: AtomicU64,
+    /// Hash of the last token sent; 0 before the first
+    token_hash: AtomicU64,
+    /// Unix milliseconds; 0 for never
+    last_success_ms: AtomicU64,
+    last_failure_ms: AtomicU64,
+    consecutive_failures: AtomicU64,
+}
+
+impl RequestCounters {
+    /// Count one answered request
+    pub fn record(&self, attempt: u32, status: u16, elapsed: Duration) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.latency.observe(elapsed);
+        if attempt > 1 {
+            self.retries.fetch_add(1, Ordering::Relaxed);
+        }
+        if status == 429 {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+        }
+        match status {
+            200..=299 => {
+                self.last_success_ms.store(unix_ms(), Ordering::Relaxed);
+                self.consecutive_failures.store(0, Ordering::Relaxed);
+            }
+            429 | 500.. => self.record_failure(),
+            _ => {}
+        }
+    }
+
+    /// Note a request that got no response at all
+    pub fn record_failure(&self) {
+        self.last_failure_ms.store(unix_ms(), Ordering::Relaxed);
+        self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Note the token a request is sent with; a different token than last
+    /// time counts as a refresh
+    pub fn observe_token(&self, token: &str) {
+        let mut hasher = DefaultHasher::new();
+        token.hash(&mut hasher);
+        // 0 is reserved for "no token yet"
+        let hash = hasher.finish().max(1);
+        let previous = self.token_hash.swap(hash, Ordering::Relaxed);
+        if previous != 0 && previous != hash {
+            self.token_refreshes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> RequestStats {
+        let latency = self.latency.snapshot();
+        let time = |ms: &AtomicU64| match ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(UNIX_EPOCH + Duration::from_millis(ms)),
+        };
+        RequestStats {
+            total_requests: self.requests.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            throttled: self.throttled.load(Ordering::Relaxed),
+            average_latency_ms: latency.mean_ms(),
+            token_refreshes: self.token_refreshes.load(Ordering::Relaxed),
+            latency,
+            last_success: time(&self.last_success_ms),
+            last_failure: time(&self.last_failure_ms),
+            consecutive_failures: self.consecutive_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Random version 4 UUID for the `client-request-id` header
+pub fn new_client_request_id() -> String {
+    let bits =
+        rand::random::<u128>() & !(0xf000 << 64) & !(0xc << 60) | (0x4000 << 64) | (0x8 << 60);
+    let hex = format!("{:032x}", bits);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Run `future` with its OData requests attributed to `client`
+/// (`name/version` of the MCP client)
+pub async fn with_mcp_client<F: Future>(client: String, future: F) -> F::Output {
+    MCP_CLIENT.scope(client, future).await
+}
+
+/// The MCP client the current call was made by, if any
+pub(crate) fn mcp_client() -> Option<String> {
+    MCP_CLIENT.try_with(String::clone).ok()
+}
+
+/// `User-Agent` for OData requests: the server, plus the MCP client when
+/// known and the deployment's `suffix`
+pub(crate) fn user_agent(suffix: Option<&str>) -> String {
+    let mut user_agent = USER_AGENT.to_string();
+    if let Some(client) = mcp_client() {
+        user_agent.push_str(&format!(" ({})", client));
+    }
+    if let Some(suffix) = suffix {
+        user_agent.push(' ');
+        user_agent.push_str(suffix);
+    }
+    user_agent
+}
+
+/// Run `future`, returning with its output the `client-request-id` of each
+/// of its requests that failed, in order
+pub async fn with_failed_request_ids<F: Future>(future: F) -> (F::Output, Vec<String>) {
+    let failed = Arc::new(Mutex::new(Vec::new()));
+    let output = FAILED_REQUESTS.scope(failed.clone(), future).await;
+    let ids = std::mem::take(&mut *failed.lock().unwrap());
+    (output, ids)
+}
+
+/// Note a failed request for the enclosing [`with_failed_request_ids`], if any
+pub(crate) fn record_failed_request(client_request_id: &str) {
+    let _ = FAILED_REQUESTS.try_with(|failed| {
+        failed.lock().unwrap().push(client_request_id.to_string());
+    });
+}
+
+/// `url` with string literals and bare values in `$filter` and `$apply`
+/// replaced by `***`; field names and operators stay readable. A `$search`,
+/// whose terms are all values, and the signature of a storage SAS URL are
+/// masked entirely.
+pub fn redact_url(url: &str) -> String {
+    let Some((base, query)) = url.split_once('?') else {
+        return url.to_string();
+    };
+    let query = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, value)) if REDACTED_OPTIONS.contains(&name) => {
+                let value = percent_decode_str(value).decode_utf8_lossy();
+                format!("{}={}", name, redact_expression(&value))
+            }
+            Some((name @ ("$search" | "sig"), _)) => format!("{}=***", name),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    format!("{}?{}", base, query)
+}
+
+/// Mask quoted strings and tokens that start like a literal (digits, GUIDs,
+/// dates); identifiers, operators and `true`/`false`/`null` are kept
+fn redact_expression(expression: &str) -> String {
+    let mut out = String::with_capacity(expression.len());
+    let mut chars = expression.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\'' {
+            // '' is an escaped quote inside a literal
+            loop {
+                match chars.next() {
+                    Some('\'') if chars.peek() == Some(&'\'') => {
+                        chars.next();
+                    }
+                    Some('\'') | None => break,
+                    Some(_) => {}
+                }
+            }
+            out.push_str("'***'");
+        } else if c.is_ascii_digit() || (c == '-' && chars.peek().is_some_and(char::is_ascii_digit))
+        {
+            while chars
+                .peek()
+                .is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | ':'))
+            {
+                chars.next();
+            }
+            out.push_str("***");
+        } else if c.is_alphanumeric() || c == '_' {
+            // Identifiers may contain digits, e.g. `address1_city`
+            out.push(c);
+            while let Some(&next) = chars.peek() {
+                if !(next.is_alphanumeric() || matches!(next, '_' | '/' | '.')) {
+                    break;
+                }
+                out.push(next);
+                chars.next();
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_values_are_masked_but_fields_stay() {
+        let url = "https://org.crm.dynamics.com/api/data/v9.2/contacts?$select=fullname\
+                   &$filter=emailaddress1%20eq%20%27jane%40contoso.com%27%20and%20address1_city%20ne%20%27O%27%27Hare%27\
+                   %20and%20_parentcustomerid_value%20eq%2000000000-0000-0000-0000-0000000000a1%20and%20revenue%20gt%20-5.5\
+                   %20and%20donotemail%20eq%20false&$top=5";
+        assert_eq!(
+            redact_url(url),
+            "https://org.crm.dynamics.com/api/data/v9.2/contacts?$select=fullname\
+             &$filter=emailaddress1 eq '***' and address1_city ne '***' \
+             and _parentcustomerid_value eq *** and revenue gt *** and donotemail eq false&$top=5"
+        );
+        assert_eq!(redact_url("https://x/accounts"), "https://x/accounts");
+        assert_eq!(
+            redact_url("https://store.blob.core.windows.net/dmf/p.zip?sv=2014-02-14&sr=b&sig=abc%2Bdef&sp=rw"),
+            "https://store.blob.core.windows.net/dmf/p.zip?sv=2014-02-14&sr=b&sig=***&sp=rw"
+        );
+        assert_eq!(
+            redact_url("https://x/accounts?$search=%22jane%20doe%22%20OR%20contoso&$top=5"),
+            "https://x/accounts?$search=***&$top=5"
+        );
+    }
+
+    #[test]
+    fn client_request_ids_are_version_4_uuids() {
+        let id = new_client_request_id();
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        assert!(matches!(&id[19..20], "8" | "9" | "a" | "b"), "{id}");
+        assert_ne!(id, new_client_request_id());
+    }
+
+    #[tokio::test]
+    async fn the_user_agent_names_the_mcp_client_of_the_call() {
+        let server = concat!("d365-odata-mcp/", env!("CARGO_PKG_VERSION"));
+        assert_eq!(user_agent(None), server);
+        assert_eq!(
+            user_agent(Some("contoso-uat")),
+            format!("{} contoso-uat", server)
+        );
+
+        let inside = with_mcp_client("claude-desktop/0.9.2".to_string(), async {
+            (user_agent(Some("contoso-uat")), mcp_client())
+        })
+        .await;
+        assert_eq!(
+            inside.0,
+            format!("{} (claude-desktop/0.9.2) contoso-uat", server)
+        );
+        assert_eq!(inside.1.as_deref(), Some("claude-desktop/0.9.2"));
+        assert_eq!(mcp_client(), None);
+    }
+
+    #[tokio::test]
+    async fn failed_request_ids_are_collected_per_call() {
+        // Outside a collecting call a failure is only logged
+        record_failed_request("ignored");
+        let ((), ids) = with_failed_request_ids(async {
+            record_failed_request("first");
+            record_failed_request("second");
+        })
+        .await;
+        assert_eq!(ids, vec!["first", "second"]);
+    }
+
+    #[test]
+    fn stats_average_latency_and_count_retries() {
+        let counters = RequestCounters::default();
+        assert_eq!(counters.stats(), RequestStats::default());
+        counters.record(1, 429, Duration::from_millis(100));
+        counters.record(2, 200, Duration::from_millis(300));
+        counters.observe_token("a");
+        counters.observe_token("a");
+        counters.observe_token("b");
+
+        let stats = counters.stats();
+        assert_eq!(
+            (
+                stats.total_requests,
+                stats.retries,
+                stats.throttled,
+                stats.average_latency_ms,
+                stats.token_refreshes
+            ),
+            (2, 1, 1, 200, 1)
+        );
+        assert!(stats.last_success.is_some() && stats.last_failure.is_some());
+        // The success after the 429 ended the streak
+        assert_eq!(stats.consecutive_failures, 0);
+        counters.record_failure();
+        assert_eq!(counters.stats().consecutive_failures, 1);
+        // 100 ms falls in the 100 ms bucket, 300 ms in the 500 ms one
+        assert_eq!(stats.latency.buckets[3..6], [1, 0, 1]);
+        assert_eq!(stats.latency.quantile_bound_ms(0.5), Some(100));
+        assert_eq!(stats.latency.quantile_bound_ms(0.99), Some(500));
+
+        let merged = stats.merge(RequestStats::default());
+        assert_eq!(merged, stats);
+    }
+}
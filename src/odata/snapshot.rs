@@ -0,0 +1,147 @@
+//! Offline `$metadata` snapshots
+//!
+//! `dump-metadata` writes the EDMX next to a JSON rendering of the parsed
+//! [`MetadataModel`] (entity sets, entity types with keys, typed properties
+//! and navigation properties, enums), so the schema can be explored in other
+//! tools without tenant access. Given back with `--metadata-file`, the EDMX
+//! makes a client offline: `$metadata` is read from the snapshot, and every
+//! other request fails with [`ODataError::Offline`] without being sent.
+
+use super::client::ODataError;
+use super::metadata::MetadataModel;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File names `dump-metadata` writes into its output directory
+pub const SNAPSHOT_EDMX_FILE: &str = "metadata.xml";
+pub const SNAPSHOT_MODEL_FILE: &str = "metadata.json";
+
+/// A `$metadata` document read from disk
+#[derive(Debug, Clone)]
+pub struct MetadataSnapshot {
+    path: PathBuf,
+    xml: String,
+}
+
+impl MetadataSnapshot {
+    /// Read the EDMX at `path`; the JSON model cannot stand in for it
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ODataError> {
+        let path = path.as_ref();
+        let xml = fs::read_to_string(path).map_err(|e| {
+            ODataError::ParseError(format!("Cannot read {}: {}", path.display(), e))
+        })?;
+        Self::from_xml(path, xml)
+    }
+
+    pub fn from_xml(path: impl Into<PathBuf>, xml: String) -> Result<Self, ODataError> {
+        let path = path.into();
+        if !xml.contains("EntityContainer") && !xml.contains("EntityType") {
+            return Err(ODataError::ParseError(format!(
+                "{} is not a $metadata document; pass the {} written by dump-metadata",
+                path.display(),
+                SNAPSHOT_EDMX_FILE
+            )));
+        }
+        Ok(Self { path, xml })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The raw EDMX
+    pub fn xml(&self) -> &str {
+        &self.xml
+    }
+}
+
+/// The parsed model as pretty-printed JSON, as `dump-metadata` writes it
+pub fn model_json(model: &MetadataModel) -> String {
+    serde_json::to_string_pretty(model).unwrap_or_default()
+}
+
+/// Write the EDMX and its JSON model into `dir`, returning both paths
+pub fn write_snapshot(dir: &Path, xml: &str) -> std::io::Result<(PathBuf, PathBuf)> {
+    fs::create_dir_all(dir)?;
+    let edmx = dir.join(SNAPSHOT_EDMX_FILE);
+    let model = dir.join(SNAPSHOT_MODEL_FILE);
+    fs::write(&edmx, xml)?;
+    fs::write(&model, model_json(&MetadataModel::parse(xml)))?;
+    Ok((edmx, model))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    const EDMX: &str = r#"<edmx:Edmx Version="4.0"><edmx:DataServices>
+        <Schema Namespace="Microsoft.Dynamics.DataEntities">
+        <EntityType Name="CustomerV3">
+          <Key><PropertyRef Name="dataAreaId" /><PropertyRef Name="CustomerAccount" /></Key>
+          <Property Name="dataAreaId" Type="Edm.String" />
+          <Property Name="CustomerAccount" Type="Edm.String" />
+          <Property Name="CreditLimit" Type="Edm.Decimal" />
+          <Property Name="Status" Type="Microsoft.Dynamics.DataEntities.CustStatus" />
+          <NavigationProperty Name="Orders" Type="Collection(Microsoft.Dynamics.DataEntities.SalesOrderHeader)" Partner="Customer">
+            <ReferentialConstraint Property="CustomerAccount" ReferencedProperty="OrderingCustomerAccountNumber" />
+          </NavigationProperty>
+        </EntityType>
+        <EnumType Name="CustStatus"><Member Name="Active" Value="0" /><Member Name="Blocked" Value="1" /></EnumType>
+        <EntityContainer Name="Resources">
+          <EntitySet Name="CustomersV3" EntityType="Microsoft.Dynamics.DataEntities.CustomerV3" />
+        </EntityContainer>
+        </Schema></edmx:DataServices></edmx:Edmx>"#;
+
+    #[test]
+    fn the_json_model_lists_types_in_name_order() {
+        let xml = (0..20)
+            .rev()
+            .map(|i| format!(r#"<EntityType Name="Type{:02}"></EntityType>"#, i))
+            .collect::<String>();
+        let json = model_json(&MetadataModel::parse(&xml));
+
+        let positions: Vec<usize> = (0..20)
+            .map(|i| json.find(&format!("\"Type{:02}\"", i)).unwrap())
+            .collect();
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]), "{json}");
+    }
+
+    #[test]
+    fn the_json_model_round_trips_to_the_parsed_model() {
+        let parsed = MetadataModel::parse(EDMX);
+        let json: Value = serde_json::from_str(&model_json(&parsed)).unwrap();
+
+        assert_eq!(json["entity_sets"], json!([["CustomersV3", "CustomerV3"]]));
+        let customer = &json["entity_types"]["CustomerV3"];
+        assert_eq!(customer["keys"], json!(["dataAreaId", "CustomerAccount"]));
+        assert_eq!(
+            customer["properties"][2],
+            json!({"name": "CreditLimit", "type_name": "Edm.Decimal"})
+        );
+        assert_eq!(
+            customer["navigation"][0]["constraints"],
+            json!([["CustomerAccount", "OrderingCustomerAccountNumber"]])
+        );
+        assert_eq!(
+            json["enums"][0]["members"],
+            json!([["Active", 0], ["Blocked", 1]])
+        );
+
+        let restored: MetadataModel = serde_json::from_value(json).unwrap();
+        assert_eq!(restored, parsed);
+    }
+
+    #[test]
+    fn snapshots_are_written_and_read_back() {
+        let dir = std::env::temp_dir().join(format!("d365-snapshot-{}", std::process::id()));
+        let (edmx, model) = write_snapshot(&dir, EDMX).unwrap();
+
+        let snapshot = MetadataSnapshot::load(&edmx).unwrap();
+        assert_eq!(snapshot.xml(), EDMX);
+        let error = MetadataSnapshot::load(&model).unwrap_err().to_string();
+        assert!(error.contains("pass the metadata.xml"), "{error}");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+//! Per-call deadlines
+//!
+//! A tool call runs inside [`with_timeout`]; every `ODataClient` request made
+//! from that call, including its retries and retry waits, must finish before
+//! the shared deadline or fails with [`ODataError::Timeout`]. Outside a
+//! scope, each request gets the client's own request timeout. Paging stops at
+//! the deadline and keeps the pages already fetched.
+
+use super::client::ODataError;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Stand-in for a deadline too far away to represent: about 30 years
+const NEVER: Duration = Duration::from_secs(30 * 365 * 24 * 60 * 60);
+
+tokio::task_local! {
+    static DEADLINE: Deadline;
+}
+
+/// When the current call must be done, and the timeout it was derived from
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Deadline {
+    at: Instant,
+    timeout: Duration,
+}
+
+impl Deadline {
+    /// The current call's deadline, or `timeout` from now outside a call
+    pub(crate) fn current_or(timeout: Duration) -> Self {
+        DEADLINE
+            .try_with(|deadline| *deadline)
+            .unwrap_or_else(|_| Self::after(timeout))
+    }
+
+    /// `timeout` from now; one too large to represent never passes
+    fn after(timeout: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            at: now.checked_add(timeout).unwrap_or_else(|| now + NEVER),
+            timeout,
+        }
+    }
+
+    /// Time left before the deadline
+    pub(crate) fn remaining(&self) -> Duration {
+        self.at.saturating_duration_since(Instant::now())
+    }
+
+    pub(crate) fn timeout_secs(&self) -> u64 {
+        self.timeout.as_secs()
+    }
+
+    /// Await `future` unless the deadline passes first
+    pub(crate) async fn run<F: Future>(&self, future: F) -> Result<F::Output, ODataError> {
+        tokio::time::timeout_at(self.at, future)
+            .await
+            .map_err(|_| ODataError::Timeout(self.timeout_secs()))
+    }
+}
+
+/// Run `future` with all of its OData calls bounded by `timeout` from now
+pub async fn with_timeout<F: Future>(timeout: Duration, future: F) -> F::Output {
+    DEADLINE.scope(Deadline::after(timeout), future).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn the_call_deadline_is_shared_by_everything_inside_it() {
+        let outside = Deadline::current_or(Duration::from_secs(5));
+        assert!(outside.run(async { 42 }).await.is_ok());
+
+        let result = with_timeout(Duration::from_millis(30), async {
+            let deadline = Deadline::current_or(Duration::from_secs(60));
+            deadline
+                .run(tokio::time::sleep(Duration::from_millis(10)))
+                .await?;
+            // The second wait would fit the fallback, but not what is left of the call
+            deadline
+                .run(tokio::time::sleep(Duration::from_millis(50)))
+                .await
+        })
+        .await;
+
+        assert!(matches!(result, Err(ODataError::Timeout(0))), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn huge_timeouts_do_not_overflow() {
+        let remaining = with_timeout(Duration::MAX, async {
+            Deadline::current_or(Duration::ZERO).remaining()
+        })
+        .await;
+        assert!(remaining > Duration::from_secs(365 * 24 * 60 * 60));
+    }
+}
@@ -0,0 +1,120 @@
+//! Typed access to records
+//!
+//! Wrappers over the `Value`-based client methods that deserialize each
+//! record into a caller's type, naming the failing record and field when
+//! one does not fit.
+
+use super::client::{EntityKey, ODataClient, ODataError, QueryOptions};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+/// Deserialize one record; the error names the field path that failed
+fn deserialize_record<T: DeserializeOwned>(record: Value) -> Result<T, String> {
+    serde_path_to_error::deserialize(record).map_err(|e| {
+        let path = e.path().to_string();
+        if path == "." {
+            e.inner().to_string()
+        } else {
+            format!("field '{}': {}", path, e.inner())
+        }
+    })
+}
+
+/// Deserialize records in order, failing on the first that does not fit `T`
+pub(crate) fn deserialize_records<T: DeserializeOwned>(
+    records: Vec<Value>,
+) -> Result<Vec<T>, ODataError> {
+    records
+        .into_iter()
+        .enumerate()
+        .map(|(index, record)| {
+            deserialize_record(record).map_err(|e| {
+                ODataError::ParseError(format!("Failed to deserialize record {}: {}", index, e))
+            })
+        })
+        .collect()
+}
+
+impl ODataClient {
+    /// Fetch all pages for an entity as `T`
+    ///
+    /// Annotations such as `@odata.etag` are part of each record, so they can
+    /// be captured with a `rename`:
+    ///
+    /// ```no_run
+    /// use d365_odata_mcp::odata::{ODataClient, ODataError, QueryOptions};
+    /// use serde::Deserialize;
+    ///
+    /// #[derive(Deserialize)]
+    /// struct Account {
+    ///     accountid: String,
+    ///     name: Option<String>,
+    ///     #[serde(rename = "@odata.etag")]
+    ///     etag: String,
+    /// }
+    ///
+    /// async fn active_accounts(client: &ODataClient) -> Result<Vec<Account>, ODataError> {
+    ///     let options = QueryOptions::builder()
+    ///         .select(["accountid", "name"])
+    ///         .filter("statecode eq 0")
+    ///         .build();
+    ///     client.fetch_entities_as("accounts", &options).await
+    /// }
+    /// ```
+    pub async fn fetch_entities_as<T: DeserializeOwned>(
+        &self,
+        entity: &str,
+        options: &QueryOptions,
+    ) -> Result<Vec<T>, ODataError> {
+        deserialize_records(self.fetch_all_pages(entity, options).await?)
+    }
+
+    /// Get a single entity by key as `T`
+    pub async fn get_entity_as<T: DeserializeOwned>(
+        &self,
+        entity: &str,
+        key: &EntityKey,
+    ) -> Result<T, ODataError> {
+        deserialize_record(self.get_entity(entity, key).await?)
+            .map_err(|e| ODataError::ParseError(format!("Failed to deserialize entity: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Account {
+        accountid: String,
+        address: Address,
+        #[serde(rename = "@odata.etag")]
+        etag: String,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Address {
+        city: Option<String>,
+    }
+
+    #[test]
+    fn failing_records_are_named_by_index_and_field_path() {
+        let good =
+            json!({"accountid": "a1", "address": {"city": "Oslo"}, "@odata.etag": "W/\"1\""});
+        let accounts: Vec<Account> = deserialize_records(vec![good.clone()]).unwrap();
+        assert_eq!(accounts[0].address.city.as_deref(), Some("Oslo"));
+        assert_eq!(accounts[0].etag, "W/\"1\"");
+
+        let bad = json!({"accountid": "a2", "address": {"city": 7}, "@odata.etag": "W/\"2\""});
+        let error = deserialize_records::<Account>(vec![good, bad]).unwrap_err();
+        let ODataError::ParseError(message) = error else {
+            panic!("unexpected error: {error:?}");
+        };
+        assert!(
+            message.starts_with("Failed to deserialize record 1: field 'address.city'"),
+            "{message}"
+        );
+    }
+}
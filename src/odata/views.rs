@@ -0,0 +1,265 @@
+//! Dataverse saved views
+//!
+//! System views (`savedqueries`) and personal views (`userqueries`) each hold
+//! a FetchXML query and a layout. Running a view executes its FetchXML as is
+//! and reports the columns in layout order, so the result reads like the view
+//! does in the app.
+
+use super::client::{EntityKey, ODataClient, ODataError, QueryOptions};
+use super::metadata::{attribute, tags};
+use serde_json::Value;
+use std::str::FromStr;
+
+/// `querytype` of the views shown in the app's view selector; advanced find,
+/// lookup and quick find views are left out
+const MAIN_APPLICATION_VIEW: i64 = 0;
+
+/// Where a view is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViewKind {
+    /// Curated by customizers (`savedqueries`)
+    System,
+    /// Saved by a user and visible to the caller (`userqueries`)
+    Personal,
+}
+
+impl ViewKind {
+    fn entity_set(self) -> &'static str {
+        match self {
+            ViewKind::System => "savedqueries",
+            ViewKind::Personal => "userqueries",
+        }
+    }
+
+    fn id_field(self) -> &'static str {
+        match self {
+            ViewKind::System => "savedqueryid",
+            ViewKind::Personal => "userqueryid",
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ViewKind::System => "system",
+            ViewKind::Personal => "personal",
+        }
+    }
+}
+
+impl FromStr for ViewKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "system" => Ok(ViewKind::System),
+            "personal" => Ok(ViewKind::Personal),
+            other => Err(format!(
+                "Invalid view_type '{}': use 'system' or 'personal'",
+                other
+            )),
+        }
+    }
+}
+
+/// A saved view
+#[derive(Debug, Clone, PartialEq)]
+pub struct SavedView {
+    pub id: String,
+    pub name: String,
+    pub kind: ViewKind,
+    /// Logical name of the table the view returns, e.g. `account`
+    pub entity: String,
+    pub description: Option<String>,
+    /// The table's default view (system views only)
+    pub is_default: bool,
+    /// FetchXML query; empty when listing views
+    pub fetch_xml: String,
+    /// Layout column names in display order; empty when listing views
+    pub columns: Vec<String>,
+}
+
+/// A `savedqueries` or `userqueries` record as a view
+fn parse_view(record: &Value, kind: ViewKind) -> Option<SavedView> {
+    let text = |field: &str| record.get(field).and_then(Value::as_str).map(String::from);
+    Some(SavedView {
+        id: text(kind.id_field())?,
+        name: text("name").unwrap_or_default(),
+        kind,
+        entity: text("returnedtypecode").unwrap_or_default(),
+        description: text("description").filter(|description| !description.is_empty()),
+        is_default: record
+            .get("isdefault")
+            .and_then(Value::as_bool)
+            .unwrap_or(false),
+        fetch_xml: text("fetchxml").unwrap_or_default(),
+        columns: text("layoutxml")
+            .map(|layout| layout_columns(&layout))
+            .unwrap_or_default(),
+    })
+}
+
+/// `cell` names of a `layoutxml`, in display order
+pub fn layout_columns(layout_xml: &str) -> Vec<String> {
+    tags(layout_xml)
+        .filter(|tag| tag.starts_with("cell "))
+        .filter_map(|tag| attribute(tag, "name"))
+        .map(String::from)
+        .collect()
+}
+
+/// Logical names of the tables a FetchXML query reads: its `entity` and
+/// every `link-entity`. A table whose name cannot be read is an error, so
+/// callers checking the tables never miss one.
+pub fn fetch_xml_tables(fetch_xml: &str) -> Result<Vec<String>, String> {
+    tags(fetch_xml)
+        .filter(|tag| {
+            matches!(
+                tag.split_whitespace().next(),
+                Some("entity" | "link-entity")
+            )
+        })
+        .map(|tag| {
+            // Attributes may be single-quoted or split across lines
+            let tag = tag.split_whitespace().collect::<Vec<_>>().join(" ");
+            attribute(&tag.replace('\'', "\""), "name")
+                .map(String::from)
+                .ok_or_else(|| format!("FetchXML element without a table name: <{}>", tag))
+        })
+        .collect()
+}
+
+/// `fetch_xml` returning at most `limit` rows: sets `count`, or lowers an
+/// existing `top` (the two cannot be combined)
+pub fn limit_fetch_xml(fetch_xml: &str, limit: usize) -> String {
+    let Some(start) = fetch_xml.find("<fetch") else {
+        return fetch_xml.to_string();
+    };
+    let Some(length) = fetch_xml[start..].find('>') else {
+        return fetch_xml.to_string();
+    };
+    let tag = &fetch_xml[start..start + length];
+
+    let (name, value) = match attribute(tag, "top") {
+        Some(top) => (
+            "top",
+            top.parse().map_or(limit, |top: usize| top.min(limit)),
+        ),
+        None => ("count", limit),
+    };
+    let mut limited = match attribute(tag, name) {
+        Some(current) => tag.replacen(
+            &format!(" {}=\"{}\"", name, current),
+            &format!(" {}=\"{}\"", name, value),
+            1,
+        ),
+        None => tag.replacen("<fetch", &format!("<fetch {}=\"{}\"", name, value), 1),
+    };
+    limited.insert_str(0, &fetch_xml[..start]);
+    limited.push_str(&fetch_xml[start + length..]);
+    limited
+}
+
+impl ODataClient {
+    /// Active main application views of a table (logical name, e.g.
+    /// `account`): system views first, then the caller's personal views
+    pub async fn list_views(&self, logical_name: &str) -> Result<Vec<SavedView>, ODataError> {
+        let mut views = Vec::new();
+        for kind in [ViewKind::System, ViewKind::Personal] {
+            let mut select = vec![
+                kind.id_field().to_string(),
+                "name".to_string(),
+                "description".to_string(),
+                "returnedtypecode".to_string(),
+            ];
+            if kind == ViewKind::System {
+                select.push("isdefault".to_string());
+            }
+            let options = QueryOptions {
+                select: Some(select),
+                filter: Some(format!(
+                    "returnedtypecode eq '{}' and statecode eq 0 and querytype eq {}",
+                    logical_name.replace('\'', "''"),
+                    MAIN_APPLICATION_VIEW
+                )),
+                orderby: Some("name".to_string()),
+                ..Default::default()
+            };
+            let records = self.fetch_all_pages(kind.entity_set(), &options).await?;
+            views.extend(records.iter().filter_map(|record| parse_view(record, kind)));
+        }
+        Ok(views)
+    }
+
+    /// A view with its FetchXML and layout columns
+    pub async fn get_view(&self, kind: ViewKind, id: &str) -> Result<SavedView, ODataError> {
+        let key = EntityKey::Single(id.trim_matches(['{', '}']).to_string());
+        let record = self.get_entity(kind.entity_set(), &key).await?;
+        parse_view(&record, kind).ok_or_else(|| {
+            ODataError::ParseError(format!("{} is not a {} view", id, kind.as_str()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn views_carry_their_layout_columns_in_order() {
+        let record = json!({
+            "savedqueryid": "00000000-0000-0000-00aa-000010001002",
+            "name": "My Active Accounts",
+            "returnedtypecode": "account",
+            "isdefault": true,
+            "description": "",
+            "fetchxml": "<fetch version=\"1.0\"><entity name=\"account\" /></fetch>",
+            "layoutxml": "<grid name=\"resultset\" object=\"1\" jump=\"name\" select=\"1\" icon=\"1\" preview=\"1\">\
+                          <row name=\"result\" id=\"accountid\"><cell name=\"name\" width=\"300\" />\
+                          <cell name=\"telephone1\" width=\"125\" /><cell name=\"primarycontactid\" width=\"150\" />\
+                          </row></grid>"
+        });
+        let view = parse_view(&record, ViewKind::System).unwrap();
+        assert_eq!(view.name, "My Active Accounts");
+        assert!(view.is_default);
+        assert_eq!(view.description, None);
+        assert_eq!(view.columns, vec!["name", "telephone1", "primarycontactid"]);
+
+        // Personal views are keyed by userqueryid
+        assert!(parse_view(&record, ViewKind::Personal).is_none());
+        assert!("shared".parse::<ViewKind>().is_err());
+    }
+
+    #[test]
+    fn fetch_xml_is_limited_with_count_or_a_lower_top() {
+        let plain = r#"<fetch version="1.0" mapping="logical"><entity name="account" /></fetch>"#;
+        assert_eq!(
+            limit_fetch_xml(plain, 50),
+            r#"<fetch count="50" version="1.0" mapping="logical"><entity name="account" /></fetch>"#
+        );
+
+        let counted = r#"<fetch version="1.0" count="5000"><entity name="account" /></fetch>"#;
+        assert!(limit_fetch_xml(counted, 50).starts_with(r#"<fetch version="1.0" count="50">"#));
+
+        let top = r#"<fetch top="10"><entity name="account" /></fetch>"#;
+        assert_eq!(limit_fetch_xml(top, 50), top);
+        assert!(limit_fetch_xml(top, 3).starts_with(r#"<fetch top="3">"#));
+    }
+
+    #[test]
+    fn fetch_xml_names_every_joined_table() {
+        let fetch_xml = r#"<fetch><entity name="account"><attribute name="name" />
+            <link-entity name="contact" from="contactid" to="primarycontactid">
+            <link-entity
+              name='systemuser' from='systemuserid' to='owninguser' /></link-entity>
+            </entity></fetch>"#;
+        assert_eq!(
+            fetch_xml_tables(fetch_xml).unwrap(),
+            vec!["account", "contact", "systemuser"]
+        );
+        assert!(
+            fetch_xml_tables(r#"<fetch><entity><link-entity from="x" /></entity></fetch>"#)
+                .is_err()
+        );
+    }
+}